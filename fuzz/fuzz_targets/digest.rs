@@ -0,0 +1,25 @@
+//! Digests, as they come in manifest and blob requests and in manifests' descriptors:  only
+//! `sha256:` and 64 lowercase hex digits parse, and they print back as they came in.
+
+#![no_main]
+
+use std::str::FromStr;
+
+use libfuzzer_sys::fuzz_target;
+use oci_registry::image::ImageReference;
+
+fuzz_target!(|input: &[u8]| {
+	let Ok(input) = std::str::from_utf8(input) else {
+		return;
+	};
+	let digest = format!("sha256:{input}");
+	match ImageReference::from_str(&digest) {
+		Ok(ImageReference::Sha256(hex)) => {
+			assert_eq!(hex, input);
+			assert!(hex.len() == 64 && hex.bytes().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c)));
+			assert_eq!(ImageReference::Sha256(hex).to_string(), digest);
+		},
+		Ok(ImageReference::Tag(_)) => panic!("{digest} parsed as a tag"),
+		Err(_) => ()
+	}
+});
@@ -0,0 +1,27 @@
+//! Image names and references, as they come in request paths:  anything that parses has to print
+//! back as it came in, so that what's sent upstream and used in storage paths is what was asked for.
+
+#![no_main]
+
+use std::str::FromStr;
+
+use libfuzzer_sys::fuzz_target;
+use oci_registry::image::ImageName;
+use oci_registry::image::ImageReference;
+use oci_registry::image::MAX_NAME_LENGTH;
+
+fuzz_target!(|input: &str| {
+	let (name, reference) = input.split_once('@').or_else(|| input.rsplit_once(':').filter(|(_, tag)| !tag.contains('/'))).unwrap_or((input, "latest"));
+	if let Ok(name) = ImageName::from_str(name) {
+		let name = name.to_string();
+		assert!(name.len() <= MAX_NAME_LENGTH);
+		assert!(!name.contains("..") && !name.contains("//") && !name.starts_with('/') && !name.ends_with('/'));
+		let (namespace, image) = oci_registry::api::split_image(None, &name, "docker.io");
+		assert!(!namespace.is_empty() && !image.is_empty());
+	}
+	if let Ok(parsed) = ImageReference::from_str(reference) {
+		assert_eq!(parsed.to_string(), reference);
+		assert_eq!(parsed.to_str(), reference);
+		assert!(!reference.contains('/'));
+	}
+});
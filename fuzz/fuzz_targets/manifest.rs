@@ -0,0 +1,26 @@
+//! Manifests, as they come from upstream or are pushed by clients:  whatever's in them, reading
+//! their media type, subject, and foreign layers never panics, the subjects they're indexed under
+//! are always digests, and rewritten manifests are JSON with nothing foreign left in them.
+
+#![no_main]
+
+use std::str::FromStr;
+
+use libfuzzer_sys::fuzz_target;
+use oci_registry::api::foreign;
+use oci_registry::api::referrers;
+use oci_registry::image::ImageReference;
+use oci_registry::storage::declared_media_type;
+
+fuzz_target!(|manifest: &[u8]| {
+	let _ = declared_media_type(manifest);
+	if let Some((subject, _)) = referrers::referrer(manifest, "application/vnd.oci.image.manifest.v1+json") {
+		assert!(matches!(ImageReference::from_str(&subject), Ok(ImageReference::Sha256(_))), "{subject}");
+	}
+	let _ = foreign::foreign_layers(manifest);
+	if let Some((rewritten, digest)) = foreign::rewrite(manifest) {
+		assert!(serde_json::from_slice::<serde_json::Value>(&rewritten).is_ok());
+		assert!(ImageReference::from_str(&digest).is_ok());
+		assert!(foreign::foreign_layers(&rewritten).is_empty());
+	}
+});
@@ -1,57 +1,925 @@
-use std::iter;
+use core::future;
+use core::future::Future;
+use core::str::FromStr;
+use core::time::Duration;
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 use actix_web::body::SizedStream;
+use actix_web::dev::Service;
+use actix_web::dev::ServiceResponse;
+use actix_web::error::PathError;
 use actix_web::http;
 use actix_web::http::header::HeaderName;
+use actix_web::http::header::HeaderValue;
+use actix_web::middleware::DefaultHeaders;
 use actix_web::rt;
 use actix_web::web;
+use actix_web::HttpMessage;
+use actix_web::HttpRequest;
 use actix_web::HttpResponse;
+use bytes::Bytes;
+use bytes::BytesMut;
 use compact_str::CompactString;
-use dkregistry::v2::Client;
+use futures::future::FutureExt;
+use futures::stream::LocalBoxStream;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
 use once_cell::sync::Lazy;
 use prometheus::register_int_counter_vec;
 use prometheus::IntCounterVec;
 use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::sync::oneshot;
 use tokio::sync::Mutex;
+use tokio::time::timeout_at;
+use tokio::time::Instant;
 use tracing::error;
+use tracing::info;
 use tracing::warn;
+use tracing::Instrument;
+use tracing::Span;
 
 use crate::image::ImageName;
 use crate::image::ImageReference;
+use crate::report;
+use crate::storage::health;
 use crate::storage::Manifest;
+use crate::storage::ManifestMetadata;
+use crate::storage::replica;
+use crate::storage::replica::Replicator;
+use crate::storage::ReadStream;
 use crate::storage::Repository;
 use crate::upstream::Clients;
+use crate::upstream::downloads::DownloadPermit;
+use crate::upstream::ForeignLayerPolicy;
+use crate::upstream::InvalidationConfig;
+use crate::upstream::NamespaceParameter;
+use crate::upstream::ProbeMethod;
+use crate::upstream::pull::PulledManifest;
+use crate::upstream::RevalidationPolicy;
+use crate::upstream::Schema1Policy;
+use crate::upstream::StalePolicy;
+use crate::upstream::throttle::RateLimited;
 
+pub mod admin_token;
+pub mod alias;
+use alias::Aliases;
+pub mod auth;
+use auth::Access;
+use auth::Entitlements;
+#[cfg(feature = "bundle")]
+pub mod bundle;
+pub mod cache_control;
+mod cache_status;
+mod cascade;
+use cache_control::CacheControl;
+pub mod cdn;
+use cdn::Cdn;
+pub mod checkpoint;
+pub mod client_ip;
+pub mod compression;
+use compression::Compress;
+pub mod cosign;
+mod counters;
+pub mod debug;
+pub mod edge;
+use client_ip::ClientIp;
 pub mod error;
 use error::should_retry_without_namespace;
+mod fan_out;
+pub mod foreign;
+pub mod handoff;
+use handoff::Handoff;
+pub mod helm;
+pub mod history;
 use error::Error;
+pub mod identity;
+pub mod image_stats;
+pub mod info;
+mod inline;
+use inline::InlineBlobs;
+pub mod inspect;
+#[cfg(test)]
+mod integration;
+pub mod known_blobs;
+pub mod labels;
+mod lazy;
+use known_blobs::KnownBlobs;
+pub mod limits;
+use limits::Limits;
+pub mod list;
+mod load;
+pub mod maintenance;
+use maintenance::Maintenance;
+pub mod mirror;
+mod mismatch;
+pub mod pins;
+use pins::Pins;
+pub mod plugin;
+use plugin::Hook;
+use plugin::ObjectKind;
+use plugin::Plugins;
+pub mod prefetch;
+use prefetch::Prefetcher;
+pub mod probe;
+pub mod provenance;
+use provenance::Provenance;
+pub mod purge;
+pub mod push;
+pub mod range;
+use range::Requested;
+mod reannotated;
+pub mod referrers;
+pub mod request_id;
+pub mod rewrite;
+pub mod sampling;
+pub mod sbom;
+pub mod schema1;
+pub mod search;
+use search::SearchIndex;
+pub mod shadow;
+pub mod shard;
+use shard::Shards;
+pub mod signed;
+use signed::SigningKey;
 pub mod stream;
+use stream::AbortNotifyingStream;
 use stream::DigestCheckedStream;
+use stream::LengthCheckedStream;
+pub mod sweep;
+mod tag_lists;
+use tag_lists::TagLists;
+pub mod tenant;
+use tenant::Tenants;
+pub mod timestamp;
+use timestamp::Timestamper;
+pub mod trace;
+use trace::CacheDecision;
+use trace::Hop;
+pub mod ttl;
+pub mod upstream_override;
+pub mod webhook;
+
+/// What to do with a blob being fetched from upstream when the client that asked for it
+/// disconnects partway through.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum ClientAbortPolicy {
+	/// Keep fetching the blob into the cache, so that the client's retry is a cache hit
+	#[default]
+	Continue,
+	/// Stop fetching, and delete what was written of the blob
+	Abort
+}
+
+/// A listen address with a default namespace of its own, given as `address=namespace`, so that one
+/// instance can stand in for a different registry on each port.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ListenerNamespace {
+	pub address: SocketAddr,
+	pub namespace: CompactString,
+	/// Serves nothing but `namespace`, the way that registry's own mirror would:  image names are
+	/// never split into a namespace and an image, and requests for other namespaces are refused
+	pub pinned: bool
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid listener namespace {0:?}; expected address=namespace")]
+pub struct InvalidListenerNamespace(String);
+
+impl FromStr for ListenerNamespace {
+	type Err = InvalidListenerNamespace;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || InvalidListenerNamespace(s.to_owned());
+		let (address, namespace) = s.trim().split_once('=').ok_or_else(invalid)?;
+		match (address.parse::<SocketAddr>(), namespace) {
+			(Ok(address), namespace) if !namespace.is_empty() => Ok(Self { address, namespace: namespace.into(), pinned: false }),
+			_ => Err(invalid())
+		}
+	}
+}
+
+impl ListenerNamespace {
+	/// This listener, serving only its own namespace.
+	pub fn pinned(self) -> Self {
+		Self { pinned: true, ..self }
+	}
+
+	/// Whether a connection accepted at `local` came in through this listener.  Listeners bound to
+	/// an unspecified address see connections at whichever address they were made to.
+	fn accepts(&self, local: SocketAddr) -> bool {
+		self.address.port() == local.port() && (self.address.ip() == local.ip() || self.address.ip().is_unspecified())
+	}
+}
 
 pub struct RequestConfig {
 	repo: Repository,
 	upstream: Mutex<Clients>,
 	default_ns: CompactString,
-	check_cache_digest: bool
+	check_cache_digest: bool,
+	check_manifest_digest: bool,
+	max_manifest_size: usize,
+	base_path: String,
+	max_page_size: usize,
+	manifest_deadline: Duration,
+	blob_deadline: Duration,
+	client_abort_policy: ClientAbortPolicy,
+	entitlements: Entitlements,
+	known_blobs: KnownBlobs,
+	inline_blobs: InlineBlobs,
+	signatures: cosign::Verified,
+	handoff: Arc<Handoff>,
+	pins: Arc<Pins>,
+	aliases: Arc<Aliases>,
+	mirror: mirror::Status,
+	tag_lists: TagLists,
+	search_index: SearchIndex,
+	webhook_token: Option<String>,
+	upstream_override_token: Option<String>,
+	admin_token: Option<String>,
+	cache_control: CacheControl,
+	/// Whether pulls say in `X-Cache` how they were answered
+	cache_status_headers: bool,
+	cdn: Option<Cdn>,
+	provenance: bool,
+	timestamper: Option<Arc<Timestamper>>,
+	trash: bool,
+	listener_namespaces: Vec<ListenerNamespace>,
+	replicator: Option<Replicator>,
+	signing_key: Option<SigningKey>,
+	tenants: Option<Arc<Tenants>>,
+	plugins: Plugins,
+	prefetch: Option<Arc<Prefetcher>>,
+	maintenance: Maintenance,
+	shards: Option<Arc<Shards>>,
+	limits: Limits,
+	compression: bool,
+	instance_name: Option<String>
 }
 
 impl RequestConfig {
-	pub fn new(repo: Repository, upstream: Clients, default_ns: CompactString, check_cache_digest: bool) -> Self {
-		Self { repo, upstream: Mutex::new(upstream), default_ns, check_cache_digest }
+	pub fn new(repo: Repository, upstream: Clients, default_ns: CompactString, check_cache_digest: bool, max_manifest_size: usize) -> Self {
+		Self {
+			repo,
+			upstream: Mutex::new(upstream),
+			default_ns,
+			check_cache_digest,
+			check_manifest_digest: false,
+			max_manifest_size,
+			base_path: String::new(),
+			max_page_size: 1000,
+			manifest_deadline: Duration::from_secs(30),
+			blob_deadline: Duration::from_secs(30 * 60),
+			client_abort_policy: ClientAbortPolicy::Continue,
+			entitlements: Entitlements::new(),
+			known_blobs: KnownBlobs::default(),
+			inline_blobs: InlineBlobs::default(),
+			signatures: cosign::Verified::default(),
+			handoff: Arc::default(),
+			pins: Arc::default(),
+			aliases: Arc::default(),
+			mirror: mirror::Status::default(),
+			tag_lists: TagLists::default(),
+			search_index: SearchIndex::default(),
+			webhook_token: None,
+			upstream_override_token: None,
+			admin_token: None,
+			cache_control: CacheControl::default(),
+			cache_status_headers: false,
+			cdn: None,
+			provenance: false,
+			timestamper: None,
+			trash: true,
+			listener_namespaces: Vec::new(),
+			replicator: None,
+			signing_key: None,
+			tenants: None,
+			plugins: Plugins::default(),
+			prefetch: None,
+			maintenance: Maintenance::default(),
+			shards: None,
+			limits: Limits::default(),
+			compression: false,
+			instance_name: None
+		}
+	}
+
+	/// Sets the path prefix the API is mounted under, as returned by [`normalize_base_path`], so
+	/// that URLs we hand out point back at it.
+	pub fn with_base_path(mut self, base_path: String) -> Self {
+		self.base_path = base_path;
+		self
+	}
+
+	/// Caps the number of entries returned in one page of a list endpoint.
+	pub fn with_max_page_size(mut self, max_page_size: usize) -> Self {
+		self.max_page_size = max_page_size;
+		self
+	}
+
+	/// Sets how long a manifest or blob request may take, end to end, before whatever it's waiting
+	/// on upstream or in storage is given up on.
+	pub fn with_deadlines(mut self, manifest: Duration, blob: Duration) -> Self {
+		self.manifest_deadline = manifest;
+		self.blob_deadline = blob;
+		self
+	}
+
+	pub fn with_client_abort_policy(mut self, policy: ClientAbortPolicy) -> Self {
+		self.client_abort_policy = policy;
+		self
+	}
+
+	/// Sets how long a blob seen in storage is assumed to still be there when answering `HEAD`
+	/// requests; zero always checks storage.
+	pub fn with_known_blob_ttl(mut self, ttl: Duration) -> Self {
+		self.known_blobs = KnownBlobs::new(ttl);
+		self
+	}
+
+	/// Shares the set of pinned images with whatever else needs it; cleanup, in particular.
+	pub fn with_pins(mut self, pins: Arc<Pins>) -> Self {
+		self.pins = pins;
+		self
+	}
+
+	pub fn with_aliases(mut self, aliases: Arc<Aliases>) -> Self {
+		self.aliases = aliases;
+		self
+	}
+
+	/// Enables `/_admin/webhook`, for senders with this token.
+	pub fn with_webhook_token(mut self, token: Option<String>) -> Self {
+		self.webhook_token = token;
+		self
+	}
+
+	/// Lets clients with this token send their requests to another upstream with
+	/// [`upstream_override::HEADER`].
+	pub fn with_upstream_override_token(mut self, token: Option<String>) -> Self {
+		self.upstream_override_token = token;
+		self
+	}
+
+	/// Puts `/_admin` behind this token, in [`admin_token::HEADER`]; signed URLs can't be minted
+	/// without one.
+	pub fn with_admin_token(mut self, token: Option<String>) -> Self {
+		self.admin_token = token;
+		self
+	}
+
+	/// Checks manifests cached under their digests against them whenever they're served.
+	pub fn with_check_manifest_digest(mut self, enabled: bool) -> Self {
+		self.check_manifest_digest = enabled;
+		self
+	}
+
+	/// Sets the `Cache-Control` headers pulls are served with, for HTTP caches in front.
+	pub fn with_cache_control(mut self, cache_control: CacheControl) -> Self {
+		self.cache_control = cache_control;
+		self
+	}
+
+	/// Has pulls say how they were answered, in `X-Cache` and `X-Cache-Age`; see [`cache_status`].
+	pub fn with_cache_status_headers(mut self, enabled: bool) -> Self {
+		self.cache_status_headers = enabled;
+		self
+	}
+
+	/// Puts the registry behind a CDN, which origin requests have to come by way of and purges are
+	/// passed on to.
+	pub fn with_cdn(mut self, cdn: Option<Cdn>) -> Self {
+		self.cdn = cdn;
+		self
+	}
+
+	/// Caps how big registry requests' headers and pushed upload chunks can be; see [`limits`].
+	pub fn with_limits(mut self, limits: Limits) -> Self {
+		self.limits = limits;
+		self
+	}
+
+	/// Compresses manifests and lists for clients that ask for it; see [`compression`].
+	pub fn with_compression(mut self, compression: bool) -> Self {
+		self.compression = compression;
+		self
+	}
+
+	/// Names this instance, for what it saves of its own in the checkpoint; see [`checkpoint`].
+	pub fn with_instance_name(mut self, name: Option<String>) -> Self {
+		self.instance_name = name.filter(|name| !name.is_empty());
+		self
+	}
+
+	/// Records where each manifest and blob cached from upstream came from; see [`provenance`].
+	pub fn with_provenance(mut self, enabled: bool) -> Self {
+		self.provenance = enabled;
+		self
+	}
+
+	/// Timestamps each manifest newly cached from upstream; see [`timestamp`].
+	pub fn with_timestamper(mut self, timestamper: Option<Timestamper>) -> Self {
+		self.timestamper = timestamper.map(Arc::new);
+		self
+	}
+
+	/// Whether admin purges move objects into the trash, where they can be restored from, rather than
+	/// deleting them outright.
+	pub fn with_trash(mut self, enabled: bool) -> Self {
+		self.trash = enabled;
+		self
+	}
+
+	/// Gives requests accepted on these listeners their own default namespace.
+	pub fn with_listener_namespaces(mut self, listeners: Vec<ListenerNamespace>) -> Self {
+		self.listener_namespaces = listeners;
+		self
+	}
+
+	/// Copies everything newly written to storage to a replica as well.
+	pub fn with_replicator(mut self, replicator: Option<Replicator>) -> Self {
+		self.replicator = replicator;
+		self
+	}
+
+	/// Enables signed URLs, minted through `/_admin/sign` with this key and lasting up to `max_ttl`,
+	/// and served under `/_signed`.
+	pub fn with_signing_key(mut self, key: Option<&str>, max_ttl: Duration) -> Self {
+		self.signing_key = key.map(|key| SigningKey::new(key, max_ttl));
+		self
+	}
+
+	/// Serves every registry request on behalf of one of these tenants, each with its own cache.
+	pub fn with_tenants(mut self, tenants: Option<Arc<Tenants>>) -> Self {
+		self.tenants = tenants;
+		self
+	}
+
+	/// Runs every request through these plugins' policy hooks, in order.
+	pub fn with_plugins(mut self, plugins: Vec<Arc<dyn plugin::Plugin>>) -> Self {
+		self.plugins = plugins.into();
+		self
+	}
+
+	/// Prefetches manifests this strategy expects to be pulled after the tags clients pull.
+	pub fn with_prefetch(mut self, strategy: Option<Arc<dyn prefetch::Strategy>>) -> Self {
+		self.prefetch = strategy.map(|s| Arc::new(Prefetcher::new(s)));
+		self
+	}
+
+	/// Redirects pulls of blobs on other nodes' shares of this ring to them.
+	pub fn with_shards(mut self, shards: Option<Arc<Shards>>) -> Self {
+		self.shards = shards;
+		self
+	}
+
+	/// Shares what the process this one is taking over from is still filling with whatever follows
+	/// it; see [`handoff::take_over`].
+	pub fn with_handoff(mut self, handoff: Arc<Handoff>) -> Self {
+		self.handoff = handoff;
+		self
+	}
+
+	/// Where a client's blob pull should go instead, if blobs are sharded and it's another node's.
+	/// Pulls with credentials are served here, as clients don't send them on to another host.
+	fn shard_redirect(&self, http_req: &HttpRequest, digest: &str) -> Option<HttpResponse> {
+		match http_req.headers().contains_key(http::header::AUTHORIZATION) {
+			true => None,
+			false => self.shards.as_deref()?.redirect(http_req, digest)
+		}
+	}
+
+	/// Whose content a request for `namespace` is for:  its tenant's, if there are tenants, and
+	/// otherwise whatever the upstream's auth mode says.
+	fn access(&self, http_req: &HttpRequest, namespace: &str, upstream: &crate::upstream::Client) -> Result<Access, Error> {
+		match self.tenants.as_deref() {
+			Some(tenants) => Ok(Access::Tenant(tenants.admit(http_req, namespace)?.name().into())),
+			None => Access::resolve(http_req, upstream)
+		}
+	}
+
+	/// Whose cache an admin API query is about:  with tenants, the tenant whose credentials it
+	/// carries, and otherwise the shared one.
+	fn admin_access(&self, http_req: &HttpRequest) -> Result<Access, Error> {
+		match self.tenants.as_deref() {
+			Some(tenants) => Ok(Access::Tenant(tenants.authenticate(http_req)?.name().into())),
+			None => Ok(Access::Shared)
+		}
+	}
+
+	/// Fails unless `access` can see `namespace`:  a tenant only sees the namespaces it's allowed.
+	fn admin_namespace(&self, access: &Access, namespace: &str) -> Result<(), Error> {
+		match (self.tenants.as_deref(), access.tenant()) {
+			(Some(tenants), Some(tenant)) if !tenants.allows(tenant, namespace) => Err(Error::NamespaceDenied { tenant: tenant.into(), namespace: namespace.into() }),
+			_ => Ok(())
+		}
+	}
+
+	/// The upstream client for a request to `namespace`:  its own, or the one for the upstream the
+	/// request asks for instead, if it's allowed to, held to `namespace`'s signature keys and rewrite
+	/// rules, since what it fetches is cached for `namespace`.
+	async fn upstream_for(&self, namespace: &str, http_req: Option<&HttpRequest>) -> Result<crate::upstream::Client, Error> {
+		let key = upstream_override::requested(self.upstream_override_token.as_deref(), http_req, namespace)?;
+		let mut upstream = self.upstream.lock().await;
+		let client = upstream.get(namespace)?.clone();
+		match key {
+			Some(key) => Ok(upstream.get_override(&key)?.clone().standing_in_for(&client)),
+			None => Ok(client)
+		}
+	}
+
+	/// Adds `Cache-Control` to a pull's response, as [`CacheControl::apply`] has it, and the cache
+	/// status headers where they're on.  Whatever's pulled with credentials, of a tenant's or to pass
+	/// through, is only for the client pulling it.
+	fn cacheable(&self, mut response: HttpResponse, immutable: bool, http_req: &HttpRequest) -> HttpResponse {
+		let private = self.tenants.is_some() || http_req.headers().contains_key(http::header::AUTHORIZATION);
+		self.cache_control.apply(&mut response, immutable, private);
+		if (self.cache_status_headers) {
+			cache_status::apply(&mut response);
+		}
+		response
+	}
+
+	/// How long what's cached for each namespace lasts, as it stands, for cleanup.
+	pub async fn invalidation_config(&self) -> InvalidationConfig {
+		self.upstream.lock().await.invalidation_config()
+	}
+
+	/// Swaps in credentials from a reloaded Docker config file, returning how many upstreams' changed.
+	pub async fn set_docker_config(&self, docker: crate::upstream::docker_config::DockerConfig) -> usize {
+		self.upstream.lock().await.set_docker_config(docker)
+	}
+
+	/// Hands the upstream clients to `update`, for credentials fetched from a secret store.
+	pub(crate) async fn set_upstream_credentials(&self, update: impl FnOnce(&mut crate::upstream::Clients)) {
+		update(&mut self.upstream.lock().await);
+	}
+
+	/// Queues an object that's just been cached to be copied to the replica, if there is one.
+	fn replicate(&self, object: &str, kind: replica::Kind) {
+		if let Some(replicator) = self.replicator.as_ref() {
+			replicator.enqueue(object, kind);
+		}
+	}
+
+	/// The namespace for image names that don't start with one:  the one configured for the
+	/// listener the request came in on, if any, and otherwise the default.
+	fn default_ns(&self, http_req: Option<&HttpRequest>) -> &str {
+		match self.listener(http_req) {
+			Some(listener) => listener.namespace.as_str(),
+			None => self.default_ns.as_str()
+		}
+	}
+
+	fn listener(&self, http_req: Option<&HttpRequest>) -> Option<&ListenerNamespace> {
+		let local = http_req.map(|r| r.app_config().local_addr())?;
+		self.listener_namespaces.iter().find(|l| l.accepts(local))
+	}
+
+	/// Which namespace and image a registry request is for:  as [`split_image`] has it, unless the
+	/// request came in on a pinned listener, which takes image names as they are, and refuses
+	/// requests naming another namespace, by `ns` parameter or registry host, rather than asking
+	/// its upstream for an image by that name.
+	fn route<'a>(&'a self, ns: Option<&'a str>, image: &'a str, http_req: Option<&HttpRequest>) -> Result<(&'a str, &'a str), Error> {
+		let Some(listener) = self.listener(http_req).filter(|l| l.pinned) else {
+			return Ok(split_image(ns, image, self.default_ns(http_req)));
+		};
+		let host = image.split_once('/').map(|(first, _)| first).filter(|first| is_registry_host(first));
+		match ns.filter(|ns| *ns != listener.namespace.as_str()).or(host) {
+			Some(other) => Err(Error::NamespaceNotServed { namespace: other.into(), served: listener.namespace.clone() }),
+			None => Ok((listener.namespace.as_str(), image))
+		}
+	}
+
+	/// Turns a path relative to where the API is mounted (e.g. `/v2/...`) into one clients can
+	/// request.
+	pub fn absolute_path(&self, path: &str) -> String {
+		format!("{}{path}", self.base_path)
 	}
 }
 
-async fn authenticate_with_upstream(upstream: &mut Client, scope: &str) -> Result<(), dkregistry::errors::Error> {
-	upstream.authenticate(&[scope]).await?;
+/// Turns a configured base path into the form actix scopes expect:  empty for the root, and
+/// otherwise with a leading slash and no trailing slash.
+pub fn normalize_base_path(base_path: &str) -> String {
+	match base_path.trim_matches('/') {
+		"" => String::new(),
+		path => format!("/{path}")
+	}
+}
+
+/// The access log format used for every scope we serve.  Requests are attributed to the
+/// [`ClientIp`] found in their extensions, falling back to the peer address.
+pub fn logger() -> actix_web::middleware::Logger {
+	actix_web::middleware::Logger::new(&format!(r#"%{{client_ip}}xi "%r" %s %b "%{{Referer}}i" "%{{User-Agent}}i" %{{{}}}o %T"#, request_id::HEADER)).custom_request_replace("client_ip", |req| {
+		match req.extensions().get::<ClientIp>() {
+			Some(ip) => ip.to_string(),
+			None => req.peer_addr().map(|a| a.ip().to_string()).unwrap_or_else(|| "-".into())
+		}
+	})
+}
+
+/// Answers a request path that doesn't parse with the registry error for whichever part of it is
+/// invalid, rather than with actix's plain-text 404, so that clients are told what's wrong with it
+/// and nothing that isn't a valid name or reference is ever sent on to upstream.
+fn path_error(error: PathError, req: &HttpRequest) -> actix_web::Error {
+	let info = req.match_info();
+	let error = match (info.get("image"), info.get("reference")) {
+		(Some(image), _) if ImageName::from_str(image).is_err() => Error::InvalidName(image.to_owned()),
+		(_, Some(reference)) if ImageReference::from_str(reference).is_err() => match reference.contains(':') {
+			true => Error::InvalidDigest,
+			false => Error::InvalidTag(reference.to_owned())
+		},
+		_ => return error.into()
+	};
+	error.into()
+}
+
+/// Path parsing for every scope with image names or references in its paths.
+fn path_config() -> web::PathConfig {
+	web::PathConfig::default().error_handler(path_error)
+}
+
+/// Registers the distribution API under `/v2`, and search under `/v1`.
+pub fn registry(cfg: &mut web::ServiceConfig) {
+	cfg.service(
+		web::scope("/v2")
+			.app_data(path_config())
+			.wrap_fn(|mut req, srv| match cdn::check(&req).and_then(|()| limits::check_headers(&req)) {
+				Ok(()) => {
+					compression::negotiate(&mut req);
+					let sample = sampling::start(&req);
+					srv
+						.call(req)
+						.map(|response| {
+							response.map(|response| {
+								compression::count(&response);
+								sampling::finish(sample, &response);
+								response.map_into_boxed_body()
+							})
+						})
+						.left_future()
+				},
+				Err(error) => future::ready(Ok(req.error_response(error))).right_future()
+			})
+			.wrap(logger())
+			.route("/", web::get().to(root))
+			.route("/_catalog", web::get().to(list::catalog).wrap(Compress::default()))
+			// /v2/library/telegraf/tags/list
+			.route("/{image:[^{}]+}/tags/list", web::get().to(list::tags).wrap(Compress::default()))
+			// /v2/library/telegraf/manifests/1.24-alpine
+			// /v2/library/redis/manifests/sha256:226cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883
+			// /v2/docker.io/library/telegraf/manifests/1.24-alpine
+			// /v2/docker.io/library/redis/manifests/sha256:226cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883
+			.route("/{image:[^{}]+}/manifests/{reference}", web::head().to(manifest))
+			.route("/{image:[^{}]+}/manifests/{reference}", web::get().to(manifest).wrap(Compress::default()))
+			.route("/{image:[^{}]+}/manifests/{reference}", web::put().to(push::put_manifest))
+			// Pushes, for namespaces configured for write-through
+			.route("/{image:[^{}]+}/blobs/uploads/", web::post().to(push::start_upload))
+			.route("/{image:[^{}]+}/blobs/uploads/{session}", web::patch().to(push::upload))
+			.route("/{image:[^{}]+}/blobs/uploads/{session}", web::put().to(push::upload))
+			.route("/{image:[^{}]+}/blobs/uploads/{session}", web::get().to(push::upload))
+			.route("/{image:[^{}]+}/blobs/uploads/{session}", web::delete().to(push::upload))
+			// /v2/grafana/grafana/blobs/sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd
+			// /v2/docker.io/grafana/grafana/blobs/sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd
+			.route("/{image:[^{}]+}/blobs/{digest}", web::head().to(blob_head))
+			.route("/{image:[^{}]+}/blobs/{digest}", web::get().to(blob))
+			// /v2/library/redis/referrers/sha256:226cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883
+			.route("/{image:[^{}]+}/referrers/{digest}", web::get().to(referrers::referrers).wrap(Compress::default()))
+			.configure(extensions)
+			.wrap(DefaultHeaders::new().add((HeaderName::from_static("docker-distribution-api-version"), HeaderValue::from_static("registry/2.0"))))
+	);
+	// The one part of the v1 API `docker search` still uses
+	cfg.service(
+		web::scope("/v1")
+			.wrap_fn(|req, srv| match cdn::check(&req) {
+				Ok(()) => srv.call(req).map(|response| response.map(ServiceResponse::map_into_boxed_body)).left_future(),
+				Err(error) => future::ready(Ok(req.error_response(error))).right_future()
+			})
+			.wrap(logger())
+			.route("/search", web::get().to(search::search).wrap(Compress::default()))
+	);
+}
+
+/// Registers the extensions to the distribution API compiled into this build.
+#[cfg_attr(not(feature = "bundle"), allow(unused_variables))]
+fn extensions(cfg: &mut web::ServiceConfig) {
+	#[cfg(feature = "bundle")]
+	cfg.route("/{image:[^{}]+}/bundle/{reference}", web::get().to(bundle::bundle));
+}
+
+/// Registers the cache management endpoints under `/_admin`.
+pub fn admin(cfg: &mut web::ServiceConfig) {
+	cfg.service(
+		web::scope("/_admin")
+			.app_data(path_config())
+			.wrap_fn(|req, srv| match admin_token::guard(&req) {
+				Ok(()) => srv.call(req).map(|response| response.map(ServiceResponse::map_into_boxed_body)).left_future(),
+				Err(error) => future::ready(Ok(req.error_response(error))).right_future()
+			})
+			.wrap(logger())
+			.route("/{image:[^{}]+}/manifests/{reference}", web::delete().to(delete_manifest))
+			.route("/{image:[^{}]+}/blobs/{digest}", web::delete().to(delete_blob))
+			.route("/trash", web::get().to(list_trash))
+			.route("/trash/{image:[^{}]+}/manifests/{reference}", web::post().to(restore_manifest))
+			.route("/trash/{image:[^{}]+}/blobs/{digest}", web::post().to(restore_blob))
+			.route("/pins", web::get().to(pins::list))
+			.route("/pins/{image:[^{}]+}/manifests/{reference}", web::put().to(pins::add))
+			.route("/pins/{image:[^{}]+}/manifests/{reference}", web::delete().to(pins::remove))
+			.route("/holds", web::get().to(pins::list_holds))
+			.route("/aliases", web::get().to(alias::list))
+			.route("/aliases/{image:[^{}]+}/manifests/{reference}", web::put().to(alias::add))
+			.route("/aliases/{image:[^{}]+}/manifests/{reference}", web::delete().to(alias::remove))
+			.route("/mirror", web::get().to(mirror::status))
+			.route("/search", web::get().to(labels::search))
+			.route("/history/{image:[^{}]+}/manifests/{reference}", web::get().to(history::history))
+			.route("/provenance/{image:[^{}]+}/manifests/{reference}", web::get().to(provenance::manifest))
+			.route("/provenance/{image:[^{}]+}/blobs/{digest}", web::get().to(provenance::blob))
+			.route("/timestamps/{image:[^{}]+}/manifests/{reference}", web::get().to(timestamp::timestamp))
+			.route("/sbom", web::get().to(sbom::query))
+			.route("/webhook/{namespace}", web::post().to(webhook::receive))
+			.route("/sign/{image:[^{}]+}/manifests/{reference}", web::post().to(signed::sign_manifest))
+			.route("/sign/{image:[^{}]+}/blobs/{digest}", web::post().to(signed::sign_blob))
+			.route("/info", web::get().to(info::info))
+			.route("/namespaces", web::get().to(info::namespaces))
+			.route("/namespaces/{namespace}", web::get().to(info::namespace))
+			.route("/stats/image/{image:[^{}]+}", web::get().to(image_stats::image))
+			.route("/ttls", web::get().to(ttl::list))
+			.route("/ttls/{namespace}", web::get().to(ttl::get))
+			.route("/ttls/{namespace}", web::put().to(ttl::set))
+			.route("/ttls/{namespace}", web::delete().to(ttl::remove))
+			.route("/maintenance", web::get().to(maintenance::get))
+			.route("/maintenance", web::put().to(maintenance::set))
+			.route("/log-level", web::get().to(crate::logging::get_level))
+			.route("/log-level", web::put().to(crate::logging::set_level))
+	);
+}
+
+/// Takes a token for `scope`, once upstream's auth rate limit lets it.
+async fn authenticate_with_upstream(upstream: &mut crate::upstream::Client, scope: &str) -> Result<(), dkregistry::errors::Error> {
+	crate::chaos::upstream_request()?;
+	upstream.auth_limit.acquire().await;
+	upstream.authenticate(scope).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RootQueryString {
+	ns: Option<CompactString>,
+	#[serde(default)]
+	capabilities: bool
+}
+
+/// What `/v2/?capabilities=true` says can be done with a namespace.
+#[derive(Debug, Serialize)]
+struct Capabilities<'a> {
+	namespace: &'a str,
+	referrers: bool,
+	tags: bool,
+	push: bool,
+	ranges: bool
+}
+
+/// The API version check.  This is where clients find out they need to log in, to a tenant or, for
+/// pass-through namespaces, with credentials for upstream; nothing's asked of upstream, so load
+/// balancers' health checks cost it nothing.
+pub async fn root(http_req: HttpRequest, config: web::Data<RequestConfig>, qstr: web::Query<RootQueryString>) -> Result<HttpResponse, Error> {
+	let namespace = qstr.ns.as_deref().unwrap_or_else(|| config.default_ns(Some(&http_req)));
+	let upstream = { config.upstream.lock().await.get(namespace)?.clone() };
+	match config.tenants.as_deref() {
+		// Whichever namespaces a tenant gets, any tenant can log in
+		Some(tenants) => {
+			tenants.authenticate(&http_req)?;
+		},
+		None => {
+			Access::resolve(&http_req, &upstream)?;
+		}
+	}
+	let mut response = HttpResponse::Ok();
+	response.insert_header((http::header::CACHE_CONTROL, "no-store"));
+	match qstr.capabilities {
+		true => Ok(response.json(Capabilities { namespace, referrers: true, tags: true, push: upstream.write_through, ranges: true })),
+		false => Ok(response.content_type("application/json").body("{}"))
+	}
+}
+
+/// Takes a token to pull `image` with, or when pulling anonymously, reuses the one taken for it
+/// last if it's still fresh.  Anonymous pulls that find another taking a token for the same image
+/// wait for that one instead of taking their own.
+async fn authenticate_for_pull(upstream: &mut crate::upstream::Client, image: &str, anonymous: bool) -> Result<(), dkregistry::errors::Error> {
+	let scope = format!("repository:{}:pull", image);
+	if let Some(token) = upstream.anonymous_tokens.get(&scope).filter(|_| anonymous) {
+		crate::chaos::upstream_request()?;
+		upstream.authorization = token;
+		return Ok(());
+	}
+	if (!anonymous || !upstream.anonymous_tokens.is_enabled()) {
+		return authenticate_with_upstream(upstream, &scope).await;
+	}
+	let auth_limit = upstream.auth_limit.clone();
+	let (_turn, waited) = auth_limit.turn(&scope).await;
+	if let Some(token) = upstream.anonymous_tokens.get(&scope).filter(|_| waited) {
+		crate::chaos::upstream_request()?;
+		upstream.authorization = token;
+		return Ok(());
+	}
+	authenticate_with_upstream(upstream, &scope).await?;
+	upstream.anonymous_tokens.insert(&scope, upstream.authorization.clone());
 	Ok(())
 }
 
-pub async fn root(config: web::Data<RequestConfig>, qstr: web::Query<ManifestQueryString>) -> Result<&'static str, Error> {
-	let mut upstream = { config.upstream.lock().await.get(qstr.ns.as_deref().unwrap_or_else(|| config.default_ns.as_ref()))?.client.clone() };
-	upstream.authenticate(&[]).await?;
-	Ok("")
+/// After upstream refuses a pull, drops the anonymous token it may have been refused, so that the
+/// next pull takes a new one.
+fn forget_anonymous_token(upstream: &crate::upstream::Client, image: &str, anonymous: bool) {
+	if (anonymous) {
+		upstream.anonymous_tokens.forget(&format!("repository:{}:pull", image));
+	}
+}
+
+/// Makes a request of upstream with the `ns` parameter or without it, as `setting` says, and where
+/// it says to, again without it after a failure with it.
+async fn with_namespace_parameter<'a, T, F>(setting: NamespaceParameter, namespace: &'a str, request: impl Fn(Option<&'a str>) -> F) -> Result<T, dkregistry::errors::Error>
+where
+	F: Future<Output = Result<T, dkregistry::errors::Error>>
+{
+	static RETRIES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_namespace_parameter_retries", "Number of upstream requests that failed with the ns parameter and were made again without it", &["namespace"]).unwrap());
+	match setting {
+		NamespaceParameter::Auto => (),
+		NamespaceParameter::Always => return request(Some(namespace)).await,
+		NamespaceParameter::Never => return request(None).await
+	};
+	match request(Some(namespace)).await {
+		Err(error) if should_retry_without_namespace(&error) => {
+			info!(namespace, %error, "Upstream request failed with the ns parameter; retrying without it");
+			RETRIES.with_label_values(&[namespace]).inc();
+			request(None).await
+		},
+		result => result
+	}
+}
+
+async fn fetch_manifest(upstream: &mut crate::upstream::Client, namespace: &str, image: &str, reference: &str, anonymous: bool, max_size: usize) -> Result<PulledManifest, dkregistry::errors::Error> {
+	authenticate_for_pull(upstream, image, anonymous).await?;
+	with_namespace_parameter(upstream.namespace_parameter, namespace, |ns| upstream.get_manifest(image, reference, ns, max_size)).await
+}
+
+/// Fails fast for an upstream that's been failing, or that's rate-limited us and hasn't said it's
+/// done yet, or while in maintenance mode.
+fn check_upstream(config: &RequestConfig, upstream: &crate::upstream::Client) -> Result<(), Error> {
+	config.maintenance.check_upstream()?;
+	upstream.circuit.check()?;
+	upstream.throttle.check()?;
+	Ok(())
+}
+
+/// After a 429 from upstream, holds off on asking it again for as long as it says to, waiting that
+/// out here if `wait` and it's short enough for the request to still make its deadline.  Fails if
+/// the request shouldn't try again.
+async fn back_off(upstream: &crate::upstream::Client, path: &str, image: &str, deadline: Instant, wait: bool) -> Result<(), Error> {
+	let backoff = upstream.throttle.rate_limited(upstream.retry_after(path, image).await);
+	if (!wait || !upstream.throttle.can_wait(backoff, deadline.into_std())) {
+		return Err(RateLimited { namespace: upstream.namespace.clone(), retry_after: backoff }.into());
+	}
+	rt::time::sleep(backoff).await;
+	Ok(())
+}
+
+/// After upstream refuses the proxy's own credentials, switches a request's copy of its client to
+/// anonymous auth if it's configured to fall back to it, returning whether it did.  Credentials a
+/// client passed through are never swapped out.
+fn fall_back_to_anonymous(upstream: &mut crate::upstream::Client, access: &Access, kind: ObjectKind, error: &Error) -> bool {
+	static FALLBACKS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_anonymous_fallbacks", "Number of pulls retried anonymously because upstream refused the configured credentials", &["namespace", "kind"]).unwrap());
+
+	if (!upstream.anonymous_fallback || !upstream.has_credentials() || matches!(access, Access::Private(_)) || !error.is_auth_failure()) {
+		return false;
+	}
+	let kind = match kind {
+		ObjectKind::Manifest => "manifest",
+		ObjectKind::Blob => "blob"
+	};
+	upstream.pull_anonymously();
+	// Only once per request
+	upstream.anonymous_fallback = false;
+	warn!(namespace = upstream.namespace.as_str(), kind, %error, "Upstream refused our credentials; retrying anonymously.  They may have expired or been revoked");
+	FALLBACKS.with_label_values(&[upstream.namespace.as_str(), kind]).inc();
+	true
+}
+
+/// Probes upstream for a blob, just to find out whether we're allowed to have it; with token-only
+/// probes, only takes a token.
+async fn verify_blob_access(upstream: &mut crate::upstream::Client, namespace: &str, image: &str, digest: &str) -> Result<(), Error> {
+	upstream.circuit.check()?;
+	let result = match authenticate_with_upstream(upstream, &format!("repository:{}:pull", image)).await {
+		Ok(_) => with_namespace_parameter(upstream.namespace_parameter, namespace, |ns| upstream.probe_blob(image, digest, ns)).await,
+		Err(e) => Err(e)
+	};
+	upstream.circuit.record(&result);
+	match result.map_err(|e| Error::from(upstream.normalize_error(e, false))) {
+		Err(error) if error.is_auth_failure() => Err(Error::Unauthorized(upstream.challenge(image).await)),
+		result => result
+	}
 }
 
 #[derive(Debug, Deserialize)]
@@ -65,11 +933,19 @@ impl ManifestRequest {
 		format!("/{}/manifests/{}", self.image, self.reference)
 	}
 
-	fn storage_path(&self, ns: &str) -> String {
-		match self.image.as_ref().split('/').next() {
-			Some(part) if part == ns => format!("manifests/{}/{}", self.image, self.reference),
-			_ => format!("manifests/{}/{}/{}", ns, self.image, self.reference)
-		}
+	fn storage_path(&self, ns: &str, access: &Access) -> String {
+		format!("{}/{}", manifest_storage_dir(ns, self.image.as_ref(), access), self.reference)
+	}
+}
+
+/// Where a namespace's manifests for an image are stored.  The image is stored under its name
+/// without the namespace, whether or not the client included it, so that `docker.io/library/alpine`
+/// and `library/alpine` with `?ns=docker.io` share one copy.
+fn manifest_storage_dir(ns: &str, image: &str, access: &Access) -> String {
+	let image = image.strip_prefix(ns).and_then(|i| i.strip_prefix('/')).unwrap_or(image);
+	match access.storage_prefix() {
+		Some(prefix) => format!("manifests/{ns}/{prefix}/{image}"),
+		None => format!("manifests/{ns}/{image}")
 	}
 }
 
@@ -80,55 +956,552 @@ pub struct ManifestQueryString {
 
 fn manifest_response(manifest: Manifest) -> HttpResponse {
 	let mut response = HttpResponse::Ok();
-	response.insert_header((http::header::CONTENT_TYPE, manifest.media_type.to_string()));
+	response.insert_header((http::header::CONTENT_TYPE, manifest.content_type().into_owned()));
+	if let Some(max_age) = manifest.max_age {
+		response.insert_header((http::header::CACHE_CONTROL, format!("max-age={max_age}")));
+	}
 	if let Some(digest) = manifest.digest {
+		response.insert_header((http::header::ETAG, format!("\"{digest}\"")));
 		response.insert_header((HeaderName::from_static("docker-content-digest"), digest));
 	}
 	response.body(manifest.manifest)
 }
 
-pub async fn manifest(req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
-	static HIT_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_cache_hits", "Number of manifests read from cache", &["namespace"]).unwrap());
-	static MISS_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_cache_misses", "Number of manifest requests that went to upstream", &["namespace"]).unwrap());
+/// Serves a manifest straight out of storage, without buffering or parsing it.
+fn stored_manifest_response(metadata: ManifestMetadata, body: ReadStream, limit: usize) -> Result<HttpResponse, Error> {
+	if (body.length() > limit as u64) {
+		return Err(Error::ManifestTooLarge { size: body.length(), limit });
+	}
+	let mut response = HttpResponse::Ok();
+	// Passed on as what's left of it
+	if let (Some(max_age), Some(age)) = (metadata.max_age, body.age()) {
+		response.insert_header((http::header::CACHE_CONTROL, format!("max-age={}", max_age.saturating_sub(age.as_secs()))));
+	}
+	response.insert_header((http::header::CONTENT_TYPE, metadata.media_type));
+	if let Some(digest) = metadata.digest {
+		response.insert_header((http::header::ETAG, format!("\"{digest}\"")));
+		response.insert_header((HeaderName::from_static("docker-content-digest"), digest));
+	}
+	Ok(response.body(SizedStream::new(body.length(), body.into_inner())))
+}
 
-	let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns.as_ref());
+/// With `--check-manifest-digest`, reads a manifest cached under its digest into memory to check it
+/// against that digest, so that one corrupted or tampered with in storage is dropped and fetched
+/// again instead of being served for as long as it's cached.  Manifests are small, so this costs
+/// little.  Returns what to serve, or `None` if it didn't match.
+async fn verify_cached_manifest(config: &RequestConfig, namespace: &str, reference: &ImageReference, storage_path: &str, body: ReadStream) -> Result<Option<ReadStream>, Error> {
+	static MISMATCHES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_cache_digest_mismatches", "Number of manifests cached under their digest that didn't match it, and were fetched again", &["namespace"]).unwrap());
 
-	let max_age = config.upstream.lock().await.get(namespace)?.manifest_invalidation_time;
-	let storage_path = req.storage_path(namespace);
-	match config.repo.read(&storage_path, max_age).await {
-		Ok(stream) => {
-			let body = stream.into_inner().try_collect::<web::BytesMut>().await?;
-			let manifest = serde_json::from_slice(body.as_ref())?;
-			HIT_COUNTER.with_label_values(&[namespace]).inc();
-			return Ok(manifest_response(manifest));
-		},
-		Err(error) => warn!(path = req.http_path(), storage_path, %error, "Manifest not found in repository; pulling from upstream")
+	let ImageReference::Sha256(wanted) = reference else {
+		return Ok(Some(body));
+	};
+	// One that's too large is refused when it's served, without being read
+	if (!config.check_manifest_digest || body.length() > config.max_manifest_size as u64) {
+		return Ok(Some(body));
+	}
+	let length = body.length();
+	let contents = body.into_inner().try_collect::<BytesMut>().await?.freeze();
+	if (hex::encode(Sha256::digest(&contents)) == *wanted) {
+		return Ok(Some(ReadStream::new(length, futures::stream::once(async move { Ok(contents) }).boxed())));
+	}
+	error!(storage_path, "Digest mismatch");
+	MISMATCHES.with_label_values(&[namespace]).inc();
+	report::report(report::Kind::DigestMismatch, "Cached manifest doesn't match its digest; re-fetching from upstream", Some(storage_path));
+	config.repo.delete_manifest(storage_path).await?;
+	Ok(None)
+}
+
+/// Fails a manifest cached by tag that's older than upstream said it stays fresh for, or where it
+/// didn't say, than `default`.
+fn check_manifest_age(metadata: ManifestMetadata, body: ReadStream, default: Duration) -> Result<(ManifestMetadata, ReadStream), crate::storage::Error> {
+	match body.age() {
+		Some(age) if age > metadata.max_age(default) => Err(crate::storage::Error::ObjectTooOld(age.into())),
+		_ => Ok((metadata, body))
+	}
+}
+
+fn stale_response(policy: StalePolicy, mut response: HttpResponse) -> HttpResponse {
+	if (policy == StalePolicy::ServeStaleWithWarningHeader) {
+		response.headers_mut().insert(http::header::WARNING, HeaderValue::from_static("110 - \"Response is Stale\""));
+	}
+	response
+}
+
+/// Asks upstream which digest a tag points at, with a `HEAD` or whatever else it's probed with; if
+/// it's the digest of the expired copy we have, restarts that copy's clock and serves it.  `None`
+/// means we couldn't tell, and the manifest should be pulled as usual.
+async fn revalidate_manifest(upstream: &mut crate::upstream::Client, config: &RequestConfig, image: &str, tag: &str, storage_path: &str, deadline: Instant) -> Option<Result<Answer, Error>> {
+	// Manifests we've rewritten are stored under their own digest, which upstream will never agree
+	// with; those just get pulled again
+	let (metadata, _) = config.repo.stat_manifest(storage_path, Duration::MAX).await.ok()?;
+	let cached_digest = metadata.digest?;
+	upstream.circuit.check().ok()?;
+	let probe = async {
+		match upstream.probe {
+			ProbeMethod::Head => {
+				authenticate_with_upstream(upstream, &format!("repository:{}:pull", image)).await?;
+				upstream.head_manifest(image, tag).await
+			},
+			ProbeMethod::RangedGet => upstream.manifest_digest(image, tag).await,
+			ProbeMethod::Token => Ok(None)
+		}
+	};
+	let result = timeout_at(deadline, probe).await.ok()?;
+	upstream.circuit.record(&result);
+	match result {
+		Ok(Some(digest)) if digest == cached_digest => (),
+		Ok(_) => return None,
+		Err(error) => {
+			warn!(image, tag, %error, "Failed to revalidate manifest with upstream");
+			return None;
+		}
+	};
+
+	let (metadata, body) = config.repo.read_manifest(storage_path, Duration::MAX).await.ok()?;
+	let age = body.age();
+	let manifest = body.into_inner().try_collect::<BytesMut>().await.ok()?.freeze();
+	// Cached before we took the manifest's own word for its media type, or otherwise mislabeled;
+	// clients might not be able to use it as it is, so get it again
+	if (!metadata.matches(manifest.as_ref())) {
+		warn!(image, tag, media_type = metadata.media_type.as_str(), "Cached manifest's media type doesn't match its contents; refreshing");
+		return None;
+	}
+	// Writing it back is what restarts the clock
+	match timeout_at(deadline, config.repo.write_manifest(storage_path, manifest.clone(), &metadata)).await {
+		Ok(Ok(())) => (),
+		Ok(Err(error)) => error!(%error, "Failed to write revalidated manifest to storage"),
+		Err(_) => error!(storage_path, "Request deadline exceeded while writing revalidated manifest to storage")
+	};
+	trace::upstream_attempts(1);
+	trace::served_from_cache(CacheDecision::Revalidated, age, manifest.len() as u64);
+	let body = ReadStream::new(manifest.len() as u64, Box::pin(futures::stream::iter(std::iter::once(Ok::<_, std::io::Error>(manifest)))));
+	Some(stored_manifest_response(metadata, body, config.max_manifest_size).map(|response| Answer::new(response, CacheDecision::Revalidated, age)))
+}
+
+/// Notes when an expired manifest's replacement from upstream is of a different media type, such as
+/// a tag that's gone from a single-platform image to an index.
+async fn record_media_type_change(config: &RequestConfig, namespace: &str, storage_path: &str, manifest: &Manifest) {
+	static MEDIA_TYPE_CHANGES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_media_type_changes", "Number of expired manifests replaced from upstream with one of a different media type", &["namespace"]).unwrap());
+	let Ok((old, _)) = config.repo.stat_manifest(storage_path, Duration::MAX).await else {
+		return;
+	};
+	let new = manifest.content_type();
+	if (old.media_type != new) {
+		MEDIA_TYPE_CHANGES.with_label_values(&[namespace]).inc();
+		info!(storage_path, old = old.media_type.as_str(), new = new.as_ref(), "Upstream manifest changed media type");
+	}
+}
+
+pub async fn manifest(http_req: HttpRequest, req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	config.maintenance.admit()?;
+	let pull = load::Pull::start("manifest");
+	let aliased = config.aliases.resolve(req.image.as_ref(), &req.reference).await;
+	let (req, ns) = match &aliased {
+		Some((namespace, aliased)) => (aliased, Some(namespace.as_str())),
+		None => (&*req, qstr.ns.as_deref())
+	};
+	let canonical = canonical_image(&config, ns, req.image.as_ref(), &http_req).await?.map(|(namespace, image)| (namespace, ManifestRequest { image, reference: req.reference.clone() }));
+	let (req, ns) = match &canonical {
+		Some((namespace, canonical)) => (canonical, Some(namespace.as_str())),
+		None => (req, ns)
+	};
+	let response = config.cacheable(serve_manifest(&config, req, ns, Some(&http_req)).await?, matches!(req.reference, ImageReference::Sha256(_)), &http_req);
+	if let (Some(_), ImageReference::Tag(tag)) = (&config.prefetch, &req.reference) {
+		let (namespace, image) = config.route(ns, req.image.as_ref(), Some(&http_req))?;
+		prefetch::pulled(&config, &http_req, namespace, image, tag).await;
+	}
+	Ok(pull.until_sent(response))
+}
+
+/// Serves a manifest from cache, or from upstream by way of the cache.  Without a client request to
+/// take credentials and the method from, this is a `GET` with the proxy's own credentials.
+pub(crate) async fn serve_manifest(config: &RequestConfig, req: &ManifestRequest, ns: Option<&str>, http_req: Option<&HttpRequest>) -> Result<HttpResponse, Error> {
+	serve_manifest_as(config, req, ns, http_req, Access::Shared).await
+}
+
+/// Like [`serve_manifest`], from `access`'s cache rather than the shared one where there's no
+/// client request to tell whose it is.
+pub(crate) async fn serve_manifest_as(config: &RequestConfig, req: &ManifestRequest, ns: Option<&str>, http_req: Option<&HttpRequest>, default_access: Access) -> Result<HttpResponse, Error> {
+	static HELD_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_held_tag_pulls", "Number of pulls of tags held at a digest, served as that digest", &["namespace"]).unwrap());
+
+	let deadline = Instant::now() + config.manifest_deadline;
+	let (namespace, image) = config.route(ns, req.image.as_ref(), http_req)?;
+	// A tag held at a digest is served as that digest, whatever upstream has moved it to since
+	let held = match &req.reference {
+		ImageReference::Tag(tag) => config.pins.held(namespace, image, tag).await.and_then(|digest| ImageReference::from_str(&digest).ok()),
+		ImageReference::Sha256(_) => None
+	};
+	let held = held.map(|reference| {
+		HELD_COUNTER.with_label_values(&[namespace]).inc();
+		ManifestRequest { image: req.image.clone(), reference }
+	});
+	let req = held.as_ref().unwrap_or(req);
+
+	let mut pull = ManifestPull::start(config, req, namespace, image, http_req, default_access, deadline).await?;
+	let answer = match pull.from_cache().await? {
+		Lookup::Hit(answer) => answer,
+		Lookup::Miss { stale } => match pull.revalidate(stale).await {
+			Some(answer) => answer?,
+			None => pull.from_upstream(stale).await?
+		}
+	};
+	Ok(answer.decorate(pull.edge_lifetime))
+}
+
+/// How a pull was answered, and how old what it was answered with is, kept with its response until
+/// the headers that say so go on.
+struct Answer {
+	response: HttpResponse,
+	decision: CacheDecision,
+	age: Option<Duration>
+}
+
+impl Answer {
+	fn new(response: HttpResponse, decision: CacheDecision, age: Option<Duration>) -> Self {
+		Self { response, decision, age }
 	}
 
-	MISS_COUNTER.with_label_values(&[namespace]).inc();
-	let manifest = {
-		let mut upstream = config.upstream.lock().await.get(namespace)?.clone();
-		authenticate_with_upstream(&mut upstream.client, &format!("repository:{}:pull", image)).await?;
+	/// The response, marked with how it was answered, and told how long it stays fresh at an edge
+	/// where `edge_lifetime` says.
+	fn decorate(self, edge_lifetime: Option<Duration>) -> HttpResponse {
+		// Revalidating restarts a manifest's clock
+		let edge_age = match self.decision {
+			CacheDecision::Revalidated => None,
+			_ => self.age
+		};
+		cache_status::mark(edge::fresh_for(self.response, edge_lifetime, edge_age), self.decision, self.age)
+	}
+}
+
+/// What looking in the cache came to.
+enum Lookup {
+	Hit(Answer),
+	/// Nothing to serve from cache; `stale` if that's because what's there has expired
+	Miss { stale: bool }
+}
+
+/// What upstream gave for a manifest.
+enum FetchedManifest {
+	Fetched { manifest: Manifest, annotations_only: bool },
+	/// Upstream's unavailable, and the expired copy in cache is served instead
+	Stale(Answer)
+}
+
+/// A manifest pull, once it's been worked out where it goes and whose it is.
+struct ManifestPull<'a> {
+	config: &'a RequestConfig,
+	req: &'a ManifestRequest,
+	http_req: Option<&'a HttpRequest>,
+	namespace: &'a str,
+	image: &'a str,
+	reference: Cow<'a, str>,
+	upstream: crate::upstream::Client,
+	upstream_image: Cow<'a, str>,
+	access: Access,
+	anonymous: bool,
+	max_age: Duration,
+	hinted: bool,
+	edge_lifetime: Option<Duration>,
+	storage_path: String,
+	degraded: bool,
+	deadline: Instant
+}
+
+impl<'a> ManifestPull<'a> {
+	/// Works out where the pull goes and whose it is, and checks that it may be made.
+	async fn start(config: &'a RequestConfig, req: &'a ManifestRequest, namespace: &'a str, image: &'a str, http_req: Option<&'a HttpRequest>, default_access: Access, deadline: Instant) -> Result<ManifestPull<'a>, Error> {
+		let mut upstream = config.upstream_for(namespace, http_req).await?;
+		let upstream_image = upstream.upstream_image(image);
+		let access = match http_req {
+			Some(http_req) => config.access(http_req, namespace, &upstream)?,
+			None => default_access
+		};
 		let reference = req.reference.to_str();
-		let (manifest, media_type, digest) = match upstream.client.get_raw_manifest_and_metadata(image, reference.as_ref(), Some(namespace)).await {
-			Ok(v) => v,
-			Err(e) if should_retry_without_namespace(&e) => upstream.client.get_raw_manifest_and_metadata(image, reference.as_ref(), None).await?,
-			Err(e) => return Err(e.into())
+		if (http_req.is_some()) {
+			config.plugins.run(&plugin::request_info(Hook::Authorize, ObjectKind::Manifest, http_req, &access, namespace, image, reference.as_ref()))?;
+		}
+		let anonymous = !matches!(access, Access::Private(_)) && !upstream.has_credentials();
+		if let Access::Private(credentials) = &access {
+			upstream.pull_as(credentials);
+		}
+		// What a digest names can't change, so it's never too old to serve
+		let max_age = match &req.reference {
+			ImageReference::Sha256(_) => Duration::MAX,
+			ImageReference::Tag(_) => upstream.manifest_invalidation_time
 		};
-		Manifest::new(manifest, media_type, digest)
-	};
+		// Where upstream's word on how long a tag stays fresh counts, it's in the manifest's metadata,
+		// so storage can't tell by itself
+		let hinted = (upstream.cache_control || upstream.parent) && matches!(req.reference, ImageReference::Tag(_));
+		// An edge asking about a tag is told how long it stays fresh here, which is `max_age` where
+		// upstream didn't say
+		let edge_lifetime = match (&req.reference, edge::is_edge(http_req)) {
+			(ImageReference::Tag(_), true) => Some(max_age),
+			_ => None
+		};
+		let storage_path = req.storage_path(namespace, &access);
+		Ok(Self { config, req, http_req, namespace, image, reference, upstream, upstream_image, access, anonymous, max_age, hinted, edge_lifetime, storage_path, degraded: health::is_degraded(), deadline })
+	}
+
+	/// Looks for the manifest in cache.  Private content is only served from cache while upstream's
+	/// word that these credentials can pull it is fresh; otherwise, go ask upstream again.  With
+	/// storage down, there's no cache.
+	async fn from_cache(&self) -> Result<Lookup, Error> {
+		let config = self.config;
+		if (self.degraded || !config.entitlements.is_fresh(&self.access, self.namespace, self.image, self.upstream.entitlement_recheck_interval)) {
+			return Ok(Lookup::Miss { stale: false });
+		}
+		let invalidation = match self.hinted {
+			true => Duration::MAX,
+			false => self.max_age
+		};
+		let head = self.http_req.is_some_and(|r| r.method() == http::Method::HEAD);
+		let cached = match head {
+			true => config.repo.stat_manifest(&self.storage_path, invalidation).await.map(|(metadata, stat)| (metadata, ReadStream::from(stat))),
+			false => config.repo.read_manifest(&self.storage_path, invalidation).await
+		};
+		let cached = match (cached, self.hinted) {
+			(Ok((metadata, body)), true) => check_manifest_age(metadata, body, self.max_age),
+			(cached, _) => cached
+		};
+		match cached {
+			Ok((metadata, body)) => {
+				let age = body.age();
+				// A HEAD has nothing to check
+				let body = match head {
+					true => Some(body),
+					false => verify_cached_manifest(config, self.namespace, &self.req.reference, &self.storage_path, body).await?
+				};
+				let Some(body) = body else {
+					return Ok(Lookup::Miss { stale: false });
+				};
+				counters::MANIFEST_HITS.with_label_values(&[self.namespace]).inc();
+				trace::served_from_cache(CacheDecision::Hit, age, body.length());
+				image_stats::hit(ObjectKind::Manifest, &self.access, self.namespace, self.image, body.length());
+				stored_manifest_response(metadata, body, config.max_manifest_size).map(|response| Lookup::Hit(Answer::new(response, CacheDecision::Hit, age)))
+			},
+			Err(error) => {
+				warn!(path = self.req.http_path(), storage_path = self.storage_path.as_str(), %error, "Manifest not found in repository; pulling from upstream");
+				Ok(Lookup::Miss { stale: matches!(error, crate::storage::Error::ObjectTooOld(_)) })
+			}
+		}
+	}
+
+	/// Where this namespace revalidates expired tags, asks upstream whether a `stale` copy is still
+	/// what the tag points at, and if so, serves it as fresh.
+	async fn revalidate(&mut self, stale: bool) -> Option<Result<Answer, Error>> {
+		let (true, RevalidationPolicy::Head, ImageReference::Tag(tag), Ok(())) = (stale, self.upstream.revalidation, &self.req.reference, self.config.maintenance.check_upstream()) else {
+			return None;
+		};
+		let answer = revalidate_manifest(&mut self.upstream, self.config, &self.upstream_image, tag, &self.storage_path, self.deadline).await?;
+		counters::MANIFEST_REVALIDATIONS.with_label_values(&[self.namespace]).inc();
+		image_stats::hit(ObjectKind::Manifest, &self.access, self.namespace, self.image, answer.as_ref().map_or(0, |answer| image_stats::response_length(&answer.response)));
+		Some(answer)
+	}
+
+	/// Pulls the manifest from upstream, and caches it.
+	async fn from_upstream(&mut self, stale: bool) -> Result<Answer, Error> {
+		self.config.plugins.run(&plugin::request_info(Hook::BeforeFetch, ObjectKind::Manifest, self.http_req, &self.access, self.namespace, self.image, self.reference.as_ref()))?;
+		counters::MANIFEST_MISSES.with_label_values(&[self.namespace]).inc();
+		image_stats::miss(ObjectKind::Manifest, &self.access, self.namespace, self.image);
+		let (manifest, annotations_only) = match self.fetch(stale).await? {
+			FetchedManifest::Fetched { manifest, annotations_only } => (manifest, annotations_only),
+			FetchedManifest::Stale(answer) => return Ok(answer)
+		};
+		// What the tag points at upstream, for its history, before it's converted or rewritten
+		let fetched = (manifest.manifest.clone(), manifest.digest.clone());
+		let (manifest, rewritten_path) = self.prepare(manifest).await?;
+		if (!self.degraded) {
+			self.store(&manifest, rewritten_path.as_deref(), &fetched.0, fetched.1.as_deref(), annotations_only).await?;
+		}
+		Ok(Answer::new(manifest_response(manifest), CacheDecision::Miss, None))
+	}
+
+	/// Fetches the manifest from upstream, retrying as upstream's errors allow, or where upstream's
+	/// unavailable, falls back on a `stale` copy.
+	async fn fetch(&mut self, stale: bool) -> Result<FetchedManifest, Error> {
+		let config = self.config;
+		let (namespace, image) = (self.namespace, self.image);
+		let mut waited = false;
+		let mut attempts = 0;
+		let mut latency = Duration::ZERO;
+		let result = loop {
+			let result = match check_upstream(config, &self.upstream) {
+				Ok(()) => {
+					attempts += 1;
+					let (span, hop) = trace::upstream(self.http_req, namespace);
+					self.upstream.hop = hop;
+					let started = Instant::now();
+					let result = timeout_at(self.deadline, fetch_manifest(&mut self.upstream, namespace, &self.upstream_image, self.reference.as_ref(), self.anonymous, config.max_manifest_size).instrument(span)).await;
+					latency = started.elapsed();
+					match result {
+						Ok(result) => {
+							self.upstream.circuit.record(&result);
+							result.map_err(|e| Error::from(self.upstream.normalize_error(e, self.anonymous)))
+						},
+						Err(_) => Err(Error::DeadlineExceeded(config.manifest_deadline))
+					}
+				},
+				Err(e) => Err(e)
+			};
+			match result {
+				Err(error) if fall_back_to_anonymous(&mut self.upstream, &self.access, ObjectKind::Manifest, &error) => self.anonymous = true,
+				Err(error) if error.is_upstream_rate_limit() => match back_off(&self.upstream, &format!("{}/manifests/{}", self.upstream_image, self.reference), &self.upstream_image, self.deadline, !waited).await {
+					Ok(()) => waited = true,
+					Err(error) => break Err(error)
+				},
+				result => break result
+			};
+		};
+		trace::upstream_attempts(attempts);
+		match result {
+			Ok(PulledManifest::TooLarge(size)) => Err(Error::ManifestTooLarge { size, limit: config.max_manifest_size }),
+			Ok(PulledManifest::Manifest(manifest, media_type, digest)) => {
+				config.entitlements.record(&self.access, namespace, image, self.upstream.entitlement_recheck_interval);
+				trace::served_from_upstream(manifest.len() as u64);
+				image_stats::fetched(&self.access, namespace, image, manifest.len() as u64);
+				// Manifests are served exactly as upstream sent them, never parsed and written back
+				// out, because clients check what they get against its digest; where upstream didn't
+				// say what that is, it's what these bytes hash to.  Signed schema1 manifests' digests
+				// leave their signatures out, so those can't be worked out this way.
+				let mut manifest = Manifest::new(manifest, media_type, digest);
+				if (manifest.digest.is_none() && !schema1::is_schema1(&manifest)) {
+					manifest.digest = Some(format!("sha256:{}", hex::encode(Sha256::digest(&manifest.manifest))));
+				}
+				if let (true, ImageReference::Tag(tag), false) = (self.hinted, &self.req.reference, matches!(self.access, Access::Private(_))) {
+					let lifetime = timeout_at(self.deadline, self.upstream.freshness(&self.upstream_image, tag)).await.ok().flatten();
+					// Never longer at an edge than it would be without its parent's word
+					let lifetime = match self.upstream.parent {
+						true => lifetime.map(|lifetime| lifetime.min(self.max_age)),
+						false => lifetime
+					};
+					manifest.max_age = lifetime.map(|lifetime| lifetime.as_secs());
+				}
+				timeout_at(self.deadline, cosign::check(config, &mut self.upstream, namespace, &self.upstream_image, &self.req.reference, &manifest, self.anonymous)).await.map_err(|_| Error::DeadlineExceeded(config.manifest_deadline))??;
+				shadow::compare(config, &self.upstream, namespace, image, self.reference.as_ref(), &manifest, latency).await;
+				let mut annotations_only = false;
+				if (stale) {
+					record_media_type_change(config, namespace, &self.storage_path, &manifest).await;
+					annotations_only = reannotated::check(config, namespace, &self.storage_path, manifest.manifest.as_ref()).await;
+				}
+				Ok(FetchedManifest::Fetched { manifest, annotations_only })
+			},
+			Err(error) if stale && self.upstream.stale_policy != StalePolicy::Fail && error.is_upstream_unavailable() => {
+				warn!(path = self.req.http_path(), storage_path = self.storage_path.as_str(), %error, "Upstream unavailable; serving expired manifest from cache");
+				let (metadata, body) = config.repo.read_manifest(&self.storage_path, Duration::MAX).await?;
+				counters::MANIFEST_STALE_HITS.with_label_values(&[namespace]).inc();
+				trace::served_from_cache(CacheDecision::Stale, body.age(), body.length());
+				image_stats::hit(ObjectKind::Manifest, &self.access, namespace, image, body.length());
+				let age = body.age();
+				let response = stale_response(self.upstream.stale_policy, stored_manifest_response(metadata, body, config.max_manifest_size)?);
+				Ok(FetchedManifest::Stale(Answer::new(response, CacheDecision::Stale, age)))
+			},
+			Err(error) if error.is_auth_failure() => {
+				forget_anonymous_token(&self.upstream, &self.upstream_image, self.anonymous);
+				Err(Error::Unauthorized(self.upstream.challenge(&self.upstream_image).await))
+			},
+			Err(error) => Err(error.or_unknown(Error::ManifestUnknown))
+		}
+	}
+
+	/// Converts, rewrites, and notes the foreign layers of a manifest from upstream, as the namespace
+	/// says to; along with where a converted or rewritten manifest's copy under its own digest goes.
+	async fn prepare(&mut self, mut manifest: Manifest) -> Result<(Manifest, Option<String>), Error> {
+		static REWRITTEN_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_rewrites", "Number of manifests from upstream changed by rewrite rules", &["namespace"]).unwrap());
+
+		let config = self.config;
+		let (namespace, req) = (self.namespace, self.req);
+		let fetched_digest = manifest.digest.clone();
+		if (schema1::is_schema1(&manifest)) {
+			manifest = match (self.upstream.schema1, &req.reference) {
+				(Schema1Policy::PassThrough, _) => manifest,
+				// Converting changes the manifest's digest, so only manifests requested by tag can be
+				(Schema1Policy::Convert, ImageReference::Tag(_)) => {
+					let convert = schema1::convert(config, &mut self.upstream, namespace, &self.upstream_image, &self.access, manifest.manifest.as_ref());
+					timeout_at(Instant::now() + config.blob_deadline, convert).await.map_err(|_| Error::DeadlineExceeded(config.blob_deadline))??
+				},
+				(Schema1Policy::Reject | Schema1Policy::Convert, _) => return Err(Error::Schema1Unsupported)
+			};
+		}
+
+		if let (ImageReference::Tag(_), Some((body, digest))) = (&req.reference, rewrite::apply(self.upstream.rewrites(), manifest.manifest.as_ref())) {
+			REWRITTEN_COUNTER.with_label_values(&[namespace]).inc();
+			manifest.manifest = body;
+			manifest.digest = Some(digest);
+		}
 
-	let body = serde_json::to_vec(&manifest).unwrap();
-	let len = body.len().try_into().unwrap_or(i64::MAX);
-	if let Err(error) = config
-		.repo
-		.write(&storage_path, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body.into()))), len)
-		.await
-	{
-		error!(%error, "Failed to write manifest to storage");
+		// Foreign layers are looked up in storage when they're pulled, so without it, they're left alone
+		if (self.upstream.foreign_layers == ForeignLayerPolicy::Cache && !self.degraded) {
+			let layers = foreign::foreign_layers(manifest.manifest.as_ref());
+			if (!layers.is_empty()) {
+				foreign::record(&config.repo, &layers).await;
+				// Rewriting changes the manifest's digest, so a manifest requested by digest has to
+				// stay as it is; its foreign layers can still be pulled through us
+				if let (ImageReference::Tag(_), Some((body, digest))) = (&req.reference, foreign::rewrite(manifest.manifest.as_ref())) {
+					manifest.manifest = body;
+					manifest.digest = Some(digest);
+				}
+			}
+		}
+
+		// Clients resolve a tag and then ask for the digest they were given, which upstream has never
+		// heard of; a copy of a manifest converted or rewritten here is cached under its final digest
+		// to serve them
+		let rewritten_path = match (&req.reference, manifest.digest.as_deref()) {
+			(ImageReference::Tag(_), Some(digest)) if manifest.digest != fetched_digest => Some(format!("{}/{digest}", manifest_storage_dir(namespace, req.image.as_ref(), &self.access))),
+			_ => None
+		};
+		Ok((manifest, rewritten_path))
 	}
 
-	Ok(manifest_response(manifest))
+	/// Caches a manifest from upstream, and what's known about it, unless the after-fill hook refuses
+	/// it; `fetched` and `fetched_digest` are what upstream sent, before it was prepared.
+	async fn store(&self, manifest: &Manifest, rewritten_path: Option<&str>, fetched: &Bytes, fetched_digest: Option<&str>, annotations_only: bool) -> Result<(), Error> {
+		let config = self.config;
+		let (namespace, image, storage_path, access) = (self.namespace, self.image, self.storage_path.as_str(), &self.access);
+		match timeout_at(self.deadline, config.repo.write_manifest(storage_path, manifest.manifest.clone(), &manifest.metadata())).await {
+			Ok(Ok(())) => config.replicate(storage_path, replica::Kind::Manifest),
+			Ok(Err(error)) => {
+				error!(%error, "Failed to write manifest to storage");
+				report::report(report::Kind::StorageWrite, format_args!("Failed to write manifest to storage: {error}"), Some(storage_path));
+			},
+			Err(_) => error!(storage_path, "Request deadline exceeded while writing manifest to storage")
+		}
+		if let Some(path) = rewritten_path {
+			// What a digest names never goes stale
+			let metadata = ManifestMetadata { max_age: None, ..manifest.metadata() };
+			match timeout_at(self.deadline, config.repo.write_manifest(path, manifest.manifest.clone(), &metadata)).await {
+				Ok(Ok(())) => config.replicate(path, replica::Kind::Manifest),
+				Ok(Err(error)) => error!(%error, "Failed to write rewritten manifest to storage under its digest"),
+				Err(_) => error!(path, "Request deadline exceeded while writing rewritten manifest to storage")
+			}
+		}
+
+		let filled = plugin::RequestInfo { size: Some(manifest.manifest.len() as u64), ..plugin::request_info(Hook::AfterFill, ObjectKind::Manifest, self.http_req, access, namespace, image, self.reference.as_ref()) };
+		if let Err(denied) = config.plugins.run(&filled) {
+			for path in std::iter::once(storage_path).chain(rewritten_path) {
+				if let Err(error) = config.repo.delete_manifest(path).await {
+					error!(path, %error, "Failed to evict manifest refused by plugin");
+				}
+			}
+			return Err(denied);
+		}
+		let manifest_dir = manifest_storage_dir(namespace, self.req.image.as_ref(), access);
+		config.inline_blobs.record(manifest.manifest.as_ref(), access);
+		referrers::record(&config.repo, &manifest_dir, manifest.manifest.as_ref(), &manifest.metadata().media_type).await;
+		labels::record(&config.repo, &manifest_dir, namespace, image, self.reference.as_ref(), manifest.manifest.as_ref(), access).await;
+		sbom::record(&config.repo, &manifest_dir, namespace, image, manifest.manifest.as_ref(), access).await;
+		if let ImageReference::Tag(tag) = &self.req.reference {
+			history::record(&config.repo, &manifest_dir, tag, fetched, fetched_digest, manifest.digest.as_deref(), annotations_only, access).await;
+		}
+		if (config.provenance) {
+			let provenance = Provenance::new(&self.upstream, &self.upstream_image, fetched_digest);
+			for path in std::iter::once(storage_path).chain(rewritten_path) {
+				provenance::record(&config.repo, path, &provenance).await;
+			}
+		}
+		timestamp::record(config, manifest_dir, manifest.manifest.as_ref(), access);
+		Ok(())
+	}
 }
 
 #[derive(Debug, Deserialize)]
@@ -142,133 +1515,753 @@ impl BlobRequest {
 		format!("/{}/blobs/{}", self.image, self.digest)
 	}
 
-	fn storage_path(&self) -> String {
-		let (method, hash) = self.digest.split_once(':').unwrap_or(("_", &self.digest));
-		let hash_prefix = hash.get(..2).unwrap_or("_");
-		let rest_of_hash = hash.get(2..).unwrap_or(hash);
-		format!("blobs/{method}/{hash_prefix}/{rest_of_hash}")
+	fn storage_path(&self, access: &Access) -> String {
+		blob_storage_path(&self.digest, access)
+	}
+}
+
+fn blob_storage_path(digest: &str, access: &Access) -> String {
+	let (method, hash) = digest.split_once(':').unwrap_or(("_", digest));
+	let hash_prefix = hash.get(..2).unwrap_or("_");
+	let rest_of_hash = hash.get(2..).unwrap_or(hash);
+	match access.storage_prefix() {
+		Some(prefix) => format!("blobs/{prefix}/{method}/{hash_prefix}/{rest_of_hash}"),
+		None => format!("blobs/{method}/{hash_prefix}/{rest_of_hash}")
+	}
+}
+
+/// Serves a blob from cache, or just the part of it a `Range` header asks for.
+async fn cached_blob_response(config: &RequestConfig, storage_path: &str, max_age: Duration, stream: ReadStream, http_req: Option<&HttpRequest>) -> Result<HttpResponse, Error> {
+	let length = stream.length();
+	let header = http_req.and_then(|r| r.headers().get(http::header::RANGE)).and_then(|v| v.to_str().ok());
+	match range::requested(header, length) {
+		Requested::Whole => Ok(HttpResponse::Ok().insert_header((http::header::ACCEPT_RANGES, "bytes")).body(SizedStream::new(length, stream.into_inner()))),
+		Requested::Part(part) => {
+			drop(stream);
+			let stream = config.repo.read_range(storage_path, max_age, part.clone()).await?;
+			Ok(part_response(&part, length, stream))
+		},
+		Requested::Unsatisfiable => Ok(unsatisfiable_response(length))
+	}
+}
+
+fn part_response(part: &core::ops::Range<u64>, length: u64, stream: ReadStream) -> HttpResponse {
+	HttpResponse::PartialContent()
+		.insert_header((http::header::ACCEPT_RANGES, "bytes"))
+		.insert_header((http::header::CONTENT_RANGE, range::content_range(part, length)))
+		.body(SizedStream::new(stream.length(), stream.into_inner()))
+}
+
+fn unsatisfiable_response(length: u64) -> HttpResponse {
+	HttpResponse::RangeNotSatisfiable().insert_header((http::header::CONTENT_RANGE, format!("bytes */{length}"))).finish()
+}
+
+/// Answers a `HEAD` for a blob from the index of blobs we've recently seen in storage, or failing
+/// that, from its length in storage; anything not in storage is handled as a `GET`, whose body
+/// actix leaves out of the response.
+pub async fn blob_head(http_req: HttpRequest, req: web::Path<BlobRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	config.maintenance.admit()?;
+	if let Some(redirect) = config.shard_redirect(&http_req, &req.digest) {
+		return Ok(redirect);
+	}
+	let (req, ns) = unalias_blob(&config, req.into_inner(), qstr.into_inner().ns).await;
+	let (req, ns) = canonical_blob(&config, req, ns, &http_req).await?;
+	let (namespace, image) = config.route(ns.as_deref(), req.image.as_ref(), Some(&http_req))?;
+	let upstream = config.upstream_for(namespace, Some(&http_req)).await?;
+	let access = config.access(&http_req, namespace, &upstream)?;
+	if (config.entitlements.is_fresh(&access, namespace, image, upstream.entitlement_recheck_interval)) {
+		let storage_path = req.storage_path(&access);
+		if let Some(data) = config.inline_blobs.get(&storage_path) {
+			config.plugins.run(&plugin::request_info(Hook::Authorize, ObjectKind::Blob, Some(&http_req), &access, namespace, image, req.digest.as_ref()))?;
+			let response = with_blob_headers(HttpResponse::Ok().body(SizedStream::new(data.len() as u64, futures::stream::empty::<Result<Bytes, std::io::Error>>())), &req.digest);
+			return Ok(config.cacheable(cache_status::mark(response, CacheDecision::Hit, None), true, &http_req));
+		}
+		let len = match config.known_blobs.get(&storage_path) {
+			Some(len) => Some(len),
+			None if health::is_degraded() => None,
+			None => config.repo.stat(&storage_path, upstream.cached_blob_max_age()).await.ok().map(|stat| stat.length())
+		};
+		if let Some(len) = len {
+			config.plugins.run(&plugin::request_info(Hook::Authorize, ObjectKind::Blob, Some(&http_req), &access, namespace, image, req.digest.as_ref()))?;
+			config.known_blobs.insert(&storage_path, len);
+			let response = with_blob_headers(HttpResponse::Ok().body(SizedStream::new(len, futures::stream::empty::<Result<Bytes, std::io::Error>>())), &req.digest);
+			return Ok(config.cacheable(cache_status::mark(response, CacheDecision::Hit, None), true, &http_req));
+		}
+	}
+	let response = serve_blob(config.clone(), req, ns.as_deref(), Some(&http_req)).await?;
+	Ok(config.cacheable(response, true, &http_req))
+}
+
+pub async fn blob(http_req: HttpRequest, req: web::Path<BlobRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	config.maintenance.admit()?;
+	if let Some(redirect) = config.shard_redirect(&http_req, &req.digest) {
+		return Ok(redirect);
+	}
+	let pull = load::Pull::start("blob");
+	let (req, ns) = unalias_blob(&config, req.into_inner(), qstr.into_inner().ns).await;
+	let (req, ns) = canonical_blob(&config, req, ns, &http_req).await?;
+	let response = serve_blob(config.clone(), req, ns.as_deref(), Some(&http_req)).await?;
+	Ok(pull.until_sent(config.cacheable(response, true, &http_req)))
+}
+
+/// A blob pulled from a repository with aliases comes from the image they stand for.
+async fn unalias_blob(config: &RequestConfig, mut req: BlobRequest, ns: Option<CompactString>) -> (BlobRequest, Option<CompactString>) {
+	match config.aliases.repository(req.image.as_ref()).await {
+		Some((namespace, image)) => {
+			req.image = image;
+			(req, Some(namespace))
+		},
+		None => (req, ns)
 	}
 }
 
-pub async fn blob(req: web::Path<BlobRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
-	static HIT_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("blob_cache_hits", "Number of blobs read from cache", &["namespace"]).unwrap());
-	static MISS_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("blob_cache_misses", "Number of blob requests that went to upstream", &["namespace"]).unwrap());
+/// A blob is pulled from upstream by the canonical name of the image it's pulled from.
+async fn canonical_blob(config: &RequestConfig, mut req: BlobRequest, ns: Option<CompactString>, http_req: &HttpRequest) -> Result<(BlobRequest, Option<CompactString>), Error> {
+	match canonical_image(config, ns.as_deref(), req.image.as_ref(), http_req).await? {
+		Some((namespace, image)) => {
+			req.image = image;
+			Ok((req, Some(namespace)))
+		},
+		None => Ok((req, ns))
+	}
+}
 
-	let Some(wanted_digest_hex) = req.digest.strip_prefix("sha256:") else {
-		return Err(Error::InvalidDigest);
+/// The namespace a client's request for `image` is routed to, and the canonical name of the image
+/// there, if the namespace's image name rules change it.
+async fn canonical_image(config: &RequestConfig, ns: Option<&str>, image: &str, http_req: &HttpRequest) -> Result<Option<(CompactString, ImageName)>, Error> {
+	let (namespace, image) = config.route(ns, image, Some(http_req))?;
+	let canonical = match config.upstream.lock().await.get(namespace)?.canonical_image(image) {
+		Cow::Borrowed(_) => return Ok(None),
+		Cow::Owned(canonical) => canonical
 	};
-	let wanted_digest = {
-		let mut buf = [0u8; 256 / 8];
-		if (hex::decode_to_slice(wanted_digest_hex, &mut buf[..]).is_err()) {
-			return Err(Error::InvalidDigest);
+	match ImageName::from_str(&canonical) {
+		Ok(canonical) => Ok(Some((namespace.into(), canonical))),
+		Err(_) => {
+			warn!(namespace, image, canonical, "Image name rules gave an invalid image name");
+			Err(Error::NameUnknown)
+		}
+	}
+}
+
+/// The headers a blob is served with wherever it came from.  Upstream's aren't passed on, but
+/// registries serve every blob as `application/octet-stream`, whatever its media type, along
+/// with its digest, and those are what clients look at.
+fn with_blob_headers(mut response: HttpResponse, digest: &str) -> HttpResponse {
+	if (response.status().is_success()) {
+		let headers = response.headers_mut();
+		headers.insert(http::header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+		if let (Ok(digest), Ok(etag)) = (HeaderValue::from_str(digest), HeaderValue::from_str(&format!("\"{digest}\""))) {
+			headers.insert(HeaderName::from_static("docker-content-digest"), digest);
+			headers.insert(http::header::ETAG, etag);
 		}
-		buf
+	}
+	response
+}
+
+/// Serves a blob from cache, or streams it from upstream while filling the cache.  Without a
+/// client request to take credentials from, the proxy's own are used.
+pub(crate) async fn serve_blob(config: web::Data<RequestConfig>, req: BlobRequest, ns: Option<&str>, http_req: Option<&HttpRequest>) -> Result<HttpResponse, Error> {
+	serve_blob_as(config, req, ns, http_req, Access::Shared).await
+}
+
+/// Like [`serve_blob`], from `access`'s cache rather than the shared one where there's no client
+/// request to tell whose it is.
+pub(crate) async fn serve_blob_as(config: web::Data<RequestConfig>, req: BlobRequest, ns: Option<&str>, http_req: Option<&HttpRequest>, default_access: Access) -> Result<HttpResponse, Error> {
+	let deadline = Instant::now() + config.blob_deadline;
+	let mut pull = BlobPull::start(&config, &req, ns, http_req, default_access, deadline).await?;
+	let answer = match pull.from_cache().await? {
+		Lookup::Hit(answer) => answer,
+		Lookup::Miss { stale } => pull.from_upstream(stale).await?
 	};
+	Ok(with_blob_headers(answer.decorate(None), &req.digest))
+}
+
+/// What upstream gave for a blob.
+enum FetchedBlob {
+	Fetched { len: u64, body: LocalBoxStream<'static, Result<Bytes, crate::storage::Error>>, foreign_urls: Vec<String> },
+	/// Upstream's unavailable, and the expired copy in cache is served instead
+	Stale(Answer)
+}
+
+/// A blob pull, once it's been worked out where it goes and whose it is.
+struct BlobPull<'a> {
+	config: &'a web::Data<RequestConfig>,
+	req: &'a BlobRequest,
+	http_req: Option<&'a HttpRequest>,
+	namespace: &'a str,
+	image: &'a str,
+	wanted_digest: [u8; 256 / 8],
+	upstream: crate::upstream::Client,
+	upstream_image: Cow<'a, str>,
+	access: Access,
+	anonymous: bool,
+	storage_path: String,
+	max_age: Duration,
+	degraded: bool,
+	deadline: Instant
+}
+
+impl<'a> BlobPull<'a> {
+	/// Works out where the pull goes and whose it is, and checks that it may be made.
+	async fn start(config: &'a web::Data<RequestConfig>, req: &'a BlobRequest, ns: Option<&'a str>, http_req: Option<&'a HttpRequest>, default_access: Access, deadline: Instant) -> Result<BlobPull<'a>, Error> {
+		let Some(wanted_digest_hex) = req.digest.strip_prefix("sha256:") else {
+			return Err(Error::InvalidDigest);
+		};
+		let wanted_digest = {
+			let mut buf = [0u8; 256 / 8];
+			if (hex::decode_to_slice(wanted_digest_hex, &mut buf[..]).is_err()) {
+				return Err(Error::InvalidDigest);
+			}
+			buf
+		};
+
+		let (namespace, image) = config.route(ns, req.image.as_ref(), http_req)?;
+
+		let mut upstream = config.upstream_for(namespace, http_req).await?;
+		let upstream_image = upstream.upstream_image(image);
+		let access = match http_req {
+			Some(http_req) => config.access(http_req, namespace, &upstream)?,
+			None => default_access
+		};
+		if (http_req.is_some()) {
+			config.plugins.run(&plugin::request_info(Hook::Authorize, ObjectKind::Blob, http_req, &access, namespace, image, req.digest.as_ref()))?;
+		}
+		let anonymous = !matches!(access, Access::Private(_)) && !upstream.has_credentials();
+		if let Access::Private(credentials) = &access {
+			upstream.pull_as(credentials);
+		}
+		let storage_path = req.storage_path(&access);
+		let max_age = upstream.cached_blob_max_age();
+		Ok(Self { config, req, http_req, namespace, image, wanted_digest, upstream, upstream_image, access, anonymous, storage_path, max_age, degraded: health::is_degraded(), deadline })
+	}
 
-	let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns.as_ref());
+	/// Counts a pull served from cache.
+	fn hit(&self, age: Option<Duration>, length: u64) {
+		counters::BLOB_HITS.with_label_values(&[self.namespace]).inc();
+		trace::served_from_cache(CacheDecision::Hit, age, length);
+		fan_out::served_from_cache(self.namespace, length);
+		image_stats::hit(ObjectKind::Blob, &self.access, self.namespace, self.image, length);
+	}
 
-	let storage_path = req.storage_path();
-	let max_age = config.upstream.lock().await.get(namespace)?.blob_invalidation_time;
-	match config.repo.read(storage_path.as_ref(), max_age).await {
-		Ok(stream) => match config.check_cache_digest {
+	/// Looks for the blob, or the part of it asked for, in cache.
+	async fn from_cache(&mut self) -> Result<Lookup, Error> {
+		let config = self.config;
+		let (namespace, image, storage_path, max_age) = (self.namespace, self.image, self.storage_path.as_str(), self.max_age);
+		if let Some(data) = config.inline_blobs.get(storage_path).filter(|_| config.entitlements.is_fresh(&self.access, namespace, image, self.upstream.entitlement_recheck_interval)) {
+			counters::BLOB_INLINE_HITS.with_label_values(&[namespace]).inc();
+			trace::served_from_cache(CacheDecision::Hit, None, data.len() as u64);
+			fan_out::served_from_cache(namespace, data.len() as u64);
+			image_stats::hit(ObjectKind::Blob, &self.access, namespace, image, data.len() as u64);
+			return Ok(Lookup::Hit(Answer::new(inline::response(data, self.http_req), CacheDecision::Hit, None)));
+		}
+		// A ranged read of a blob we know we have is served straight from storage, without reading
+		// the whole blob to check its digest first, which for lazy pulls' small reads would be most of
+		// the work
+		if let (Some(header), Some(length)) = (lazy::wanted_part(self.http_req).filter(|_| !self.degraded), config.known_blobs.get(storage_path)) {
+			if (config.entitlements.is_fresh(&self.access, namespace, image, self.upstream.entitlement_recheck_interval)) {
+				match range::requested(Some(header), length) {
+					Requested::Part(part) => match config.repo.read_range(storage_path, max_age, part.clone()).await {
+						Ok(stream) => {
+							let age = stream.age();
+							self.hit(age, stream.length());
+							return Ok(Lookup::Hit(Answer::new(part_response(&part, length, stream), CacheDecision::Hit, age)));
+						},
+						Err(error) => {
+							config.known_blobs.remove(storage_path);
+							warn!(path = storage_path, %error, "Failed to read range of known blob from repository");
+						}
+					},
+					Requested::Unsatisfiable => return Ok(Lookup::Hit(Answer::new(unsatisfiable_response(length), CacheDecision::Hit, None))),
+					Requested::Whole => ()
+				};
+			}
+		}
+		// With storage down, there's no cache to look in
+		if (self.degraded) {
+			return Ok(Lookup::Miss { stale: false });
+		}
+		let cached = match config.entitlements.is_fresh(&self.access, namespace, image, self.upstream.entitlement_recheck_interval) {
+			true => config.repo.read(storage_path, max_age).await,
+			false => match config.repo.stat(storage_path, max_age).await {
+				// We have the blob, but need upstream to confirm that these credentials can still pull
+				// it; if it does, serve from cache as usual.
+				Ok(_) => {
+					config.maintenance.check_upstream()?;
+					verify_blob_access(&mut self.upstream, namespace, &self.upstream_image, self.req.digest.as_ref()).await?;
+					config.entitlements.record(&self.access, namespace, image, self.upstream.entitlement_recheck_interval);
+					config.repo.read(storage_path, max_age).await
+				},
+				Err(error) => Err(error)
+			}
+		};
+		let stream = match cached {
+			Ok(stream) => stream,
+			Err(error) => {
+				config.known_blobs.remove(storage_path);
+				warn!(path = storage_path, %error, "Blob not found in repository; pulling from upstream");
+				return Ok(Lookup::Miss { stale: matches!(error, crate::storage::Error::ObjectTooOld(_)) });
+			}
+		};
+		let stream = match config.check_cache_digest {
 			true => {
 				let hash = stream::hash(stream.into_inner()).await?;
-				if (hash == wanted_digest) {
-					HIT_COUNTER.with_label_values(&[namespace]).inc();
-					let stream = config.repo.read(storage_path.as_ref(), max_age).await?;
-					return Ok(HttpResponse::Ok().body(SizedStream::new(stream.length(), stream.into_inner())));
+				if (hash != self.wanted_digest) {
+					error!(storage_path, "Digest mismatch");
+					config.known_blobs.remove(storage_path);
+					report::report(report::Kind::DigestMismatch, "Cached blob doesn't match its digest; re-fetching from upstream", Some(storage_path));
+					config.repo.delete(storage_path).await?;
+					return Ok(Lookup::Miss { stale: false });
 				}
-				error!(storage_path, "Digest mismatch");
-				config.repo.delete(storage_path.as_ref()).await?;
+				config.repo.read(storage_path, max_age).await?
 			},
-			false => {
-				HIT_COUNTER.with_label_values(&[namespace]).inc();
-				let stream = config.repo.read(storage_path.as_ref(), max_age).await?;
-				return Ok(HttpResponse::Ok().body(SizedStream::new(stream.length(), stream.into_inner())));
+			false => stream
+		};
+		let age = stream.age();
+		self.hit(age, stream.length());
+		config.known_blobs.insert(storage_path, stream.length());
+		cached_blob_response(config, storage_path, max_age, stream, self.http_req).await.map(|response| Lookup::Hit(Answer::new(response, CacheDecision::Hit, age)))
+	}
+
+	/// Pulls the blob from upstream, streaming it to the client as it's cached.
+	async fn from_upstream(&mut self, stale: bool) -> Result<Answer, Error> {
+		let config = self.config;
+		let (namespace, image, storage_path, max_age) = (self.namespace, self.image, self.storage_path.as_str(), self.max_age);
+		// What's already cached is still served to a tenant over its quota, but nothing more is
+		if let (Some(tenant), Some(tenants)) = (self.access.tenant(), config.tenants.as_deref()) {
+			tenants.check_quota(tenant)?;
+		}
+		config.plugins.run(&plugin::request_info(Hook::BeforeFetch, ObjectKind::Blob, self.http_req, &self.access, namespace, image, self.req.digest.as_ref()))?;
+		// Rather than fetch a blob twice, wait for the process this one took over from to finish
+		// filling it, if it's filling it; credentials upstream hasn't vouched for lately go upstream
+		if (!self.degraded && config.entitlements.is_fresh(&self.access, namespace, image, self.upstream.entitlement_recheck_interval) && config.handoff.wait(storage_path, self.deadline).await) {
+			if let Ok(stream) = config.repo.read(storage_path, max_age).await {
+				let age = stream.age();
+				self.hit(age, stream.length());
+				return cached_blob_response(config, storage_path, max_age, stream, self.http_req).await.map(|response| Answer::new(response, CacheDecision::Hit, age));
 			}
-		},
-		Err(error) => warn!(path = storage_path, %error, "Blob not found in repository; pulling from upstream")
-	};
+		}
+		counters::BLOB_MISSES.with_label_values(&[namespace]).inc();
+		image_stats::miss(ObjectKind::Blob, &self.access, namespace, image);
+		let (span, hop) = trace::upstream(self.http_req, namespace);
+		if let Some(response) = lazy::pass_through(config, &self.upstream, self.http_req, &self.access, namespace, image, &self.upstream_image, self.req.digest.as_ref(), self.anonymous, self.deadline, &hop).instrument(span.clone()).await {
+			return Ok(Answer::new(response, CacheDecision::Miss, None));
+		}
+		// Held until the whole blob has been read from upstream
+		let download = timeout_at(self.deadline, self.upstream.downloads.acquire()).await.map_err(|_| Error::DeadlineExceeded(config.blob_deadline))??;
+		match self.fetch(stale, &span, &hop).await? {
+			FetchedBlob::Fetched { len, body, foreign_urls } => Ok(self.fill(len, body, foreign_urls, download)),
+			FetchedBlob::Stale(answer) => Ok(answer)
+		}
+	}
 
-	MISS_COUNTER.with_label_values(&[namespace]).inc();
-	let response = {
-		let mut upstream = config.upstream.lock().await.get(namespace)?.clone();
-		authenticate_with_upstream(&mut upstream.client, &format!("repository:{}:pull", image)).await?;
-		match upstream.client.get_blob_response(image, req.digest.as_ref(), Some(namespace)).await {
-			Ok(v) => v,
-			Err(e) if should_retry_without_namespace(&e) => upstream.client.get_blob_response(image, req.digest.as_ref(), None).await?,
-			Err(e) => return Err(e.into())
+	/// Fetches the blob from upstream, retrying as upstream's errors allow, or from where its
+	/// manifest says it's kept if it's a foreign layer; or where upstream's unavailable, falls back
+	/// on a `stale` copy.
+	async fn fetch(&mut self, stale: bool, span: &Span, hop: &Hop) -> Result<FetchedBlob, Error> {
+		let config = self.config;
+		let (namespace, image, storage_path) = (self.namespace, self.image, self.storage_path.as_str());
+		let req = self.req;
+		let digest: &str = req.digest.as_ref();
+		self.upstream.hop = hop.clone();
+		let mut waited = false;
+		let mut attempts = 0;
+		let result = loop {
+			let result = match check_upstream(config, &self.upstream) {
+				Ok(()) => {
+					attempts += 1;
+					let (upstream, upstream_image, anonymous) = (&mut self.upstream, &self.upstream_image, self.anonymous);
+					let fetch = async {
+						authenticate_for_pull(upstream, upstream_image, anonymous).await?;
+						with_namespace_parameter(upstream.namespace_parameter, namespace, |ns| upstream.get_blob(upstream_image, digest, ns)).await
+					};
+					match timeout_at(self.deadline, fetch.instrument(span.clone())).await {
+						Ok(result) => {
+							self.upstream.circuit.record(&result);
+							result.map_err(|e| Error::from(self.upstream.normalize_error(e, self.anonymous)))
+						},
+						Err(_) => Err(Error::DeadlineExceeded(config.blob_deadline))
+					}
+				},
+				Err(e) => Err(e)
+			};
+			match result {
+				Err(error) if fall_back_to_anonymous(&mut self.upstream, &self.access, ObjectKind::Blob, &error) => self.anonymous = true,
+				Err(error) if error.is_upstream_rate_limit() => match back_off(&self.upstream, &format!("{}/blobs/{digest}", self.upstream_image), &self.upstream_image, self.deadline, !waited).await {
+					Ok(()) => waited = true,
+					Err(error) => break Err(error)
+				},
+				result => break result
+			};
+		};
+		trace::upstream_attempts(attempts);
+		match result {
+			Ok(v) => {
+				config.entitlements.record(&self.access, namespace, image, self.upstream.entitlement_recheck_interval);
+				let size = v.size().ok_or(Error::MissingContentLength)?;
+				// Ranges are asked for with the configured credentials, so not for pass-through pulls
+				let ranged = match (self.upstream.ranged.applies(size) && !matches!(self.access, Access::Private(_))) {
+					true => timeout_at(self.deadline, self.upstream.fetch_ranges(&self.upstream_image, digest, size, self.anonymous, hop).instrument(span.clone())).await.ok().flatten(),
+					false => None
+				};
+				let body = match ranged {
+					Some(body) => body,
+					None => v.stream().boxed_local()
+				};
+				Ok(FetchedBlob::Fetched { len: size, body: crate::chaos::upstream_blob(body), foreign_urls: Vec::new() })
+			},
+			Err(error) if stale && self.upstream.stale_policy != StalePolicy::Fail && error.is_upstream_unavailable() => {
+				warn!(path = storage_path, %error, "Upstream unavailable; serving expired blob from cache");
+				let stream = config.repo.read(storage_path, Duration::MAX).await?;
+				counters::BLOB_STALE_HITS.with_label_values(&[namespace]).inc();
+				let age = stream.age();
+				trace::served_from_cache(CacheDecision::Stale, age, stream.length());
+				fan_out::served_from_cache(namespace, stream.length());
+				image_stats::hit(ObjectKind::Blob, &self.access, namespace, image, stream.length());
+				let response = stale_response(self.upstream.stale_policy, HttpResponse::Ok().body(SizedStream::new(stream.length(), stream.into_inner())));
+				Ok(FetchedBlob::Stale(Answer::new(response, CacheDecision::Stale, age)))
+			},
+			Err(error) if error.is_not_found() && self.upstream.foreign_layers == ForeignLayerPolicy::Cache && !self.degraded => match foreign::lookup(&config.repo, digest).await? {
+				Some(urls) => {
+					let (len, body) = timeout_at(self.deadline, foreign::fetch(&self.upstream.http, &urls, hop).instrument(span.clone())).await.map_err(|_| Error::DeadlineExceeded(config.blob_deadline))??;
+					Ok(FetchedBlob::Fetched { len, body, foreign_urls: urls })
+				},
+				None => Err(Error::BlobUnknown)
+			},
+			Err(error) if error.is_auth_failure() => {
+				forget_anonymous_token(&self.upstream, &self.upstream_image, self.anonymous);
+				Err(Error::Unauthorized(self.upstream.challenge(&self.upstream_image).await))
+			},
+			Err(error) => Err(error.or_unknown(Error::BlobUnknown))
 		}
-	};
+	}
 
-	let len = response.size().ok_or(Error::MissingContentLength)?;
-	let (tx, rx) = async_broadcast::broadcast(16);
-	{
-		let mut stream = DigestCheckedStream::<_, crate::storage::Error, _>::new(response.stream().err_into::<crate::storage::Error>(), wanted_digest);
-		rt::spawn(async move {
-			while let Some(chunk) = stream.next().await {
-				let chunk = match chunk {
-					Ok(v) => Ok(v),
-					Err(error) => {
-						error!(%error, "Error reading from upstream");
-						Err(error)
+	/// Streams a blob from upstream, `len` long, to the client while it's checked against its digest
+	/// and cached; `download` is held until it's all been read.
+	fn fill(&self, len: u64, body: LocalBoxStream<'static, Result<Bytes, crate::storage::Error>>, foreign_urls: Vec<String>, download: Option<DownloadPermit>) -> Answer {
+		static LENGTH_MISMATCH_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_blob_length_mismatches", "Number of blobs from upstream that were shorter or longer than upstream said", &["namespace"]).unwrap());
+
+		let config = self.config;
+		let (namespace, image, deadline) = (self.namespace, self.image, self.deadline);
+		trace::served_from_upstream(len);
+		image_stats::fetched(&self.access, namespace, image, len);
+		let fetch = fan_out::Fetch::start(namespace, &self.storage_path, len);
+		let provenance = config.provenance.then(|| Provenance { foreign_urls, ..Provenance::new(&self.upstream, &self.upstream_image, None) });
+
+		// The blob is streamed to the client as it's cached, so all that the after-fill hook can still
+		// refuse it is the cache
+		let filled = (self.http_req.cloned(), self.access.clone(), CompactString::from(namespace), image.to_owned(), self.req.digest.clone());
+		let mut refetch = Some(mismatch::Refetch::new(config, &self.upstream, namespace, image, self.req.digest.as_ref(), &self.access));
+		let (tx, rx) = async_broadcast::broadcast(16);
+		// Whether the whole blob was read and matched its digest; the storage write can't tell by
+		// itself, as a write that knows its length may finish before the stream's last item is read
+		let (verified_tx, verified_rx) = oneshot::channel();
+		// Only wired up under the abort policy; otherwise, the client going away just leaves the
+		// storage write as the channel's only reader
+		let (abort_tx, abort_rx) = oneshot::channel();
+		let (abort_tx, mut abort_rx) = match config.client_abort_policy {
+			ClientAbortPolicy::Continue => (None, None),
+			ClientAbortPolicy::Abort => (Some(abort_tx), Some(abort_rx))
+		};
+		{
+			// Checked for length inside the digest check, so that a short blob fails as one
+			let body = LengthCheckedStream::<_, crate::storage::Error>::new(body, len);
+			let mut stream = DigestCheckedStream::<_, crate::storage::Error, _>::new(body, self.wanted_digest);
+			let namespace = CompactString::from(namespace);
+			let path = self.req.http_path();
+			rt::spawn(async move {
+				let _download = download;
+				let _fetch = fetch;
+				let _fill = debug::Fill::start();
+				let mut buffered = load::Buffered::default();
+				let verified = loop {
+					// Past the deadline, give up on upstream; the storage write sees the error and cleans up
+					let next = async { timeout_at(deadline, stream.next()).await.unwrap_or(Some(Err(crate::storage::Error::DeadlineExceeded))) };
+					let chunk = match abort_rx.as_mut() {
+						Some(abort) => tokio::select! {
+							chunk = next => chunk,
+							Ok(()) = abort => Some(Err(crate::storage::Error::ClientAborted))
+						},
+						None => next.await
+					};
+					let Some(chunk) = chunk else {
+						break true;
+					};
+					let chunk = match chunk {
+						Ok(v) => Ok(v),
+						Err(crate::storage::Error::ClientAborted) => {
+							info!(path = path.as_str(), "Client disconnected; abandoning blob");
+							Err(crate::storage::Error::ClientAborted)
+						},
+						Err(crate::storage::Error::DataCorrupt(error)) => {
+							report::report(report::Kind::DigestMismatch, &error, Some(path.as_str()));
+							if let Some(refetch) = refetch.take() {
+								refetch.mismatched(&error);
+							}
+							Err(crate::storage::Error::DataCorrupt(error))
+						},
+						Err(crate::storage::Error::LengthMismatch(error)) => {
+							LENGTH_MISMATCH_COUNTER.with_label_values(&[namespace.as_str()]).inc();
+							warn!(path = path.as_str(), %error, "Blob from upstream isn't the length it said; abandoning it");
+							Err(crate::storage::Error::LengthMismatch(error))
+						},
+						Err(error) => {
+							error!(%error, "Error reading from upstream");
+							Err(error)
+						}
+					};
+					let is_err = chunk.is_err();
+					let blocked = tx.is_full().then(load::Blocked::start);
+					let sent = tx.broadcast(chunk).await;
+					drop(blocked);
+					buffered.set(tx.len());
+					if (sent.is_err()) {
+						error!(path = path.as_str(), "Readers for proxied blob request all closed");
+						break false;
+					} else if is_err {
+						break false;
 					}
 				};
-				let is_err = chunk.is_err();
-				if (tx.broadcast(chunk).await.is_err()) {
-					error!(path = req.http_path(), "Readers for proxied blob request all closed");
-					return;
-				} else if is_err {
+				let _ = verified_tx.send(verified);
+			}.instrument(Span::current()));
+		}
+
+		// Passed through as it is, without waiting on storage
+		if (!self.degraded) {
+			let rx2 = rx.clone();
+			let config = config.clone();
+			let storage_path = self.storage_path.clone();
+			let tenant = self.access.tenant().map(CompactString::from);
+			let filling = handoff::Filling::start(&storage_path);
+			rt::spawn(async move {
+				let _filling = filling;
+				let result = timeout_at(deadline, config.repo.write(storage_path.as_ref(), rx2, len.try_into().unwrap_or(i64::MAX))).await.unwrap_or(Err(crate::storage::Error::DeadlineExceeded));
+				// The error reading from upstream has been logged already
+				if (result.is_ok() && !verified_rx.await.unwrap_or(false)) {
+					if let Err(error) = config.repo.delete(storage_path.as_ref()).await {
+						error!(%error, "Failed to delete blob that didn't match its digest from storage");
+					}
 					return;
 				}
-			}
-		});
-	}
-
-	{
-		let rx2 = rx.clone();
-		let config = config.clone();
-		rt::spawn(async move {
-			if let Err(error) = config.repo.write(storage_path.as_ref(), rx2, len.try_into().unwrap_or(i64::MAX)).await {
-				error!(%error, "Failed to write blob to storage");
-				if let Err(error) = config.repo.delete(storage_path.as_ref()).await {
-					error!(%error, "Failed to delete failed blob from storage");
+				match result {
+					Ok(()) => {
+						let (http_req, access, namespace, image, digest) = &filled;
+						let info = plugin::RequestInfo { size: Some(len), ..plugin::request_info(Hook::AfterFill, ObjectKind::Blob, http_req.as_ref(), access, namespace, image, digest) };
+						if (config.plugins.run(&info).is_err()) {
+							if let Err(error) = config.repo.delete(storage_path.as_ref()).await {
+								error!(%error, "Failed to evict blob refused by plugin");
+							}
+							return;
+						}
+						config.known_blobs.insert(&storage_path, len);
+						if let Some(provenance) = &provenance {
+							provenance::record(&config.repo, &storage_path, provenance).await;
+						}
+						config.replicate(&storage_path, replica::Kind::Blob);
+						if let (Some(tenant), Some(tenants)) = (tenant, config.tenants.as_deref()) {
+							tenants.record(&tenant, len);
+						}
+					},
+					Err(error) => {
+						error!(%error, "Failed to write blob to storage");
+						// Errors reading from upstream or the client surface here too, but they're not ours
+						if (!matches!(error, crate::storage::Error::Upstream(_) | crate::storage::Error::DataCorrupt(_) | crate::storage::Error::LengthMismatch(_) | crate::storage::Error::ClientAborted | crate::storage::Error::DeadlineExceeded)) {
+							report::report(report::Kind::StorageWrite, format_args!("Failed to write blob to storage: {error}"), Some(storage_path.as_str()));
+						}
+						match config.repo.delete(storage_path.as_ref()).await {
+							// The filesystem never puts a failed write in place
+							Err(error) if !error.is_not_found() => error!(%error, "Failed to delete failed blob from storage"),
+							_ => ()
+						};
+					}
 				}
-			}
-		});
+			}.instrument(Span::current()));
+		}
+
+		let body = AbortNotifyingStream::new(rx.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)), abort_tx);
+		Answer::new(HttpResponse::Ok().body(SizedStream::new(len, body)), CacheDecision::Miss, None)
 	}
+}
 
-	Ok(HttpResponse::Ok().body(SizedStream::new(len, rx.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)))))
+/// Whether the first component of an image name is a registry host rather than part of the
+/// repository, the way Docker tells them apart:  it has a `.` or a port, or is `localhost`.
+#[inline]
+pub(crate) fn is_registry_host(component: &str) -> bool {
+	component.contains(['.', ':']) || component == "localhost"
 }
 
+/// Splits the namespace off the front of `image`, unless one was given in `ns`.  The first component
+/// is taken as the namespace if it looks like a registry host, or if there are at least two more
+/// components after it, so that namespaces configured under names that aren't hosts still work.
 #[inline]
 pub fn split_image<'a>(ns: Option<&'a str>, image: &'a str, default_ns: &'a str) -> (&'a str, &'a str) {
 	match ns {
 		Some(v) => (v, image),
 		None => match image.split_once('/') {
-			Some((ns, image)) if image.contains('/') => (ns, image),
+			Some((ns, image)) if is_registry_host(ns) || image.contains('/') => (ns, image),
 			Some(_) | None => (default_ns, image)
 		}
 	}
 }
 
-pub async fn delete_manifest(req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
-	let (namespace, _) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns.as_ref());
-	let storage_path = req.storage_path(namespace);
-	config.repo.delete(storage_path.as_ref()).await?;
+#[derive(Debug, Deserialize)]
+pub struct DeleteQueryString {
+	/// Skip the trash
+	#[serde(default)]
+	permanent: bool,
+	/// Purge the tags cached pointing at a digest, and an index's platform manifests, along with it
+	#[serde(default)]
+	cascade: bool,
+	/// Purge the blobs only a digest references along with it too
+	#[serde(default)]
+	blobs: bool,
+	/// Only say what would be purged
+	#[serde(default)]
+	dry_run: bool
+}
+
+/// Purges a manifest from the cache; into the trash, unless that's disabled or the purge is
+/// `permanent`.  With `cascade`, `blobs` or `dry_run`, the manifest has to be named by digest, and
+/// what's purged along with it is listed in the response.
+pub async fn delete_manifest(req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, delete: web::Query<DeleteQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns(None));
+	let manifest_dir = manifest_storage_dir(namespace, req.image.as_ref(), &Access::Shared);
+	let trash = config.trash && !delete.permanent;
+	if (!delete.cascade && !delete.blobs && !delete.dry_run) {
+		purge_manifest(&config, namespace, image, &manifest_dir, &req.reference.to_str(), trash).await?;
+		return Ok(HttpResponse::Ok().finish());
+	}
+	let ImageReference::Sha256(_) = &req.reference else {
+		return Err(Error::CascadeNeedsDigest);
+	};
+	let plan = cascade::plan(&config.repo, &manifest_dir, &req.reference.to_str(), delete.blobs).await?;
+	if (!delete.dry_run) {
+		for reference in &plan.manifests {
+			match purge_manifest(&config, namespace, image, &manifest_dir, reference, trash).await {
+				Ok(()) | Err(Error::ManifestUnknown) => (),
+				Err(error) => return Err(error)
+			};
+		}
+		for digest in &plan.blobs {
+			match purge_blob(&config, image, digest, trash).await {
+				Ok(()) | Err(Error::BlobUnknown) => (),
+				Err(error) => return Err(error)
+			};
+		}
+		info!(namespace, image, digest = req.reference.to_str().as_ref(), manifests = plan.manifests.len(), blobs = plan.blobs.len(), permanent = !trash, "Purged manifest along with what references it");
+	}
+	Ok(HttpResponse::Ok().json(serde_json::json!({
+		"manifests": plan.manifests,
+		"blobs": plan.blobs,
+		"dry_run": delete.dry_run
+	})))
+}
+
+/// Purges one of the manifests in `manifest_dir`, and its copies in front of us.
+async fn purge_manifest(config: &RequestConfig, namespace: &str, image: &str, manifest_dir: &str, reference: &str, trash: bool) -> Result<(), Error> {
+	let storage_path = format!("{manifest_dir}/{reference}");
+	let result = match trash {
+		true => config.repo.trash_manifest(storage_path.as_ref()).await,
+		false => config.repo.delete_manifest(storage_path.as_ref()).await
+	};
+	result.map_err(|e| Error::from(e).or_unknown(Error::ManifestUnknown))?;
+	if (config.provenance && !trash) {
+		provenance::forget(&config.repo, &storage_path).await;
+	}
+	if let Some(cdn) = &config.cdn {
+		cdn.purge_manifest(&config.base_path, &config.default_ns, namespace, image, reference);
+	}
+	Ok(())
+}
+
+/// Purges a blob from the cache, the same way as [`delete_manifest`].
+pub async fn delete_blob(req: web::Path<BlobRequest>, delete: web::Query<DeleteQueryString>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
+	purge_blob(&config, req.image.as_ref(), &req.digest, config.trash && !delete.permanent).await?;
+	Ok("")
+}
+
+async fn purge_blob(config: &RequestConfig, image: &str, digest: &str, trash: bool) -> Result<(), Error> {
+	let storage_path = blob_storage_path(digest, &Access::Shared);
+	config.known_blobs.remove(&storage_path);
+	let result = match trash {
+		true => config.repo.trash(storage_path.as_ref()).await,
+		false => config.repo.delete(storage_path.as_ref()).await
+	};
+	result.map_err(|e| Error::from(e).or_unknown(Error::BlobUnknown))?;
+	if (config.provenance && !trash) {
+		provenance::forget(&config.repo, &storage_path).await;
+	}
+	if let Some(cdn) = &config.cdn {
+		cdn.purge_blob(&config.base_path, image, digest);
+	}
+	Ok(())
+}
+
+pub async fn list_trash(http_req: HttpRequest, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let access = config.admin_access(&http_req)?;
+	let mut objects = config.repo.list_trash().await?;
+	objects.retain(|object| in_cache_of(object, &access));
+	Ok(HttpResponse::Ok().json(objects))
+}
+
+/// Whether the stored `object` is in `access`'s cache, rather than someone else's.
+fn in_cache_of(object: &str, access: &Access) -> bool {
+	match object.strip_prefix("blobs/") {
+		Some(blob) => match access.storage_prefix() {
+			Some(prefix) => blob.starts_with(&format!("{prefix}/")),
+			None => !blob.starts_with("_private/") && !blob.starts_with("_tenant/")
+		},
+		None => list::repository_name(object, access).is_some()
+	}
+}
+
+/// Puts a purged manifest back where it was.  It counts as freshly cached, so a tag that's moved
+/// upstream since will be served as it was until it expires again.
+pub async fn restore_manifest(http_req: HttpRequest, req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
+	let (namespace, _) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns(None));
+	let access = config.admin_access(&http_req)?;
+	let storage_path = req.storage_path(namespace, &access);
+	config.repo.restore_manifest(storage_path.as_ref()).await.map_err(|e| Error::from(e).or_unknown(Error::ManifestUnknown))?;
 	Ok("")
 }
 
-pub async fn delete_blob(req: web::Path<BlobRequest>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
-	let storage_path = req.storage_path();
-	config.repo.delete(storage_path.as_ref()).await?;
+pub async fn restore_blob(http_req: HttpRequest, req: web::Path<BlobRequest>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
+	let access = config.admin_access(&http_req)?;
+	let storage_path = req.storage_path(&access);
+	config.repo.restore(storage_path.as_ref()).await.map_err(|e| Error::from(e).or_unknown(Error::BlobUnknown))?;
 	Ok("")
 }
 
 #[cfg(test)]
 mod tests {
+	use core::str::FromStr;
+
 	use super::*;
 
+	#[test]
+	fn base_path() {
+		assert_eq!(normalize_base_path(""), "");
+		assert_eq!(normalize_base_path("/"), "");
+		assert_eq!(normalize_base_path("registry"), "/registry");
+		assert_eq!(normalize_base_path("/registry/"), "/registry");
+		assert_eq!(normalize_base_path("/mirrors/oci"), "/mirrors/oci");
+	}
+
+	#[test]
+	fn listener_namespaces() {
+		let listener = ListenerNamespace::from_str("0.0.0.0:5002=ghcr.io").unwrap();
+		assert_eq!(listener.namespace, "ghcr.io");
+		assert!(listener.accepts("10.0.0.5:5002".parse().unwrap()));
+		assert!(!listener.accepts("10.0.0.5:5001".parse().unwrap()));
+
+		let listener = ListenerNamespace::from_str("[::1]:5001=docker.io").unwrap();
+		assert!(listener.accepts("[::1]:5001".parse().unwrap()));
+		assert!(!listener.accepts("[::2]:5001".parse().unwrap()));
+
+		assert!(ListenerNamespace::from_str("0.0.0.0:5002").is_err());
+		assert!(ListenerNamespace::from_str("0.0.0.0:5002=").is_err());
+		assert!(ListenerNamespace::from_str("localhost=ghcr.io").is_err());
+	}
+
 	#[test]
 	fn split_image_with_ns() {
 		let (ns, image) = split_image(Some("docker.io"), "envoyproxy/envoy", "");
@@ -337,4 +2330,51 @@ mod tests {
 		assert_eq!(ns, "docker.io");
 		assert_eq!(image, "grafana/mimirtool");
 	}
+
+	#[test]
+	fn manifest_storage_dirs() {
+		let tenant = Access::Tenant("team-a".into());
+		assert_eq!(manifest_storage_dir("docker.io", "library/busybox", &Access::Shared), "manifests/docker.io/library/busybox");
+		assert_eq!(manifest_storage_dir("docker.io", "docker.io/library/busybox", &Access::Shared), "manifests/docker.io/library/busybox");
+		assert_eq!(manifest_storage_dir("docker.io", "library/busybox", &tenant), "manifests/docker.io/_tenant/team-a/library/busybox");
+		assert_eq!(manifest_storage_dir("docker.io", "docker.io/library/busybox", &tenant), "manifests/docker.io/_tenant/team-a/library/busybox");
+		assert_eq!(manifest_storage_dir("docker.io", "docker.iox/busybox", &Access::Shared), "manifests/docker.io/docker.iox/busybox");
+	}
+
+	#[test]
+	fn split_image_with_registry_host() {
+		let (ns, image) = split_image(None, "registry.local:5000/app/image", "docker.io");
+		assert_eq!(ns, "registry.local:5000");
+		assert_eq!(image, "app/image");
+
+		let (ns, image) = split_image(None, "[::1]:5000/foo/bar", "docker.io");
+		assert_eq!(ns, "[::1]:5000");
+		assert_eq!(image, "foo/bar");
+
+		let (ns, image) = split_image(None, "localhost:5000/busybox", "docker.io");
+		assert_eq!(ns, "localhost:5000");
+		assert_eq!(image, "busybox");
+
+		let (ns, image) = split_image(None, "localhost/busybox", "docker.io");
+		assert_eq!(ns, "localhost");
+		assert_eq!(image, "busybox");
+
+		let (ns, image) = split_image(None, "quay.io/busybox", "docker.io");
+		assert_eq!(ns, "quay.io");
+		assert_eq!(image, "busybox");
+
+		let (ns, image) = split_image(None, "192.168.1.10:5000/busybox", "docker.io");
+		assert_eq!(ns, "192.168.1.10:5000");
+		assert_eq!(image, "busybox");
+	}
+
+	#[test]
+	fn image_names_with_registry_host() {
+		for name in ["registry.local:5000/app/image", "[::1]:5000/foo/bar", "[fe80::1]/foo", "localhost:5000/busybox", "Registry.Example.com/app", "library/busybox", "busybox"] {
+			assert!(ImageName::from_str(name).is_ok(), "{name}");
+		}
+		for name in ["[::1/foo", "registry:port/app", "-registry/app", "registry.local:5000/App", "registry.local:5000", "a//b"] {
+			assert!(ImageName::from_str(name).is_err(), "{name}");
+		}
+	}
 }
@@ -0,0 +1,54 @@
+//! The admin token:  with `--admin-token`, everything under `/_admin` needs it in `X-Admin-Token`,
+//! but webhooks, which carry a token of their own.  Without one, the admin API is open to whoever
+//! can reach it, as it always was, but signed URLs can't be minted, since what they serve is fetched
+//! with the proxy's own credentials.  It goes in a header of its own so that `Authorization` is
+//! still free to say whose cache the caller is asking about.
+
+use actix_web::dev::ServiceRequest;
+use actix_web::web;
+use actix_web::HttpRequest;
+
+use super::error::Error;
+use super::webhook::tokens_match;
+use super::RequestConfig;
+
+/// Carries the admin token
+pub const HEADER: &str = "x-admin-token";
+
+/// Checks that `http_req` carries the admin token; with none configured, nobody does.
+pub(super) fn check(expected: Option<&str>, http_req: &HttpRequest) -> Result<(), Error> {
+	let token = http_req.headers().get(HEADER).map(|v| v.as_bytes());
+	match (expected, token) {
+		(Some(expected), Some(token)) if tokens_match(token, expected.as_bytes()) => Ok(()),
+		_ => Err(Error::Unauthorized(None))
+	}
+}
+
+/// Turns away requests under `/_admin` without the admin token, where there is one.
+pub(super) fn guard(req: &ServiceRequest) -> Result<(), Error> {
+	let Some(expected) = req.app_data::<web::Data<RequestConfig>>().and_then(|config| config.admin_token.as_deref()) else {
+		return Ok(());
+	};
+	if (req.path().split_once("/_admin/").is_some_and(|(_, rest)| rest.starts_with("webhook/"))) {
+		return Ok(());
+	}
+	check(Some(expected), req.request())
+}
+
+#[cfg(test)]
+mod tests {
+	use actix_web::test::TestRequest;
+
+	use super::*;
+
+	#[test]
+	fn needs_the_token() {
+		let plain = TestRequest::default().to_http_request();
+		assert!(check(Some("secret"), &plain).is_err());
+		let wrong = TestRequest::default().insert_header((HEADER, "guess")).to_http_request();
+		assert!(check(Some("secret"), &wrong).is_err());
+		let right = TestRequest::default().insert_header((HEADER, "secret")).to_http_request();
+		assert!(check(None, &right).is_err());
+		assert!(check(Some("secret"), &right).is_ok());
+	}
+}
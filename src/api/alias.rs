@@ -0,0 +1,340 @@
+//! Aliases:  tags that exist only here, each standing for a tag or digest of an upstream image, so
+//! that which image `corp/base:approved` is can be changed in one place and every pull of it follows.
+//! Aliases are declared in a YAML file read at startup, or added and removed at runtime through the
+//! admin API; runtime aliases are kept in storage, like runtime pins.
+//!
+//! An alias is looked up by the repository its tag is pulled from, as the client names it, before
+//! the repository is routed to a namespace.  Pulling it serves what it stands for, as if it were
+//! that image's own tag; what's pulled from there by digest, manifests and blobs, comes from the
+//! image it stands for as well, so all of a repository's aliases have to stand for the same image.
+//! Its other tags are routed as usual.
+
+use core::fmt;
+use core::str::FromStr;
+use core::time::Duration;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use actix_web::web;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use bytes::BytesMut;
+use compact_str::CompactString;
+use futures::stream::TryStreamExt;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use super::error::Error;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::image::ImageReference;
+use crate::storage::Repository;
+
+static PULLS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_alias_pulls", "Number of pulls of aliases, served as what each stands for", &["alias"]).unwrap());
+
+/// Where runtime aliases are kept.
+const ALIASES_OBJECT: &str = "aliases.json";
+
+/// An alias, like `corp/base:approved`:  a repository, as clients pull it, and a tag.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Alias {
+	repository: String,
+	tag: String
+}
+
+impl FromStr for Alias {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || Error::InvalidAlias(format!("Invalid alias '{s}'; expected repository:tag"));
+		let (repository, tag) = s.rsplit_once(':').filter(|(_, tag)| !tag.contains('/')).ok_or_else(invalid)?;
+		if (ImageName::from_str(repository).is_err() || !matches!(ImageReference::from_str(tag), Ok(ImageReference::Tag(_)))) {
+			return Err(invalid());
+		}
+		Ok(Self { repository: repository.to_owned(), tag: tag.to_owned() })
+	}
+}
+
+impl TryFrom<String> for Alias {
+	type Error = Error;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		s.parse()
+	}
+}
+
+impl fmt::Display for Alias {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}:{}", self.repository, self.tag)
+	}
+}
+
+impl From<Alias> for String {
+	fn from(alias: Alias) -> Self {
+		alias.to_string()
+	}
+}
+
+/// What an alias stands for, like `docker.io/library/debian:12.5-slim@sha256:...`:  a tag of an
+/// upstream image, a digest, or both, in which case the digest is what's served and the tag is
+/// there for people to read.  The first path component is the namespace.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Target {
+	namespace: CompactString,
+	image: String,
+	tag: Option<String>,
+	digest: Option<String>
+}
+
+impl FromStr for Target {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || Error::InvalidAlias(format!("Invalid alias target '{s}'; expected namespace/image:tag, namespace/image@digest, or both"));
+		let (rest, digest) = match s.rsplit_once('@') {
+			Some((rest, digest)) => (rest, Some(digest)),
+			None => (s, None)
+		};
+		let (name, tag) = match rest.rsplit_once(':') {
+			// A colon before the last slash is a port, not a tag
+			Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+			_ => (rest, None)
+		};
+		let (namespace, image) = name.split_once('/').ok_or_else(invalid)?;
+		let tag_valid = tag.map_or(true, |tag| matches!(ImageReference::from_str(tag), Ok(ImageReference::Tag(_))));
+		let digest_valid = digest.map_or(true, |digest| matches!(ImageReference::from_str(digest), Ok(ImageReference::Sha256(_))));
+		if (namespace.is_empty() || ImageName::from_str(image).is_err() || !tag_valid || !digest_valid || (tag.is_none() && digest.is_none())) {
+			return Err(invalid());
+		}
+		Ok(Self { namespace: namespace.into(), image: image.to_owned(), tag: tag.map(str::to_owned), digest: digest.map(str::to_owned) })
+	}
+}
+
+impl TryFrom<String> for Target {
+	type Error = Error;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		s.parse()
+	}
+}
+
+impl fmt::Display for Target {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}", self.namespace, self.image)?;
+		if let Some(tag) = &self.tag {
+			write!(f, ":{tag}")?;
+		}
+		if let Some(digest) = &self.digest {
+			write!(f, "@{digest}")?;
+		}
+		Ok(())
+	}
+}
+
+impl From<Target> for String {
+	fn from(target: Target) -> Self {
+		target.to_string()
+	}
+}
+
+impl Target {
+	pub fn namespace(&self) -> &str {
+		self.namespace.as_str()
+	}
+
+	fn repository(&self) -> String {
+		format!("{}/{}", self.namespace, self.image)
+	}
+
+	/// The request to serve in place of a pull of the alias.
+	fn request(&self) -> ManifestRequest {
+		let reference = self.digest.as_deref().or(self.tag.as_deref()).unwrap();
+		ManifestRequest { image: ImageName::from_str(&self.image).unwrap(), reference: ImageReference::from_str(reference).unwrap() }
+	}
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+	#[error("Failed to read aliases file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Invalid aliases file: {0}")]
+	Yaml(#[from] serde_yaml::Error),
+	#[error("Failed to read aliases from storage: {0}")]
+	Storage(#[from] crate::storage::Error),
+	#[error("Invalid aliases in storage: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("In aliases file: {0}")]
+	Conflict(Error)
+}
+
+/// The current set of aliases.
+#[derive(Default)]
+pub struct Aliases {
+	/// From the aliases file; can't be changed at runtime
+	declared: BTreeMap<Alias, Target>,
+	/// Added through the admin API
+	added: Mutex<BTreeMap<Alias, Target>>
+}
+
+/// Checks that `target` is the same image as the other aliases in `alias`'s repository stand for.
+fn check_conflict<'a>(mut aliases: impl Iterator<Item = (&'a Alias, &'a Target)>, alias: &Alias, target: &Target) -> Result<(), Error> {
+	match aliases.find(|(other, other_target)| other.repository == alias.repository && *other != alias && other_target.repository() != target.repository()) {
+		Some((_, other)) => Err(Error::AliasConflict(other.repository())),
+		None => Ok(())
+	}
+}
+
+async fn read_all(repo: &Repository, object: &str) -> Result<BytesMut, crate::storage::Error> {
+	let stream = repo.read(object, Duration::MAX).await?;
+	Ok(stream.into_inner().try_collect::<BytesMut>().await?)
+}
+
+impl Aliases {
+	/// Reads the aliases file, if there is one, along with the runtime aliases in storage.
+	pub async fn load(repo: &Repository, file: Option<&Path>) -> Result<Self, LoadError> {
+		let declared: BTreeMap<Alias, Target> = match file {
+			Some(path) => serde_yaml::from_slice(&tokio::fs::read(path).await?)?,
+			None => BTreeMap::new()
+		};
+		for (alias, target) in declared.iter() {
+			check_conflict(declared.iter(), alias, target).map_err(LoadError::Conflict)?;
+		}
+		let added = match read_all(repo, ALIASES_OBJECT).await {
+			Ok(body) => serde_json::from_slice(body.as_ref())?,
+			Err(e) if e.is_not_found() => BTreeMap::new(),
+			Err(e) => return Err(e.into())
+		};
+		Ok(Self { declared, added: Mutex::new(added) })
+	}
+
+	/// Every alias, with what it stands for; the aliases file wins over runtime aliases.
+	pub async fn all(&self) -> BTreeMap<Alias, Target> {
+		let mut all = self.added.lock().await.clone();
+		all.extend(self.declared.iter().map(|(alias, target)| (alias.clone(), target.clone())));
+		all
+	}
+
+	/// What to serve in place of a pull of `reference` from `repository`, and from which namespace,
+	/// if `repository` has aliases:  what the alias stands for, for a tag that's one, and the same
+	/// digest from the image its aliases stand for, for a digest.
+	pub(super) async fn resolve(&self, repository: &str, reference: &ImageReference) -> Option<(CompactString, ManifestRequest)> {
+		let added = self.added.lock().await;
+		let mut aliases = self.declared.iter().chain(added.iter().filter(|(alias, _)| !self.declared.contains_key(alias)));
+		match reference {
+			ImageReference::Tag(tag) => {
+				let (alias, target) = aliases.find(|(alias, _)| alias.repository == repository && alias.tag == tag.as_str())?;
+				PULLS.with_label_values(&[&alias.to_string()]).inc();
+				Some((target.namespace.clone(), target.request()))
+			},
+			ImageReference::Sha256(digest) => {
+				let (_, target) = aliases.find(|(alias, _)| alias.repository == repository)?;
+				Some((target.namespace.clone(), ManifestRequest { image: ImageName::from_str(&target.image).unwrap(), reference: ImageReference::Sha256(digest.clone()) }))
+			}
+		}
+	}
+
+	/// The namespace and image to fetch blobs pulled from `repository` from, if it has aliases.
+	pub(super) async fn repository(&self, repository: &str) -> Option<(CompactString, ImageName)> {
+		let added = self.added.lock().await;
+		let (_, target) = self.declared.iter().chain(added.iter()).find(|(alias, _)| alias.repository == repository)?;
+		Some((target.namespace.clone(), ImageName::from_str(&target.image).unwrap()))
+	}
+
+	async fn add(&self, repo: &Repository, alias: Alias, target: Target) -> Result<(), Error> {
+		if (self.declared.contains_key(&alias)) {
+			return Err(Error::AliasedByConfig);
+		}
+		let mut added = self.added.lock().await;
+		check_conflict(self.declared.iter().chain(added.iter()), &alias, &target)?;
+		if (added.get(&alias) == Some(&target)) {
+			return Ok(());
+		}
+		added.insert(alias, target);
+		save(repo, &added).await
+	}
+
+	async fn remove(&self, repo: &Repository, alias: &Alias) -> Result<(), Error> {
+		let mut added = self.added.lock().await;
+		if (added.remove(alias).is_some()) {
+			return save(repo, &added).await;
+		}
+		match self.declared.contains_key(alias) {
+			true => Err(Error::AliasedByConfig),
+			false => Err(Error::ManifestUnknown)
+		}
+	}
+}
+
+async fn save(repo: &Repository, aliases: &BTreeMap<Alias, Target>) -> Result<(), Error> {
+	let body = Bytes::from(serde_json::to_vec(aliases)?);
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	repo.write(ALIASES_OBJECT, futures::stream::iter(std::iter::once(Result::<_, std::io::Error>::Ok(body))), len).await?;
+	Ok(())
+}
+
+/// The alias a request to the admin API is for.
+fn from_request(req: &ManifestRequest) -> Result<Alias, Error> {
+	format!("{}:{}", req.image, req.reference).parse()
+}
+
+pub async fn list(config: web::Data<RequestConfig>) -> HttpResponse {
+	HttpResponse::Ok().json(config.aliases.all().await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AliasQueryString {
+	/// What the alias stands for
+	target: String
+}
+
+pub async fn add(req: web::Path<ManifestRequest>, qstr: web::Query<AliasQueryString>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
+	config.aliases.add(&config.repo, from_request(&req)?, qstr.target.parse()?).await?;
+	Ok("")
+}
+
+pub async fn remove(req: web::Path<ManifestRequest>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
+	config.aliases.remove(&config.repo, &from_request(&req)?).await?;
+	Ok("")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_aliases() {
+		let alias: Alias = "corp/base:approved".parse().unwrap();
+		assert_eq!((alias.repository.as_str(), alias.tag.as_str()), ("corp/base", "approved"));
+		assert_eq!(alias.to_string(), "corp/base:approved");
+		assert!("corp/base".parse::<Alias>().is_err());
+		assert!("localhost:5000/base".parse::<Alias>().is_err());
+
+		let digest = "sha256:226cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883";
+		let target: Target = format!("docker.io/library/debian:12.5-slim@{digest}").parse().unwrap();
+		assert_eq!((target.namespace.as_str(), target.image.as_str(), target.tag.as_deref(), target.digest.as_deref()), ("docker.io", "library/debian", Some("12.5-slim"), Some(digest)));
+		assert_eq!(target.to_string(), format!("docker.io/library/debian:12.5-slim@{digest}"));
+		assert_eq!(target.request().reference.to_string(), digest);
+		let target: Target = "localhost:5000/app:stable".parse().unwrap();
+		assert_eq!((target.namespace.as_str(), target.tag.as_deref(), target.digest), ("localhost:5000", Some("stable"), None));
+		assert!("localhost:5000/app".parse::<Target>().is_err());
+		assert!("docker.io/library/debian@sha256:nope".parse::<Target>().is_err());
+	}
+
+	#[test]
+	fn conflicts() {
+		let aliases: BTreeMap<Alias, Target> = [("corp/base:approved", "docker.io/library/debian:12"), ("corp/base:next", "docker.io/library/debian:13")].into_iter().map(|(a, t)| (a.parse().unwrap(), t.parse().unwrap())).collect();
+		let alias = "corp/base:old".parse().unwrap();
+		assert!(check_conflict(aliases.iter(), &alias, &"docker.io/library/debian:11".parse().unwrap()).is_ok());
+		assert!(matches!(check_conflict(aliases.iter(), &alias, &"docker.io/library/ubuntu:22.04".parse().unwrap()), Err(Error::AliasConflict(_))));
+		// Aliases in other repositories can stand for any image
+		let alias = "corp/other:approved".parse().unwrap();
+		assert!(check_conflict(aliases.iter(), &alias, &"docker.io/library/ubuntu:22.04".parse().unwrap()).is_ok());
+	}
+}
@@ -0,0 +1,197 @@
+use core::time::Duration;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use compact_str::CompactString;
+use once_cell::sync::OnceCell;
+use tracing::warn;
+
+use crate::api::error::Error;
+use crate::api::signed::hmac;
+use crate::api::tenant::Tenant;
+use crate::storage::Repository;
+use crate::upstream::AuthMode;
+use crate::upstream::Client;
+use crate::util::SecretString;
+
+/// Where earlier versions kept a partition key they made up, next to what it keys.
+const PARTITION_KEY_OBJECT: &str = "partition-key";
+
+/// What pass-through credentials are hashed with to name their partitions; see [`set_partition_key`].
+static PARTITION_KEY: OnceCell<Vec<u8>> = OnceCell::new();
+
+/// Sets the key pass-through credentials' partitions are named with, from `--partition-key` or
+/// `--partition-key-file`, so that storage doesn't give away an unsalted hash of anybody's password
+/// to whoever can list it.  It isn't kept in storage, where the same people could read it.
+/// Namespaces in pass-through auth mode need one; the first one set stays.
+pub fn set_partition_key(key: Option<Vec<u8>>) {
+	if let Some(key) = key {
+		let _ = PARTITION_KEY.set(key);
+	}
+}
+
+pub fn has_partition_key() -> bool {
+	PARTITION_KEY.get().is_some()
+}
+
+/// Warns about a partition key that an earlier version made up and kept in storage, which anyone
+/// who can read storage can read too.
+pub async fn check_stored_partition_key(repo: &Repository) -> Result<(), crate::storage::Error> {
+	match repo.read(PARTITION_KEY_OBJECT, Duration::MAX).await {
+		Ok(_) => {
+			warn!(object = PARTITION_KEY_OBJECT, "A partition key is still kept in storage; pass it as --partition-key to keep what's cached for pass-through credentials, then delete it");
+			Ok(())
+		},
+		Err(e) if e.is_not_found() => Ok(()),
+		Err(e) => Err(e)
+	}
+}
+
+/// Credentials presented by a client, to be used against upstream in pass-through auth mode.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+	pub(crate) username: CompactString,
+	pub(crate) password: SecretString
+}
+
+impl Credentials {
+	/// Parses HTTP basic credentials out of the request's `Authorization` header.
+	pub fn from_request(req: &HttpRequest) -> Option<Self> {
+		let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+		let (scheme, encoded) = value.split_once(' ')?;
+		if (!scheme.eq_ignore_ascii_case("basic")) {
+			return None;
+		}
+		let decoded = BASE64.decode(encoded.trim()).ok()?;
+		let decoded = core::str::from_utf8(&decoded).ok()?;
+		let (username, password) = decoded.split_once(':')?;
+		Some(Self { username: username.into(), password: password.into() })
+	}
+
+	/// A stable, non-reversible identifier for these credentials, used to keep cache entries
+	/// fetched with them apart from everybody else's:  an HMAC of them under the partition key, so
+	/// that it can't be checked against guessed passwords without the key.
+	pub fn partition(&self) -> String {
+		// Validation refuses pass-through auth mode without a key, and nothing else has credentials
+		let key = PARTITION_KEY.get().expect("pass-through credentials are only accepted with a partition key set");
+		let mac = hmac(key, format!("{}:{}", self.username, self.password.expose()).as_bytes());
+		hex::encode(&mac[..16])
+	}
+}
+
+/// Whose content a request is for:  either the shared cache, fetched with the proxy's own
+/// credentials (if any), content only available with the client's credentials, or a tenant's own
+/// cache, fetched with the proxy's credentials but kept apart from everyone else's.
+#[derive(Clone, Debug)]
+pub enum Access {
+	Shared,
+	Private(Credentials),
+	Tenant(CompactString)
+}
+
+impl Access {
+	pub fn resolve(req: &HttpRequest, upstream: &Client) -> Result<Self, Error> {
+		match upstream.auth_mode {
+			AuthMode::Proxy => Ok(Self::Shared),
+			AuthMode::Passthrough => match Credentials::from_request(req) {
+				Some(credentials) => Ok(Self::Private(credentials)),
+				None => Err(Error::Unauthorized(upstream.basic_challenge()))
+			}
+		}
+	}
+
+	/// Key under which a client's entitlement to an image is remembered; `None` for shared
+	/// content, which everybody is entitled to.
+	fn entitlement_key(&self, namespace: &str, image: &str) -> Option<String> {
+		match self {
+			Self::Shared | Self::Tenant(_) => None,
+			Self::Private(credentials) => Some(format!("{}/{namespace}/{image}", credentials.partition()))
+		}
+	}
+
+	/// Prefix under which objects fetched with this access are stored, so that private content is
+	/// never served to clients presenting different credentials.  Leading underscores can't
+	/// appear in image names, so this can't collide with a real repository.
+	pub fn storage_prefix(&self) -> Option<String> {
+		match self {
+			Self::Shared => None,
+			Self::Private(credentials) => Some(format!("_private/{}", credentials.partition())),
+			Self::Tenant(name) => Some(Tenant::storage_prefix(name))
+		}
+	}
+
+	/// The tenant this access is for, if any.
+	pub fn tenant(&self) -> Option<&str> {
+		match self {
+			Self::Tenant(name) => Some(name.as_str()),
+			Self::Shared | Self::Private(_) => None
+		}
+	}
+}
+
+/// Remembers when each set of pass-through credentials was last confirmed by upstream to have
+/// access to an image.  Cached private content is only served while that confirmation is fresh; after
+/// that, upstream has to be asked again, so that revoking someone's access upstream also revokes it
+/// here.
+pub struct Entitlements {
+	verified: Mutex<HashMap<String, Instant>>
+}
+
+impl Entitlements {
+	const PRUNE_THRESHOLD: usize = 16384;
+
+	pub fn new() -> Self {
+		Self { verified: Mutex::new(HashMap::new()) }
+	}
+
+	pub fn is_fresh(&self, access: &Access, namespace: &str, image: &str, max_age: Duration) -> bool {
+		let Some(key) = access.entitlement_key(namespace, image) else {
+			return true;
+		};
+		match self.verified.lock().unwrap().get(&key) {
+			Some(at) => at.elapsed() < max_age,
+			None => false
+		}
+	}
+
+	pub fn record(&self, access: &Access, namespace: &str, image: &str, max_age: Duration) {
+		let Some(key) = access.entitlement_key(namespace, image) else {
+			return;
+		};
+		let mut verified = self.verified.lock().unwrap();
+		if (verified.len() >= Self::PRUNE_THRESHOLD) {
+			verified.retain(|_, at| at.elapsed() < max_age);
+		}
+		verified.insert(key, Instant::now());
+	}
+
+	/// Every confirmation, with how long ago upstream gave it.
+	pub fn snapshot(&self) -> Vec<(String, Duration)> {
+		self.verified.lock().unwrap().iter().map(|(key, at)| (key.clone(), at.elapsed())).collect()
+	}
+
+	/// Takes back confirmations from a [`snapshot`](Self::snapshot), as if given as long ago as they
+	/// were then; whether they're still fresh is up to [`is_fresh`](Self::is_fresh), as ever.
+	pub fn restore(&self, entries: impl IntoIterator<Item = (String, Duration)>) {
+		let now = Instant::now();
+		let mut verified = self.verified.lock().unwrap();
+		for (key, age) in entries {
+			if let Some(at) = now.checked_sub(age) {
+				if (verified.len() < Self::PRUNE_THRESHOLD) {
+					verified.insert(key, at);
+				}
+			}
+		}
+	}
+}
+
+impl Default for Entitlements {
+	fn default() -> Self {
+		Self::new()
+	}
+}
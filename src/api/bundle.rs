@@ -0,0 +1,109 @@
+//! Bundles:  `/v2/<name>/bundle/<reference>`, in builds with the `bundle` feature, answers with an
+//! image's manifest and its config blob together, so that tooling inspecting images saves the
+//! second round trip.  Both are embedded in OCI descriptors, base64-encoded in `data`, so that the
+//! manifest's bytes, and so its digest, come back exactly as they'd be pulled.  This is an
+//! extension, not part of the distribution spec; clients asking for an index just get the index.
+//!
+//! Both are served just as their own pulls would be, from cache or by way of it, with the same
+//! authentication, aliases, and plugins.
+
+use actix_web::body;
+use actix_web::http::header;
+use actix_web::http::StatusCode;
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use base64::Engine;
+use bytes::Bytes;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use super::error::Error;
+use super::serve_blob;
+use super::serve_manifest;
+use super::BlobRequest;
+use super::ManifestQueryString;
+use super::ManifestRequest;
+use super::RequestConfig;
+
+/// What's needed of an image manifest to find its config
+#[derive(Deserialize)]
+struct Manifest {
+	config: Option<Config>
+}
+
+#[derive(Deserialize)]
+struct Config {
+	#[serde(rename = "mediaType")]
+	media_type: String,
+	digest: String,
+	size: u64
+}
+
+#[derive(Debug, Serialize)]
+struct Descriptor {
+	#[serde(rename = "mediaType")]
+	media_type: String,
+	digest: String,
+	size: u64,
+	/// Left out for configs too large to embed
+	#[serde(skip_serializing_if = "Option::is_none")]
+	data: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+struct Bundle {
+	manifest: Descriptor,
+	/// `None` for indexes and anything else without a config
+	config: Option<Descriptor>
+}
+
+async fn read_body(response: HttpResponse) -> Result<Bytes, Error> {
+	body::to_bytes(response.into_body()).await.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()).into())
+}
+
+fn encode(data: &[u8]) -> String {
+	base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+pub async fn bundle(http_req: HttpRequest, req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	config.maintenance.admit()?;
+	let aliased = config.aliases.resolve(req.image.as_ref(), &req.reference).await;
+	let (req, ns) = match &aliased {
+		Some((namespace, aliased)) => (aliased, Some(namespace.as_str())),
+		None => (&*req, qstr.ns.as_deref())
+	};
+
+	let response = serve_manifest(&config, req, ns, Some(&http_req)).await?;
+	let value = |name: &str| response.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_owned);
+	let media_type = value(header::CONTENT_TYPE.as_str()).unwrap_or_default();
+	let digest = value("docker-content-digest");
+	let manifest = read_body(response).await?;
+	let digest = digest.unwrap_or_else(|| format!("sha256:{}", hex::encode(Sha256::digest(&manifest))));
+
+	let descriptor = serde_json::from_slice::<Manifest>(&manifest).ok().and_then(|m| m.config);
+	let config_blob = match descriptor {
+		Some(descriptor) => {
+			// Configs are about the size of manifests, so the same limit keeps this in bounds
+			let data = match descriptor.size <= config.max_manifest_size as u64 {
+				true => {
+					let response = serve_blob(config.clone(), BlobRequest { image: req.image.clone(), digest: descriptor.digest.clone() }, ns, Some(&http_req)).await?;
+					match response.status() {
+						StatusCode::OK => Some(encode(&read_body(response).await?)),
+						_ => None
+					}
+				},
+				false => None
+			};
+			Some(Descriptor { media_type: descriptor.media_type, digest: descriptor.digest, size: descriptor.size, data })
+		},
+		None => None
+	};
+
+	Ok(HttpResponse::Ok().json(Bundle {
+		manifest: Descriptor { media_type, digest, size: manifest.len() as u64, data: Some(encode(&manifest)) },
+		config: config_blob
+	}))
+}
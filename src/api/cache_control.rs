@@ -0,0 +1,88 @@
+//! `Cache-Control` for HTTP caches in front of the registry, such as a CDN or nginx:  what's pulled
+//! by digest can never change, so it's marked `immutable` and cached for as long as such caches
+//! like, while manifests pulled by tag are marked `no-cache`, so that a cache in front asks again
+//! each time rather than serving a tag that's since moved.  Anything pulled with credentials is
+//! `private`, so that shared caches keep it to the client that pulled it.
+
+use core::time::Duration;
+
+use actix_web::http::header;
+use actix_web::http::header::HeaderValue;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use clap::ValueEnum;
+
+/// Which `Cache-Control` headers successful pulls are served with.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum Policy {
+	/// `immutable` for what's pulled by digest, `no-cache` (or `--tag-max-age`) for tags
+	#[default]
+	Standard,
+	/// None of our own; only what upstream said of a tag, where it's configured to count
+	Off
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheControl {
+	pub policy: Policy,
+	/// How long caches in front keep what's pulled by digest
+	pub immutable_max_age: Duration,
+	/// How long caches in front keep manifests pulled by tag, where upstream didn't say; zero has
+	/// them ask every time
+	pub tag_max_age: Duration
+}
+
+impl Default for CacheControl {
+	fn default() -> Self {
+		Self { policy: Policy::Standard, immutable_max_age: Duration::from_secs(365 * 24 * 60 * 60), tag_max_age: Duration::ZERO }
+	}
+}
+
+impl CacheControl {
+	/// Sets `Cache-Control` on a successful response for something pulled by digest, if
+	/// `immutable`, or by tag.  A tag's freshness as upstream gave it is kept, but scoped.
+	pub fn apply(&self, response: &mut HttpResponse, immutable: bool, private: bool) {
+		if (self.policy == Policy::Off || !matches!(response.status(), StatusCode::OK | StatusCode::PARTIAL_CONTENT)) {
+			return;
+		}
+		let scope = match private {
+			true => "private",
+			false => "public"
+		};
+		let headers = response.headers_mut();
+		let value = match (immutable, headers.get(header::CACHE_CONTROL).and_then(|v| v.to_str().ok())) {
+			(true, _) => format!("{scope}, max-age={}, immutable", self.immutable_max_age.as_secs()),
+			(false, Some(hinted)) => format!("{scope}, {hinted}"),
+			(false, None) if self.tag_max_age.is_zero() => format!("{scope}, no-cache"),
+			(false, None) => format!("{scope}, max-age={}, must-revalidate", self.tag_max_age.as_secs())
+		};
+		if let Ok(value) = HeaderValue::from_str(&value) {
+			headers.insert(header::CACHE_CONTROL, value);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn cache_control(policy: &CacheControl, mut response: HttpResponse, immutable: bool, private: bool) -> Option<String> {
+		policy.apply(&mut response, immutable, private);
+		response.headers().get(header::CACHE_CONTROL).map(|v| v.to_str().unwrap().to_owned())
+	}
+
+	#[test]
+	fn headers() {
+		let policy = CacheControl::default();
+		assert_eq!(cache_control(&policy, HttpResponse::Ok().finish(), true, false).as_deref(), Some("public, max-age=31536000, immutable"));
+		assert_eq!(cache_control(&policy, HttpResponse::Ok().finish(), true, true).as_deref(), Some("private, max-age=31536000, immutable"));
+		assert_eq!(cache_control(&policy, HttpResponse::Ok().finish(), false, false).as_deref(), Some("public, no-cache"));
+		assert_eq!(cache_control(&policy, HttpResponse::Ok().insert_header((header::CACHE_CONTROL, "max-age=60")).finish(), false, false).as_deref(), Some("public, max-age=60"));
+		assert_eq!(cache_control(&policy, HttpResponse::NotFound().finish(), true, false), None);
+
+		let policy = CacheControl { tag_max_age: Duration::from_secs(30), ..CacheControl::default() };
+		assert_eq!(cache_control(&policy, HttpResponse::Ok().finish(), false, true).as_deref(), Some("private, max-age=30, must-revalidate"));
+		let policy = CacheControl { policy: Policy::Off, ..CacheControl::default() };
+		assert_eq!(cache_control(&policy, HttpResponse::Ok().finish(), true, false), None);
+	}
+}
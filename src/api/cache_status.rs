@@ -0,0 +1,70 @@
+//! `X-Cache` and `X-Cache-Age` on pulls, with `--cache-status-headers`, so that whoever's looking
+//! at a slow pull can see whether it came from cache without the server's logs:  `X-Cache` is
+//! `HIT`, `MISS`, `STALE`, or `REVALIDATED`, as in the request's `cache` log field, and
+//! `X-Cache-Age` how many seconds ago what was served from cache was stored, where that's known.
+//! Where a pull is answered, its response is marked with how; the headers go on as it's sent.
+
+use core::time::Duration;
+
+use actix_web::http::header::HeaderName;
+use actix_web::http::header::HeaderValue;
+use actix_web::HttpResponse;
+
+use super::trace::CacheDecision;
+
+const X_CACHE: &str = "x-cache";
+const X_CACHE_AGE: &str = "x-cache-age";
+
+/// How a pull was answered, kept with its response until the headers go on.
+#[derive(Clone, Copy, Debug)]
+struct CacheStatus {
+	decision: CacheDecision,
+	age: Option<Duration>
+}
+
+/// Marks `response` as answered as `decision` says, with what was served `age` old.
+pub(super) fn mark(mut response: HttpResponse, decision: CacheDecision, age: Option<Duration>) -> HttpResponse {
+	response.extensions_mut().insert(CacheStatus { decision, age });
+	response
+}
+
+/// Adds the headers to a marked response.
+pub(super) fn apply(response: &mut HttpResponse) {
+	let Some(status) = response.extensions_mut().remove::<CacheStatus>() else {
+		return;
+	};
+	let headers = response.headers_mut();
+	headers.insert(HeaderName::from_static(X_CACHE), HeaderValue::from_static(header_value(status.decision)));
+	if let Some(age) = status.age {
+		headers.insert(HeaderName::from_static(X_CACHE_AGE), HeaderValue::from(age.as_secs()));
+	}
+}
+
+fn header_value(decision: CacheDecision) -> &'static str {
+	match decision {
+		CacheDecision::Hit => "HIT",
+		CacheDecision::Revalidated => "REVALIDATED",
+		CacheDecision::Stale => "STALE",
+		CacheDecision::Miss => "MISS"
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn headers(mut response: HttpResponse) -> (Option<String>, Option<String>) {
+		apply(&mut response);
+		let header = |name| response.headers().get(name).map(|v: &HeaderValue| v.to_str().unwrap().to_owned());
+		(header(X_CACHE), header(X_CACHE_AGE))
+	}
+
+	#[test]
+	fn marked_responses() {
+		let hit = mark(HttpResponse::Ok().finish(), CacheDecision::Hit, Some(Duration::from_millis(90_500)));
+		assert_eq!(headers(hit), (Some("HIT".to_owned()), Some("90".to_owned())));
+		let miss = mark(HttpResponse::Ok().finish(), CacheDecision::Miss, None);
+		assert_eq!(headers(miss), (Some("MISS".to_owned()), None));
+		assert_eq!(headers(HttpResponse::Ok().finish()), (None, None));
+	}
+}
@@ -0,0 +1,131 @@
+//! Cascading deletes:  `DELETE /_admin/<name>/manifests/<digest>?cascade=true` purges a manifest
+//! along with everything in its repository that's only there because of it.  Tags are cached as
+//! copies of the manifest they point at rather than as pointers to it, so the tags going with it are
+//! those cached with its digest.  An index's platform manifests go too, unless another index cached
+//! for the repository lists them.  With `blobs=true`, so do the configs and layers nothing else
+//! cached references, which takes reading every manifest in storage to be sure of.
+
+use core::time::Duration;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use super::blob_storage_path;
+use super::error::Error;
+use super::pins::References;
+use super::Access;
+use crate::storage::is_sidecar;
+use crate::storage::ManifestMetadata;
+use crate::storage::Repository;
+
+/// What a cascading delete purges.
+#[derive(Debug, Default, Serialize)]
+pub struct Plan {
+	/// The repository's manifests, by reference
+	pub manifests: Vec<String>,
+	/// Blobs, by digest
+	pub blobs: Vec<String>
+}
+
+/// A stored manifest and its metadata, or `None` if it isn't there.
+async fn read(repo: &Repository, path: &str) -> Result<Option<(ManifestMetadata, Bytes)>, Error> {
+	let (metadata, body) = match repo.read_manifest(path, Duration::MAX).await {
+		Ok(v) => v,
+		Err(e) if e.is_not_found() => return Ok(None),
+		Err(e) => return Err(e.into())
+	};
+	Ok(Some((metadata, body.into_inner().try_collect::<BytesMut>().await?.freeze())))
+}
+
+/// The digest of a manifest cached under `reference`:  the reference itself for a digest, and for a
+/// tag, that of the manifest it was pointing at.
+fn cached_digest(reference: &str, metadata: ManifestMetadata, body: &[u8]) -> String {
+	match (reference.starts_with("sha256:"), metadata.digest) {
+		(true, _) => reference.to_owned(),
+		(false, Some(digest)) => digest,
+		(false, None) => format!("sha256:{}", hex::encode(Sha256::digest(body)))
+	}
+}
+
+/// Works out what purging `digest` from the repository whose manifests are stored in
+/// `manifest_dir` takes with it, purging nothing.
+pub(super) async fn plan(repo: &Repository, manifest_dir: &str, digest: &str, blobs: bool) -> Result<Plan, Error> {
+	let prefix = format!("{manifest_dir}/");
+	let mut cached = Vec::new();
+	for object in repo.list(&prefix).await? {
+		// Images whose names go on from this one's are stored under it too
+		let Some(reference) = object.strip_prefix(&prefix).filter(|r| !r.contains('/') && !is_sidecar(r)) else {
+			continue;
+		};
+		if let Some((metadata, body)) = read(repo, &object).await? {
+			cached.push((reference.to_owned(), cached_digest(reference, metadata, &body), body));
+		}
+	}
+	// Cached under its digest, or only as a tag
+	let Some((_, _, root)) = cached.iter().find(|(_, cached_digest, _)| cached_digest == digest) else {
+		return Err(Error::ManifestUnknown);
+	};
+
+	let platforms = serde_json::from_slice::<References>(root).map(|r| r.manifests.into_iter().map(|m| m.digest).collect()).unwrap_or_else(|_| HashSet::new());
+	let mut purged = platforms.clone();
+	for (_, cached_digest, body) in &cached {
+		if (cached_digest == digest || platforms.contains(cached_digest)) {
+			continue;
+		}
+		if let Ok(references) = serde_json::from_slice::<References>(body) {
+			for manifest in references.manifests {
+				purged.remove(&manifest.digest);
+			}
+		}
+	}
+	purged.insert(digest.to_owned());
+	let purged = cached.into_iter().filter(|(_, cached_digest, _)| purged.contains(cached_digest)).collect::<Vec<_>>();
+	let mut plan = Plan { manifests: purged.iter().map(|(reference, ..)| reference.clone()).collect(), blobs: Vec::new() };
+	if (!blobs) {
+		return Ok(plan);
+	}
+
+	let mut candidates = purged.iter().filter_map(|(_, _, body)| serde_json::from_slice::<References>(body).ok()).flat_map(|r| r.config.into_iter().chain(r.layers)).map(|d| d.digest).collect::<BTreeSet<_>>();
+	let purged = plan.manifests.iter().map(|reference| format!("{prefix}{reference}")).collect::<HashSet<_>>();
+	for object in repo.list_manifests().await? {
+		if (candidates.is_empty()) {
+			break;
+		}
+		if (purged.contains(&object)) {
+			continue;
+		}
+		let Some((_, body)) = read(repo, &object).await? else {
+			continue;
+		};
+		if let Ok(references) = serde_json::from_slice::<References>(&body) {
+			for blob in references.config.into_iter().chain(references.layers) {
+				candidates.remove(&blob.digest);
+			}
+		}
+	}
+	for digest in candidates {
+		if (repo.stat(&blob_storage_path(&digest, &Access::Shared), Duration::MAX).await.is_ok()) {
+			plan.blobs.push(digest);
+		}
+	}
+	Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn cached_digests() {
+		let metadata = |digest: Option<&str>| ManifestMetadata::new("application/vnd.oci.image.manifest.v1+json".to_owned(), digest.map(str::to_owned));
+		assert_eq!(cached_digest("sha256:aa", metadata(None), b"{}"), "sha256:aa");
+		assert_eq!(cached_digest("latest", metadata(Some("sha256:bb")), b"{}"), "sha256:bb");
+		assert_eq!(cached_digest("latest", metadata(None), b"{}"), "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a");
+	}
+}
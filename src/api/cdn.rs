@@ -0,0 +1,176 @@
+//! CDN origin mode:  with origin secrets configured, registry requests (and signed URLs) are only
+//! served if they carry one of them in a header the CDN adds on its way to the origin, so that the
+//! CDN can't be bypassed by anyone who finds the origin's address.  A list rather than one secret,
+//! so that it can be rotated without turning anyone away:  add the new one, switch the CDN over,
+//! then drop the old one.
+//!
+//! With a purge URL, whatever's purged here through the admin API, or invalidated by an upstream
+//! webhook, is also POSTed there, one object at a time and with the paths it could have been pulled
+//! under, for whatever speaks to the CDN's own purge API to drop its copies too.  Failed purges are
+//! logged and counted, not retried.
+
+use core::time::Duration;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header;
+use actix_web::http::header::HeaderMap;
+use actix_web::http::header::HeaderName;
+use actix_web::rt;
+use actix_web::web;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounter;
+use prometheus::IntCounterVec;
+use serde::Serialize;
+use tracing::warn;
+
+use super::error::Error;
+use super::webhook::tokens_match;
+use super::RequestConfig;
+
+static REFUSED: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("cdn_origin_refused", "Number of requests refused for not carrying an origin secret").unwrap());
+static PURGES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("cdn_purges", "Number of objects sent to the CDN purge hook, by result", &["result"]).unwrap());
+
+/// The header origin secrets are presented in, unless configured otherwise
+pub const DEFAULT_HEADER: &str = "x-origin-secret";
+
+/// How long the purge hook gets to answer
+const PURGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Kind {
+	Manifest,
+	Blob
+}
+
+/// What's sent to the purge hook for each purged object.
+#[derive(Debug, Serialize)]
+struct PurgeRequest<'a> {
+	kind: Kind,
+	/// `None` for blobs, which are shared between namespaces
+	namespace: Option<&'a str>,
+	image: &'a str,
+	reference: &'a str,
+	/// Each path, under the base path, that the object could be served from
+	paths: Vec<String>
+}
+
+struct Purge {
+	url: reqwest::Url,
+	token: Option<String>,
+	http: reqwest::Client
+}
+
+pub struct Cdn {
+	header: HeaderName,
+	secrets: Vec<String>,
+	purge: Option<Purge>
+}
+
+impl Cdn {
+	/// Only admits requests with one of `secrets` in `header`; with none, every request is.
+	pub fn new(header: HeaderName, secrets: Vec<String>) -> Self {
+		Self { header, secrets, purge: None }
+	}
+
+	/// POSTs each purged object to `url`, with `token` as a bearer token if there is one.
+	pub fn with_purge(mut self, url: reqwest::Url, token: Option<String>) -> Self {
+		self.purge = Some(Purge { url, token, http: reqwest::Client::new() });
+		self
+	}
+
+	fn admit(&self, headers: &HeaderMap) -> Result<(), Error> {
+		if (self.secrets.is_empty()) {
+			return Ok(());
+		}
+		let presented = headers.get(&self.header).map(|v| v.as_bytes());
+		match presented.is_some_and(|presented| self.secrets.iter().any(|secret| tokens_match(presented, secret.as_bytes()))) {
+			true => Ok(()),
+			false => {
+				REFUSED.inc();
+				Err(Error::NotFromCdn)
+			}
+		}
+	}
+
+	/// Tells the purge hook, if there is one, in the background, that a manifest's gone.
+	pub(super) fn purge_manifest(&self, base_path: &str, default_ns: &str, namespace: &str, image: &str, reference: &str) {
+		let mut paths = vec![format!("{base_path}/v2/{namespace}/{image}/manifests/{reference}"), format!("{base_path}/v2/{image}/manifests/{reference}?ns={namespace}")];
+		if (namespace == default_ns) {
+			paths.push(format!("{base_path}/v2/{image}/manifests/{reference}"));
+		}
+		self.send(PurgeRequest { kind: Kind::Manifest, namespace: Some(namespace), image, reference, paths });
+	}
+
+	/// Tells the purge hook that a blob's gone.  Blobs can be pulled through any image, and only the
+	/// one it was purged through is known, so hooks that can should purge by digest instead.
+	pub(super) fn purge_blob(&self, base_path: &str, image: &str, digest: &str) {
+		let paths = vec![format!("{base_path}/v2/{image}/blobs/{digest}")];
+		self.send(PurgeRequest { kind: Kind::Blob, namespace: None, image, reference: digest, paths });
+	}
+
+	fn send(&self, request: PurgeRequest<'_>) {
+		let Some(purge) = &self.purge else {
+			return;
+		};
+		let body = match serde_json::to_vec(&request) {
+			Ok(v) => v,
+			Err(error) => {
+				warn!(%error, "Failed to serialize CDN purge");
+				return;
+			}
+		};
+		let mut builder = purge.http.post(purge.url.clone()).timeout(PURGE_TIMEOUT).header(header::CONTENT_TYPE.as_str(), "application/json").body(body);
+		if let Some(token) = &purge.token {
+			builder = builder.bearer_auth(token);
+		}
+		let image = request.image.to_owned();
+		let reference = request.reference.to_owned();
+		rt::spawn(async move {
+			match builder.send().await.and_then(reqwest::Response::error_for_status) {
+				Ok(_) => PURGES.with_label_values(&["ok"]).inc(),
+				Err(error) => {
+					warn!(image, reference, %error, "Failed to purge object from CDN");
+					PURGES.with_label_values(&["error"]).inc();
+				}
+			};
+		});
+	}
+}
+
+/// Turns away requests without an origin secret, where they're required.
+pub(super) fn check(req: &ServiceRequest) -> Result<(), Error> {
+	match req.app_data::<web::Data<RequestConfig>>().and_then(|config| config.cdn.as_ref()) {
+		Some(cdn) => cdn.admit(req.headers()),
+		None => Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use actix_web::http::header::HeaderValue;
+
+	use super::*;
+
+	fn headers(secret: Option<&'static str>) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		if let Some(secret) = secret {
+			headers.insert(HeaderName::from_static(DEFAULT_HEADER), HeaderValue::from_static(secret));
+		}
+		headers
+	}
+
+	#[test]
+	fn origin_secrets() {
+		let cdn = Cdn::new(HeaderName::from_static(DEFAULT_HEADER), vec!["old-secret".into(), "new-secret".into()]);
+		assert!(cdn.admit(&headers(Some("new-secret"))).is_ok());
+		assert!(cdn.admit(&headers(Some("old-secret"))).is_ok());
+		assert!(matches!(cdn.admit(&headers(Some("guessed"))), Err(Error::NotFromCdn)));
+		assert!(matches!(cdn.admit(&headers(None)), Err(Error::NotFromCdn)));
+
+		let cdn = Cdn::new(HeaderName::from_static(DEFAULT_HEADER), Vec::new());
+		assert!(cdn.admit(&headers(None)).is_ok());
+	}
+}
@@ -0,0 +1,272 @@
+//! Checkpoints of in-memory state worth keeping across a restart:  which blobs are known to be in
+//! storage, and which credentials upstream recently confirmed have access to which images.  Without
+//! them, every instance coming back from a routine deploy asks storage and upstream about all of
+//! that again at once.
+//!
+//! Which scopes anonymous tokens were recently taken for is kept too, though not the tokens, which
+//! would have expired by the time they were read back anyway:  after a restore, tokens for the most
+//! recent of them are taken again in the background, so the first pulls after a deploy don't each
+//! wait on upstream's token endpoint, and find connections to upstream already open and through
+//! their TLS handshakes.  With `--checkpoint-tls-key`, the TLS sessions of the proxy's own
+//! requests of upstreams are kept as well, encrypted (see
+//! [`tls_sessions`](crate::upstream::tls_sessions)), so that even the first connection after a
+//! restart can resume one.
+//!
+//! Unlike the rest, hit and miss counters are each instance's own, so they're kept under the
+//! instance's name, alongside other instances' (see [`counters`](super::counters)), for as long as
+//! [`COUNTER_RETENTION`]; an instance without a name keeps none.
+
+use core::time::Duration;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use bytes::Bytes;
+use bytes::BytesMut;
+use compact_str::CompactString;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+use super::authenticate_with_upstream;
+use super::check_upstream;
+use super::counters;
+use super::RequestConfig;
+use crate::upstream::tls_sessions;
+
+static WARM_UPS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_token_warm_ups", "Number of anonymous tokens taken again after a restart for scopes taken before it, by result", &["namespace", "result"]).unwrap());
+
+/// Where the checkpoint is kept.
+const CHECKPOINT_OBJECT: &str = "checkpoint.json";
+
+/// How long before a checkpoint a token has to have been taken for its scope to be kept
+const RECENT_TOKENS: Duration = Duration::from_secs(60 * 60);
+
+/// How many tokens are taken at once while warming up
+const WARM_UP_CONCURRENCY: usize = 4;
+
+/// How long an instance's counters are kept after it last saved them, for it to come back to
+pub const COUNTER_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("Storage error:  {0}")]
+	Storage(#[from] crate::storage::Error),
+	#[error("Invalid checkpoint:  {0}")]
+	Json(#[from] serde_json::Error)
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Checkpoint {
+	/// Seconds since the epoch; entry ages are as of then
+	saved_at: u64,
+	known_blobs: Vec<KnownBlob>,
+	entitlements: Vec<Entitlement>,
+	/// Scopes anonymous tokens were recently taken for
+	#[serde(default)]
+	tokens: Vec<TokenScope>,
+	/// What the prefetch strategy has learned, if it keeps anything
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	prefetch: Option<serde_json::Value>,
+	/// Each named instance's hit and miss counters, by name
+	#[serde(default)]
+	counters: BTreeMap<String, InstanceCounters>,
+	/// Whether pass-through credentials' partitions were named with the partition key; before they
+	/// were, they're named for nothing that'll be asked for again
+	#[serde(default)]
+	keyed_partitions: bool,
+	/// Upstream TLS sessions, encrypted, with `--checkpoint-tls-key`
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	tls_sessions: Option<String>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct InstanceCounters {
+	/// Seconds since the epoch
+	saved_at: u64,
+	counts: Vec<counters::Count>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct KnownBlob {
+	path: String,
+	length: u64,
+	age_ms: u64
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Entitlement {
+	key: String,
+	age_ms: u64
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct TokenScope {
+	namespace: CompactString,
+	scope: String,
+	age_ms: u64
+}
+
+/// What's left to do once a checkpoint's been read back.
+#[derive(Debug, Default)]
+pub struct Restored {
+	tokens: Vec<TokenScope>
+}
+
+impl Restored {
+	/// Takes anonymous tokens again for up to `limit` of the scopes most recently taken before the
+	/// checkpoint, newest first, from the upstreams that pull anonymously and are up.
+	pub async fn warm_up(mut self, config: &RequestConfig, limit: usize) {
+		self.tokens.sort_by_key(|t| t.age_ms);
+		self.tokens.truncate(limit);
+		if (self.tokens.is_empty()) {
+			return;
+		}
+		let started = std::time::Instant::now();
+		let results = futures::stream::iter(self.tokens.iter().map(|token| async move { (token, warm_up(config, token).await) })).buffer_unordered(WARM_UP_CONCURRENCY).collect::<Vec<_>>().await;
+		let mut taken = 0;
+		for (token, result) in results {
+			let label = match result {
+				Ok(true) => {
+					taken += 1;
+					"taken"
+				},
+				Ok(false) => "skipped",
+				Err(error) => {
+					debug!(namespace = token.namespace.as_str(), scope = token.scope.as_str(), %error, "Failed to take a token again");
+					"failed"
+				}
+			};
+			WARM_UPS.with_label_values(&[token.namespace.as_str(), label]).inc();
+		}
+		info!(taken, scopes = self.tokens.len(), elapsed_ms = started.elapsed().as_millis() as u64, "Warmed up upstream tokens");
+	}
+}
+
+/// Takes a token for `token`'s scope, unless its upstream has credentials of its own, for which
+/// tokens aren't shared, or is down; returns whether it did.
+async fn warm_up(config: &RequestConfig, token: &TokenScope) -> Result<bool, super::error::Error> {
+	let mut upstream = { config.upstream.lock().await.get(&token.namespace)?.clone() };
+	if (upstream.has_credentials() || check_upstream(config, &upstream).is_err()) {
+		return Ok(false);
+	}
+	authenticate_with_upstream(&mut upstream, &token.scope).await?;
+	upstream.anonymous_tokens.insert(&token.scope, upstream.authorization.clone());
+	Ok(true)
+}
+
+fn unix_time() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn millis(age: Duration) -> u64 {
+	age.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Reads the last checkpoint, if there is one.
+async fn read(config: &RequestConfig) -> Result<Option<Checkpoint>, Error> {
+	let body = match config.repo.read(CHECKPOINT_OBJECT, Duration::MAX).await {
+		Ok(stream) => stream.into_inner().try_collect::<BytesMut>().await.map_err(crate::storage::Error::from)?,
+		Err(e) if e.is_not_found() => return Ok(None),
+		Err(e) => return Err(e.into())
+	};
+	Ok(Some(serde_json::from_slice(body.as_ref())?))
+}
+
+/// Other instances' counters from the last checkpoint, those saved too long ago left out, with this
+/// one's as they stand now.
+fn merge_counters(mut saved: BTreeMap<String, InstanceCounters>, name: Option<&str>, counts: Vec<counters::Count>, now: u64) -> BTreeMap<String, InstanceCounters> {
+	saved.retain(|_, counters| now.saturating_sub(counters.saved_at) < COUNTER_RETENTION.as_secs());
+	if let Some(name) = name {
+		saved.insert(name.to_owned(), InstanceCounters { saved_at: now, counts });
+	}
+	saved
+}
+
+/// Writes the current state to storage, replacing any earlier checkpoint but for other instances'
+/// counters.
+pub async fn save(config: &RequestConfig) -> Result<(), Error> {
+	let now = unix_time();
+	// One that can't be read is replaced all the same
+	let saved = match read(config).await {
+		Ok(saved) => saved.map(|checkpoint| checkpoint.counters).unwrap_or_default(),
+		Err(error) => {
+			debug!(%error, "Failed to read the last checkpoint for other instances' counters");
+			BTreeMap::new()
+		}
+	};
+	let checkpoint = Checkpoint {
+		saved_at: now,
+		known_blobs: config.known_blobs.snapshot().into_iter().map(|(path, length, age)| KnownBlob { path, length, age_ms: millis(age) }).collect(),
+		entitlements: config.entitlements.snapshot().into_iter().map(|(key, age)| Entitlement { key, age_ms: millis(age) }).collect(),
+		tokens: config.upstream.lock().await.recent_anonymous_scopes(RECENT_TOKENS).into_iter().map(|(namespace, scope, age)| TokenScope { namespace, scope, age_ms: millis(age) }).collect(),
+		prefetch: config.prefetch.as_ref().and_then(|p| p.strategy().snapshot()),
+		counters: merge_counters(saved, config.instance_name.as_deref(), counters::snapshot(), now),
+		keyed_partitions: true,
+		tls_sessions: tls_sessions::save()
+	};
+	let body = Bytes::from(serde_json::to_vec(&checkpoint)?);
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	config.repo.write(CHECKPOINT_OBJECT, futures::stream::iter(std::iter::once(Result::<_, std::io::Error>::Ok(body))), len).await?;
+	info!(known_blobs = checkpoint.known_blobs.len(), entitlements = checkpoint.entitlements.len(), tokens = checkpoint.tokens.len(), "Saved checkpoint");
+	Ok(())
+}
+
+/// Reads back the last checkpoint, if there is one.  Entries count as however old they were when it
+/// was saved, plus however long ago that was, so anything that's expired since is left out.  The
+/// tokens to take again are left for [`Restored::warm_up`], so as not to hold up startup.
+pub async fn restore(config: &RequestConfig) -> Result<Restored, Error> {
+	let Some(mut checkpoint) = read(config).await? else {
+		return Ok(Restored::default());
+	};
+	let since = Duration::from_secs(unix_time().saturating_sub(checkpoint.saved_at));
+	let age = |age_ms: u64| Duration::from_millis(age_ms).saturating_add(since);
+	info!(known_blobs = checkpoint.known_blobs.len(), entitlements = checkpoint.entitlements.len(), age = %humantime::format_duration(since), "Restoring checkpoint");
+	if (!checkpoint.keyed_partitions) {
+		checkpoint.known_blobs.retain(|b| !b.path.contains("_private/"));
+		checkpoint.entitlements.clear();
+	}
+	config.known_blobs.restore(checkpoint.known_blobs.into_iter().map(|b| (b.path, b.length, age(b.age_ms))));
+	config.entitlements.restore(checkpoint.entitlements.into_iter().map(|e| (e.key, age(e.age_ms))));
+	if let (Some(sealed), true) = (checkpoint.tls_sessions.as_deref(), tls_sessions::enabled()) {
+		match tls_sessions::restore(sealed) {
+			Some(count) => info!(count, "Restored upstream TLS sessions"),
+			None => warn!("Failed to decrypt the checkpoint's upstream TLS sessions; has --checkpoint-tls-key changed?")
+		};
+	}
+	if let (Some(prefetcher), Some(state)) = (&config.prefetch, checkpoint.prefetch) {
+		prefetcher.strategy().restore(state);
+	}
+	if let Some(counters) = config.instance_name.as_deref().and_then(|name| checkpoint.counters.remove(name)) {
+		counters::restore(counters.counts);
+	}
+	Ok(Restored { tokens: checkpoint.tokens })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn counts(value: u64) -> Vec<counters::Count> {
+		vec![counters::Count { name: "blob_cache_hits".to_owned(), namespace: "docker.io".to_owned(), value }]
+	}
+
+	#[test]
+	fn counters_are_kept_by_instance() {
+		let now = 100 * 24 * 60 * 60;
+		let saved = [("a", now - 60), ("b", now - 60), ("gone", now - COUNTER_RETENTION.as_secs())].into_iter().map(|(name, saved_at)| (name.to_owned(), InstanceCounters { saved_at, counts: counts(1) })).collect();
+		let merged = merge_counters(saved, Some("a"), counts(5), now);
+		assert_eq!(merged.keys().collect::<Vec<_>>(), ["a", "b"]);
+		assert_eq!((merged["a"].saved_at, merged["a"].counts.clone()), (now, counts(5)));
+		assert_eq!(merged["b"].counts, counts(1));
+		// Without a name, nothing of this instance's is kept
+		assert_eq!(merge_counters(merged, None, counts(7), now).len(), 2);
+	}
+}
@@ -0,0 +1,198 @@
+use core::fmt;
+use core::str::FromStr;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header;
+
+/// A network in CIDR notation, or a single address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IpNetwork {
+	addr: IpAddr,
+	prefix_len: u8
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid network '{0}'; expected an IP address or CIDR block")]
+pub struct InvalidNetwork(String);
+
+impl FromStr for IpNetwork {
+	type Err = InvalidNetwork;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || InvalidNetwork(s.to_owned());
+		let (addr, prefix_len) = match s.trim().split_once('/') {
+			Some((addr, len)) => (addr.parse::<IpAddr>().map_err(|_| invalid())?, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+			None => (s.trim().parse::<IpAddr>().map_err(|_| invalid())?, None)
+		};
+		let max = match addr {
+			IpAddr::V4(_) => 32,
+			IpAddr::V6(_) => 128
+		};
+		match prefix_len.unwrap_or(max) {
+			len if len > max => Err(invalid()),
+			prefix_len => Ok(Self { addr, prefix_len })
+		}
+	}
+}
+
+impl IpNetwork {
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		// Dual-stack listeners see IPv4 clients as IPv4-mapped IPv6 addresses
+		let ip = match ip {
+			IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+			ip => ip
+		};
+		match (self.addr, ip) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => {
+				let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+				u32::from(net) & mask == u32::from(ip) & mask
+			},
+			(IpAddr::V6(net), IpAddr::V6(ip)) => {
+				let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+				u128::from(net) & mask == u128::from(ip) & mask
+			},
+			_ => false
+		}
+	}
+}
+
+/// The frontends (load balancers, ingress controllers) whose `Forwarded` and `X-Forwarded-For`
+/// headers we believe.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Vec<IpNetwork>);
+
+impl From<Vec<IpNetwork>> for TrustedProxies {
+	fn from(networks: Vec<IpNetwork>) -> Self {
+		Self(networks)
+	}
+}
+
+impl TrustedProxies {
+	fn contains(&self, ip: IpAddr) -> bool {
+		self.0.iter().any(|n| n.contains(ip))
+	}
+
+	/// Connections over a Unix socket have no peer address; they can only come from something
+	/// running on the same host, so they're trusted as long as any proxies are.
+	fn trusts(&self, peer: Option<IpAddr>) -> bool {
+		match peer {
+			Some(ip) => self.contains(ip),
+			None => !self.0.is_empty()
+		}
+	}
+}
+
+/// The address of the client a request came from, looking through any trusted proxies in front
+/// of us.  `None` if the request came over a Unix socket from something that didn't say.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(Option<IpAddr>);
+
+impl fmt::Display for ClientIp {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.0 {
+			Some(ip) => ip.fmt(f),
+			None => f.write_str("-")
+		}
+	}
+}
+
+/// Extracts the address from a single hop, which may be bracketed, quoted, or carry a port.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+	let hop = hop.trim().trim_matches('"');
+	hop.parse::<IpAddr>()
+		.ok()
+		.or_else(|| hop.parse::<SocketAddr>().ok().map(|a| a.ip()))
+		.or_else(|| hop.strip_prefix('[').and_then(|h| h.strip_suffix(']')).and_then(|h| h.parse().ok()))
+}
+
+/// The `for=` hops of an RFC 7239 `Forwarded` header, nearest client first.
+fn forwarded_hops(value: &str) -> Vec<&str> {
+	value
+		.split(',')
+		.filter_map(|element| element.split(';').find_map(|pair| pair.trim().split_once('=').filter(|(k, _)| k.eq_ignore_ascii_case("for")).map(|(_, v)| v)))
+		.collect()
+}
+
+/// Walks the forwarding chain back from the nearest hop, stopping at the first address that
+/// isn't one of our trusted proxies.
+fn resolve(peer: Option<IpAddr>, hops: &[&str], trusted: &TrustedProxies) -> Option<IpAddr> {
+	if (!trusted.trusts(peer)) {
+		return peer;
+	}
+	let mut client = peer;
+	for hop in hops.iter().rev() {
+		let Some(ip) = parse_hop(hop) else {
+			break;
+		};
+		client = Some(ip);
+		if (!trusted.contains(ip)) {
+			break;
+		}
+	}
+	client
+}
+
+impl ClientIp {
+	pub fn from_request(req: &ServiceRequest, trusted: &TrustedProxies) -> Self {
+		let peer = req.peer_addr().map(|a| a.ip());
+		if (!trusted.trusts(peer)) {
+			return Self(peer);
+		}
+		let headers = req.headers();
+		let forwarded = headers.get_all(header::FORWARDED).filter_map(|v| v.to_str().ok()).flat_map(forwarded_hops).collect::<Vec<_>>();
+		let hops = match forwarded.is_empty() {
+			true => headers.get_all("x-forwarded-for").filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')).collect(),
+			false => forwarded
+		};
+		Self(resolve(peer, &hops, trusted))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn trusted(networks: &[&str]) -> TrustedProxies {
+		networks.iter().map(|n| n.parse().unwrap()).collect::<Vec<_>>().into()
+	}
+
+	#[test]
+	fn networks() {
+		let net: IpNetwork = "10.0.0.0/8".parse().unwrap();
+		assert!(net.contains("10.1.2.3".parse().unwrap()));
+		assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+		assert!(!net.contains("11.0.0.1".parse().unwrap()));
+		let net: IpNetwork = "fd00::/8".parse().unwrap();
+		assert!(net.contains("fd12::1".parse().unwrap()));
+		assert!(!net.contains("fe80::1".parse().unwrap()));
+		assert!("0.0.0.0/0".parse::<IpNetwork>().unwrap().contains("192.0.2.1".parse().unwrap()));
+		assert!("192.0.2.1".parse::<IpNetwork>().unwrap().contains("192.0.2.1".parse().unwrap()));
+		assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+		assert!("bogus".parse::<IpNetwork>().is_err());
+	}
+
+	#[test]
+	fn chain() {
+		let lb = Some("10.0.0.5".parse().unwrap());
+		let proxies = trusted(&["10.0.0.0/8"]);
+		assert_eq!(resolve(lb, &["203.0.113.7"], &proxies), "203.0.113.7".parse().ok());
+		// Only the hops our own proxies appended can be believed
+		assert_eq!(resolve(lb, &["198.51.100.1", "203.0.113.7", "10.0.0.9"], &proxies), "203.0.113.7".parse().ok());
+		assert_eq!(resolve(lb, &["garbage", "10.0.0.9"], &proxies), "10.0.0.9".parse().ok());
+		// Untrusted peers don't get to pick their address
+		let client = Some("203.0.113.7".parse().unwrap());
+		assert_eq!(resolve(client, &["198.51.100.1"], &proxies), client);
+		assert_eq!(resolve(lb, &["198.51.100.1"], &TrustedProxies::default()), lb);
+		assert_eq!(resolve(None, &["198.51.100.1"], &proxies), "198.51.100.1".parse().ok());
+	}
+
+	#[test]
+	fn forwarded_header() {
+		assert_eq!(forwarded_hops(r#"for=192.0.2.60;proto=http;by=203.0.113.43, for="[2001:db8:cafe::17]:4711""#), ["192.0.2.60", r#""[2001:db8:cafe::17]:4711""#]);
+		assert_eq!(parse_hop(r#""[2001:db8:cafe::17]:4711""#), "2001:db8:cafe::17".parse().ok());
+		assert_eq!(parse_hop("[2001:db8::1]"), "2001:db8::1".parse().ok());
+		assert_eq!(parse_hop(" 192.0.2.60:1234"), "192.0.2.60".parse().ok());
+	}
+}
@@ -0,0 +1,74 @@
+//! Response compression for the registry's JSON, with `--compress-responses`:  manifests, tag lists,
+//! referrers and the catalog are sent gzip- or zstd-encoded to clients whose `Accept-Encoding` asks
+//! for it.  Indexes with many platforms, and referrers lists, compress to a tenth or so of their
+//! size, which adds up for edge sites pulling them over a WAN.  Blobs are never compressed:  they're
+//! mostly compressed already, and clients check their length and digest as sent.
+//!
+//! The routes that may be compressed are wrapped in [`Compress`]; [`negotiate`] leaves it only the
+//! encodings it's meant to use, and nothing at all when compression is off or for a `HEAD`, whose
+//! `Content-Length` clients rely on.
+
+use actix_web::dev::ServiceRequest;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header;
+use actix_web::http::header::HeaderValue;
+use actix_web::http::Method;
+use actix_web::web;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+
+use super::RequestConfig;
+
+pub use actix_web::middleware::Compress;
+
+/// The encodings responses are compressed with
+const ENCODINGS: [&str; 2] = ["gzip", "zstd"];
+
+static COMPRESSED: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("compressed_responses", "Number of registry responses sent compressed, by encoding", &["encoding"]).unwrap());
+
+/// The entries of an `Accept-Encoding` value for encodings responses are compressed with, if any.
+fn accepted(value: &str) -> Option<String> {
+	let accepted = value.split(',').map(str::trim).filter(|entry| ENCODINGS.iter().any(|encoding| entry.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(encoding))).collect::<Vec<_>>();
+	match accepted.is_empty() {
+		true => None,
+		false => Some(accepted.join(", "))
+	}
+}
+
+/// Narrows a registry request's `Accept-Encoding` to what its response may be compressed with.
+pub(super) fn negotiate(req: &mut ServiceRequest) {
+	let enabled = req.method() == Method::GET && req.app_data::<web::Data<RequestConfig>>().is_some_and(|config| config.compression);
+	let accepted = match enabled {
+		true => req.headers().get(header::ACCEPT_ENCODING).and_then(|v| v.to_str().ok()).and_then(accepted),
+		false => None
+	};
+	match accepted.and_then(|v| HeaderValue::from_str(&v).ok()) {
+		Some(value) => {
+			req.headers_mut().insert(header::ACCEPT_ENCODING, value);
+		},
+		None => {
+			req.headers_mut().remove(header::ACCEPT_ENCODING);
+		}
+	}
+}
+
+/// Counts a response that went out compressed.
+pub(super) fn count<B>(response: &ServiceResponse<B>) {
+	if let Some(encoding) = response.headers().get(header::CONTENT_ENCODING).and_then(|v| v.to_str().ok()).filter(|v| ENCODINGS.contains(v)) {
+		COMPRESSED.with_label_values(&[encoding]).inc();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn only_gzip_and_zstd_are_accepted() {
+		assert_eq!(accepted("gzip").as_deref(), Some("gzip"));
+		assert_eq!(accepted("br, zstd;q=0.9 , GZIP;q=0.5, deflate").as_deref(), Some("zstd;q=0.9, GZIP;q=0.5"));
+		assert_eq!(accepted("br, *"), None);
+		assert_eq!(accepted(""), None);
+	}
+}
@@ -0,0 +1,282 @@
+//! Cosign signatures, for upstreams with `signature_keys`:  a manifest fetched from one of those is
+//! only cached and served once it's found to be signed by one of the keys, so that which images can
+//! be pulled is enforced here, once, rather than by every cluster pulling through us.  Cosign keeps
+//! an image's signatures in its repository under the tag `sha256-<hash>.sig`, as the layers of a
+//! manifest:  each is a "simple signing" payload naming the digest of the manifest signed, with
+//! its signature in an annotation.
+//!
+//! What's signed is usually an index, rather than the manifest for each platform in it, so a
+//! manifest listed in an index found to be signed counts as signed too, for as long as this process
+//! remembers it.  Cosign's own artifacts, at their `.sig`, `.att` and `.sbom` tags, don't need
+//! signing, so that clients can check signatures through us, as long as they have no layers that
+//! could be run.  Manifests cached before the keys were configured aren't checked again.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use base64::Engine;
+use bytes::Bytes;
+use bytes::BytesMut;
+use compact_str::CompactString;
+use futures::stream::Stream;
+use futures::stream::TryStreamExt;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use ring::signature::UnparsedPublicKey;
+use ring::signature::ECDSA_P256_SHA256_ASN1;
+use serde::Deserialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::info;
+use tracing::warn;
+
+use super::error::Error;
+use super::fetch_manifest;
+use super::with_namespace_parameter;
+use super::RequestConfig;
+use crate::image::ImageReference;
+use crate::storage::Manifest;
+use crate::upstream::pull::PulledManifest;
+use crate::upstream::Client;
+
+static CHECKS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("signature_checks", "Number of manifests checked for a cosign signature, by result", &["namespace", "result"]).unwrap());
+
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+
+/// What a P-256 public key's DER `SubjectPublicKeyInfo` starts with, ahead of the point itself
+const P256_SPKI_PREFIX: &[u8] = &[0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00];
+
+/// Signature payloads bigger than this aren't read; they're a few hundred bytes.
+const MAX_PAYLOAD_SIZE: u64 = 64 * 1024;
+
+/// How many signed manifests are remembered; past this, they're forgotten and start over, like the
+/// known blobs index does.
+const MAX_VERIFIED: usize = 65536;
+
+/// A cosign public key, as PEM:  ECDSA on P-256, the kind `cosign generate-key-pair` makes.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PublicKey(Vec<u8>);
+
+impl TryFrom<String> for PublicKey {
+	type Error = String;
+
+	fn try_from(pem: String) -> Result<Self, Self::Error> {
+		if (!pem.contains("-----BEGIN PUBLIC KEY-----")) {
+			return Err("signature key isn't a PEM public key".into());
+		}
+		let body = pem.lines().map(str::trim).filter(|line| !line.starts_with("-----")).collect::<String>();
+		let der = base64::engine::general_purpose::STANDARD.decode(body).map_err(|e| format!("signature key isn't valid PEM: {e}"))?;
+		let point = der.strip_prefix(P256_SPKI_PREFIX).ok_or("signature key isn't an ECDSA P-256 key")?;
+		// An uncompressed point; whether it's on the curve, ring works out as it verifies
+		match (point.len(), point.first()) {
+			(65, Some(0x04)) => Ok(Self(point.to_vec())),
+			_ => Err("signature key isn't an uncompressed P-256 point".into())
+		}
+	}
+}
+
+impl PublicKey {
+	/// Whether `signature`, ASN.1 DER as cosign makes them, is this key's over `message`.
+	fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+		UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &self.0).verify(message, signature).is_ok()
+	}
+}
+
+/// The manifests found to be signed, and those listed in indexes found to be signed, by namespace
+/// and digest.
+#[derive(Default)]
+pub struct Verified {
+	digests: Mutex<HashSet<(CompactString, String)>>
+}
+
+impl Verified {
+	fn contains(&self, namespace: &str, digest: &str) -> bool {
+		self.digests.lock().unwrap().contains(&(namespace.into(), digest.to_owned()))
+	}
+
+	fn insert(&self, namespace: &str, digests: impl IntoIterator<Item = String>) {
+		let mut verified = self.digests.lock().unwrap();
+		for digest in digests {
+			if (verified.len() >= MAX_VERIFIED) {
+				verified.clear();
+			}
+			verified.insert((namespace.into(), digest));
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+	#[serde(default, rename = "mediaType")]
+	media_type: String,
+	digest: String,
+	#[serde(default)]
+	size: u64,
+	#[serde(default)]
+	annotations: HashMap<String, String>
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Parsed {
+	#[serde(default)]
+	layers: Vec<Descriptor>,
+	#[serde(default)]
+	manifests: Vec<Descriptor>
+}
+
+#[derive(Debug, Deserialize)]
+struct Payload {
+	critical: Critical
+}
+
+#[derive(Debug, Deserialize)]
+struct Critical {
+	image: SignedImage
+}
+
+#[derive(Debug, Deserialize)]
+struct SignedImage {
+	#[serde(rename = "docker-manifest-digest")]
+	digest: String
+}
+
+/// Whether `tag` is where cosign keeps a signature, attestation or SBOM for an image.
+fn is_cosign_tag(tag: &str) -> bool {
+	let Some((hash, suffix)) = tag.strip_prefix("sha256-").and_then(|rest| rest.split_once('.')) else {
+		return false;
+	};
+	hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) && matches!(suffix, "sig" | "att" | "sbom")
+}
+
+/// Whether `digest` is a SHA-256 digest, the only kind a manifest's hash is checked against.
+fn is_sha256_digest(digest: &str) -> bool {
+	digest.strip_prefix("sha256:").is_some_and(|hash| hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Whether `manifest` has layers a container runtime would unpack.
+fn runnable(manifest: &Parsed) -> bool {
+	manifest.layers.iter().any(|layer| layer.media_type.starts_with("application/vnd.oci.image.layer.") || layer.media_type.starts_with("application/vnd.docker.image.rootfs."))
+}
+
+/// Checks that one of `upstream`'s signature keys, if it has any, has signed `manifest`, just
+/// fetched from it as `reference`.
+pub(super) async fn check(config: &RequestConfig, upstream: &mut Client, namespace: &str, upstream_image: &str, reference: &ImageReference, manifest: &Manifest, anonymous: bool) -> Result<(), Error> {
+	if (upstream.signature_keys().is_empty()) {
+		return Ok(());
+	}
+	let parsed = serde_json::from_slice::<Parsed>(&manifest.manifest).unwrap_or_default();
+	if let ImageReference::Tag(tag) = reference {
+		if (is_cosign_tag(tag) && !runnable(&parsed)) {
+			return Ok(());
+		}
+	}
+	// What's checked is what was fetched, not what upstream says it was
+	let digest = format!("sha256:{}", hex::encode(Sha256::digest(&manifest.manifest)));
+	if let Some(claimed) = manifest.digest.as_deref().filter(|claimed| *claimed != digest) {
+		CHECKS.with_label_values(&[namespace, "mismatched"]).inc();
+		warn!(namespace, image = upstream_image, claimed, digest, "Refusing manifest whose digest isn't the one upstream gave");
+		return Err(Error::SignatureRequired(claimed.to_owned()));
+	}
+	if (config.signatures.contains(namespace, &digest)) {
+		CHECKS.with_label_values(&[namespace, "listed"]).inc();
+		return Ok(());
+	}
+	if (!signed(upstream, namespace, upstream_image, &digest, anonymous, config.max_manifest_size).await?) {
+		CHECKS.with_label_values(&[namespace, "unsigned"]).inc();
+		info!(namespace, image = upstream_image, digest, "Refusing manifest with no valid signature");
+		return Err(Error::SignatureRequired(digest));
+	}
+	CHECKS.with_label_values(&[namespace, "signed"]).inc();
+	// Children are remembered by the digests the signed index lists, and only count as signed when
+	// what's fetched for them hashes to one of those, as above
+	config.signatures.insert(namespace, std::iter::once(digest).chain(parsed.manifests.into_iter().map(|child| child.digest).filter(|digest| is_sha256_digest(digest))));
+	Ok(())
+}
+
+/// Whether any of the signatures upstream has for the manifest with digest `digest` is valid.
+async fn signed(upstream: &mut Client, namespace: &str, upstream_image: &str, digest: &str, anonymous: bool, max_manifest_size: usize) -> Result<bool, Error> {
+	let tag = format!("{}.sig", digest.replace(':', "-"));
+	let signatures = match fetch_manifest(upstream, namespace, upstream_image, &tag, anonymous, max_manifest_size).await.map_err(|e| Error::from(upstream.normalize_error(e, anonymous))) {
+		Ok(PulledManifest::Manifest(body, ..)) => serde_json::from_slice::<Parsed>(&body)?,
+		Ok(PulledManifest::TooLarge(size)) => {
+			warn!(namespace, image = upstream_image, tag, size, "Skipping oversized signatures manifest");
+			return Ok(false);
+		},
+		Err(error) if error.is_not_found() => return Ok(false),
+		Err(error) => return Err(error)
+	};
+	for layer in signatures.layers {
+		let Some(signature) = layer.annotations.get(SIGNATURE_ANNOTATION).and_then(|v| base64::engine::general_purpose::STANDARD.decode(v).ok()) else {
+			continue;
+		};
+		if (layer.size > MAX_PAYLOAD_SIZE) {
+			warn!(namespace, image = upstream_image, digest = layer.digest, size = layer.size, "Skipping oversized signature payload");
+			continue;
+		}
+		let response = with_namespace_parameter(upstream.namespace_parameter, namespace, |ns| upstream.get_blob(upstream_image, &layer.digest, ns)).await;
+		// The layer's size is only what the manifest says
+		let Some(payload) = read_payload(response?.stream()).await? else {
+			warn!(namespace, image = upstream_image, digest = layer.digest, "Skipping signature payload bigger than its layer said");
+			continue;
+		};
+		// The signature covers the payload, and the payload names the manifest; the layer's digest
+		// only has to be right for the payload to be the one upstream meant
+		if (format!("sha256:{}", hex::encode(Sha256::digest(&payload))) != layer.digest) {
+			continue;
+		}
+		if (serde_json::from_slice::<Payload>(&payload).ok().map(|p| p.critical.image.digest).as_deref() != Some(digest)) {
+			continue;
+		}
+		if (upstream.signature_keys().iter().any(|key| key.verify(&payload, &signature))) {
+			return Ok(true);
+		}
+	}
+	Ok(false)
+}
+
+/// Reads a signature payload, or as much of it as it takes to tell it's over `MAX_PAYLOAD_SIZE`,
+/// in which case it's `None`.
+async fn read_payload(stream: impl Stream<Item = Result<Bytes, crate::storage::Error>>) -> Result<Option<Bytes>, crate::storage::Error> {
+	let mut stream = std::pin::pin!(stream);
+	let mut payload = BytesMut::new();
+	while let Some(chunk) = stream.try_next().await? {
+		if ((payload.len() + chunk.len()) as u64 > MAX_PAYLOAD_SIZE) {
+			return Ok(None);
+		}
+		payload.extend_from_slice(&chunk);
+	}
+	Ok(Some(payload.freeze()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn keys() {
+		let pem = "-----BEGIN PUBLIC KEY-----\nMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEmFQseuBeda69davDL0F2oblH83dQ\nPEFbDhU+CIOl5sn/GjZ1oDpi2cJpL5zLGWYTrWJRa9o/YXMynaeXUZWPnQ==\n-----END PUBLIC KEY-----\n";
+		assert!(PublicKey::try_from(pem.to_owned()).is_ok());
+		assert!(PublicKey::try_from(pem.replace("MFkw", "MFkx")).is_err());
+		assert!(PublicKey::try_from("ssh-ed25519 AAAA".to_owned()).is_err());
+	}
+
+	#[actix_web::test]
+	async fn payload_size() {
+		let chunks = |n: usize| futures::stream::iter((0..n).map(|_| Ok(Bytes::from(vec![0; 1024]))));
+		assert_eq!(read_payload(chunks(64)).await.unwrap().map(|p| p.len()), Some(64 * 1024));
+		assert_eq!(read_payload(chunks(65)).await.unwrap(), None);
+	}
+
+	#[test]
+	fn cosign_tags() {
+		let hash = "a".repeat(64);
+		assert!(is_cosign_tag(&format!("sha256-{hash}.sig")));
+		assert!(is_cosign_tag(&format!("sha256-{hash}.att")));
+		assert!(!is_cosign_tag(&format!("sha256-{hash}")));
+		assert!(!is_cosign_tag(&format!("sha256-{hash}.tar")));
+		assert!(!is_cosign_tag("sha256-abcd.sig"));
+	}
+}
@@ -0,0 +1,90 @@
+//! The cache's hit and miss counters, by namespace.  With `--checkpoint` and an instance name, they
+//! carry over restarts:  each instance's counts are saved in the checkpoint under its name, and an
+//! instance starting up under that name carries on from them, so that hit rates summed over a fleet
+//! don't drop to nothing in the middle of every rollout.
+
+use once_cell::sync::Lazy;
+use prometheus::core::Collector;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use serde::Serialize;
+
+pub(super) static MANIFEST_HITS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_cache_hits", "Number of manifests read from cache", &["namespace"]).unwrap());
+pub(super) static MANIFEST_MISSES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_cache_misses", "Number of manifest requests that went to upstream", &["namespace"]).unwrap());
+pub(super) static MANIFEST_STALE_HITS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_cache_stale_hits", "Number of expired manifests served from cache because upstream was unavailable", &["namespace"]).unwrap());
+pub(super) static MANIFEST_REVALIDATIONS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_cache_revalidations", "Number of expired manifests served from cache because upstream said the tag hadn't moved", &["namespace"]).unwrap());
+pub(super) static BLOB_HITS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("blob_cache_hits", "Number of blobs read from cache", &["namespace"]).unwrap());
+pub(super) static BLOB_MISSES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("blob_cache_misses", "Number of blob requests that went to upstream", &["namespace"]).unwrap());
+pub(super) static BLOB_STALE_HITS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("blob_cache_stale_hits", "Number of expired blobs served from cache because upstream was unavailable", &["namespace"]).unwrap());
+pub(super) static BLOB_INLINE_HITS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("blob_inline_hits", "Number of blobs served from the data embedded in a manifest's descriptor for them", &["namespace"]).unwrap());
+
+/// The counters that are saved, by name
+fn persisted() -> [(&'static str, &'static IntCounterVec); 8] {
+	[
+		("manifest_cache_hits", &MANIFEST_HITS),
+		("manifest_cache_misses", &MANIFEST_MISSES),
+		("manifest_cache_stale_hits", &MANIFEST_STALE_HITS),
+		("manifest_cache_revalidations", &MANIFEST_REVALIDATIONS),
+		("blob_cache_hits", &BLOB_HITS),
+		("blob_cache_misses", &BLOB_MISSES),
+		("blob_cache_stale_hits", &BLOB_STALE_HITS),
+		("blob_inline_hits", &BLOB_INLINE_HITS)
+	]
+}
+
+/// One counter's count for one namespace.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Count {
+	pub name: String,
+	pub namespace: String,
+	pub value: u64
+}
+
+/// Every count so far, leaving out namespaces nothing's been counted for.
+pub(super) fn snapshot() -> Vec<Count> {
+	let mut counts = Vec::new();
+	for (name, counter) in persisted() {
+		for metric in counter.collect().iter().flat_map(|family| family.get_metric()) {
+			let Some(namespace) = metric.get_label().iter().find(|label| label.get_name() == "namespace") else {
+				continue;
+			};
+			let value = metric.get_counter().get_value() as u64;
+			if (value != 0) {
+				counts.push(Count { name: name.to_owned(), namespace: namespace.get_value().to_owned(), value });
+			}
+		}
+	}
+	counts
+}
+
+/// Carries on from `counts`, on top of whatever's been counted since startup.  Counters this
+/// version doesn't keep are ignored.
+pub(super) fn restore(counts: impl IntoIterator<Item = Count>) {
+	let persisted = persisted();
+	for count in counts {
+		if let Some((_, counter)) = persisted.iter().find(|(name, _)| *name == count.name) {
+			counter.with_label_values(&[count.namespace.as_str()]).inc_by(count.value);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn count(name: &str, value: u64) -> Count {
+		Count { name: name.to_owned(), namespace: "counters-test".to_owned(), value }
+	}
+
+	#[test]
+	fn snapshot_and_restore() {
+		MANIFEST_HITS.with_label_values(&["counters-test"]).inc_by(3);
+		BLOB_MISSES.with_label_values(&["counters-test"]).inc();
+		let snapshot = snapshot().into_iter().filter(|c| c.namespace == "counters-test").collect::<Vec<_>>();
+		assert_eq!(snapshot, [count("manifest_cache_hits", 3), count("blob_cache_misses", 1)]);
+
+		restore([count("manifest_cache_hits", 10), count("no_such_counter", 5)]);
+		assert_eq!(MANIFEST_HITS.with_label_values(&["counters-test"]).get(), 13);
+	}
+}
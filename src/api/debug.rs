@@ -0,0 +1,224 @@
+//! Runtime diagnostics under `/_admin/debug`, for working out where memory goes while many blobs
+//! are being filled from upstream at once:  the process's memory as the kernel counts it, the heap
+//! as the allocator sees it (in builds with the `heap-stats` feature), and how much the cache is
+//! holding on to, and in builds with the `cpu-profile` feature, a CPU profile taken over however
+//! long it's asked for.  Registered only with `--debug-endpoints`, and like the rest of `/_admin`,
+//! only served with the admin token, where there is one.
+
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use core::future;
+use std::collections::BTreeMap;
+
+use actix_web::dev::Service;
+use actix_web::dev::ServiceResponse;
+use actix_web::web;
+use actix_web::HttpResponse;
+use futures::future::FutureExt;
+use once_cell::sync::Lazy;
+use prometheus::register_int_gauge;
+use prometheus::IntGauge;
+
+use super::admin_token;
+use super::RequestConfig;
+
+static FILLS: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("blob_fills_in_progress", "Number of blobs being streamed from upstream into the cache").unwrap());
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting what's allocated through it.  Installed as the global allocator
+/// by the `oci-registry` binary when it's built with the `heap-stats` feature; costs a few atomic
+/// updates per allocation.
+pub struct CountingAllocator;
+
+fn allocated(size: usize) {
+	let total = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+	PEAK_ALLOCATED.fetch_max(total, Ordering::Relaxed);
+	ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+fn freed(size: usize) {
+	ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		let ptr = System.alloc(layout);
+		if (!ptr.is_null()) {
+			allocated(layout.size());
+		}
+		ptr
+	}
+
+	unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+		let ptr = System.alloc_zeroed(layout);
+		if (!ptr.is_null()) {
+			allocated(layout.size());
+		}
+		ptr
+	}
+
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		System.dealloc(ptr, layout);
+		freed(layout.size());
+	}
+
+	unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+		let new = System.realloc(ptr, layout, new_size);
+		if (!new.is_null()) {
+			freed(layout.size());
+			allocated(new_size);
+		}
+		new
+	}
+}
+
+/// Held for as long as a blob is being filled from upstream.
+pub(super) struct Fill(());
+
+impl Fill {
+	pub(super) fn start() -> Self {
+		FILLS.inc();
+		Self(())
+	}
+}
+
+impl Drop for Fill {
+	fn drop(&mut self) {
+		FILLS.dec();
+	}
+}
+
+/// The memory figures in `/proc/self/status`, in bytes, under names of our own.
+fn parse_status(status: &str) -> BTreeMap<&'static str, u64> {
+	const FIELDS: &[(&str, &str)] = &[
+		("VmRSS", "resident_bytes"),
+		("VmHWM", "peak_resident_bytes"),
+		("RssAnon", "resident_anonymous_bytes"),
+		("RssFile", "resident_file_bytes"),
+		("VmData", "data_bytes"),
+		("VmSize", "virtual_bytes"),
+		("Threads", "threads")
+	];
+	let mut memory = BTreeMap::new();
+	for line in status.lines() {
+		let Some((key, value)) = line.split_once(':') else {
+			continue;
+		};
+		let Some((_, name)) = FIELDS.iter().find(|(field, _)| *field == key) else {
+			continue;
+		};
+		let mut value = value.split_whitespace();
+		let Some(n) = value.next().and_then(|n| n.parse::<u64>().ok()) else {
+			continue;
+		};
+		let n = match value.next() {
+			Some("kB") => n * 1024,
+			_ => n
+		};
+		memory.insert(*name, n);
+	}
+	memory
+}
+
+pub async fn memory(config: web::Data<RequestConfig>) -> HttpResponse {
+	// Where there's no procfs, there's nothing to say
+	let process = tokio::fs::read_to_string("/proc/self/status").await.ok().map(|status| parse_status(&status));
+	let heap = cfg!(feature = "heap-stats").then(|| {
+		serde_json::json!({
+			"allocated_bytes": ALLOCATED.load(Ordering::Relaxed),
+			"peak_allocated_bytes": PEAK_ALLOCATED.load(Ordering::Relaxed),
+			"allocations": ALLOCATIONS.load(Ordering::Relaxed)
+		})
+	});
+	HttpResponse::Ok().json(serde_json::json!({
+		"process": process,
+		"heap": heap,
+		"cache": {
+			"blob_fills_in_progress": FILLS.get(),
+			"known_blobs": config.known_blobs.entries()
+		}
+	}))
+}
+
+#[cfg(feature = "cpu-profile")]
+mod profile {
+	use core::time::Duration;
+
+	use actix_web::web;
+	use actix_web::HttpResponse;
+	use pprof::protos::Message;
+	use serde::Deserialize;
+
+	/// How often the profiler samples the stack, a little off round numbers so as not to run in
+	/// step with timers
+	const FREQUENCY: i32 = 99;
+	/// The longest a profile can be taken over
+	const MAX_SECONDS: u64 = 300;
+
+	#[derive(Deserialize)]
+	pub struct Query {
+		#[serde(default = "default_seconds")]
+		seconds: u64
+	}
+
+	fn default_seconds() -> u64 {
+		30
+	}
+
+	/// Profiles every thread for `seconds` (30 by default, up to 5 minutes), and answers with the
+	/// profile in pprof's protobuf format, for `go tool pprof`.  One at a time.
+	pub async fn cpu(query: web::Query<Query>) -> HttpResponse {
+		let seconds = query.seconds.clamp(1, MAX_SECONDS);
+		let guard = match pprof::ProfilerGuardBuilder::default().frequency(FREQUENCY).blocklist(&["libc", "libgcc", "pthread", "vdso"]).build() {
+			Ok(v) => v,
+			Err(error) => return HttpResponse::Conflict().body(format!("Couldn't start profiling, likely because another profile is being taken: {error}"))
+		};
+		tokio::time::sleep(Duration::from_secs(seconds)).await;
+		let profile = match guard.report().build().and_then(|report| report.pprof()) {
+			Ok(v) => v,
+			Err(error) => return HttpResponse::InternalServerError().body(error.to_string())
+		};
+		let mut body = Vec::new();
+		if let Err(error) = profile.encode(&mut body) {
+			return HttpResponse::InternalServerError().body(error.to_string());
+		}
+		HttpResponse::Ok().content_type("application/octet-stream").insert_header(("content-disposition", "attachment; filename=\"profile.pb\"")).body(body)
+	}
+}
+
+/// Registers the debug endpoints; has to come ahead of [`admin`](super::admin), whose scope would
+/// otherwise take their requests.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+	let scope = web::scope("/_admin/debug")
+		.wrap_fn(|req, srv| match admin_token::guard(&req) {
+			Ok(()) => srv.call(req).map(|response| response.map(ServiceResponse::map_into_boxed_body)).left_future(),
+			Err(error) => future::ready(Ok(req.error_response(error))).right_future()
+		})
+		.wrap(super::logger())
+		.route("/memory", web::get().to(memory));
+	#[cfg(feature = "cpu-profile")]
+	let scope = scope.route("/pprof/profile", web::get().to(profile::cpu));
+	cfg.service(scope);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn status() {
+		let status = "Name:\toci-registry\nVmHWM:\t  204800 kB\nVmRSS:\t  102400 kB\nRssAnon:\t   81920 kB\nThreads:\t12\nVmSwap:\t       0 kB\n";
+		let memory = parse_status(status);
+		assert_eq!(memory["resident_bytes"], 100 * 1024 * 1024);
+		assert_eq!(memory["peak_resident_bytes"], 200 * 1024 * 1024);
+		assert_eq!(memory["resident_anonymous_bytes"], 80 * 1024 * 1024);
+		assert_eq!(memory["threads"], 12);
+		assert_eq!(memory.len(), 4);
+	}
+}
@@ -0,0 +1,61 @@
+//! Cache hierarchies, where one instance's upstream is another instance (its parent), as in edge →
+//! regional → origin.  An edge configured with `parent: true` sends its namespace along in `ns`
+//! with every pull, and asks its parent how long each tag it fetches stays fresh, with a header
+//! saying it's an edge; the parent answers with what's left of its own copy's freshness.  A tag then
+//! expires at the edge when it does at the parent, or sooner, so that edge and parent don't each
+//! keep it for their full invalidation time, one after the other.
+
+use core::time::Duration;
+
+use actix_web::http::header;
+use actix_web::http::header::HeaderValue;
+use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+
+/// Sent by an edge asking its parent about a tag
+pub const EDGE_HEADER: &str = "x-oci-edge";
+
+/// Whether a request comes from an edge.
+pub(super) fn is_edge(http_req: Option<&HttpRequest>) -> bool {
+	http_req.is_some_and(|r| r.headers().contains_key(EDGE_HEADER))
+}
+
+/// Says how much longer a tag's manifest stays fresh here, where an edge asked, it's been `age` in
+/// cache, and neither upstream nor anything else has said already.  `lifetime` is `None` for
+/// anything edges aren't told about.
+pub(super) fn fresh_for(mut response: HttpResponse, lifetime: Option<Duration>, age: Option<Duration>) -> HttpResponse {
+	let Some(lifetime) = lifetime else {
+		return response;
+	};
+	if (response.status() != StatusCode::OK || response.headers().contains_key(header::CACHE_CONTROL)) {
+		return response;
+	}
+	let remaining = lifetime.saturating_sub(age.unwrap_or_default());
+	if let Ok(value) = HeaderValue::from_str(&format!("max-age={}", remaining.as_secs())) {
+		response.headers_mut().insert(header::CACHE_CONTROL, value);
+	}
+	response
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn cache_control(response: HttpResponse, lifetime: Option<Duration>, age: Option<Duration>) -> Option<String> {
+		fresh_for(response, lifetime, age).headers().get(header::CACHE_CONTROL).map(|v| v.to_str().unwrap().to_owned())
+	}
+
+	#[test]
+	fn whats_left_of_a_tags_freshness() {
+		let hour = Some(Duration::from_secs(3600));
+		assert_eq!(cache_control(HttpResponse::Ok().finish(), hour, Some(Duration::from_secs(600))).as_deref(), Some("max-age=3000"));
+		assert_eq!(cache_control(HttpResponse::Ok().finish(), hour, None).as_deref(), Some("max-age=3600"));
+		// Served stale
+		assert_eq!(cache_control(HttpResponse::Ok().finish(), hour, Some(Duration::from_secs(7200))).as_deref(), Some("max-age=0"));
+		// Upstream's word stands
+		assert_eq!(cache_control(HttpResponse::Ok().insert_header((header::CACHE_CONTROL, "max-age=60")).finish(), hour, None).as_deref(), Some("max-age=60"));
+		assert_eq!(cache_control(HttpResponse::Ok().finish(), None, None), None);
+		assert_eq!(cache_control(HttpResponse::NotFound().finish(), hour, None), None);
+	}
+}
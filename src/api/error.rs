@@ -1,15 +1,27 @@
+use core::time::Duration;
+
 use actix_web::body::BoxBody;
+use actix_web::http::header;
+use actix_web::http::header::HeaderValue;
 use actix_web::http::StatusCode;
 use actix_web::HttpResponse;
 use actix_web::HttpResponseBuilder;
+use actix_web::ResponseError;
+use compact_str::CompactString;
 use dkregistry::errors::Error as Upstream;
-use rusoto_core::request::BufferedHttpResponse;
-use rusoto_core::RusotoError;
-use rusoto_s3::GetObjectError;
+use serde::Serialize;
 use tracing::error;
 
 use crate::api::stream::DigestMismatchError;
 use crate::storage::Error as Storage;
+use crate::upstream::circuit;
+use crate::upstream::circuit::CircuitOpen;
+use crate::upstream::downloads::DownloadQueueFull;
+use crate::upstream::throttle::RateLimited;
+
+/// Upstream's errors don't carry its Retry-After header, so when upstream rate-limits us, this is
+/// what we pass on to the client.
+const UPSTREAM_RATE_LIMIT_RETRY_AFTER: Duration = Duration::from_secs(60);
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -17,8 +29,18 @@ pub enum Error {
 	Storage(#[from] Storage),
 	#[error("Error with upstream registry: {0}")]
 	Upstream(#[from] Upstream),
-	#[error("Not found")]
+	#[error("Manifest unknown")]
+	ManifestUnknown,
+	#[error("Blob unknown")]
+	BlobUnknown,
+	#[error("Repository name not known to registry")]
+	NameUnknown,
+	#[error("Invalid digest")]
 	InvalidDigest,
+	#[error("Invalid repository name '{0}'")]
+	InvalidName(String),
+	#[error("Invalid tag '{0}'")]
+	InvalidTag(String),
 	#[error("Missing Content-Length header from upstream")]
 	MissingContentLength,
 	#[error("I/O error: {0}")]
@@ -26,40 +48,372 @@ pub enum Error {
 	#[error("JSON error: {0}")]
 	Json(#[from] serde_json::Error),
 	#[error("{0}")]
-	DataCorrupt(#[from] DigestMismatchError)
+	DataCorrupt(#[from] DigestMismatchError),
+	#[error("{0}")]
+	CircuitOpen(#[from] CircuitOpen),
+	#[error("{0}")]
+	DownloadQueueFull(#[from] DownloadQueueFull),
+	#[error("{0}")]
+	RateLimited(#[from] RateLimited),
+	#[error("Upstream registry refused access")]
+	Unauthorized(Option<HeaderValue>),
+	#[error("Error fetching foreign layer: {0}")]
+	ForeignLayer(reqwest::Error),
+	#[error("Request did not complete within {}", humantime::format_duration(*.0))]
+	DeadlineExceeded(Duration),
+	#[error("Manifest is at least {size} bytes, over the {limit} byte limit")]
+	ManifestTooLarge { size: u64, limit: usize },
+	#[error("Pinned in the pins file; remove it from there instead")]
+	PinnedByConfig,
+	#[error("Only a tag can be held at a digest")]
+	HoldNeedsTag,
+	#[error("Only a manifest named by digest can be purged along with what references it")]
+	CascadeNeedsDigest,
+	#[error("Aliased in the aliases file; remove it from there instead")]
+	AliasedByConfig,
+	#[error("{0}")]
+	InvalidAlias(String),
+	#[error("Other aliases in this repository stand for {0}; aliases in one repository have to stand for tags of the same one")]
+	AliasConflict(String),
+	#[error("Upstream only has this image as a Docker schema1 manifest, which isn't supported")]
+	Schema1Unsupported,
+	#[error("Couldn't convert schema1 manifest: {0}")]
+	Schema1Conversion(String),
+	#[error("Pushes to this namespace aren't forwarded to upstream")]
+	PushDisabled,
+	#[error("Blob upload unknown")]
+	BlobUploadUnknown,
+	#[error("Manifest is over the {limit} byte limit")]
+	PushedManifestTooLarge { limit: usize },
+	#[error("Error pushing to upstream registry: {0}")]
+	Push(reqwest::Error),
+	#[error("Error reading request body: {0}")]
+	Payload(#[from] actix_web::error::PayloadError),
+	#[error("URL signing isn't enabled")]
+	SigningDisabled,
+	#[error("Missing or invalid URL signature")]
+	SignatureInvalid,
+	#[error("Signed URL has expired")]
+	SignedUrlExpired,
+	#[error("Signed URLs can last from one second to {}", humantime::format_duration(*.0))]
+	SignedUrlTtl(Duration),
+	#[error("What's pulled with pass-through credentials can't be handed out as a signed URL")]
+	SignedUrlPrivate,
+	#[error("Tenant {tenant} isn't allowed to pull from {namespace}")]
+	NamespaceDenied { tenant: CompactString, namespace: CompactString },
+	#[error("This address only serves {served}, not {namespace}")]
+	NamespaceNotServed { namespace: CompactString, served: CompactString },
+	#[error("Tenant {0} is over its storage quota")]
+	QuotaExceeded(CompactString),
+	#[error("Refused by plugin {plugin}: {reason}")]
+	PolicyDenied { plugin: String, reason: String },
+	#[error("Manifest {0} isn't signed by any of the keys this namespace requires")]
+	SignatureRequired(String),
+	#[error("Overriding the upstream needs a valid X-Oci-Upstream-Token and an upstream host")]
+	UpstreamOverrideDenied,
+	#[error("Only requests by way of the CDN are served here")]
+	NotFromCdn,
+	#[error("Down for maintenance")]
+	Maintenance(Duration),
+	#[error("Give manifest_invalidation_time, blob_invalidation_time, or both; delete the override to go back to the configured ones")]
+	TtlUnset,
+	#[error("Request headers are over the {limit} byte limit")]
+	HeadersTooLarge { limit: usize },
+	#[error("Upload chunk is over the {limit} byte limit; send the blob in smaller chunks")]
+	UploadChunkTooLarge { limit: u64 }
+}
+
+/// Error codes from the OCI distribution spec, plus the `TAG_INVALID`, `UNAVAILABLE` and `UNKNOWN`
+/// codes that docker/distribution also uses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+	BlobUnknown,
+	BlobUploadUnknown,
+	DigestInvalid,
+	ManifestUnknown,
+	ManifestInvalid,
+	NameInvalid,
+	NameUnknown,
+	SizeInvalid,
+	TagInvalid,
+	Unauthorized,
+	Denied,
+	Toomanyrequests,
+	Unsupported,
+	Unavailable,
+	Unknown
 }
 
-impl actix_web::ResponseError for Error {
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+	errors: [ErrorEntry<'a>; 1]
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorEntry<'a> {
+	code: ErrorCode,
+	message: &'a str,
+	detail: ErrorDetail
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorDetail {
+	retryable: bool
+}
+
+impl Error {
+	/// Whether this error means upstream couldn't be reached, as opposed to upstream answering
+	/// with something we can relay to the client.
+	pub fn is_upstream_unavailable(&self) -> bool {
+		match self {
+			Self::Upstream(e) => circuit::is_unavailable(e),
+			Self::CircuitOpen(_) => true,
+			Self::RateLimited(_) => true,
+			Self::DeadlineExceeded(_) => true,
+			// Cache-only, so an expired copy is as good as it gets
+			Self::Maintenance(_) => true,
+			_ => false
+		}
+	}
+
+	/// Whether upstream refused the request because we (or the client) aren't authorized.
+	pub fn is_auth_failure(&self) -> bool {
+		matches!(
+			self,
+			Self::Upstream(Upstream::UnexpectedHttpStatus(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) | Upstream::Client { status: StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN })
+		)
+	}
+
+	/// Whether upstream answered with a 429.
+	pub fn is_upstream_rate_limit(&self) -> bool {
+		matches!(self, Self::Upstream(Upstream::UnexpectedHttpStatus(StatusCode::TOO_MANY_REQUESTS) | Upstream::Client { status: StatusCode::TOO_MANY_REQUESTS }))
+	}
+
+	pub fn is_not_found(&self) -> bool {
+		self.status_code() == StatusCode::NOT_FOUND
+	}
+
+	/// Replaces a generic "not found" with the more specific error the client expects for the kind
+	/// of object it asked for.
+	pub fn or_unknown(self, unknown: Self) -> Self {
+		match self.status_code() {
+			StatusCode::NOT_FOUND => unknown,
+			_ => self
+		}
+	}
+
+	/// Whether the client can reasonably expect the same request to succeed later.
+	pub fn is_retryable(&self) -> bool {
+		match self {
+			Self::Storage(e) => !e.is_not_found(),
+			Self::Upstream(e) => circuit::is_unavailable(e),
+			Self::ManifestUnknown | Self::BlobUnknown | Self::NameUnknown | Self::InvalidDigest => false,
+			Self::InvalidName(_) | Self::InvalidTag(_) => false,
+			Self::MissingContentLength => false,
+			Self::Io(_) => true,
+			Self::Json(_) => false,
+			Self::DataCorrupt(_) => true,
+			Self::CircuitOpen(_) => true,
+			Self::DownloadQueueFull(_) => true,
+			Self::RateLimited(_) => true,
+			Self::Unauthorized(_) => false,
+			Self::ForeignLayer(_) => true,
+			Self::DeadlineExceeded(_) => true,
+			Self::ManifestTooLarge { .. } => false,
+			Self::PinnedByConfig | Self::HoldNeedsTag | Self::CascadeNeedsDigest => false,
+			Self::AliasedByConfig | Self::InvalidAlias(_) | Self::AliasConflict(_) => false,
+			Self::Schema1Unsupported | Self::Schema1Conversion(_) => false,
+			Self::PushDisabled | Self::BlobUploadUnknown | Self::PushedManifestTooLarge { .. } => false,
+			Self::Push(_) => true,
+			Self::Payload(_) => true,
+			Self::SigningDisabled | Self::SignatureInvalid | Self::SignedUrlExpired | Self::SignedUrlTtl(_) | Self::SignedUrlPrivate => false,
+			Self::NamespaceDenied { .. } | Self::NamespaceNotServed { .. } => false,
+			// Cleanup may free up room
+			Self::QuotaExceeded(_) => true,
+			Self::PolicyDenied { .. } | Self::SignatureRequired(_) => false,
+			Self::UpstreamOverrideDenied | Self::NotFromCdn => false,
+			Self::Maintenance(_) => true,
+			Self::TtlUnset => false,
+			Self::HeadersTooLarge { .. } | Self::UploadChunkTooLarge { .. } => false
+		}
+	}
+
+	pub fn retry_after(&self) -> Option<Duration> {
+		match self {
+			Self::CircuitOpen(e) => Some(e.retry_after),
+			Self::DownloadQueueFull(e) => Some(e.retry_after),
+			Self::RateLimited(e) => Some(e.retry_after),
+			Self::Maintenance(retry_after) => Some(*retry_after),
+			_ if self.status_code() == StatusCode::TOO_MANY_REQUESTS => Some(UPSTREAM_RATE_LIMIT_RETRY_AFTER),
+			_ => None
+		}
+	}
+
+	pub fn code(&self) -> ErrorCode {
+		match self {
+			Self::ManifestTooLarge { .. } | Self::PushedManifestTooLarge { .. } | Self::Schema1Conversion(_) => return ErrorCode::ManifestInvalid,
+			Self::Schema1Unsupported | Self::PushDisabled | Self::SigningDisabled | Self::HoldNeedsTag | Self::CascadeNeedsDigest => return ErrorCode::Unsupported,
+			Self::SignedUrlTtl(_) | Self::TtlUnset => return ErrorCode::Unknown,
+			Self::Payload(_) => return ErrorCode::Unknown,
+			Self::HeadersTooLarge { .. } => return ErrorCode::Denied,
+			Self::UploadChunkTooLarge { .. } => return ErrorCode::SizeInvalid,
+			_ => ()
+		};
+		match self.status_code() {
+			StatusCode::NOT_FOUND => match self {
+				Self::ManifestUnknown => ErrorCode::ManifestUnknown,
+				Self::BlobUnknown => ErrorCode::BlobUnknown,
+				Self::BlobUploadUnknown => ErrorCode::BlobUploadUnknown,
+				_ => ErrorCode::NameUnknown
+			},
+			StatusCode::BAD_REQUEST => match self {
+				Self::InvalidDigest => ErrorCode::DigestInvalid,
+				Self::InvalidName(_) => ErrorCode::NameInvalid,
+				Self::InvalidTag(_) => ErrorCode::TagInvalid,
+				_ => ErrorCode::ManifestInvalid
+			},
+			StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+			StatusCode::FORBIDDEN | StatusCode::CONFLICT => ErrorCode::Denied,
+			StatusCode::TOO_MANY_REQUESTS => ErrorCode::Toomanyrequests,
+			StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => ErrorCode::Unavailable,
+			_ => ErrorCode::Unknown
+		}
+	}
+}
+
+/// Maps a status code returned by upstream onto the one we should return to our client.
+fn upstream_status(status: StatusCode) -> StatusCode {
+	match status {
+		StatusCode::NOT_FOUND | StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => status,
+		_ => StatusCode::BAD_GATEWAY
+	}
+}
+
+impl ResponseError for Error {
 	fn status_code(&self) -> StatusCode {
 		match self {
-			Self::Storage(e) => match e {
-				Storage::Io(e) if e.kind() == std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
-				Storage::RusotoGet(e) if matches!(e.as_ref(), &RusotoError::Service(GetObjectError::NoSuchKey(_))) => StatusCode::NOT_FOUND,
-				Storage::RusotoDelete(e) if matches!(e.as_ref(), &RusotoError::Unknown(BufferedHttpResponse { status: StatusCode::NOT_FOUND, .. })) => StatusCode::NOT_FOUND,
-				_ => StatusCode::INTERNAL_SERVER_ERROR
+			// Upstream's fault, surfacing through the blob's write
+			Self::Storage(Storage::LengthMismatch(_)) => StatusCode::BAD_GATEWAY,
+			Self::Storage(e) => match e.is_not_found() {
+				true => StatusCode::NOT_FOUND,
+				false => StatusCode::INTERNAL_SERVER_ERROR
 			},
 			Self::Upstream(e) => match e {
-				Upstream::UnexpectedHttpStatus(StatusCode::NOT_FOUND) => StatusCode::NOT_FOUND,
-				Upstream::UnexpectedHttpStatus(_) => StatusCode::INTERNAL_SERVER_ERROR,
-				Upstream::Client { status: StatusCode::NOT_FOUND } => StatusCode::NOT_FOUND,
-				Upstream::Client { .. } => StatusCode::INTERNAL_SERVER_ERROR,
-				_ => StatusCode::INTERNAL_SERVER_ERROR
+				Upstream::UnexpectedHttpStatus(status) => upstream_status(*status),
+				Upstream::Client { status } => upstream_status(*status),
+				Upstream::Reqwest(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+				_ => StatusCode::BAD_GATEWAY
 			},
-			Self::InvalidDigest => StatusCode::NOT_FOUND,
-			Self::MissingContentLength => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::ManifestUnknown => StatusCode::NOT_FOUND,
+			Self::BlobUnknown => StatusCode::NOT_FOUND,
+			Self::NameUnknown => StatusCode::NOT_FOUND,
+			Self::InvalidDigest => StatusCode::BAD_REQUEST,
+			Self::InvalidName(_) | Self::InvalidTag(_) => StatusCode::BAD_REQUEST,
+			Self::MissingContentLength => StatusCode::BAD_GATEWAY,
 			Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
 			Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
-			Self::DataCorrupt(_) => StatusCode::INTERNAL_SERVER_ERROR
+			Self::DataCorrupt(_) => StatusCode::BAD_GATEWAY,
+			Self::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+			Self::DownloadQueueFull(_) | Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+			Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+			Self::ForeignLayer(_) => StatusCode::BAD_GATEWAY,
+			Self::DeadlineExceeded(_) => StatusCode::GATEWAY_TIMEOUT,
+			Self::ManifestTooLarge { .. } => StatusCode::BAD_GATEWAY,
+			Self::PinnedByConfig => StatusCode::CONFLICT,
+			Self::HoldNeedsTag | Self::CascadeNeedsDigest => StatusCode::BAD_REQUEST,
+			Self::AliasedByConfig | Self::AliasConflict(_) => StatusCode::CONFLICT,
+			Self::InvalidAlias(_) => StatusCode::BAD_REQUEST,
+			Self::Schema1Unsupported => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+			Self::Schema1Conversion(_) => StatusCode::BAD_GATEWAY,
+			Self::PushDisabled => StatusCode::METHOD_NOT_ALLOWED,
+			Self::BlobUploadUnknown => StatusCode::NOT_FOUND,
+			Self::PushedManifestTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+			Self::Push(_) => StatusCode::BAD_GATEWAY,
+			Self::Payload(_) => StatusCode::BAD_REQUEST,
+			Self::SigningDisabled => StatusCode::NOT_FOUND,
+			Self::SignatureInvalid | Self::SignedUrlExpired | Self::SignedUrlPrivate => StatusCode::FORBIDDEN,
+			Self::SignedUrlTtl(_) => StatusCode::BAD_REQUEST,
+			Self::NamespaceNotServed { .. } => StatusCode::NOT_FOUND,
+			Self::NamespaceDenied { .. } | Self::QuotaExceeded(_) | Self::PolicyDenied { .. } | Self::SignatureRequired(_) | Self::UpstreamOverrideDenied | Self::NotFromCdn => StatusCode::FORBIDDEN,
+			Self::Maintenance(_) => StatusCode::SERVICE_UNAVAILABLE,
+			Self::TtlUnset => StatusCode::BAD_REQUEST,
+			Self::HeadersTooLarge { .. } => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+			Self::UploadChunkTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE
 		}
 	}
 
 	fn error_response(&self) -> HttpResponse<BoxBody> {
 		let status_code = self.status_code();
 		error!("{}: {}", status_code.as_u16(), self);
-		HttpResponseBuilder::new(status_code).body(self.to_string())
+		let mut response = HttpResponseBuilder::new(status_code);
+		if let Some(retry_after) = self.retry_after() {
+			response.insert_header((header::RETRY_AFTER, retry_after.as_secs().max(1)));
+		}
+		if let Self::Unauthorized(Some(challenge)) = self {
+			response.insert_header((header::WWW_AUTHENTICATE, challenge.clone()));
+		}
+		let message = self.to_string();
+		let body = ErrorBody {
+			errors: [ErrorEntry {
+				code: self.code(),
+				message: message.as_ref(),
+				detail: ErrorDetail { retryable: self.is_retryable() }
+			}]
+		};
+		response.json(body)
 	}
 }
 
 pub fn should_retry_without_namespace(err: &Upstream) -> bool {
 	matches!(err, dkregistry::errors::Error::Reqwest(_) | dkregistry::errors::Error::UnexpectedHttpStatus(_) | dkregistry::errors::Error::Client { .. })
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn upstream_status_passthrough() {
+		assert_eq!(upstream_status(StatusCode::NOT_FOUND), StatusCode::NOT_FOUND);
+		assert_eq!(upstream_status(StatusCode::UNAUTHORIZED), StatusCode::UNAUTHORIZED);
+		assert_eq!(upstream_status(StatusCode::FORBIDDEN), StatusCode::FORBIDDEN);
+		assert_eq!(upstream_status(StatusCode::TOO_MANY_REQUESTS), StatusCode::TOO_MANY_REQUESTS);
+		assert_eq!(upstream_status(StatusCode::INTERNAL_SERVER_ERROR), StatusCode::BAD_GATEWAY);
+		assert_eq!(upstream_status(StatusCode::BAD_REQUEST), StatusCode::BAD_GATEWAY);
+	}
+
+	#[test]
+	fn error_codes() {
+		assert_eq!(Error::ManifestUnknown.code(), ErrorCode::ManifestUnknown);
+		assert_eq!(Error::BlobUnknown.code(), ErrorCode::BlobUnknown);
+		assert_eq!(Error::InvalidDigest.code(), ErrorCode::DigestInvalid);
+		assert_eq!(Error::InvalidName("Library/Alpine".into()).code(), ErrorCode::NameInvalid);
+		assert_eq!(Error::InvalidTag(".latest".into()).code(), ErrorCode::TagInvalid);
+		assert_eq!(Error::Upstream(Upstream::UnexpectedHttpStatus(StatusCode::TOO_MANY_REQUESTS)).code(), ErrorCode::Toomanyrequests);
+		assert_eq!(Error::Upstream(Upstream::UnexpectedHttpStatus(StatusCode::NOT_FOUND)).or_unknown(Error::ManifestUnknown).code(), ErrorCode::ManifestUnknown);
+		assert_eq!(Error::Upstream(Upstream::Client { status: StatusCode::SERVICE_UNAVAILABLE }).code(), ErrorCode::Unavailable);
+		assert_eq!(Error::Schema1Unsupported.code(), ErrorCode::Unsupported);
+		assert_eq!(Error::BlobUploadUnknown.code(), ErrorCode::BlobUploadUnknown);
+		assert_eq!(Error::PushDisabled.code(), ErrorCode::Unsupported);
+		assert_eq!(Error::SignedUrlExpired.code(), ErrorCode::Denied);
+		assert_eq!(Error::QuotaExceeded("team-a".into()).code(), ErrorCode::Denied);
+		assert_eq!(Error::NamespaceNotServed { namespace: "ghcr.io".into(), served: "docker.io".into() }.code(), ErrorCode::NameUnknown);
+	}
+
+	#[test]
+	fn rate_limit_sets_retry_after() {
+		let error = Error::Upstream(Upstream::Client { status: StatusCode::TOO_MANY_REQUESTS });
+		assert_eq!(error.retry_after(), Some(UPSTREAM_RATE_LIMIT_RETRY_AFTER));
+		assert!(error.is_retryable());
+		assert_eq!(Error::ManifestUnknown.retry_after(), None);
+
+		let error = Error::DownloadQueueFull(DownloadQueueFull { namespace: "docker.io".into(), retry_after: Duration::from_secs(10) });
+		assert_eq!((error.status_code(), error.code(), error.retry_after()), (StatusCode::TOO_MANY_REQUESTS, ErrorCode::Toomanyrequests, Some(Duration::from_secs(10))));
+
+		// Upstream's own Retry-After, passed on
+		let error = Error::RateLimited(RateLimited { namespace: "docker.io".into(), retry_after: Duration::from_secs(90) });
+		assert_eq!((error.status_code(), error.retry_after(), error.is_upstream_unavailable()), (StatusCode::TOO_MANY_REQUESTS, Some(Duration::from_secs(90)), true));
+		assert!(Error::Upstream(Upstream::Client { status: StatusCode::TOO_MANY_REQUESTS }).is_upstream_rate_limit());
+	}
+}
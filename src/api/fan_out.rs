@@ -0,0 +1,94 @@
+//! How much work the cache saves upstream, for making the case for what it costs to run:  bytes of
+//! blobs served from cache rather than fetched, and how often a blob fetched from upstream was
+//! wanted by more than one pull at once.  Each such pull currently fetches the blob for itself, so
+//! the fan-out is how many pulls a fetch could have served, were they to share it, and the
+//! duplicates are what that would save.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::exponential_buckets;
+use prometheus::register_histogram_vec;
+use prometheus::register_int_counter_vec;
+use prometheus::HistogramVec;
+use prometheus::IntCounterVec;
+
+static SAVED: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_bytes_saved", "Bytes of blobs served from cache rather than fetched from upstream", &["namespace"]).unwrap());
+static FETCHED: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_bytes_fetched", "Bytes of blobs fetched from upstream", &["namespace"]).unwrap());
+static DUPLICATES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_duplicate_fetches", "Number of blobs fetched from upstream while the same blob was already being fetched", &["namespace"]).unwrap());
+static DUPLICATE_BYTES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_duplicate_fetch_bytes", "Bytes of blobs fetched from upstream while the same blob was already being fetched", &["namespace"]).unwrap());
+static FAN_OUT: Lazy<HistogramVec> = Lazy::new(|| register_histogram_vec!("upstream_fetch_fan_out", "Number of pulls that wanted a blob while it was being fetched from upstream, counted once its last fetch is done", &["namespace"], exponential_buckets(1.0, 2.0, 8).unwrap()).unwrap());
+
+/// The fetches of each blob in progress, by storage path:  how many are, and how many there have
+/// been since the first of them started.
+static FETCHING: Lazy<Mutex<HashMap<String, (usize, usize)>>> = Lazy::new(Default::default);
+
+/// Counts `bytes` of a blob served from cache in `namespace`.
+pub(super) fn served_from_cache(namespace: &str, bytes: u64) {
+	SAVED.with_label_values(&[namespace]).inc_by(bytes);
+}
+
+/// Held for as long as a blob is being read from upstream.
+pub(super) struct Fetch {
+	namespace: CompactString,
+	storage_path: String
+}
+
+impl Fetch {
+	pub(super) fn start(namespace: &str, storage_path: &str, bytes: u64) -> Self {
+		FETCHED.with_label_values(&[namespace]).inc_by(bytes);
+		let mut fetching = FETCHING.lock().unwrap();
+		let (active, total) = fetching.entry(storage_path.to_owned()).or_default();
+		if (*active > 0) {
+			DUPLICATES.with_label_values(&[namespace]).inc();
+			DUPLICATE_BYTES.with_label_values(&[namespace]).inc_by(bytes);
+		}
+		*active += 1;
+		*total += 1;
+		Self { namespace: namespace.into(), storage_path: storage_path.to_owned() }
+	}
+}
+
+impl Drop for Fetch {
+	fn drop(&mut self) {
+		let mut fetching = FETCHING.lock().unwrap();
+		let Some((active, total)) = fetching.get_mut(&self.storage_path) else {
+			return;
+		};
+		*active -= 1;
+		if (*active == 0) {
+			FAN_OUT.with_label_values(&[self.namespace.as_str()]).observe(*total as f64);
+			fetching.remove(&self.storage_path);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn overlapping_fetches_count_as_one_fan_out() {
+		let namespace = "fan-out-test";
+		let path = "blobs/sha256/fa/nout";
+		let samples = || FAN_OUT.with_label_values(&[namespace]).get_sample_count();
+
+		let first = Fetch::start(namespace, path, 100);
+		let second = Fetch::start(namespace, path, 100);
+		drop(first);
+		let third = Fetch::start(namespace, path, 100);
+		assert_eq!(DUPLICATES.with_label_values(&[namespace]).get(), 2);
+		assert_eq!(DUPLICATE_BYTES.with_label_values(&[namespace]).get(), 200);
+		drop(second);
+		assert_eq!(samples(), 0);
+		drop(third);
+		assert_eq!((samples(), FAN_OUT.with_label_values(&[namespace]).get_sample_sum()), (1, 3.0));
+
+		// Once they're all done, the next one starts over
+		drop(Fetch::start(namespace, path, 100));
+		assert_eq!((samples(), FAN_OUT.with_label_values(&[namespace]).get_sample_sum()), (2, 4.0));
+		assert_eq!(FETCHED.with_label_values(&[namespace]).get(), 400);
+	}
+}
@@ -0,0 +1,169 @@
+use std::iter;
+use std::str::FromStr;
+
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::LocalBoxStream;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::warn;
+
+use crate::api::error::Error;
+use crate::api::trace;
+use crate::api::trace::Hop;
+use crate::image::ImageReference;
+use crate::storage::Repository;
+
+/// Foreign (non-distributable) layer media types, and the distributable equivalent we rewrite them
+/// to when serving them ourselves.
+const FOREIGN_MEDIA_TYPES: &[(&str, &str)] = &[
+	("application/vnd.docker.image.rootfs.foreign.diff.tar.gzip", "application/vnd.docker.image.rootfs.diff.tar.gzip"),
+	("application/vnd.oci.image.layer.nondistributable.v1.tar", "application/vnd.oci.image.layer.v1.tar"),
+	("application/vnd.oci.image.layer.nondistributable.v1.tar+gzip", "application/vnd.oci.image.layer.v1.tar+gzip"),
+	("application/vnd.oci.image.layer.nondistributable.v1.tar+zstd", "application/vnd.oci.image.layer.v1.tar+zstd")
+];
+
+pub(super) fn distributable_media_type(media_type: &str) -> Option<&'static str> {
+	FOREIGN_MEDIA_TYPES.iter().find(|(foreign, _)| *foreign == media_type).map(|(_, distributable)| *distributable)
+}
+
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+pub struct ForeignLayer {
+	#[serde(rename = "mediaType")]
+	media_type: String,
+	digest: String,
+	#[serde(default)]
+	urls: Vec<String>
+}
+
+#[derive(Debug, Deserialize)]
+struct Layers {
+	#[serde(default)]
+	layers: Vec<ForeignLayer>
+}
+
+/// Returns the layers in an image manifest that must be fetched from somewhere other than the
+/// registry.  Manifests that aren't image manifests simply have none, and layers whose digests
+/// aren't digests are left out, as their URLs are stored under them.
+pub fn foreign_layers(manifest: &[u8]) -> Vec<ForeignLayer> {
+	let Ok(parsed) = serde_json::from_slice::<Layers>(manifest) else {
+		return Vec::new();
+	};
+	parsed.layers.into_iter().filter(|l| distributable_media_type(&l.media_type).is_some() && !l.urls.is_empty() && matches!(ImageReference::from_str(&l.digest), Ok(ImageReference::Sha256(_)))).collect()
+}
+
+/// Rewrites a manifest so that its foreign layers look like ordinary ones, to be pulled from us.
+/// Returns the new manifest and its digest, or `None` if there was nothing to rewrite.
+pub fn rewrite(manifest: &[u8]) -> Option<(Bytes, String)> {
+	let mut parsed: Value = serde_json::from_slice(manifest).ok()?;
+	let mut changed = false;
+	for layer in parsed.get_mut("layers")?.as_array_mut()? {
+		let Some(layer) = layer.as_object_mut() else {
+			continue;
+		};
+		let Some(media_type) = layer.get("mediaType").and_then(Value::as_str).and_then(distributable_media_type) else {
+			continue;
+		};
+		layer.insert("mediaType".into(), media_type.into());
+		layer.remove("urls");
+		changed = true;
+	}
+	if (!changed) {
+		return None;
+	}
+	let body = serde_json::to_vec(&parsed).ok()?;
+	let digest = format!("sha256:{}", hex::encode(Sha256::digest(&body)));
+	Some((body.into(), digest))
+}
+
+fn storage_path(digest: &str) -> String {
+	let (method, hash) = digest.split_once(':').unwrap_or(("_", digest));
+	let hash_prefix = hash.get(..2).unwrap_or("_");
+	let rest_of_hash = hash.get(2..).unwrap_or(hash);
+	format!("foreign/{method}/{hash_prefix}/{rest_of_hash}")
+}
+
+/// Remembers where a manifest's foreign layers can be found, so that blob requests for them can be
+/// served even though upstream doesn't have them.
+pub async fn record(repo: &Repository, layers: &[ForeignLayer]) {
+	for layer in layers {
+		let body = match serde_json::to_vec(&layer.urls) {
+			Ok(v) => Bytes::from(v),
+			Err(_) => continue
+		};
+		let len = body.len().try_into().unwrap_or(i64::MAX);
+		if let Err(error) = repo.write(&storage_path(&layer.digest), futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body))), len).await {
+			warn!(digest = layer.digest.as_str(), %error, "Failed to record foreign layer URLs");
+		}
+	}
+}
+
+pub async fn lookup(repo: &Repository, digest: &str) -> Result<Option<Vec<String>>, Error> {
+	let stream = match repo.read(&storage_path(digest), core::time::Duration::MAX).await {
+		Ok(v) => v,
+		Err(e) if e.is_not_found() => return Ok(None),
+		Err(e) => return Err(e.into())
+	};
+	let body = stream.into_inner().try_collect::<BytesMut>().await?;
+	Ok(Some(serde_json::from_slice(body.as_ref())?))
+}
+
+/// Fetches a foreign layer from the first of its URLs that works.
+pub async fn fetch(http: &reqwest::Client, urls: &[String], hop: &Hop) -> Result<(u64, LocalBoxStream<'static, Result<Bytes, crate::storage::Error>>), Error> {
+	let mut last_error = None;
+	for url in urls.iter().filter(|u| u.starts_with("https://") || u.starts_with("http://")) {
+		let response = match trace::inject(http.get(url), hop).send().await.and_then(|r| r.error_for_status()) {
+			Ok(v) => v,
+			Err(error) => {
+				warn!(url = url.as_str(), %error, "Failed to fetch foreign layer");
+				last_error = Some(error);
+				continue;
+			}
+		};
+		let len = response.content_length().ok_or(Error::MissingContentLength)?;
+		let stream = response.bytes_stream().map_err(|e| crate::storage::Error::from(std::io::Error::new(std::io::ErrorKind::Other, e)));
+		return Ok((len, stream.boxed_local()));
+	}
+	Err(last_error.map(Error::ForeignLayer).unwrap_or(Error::BlobUnknown))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MANIFEST: &str = r#"{
+		"schemaVersion": 2,
+		"mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+		"config": {"mediaType": "application/vnd.docker.container.image.v1+json", "size": 1, "digest": "sha256:aa"},
+		"layers": [
+			{"mediaType": "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip", "size": 2, "digest": "sha256:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb", "urls": ["https://mcr.microsoft.com/v2/windows/servercore/blobs/sha256:bb"]},
+			{"mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "size": 3, "digest": "sha256:cc"}
+		]
+	}"#;
+
+	#[test]
+	fn finds_foreign_layers() {
+		let layers = foreign_layers(MANIFEST.as_bytes());
+		assert_eq!(layers.len(), 1);
+		assert_eq!(layers[0].digest, "sha256:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb");
+		assert_eq!(layers[0].urls, ["https://mcr.microsoft.com/v2/windows/servercore/blobs/sha256:bb"]);
+
+		let traversal = MANIFEST.replace("sha256:bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb", "sha256:../../../pins.json");
+		assert!(foreign_layers(traversal.as_bytes()).is_empty());
+	}
+
+	#[test]
+	fn rewrites_foreign_layers() {
+		let (body, digest) = rewrite(MANIFEST.as_bytes()).unwrap();
+		assert!(foreign_layers(&body).is_empty());
+		let parsed: Value = serde_json::from_slice(&body).unwrap();
+		assert_eq!(parsed["layers"][0]["mediaType"], "application/vnd.docker.image.rootfs.diff.tar.gzip");
+		assert!(parsed["layers"][0].get("urls").is_none());
+		assert_eq!(digest, format!("sha256:{}", hex::encode(Sha256::digest(&body))));
+		assert!(rewrite(&body).is_none());
+	}
+}
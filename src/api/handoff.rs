@@ -0,0 +1,287 @@
+//! Handing off to a new process on restart, without a blip of failed pulls or downloads thrown away
+//! half done.  Both processes bind their listeners with `SO_REUSEPORT`, so the new one can start
+//! accepting connections while the old one is still up, and the two talk over a Unix socket, the
+//! state socket:  once it's listening, the new process tells the old one to take over from it, and
+//! the old one stops accepting connections, finishes the pulls it's serving, and says which blobs
+//! it's still filling, and when each is done.  A pull of one of those that reaches the new process
+//! meanwhile waits for it to land in storage instead of fetching it from upstream as well.
+//!
+//! The protocol is lines of text:  `takeover` from the new process; `filling <path>` for each
+//! blob, by storage path, then `ready`, then `filled <path>` as each is done, from the old one.
+//! When the old process exits, whatever it hadn't finished is fetched as usual.
+
+use core::time::Duration;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use actix_web::dev::ServerHandle;
+use actix_web::rt;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter;
+use prometheus::IntCounter;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpSocket;
+use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+use tokio::sync::broadcast;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use tokio::time::timeout_at;
+use tokio::time::Instant;
+use tracing::info;
+use tracing::warn;
+
+static WAITS: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("blob_handoff_waits", "Number of blob pulls that waited for the process this one took over from to fill the blob").unwrap());
+
+/// How long the old process gets to say what it's filling before the new one starts serving
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The blobs being filled here, by storage path, with how many fills of each there are.
+static FILLING: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(Default::default);
+/// The storage path of each blob as its last fill here finishes.
+static FILLED: Lazy<broadcast::Sender<String>> = Lazy::new(|| broadcast::channel(1024).0);
+
+/// Held for as long as a blob is being written to storage from upstream.
+pub(super) struct Filling(String);
+
+impl Filling {
+	pub(super) fn start(storage_path: &str) -> Self {
+		*FILLING.lock().unwrap().entry(storage_path.to_owned()).or_default() += 1;
+		Self(storage_path.to_owned())
+	}
+}
+
+impl Drop for Filling {
+	fn drop(&mut self) {
+		let mut filling = FILLING.lock().unwrap();
+		let Some(count) = filling.get_mut(&self.0) else {
+			return;
+		};
+		*count -= 1;
+		if (*count == 0) {
+			filling.remove(&self.0);
+			let _ = FILLED.send(self.0.clone());
+		}
+	}
+}
+
+/// What the process this one took over from is still filling.
+#[derive(Default)]
+pub struct Handoff {
+	pending: Mutex<HashSet<String>>,
+	changed: Notify
+}
+
+impl Handoff {
+	fn insert(&self, storage_path: &str) {
+		self.pending.lock().unwrap().insert(storage_path.to_owned());
+	}
+
+	fn remove(&self, storage_path: &str) {
+		self.pending.lock().unwrap().remove(storage_path);
+		self.changed.notify_waiters();
+	}
+
+	fn clear(&self) {
+		self.pending.lock().unwrap().clear();
+		self.changed.notify_waiters();
+	}
+
+	/// Waits, until `deadline` at the latest, for the process this one took over from to finish
+	/// filling the blob at `storage_path`; whether it was filling it, and so whether it's worth
+	/// looking in storage again.
+	pub(super) async fn wait(&self, storage_path: &str, deadline: Instant) -> bool {
+		let mut waited = false;
+		loop {
+			// Created before looking, so as not to miss the change that's looked for
+			let changed = self.changed.notified();
+			if (!self.pending.lock().unwrap().contains(storage_path)) {
+				return waited;
+			}
+			if (!waited) {
+				WAITS.inc();
+				waited = true;
+			}
+			if (timeout_at(deadline, changed).await.is_err()) {
+				return waited;
+			}
+		}
+	}
+}
+
+/// Binds `addr` with `SO_REUSEPORT`, for a process taking over to bind alongside.
+pub fn reuse_port_listeners(addr: impl ToSocketAddrs, backlog: u32) -> std::io::Result<Vec<std::net::TcpListener>> {
+	let mut listeners = Vec::new();
+	for addr in addr.to_socket_addrs()? {
+		let socket = match addr.is_ipv4() {
+			true => TcpSocket::new_v4()?,
+			false => TcpSocket::new_v6()?
+		};
+		socket.set_reuseaddr(true)?;
+		socket.set_reuseport(true)?;
+		socket.bind(addr)?;
+		listeners.push(socket.listen(backlog)?.into_std()?);
+	}
+	Ok(listeners)
+}
+
+/// Tells the process listening on the state socket at `path`, if there is one, to hand off to this
+/// one, which should be listening by now, and follows what it's still filling from then on.
+pub async fn take_over(path: &Path, handoff: Arc<Handoff>) {
+	let stream = match UnixStream::connect(path).await {
+		Ok(v) => v,
+		Err(error) => {
+			info!(path = %path.display(), %error, "No process to take over from");
+			return;
+		}
+	};
+	info!(path = %path.display(), "Taking over from running process");
+	follow(stream, handoff).await;
+}
+
+async fn follow<S>(stream: S, handoff: Arc<Handoff>)
+where
+	S: AsyncRead + AsyncWrite + Unpin + 'static
+{
+	let (read, mut write) = tokio::io::split(stream);
+	if let Err(error) = write.write_all(b"takeover\n").await {
+		warn!(%error, "Failed to ask running process to hand off");
+		return;
+	}
+	let mut lines = BufReader::new(read).lines();
+	let ready = timeout(READY_TIMEOUT, async {
+		while let Ok(Some(line)) = lines.next_line().await {
+			match line.split_once(' ') {
+				Some(("filling", storage_path)) => handoff.insert(storage_path),
+				_ if line == "ready" => return true,
+				_ => ()
+			};
+		}
+		false
+	});
+	if (!ready.await.unwrap_or(false)) {
+		warn!("Running process didn't say what it's filling; fetching whatever it was from upstream");
+		handoff.clear();
+		return;
+	}
+	rt::spawn(async move {
+		let _write = write;
+		while let Ok(Some(line)) = lines.next_line().await {
+			if let Some(("filled", storage_path)) = line.split_once(' ') {
+				handoff.remove(storage_path);
+			}
+		}
+		// It's exited, and what it didn't finish never will be
+		handoff.clear();
+	});
+}
+
+/// Listens on the state socket at `path` for a process to take over from this one, and hands off
+/// to it:  stops `server` accepting connections, leaving it to finish what it's serving, and says
+/// which blobs are still being filled, and when each is done.  Runs until the task is dropped.
+pub async fn serve(path: PathBuf, server: ServerHandle) {
+	// Whatever was listening here has either exited or been told to hand off already
+	let _ = std::fs::remove_file(&path);
+	let listener = match UnixListener::bind(&path) {
+		Ok(v) => v,
+		Err(error) => {
+			warn!(path = %path.display(), %error, "Failed to listen on handoff socket; restarts won't be handed off");
+			return;
+		}
+	};
+	loop {
+		let stream = match listener.accept().await {
+			Ok((stream, _)) => stream,
+			Err(error) => {
+				warn!(%error, "Failed to accept connection on handoff socket");
+				continue;
+			}
+		};
+		let server = server.clone();
+		rt::spawn(async move {
+			if let Err(error) = hand_off(stream, move || rt::spawn(server.stop(true))).await {
+				warn!(%error, "Failed to hand off to new process");
+			}
+		});
+	}
+}
+
+async fn hand_off<S, F, T>(stream: S, stop: F) -> std::io::Result<()>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+	F: FnOnce() -> T
+{
+	let (read, mut write) = tokio::io::split(stream);
+	let mut lines = BufReader::new(read).lines();
+	if (lines.next_line().await?.as_deref() != Some("takeover")) {
+		return Ok(());
+	}
+	info!("Handing off to new process; no longer accepting connections");
+	// Subscribed before looking, so as not to miss a fill finishing in between
+	let mut filled = FILLED.subscribe();
+	let mut filling = FILLING.lock().unwrap().keys().cloned().collect::<HashSet<_>>();
+	stop();
+	let mut message = String::new();
+	for storage_path in filling.iter() {
+		message.push_str(&format!("filling {storage_path}\n"));
+	}
+	message.push_str("ready\n");
+	write.write_all(message.as_bytes()).await?;
+	while (!filling.is_empty()) {
+		let storage_path = match filled.recv().await {
+			Ok(v) => v,
+			// Missed some; the new process fetches whatever they were for itself
+			Err(_) => break
+		};
+		if (filling.remove(&storage_path)) {
+			write.write_all(format!("filled {storage_path}\n").as_bytes()).await?;
+		}
+	}
+	info!("Finished filling blobs for new process");
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[actix_web::test]
+	async fn hands_off_fills() {
+		let path = "blobs/sha256/ha/ndoff";
+		let fill = Filling::start(path);
+		let second = Filling::start(path);
+		let handoff = Arc::new(Handoff::default());
+		let (old, new) = UnixStream::pair().unwrap();
+		let stopped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+		let old = rt::spawn({
+			let stopped = stopped.clone();
+			async move { hand_off(old, || stopped.store(true, std::sync::atomic::Ordering::Relaxed)).await.unwrap() }
+		});
+		follow(new, handoff.clone()).await;
+		assert!(stopped.load(std::sync::atomic::Ordering::Relaxed));
+		assert!(handoff.pending.lock().unwrap().contains(path));
+
+		// Still filling, until the last fill of it drops
+		let deadline = Instant::now() + Duration::from_secs(5);
+		let waiter = rt::spawn({
+			let handoff = handoff.clone();
+			async move { handoff.wait(path, deadline).await }
+		});
+		tokio::task::yield_now().await;
+		drop(second);
+		assert!(!waiter.is_finished());
+		drop(fill);
+		assert!(waiter.await.unwrap());
+		old.await.unwrap();
+		assert!(!handoff.wait(path, deadline).await);
+	}
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+
+use actix_web::body::SizedStream;
+use actix_web::http;
+use actix_web::web;
+use actix_web::HttpResponse;
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::warn;
+
+use super::blob_storage_path;
+use super::error::Error;
+use super::RequestConfig;
+use crate::api::auth::Access;
+use crate::storage::Repository;
+
+const HELM_CONFIG_MEDIA_TYPE: &str = "application/vnd.cncf.helm.config.v1+json";
+const HELM_CHART_MEDIA_TYPE: &str = "application/vnd.cncf.helm.chart.content.v1.tar+gzip";
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+	#[serde(rename = "mediaType")]
+	media_type: String,
+	digest: String
+}
+
+#[derive(Debug, Deserialize)]
+struct ChartManifest {
+	config: Descriptor,
+	#[serde(default)]
+	layers: Vec<Descriptor>
+}
+
+/// A classic Helm repository index.
+#[derive(Debug, Serialize)]
+struct Index {
+	#[serde(rename = "apiVersion")]
+	api_version: &'static str,
+	entries: BTreeMap<String, Vec<Map<String, Value>>>,
+	generated: String
+}
+
+async fn read_all(repo: &Repository, path: &str) -> Result<BytesMut, Error> {
+	let stream = repo.read(path, core::time::Duration::MAX).await?;
+	Ok(stream.into_inner().try_collect::<BytesMut>().await?)
+}
+
+/// Builds an index entry for a cached manifest, if it's a Helm chart whose metadata and tarball we
+/// also have cached.
+async fn entry(repo: &Repository, manifest: &[u8]) -> Result<Option<Map<String, Value>>, Error> {
+	let Ok(chart) = serde_json::from_slice::<ChartManifest>(manifest) else {
+		return Ok(None);
+	};
+	if (chart.config.media_type != HELM_CONFIG_MEDIA_TYPE) {
+		return Ok(None);
+	}
+	let Some(tarball) = chart.layers.iter().find(|l| l.media_type == HELM_CHART_MEDIA_TYPE) else {
+		return Ok(None);
+	};
+	let Some(hash) = tarball.digest.strip_prefix("sha256:") else {
+		return Ok(None);
+	};
+	if let Err(e) = repo.read(&blob_storage_path(&tarball.digest, &Access::Shared), core::time::Duration::MAX).await {
+		return match e.is_not_found() {
+			true => Ok(None),
+			false => Err(e.into())
+		};
+	}
+	let metadata = match read_all(repo, &blob_storage_path(&chart.config.digest, &Access::Shared)).await {
+		Ok(v) => v,
+		Err(e) if e.is_not_found() => return Ok(None),
+		Err(e) => return Err(e)
+	};
+	// The config blob is the chart's Chart.yaml, as JSON
+	let Ok(mut entry) = serde_json::from_slice::<Map<String, Value>>(metadata.as_ref()) else {
+		return Ok(None);
+	};
+	let (Some(name), Some(version)) = (entry.get("name").and_then(Value::as_str), entry.get("version").and_then(Value::as_str)) else {
+		return Ok(None);
+	};
+	let url = format!("charts/{}/{name}-{version}.tgz", tarball.digest);
+	entry.insert("digest".into(), hash.into());
+	entry.insert("urls".into(), vec![Value::from(url)].into());
+	Ok(Some(entry))
+}
+
+/// Registers the classic Helm chart repository under `/_helm`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+	cfg.service(
+		web::scope("/_helm")
+			.wrap(super::logger())
+			.route("/index.yaml", web::get().to(index))
+			.route("/charts/{digest}/{filename}", web::get().to(chart))
+	);
+}
+
+/// Serves an `index.yaml` listing every Helm chart in the cache.
+pub async fn index(config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let mut entries: BTreeMap<String, Vec<Map<String, Value>>> = BTreeMap::new();
+	for path in config.repo.list_manifests().await? {
+		// Charts pulled with someone else's credentials, or by a tenant, aren't ours to advertise
+		if (path.contains("/_private/") || path.contains("/_tenant/")) {
+			continue;
+		}
+		let manifest = match config.repo.read_manifest(&path, core::time::Duration::MAX).await {
+			Ok((_, body)) => body.into_inner().try_collect::<BytesMut>().await?,
+			Err(error) => {
+				warn!(path = path.as_str(), %error, "Unreadable manifest in storage");
+				continue;
+			}
+		};
+		let Some(entry) = entry(&config.repo, manifest.as_ref()).await? else {
+			continue;
+		};
+		let name = entry.get("name").and_then(Value::as_str).unwrap_or_default().to_owned();
+		let versions = entries.entry(name).or_default();
+		// The same chart is usually cached under both its tag and its digest
+		if (!versions.iter().any(|v| v.get("digest") == entry.get("digest"))) {
+			versions.push(entry);
+		}
+	}
+	let index = Index {
+		api_version: "v1",
+		entries,
+		generated: OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default()
+	};
+	let body = serde_yaml::to_string(&index).map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+	Ok(HttpResponse::Ok().insert_header((http::header::CONTENT_TYPE, "application/x-yaml")).body(body))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChartRequest {
+	digest: String,
+	#[allow(dead_code)] // Only there so that Helm sees a sensible filename
+	filename: String
+}
+
+/// Serves a chart tarball straight out of blob storage.
+pub async fn chart(req: web::Path<ChartRequest>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	if (!req.digest.starts_with("sha256:")) {
+		return Err(Error::InvalidDigest);
+	}
+	let stream = config.repo.read(&blob_storage_path(&req.digest, &Access::Shared), core::time::Duration::MAX).await.map_err(|e| Error::from(e).or_unknown(Error::BlobUnknown))?;
+	Ok(HttpResponse::Ok().insert_header((http::header::CONTENT_TYPE, "application/gzip")).body(SizedStream::new(stream.length(), stream.into_inner())))
+}
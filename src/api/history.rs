@@ -0,0 +1,164 @@
+//! Tag history:  each time a tag is fetched from upstream and points somewhere new, the digest
+//! upstream gave for it is recorded, with when it was first seen there, so that `/_admin/history`
+//! can say when `latest` moved and what to while an incident is picked apart.  Only the last
+//! [`MAX_ENTRIES`] moves of each tag are kept, and only moves seen from here count:  a tag that moved
+//! twice between two fetches shows one move.
+
+use std::iter;
+
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::warn;
+
+use super::error::Error;
+use super::manifest_storage_dir;
+use super::split_image;
+use super::Access;
+use super::ManifestQueryString;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::image::ImageReference;
+use crate::storage::Repository;
+
+const INDEX_PREFIX: &str = "history/";
+
+/// How many of a tag's digests are remembered.
+const MAX_ENTRIES: usize = 20;
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct Entry {
+	/// What upstream said the tag pointed at
+	digest: String,
+	/// What it was served as, when rewriting changed it
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	served_digest: Option<String>,
+	/// Whether the manifest only differed from the one before in its annotations
+	#[serde(default, skip_serializing_if = "core::ops::Not::not")]
+	annotations_only: bool,
+	/// When it was first seen pointing there
+	seen: String
+}
+
+/// A tag's digests, oldest first.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct History {
+	entries: Vec<Entry>
+}
+
+impl History {
+	/// Adds `entry`, unless the tag was already known to point there; returns whether it did.
+	fn push(&mut self, entry: Entry) -> bool {
+		if (self.entries.last().is_some_and(|last| last.digest == entry.digest && last.served_digest == entry.served_digest)) {
+			return false;
+		}
+		self.entries.push(entry);
+		if (self.entries.len() > MAX_ENTRIES) {
+			self.entries.drain(..self.entries.len() - MAX_ENTRIES);
+		}
+		true
+	}
+}
+
+/// Where a tag's history is kept, given where its image's manifests are stored.
+fn index_path(manifest_dir: &str, tag: &str) -> String {
+	let dir = manifest_dir.strip_prefix("manifests/").unwrap_or(manifest_dir);
+	format!("{INDEX_PREFIX}{dir}/{tag}")
+}
+
+async fn read(repo: &Repository, path: &str) -> Result<History, Error> {
+	let body = match repo.read(path, core::time::Duration::MAX).await {
+		Ok(stream) => stream.into_inner().try_collect::<BytesMut>().await?,
+		Err(e) if e.is_not_found() => return Ok(History::default()),
+		Err(e) => return Err(e.into())
+	};
+	Ok(serde_json::from_slice(&body)?)
+}
+
+/// Records what a tag pointed at when it was just fetched from upstream:  `upstream` as upstream
+/// sent it, and `served` as it was cached, and whether it only moved for new annotations.
+/// Manifests cached for pass-through credentials are private to them, and left out.  Only fails in
+/// the logs.
+pub async fn record(repo: &Repository, manifest_dir: &str, tag: &str, upstream: &[u8], upstream_digest: Option<&str>, served_digest: Option<&str>, annotations_only: bool, access: &Access) {
+	if (matches!(access, Access::Private(_))) {
+		return;
+	}
+	let digest = match upstream_digest {
+		Some(digest) => digest.to_owned(),
+		None => format!("sha256:{}", hex::encode(Sha256::digest(upstream)))
+	};
+	let path = index_path(manifest_dir, tag);
+	let mut history = match read(repo, &path).await {
+		Ok(v) => v,
+		// Starting over beats never recording anything again
+		Err(error) => {
+			warn!(path, %error, "Replacing unreadable tag history");
+			History::default()
+		}
+	};
+	let seen = OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default();
+	if (!history.push(Entry { served_digest: served_digest.filter(|served| *served != digest).map(str::to_owned), digest, annotations_only, seen })) {
+		return;
+	}
+	let body = match serde_json::to_vec(&history) {
+		Ok(v) => Bytes::from(v),
+		Err(_) => return
+	};
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	if let Err(error) = repo.write(&path, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body))), len).await {
+		warn!(path, %error, "Failed to record tag history");
+	}
+}
+
+/// Lists the digests a tag has pointed at in the caller's cache, newest first.
+pub async fn history(http_req: HttpRequest, req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let ImageReference::Tag(tag) = &req.reference else {
+		return Err(Error::ManifestUnknown);
+	};
+	let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns(None));
+	let access = config.admin_access(&http_req)?;
+	let history = read(&config.repo, &index_path(&manifest_storage_dir(namespace, image, &access), tag)).await?;
+	Ok(HttpResponse::Ok().json(serde_json::json!({
+		"namespace": namespace,
+		"image": image,
+		"tag": tag.as_str(),
+		"history": history.entries.iter().rev().collect::<Vec<_>>()
+	})))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn entry(digest: &str) -> Entry {
+		Entry { digest: digest.into(), served_digest: None, annotations_only: false, seen: String::new() }
+	}
+
+	#[test]
+	fn moves() {
+		let mut history = History::default();
+		assert!(history.push(entry("sha256:aa")));
+		assert!(!history.push(entry("sha256:aa")));
+		assert!(history.push(Entry { served_digest: Some("sha256:ff".into()), ..entry("sha256:aa") }));
+		assert!(history.push(entry("sha256:bb")));
+		// Moving back counts as a move
+		assert!(history.push(entry("sha256:aa")));
+		assert_eq!(history.entries.len(), 4);
+
+		for i in 0..MAX_ENTRIES {
+			history.push(entry(&format!("sha256:{i}")));
+		}
+		assert_eq!(history.entries.len(), MAX_ENTRIES);
+		assert_eq!(history.entries[0].digest, "sha256:0");
+		assert_eq!(index_path("manifests/docker.io/library/alpine", "3.19"), "history/docker.io/library/alpine/3.19");
+	}
+}
@@ -0,0 +1,224 @@
+//! Per-image cache statistics, for teams asking whether the cache is doing anything for their image:
+//! `/_admin/stats/image/{namespace}/{image}` says how many of its manifest and blob pulls were served
+//! from cache and how many went to upstream, how many bytes each way, when it was last fetched from
+//! upstream, and which of its tags and digests are cached now.  Counts are kept in memory by each
+//! instance from when it started, for up to [`MAX_IMAGES`] images; images pulled once that many are
+//! counted aren't, though what's cached of them is still listed.  With tenants, each tenant's pulls
+//! are counted, and its cache listed, apart from everyone else's.
+
+use core::time::Duration;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::body::BodySize;
+use actix_web::body::MessageBody;
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use super::error::Error;
+use super::manifest_storage_dir;
+use super::plugin::ObjectKind;
+use super::split_image;
+use super::Access;
+use super::ManifestQueryString;
+use super::RequestConfig;
+use crate::image::ImageName;
+
+/// How many images are counted, so that memory stays bounded however many are pulled
+const MAX_IMAGES: usize = 10_000;
+
+/// When counting started.
+static SINCE: Lazy<OffsetDateTime> = Lazy::new(OffsetDateTime::now_utc);
+static STATS: Lazy<Mutex<HashMap<Key, ImageStats>>> = Lazy::new(Default::default);
+
+/// The tenant pulling, if any, then the namespace and image
+type Key = (Option<CompactString>, CompactString, CompactString);
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+struct Counts {
+	hits: u64,
+	misses: u64
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+struct ImageStats {
+	manifests: Counts,
+	blobs: Counts,
+	bytes_from_cache: u64,
+	bytes_from_upstream: u64,
+	#[serde(skip)]
+	last_upstream_fetch: Option<OffsetDateTime>
+}
+
+/// The image under the name it's counted by:  without the namespace, whether or not the client
+/// included it, as it's stored.
+fn key(access: &Access, namespace: &str, image: &str) -> Key {
+	let image = image.strip_prefix(namespace).and_then(|i| i.strip_prefix('/')).unwrap_or(image);
+	(access.tenant().map(CompactString::from), namespace.into(), image.into())
+}
+
+fn update(access: &Access, namespace: &str, image: &str, f: impl FnOnce(&mut ImageStats)) {
+	Lazy::force(&SINCE);
+	let key = key(access, namespace, image);
+	let mut stats = STATS.lock().unwrap();
+	if (!stats.contains_key(&key) && stats.len() >= MAX_IMAGES) {
+		return;
+	}
+	f(stats.entry(key).or_default());
+}
+
+fn counts(stats: &mut ImageStats, kind: ObjectKind) -> &mut Counts {
+	match kind {
+		ObjectKind::Manifest => &mut stats.manifests,
+		ObjectKind::Blob => &mut stats.blobs
+	}
+}
+
+/// Counts a pull of `image` served from cache, stale or revalidated ones included.
+pub(super) fn hit(kind: ObjectKind, access: &Access, namespace: &str, image: &str, bytes: u64) {
+	update(access, namespace, image, |stats| {
+		counts(stats, kind).hits += 1;
+		stats.bytes_from_cache += bytes;
+	});
+}
+
+/// Counts a pull of `image` that had to go to upstream.
+pub(super) fn miss(kind: ObjectKind, access: &Access, namespace: &str, image: &str) {
+	update(access, namespace, image, |stats| counts(stats, kind).misses += 1);
+}
+
+/// Counts `bytes` of `image` fetched from upstream just now.
+pub(super) fn fetched(access: &Access, namespace: &str, image: &str, bytes: u64) {
+	update(access, namespace, image, |stats| {
+		stats.bytes_from_upstream += bytes;
+		stats.last_upstream_fetch = Some(OffsetDateTime::now_utc());
+	});
+}
+
+/// How long a response's body is, where it says.
+pub(super) fn response_length(response: &HttpResponse) -> u64 {
+	match response.body().size() {
+		BodySize::Sized(length) => length,
+		BodySize::None | BodySize::Stream => 0
+	}
+}
+
+#[derive(Debug, Serialize)]
+struct CachedTag {
+	tag: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	digest: Option<String>,
+	/// When it was cached or last refreshed, in RFC 3339
+	cached: String
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+	namespace: String,
+	image: String,
+	/// When counting started, in RFC 3339
+	since: String,
+	#[serde(flatten)]
+	stats: ImageStats,
+	/// The share of manifest and blob pulls served from cache; `None` before any
+	hit_ratio: Option<f64>,
+	/// In RFC 3339
+	last_upstream_fetch: Option<String>,
+	/// Cached tags, with the digests they point at
+	tags: Vec<CachedTag>,
+	/// Manifests cached by digest
+	digests: Vec<String>
+}
+
+fn rfc3339(time: OffsetDateTime) -> String {
+	time.format(&Rfc3339).unwrap_or_default()
+}
+
+fn hit_ratio(stats: &ImageStats) -> Option<f64> {
+	let hits = stats.manifests.hits + stats.blobs.hits;
+	match hits + stats.manifests.misses + stats.blobs.misses {
+		0 => None,
+		total => Some(hits as f64 / total as f64)
+	}
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsRequest {
+	image: ImageName
+}
+
+/// An image's statistics, and what's cached of it in the caller's cache.
+pub async fn image(http_req: HttpRequest, req: web::Path<StatsRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns(None));
+	let access = config.admin_access(&http_req)?;
+	config.admin_namespace(&access, namespace)?;
+	let key = key(&access, namespace, image);
+	let stats = STATS.lock().unwrap().get(&key).cloned().unwrap_or_default();
+	let (_, _, image) = key;
+
+	let prefix = format!("{}/", manifest_storage_dir(namespace, &image, &access));
+	let mut tags = Vec::new();
+	let mut digests = Vec::new();
+	for object in config.repo.list(&prefix).await? {
+		let Some(reference) = object.strip_prefix(prefix.as_str()).filter(|r| !r.contains('/') && !r.starts_with('.')) else {
+			continue;
+		};
+		if (reference.contains(':')) {
+			digests.push(reference.to_owned());
+			continue;
+		}
+		match config.repo.stat_manifest(&object, Duration::MAX).await {
+			Ok((metadata, stat)) => tags.push(CachedTag { tag: reference.to_owned(), digest: metadata.digest, cached: rfc3339(stat.modified().into()) }),
+			// Gone since it was listed
+			Err(e) if e.is_not_found() => (),
+			Err(e) => return Err(e.into())
+		};
+	}
+	tags.sort_by(|a, b| a.tag.cmp(&b.tag));
+	digests.sort();
+
+	Ok(HttpResponse::Ok().json(Report {
+		namespace: namespace.to_owned(),
+		image: image.to_string(),
+		since: rfc3339(*SINCE),
+		hit_ratio: hit_ratio(&stats),
+		last_upstream_fetch: stats.last_upstream_fetch.map(rfc3339),
+		stats,
+		tags,
+		digests
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn counts_by_image_whichever_way_its_named() {
+		let shared = Access::Shared;
+		hit(ObjectKind::Manifest, &shared, "stats-test", "library/alpine", 1000);
+		hit(ObjectKind::Blob, &shared, "stats-test", "stats-test/library/alpine", 3000);
+		miss(ObjectKind::Blob, &shared, "stats-test", "library/alpine");
+		fetched(&shared, "stats-test", "library/alpine", 5000);
+		hit(ObjectKind::Manifest, &shared, "stats-test", "library/busybox", 1000);
+		// A tenant's pulls are its own
+		let tenant = Access::Tenant("team-a".into());
+		hit(ObjectKind::Manifest, &tenant, "stats-test", "library/alpine", 1000);
+
+		let stats = STATS.lock().unwrap().get(&key(&shared, "stats-test", "library/alpine")).cloned().unwrap();
+		assert_eq!((stats.manifests, stats.blobs), (Counts { hits: 1, misses: 0 }, Counts { hits: 1, misses: 1 }));
+		assert_eq!((stats.bytes_from_cache, stats.bytes_from_upstream), (4000, 5000));
+		assert!(stats.last_upstream_fetch.is_some());
+		assert_eq!(hit_ratio(&stats), Some(2.0 / 3.0));
+		let stats = STATS.lock().unwrap().get(&key(&tenant, "stats-test", "library/alpine")).cloned().unwrap();
+		assert_eq!((stats.manifests, stats.bytes_from_upstream), (Counts { hits: 1, misses: 0 }, 0));
+		assert_eq!(hit_ratio(&ImageStats::default()), None);
+	}
+}
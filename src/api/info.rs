@@ -0,0 +1,96 @@
+//! What a cache node is running:  its build, storage backend, and upstream configuration, so that
+//! fleet tooling can tell nodes apart without access to their deployment config.
+
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_gauge_vec;
+use prometheus::IntGaugeVec;
+
+use super::error::Error;
+use super::RequestConfig;
+use crate::storage::Repository;
+
+/// Set at build time from `$GIT_COMMIT`, which the Dockerfiles take as a build argument.
+pub const GIT_COMMIT: &str = match option_env!("GIT_COMMIT") {
+	Some(v) => v,
+	None => "unknown"
+};
+
+static BUILD_INFO: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("build_info", "Always 1; labeled with what this instance was built from and is storing into", &["version", "git_commit", "storage"]).unwrap());
+
+/// The optional features compiled into this build.
+fn features() -> Vec<&'static str> {
+	let mut features = Vec::new();
+	if (cfg!(feature = "bundle")) {
+		features.push("bundle");
+	}
+	if (cfg!(feature = "cpu-profile")) {
+		features.push("cpu-profile");
+	}
+	if (cfg!(feature = "chaos")) {
+		features.push("chaos");
+	}
+	if (cfg!(feature = "heap-stats")) {
+		features.push("heap-stats");
+	}
+	if (cfg!(feature = "tokio-console")) {
+		features.push("tokio-console");
+	}
+	if (cfg!(feature = "wasm-plugins")) {
+		features.push("wasm-plugins");
+	}
+	features
+}
+
+/// Sets the `build_info` gauge; called once at startup.
+pub fn export_build_info(repo: &Repository) {
+	BUILD_INFO.with_label_values(&[env!("CARGO_PKG_VERSION"), GIT_COMMIT, repo.backend()]).set(1);
+}
+
+/// What this node is running.  A tenant is only told about the namespaces it's allowed.
+pub async fn info(http_req: HttpRequest, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let access = config.admin_access(&http_req)?;
+	let visible = |namespace: &str| config.admin_namespace(&access, namespace).is_ok();
+	let mut upstreams = config.upstream.lock().await.summary();
+	upstreams.retain(|upstream| visible(upstream.namespace()));
+	Ok(HttpResponse::Ok().json(serde_json::json!({
+		"version": env!("CARGO_PKG_VERSION"),
+		"git_commit": GIT_COMMIT,
+		"features": features(),
+		"storage": config.repo.backend(),
+		"default_namespace": config.default_ns,
+		"listener_namespaces": config.listener_namespaces.iter().filter(|l| visible(l.namespace.as_str())).map(|l| (l.address.to_string(), l.namespace.as_str())).collect::<std::collections::BTreeMap<_, _>>(),
+		"settings": {
+			"base_path": config.base_path,
+			"check_cache_digest": config.check_cache_digest,
+			"max_manifest_size": config.max_manifest_size,
+			"max_page_size": config.max_page_size,
+			"manifest_deadline": humantime::format_duration(config.manifest_deadline).to_string(),
+			"blob_deadline": humantime::format_duration(config.blob_deadline).to_string(),
+			"client_abort_policy": format!("{:?}", config.client_abort_policy).to_lowercase(),
+			"trash": config.trash,
+			"webhooks": config.webhook_token.is_some()
+		},
+		"upstreams": upstreams
+	})))
+}
+
+/// Each configured upstream's effective settings, less anything secret, and how it's doing; a
+/// tenant only sees those it's allowed.
+pub async fn namespaces(http_req: HttpRequest, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let access = config.admin_access(&http_req)?;
+	let mut status = config.upstream.lock().await.status();
+	status.retain(|status| config.admin_namespace(&access, status.namespace()).is_ok());
+	Ok(HttpResponse::Ok().json(status))
+}
+
+/// One namespace's, as for [`namespaces`]; for one that isn't configured, the defaults it's
+/// pulled from with.
+pub async fn namespace(http_req: HttpRequest, namespace: web::Path<CompactString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	config.admin_namespace(&config.admin_access(&http_req)?, &namespace)?;
+	let status = config.upstream.lock().await.get(&namespace)?.status();
+	Ok(HttpResponse::Ok().json(status))
+}
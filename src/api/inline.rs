@@ -0,0 +1,133 @@
+//! Blobs embedded in the manifests that reference them:  an OCI descriptor can carry the content
+//! it describes in its `data` field, base64-encoded, which artifact tooling does for config-sized
+//! objects, like the empty `{}` config most artifacts have.  As manifests are fetched from
+//! upstream, the blobs embedded in them are kept in memory, by storage path, so that pulls of them
+//! need neither storage nor upstream.  Each is checked against its descriptor's size and digest
+//! first, since the manifest's own digest vouches only for what the descriptor says.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use actix_web::http;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use base64::Engine;
+use bytes::Bytes;
+use serde::Deserialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use super::blob_storage_path;
+use super::range;
+use super::range::Requested;
+use super::unsatisfiable_response;
+use super::Access;
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+	digest: String,
+	size: u64,
+	#[serde(default)]
+	data: Option<String>
+}
+
+/// The descriptors of blobs, in image manifests and artifact manifests.
+#[derive(Debug, Deserialize)]
+struct Manifest {
+	#[serde(default)]
+	config: Option<Descriptor>,
+	#[serde(default)]
+	layers: Vec<Descriptor>,
+	#[serde(default)]
+	blobs: Vec<Descriptor>
+}
+
+/// The blobs embedded in `manifest` that match their descriptors, by digest.
+fn embedded(manifest: &[u8]) -> Vec<(String, Bytes)> {
+	let Ok(manifest) = serde_json::from_slice::<Manifest>(manifest) else {
+		return Vec::new();
+	};
+	manifest.config.into_iter().chain(manifest.layers).chain(manifest.blobs).filter_map(|descriptor| {
+		let data = base64::engine::general_purpose::STANDARD.decode(descriptor.data?).ok()?;
+		let hash = descriptor.digest.strip_prefix("sha256:")?;
+		(data.len() as u64 == descriptor.size && hex::encode(Sha256::digest(&data)) == hash).then(|| (descriptor.digest, Bytes::from(data)))
+	}).collect()
+}
+
+#[derive(Default)]
+struct Blobs {
+	blobs: HashMap<String, Bytes>,
+	bytes: usize
+}
+
+#[derive(Default)]
+pub struct InlineBlobs {
+	blobs: Mutex<Blobs>
+}
+
+impl InlineBlobs {
+	/// How much embedded content is kept, in all; past this, it's forgotten and starts over, like
+	/// the known blobs index does.
+	const MAX_BYTES: usize = 16 * 1024 * 1024;
+
+	/// Keeps the blobs embedded in a manifest fetched with `access`.
+	pub fn record(&self, manifest: &[u8], access: &Access) {
+		let embedded = embedded(manifest);
+		if (embedded.is_empty()) {
+			return;
+		}
+		let mut blobs = self.blobs.lock().unwrap();
+		for (digest, data) in embedded {
+			if (blobs.bytes + data.len() > Self::MAX_BYTES) {
+				*blobs = Blobs::default();
+			}
+			blobs.bytes += data.len();
+			if let Some(replaced) = blobs.blobs.insert(blob_storage_path(&digest, access), data) {
+				blobs.bytes -= replaced.len();
+			}
+		}
+	}
+
+	/// The blob at `storage_path`, if it was embedded in a manifest fetched since it was last
+	/// forgotten.
+	pub fn get(&self, storage_path: &str) -> Option<Bytes> {
+		self.blobs.lock().unwrap().blobs.get(storage_path).cloned()
+	}
+}
+
+/// Serves an embedded blob, or just the part of it a `Range` header asks for.
+pub(super) fn response(data: Bytes, http_req: Option<&HttpRequest>) -> HttpResponse {
+	let length = data.len() as u64;
+	let header = http_req.and_then(|r| r.headers().get(http::header::RANGE)).and_then(|v| v.to_str().ok());
+	match range::requested(header, length) {
+		Requested::Whole => HttpResponse::Ok().body(data),
+		Requested::Part(part) => HttpResponse::PartialContent()
+			.insert_header((http::header::ACCEPT_RANGES, "bytes"))
+			.insert_header((http::header::CONTENT_RANGE, range::content_range(&part, length)))
+			.body(data.slice(part.start as usize..part.end as usize)),
+		Requested::Unsatisfiable => unsatisfiable_response(length)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn only_matching_blobs() {
+		let config = b"{}";
+		let digest = format!("sha256:{}", hex::encode(Sha256::digest(config)));
+		let manifest = format!(
+			r#"{{"schemaVersion":2,"config":{{"mediaType":"application/vnd.oci.empty.v1+json","digest":"{digest}","size":2,"data":"e30="}},"layers":[{{"digest":"{digest}","size":3,"data":"e30="}},{{"digest":"sha256:0000","size":2,"data":"e30="}},{{"digest":"{digest}","size":2}}]}}"#
+		);
+		assert_eq!(embedded(manifest.as_bytes()), vec![(digest.clone(), Bytes::from_static(config))]);
+
+		let inline = InlineBlobs::default();
+		inline.record(manifest.as_bytes(), &Access::Shared);
+		assert_eq!(inline.get(&blob_storage_path(&digest, &Access::Shared)).as_deref(), Some(&config[..]));
+		assert_eq!(inline.blobs.lock().unwrap().bytes, 2);
+		// The same blob again doesn't count twice
+		inline.record(manifest.as_bytes(), &Access::Shared);
+		assert_eq!(inline.blobs.lock().unwrap().bytes, 2);
+	}
+}
@@ -0,0 +1,193 @@
+//! `inspect`:  what the cache holds for an image, straight from storage, without asking upstream or
+//! a running instance.  It follows a tag or digest to its manifest, and an index to the manifest for
+//! each platform, and says for each blob they reference whether it's in storage and how long it's
+//! been there, so that nobody has to work out storage keys by hand to find out.
+
+use core::time::Duration;
+
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::blob_storage_path;
+use super::manifest_storage_dir;
+use super::provenance;
+use super::provenance::Provenance;
+use super::Access;
+use crate::command::InspectConfig;
+use crate::storage::Repository;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("Expected <namespace>/<image>[:<tag>|@<digest>], not {0}")]
+	InvalidReference(String),
+	#[error("{0} isn't cached")]
+	NotCached(String),
+	#[error("Cached manifest {0} isn't valid JSON: {1}")]
+	Json(String, serde_json::Error),
+	#[error("Failed to read {0} from storage: {1}")]
+	Storage(String, crate::storage::Error)
+}
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+	#[serde(default, rename = "mediaType")]
+	media_type: Option<String>,
+	digest: String,
+	#[serde(default)]
+	size: u64,
+	#[serde(default)]
+	platform: Option<Platform>
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+	os: String,
+	architecture: String,
+	#[serde(default)]
+	variant: Option<String>
+}
+
+impl Platform {
+	fn name(&self) -> String {
+		match self.variant.as_deref() {
+			Some(variant) => format!("{}/{}/{variant}", self.os, self.architecture),
+			None => format!("{}/{}", self.os, self.architecture)
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct Parsed {
+	#[serde(default)]
+	config: Option<Descriptor>,
+	#[serde(default)]
+	layers: Vec<Descriptor>,
+	#[serde(default)]
+	manifests: Vec<Descriptor>
+}
+
+#[derive(Debug, Serialize)]
+pub struct ManifestReport {
+	path: String,
+	media_type: String,
+	digest: Option<String>,
+	size: u64,
+	age_secs: Option<u64>,
+	/// Where it was fetched from, where that was recorded
+	#[serde(skip_serializing_if = "Option::is_none")]
+	provenance: Option<Provenance>,
+	/// For an index, the manifest for each platform
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	platforms: Vec<PlatformReport>,
+	/// For an image, its config and layers
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	blobs: Vec<BlobReport>,
+	/// The manifest itself, when asked for
+	#[serde(skip_serializing_if = "Option::is_none")]
+	manifest: Option<serde_json::Value>
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlatformReport {
+	platform: Option<String>,
+	digest: String,
+	/// `None` if this platform's manifest isn't cached
+	cached: Option<ManifestReport>
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlobReport {
+	digest: String,
+	media_type: Option<String>,
+	size: u64,
+	path: String,
+	cached: bool,
+	age_secs: Option<u64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	provenance: Option<Provenance>
+}
+
+/// Splits `<namespace>/<image>[:<tag>|@<digest>]` into its parts; the tag is `latest` if there's
+/// neither.
+fn parse(reference: &str) -> Option<(&str, &str, &str)> {
+	let (namespace, rest) = reference.split_once('/')?;
+	let (image, reference) = match rest.split_once('@') {
+		Some((image, digest)) => (image, digest),
+		None => match rest.rsplit_once(':') {
+			Some((image, tag)) if !tag.contains('/') => (image, tag),
+			_ => (rest, "latest")
+		}
+	};
+	(!namespace.is_empty() && !image.is_empty() && !reference.is_empty()).then_some((namespace, image, reference))
+}
+
+/// Reports on what's cached for the image `config` names.
+pub async fn run(repo: &Repository, config: &InspectConfig) -> Result<ManifestReport, Error> {
+	let (namespace, image, reference) = parse(&config.image).ok_or_else(|| Error::InvalidReference(config.image.clone()))?;
+	let access = match config.tenant.as_ref() {
+		Some(tenant) => Access::Tenant(tenant.clone()),
+		None => Access::Shared
+	};
+	let dir = manifest_storage_dir(namespace, image, &access);
+	let mut report = manifest(repo, &dir, reference, &access, config.manifest).await?.ok_or_else(|| Error::NotCached(format!("{dir}/{reference}")))?;
+	let children = std::mem::take(&mut report.platforms);
+	for mut child in children {
+		child.cached = manifest(repo, &dir, &child.digest, &access, config.manifest).await?;
+		report.platforms.push(child);
+	}
+	Ok(report)
+}
+
+/// Reports on the manifest cached as `reference`, without following an index to its manifests;
+/// `None` if it isn't cached.
+async fn manifest(repo: &Repository, dir: &str, reference: &str, access: &Access, include: bool) -> Result<Option<ManifestReport>, Error> {
+	let path = format!("{dir}/{reference}");
+	let (metadata, body) = match repo.read_manifest(&path, Duration::MAX).await {
+		Ok(v) => v,
+		Err(error) if error.is_not_found() => return Ok(None),
+		Err(error) => return Err(Error::Storage(path, error))
+	};
+	let age_secs = body.age().map(|age| age.as_secs());
+	let body = body.into_inner().try_collect::<BytesMut>().await.map_err(|e| Error::Storage(path.clone(), e.into()))?;
+	let parsed: Parsed = serde_json::from_slice(&body).map_err(|e| Error::Json(path.clone(), e))?;
+
+	let descriptors = parsed.config.into_iter().chain(parsed.layers).collect::<Vec<_>>();
+	let paths = descriptors.iter().map(|d| blob_storage_path(&d.digest, access)).collect::<Vec<_>>();
+	let stats = repo.stat_all(&paths.iter().map(String::as_str).collect::<Vec<_>>(), Duration::MAX).await;
+	let mut blobs = Vec::with_capacity(descriptors.len());
+	for ((descriptor, path), stat) in descriptors.into_iter().zip(paths).zip(stats) {
+		let stat = match stat {
+			Ok(stat) => Some(stat),
+			Err(error) if error.is_not_found() => None,
+			Err(error) => return Err(Error::Storage(path, error))
+		};
+		let provenance = match stat {
+			Some(_) => provenance::read(repo, &path).await.map_err(|e| Error::Storage(path.clone(), e))?,
+			None => None
+		};
+		blobs.push(BlobReport { digest: descriptor.digest, media_type: descriptor.media_type, size: descriptor.size, path, cached: stat.is_some(), age_secs: stat.map(|stat| stat.age().as_secs()), provenance });
+	}
+	let provenance = provenance::read(repo, &path).await.map_err(|e| Error::Storage(path.clone(), e))?;
+	let platforms = parsed.manifests.into_iter().map(|d| PlatformReport { platform: d.platform.as_ref().map(Platform::name), digest: d.digest, cached: None }).collect();
+	let manifest = match include {
+		true => Some(serde_json::from_slice(&body).map_err(|e| Error::Json(path.clone(), e))?),
+		false => None
+	};
+	Ok(Some(ManifestReport { path, media_type: metadata.media_type, digest: metadata.digest, size: body.len() as u64, age_secs, provenance, platforms, blobs, manifest }))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn references() {
+		assert_eq!(parse("docker.io/library/alpine"), Some(("docker.io", "library/alpine", "latest")));
+		assert_eq!(parse("docker.io/library/alpine:3.19"), Some(("docker.io", "library/alpine", "3.19")));
+		assert_eq!(parse("localhost:5000/app@sha256:abcd"), Some(("localhost:5000", "app", "sha256:abcd")));
+		assert_eq!(parse("alpine"), None);
+		assert_eq!(parse("docker.io/"), None);
+	}
+}
@@ -0,0 +1,2464 @@
+//! End-to-end tests of the proxy handlers against a mock upstream registry and filesystem storage,
+//! so that changes to the request paths can be checked without a real upstream or S3.
+
+use core::time::Duration;
+use std::collections::HashMap;
+use std::iter;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use actix_web::body;
+use actix_web::body::MessageBody;
+use actix_web::dev::Service;
+use actix_web::http;
+use actix_web::http::StatusCode;
+use actix_web::middleware::DefaultHeaders;
+use actix_web::rt;
+use actix_web::test;
+use actix_web::web;
+use actix_web::App;
+use actix_web::HttpMessage;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use actix_web::HttpServer;
+use base64::Engine;
+use bytes::Bytes;
+use bytes::BytesMut;
+use camino::Utf8PathBuf;
+use compact_str::CompactString;
+use futures::stream::TryStreamExt;
+use sha2::Digest;
+use sha2::Sha256;
+
+use super::limits::Limits;
+use super::ListenerNamespace;
+use super::RequestConfig;
+use crate::storage::filesystem;
+use crate::storage::pacing::Pacer;
+use crate::storage::replica::ReplicaConfig;
+use crate::storage::Repository;
+use crate::upstream::Client;
+use crate::upstream::Clients;
+use crate::upstream::SingleUpstreamConfig;
+
+const NAMESPACE: &str = "mock";
+const IMAGE: &str = "library/busybox";
+const MANIFEST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+const CONFIG_BLOB: &[u8] = br#"{"architecture":"amd64","os":"linux","rootfs":{"type":"layers","diff_ids":[]}}"#;
+const LAYER_BLOB: &[u8] = b"not really a tarball, but upstream doesn't care";
+
+fn digest(body: &[u8]) -> String {
+	format!("sha256:{}", hex::encode(Sha256::digest(body)))
+}
+
+fn manifest() -> String {
+	format!(
+		r#"{{"schemaVersion":2,"mediaType":"{MANIFEST_MEDIA_TYPE}","config":{{"mediaType":"application/vnd.docker.container.image.v1+json","size":{},"digest":"{}"}},"layers":[{{"mediaType":"application/vnd.docker.image.rootfs.diff.tar.gzip","size":{},"digest":"{}"}}]}}"#,
+		CONFIG_BLOB.len(),
+		digest(CONFIG_BLOB),
+		LAYER_BLOB.len(),
+		digest(LAYER_BLOB)
+	)
+}
+
+/// A registry serving a single image, which can be told to misbehave in various ways.
+#[derive(Default)]
+struct MockUpstream {
+	manifests: HashMap<String, Bytes>,
+	blobs: HashMap<String, Bytes>,
+	manifest_requests: AtomicUsize,
+	manifest_head_requests: AtomicUsize,
+	/// `GET`s for a manifest with a `Range`, which aren't counted in `manifest_requests`
+	manifest_range_requests: AtomicUsize,
+	blob_requests: AtomicUsize,
+	blob_head_requests: AtomicUsize,
+	token_requests: AtomicUsize,
+	tag_requests: AtomicUsize,
+	/// The headers manifest and blob `GET`s came with, in the order they came
+	pull_headers: Mutex<Vec<http::header::HeaderMap>>,
+	/// Serve manifests with this `Cache-Control`
+	manifest_cache_control: Option<&'static str>,
+	/// Serve manifests without a `Docker-Content-Digest`
+	omit_manifest_digest: bool,
+	/// Serve manifests with a `Docker-Content-Digest` that isn't theirs
+	wrong_manifest_digest: bool,
+	/// Answer every manifest and blob request with a 503
+	failing: AtomicBool,
+	/// Answer requests that carry an `ns` parameter with a 400, like registries that don't
+	/// understand it
+	reject_namespace: AtomicBool,
+	/// Serve blobs with a byte flipped
+	corrupt_blobs: AtomicBool,
+	/// Serve this many blobs with a byte flipped, and the rest intact
+	corrupt_blob_responses: AtomicUsize,
+	/// Answer a `Range` request for a blob with just that part of it
+	serve_ranges: AtomicBool,
+	/// Answer requests made with a token issued for credentials with a 401, as for revoked
+	/// credentials, while still serving anonymous ones
+	reject_credentials: AtomicBool,
+	/// Uploads in progress, by ID
+	uploads: Mutex<HashMap<String, BytesMut>>,
+	/// Blobs and manifests pushed to us, by digest
+	pushed: Mutex<HashMap<String, Bytes>>,
+	/// Where the registry API is served, in front of `/v2/`, like Nexus does
+	base_path: &'static str
+}
+
+impl MockUpstream {
+	fn new() -> Self {
+		let manifest = Bytes::from(manifest());
+		Self {
+			manifests: [("latest".to_owned(), manifest.clone()), (digest(&manifest), manifest)].into_iter().collect(),
+			blobs: [CONFIG_BLOB, LAYER_BLOB].into_iter().map(|b| (digest(b), Bytes::from_static(b))).collect(),
+			..Self::default()
+		}
+	}
+
+	fn misbehavior(&self, req: &HttpRequest) -> Option<HttpResponse> {
+		if (self.failing.load(Ordering::Relaxed)) {
+			return Some(HttpResponse::ServiceUnavailable().finish());
+		}
+		if (self.reject_namespace.load(Ordering::Relaxed) && req.query_string().contains("ns=")) {
+			return Some(HttpResponse::BadRequest().finish());
+		}
+		if (self.reject_credentials.load(Ordering::Relaxed) && req.headers().get("authorization").is_some_and(|v| v.as_bytes().ends_with(b"mock-credentialed"))) {
+			return Some(HttpResponse::Unauthorized().finish());
+		}
+		None
+	}
+
+	/// Starts serving on an ephemeral port, returning the mock's state and its `host:port`.
+	fn start(self) -> (web::Data<Self>, String) {
+		let mock = web::Data::new(self);
+		let server = {
+			let mock = mock.clone();
+			HttpServer::new(move || {
+				App::new().app_data(mock.clone()).wrap(DefaultHeaders::new().add(("Docker-Distribution-API-Version", "registry/2.0"))).route("/token", web::get().to(mock_token)).service(
+					web::scope(mock.base_path)
+						.route("/v2/", web::get().to(mock_root))
+						.route("/v2/{image:[^{}]+}/tags/list", web::get().to(mock_tags))
+						.route("/v2/{image:[^{}]+}/manifests/{reference}", web::head().to(mock_manifest))
+						.route("/v2/{image:[^{}]+}/manifests/{reference}", web::get().to(mock_manifest))
+						.route("/v2/{image:[^{}]+}/manifests/{reference}", web::put().to(mock_put_manifest))
+						.route("/v2/{image:[^{}]+}/blobs/uploads/", web::post().to(mock_start_upload))
+						.route("/v2/{image:[^{}]+}/blobs/uploads/{id}", web::patch().to(mock_patch_upload))
+						.route("/v2/{image:[^{}]+}/blobs/uploads/{id}", web::put().to(mock_finish_upload))
+						.route("/v2/{image:[^{}]+}/blobs/{digest}", web::head().to(mock_blob))
+						.route("/v2/{image:[^{}]+}/blobs/{digest}", web::get().to(mock_blob))
+				)
+			})
+			.workers(1)
+			.bind(("127.0.0.1", 0))
+			.unwrap()
+		};
+		let host = server.addrs()[0].to_string();
+		rt::spawn(server.run());
+		(mock, host)
+	}
+}
+
+async fn mock_root(req: HttpRequest) -> HttpResponse {
+	match req.headers().contains_key("authorization") {
+		true => HttpResponse::Ok().finish(),
+		false => HttpResponse::Unauthorized().insert_header(("WWW-Authenticate", format!(r#"Bearer realm="http://{}/token",service="mock""#, req.connection_info().host()))).finish()
+	}
+}
+
+async fn mock_token(req: HttpRequest, mock: web::Data<MockUpstream>) -> HttpResponse {
+	mock.token_requests.fetch_add(1, Ordering::Relaxed);
+	// Tokens issued for credentials can be told apart, to refuse them
+	let token = match req.headers().contains_key("authorization") {
+		true => "mock-credentialed",
+		false => "mock"
+	};
+	HttpResponse::Ok().json(serde_json::json!({ "token": token, "access_token": token }))
+}
+
+async fn mock_tags(req: HttpRequest, path: web::Path<String>, query: web::Query<HashMap<String, String>>, mock: web::Data<MockUpstream>) -> HttpResponse {
+	mock.tag_requests.fetch_add(1, Ordering::Relaxed);
+	if let Some(response) = mock.misbehavior(&req) {
+		return response;
+	}
+	let image = path.into_inner();
+	if (image != IMAGE) {
+		return HttpResponse::NotFound().finish();
+	}
+	let mut tags = mock.manifests.keys().filter(|r| !r.starts_with("sha256:")).filter(|r| query.get("last").map_or(true, |last| r.as_str() > last.as_str())).collect::<Vec<_>>();
+	tags.sort();
+	let n = query.get("n").and_then(|n| n.parse().ok()).unwrap_or(usize::MAX);
+	let mut response = HttpResponse::Ok();
+	if (tags.len() > n) {
+		tags.truncate(n);
+		response.insert_header(("link", format!(r#"</v2/{image}/tags/list?n={n}&last={}>; rel="next""#, tags[n - 1])));
+	}
+	response.json(serde_json::json!({ "name": image, "tags": tags }))
+}
+
+async fn mock_manifest(req: HttpRequest, path: web::Path<(String, String)>, mock: web::Data<MockUpstream>) -> HttpResponse {
+	match (req.method() == http::Method::HEAD, req.headers().contains_key(http::header::RANGE)) {
+		(true, _) => mock.manifest_head_requests.fetch_add(1, Ordering::Relaxed),
+		(false, true) => mock.manifest_range_requests.fetch_add(1, Ordering::Relaxed),
+		(false, false) => {
+			mock.pull_headers.lock().unwrap().push(req.headers().clone());
+			mock.manifest_requests.fetch_add(1, Ordering::Relaxed)
+		}
+	};
+	if let Some(response) = mock.misbehavior(&req) {
+		return response;
+	}
+	let (image, reference) = path.into_inner();
+	match (image == IMAGE, mock.manifests.get(&reference)) {
+		(true, Some(manifest)) => {
+			let mut response = HttpResponse::Ok();
+			if let Some(cache_control) = mock.manifest_cache_control {
+				response.insert_header((http::header::CACHE_CONTROL, cache_control));
+			}
+			if (!mock.omit_manifest_digest) {
+				let served = match mock.wrong_manifest_digest {
+					true => digest(CONFIG_BLOB),
+					false => digest(manifest)
+				};
+				response.insert_header(("Docker-Content-Digest", served));
+			}
+			response.content_type(MANIFEST_MEDIA_TYPE).body(manifest.clone())
+		},
+		_ => HttpResponse::NotFound().finish()
+	}
+}
+
+async fn mock_blob(req: HttpRequest, path: web::Path<(String, String)>, mock: web::Data<MockUpstream>) -> HttpResponse {
+	match req.method() == http::Method::HEAD {
+		true => mock.blob_head_requests.fetch_add(1, Ordering::Relaxed),
+		false => {
+			mock.pull_headers.lock().unwrap().push(req.headers().clone());
+			mock.blob_requests.fetch_add(1, Ordering::Relaxed)
+		}
+	};
+	if let Some(response) = mock.misbehavior(&req) {
+		return response;
+	}
+	let (image, digest) = path.into_inner();
+	let pushed = mock.pushed.lock().unwrap().get(&digest).cloned();
+	let Some(blob) = pushed.or_else(|| mock.blobs.get(&digest).filter(|_| image == IMAGE).cloned()) else {
+		return HttpResponse::NotFound().finish();
+	};
+	match mock.corrupt_blobs.load(Ordering::Relaxed) || mock.corrupt_blob_responses.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)).is_ok() {
+		true => {
+			let mut corrupted = blob.to_vec();
+			corrupted[0] ^= 0xff;
+			HttpResponse::Ok().content_type("application/octet-stream").body(corrupted)
+		},
+		false => {
+			let header = req.headers().get(http::header::RANGE).and_then(|v| v.to_str().ok()).filter(|_| mock.serve_ranges.load(Ordering::Relaxed));
+			match super::range::requested(header, blob.len() as u64) {
+				super::range::Requested::Part(part) => HttpResponse::PartialContent()
+					.content_type("application/octet-stream")
+					.insert_header((http::header::CONTENT_RANGE, super::range::content_range(&part, blob.len() as u64)))
+					.body(blob.slice(part.start as usize..part.end as usize)),
+				_ => HttpResponse::Ok().content_type("application/octet-stream").body(blob)
+			}
+		}
+	}
+}
+
+/// Pushes need the token the mock's token endpoint hands out.
+fn push_unauthorized(req: &HttpRequest) -> Option<HttpResponse> {
+	match req.headers().get("authorization").is_some_and(|v| v == "Bearer mock") {
+		true => None,
+		false => Some(HttpResponse::Unauthorized().finish())
+	}
+}
+
+async fn mock_start_upload(req: HttpRequest, path: web::Path<String>, mock: web::Data<MockUpstream>) -> HttpResponse {
+	if let Some(response) = push_unauthorized(&req) {
+		return response;
+	}
+	let mut uploads = mock.uploads.lock().unwrap();
+	let id = uploads.len().to_string();
+	uploads.insert(id.clone(), BytesMut::new());
+	HttpResponse::Accepted().insert_header(("Location", format!("{}/v2/{path}/blobs/uploads/{id}", mock.base_path))).finish()
+}
+
+async fn mock_patch_upload(req: HttpRequest, path: web::Path<(String, String)>, body: Bytes, mock: web::Data<MockUpstream>) -> HttpResponse {
+	if let Some(response) = push_unauthorized(&req) {
+		return response;
+	}
+	let (image, id) = path.into_inner();
+	let mut uploads = mock.uploads.lock().unwrap();
+	let Some(upload) = uploads.get_mut(&id) else {
+		return HttpResponse::NotFound().finish();
+	};
+	upload.extend_from_slice(&body);
+	HttpResponse::Accepted().insert_header(("Location", format!("{}/v2/{image}/blobs/uploads/{id}", mock.base_path))).insert_header(("Range", format!("0-{}", upload.len().saturating_sub(1)))).finish()
+}
+
+async fn mock_finish_upload(req: HttpRequest, path: web::Path<(String, String)>, query: web::Query<HashMap<String, String>>, body: Bytes, mock: web::Data<MockUpstream>) -> HttpResponse {
+	if let Some(response) = push_unauthorized(&req) {
+		return response;
+	}
+	let (image, id) = path.into_inner();
+	let Some(mut upload) = mock.uploads.lock().unwrap().remove(&id) else {
+		return HttpResponse::NotFound().finish();
+	};
+	upload.extend_from_slice(&body);
+	let blob = upload.freeze();
+	if (query.get("digest") != Some(&digest(&blob))) {
+		return HttpResponse::BadRequest().finish();
+	}
+	mock.pushed.lock().unwrap().insert(digest(&blob), blob.clone());
+	HttpResponse::Created().insert_header(("Location", format!("/v2/{image}/blobs/{}", digest(&blob)))).insert_header(("Docker-Content-Digest", digest(&blob))).finish()
+}
+
+async fn mock_put_manifest(req: HttpRequest, path: web::Path<(String, String)>, body: Bytes, mock: web::Data<MockUpstream>) -> HttpResponse {
+	if let Some(response) = push_unauthorized(&req) {
+		return response;
+	}
+	let (image, _) = path.into_inner();
+	mock.pushed.lock().unwrap().insert(digest(&body), body.clone());
+	HttpResponse::Created().insert_header(("Location", format!("/v2/{image}/manifests/{}", digest(&body)))).insert_header(("Docker-Content-Digest", digest(&body))).finish()
+}
+
+/// A storage root under the system temp directory, removed when the test is done with it.
+struct TempRoot(Utf8PathBuf);
+
+impl TempRoot {
+	fn new() -> Self {
+		static NEXT: AtomicUsize = AtomicUsize::new(0);
+		let path = std::env::temp_dir().join(format!("oci-registry-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+		Self(Utf8PathBuf::try_from(path).unwrap())
+	}
+}
+
+impl Drop for TempRoot {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_dir_all(&self.0);
+	}
+}
+
+struct Harness {
+	upstream: web::Data<MockUpstream>,
+	repo: Repository,
+	config: web::Data<RequestConfig>,
+	_root: TempRoot
+}
+
+/// Starts `mock`, and points a proxy with the given extra upstream settings (as YAML) at it.
+fn harness(mock: MockUpstream, settings: &str, check_cache_digest: bool) -> Harness {
+	harness_with(mock, settings, check_cache_digest, |config| config)
+}
+
+/// Like [`harness`], for tests that need more of the proxy configured.
+fn harness_with(mock: MockUpstream, settings: &str, check_cache_digest: bool, configure: impl FnOnce(RequestConfig) -> RequestConfig) -> Harness {
+	let (upstream, host) = mock.start();
+	let config: SingleUpstreamConfig = serde_yaml::from_str(&format!("namespace: {NAMESPACE}\nhost: \"{host}\"\ntls: false\ncircuit_failure_threshold: 0\n{settings}")).unwrap();
+	let clients = iter::once((CompactString::from(NAMESPACE), Client::try_from(config).unwrap())).collect::<Clients>();
+	let root = TempRoot::new();
+	let repo = Repository::Filesystem(filesystem::Repository::new(root.0.clone()));
+	let config = web::Data::new(configure(RequestConfig::new(repo.clone(), clients, NAMESPACE.into(), check_cache_digest, 4 * 1024 * 1024)));
+	Harness { upstream, repo, config, _root: root }
+}
+
+fn blob_storage_path(blob: &[u8]) -> String {
+	super::blob_storage_path(&digest(blob), &super::Access::Shared)
+}
+
+/// Blobs are written to storage in the background while they're streamed to the client; waits for
+/// the write to finish.
+async fn wait_for_blob(repo: &Repository, blob: &[u8]) {
+	for _ in 0..100 {
+		match repo.read(&blob_storage_path(blob), Duration::MAX).await {
+			Ok(stream) if stream.length() == blob.len() as u64 => return,
+			_ => rt::time::sleep(Duration::from_millis(10)).await
+		}
+	}
+	panic!("Blob was never written to storage");
+}
+
+#[actix_web::test]
+async fn manifest_miss_then_hit() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers().get("content-type").unwrap(), MANIFEST_MEDIA_TYPE);
+		assert_eq!(response.headers().get("docker-content-digest").unwrap().to_str().unwrap(), digest(manifest().as_bytes()));
+		assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	}
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+
+	// HEAD gets the same answer from the manifest's metadata, without reading it
+	let response = test::call_service(&app, test::TestRequest::default().method(http::Method::HEAD).uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(response.headers().get("content-type").unwrap(), MANIFEST_MEDIA_TYPE);
+	assert_eq!(response.headers().get("docker-content-digest").unwrap().to_str().unwrap(), digest(manifest().as_bytes()));
+	assert_eq!(response.response().body().size(), body::BodySize::Sized(manifest().len() as u64));
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn oversized_manifests_are_refused() {
+	let h = harness_with(MockUpstream::new(), "", false, |mut config| {
+		config.max_manifest_size = 16;
+		config
+	});
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+		assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+	}
+	// Never cached
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn cache_status_headers() {
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_cache_status_headers(true));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let status = |response: &actix_web::dev::ServiceResponse| (response.headers().get("x-cache").map(|v| v.to_str().unwrap().to_owned()), response.headers().contains_key("x-cache-age"));
+
+	let manifest = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+	let response = test::call_service(&app, test::TestRequest::get().uri(&manifest).to_request()).await;
+	assert_eq!(status(&response), (Some("MISS".to_owned()), false));
+	test::read_body(response).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&manifest).to_request()).await;
+	assert_eq!(status(&response), (Some("HIT".to_owned()), true));
+
+	let blob = format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB));
+	let response = test::call_service(&app, test::TestRequest::get().uri(&blob).to_request()).await;
+	assert_eq!(status(&response), (Some("MISS".to_owned()), false));
+	test::read_body(response).await;
+	wait_for_blob(&h.repo, LAYER_BLOB).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&blob).to_request()).await;
+	assert_eq!(status(&response), (Some("HIT".to_owned()), true));
+
+	// Off by default
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&manifest).to_request()).await;
+	assert_eq!(status(&response), (None, false));
+}
+
+#[actix_web::test]
+async fn purge_by_glob_and_age() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	for reference in ["latest".to_owned(), digest(manifest().as_bytes())] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{reference}")).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+	let latest = format!("manifests/{NAMESPACE}/{IMAGE}/latest");
+	let purge = |tag: &str, older_than: Option<&str>, dry_run: bool| crate::command::PurgeConfig {
+		namespace: NAMESPACE.into(),
+		repo: "library/*".to_owned(),
+		tag: Some(tag.to_owned()),
+		older_than: older_than.map(|v| v.parse().unwrap()),
+		tenant: None,
+		permanent: false,
+		dry_run
+	};
+
+	assert_eq!(super::purge::run(&h.repo, &purge("lat*", Some("1h"), false)).await.unwrap(), Vec::<String>::new());
+	assert_eq!(super::purge::run(&h.repo, &purge("lat*", None, true)).await.unwrap(), vec![latest.clone()]);
+	assert!(h.repo.read_manifest(&latest, Duration::MAX).await.is_ok());
+
+	assert_eq!(super::purge::run(&h.repo, &purge("lat*", None, false)).await.unwrap(), vec![latest.clone()]);
+	assert!(matches!(h.repo.read_manifest(&latest, Duration::MAX).await, Err(error) if error.is_not_found()));
+	assert_eq!(h.repo.list_trash().await.unwrap(), vec![latest]);
+	// The digest didn't match the tag glob
+	assert!(h.repo.read_manifest(&format!("manifests/{NAMESPACE}/{IMAGE}/{}", digest(manifest().as_bytes())), Duration::MAX).await.is_ok());
+}
+
+#[actix_web::test]
+async fn anonymous_tokens_are_reused() {
+	for (ttl, tokens) in [("60s", 1), ("0s", 3)] {
+		let h = harness(MockUpstream::new(), &format!("anonymous_token_ttl: {ttl}"), false);
+		let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+		for uri in [format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(CONFIG_BLOB)), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))] {
+			let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+			assert_eq!(response.status(), StatusCode::OK);
+			test::read_body(response).await;
+		}
+		assert_eq!(h.upstream.token_requests.load(Ordering::Relaxed), tokens, "{ttl}");
+	}
+}
+
+#[actix_web::test]
+async fn pulls_carry_the_configured_headers() {
+	let h = harness(MockUpstream::new(), "headers:\n  X-Egress-Owner: platform-team", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	for uri in [format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		test::read_body(response).await;
+	}
+	let pulls = h.upstream.pull_headers.lock().unwrap();
+	assert_eq!(pulls.len(), 2);
+	assert!(pulls.iter().all(|headers| headers.get("x-egress-owner").is_some_and(|v| v == "platform-team")));
+	assert!(pulls.iter().all(|headers| headers.get("authorization").is_some_and(|v| v == "Bearer mock")));
+}
+
+#[actix_web::test]
+async fn pulls_carry_the_trace_context() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = App::new().app_data(h.config.clone()).wrap_fn(|req, srv| {
+		if let Some(context) = super::trace::TraceContext::from_request(&req) {
+			req.extensions_mut().insert(context);
+		}
+		srv.call(req)
+	});
+	let app = test::init_service(app.configure(super::registry)).await;
+	for uri in [format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header(("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		test::read_body(response).await;
+	}
+	let pulls = h.upstream.pull_headers.lock().unwrap();
+	assert_eq!(pulls.len(), 2);
+	for headers in pulls.iter() {
+		// In the same trace, as a child of the client's span
+		let traceparent = headers.get("traceparent").unwrap().to_str().unwrap();
+		assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"), "{traceparent}");
+		assert!(!traceparent.contains("00f067aa0ba902b7"), "{traceparent}");
+	}
+}
+
+#[actix_web::test]
+async fn pulls_carry_the_request_id() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = App::new().app_data(h.config.clone()).wrap_fn(|req, srv| {
+		let request_id = super::request_id::RequestId::from_request(&req);
+		req.extensions_mut().insert(request_id);
+		srv.call(req)
+	});
+	let app = test::init_service(app.configure(super::registry)).await;
+	for uri in [format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header(("x-request-id", "ci-build-1234")).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		test::read_body(response).await;
+	}
+	let pulls = h.upstream.pull_headers.lock().unwrap();
+	assert_eq!(pulls.len(), 2);
+	// Untraced, but still tied to the client's request
+	assert!(pulls.iter().all(|headers| headers.get("x-request-id").is_some_and(|v| v == "ci-build-1234")));
+	assert!(pulls.iter().all(|headers| !headers.contains_key("traceparent")));
+}
+
+#[actix_web::test]
+async fn cached_blobs_are_rechecked_with_a_head() {
+	super::auth::set_partition_key(Some(b"integration-test-partition-key".to_vec()));
+	let h = harness(MockUpstream::new(), "auth_mode: passthrough\nentitlement_recheck_interval: 0s", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let pull = || test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).insert_header(("authorization", "Basic dXNlcjpwYXNz")).to_request();
+	// Pulled until it's been cached, and upstream's only asked whether these credentials can still
+	// have it
+	for _ in 0..100 {
+		let response = test::call_service(&app, pull()).await;
+		assert_eq!(test::read_body(response).await, LAYER_BLOB);
+		if (h.upstream.blob_head_requests.load(Ordering::Relaxed) > 0) {
+			break;
+		}
+		rt::time::sleep(Duration::from_millis(10)).await;
+	}
+	let gets = h.upstream.blob_requests.load(Ordering::Relaxed);
+	let response = test::call_service(&app, pull()).await;
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), gets);
+	assert_eq!(h.upstream.blob_head_requests.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn concurrent_anonymous_pulls_share_a_token() {
+	let h = harness(MockUpstream::new(), "auth_rate_limit: 5\nauth_burst: 1", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let pulls = [format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(CONFIG_BLOB)), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))].map(|uri| {
+		let app = &app;
+		async move {
+			let response = test::call_service(app, test::TestRequest::get().uri(&uri).to_request()).await;
+			assert_eq!(response.status(), StatusCode::OK);
+			test::read_body(response).await;
+		}
+	});
+	futures::future::join_all(pulls).await;
+	assert_eq!(h.upstream.token_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn upstream_under_base_path() {
+	let h = harness(MockUpstream { base_path: "/repository/docker-proxy", ..MockUpstream::new() }, "base_path: /repository/docker-proxy/", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn blob_miss_then_hit() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB));
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+	wait_for_blob(&h.repo, LAYER_BLOB).await;
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn expired_blob_is_only_refetched_when_configured() {
+	for (refetch, requests) in [(false, 1), (true, 2)] {
+		let h = harness(MockUpstream::new(), &format!("blob_invalidation_time: 1ms\nrefetch_expired_blobs: {refetch}"), false);
+		let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+		let uri = format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB));
+
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(test::read_body(response).await, LAYER_BLOB);
+		wait_for_blob(&h.repo, LAYER_BLOB).await;
+		rt::time::sleep(Duration::from_millis(10)).await;
+
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(test::read_body(response).await, LAYER_BLOB);
+		assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), requests, "refetch_expired_blobs: {refetch}");
+	}
+}
+
+#[actix_web::test]
+async fn known_blob_head_skips_storage() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB));
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+	wait_for_blob(&h.repo, LAYER_BLOB).await;
+	// Still answered from the index with the blob gone from storage, which is how we know storage
+	// wasn't asked
+	h.repo.delete(&blob_storage_path(LAYER_BLOB)).await.unwrap();
+	let response = test::call_service(&app, test::TestRequest::default().method(http::Method::HEAD).uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(response.response().body().size(), body::BodySize::Sized(LAYER_BLOB.len() as u64));
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn unknown_manifest() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/nope")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn expired_manifest_is_refetched() {
+	let h = harness(MockUpstream::new(), "manifest_invalidation_time: 1ms\nstale_policy: serve-stale-with-warning-header\nrevalidation: get", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	rt::time::sleep(Duration::from_millis(10)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert!(response.headers().get("warning").is_none());
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 2);
+
+	// With upstream down, the expired copy is served instead, marked as stale
+	h.upstream.failing.store(true, Ordering::Relaxed);
+	rt::time::sleep(Duration::from_millis(10)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert!(response.headers().get("warning").is_some());
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+}
+
+#[actix_web::test]
+async fn expired_tag_is_revalidated_with_head() {
+	let h = harness(MockUpstream::new(), "manifest_invalidation_time: 50ms", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	rt::time::sleep(Duration::from_millis(100)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	assert_eq!(h.upstream.manifest_head_requests.load(Ordering::Relaxed), 1);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+
+	// Revalidating restarted the cached copy's clock
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(h.upstream.manifest_head_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn expired_tag_is_revalidated_the_way_upstream_is_probed() {
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+	let h = harness(MockUpstream::new(), "manifest_invalidation_time: 50ms\nprobe: ranged-get", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		rt::time::sleep(Duration::from_millis(100)).await;
+	}
+	assert_eq!(h.upstream.manifest_range_requests.load(Ordering::Relaxed), 1);
+	assert_eq!(h.upstream.manifest_head_requests.load(Ordering::Relaxed), 0);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+
+	// Token-only probes can't ask, so the manifest is downloaded again
+	let h = harness(MockUpstream::new(), "manifest_invalidation_time: 50ms\nprobe: token", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		rt::time::sleep(Duration::from_millis(100)).await;
+	}
+	assert_eq!(h.upstream.manifest_range_requests.load(Ordering::Relaxed) + h.upstream.manifest_head_requests.load(Ordering::Relaxed), 0);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn invalidation_times_can_be_changed_at_runtime() {
+	let h = harness(MockUpstream::new(), "manifest_invalidation_time: 50ms", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+	let ttls = format!("/_admin/ttls/{NAMESPACE}");
+
+	let response = test::call_service(&app, test::TestRequest::put().uri(&ttls).to_request()).await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+	let response = test::call_service(&app, test::TestRequest::put().uri(&format!("{ttls}?manifest_invalidation_time=1h")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let body: serde_json::Value = test::read_body_json(response).await;
+	assert_eq!((body["manifest_invalidation_time"].as_str(), body["overridden"]["manifest_invalidation_time"].as_str()), (Some("1h"), Some("1h")));
+	// What isn't overridden stays as configured
+	assert_eq!(body["blob_invalidation_time"], "14days");
+
+	// The tag stays fresh for the hour
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		rt::time::sleep(Duration::from_millis(100)).await;
+	}
+	assert_eq!(h.upstream.manifest_head_requests.load(Ordering::Relaxed), 0);
+
+	// It's kept for the next start
+	let config: SingleUpstreamConfig = serde_yaml::from_str(&format!("namespace: {NAMESPACE}\nhost: example.com\nmanifest_invalidation_time: 50ms")).unwrap();
+	let mut clients = iter::once((CompactString::from(NAMESPACE), Client::try_from(config).unwrap())).collect::<Clients>();
+	super::ttl::load(&h.repo, &mut clients).await.unwrap();
+	assert_eq!(clients.get(NAMESPACE).unwrap().manifest_invalidation_time, Duration::from_secs(3600));
+
+	// And without the override, it's expired again
+	let response = test::call_service(&app, test::TestRequest::delete().uri(&ttls).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(h.upstream.manifest_head_requests.load(Ordering::Relaxed), 1);
+	let response = test::call_service(&app, test::TestRequest::get().uri("/_admin/ttls").to_request()).await;
+	assert_eq!(test::read_body(response).await, "{}");
+}
+
+#[actix_web::test]
+async fn image_stats_say_what_the_cache_served() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	for _ in 0..2 {
+		for uri in [format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))] {
+			let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+			assert_eq!(response.status(), StatusCode::OK);
+			test::read_body(response).await;
+		}
+	}
+
+	let stats: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/_admin/stats/image/{NAMESPACE}/{IMAGE}")).to_request()).await;
+	assert_eq!((stats["namespace"].as_str(), stats["image"].as_str()), (Some(NAMESPACE), Some(IMAGE)));
+	// Other tests pull the same image, so the counts are at least this test's
+	for kind in ["manifests", "blobs"] {
+		assert!(stats[kind]["hits"].as_u64().unwrap() >= 1, "{stats}");
+		assert!(stats[kind]["misses"].as_u64().unwrap() >= 1, "{stats}");
+	}
+	assert!(stats["bytes_from_upstream"].as_u64().unwrap() >= (manifest().len() + LAYER_BLOB.len()) as u64);
+	assert!(stats["last_upstream_fetch"].is_string());
+	assert!(stats["hit_ratio"].as_f64().is_some());
+	// What's cached is this instance's storage's alone
+	assert_eq!(stats["tags"], serde_json::json!([{ "tag": "latest", "digest": digest(manifest().as_bytes()), "cached": stats["tags"][0]["cached"] }]));
+	assert_eq!(stats["digests"], serde_json::json!([]));
+}
+
+#[actix_web::test]
+async fn caches_in_front_are_told_what_can_change() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let cache_control = |response: &actix_web::dev::ServiceResponse| response.headers().get(http::header::CACHE_CONTROL).map(|v| v.to_str().unwrap().to_owned());
+
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+		assert_eq!(cache_control(&response).as_deref(), Some("public, no-cache"));
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{}", digest(manifest().as_bytes()))).to_request()).await;
+		assert_eq!(cache_control(&response).as_deref(), Some("public, max-age=31536000, immutable"));
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+		assert_eq!(cache_control(&response).as_deref(), Some("public, max-age=31536000, immutable"));
+		let response = test::call_service(&app, test::TestRequest::default().method(http::Method::HEAD).uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+		assert_eq!(cache_control(&response).as_deref(), Some("public, max-age=31536000, immutable"));
+	}
+	// Pulled with credentials, it's only for whoever pulled it
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).insert_header(("Authorization", "Basic dXNlcjpwYXNz")).to_request()).await;
+	assert_eq!(cache_control(&response).as_deref(), Some("private, no-cache"));
+	// Nor is anything that failed to be cached
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/missing")).to_request()).await;
+	assert_eq!(cache_control(&response), None);
+}
+
+#[actix_web::test]
+async fn upstream_cache_control_decides_freshness() {
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+
+	// Longer than the namespace's invalidation time
+	let h = harness(MockUpstream { manifest_cache_control: Some("public, max-age=3600"), ..MockUpstream::new() }, "manifest_invalidation_time: 1ms\ncache_control: true", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		let max_age = response.headers().get(http::header::CACHE_CONTROL).unwrap().to_str().unwrap().strip_prefix("public, max-age=").unwrap().parse::<u64>().unwrap();
+		assert!(max_age > 3500 && max_age <= 3600, "{max_age}");
+		rt::time::sleep(Duration::from_millis(10)).await;
+	}
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+
+	// And shorter; the tag has to be revalidated on every pull
+	let h = harness(MockUpstream { manifest_cache_control: Some("no-cache"), ..MockUpstream::new() }, "cache_control: true", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(test::read_body(response).await, manifest().as_bytes());
+		rt::time::sleep(Duration::from_millis(10)).await;
+	}
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+	// One to find out how fresh it is, and one to revalidate it
+	assert_eq!(h.upstream.manifest_head_requests.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn edges_expire_tags_with_their_parent() {
+	let parent = harness(MockUpstream::new(), "manifest_invalidation_time: 10m", false);
+	let server = {
+		let config = parent.config.clone();
+		HttpServer::new(move || App::new().app_data(config.clone()).configure(super::registry)).workers(1).bind(("127.0.0.1", 0)).unwrap()
+	};
+	let parent_host = server.addrs()[0].to_string();
+	rt::spawn(server.run());
+
+	let settings: SingleUpstreamConfig = serde_yaml::from_str(&format!("namespace: {NAMESPACE}\nhost: \"{parent_host}\"\ntls: false\nparent: true\nmanifest_invalidation_time: 1h")).unwrap();
+	let root = TempRoot::new();
+	let repo = Repository::Filesystem(filesystem::Repository::new(root.0.clone()));
+	let clients = iter::once((CompactString::from(NAMESPACE), Client::try_from(settings).unwrap())).collect::<Clients>();
+	let edge = web::Data::new(RequestConfig::new(repo, clients, NAMESPACE.into(), false, 4 * 1024 * 1024));
+	let app = test::init_service(App::new().app_data(edge.clone()).configure(super::registry)).await;
+
+	// The parent's ten minutes win over the edge's hour
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let max_age = response.headers().get(http::header::CACHE_CONTROL).unwrap().to_str().unwrap().strip_prefix("public, max-age=").unwrap().parse::<u64>().unwrap();
+	assert!(max_age > 500 && max_age <= 600, "{max_age}");
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	assert_eq!(parent.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+
+	// Clients other than edges get the parent's usual headers
+	let parent_app = test::init_service(App::new().app_data(parent.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&parent_app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.headers().get(http::header::CACHE_CONTROL).unwrap(), "public, no-cache");
+}
+
+#[actix_web::test]
+async fn digest_addressed_manifest_never_expires() {
+	let h = harness(MockUpstream::new(), "manifest_invalidation_time: 1ms", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{}", digest(manifest().as_bytes()));
+
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(test::read_body(response).await, manifest().as_bytes());
+		rt::time::sleep(Duration::from_millis(10)).await;
+	}
+	assert_eq!(h.upstream.manifest_head_requests.load(Ordering::Relaxed), 0);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn refused_credentials_fall_back_to_anonymous_when_configured() {
+	for (fallback, status) in [(false, StatusCode::UNAUTHORIZED), (true, StatusCode::OK)] {
+		let mock = MockUpstream::new();
+		mock.reject_credentials.store(true, Ordering::Relaxed);
+		let h = harness(mock, &format!("username: revoked\npassword: hunter2\nanonymous_fallback: {fallback}"), false);
+		let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+		assert_eq!(response.status(), status, "anonymous_fallback: {fallback}");
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+		assert_eq!(response.status(), status, "anonymous_fallback: {fallback}");
+	}
+}
+
+#[actix_web::test]
+async fn cached_referrers_are_listed_by_subject() {
+	let subject = digest(manifest().as_bytes());
+	let signature = Bytes::from(format!(r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","artifactType":"application/vnd.dev.cosign.artifact.sig.v1+json","config":{{"mediaType":"application/vnd.oci.empty.v1+json","size":2,"digest":"sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"}},"layers":[],"subject":{{"mediaType":"{MANIFEST_MEDIA_TYPE}","size":{},"digest":"{subject}"}}}}"#, manifest().len()));
+	let mut mock = MockUpstream::new();
+	mock.manifests.insert(digest(&signature), signature.clone());
+	let h = harness(mock, "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/referrers/{subject}");
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	let index: serde_json::Value = test::read_body_json(response).await;
+	assert_eq!(index["manifests"], serde_json::json!([]));
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{}", digest(&signature))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.headers().get(http::header::CONTENT_TYPE).unwrap(), "application/vnd.oci.image.index.v1+json");
+	let index: serde_json::Value = test::read_body_json(response).await;
+	assert_eq!(index["manifests"][0]["digest"], digest(&signature));
+	assert_eq!(index["manifests"][0]["artifactType"], "application/vnd.dev.cosign.artifact.sig.v1+json");
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("{uri}?artifactType=application/spdx%2Bjson")).to_request()).await;
+	assert_eq!(response.headers().get("oci-filters-applied").unwrap(), "artifactType");
+	let index: serde_json::Value = test::read_body_json(response).await;
+	assert_eq!(index["manifests"], serde_json::json!([]));
+}
+
+#[actix_web::test]
+async fn cached_blobs_serve_ranges() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB));
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header((http::header::RANGE, "bytes=4-9")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+	assert_eq!(response.headers().get(http::header::CONTENT_RANGE).unwrap().to_str().unwrap(), format!("bytes 4-9/{}", LAYER_BLOB.len()));
+	assert_eq!(test::read_body(response).await, &LAYER_BLOB[4..10]);
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header((http::header::RANGE, format!("bytes={}-", LAYER_BLOB.len()))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn embedded_blobs_are_served_from_the_manifest() {
+	let embedded = base64::engine::general_purpose::STANDARD.encode(CONFIG_BLOB);
+	let manifest = manifest().replacen(&format!(r#""digest":"{}""#, digest(CONFIG_BLOB)), &format!(r#""digest":"{}","data":"{embedded}""#, digest(CONFIG_BLOB)), 1);
+	let mut mock = MockUpstream::new();
+	mock.manifests.insert("latest".to_owned(), Bytes::from(manifest));
+	let h = harness(mock, "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(CONFIG_BLOB));
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(response.headers().get("docker-content-digest").unwrap().to_str().unwrap(), digest(CONFIG_BLOB));
+	assert_eq!(test::read_body(response).await, CONFIG_BLOB);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header((http::header::RANGE, "bytes=0-0")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+	assert_eq!(test::read_body(response).await, &CONFIG_BLOB[..1]);
+	let response = test::call_service(&app, test::TestRequest::default().method(http::Method::HEAD).uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 0);
+
+	// The layer wasn't embedded, so it's pulled as usual
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn ranged_reads_of_uncached_blobs_pass_through() {
+	let mock = MockUpstream::new();
+	mock.serve_ranges.store(true, Ordering::Relaxed);
+	let h = harness(mock, "", true);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB));
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header((http::header::RANGE, "bytes=4-9")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+	assert_eq!(response.headers().get(http::header::CONTENT_RANGE).unwrap().to_str().unwrap(), format!("bytes 4-9/{}", LAYER_BLOB.len()));
+	assert_eq!(test::read_body(response).await, &LAYER_BLOB[4..10]);
+
+	// The whole blob is filled in the background, and the reads after that come from cache
+	wait_for_blob(&h.repo, LAYER_BLOB).await;
+	for _ in 0..100 {
+		if (h.config.known_blobs.get(&blob_storage_path(LAYER_BLOB)).is_some()) {
+			break;
+		}
+		rt::time::sleep(Duration::from_millis(10)).await;
+	}
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header((http::header::RANGE, "bytes=-3")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+	assert_eq!(test::read_body(response).await, &LAYER_BLOB[LAYER_BLOB.len() - 3..]);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn blobs_are_served_with_their_digest() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB));
+	// From upstream, then from cache, then from what's known to be cached
+	for request in [test::TestRequest::get(), test::TestRequest::get(), test::TestRequest::default().method(http::Method::HEAD)] {
+		let response = test::call_service(&app, request.uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers().get(http::header::CONTENT_TYPE).unwrap(), "application/octet-stream");
+		assert_eq!(response.headers().get("docker-content-digest").unwrap().to_str().unwrap(), digest(LAYER_BLOB));
+		assert_eq!(response.headers().get(http::header::ETAG).unwrap().to_str().unwrap(), format!("\"{}\"", digest(LAYER_BLOB)));
+	}
+}
+
+#[actix_web::test]
+async fn cached_images_are_searchable_by_label() {
+	let config = Bytes::from_static(br#"{"architecture":"amd64","os":"linux","config":{"Labels":{"org.opencontainers.image.version":"2.1"}},"rootfs":{"type":"layers","diff_ids":[]}}"#);
+	let labelled = Bytes::from(format!(
+		r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","size":{},"digest":"{}"}},"layers":[],"annotations":{{"org.opencontainers.image.source":"https://github.com/example/app"}}}}"#,
+		config.len(),
+		digest(&config)
+	));
+	let mut mock = MockUpstream::new();
+	mock.manifests.insert("2.1".to_owned(), labelled.clone());
+	mock.blobs.insert(digest(&config), config.clone());
+	let h = harness(mock, "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	for uri in [format!("/v2/{NAMESPACE}/{IMAGE}/manifests/2.1"), format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(&config))] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK, "{uri}");
+		test::read_body(response).await;
+	}
+
+	let search = |query: &str| test::TestRequest::get().uri(&format!("/_admin/search?{query}")).to_request();
+	let found: serde_json::Value = test::read_body_json(test::call_service(&app, search("label=org.opencontainers.image.source%3Dhttps://github.com/example/*")).await).await;
+	assert_eq!(found.as_array().unwrap().len(), 1);
+	assert_eq!(found[0]["reference"], "2.1");
+	assert_eq!(found[0]["digest"], digest(&labelled));
+	assert_eq!(found[0]["labels"]["org.opencontainers.image.version"], "2.1");
+	let found: serde_json::Value = test::read_body_json(test::call_service(&app, search("label=org.opencontainers.image.version%3D2.1")).await).await;
+	assert_eq!(found.as_array().unwrap().len(), 1);
+	// Config labels aren't annotations
+	let found: serde_json::Value = test::read_body_json(test::call_service(&app, search("annotation=org.opencontainers.image.version")).await).await;
+	assert_eq!(found, serde_json::json!([]));
+
+	// Purged images drop out
+	let response = test::call_service(&app, test::TestRequest::delete().uri(&format!("/_admin/{NAMESPACE}/{IMAGE}/manifests/2.1")).to_request()).await;
+	assert!(response.status().is_success());
+	let found: serde_json::Value = test::read_body_json(test::call_service(&app, search("label=org.opencontainers.image.source")).await).await;
+	assert_eq!(found, serde_json::json!([]));
+}
+
+#[actix_web::test]
+async fn purging_a_digest_takes_its_tags_and_blobs() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	for uri in [format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(CONFIG_BLOB)), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		test::read_body(response).await;
+	}
+	wait_for_blob(&h.repo, CONFIG_BLOB).await;
+	wait_for_blob(&h.repo, LAYER_BLOB).await;
+	let uri = format!("/_admin/{NAMESPACE}/{IMAGE}/manifests/{}", digest(manifest().as_bytes()));
+
+	let plan: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::delete().uri(&format!("{uri}?cascade=true&blobs=true&dry_run=true")).to_request()).await;
+	assert_eq!(plan["manifests"], serde_json::json!(["latest"]));
+	let mut blobs = vec![digest(CONFIG_BLOB), digest(LAYER_BLOB)];
+	blobs.sort();
+	assert_eq!(plan["blobs"], serde_json::json!(blobs));
+	assert_eq!(plan["dry_run"], true);
+	assert!(h.repo.stat(&blob_storage_path(LAYER_BLOB), Duration::MAX).await.is_ok());
+
+	let plan: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::delete().uri(&format!("{uri}?cascade=true&blobs=true&permanent=true")).to_request()).await;
+	assert_eq!(plan["dry_run"], false);
+	assert!(h.repo.stat_manifest(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Duration::MAX).await.is_err());
+	assert!(h.repo.stat(&blob_storage_path(CONFIG_BLOB), Duration::MAX).await.is_err());
+	assert!(h.repo.stat(&blob_storage_path(LAYER_BLOB), Duration::MAX).await.is_err());
+
+	// Nothing's cached with that digest any more, and a tag can't be cascaded from
+	let response = test::call_service(&app, test::TestRequest::delete().uri(&format!("{uri}?cascade=true")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+	let response = test::call_service(&app, test::TestRequest::delete().uri(&format!("/_admin/{NAMESPACE}/{IMAGE}/manifests/latest?dry_run=true")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn digest_addressed_manifest_survives_cleanup() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let by_digest = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{}", digest(manifest().as_bytes()));
+	for uri in [by_digest.clone(), format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+
+	// Only the tag (and its metadata) ages out
+	assert_eq!(h.repo.delete_old_manifests(NAMESPACE, SystemTime::now() + Duration::from_secs(60), &Default::default(), &mut Pacer::default()).await.unwrap(), 2);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&by_digest).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn mislabeled_manifest_is_refreshed_on_revalidation() {
+	let h = harness(MockUpstream::new(), "manifest_invalidation_time: 50ms", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let metadata = crate::storage::ManifestMetadata::new("application/vnd.oci.image.index.v1+json".to_owned(), Some(digest(manifest().as_bytes())));
+	h.repo.write_manifest(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Bytes::from(manifest()), &metadata).await.unwrap();
+	rt::time::sleep(Duration::from_millis(100)).await;
+
+	// Upstream agrees on the digest, but what we have is labeled wrong, so it's pulled again
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(response.headers().get(http::header::CONTENT_TYPE).unwrap(), MANIFEST_MEDIA_TYPE);
+	assert_eq!(h.upstream.manifest_head_requests.load(Ordering::Relaxed), 1);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn tampered_manifests_are_fetched_again() {
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_check_manifest_digest(true));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let reference = digest(manifest().as_bytes());
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{reference}");
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+
+	let metadata = crate::storage::ManifestMetadata::new(MANIFEST_MEDIA_TYPE.to_owned(), Some(reference.clone()));
+	let tampered = manifest().replace("schemaVersion", "schemaversion");
+	h.repo.write_manifest(&format!("manifests/{NAMESPACE}/{IMAGE}/{reference}"), Bytes::from(tampered), &metadata).await.unwrap();
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 2);
+}
+
+#[cfg(feature = "bundle")]
+#[actix_web::test]
+async fn bundles_carry_the_manifest_and_its_config() {
+	use base64::Engine;
+
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let bundle: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/bundle/latest")).to_request()).await;
+	let decode = |v: &serde_json::Value| base64::engine::general_purpose::STANDARD.decode(v.as_str().unwrap()).unwrap();
+	assert_eq!(bundle["manifest"]["mediaType"], MANIFEST_MEDIA_TYPE);
+	assert_eq!(bundle["manifest"]["digest"], digest(manifest().as_bytes()));
+	assert_eq!(decode(&bundle["manifest"]["data"]), manifest().as_bytes());
+	assert_eq!(bundle["config"]["digest"], digest(CONFIG_BLOB));
+	assert_eq!(decode(&bundle["config"]["data"]), CONFIG_BLOB);
+	// Both were cached on the way
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 1);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(CONFIG_BLOB))).to_request()).await;
+	assert_eq!(test::read_body(response).await, CONFIG_BLOB);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn expired_manifest_without_stale_policy() {
+	let h = harness(MockUpstream::new(), "manifest_invalidation_time: 1ms", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	h.upstream.failing.store(true, Ordering::Relaxed);
+	rt::time::sleep(Duration::from_millis(10)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[actix_web::test]
+async fn corrupt_blob_from_upstream_is_not_cached() {
+	let mock = MockUpstream::new();
+	mock.corrupt_blobs.store(true, Ordering::Relaxed);
+	let h = harness(mock, "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert!(body::to_bytes(response.into_body()).await.is_err());
+	rt::time::sleep(Duration::from_millis(100)).await;
+	assert!(h.repo.read(&blob_storage_path(LAYER_BLOB), Duration::MAX).await.is_err_and(|e| e.is_not_found()));
+}
+
+#[actix_web::test]
+async fn corrupt_blob_from_upstream_is_fetched_again() {
+	let mock = MockUpstream::new();
+	mock.corrupt_blob_responses.store(2, Ordering::Relaxed);
+	let h = harness(mock, "digest_mismatch_retries: 2", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB));
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert!(body::to_bytes(response.into_body()).await.is_err());
+	// Corrupt again the first time it's fetched again, and intact the second
+	wait_for_blob(&h.repo, LAYER_BLOB).await;
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 3);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 3);
+}
+
+#[actix_web::test]
+async fn corrupt_blob_in_cache_is_repaired() {
+	let h = harness(MockUpstream::new(), "", true);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let corrupted = Bytes::from_static(b"bit rot");
+	h.repo.write(&blob_storage_path(LAYER_BLOB), futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(corrupted.clone()))), corrupted.len() as i64).await.unwrap();
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 1);
+	wait_for_blob(&h.repo, LAYER_BLOB).await;
+}
+
+#[actix_web::test]
+async fn retry_without_namespace() {
+	let mock = MockUpstream::new();
+	mock.reject_namespace.store(true, Ordering::Relaxed);
+	let h = harness(mock, "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 2);
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(CONFIG_BLOB))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, CONFIG_BLOB);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn namespace_parameter_can_be_left_out_or_kept() {
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+	let mock = MockUpstream::new();
+	mock.reject_namespace.store(true, Ordering::Relaxed);
+	let h = harness(mock, "namespace_parameter: never", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+
+	// Upstream's refusal stands, rather than being tried again
+	let mock = MockUpstream::new();
+	mock.reject_namespace.store(true, Ordering::Relaxed);
+	let h = harness(mock, "namespace_parameter: always", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn images_are_cached_and_fetched_by_their_canonical_names() {
+	let h = harness(MockUpstream::new(), "image_names:\n  - rule: strip-prefix\n    prefix: corp/\n  - rule: regex\n    pattern: ^mirror/(.*)$\n    replacement: library/$1", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	for image in ["corp/library/busybox", "mirror/busybox", IMAGE] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{image}/manifests/latest")).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK, "{image}");
+		assert_eq!(test::read_body(response).await, manifest().as_bytes());
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{image}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK, "{image}");
+	}
+	// One copy, under the name upstream knows
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 1);
+	let tags: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/corp/{IMAGE}/tags/list")).to_request()).await;
+	assert_eq!(tags, serde_json::json!({ "name": format!("{NAMESPACE}/corp/{IMAGE}"), "tags": ["latest"] }));
+}
+
+#[actix_web::test]
+async fn pinned_listener_only_serves_its_namespace() {
+	// The address test requests come in at
+	let listener = "127.0.0.1:8080=mock".parse::<ListenerNamespace>().unwrap().pinned();
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_listener_namespaces(vec![listener]));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	for uri in [format!("/v2/{IMAGE}/manifests/latest"), format!("/v2/{IMAGE}/manifests/latest?ns={NAMESPACE}")] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	}
+	for uri in [format!("/v2/ghcr.io/{IMAGE}/manifests/latest"), format!("/v2/{IMAGE}/manifests/latest?ns=ghcr.io")] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+		let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+		assert_eq!(body["errors"][0]["code"], "NAME_UNKNOWN");
+	}
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn cached_repositories_and_tags_are_listed() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/tags/list")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+	for reference in ["latest".to_owned(), digest(manifest().as_bytes())] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{reference}")).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+	}
+
+	let catalog: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/v2/_catalog").to_request()).await;
+	assert_eq!(catalog, serde_json::json!({ "repositories": [format!("{NAMESPACE}/{IMAGE}")] }));
+	let tags: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/tags/list")).to_request()).await;
+	assert_eq!(tags, serde_json::json!({ "name": format!("{NAMESPACE}/{IMAGE}"), "tags": ["latest"] }));
+}
+
+#[actix_web::test]
+async fn cached_repositories_are_searched() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let results: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/v1/search?q=BusyBox&n=25").to_request()).await;
+	assert_eq!(results["num_results"], 1);
+	assert_eq!(results["num_pages"], 1);
+	assert_eq!(results["results"][0]["name"], format!("{NAMESPACE}/{IMAGE}"));
+	assert_eq!(results["results"][0]["tags"], serde_json::json!(["latest"]));
+	// By tag
+	let results: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/v1/search?q=lates").to_request()).await;
+	assert_eq!(results["num_results"], 1);
+	let results: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/v1/search?q=alpine").to_request()).await;
+	assert_eq!(results["num_results"], 0);
+	assert_eq!(results["results"], serde_json::json!([]));
+	// However many are asked for
+	let results: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/v1/search?q=busybox&n=100000").to_request()).await;
+	assert_eq!(results["page_size"], 100);
+}
+
+#[actix_web::test]
+async fn upstream_tags_are_listed_where_configured() {
+	let mut mock = MockUpstream::new();
+	let manifest = mock.manifests["latest"].clone();
+	mock.manifests.insert("1.36".to_owned(), manifest);
+	let h = harness(mock, "tag_list_ttl: 1h", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/tags/list");
+
+	// Nothing's been pulled, but upstream has the tags; a client paging through them only costs
+	// upstream one listing
+	let tags: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("{uri}?n=1")).to_request()).await;
+	assert_eq!(tags["tags"], serde_json::json!(["1.36"]));
+	let tags: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("{uri}?n=1&last=1.36")).to_request()).await;
+	assert_eq!(tags["tags"], serde_json::json!(["latest"]));
+	assert_eq!(h.upstream.tag_requests.load(Ordering::Relaxed), 1);
+
+	// Pages of upstream's list are asked for in turn
+	let upstream = h.config.upstream.lock().await.get(NAMESPACE).unwrap().clone();
+	let (page, more) = upstream.tags_page(IMAGE, 1, None).await.unwrap();
+	assert_eq!((page, more), (vec!["1.36".to_owned()], true));
+	let (page, more) = upstream.tags_page(IMAGE, 1, Some("1.36")).await.unwrap();
+	assert_eq!((page, more), (vec!["latest".to_owned()], false));
+
+	// With upstream down and no list yet, what's cached here is served
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	h.upstream.failing.store(true, Ordering::Relaxed);
+	let listed = super::TagLists::default().get(&upstream, IMAGE, Duration::from_secs(3600), vec!["latest".to_owned()]).await.unwrap();
+	assert_eq!(listed, vec!["latest".to_owned()]);
+	assert!(super::TagLists::default().get(&upstream, IMAGE, Duration::from_secs(3600), Vec::new()).await.is_err());
+	// The list from before is still served
+	let tags: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(tags["tags"], serde_json::json!(["1.36", "latest"]));
+}
+
+#[actix_web::test]
+async fn version_check_does_not_go_upstream() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	for _ in 0..3 {
+		let response = test::call_service(&app, test::TestRequest::get().uri("/v2/").to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()["docker-distribution-api-version"], "registry/2.0");
+		assert_eq!(response.headers()["content-type"], "application/json");
+		assert_eq!(test::read_body(response).await, "{}");
+	}
+	assert_eq!(h.upstream.token_requests.load(Ordering::Relaxed), 0);
+
+	let capabilities: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/v2/?ns={NAMESPACE}&capabilities=true")).to_request()).await;
+	assert_eq!(capabilities, serde_json::json!({ "namespace": NAMESPACE, "referrers": true, "tags": true, "push": false, "ranges": true }));
+}
+
+/// Manifests formatted and ordered the way serde wouldn't write them, with fields it doesn't know
+/// about, which a round trip through it would change.
+fn unusual_manifests() -> Vec<(&'static str, Bytes)> {
+	let schema2 = format!(
+		"{{\n   \"layers\" : [ {{ \"digest\": \"{}\", \"size\": {}, \"mediaType\": \"application/vnd.docker.image.rootfs.diff.tar.gzip\" }} ],\n   \"config\" : {{ \"digest\": \"{}\", \"size\": {}, \"mediaType\": \"application/vnd.docker.container.image.v1+json\" }},\n   \"mediaType\" : \"{MANIFEST_MEDIA_TYPE}\",\n   \"schemaVersion\" : 2\n}}\n",
+		digest(LAYER_BLOB),
+		LAYER_BLOB.len(),
+		digest(CONFIG_BLOB),
+		CONFIG_BLOB.len()
+	);
+	let index = format!(
+		r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[{{"platform":{{"os":"linux","architecture":"arm64"}},"size":{},"digest":"{}","mediaType":"application/vnd.oci.image.manifest.v1+json"}}],"annotations":{{"org.opencontainers.image.title":"café \/ bar"}}}}"#,
+		manifest().len(),
+		digest(manifest().as_bytes())
+	);
+	let artifact = format!(
+		r#"{{ "schemaVersion": 2, "mediaType": "application/vnd.oci.image.manifest.v1+json", "artifactType": "application/vnd.example.sbom+json", "config": {{ "mediaType": "application/vnd.oci.empty.v1+json", "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a", "size": 2 }}, "layers": [], "subject": {{ "mediaType": "{MANIFEST_MEDIA_TYPE}", "digest": "{}", "size": {} }}, "x-example-unknown": {{ "weights": [1.0, 1e3, -0.0], "nested": {{ "b": null, "a": true }} }} }}"#,
+		digest(manifest().as_bytes()),
+		manifest().len()
+	);
+	vec![("schema2", Bytes::from(schema2)), ("index", Bytes::from(index)), ("artifact", Bytes::from(artifact))]
+}
+
+#[actix_web::test]
+async fn manifests_are_served_byte_for_byte() {
+	let mut mock = MockUpstream::new();
+	for (tag, body) in unusual_manifests() {
+		mock.manifests.insert(tag.to_owned(), body.clone());
+		mock.manifests.insert(digest(&body), body);
+	}
+	let h = harness(mock, "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	for (tag, body) in unusual_manifests() {
+		// From upstream, from the cache, and by digest
+		for reference in [tag.to_owned(), tag.to_owned(), digest(&body)] {
+			let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{reference}")).to_request()).await;
+			assert_eq!(response.status(), StatusCode::OK, "{tag}");
+			assert_eq!(response.headers()["docker-content-digest"].to_str().unwrap(), digest(&body), "{tag}");
+			assert_eq!(test::read_body(response).await, body, "{tag} ({reference})");
+		}
+	}
+}
+
+#[actix_web::test]
+async fn manifest_digests_are_worked_out_where_upstream_leaves_them_out() {
+	let h = harness(MockUpstream { omit_manifest_digest: true, ..MockUpstream::new() }, "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers()["docker-content-digest"].to_str().unwrap(), digest(manifest().as_bytes()));
+		assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	}
+}
+
+#[actix_web::test]
+async fn tag_history() {
+	let h = harness(MockUpstream::new(), "manifest_invalidation_time: 1ms\nrevalidation: get", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		rt::time::sleep(Duration::from_millis(10)).await;
+	}
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 2);
+
+	// Fetched twice, but it hasn't moved
+	let history: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/_admin/history/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(history["tag"], "latest");
+	assert_eq!(history["history"].as_array().unwrap().len(), 1);
+	assert_eq!(history["history"][0]["digest"], digest(manifest().as_bytes()));
+	let history: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/_admin/history/{NAMESPACE}/{IMAGE}/manifests/other")).to_request()).await;
+	assert_eq!(history["history"], serde_json::json!([]));
+}
+
+#[actix_web::test]
+async fn annotation_only_updates_are_marked_in_tag_history() {
+	let h = harness(MockUpstream::new(), "manifest_invalidation_time: 1ms\nrevalidation: get", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	// What was cached before upstream signed it again
+	let signed = manifest().replacen('{', r#"{"annotations":{"signed":"yesterday"},"#, 1);
+	let metadata = crate::storage::ManifestMetadata::new(MANIFEST_MEDIA_TYPE.to_owned(), Some(digest(signed.as_bytes())));
+	h.repo.write_manifest(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Bytes::from(signed), &metadata).await.unwrap();
+	rt::time::sleep(Duration::from_millis(10)).await;
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	let history: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/_admin/history/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(history["history"][0]["digest"], digest(manifest().as_bytes()));
+	assert_eq!(history["history"][0]["annotations_only"], true);
+}
+
+#[actix_web::test]
+async fn held_tags_are_served_as_their_digest() {
+	let mut mock = MockUpstream::new();
+	// An older build of the image, no longer tagged
+	let old = Bytes::from(format!("{}\n", manifest()));
+	mock.manifests.insert(digest(&old), old.clone());
+	let h = harness(mock, "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+
+	let response = test::call_service(&app, test::TestRequest::put().uri(&format!("/_admin/pins/{NAMESPACE}/{IMAGE}/manifests/latest?digest={}", digest(&old))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let holds: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/_admin/holds").to_request()).await;
+	assert_eq!(holds, serde_json::json!({ format!("{NAMESPACE}/{IMAGE}:latest"): digest(&old) }));
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.headers().get("docker-content-digest").unwrap().to_str().unwrap(), digest(&old));
+	assert_eq!(test::read_body(response).await, old);
+	// Only digests can be what a tag's held at, and only tags can be held
+	let response = test::call_service(&app, test::TestRequest::put().uri(&format!("/_admin/pins/{NAMESPACE}/{IMAGE}/manifests/{}?digest={}", digest(&old), digest(&old))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+	let response = test::call_service(&app, test::TestRequest::put().uri(&format!("/_admin/pins/{NAMESPACE}/{IMAGE}/manifests/latest?digest=stable")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	let response = test::call_service(&app, test::TestRequest::delete().uri(&format!("/_admin/pins/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+}
+
+#[actix_web::test]
+async fn aliases_are_served_as_what_they_stand_for() {
+	let mut mock = MockUpstream::new();
+	let old = Bytes::from(format!("{}\n", manifest()));
+	mock.manifests.insert(digest(&old), old.clone());
+	let h = harness(mock, "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	let target = format!("{NAMESPACE}/{IMAGE}:1.36@{}", digest(&old));
+
+	let response = test::call_service(&app, test::TestRequest::put().uri(&format!("/_admin/aliases/corp/base/manifests/approved?target={target}")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let aliases: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/_admin/aliases").to_request()).await;
+	assert_eq!(aliases, serde_json::json!({ "corp/base:approved": target }));
+	let response = test::call_service(&app, test::TestRequest::get().uri("/v2/corp/base/manifests/approved").to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(response.headers().get("docker-content-digest").unwrap().to_str().unwrap(), digest(&old));
+	assert_eq!(test::read_body(response).await, old);
+	// What the alias's manifest references comes from the image it stands for
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/corp/base/manifests/{}", digest(&old))).to_request()).await;
+	assert_eq!(test::read_body(response).await, old);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/corp/base/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+
+	// Another alias in the same repository has to stand for the same image
+	let response = test::call_service(&app, test::TestRequest::put().uri(&format!("/_admin/aliases/corp/base/manifests/next?target={NAMESPACE}/library/alpine:3.19")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::CONFLICT);
+	let response = test::call_service(&app, test::TestRequest::put().uri("/_admin/aliases/corp/base/manifests/next?target=alpine").to_request()).await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+	let response = test::call_service(&app, test::TestRequest::delete().uri("/_admin/aliases/corp/base/manifests/approved").to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let aliases: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/_admin/aliases").to_request()).await;
+	assert_eq!(aliases, serde_json::json!({}));
+	// Kept in storage, for the next start
+	let reloaded = super::Aliases::load(&h.repo, None).await.unwrap();
+	assert!(reloaded.all().await.is_empty());
+}
+
+#[actix_web::test]
+async fn maintenance_mode() {
+	let mut mock = MockUpstream::new();
+	let manifest = mock.manifests["latest"].clone();
+	mock.manifests.insert("1.36".to_owned(), manifest);
+	let h = harness(mock, "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let status: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::put().uri("/_admin/maintenance?mode=unavailable&retry_after=120").to_request()).await;
+	assert_eq!(status["mode"], "unavailable");
+	assert_eq!(status["retry_after_seconds"], 120);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+	assert_eq!(response.headers().get("retry-after").unwrap(), "120");
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+	// What's cached is still served, but nothing goes to upstream
+	test::call_service(&app, test::TestRequest::put().uri("/_admin/maintenance?mode=cache-only").to_request()).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/1.36")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+	assert_eq!(response.headers().get("retry-after").unwrap(), "30");
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+
+	let status: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::put().uri("/_admin/maintenance?mode=off").to_request()).await;
+	assert_eq!(status["mode"], "off");
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/1.36")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn blobs_on_other_shards_are_redirected() {
+	// This node takes no share of the ring, as while it's being drained
+	let shards = super::Shards::parse(b"- url: http://self:5000\n  weight: 0\n- http://other:5000/\n", "http://self:5000").unwrap();
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_shards(Some(std::sync::Arc::new(shards))));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB));
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+	let location = response.headers().get("location").unwrap().to_str().unwrap().to_owned();
+	assert_eq!(location, format!("http://other:5000{uri}?shard-hop=1"));
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 0);
+
+	// Once redirected, a pull is served wherever it lands, as are pulls with credentials
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("{uri}?shard-hop=1")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header(("authorization", "Basic dXNlcjpwYXNz")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	// Manifests aren't sharded
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn pinned_images_are_protected() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let response = test::call_service(&app, test::TestRequest::put().uri(&format!("/_admin/pins/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let pins: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/_admin/pins").to_request()).await;
+	assert_eq!(pins, serde_json::json!([format!("{NAMESPACE}/{IMAGE}:latest")]));
+
+	// Pinned before its blobs were ever pulled, but they're protected all the same
+	let keep = h.config.pins.protected_paths(&h.repo).await;
+	assert!(keep.contains(&format!("manifests/{NAMESPACE}/{IMAGE}/latest")));
+	assert!(keep.contains(&blob_storage_path(CONFIG_BLOB)));
+	assert!(keep.contains(&blob_storage_path(LAYER_BLOB)));
+	assert_eq!(h.repo.delete_old_manifests(NAMESPACE, SystemTime::now() + Duration::from_secs(60), &keep, &mut Pacer::default()).await.unwrap(), 0);
+
+	let response = test::call_service(&app, test::TestRequest::delete().uri(&format!("/_admin/pins/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let pins: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/_admin/pins").to_request()).await;
+	assert_eq!(pins, serde_json::json!([]));
+	assert_eq!(h.repo.delete_old_manifests(NAMESPACE, SystemTime::now() + Duration::from_secs(60), &Default::default(), &mut Pacer::default()).await.unwrap(), 2);
+}
+
+#[actix_web::test]
+async fn mirror_pulls_matching_tags() {
+	let mut mock = MockUpstream::new();
+	let manifest = mock.manifests["latest"].clone();
+	mock.manifests.insert("1.36".to_owned(), manifest.clone());
+	mock.manifests.insert("1.36-musl".to_owned(), manifest);
+	let h = harness(mock, "", false);
+	let entries: Vec<super::mirror::Entry> = serde_yaml::from_str(&format!("- repository: {NAMESPACE}/{IMAGE}\n  tags: [\"1.*\"]")).unwrap();
+
+	let status = serde_json::to_value(super::mirror::reconcile(&h.config, &entries[0]).await).unwrap();
+	assert_eq!(status["tags"], serde_json::json!(["1.36", "1.36-musl"]));
+	assert_eq!(status["cached"], 2);
+	assert_eq!(status["errors"], serde_json::json!([]));
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/{IMAGE}/1.36-musl"), Duration::MAX).await.is_ok());
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Duration::MAX).await.is_err());
+	wait_for_blob(&h.repo, CONFIG_BLOB).await;
+	wait_for_blob(&h.repo, LAYER_BLOB).await;
+
+	// Everything's cached now, so another pass only lists tags
+	let blob_requests = h.upstream.blob_requests.load(Ordering::Relaxed);
+	let status = serde_json::to_value(super::mirror::reconcile(&h.config, &entries[0]).await).unwrap();
+	assert_eq!(status["cached"], 2);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 2);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), blob_requests);
+}
+
+#[actix_web::test]
+async fn probe_pulls_from_upstream_every_time() {
+	let h = harness(MockUpstream::new(), "", false);
+	let image: super::pins::Pin = format!("{NAMESPACE}/{IMAGE}:latest").parse().unwrap();
+
+	for requests in 1..=2 {
+		super::probe::probe(&h.config, &image).await.unwrap();
+		assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), requests);
+	}
+	wait_for_blob(&h.repo, CONFIG_BLOB).await;
+
+	h.upstream.failing.store(true, Ordering::Relaxed);
+	let failure = super::probe::probe(&h.config, &image).await.unwrap_err();
+	assert_eq!(failure.stage, super::probe::Stage::Upstream);
+}
+
+#[actix_web::test]
+async fn webhook_invalidates_tags() {
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_webhook_token(Some("secret".into())));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let payload = serde_json::json!({ "push_data": { "tag": "latest" }, "repository": { "repo_name": IMAGE } });
+
+	let response = test::call_service(&app, test::TestRequest::post().uri(&format!("/_admin/webhook/{NAMESPACE}?token=wrong")).set_json(&payload).to_request()).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Duration::MAX).await.is_ok());
+
+	let response = test::call_service(&app, test::TestRequest::post().uri(&format!("/_admin/webhook/{NAMESPACE}")).insert_header(("Authorization", "Bearer secret")).set_json(&payload).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body_json::<serde_json::Value, _>(response).await, serde_json::json!({ "invalidated": [format!("{NAMESPACE}/{IMAGE}:latest")] }));
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Duration::MAX).await.is_err());
+
+	let response = test::call_service(&app, test::TestRequest::post().uri(&format!("/_admin/webhook/{NAMESPACE}?token=secret&refresh=true")).set_json(&payload).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Duration::MAX).await.is_ok());
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 2);
+}
+
+#[actix_web::test]
+async fn signed_urls_serve_until_they_expire() {
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_signing_key(Some("secret"), Duration::from_secs(3600)).with_admin_token(Some("admin".into())));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::admin).configure(super::signed::configure)).await;
+	let sign = |uri: String| test::TestRequest::post().uri(&uri).insert_header((super::admin_token::HEADER, "admin")).to_request();
+
+	// Only with the admin token
+	let response = test::call_service(&app, test::TestRequest::post().uri(&format!("/_admin/sign/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let response = test::call_service(&app, sign(format!("/_admin/sign/{NAMESPACE}/{IMAGE}/blobs/{}?ttl=2h", digest(LAYER_BLOB)))).await;
+	assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+	let response = test::call_service(&app, sign(format!("/_admin/sign/{NAMESPACE}/{IMAGE}/blobs/{}?ttl=10m", digest(LAYER_BLOB)))).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let body = test::read_body_json::<serde_json::Value, _>(response).await;
+	let url = body["url"].as_str().unwrap();
+	let uri = &url[url.find("/_signed/").unwrap()..];
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+
+	// Anything else under /_signed, or the same URL with a different expiry, is refused
+	let (path, _) = uri.split_once('?').unwrap();
+	let response = test::call_service(&app, test::TestRequest::get().uri(path).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	let tampered = uri.replacen("expires=", "expires=9", 1);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&tampered).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	let other = uri.replace(&digest(LAYER_BLOB), &digest(b"other"));
+	let response = test::call_service(&app, test::TestRequest::get().uri(&other).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 1);
+}
+
+#[actix_web::test]
+async fn signed_urls_serve_the_minting_tenants_cache() {
+	let yaml = format!("- name: team-a\n  username: a\n  password: secret-a\n  namespaces: [{NAMESPACE}]\n- name: team-b\n  username: b\n  password: secret-b\n  namespaces: [ghcr.io]");
+	let tenants = std::sync::Arc::new(super::Tenants::parse(yaml.as_bytes()).unwrap());
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_signing_key(Some("secret"), Duration::from_secs(3600)).with_admin_token(Some("admin".into())).with_tenants(Some(tenants.clone())));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::admin).configure(super::signed::configure)).await;
+	let sign = |credentials: &str| test::TestRequest::post().uri(&format!("/_admin/sign/{NAMESPACE}/{IMAGE}/manifests/latest")).insert_header((super::admin_token::HEADER, "admin")).insert_header(("Authorization", format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials)))).to_request();
+
+	// A tenant that can't pull from the namespace can't mint for it either
+	let response = test::call_service(&app, sign("b:secret-b")).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	let response = test::call_service(&app, sign("a:secret-a")).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let body = test::read_body_json::<serde_json::Value, _>(response).await;
+	let url = body["url"].as_str().unwrap();
+	let uri = &url[url.find("/_signed/").unwrap()..];
+	assert!(uri.ends_with("&tenant=team-a"));
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/_tenant/team-a/{IMAGE}/latest"), Duration::MAX).await.is_ok());
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Duration::MAX).await.is_err());
+	// Nor can it be moved to another tenant's cache, or the shared one
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri.replace("team-a", "team-b")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	let response = test::call_service(&app, test::TestRequest::get().uri(uri.trim_end_matches("&tenant=team-a")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+/// What a CDN purge hook has been sent, with the `Authorization` it came with
+type Purges = Mutex<Vec<(Option<String>, serde_json::Value)>>;
+
+async fn record_purge(req: HttpRequest, body: web::Json<serde_json::Value>, purges: web::Data<Purges>) -> HttpResponse {
+	let authorization = req.headers().get("authorization").and_then(|v| v.to_str().ok()).map(str::to_owned);
+	purges.lock().unwrap().push((authorization, body.into_inner()));
+	HttpResponse::Ok().finish()
+}
+
+#[actix_web::test]
+async fn cdn_origin_requests_need_a_secret_and_purges_are_passed_on() {
+	let purges = web::Data::new(Purges::default());
+	let server = {
+		let purges = purges.clone();
+		HttpServer::new(move || App::new().app_data(purges.clone()).route("/purge", web::post().to(record_purge))).workers(1).bind(("127.0.0.1", 0)).unwrap()
+	};
+	let url = format!("http://{}/purge", server.addrs()[0]).parse().unwrap();
+	rt::spawn(server.run());
+	let cdn = super::cdn::Cdn::new(http::header::HeaderName::from_static(super::cdn::DEFAULT_HEADER), vec!["secret".into()]).with_purge(url, Some("purge-token".into()));
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_cdn(Some(cdn)));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header((super::cdn::DEFAULT_HEADER, "wrong")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 0);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header((super::cdn::DEFAULT_HEADER, "secret")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	// The admin API isn't for the CDN
+	let response = test::call_service(&app, test::TestRequest::delete().uri(&format!("/_admin/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	for _ in 0..100 {
+		if (!purges.lock().unwrap().is_empty()) {
+			break;
+		}
+		rt::time::sleep(Duration::from_millis(10)).await;
+	}
+	let purges = purges.lock().unwrap();
+	let (authorization, purge) = purges.first().expect("Purge was never sent");
+	assert_eq!(authorization.as_deref(), Some("Bearer purge-token"));
+	assert_eq!(purge["kind"], "manifest");
+	assert_eq!(purge["reference"], "latest");
+	// The default namespace can be left out of the path
+	assert_eq!(purge["paths"], serde_json::json!([uri, format!("/v2/{IMAGE}/manifests/latest?ns={NAMESPACE}"), format!("/v2/{IMAGE}/manifests/latest")]));
+}
+
+#[actix_web::test]
+async fn cached_objects_say_where_they_came_from() {
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_provenance(true));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+	test::read_body(response).await;
+
+	let provenance: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/_admin/provenance/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(provenance["namespace"], NAMESPACE);
+	assert_eq!(provenance["upstream_image"], IMAGE);
+	assert_eq!(provenance["upstream_digest"], digest(manifest().as_bytes()));
+	assert_eq!(provenance["version"], env!("CARGO_PKG_VERSION"));
+	assert!(provenance["upstream"].as_str().unwrap().starts_with("http://127.0.0.1:"));
+	let blob_uri = format!("/_admin/provenance/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB));
+	let mut found = false;
+	for _ in 0..100 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&blob_uri).to_request()).await;
+		if (response.status() == StatusCode::OK) {
+			found = true;
+			break;
+		}
+		rt::time::sleep(Duration::from_millis(10)).await;
+	}
+	assert!(found, "Blob provenance was never recorded");
+
+	// Gone with the manifest, once it's purged for good
+	let response = test::call_service(&app, test::TestRequest::delete().uri(&format!("/_admin/{NAMESPACE}/{IMAGE}/manifests/latest?permanent=true")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/_admin/provenance/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn cached_manifests_are_attested_to() {
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_timestamper(super::Timestamper::new(None, Some("attestation-key"))));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let mut attestation = None;
+	for _ in 0..100 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/_admin/timestamps/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+		if (response.status() == StatusCode::OK) {
+			attestation = Some(test::read_body_json::<serde_json::Value, _>(response).await);
+			break;
+		}
+		rt::time::sleep(Duration::from_millis(10)).await;
+	}
+	let attestation = attestation.expect("Manifest was never attested to");
+	let manifest_digest = digest(manifest().as_bytes());
+	assert_eq!(attestation["digest"], manifest_digest);
+	let hmac = super::signed::hmac(b"attestation-key", format!("{manifest_digest}\n{}", attestation["time"].as_str().unwrap()).as_bytes());
+	assert_eq!(attestation["hmac"], base64::engine::general_purpose::STANDARD.encode(hmac));
+
+	// The same attestation, looked up by digest
+	let by_digest: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri(&format!("/_admin/timestamps/{NAMESPACE}/{IMAGE}/manifests/{manifest_digest}")).to_request()).await;
+	assert_eq!(by_digest, attestation);
+}
+
+#[actix_web::test]
+async fn purged_manifest_is_restored() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let response = test::call_service(&app, test::TestRequest::delete().uri(&format!("/_admin/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Duration::MAX).await.is_err());
+	let trash: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/_admin/trash").to_request()).await;
+	assert_eq!(trash, serde_json::json!([format!("manifests/{NAMESPACE}/{IMAGE}/latest")]));
+
+	let response = test::call_service(&app, test::TestRequest::post().uri(&format!("/_admin/trash/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+
+	// Nothing left to restore, and a permanent purge skips the trash
+	let response = test::call_service(&app, test::TestRequest::post().uri(&format!("/_admin/trash/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+	let response = test::call_service(&app, test::TestRequest::delete().uri(&format!("/_admin/{NAMESPACE}/{IMAGE}/manifests/latest?permanent=true")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(h.repo.list_trash().await.unwrap(), Vec::<String>::new());
+}
+
+#[actix_web::test]
+async fn info_leaves_out_credentials() {
+	let h = harness(MockUpstream::new(), "username: someone\npassword: hunter2", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::admin)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri("/_admin/info").to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let body = test::read_body(response).await;
+	assert!(!String::from_utf8_lossy(&body).contains("hunter2"));
+	let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+	assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+	assert_eq!(info["storage"], "filesystem");
+	assert_eq!(info["upstreams"][0]["namespace"], NAMESPACE);
+	assert_eq!(info["upstreams"][0]["credentials"], true);
+}
+
+#[actix_web::test]
+async fn namespaces_show_effective_settings_and_health() {
+	let h = harness(MockUpstream::new(), "username: someone\npassword: hunter2\ntag_list_ttl: 1h\nauth_mode: proxy", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::admin)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri("/_admin/namespaces").to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let body = test::read_body(response).await;
+	assert!(!String::from_utf8_lossy(&body).contains("hunter2"));
+	let namespaces: serde_json::Value = serde_json::from_slice(&body).unwrap();
+	assert_eq!(namespaces[0]["namespace"], NAMESPACE);
+	assert_eq!(namespaces[0]["auth_mode"], "proxy");
+	assert_eq!(namespaces[0]["tag_list_ttl"], "1h");
+	assert_eq!(namespaces[0]["health"]["circuit_open"], false);
+	assert_eq!(namespaces[0]["health"]["consecutive_failures"], 0);
+
+	// Ones that aren't configured show the defaults they'd get
+	let namespace: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/_admin/namespaces/quay.io").to_request()).await;
+	assert_eq!(namespace["host"], "quay.io");
+	assert_eq!(namespace["tag_list_ttl"], serde_json::Value::Null);
+}
+
+#[actix_web::test]
+async fn checkpoint_keeps_known_blobs() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+	wait_for_blob(&h.repo, LAYER_BLOB).await;
+	// Answered from storage, which leaves the blob known
+	let response = test::call_service(&app, test::TestRequest::default().method(http::Method::HEAD).uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	super::checkpoint::save(&h.config).await.unwrap();
+
+	let restored = RequestConfig::new(h.repo.clone(), iter::empty().collect(), NAMESPACE.into(), false, 4 * 1024 * 1024);
+	assert_eq!(restored.known_blobs.get(&blob_storage_path(LAYER_BLOB)), None);
+	super::checkpoint::restore(&restored).await.unwrap();
+	assert_eq!(restored.known_blobs.get(&blob_storage_path(LAYER_BLOB)), Some(LAYER_BLOB.len() as u64));
+}
+
+#[actix_web::test]
+async fn checkpoint_warms_up_anonymous_tokens() {
+	let h = harness(MockUpstream::new(), "anonymous_token_ttl: 60s", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(h.upstream.token_requests.load(Ordering::Relaxed), 1);
+	super::checkpoint::save(&h.config).await.unwrap();
+
+	// As if restarted, with no tokens left
+	let scopes = h.config.upstream.lock().await.recent_anonymous_scopes(Duration::from_secs(60));
+	assert_eq!(scopes.len(), 1);
+	for (namespace, scope, _) in scopes {
+		h.config.upstream.lock().await.get(&namespace).unwrap().anonymous_tokens.forget(&scope);
+	}
+	let restored = super::checkpoint::restore(&h.config).await.unwrap();
+	restored.warm_up(&h.config, 100).await;
+	assert_eq!(h.upstream.token_requests.load(Ordering::Relaxed), 2);
+
+	// The first pull after the restart takes the token that's waiting for it
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(CONFIG_BLOB))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(h.upstream.token_requests.load(Ordering::Relaxed), 2);
+}
+
+fn schema1_manifest() -> String {
+	let v1 = serde_json::json!({ "id": "a", "architecture": "amd64", "os": "linux", "created": "2016-01-01T00:00:00Z", "config": { "Cmd": ["sh"] }, "container_config": { "Cmd": ["/bin/sh", "-c", "#(nop) ADD file:abc in /"] } });
+	serde_json::json!({ "schemaVersion": 1, "name": IMAGE, "tag": "old", "architecture": "amd64", "fsLayers": [{ "blobSum": digest(LAYER_BLOB) }], "history": [{ "v1Compatibility": v1.to_string() }] }).to_string()
+}
+
+#[actix_web::test]
+async fn schema1_manifests_are_converted_or_rejected() {
+	let mut mock = MockUpstream::new();
+	for reference in ["old".to_owned(), digest(schema1_manifest().as_bytes())] {
+		mock.manifests.insert(reference, Bytes::from(schema1_manifest()));
+	}
+	let h = harness(mock, "schema1: convert", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/old")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(response.headers().get(http::header::CONTENT_TYPE).unwrap(), MANIFEST_MEDIA_TYPE);
+	let body = test::read_body(response).await;
+	let converted: serde_json::Value = serde_json::from_slice(&body).unwrap();
+	assert_eq!(converted["layers"][0]["digest"], digest(LAYER_BLOB));
+	assert_eq!(converted["layers"][0]["size"], LAYER_BLOB.len());
+
+	// Clients go on to ask for the digest they were given, which upstream has never heard of
+	let requests = h.upstream.manifest_requests.load(Ordering::Relaxed);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{}", digest(&body))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, body);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), requests);
+
+	// The image config is served from the cache; upstream never had it
+	let config_digest = converted["config"]["digest"].as_str().unwrap().to_owned();
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{config_digest}")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let config: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+	assert_eq!(config["rootfs"]["diff_ids"], serde_json::json!([digest(LAYER_BLOB)]));
+	assert_eq!(config["config"]["Cmd"], serde_json::json!(["sh"]));
+	assert!(config.get("id").is_none());
+
+	// By digest, it can't be converted
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{}", digest(schema1_manifest().as_bytes()))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[actix_web::test]
+async fn pushes_are_written_through() {
+	let h = harness(MockUpstream::new(), "write_through: true\npush_username: ci\npush_password: hunter2", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let blob: &'static [u8] = b"a layer fresh out of CI";
+	fn header<B>(response: &actix_web::dev::ServiceResponse<B>, name: &str) -> String {
+		response.headers().get(name).unwrap().to_str().unwrap().to_owned()
+	}
+
+	let response = test::call_service(&app, test::TestRequest::post().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/uploads/")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::ACCEPTED);
+	let location = header(&response, "location");
+	assert!(location.starts_with(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/uploads/")), "{location}");
+	let response = test::call_service(&app, test::TestRequest::patch().uri(&location).set_payload(blob).to_request()).await;
+	assert_eq!(response.status(), StatusCode::ACCEPTED);
+	let location = header(&response, "location");
+	let response = test::call_service(&app, test::TestRequest::put().uri(&format!("{location}?digest={}", digest(blob))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::CREATED);
+	assert_eq!(header(&response, "location"), format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(blob)));
+	assert_eq!(h.upstream.pushed.lock().unwrap().get(&digest(blob)), Some(&Bytes::from_static(blob)));
+	// Pulled back into the cache in the background
+	wait_for_blob(&h.repo, blob).await;
+
+	let response = test::call_service(&app, test::TestRequest::put().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/ci")).insert_header(("content-type", MANIFEST_MEDIA_TYPE)).set_payload(manifest()).to_request()).await;
+	assert_eq!(response.status(), StatusCode::CREATED);
+	assert_eq!(header(&response, "location"), format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{}", digest(manifest().as_bytes())));
+	assert!(h.upstream.pushed.lock().unwrap().contains_key(&digest(manifest().as_bytes())));
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/ci")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(response.headers().get("content-type").unwrap(), MANIFEST_MEDIA_TYPE);
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 0);
+
+	// Session IDs are only followed to upstream
+	let session = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode("http://127.0.0.1:1/v2/");
+	let response = test::call_service(&app, test::TestRequest::patch().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/uploads/{session}")).set_payload(blob).to_request()).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn pushes_need_write_through() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::post().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/uploads/")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+	assert!(h.upstream.uploads.lock().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn invalid_names_and_references_are_refused() {
+	let h = harness(MockUpstream::new(), "", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	for (uri, code) in [
+		(format!("/v2/{NAMESPACE}/Library/Alpine/manifests/latest"), "NAME_INVALID"),
+		(format!("/v2/{NAMESPACE}/library//alpine/tags/list"), "NAME_INVALID"),
+		(format!("/v2/{NAMESPACE}/{IMAGE}/manifests/.latest"), "TAG_INVALID"),
+		(format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{}", "a".repeat(129)), "TAG_INVALID"),
+		(format!("/v2/{NAMESPACE}/{IMAGE}/manifests/sha256:abc"), "DIGEST_INVALID")
+	] {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+		assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+		let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+		assert_eq!(body["errors"][0]["code"], code, "{uri}");
+	}
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 0);
+}
+
+#[actix_web::test]
+async fn oversized_requests_are_refused() {
+	let limits = Limits { max_header_size: 1024, max_upload_chunk_size: 8 };
+	let h = harness_with(MockUpstream::new(), "write_through: true\npush_username: ci\npush_password: hunter2", false, |config| config.with_limits(limits));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header(("x-padding", "a".repeat(2048))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+	let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+	assert_eq!(body["errors"][0]["code"], "DENIED");
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 0);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let blob: &'static [u8] = b"a layer too big for one chunk";
+	let response = test::call_service(&app, test::TestRequest::post().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/uploads/?digest={}", digest(blob))).set_payload(blob).to_request()).await;
+	assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+	let body: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+	assert_eq!(body["errors"][0]["code"], "SIZE_INVALID");
+	assert!(h.upstream.pushed.lock().unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn manifests_are_compressed_for_clients_that_ask() {
+	for enabled in [true, false] {
+		let h = harness_with(MockUpstream::new(), "", false, |config| config.with_compression(enabled));
+		let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+		let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+
+		let response = test::call_service(&app, test::TestRequest::get().uri(&uri).insert_header(("Accept-Encoding", "br, gzip")).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(response.headers().get("docker-content-digest").unwrap().to_str().unwrap(), digest(manifest().as_bytes()));
+		assert_eq!(response.headers().get(http::header::CONTENT_ENCODING).map(|v| v.to_str().unwrap()), enabled.then_some("gzip"));
+		let body = test::read_body(response).await;
+		match enabled {
+			true => {
+				let mut decoded = String::new();
+				std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded).unwrap();
+				assert_eq!(decoded, manifest());
+			},
+			false => assert_eq!(body, manifest().as_bytes())
+		};
+
+		// Never for HEAD, or for blobs
+		let response = test::call_service(&app, test::TestRequest::default().method(http::Method::HEAD).uri(&uri).insert_header(("Accept-Encoding", "gzip")).to_request()).await;
+		assert_eq!(response.headers().get(http::header::CONTENT_ENCODING), None);
+		assert_eq!(response.response().body().size(), body::BodySize::Sized(manifest().len() as u64));
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(CONFIG_BLOB))).insert_header(("Accept-Encoding", "gzip, zstd")).to_request()).await;
+		assert_eq!(response.headers().get(http::header::CONTENT_ENCODING), None);
+		assert_eq!(test::read_body(response).await, CONFIG_BLOB);
+	}
+}
+
+#[actix_web::test]
+async fn cached_objects_are_replicated() {
+	let replica_root = TempRoot::new();
+	let replica: ReplicaConfig = serde_yaml::from_str(&format!("backend: filesystem\nroot: \"{}\"", replica_root.0)).unwrap();
+	let h = harness_with(MockUpstream::new(), "", false, |config| {
+		let (replicator, worker) = replica.start(config.repo.clone());
+		rt::spawn(worker);
+		config.with_replicator(Some(replicator))
+	});
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB))).to_request()).await;
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+
+	let replica = Repository::Filesystem(filesystem::Repository::new(replica_root.0.clone()));
+	wait_for_blob(&replica, LAYER_BLOB).await;
+	let storage_path = format!("manifests/{NAMESPACE}/{IMAGE}/latest");
+	for _ in 0..100 {
+		if let Ok((metadata, body)) = replica.read_manifest(&storage_path, Duration::MAX).await {
+			assert_eq!(metadata.media_type, MANIFEST_MEDIA_TYPE);
+			assert_eq!(body.into_inner().try_collect::<BytesMut>().await.unwrap(), manifest().as_bytes());
+			return;
+		}
+		rt::time::sleep(Duration::from_millis(10)).await;
+	}
+	panic!("Manifest was never replicated");
+}
+
+#[actix_web::test]
+async fn tenants_are_kept_apart_and_held_to_quotas() {
+	let yaml = format!("- name: team-a\n  username: a\n  password: secret-a\n  namespaces: [{NAMESPACE}]\n  quota_bytes: {}\n- name: team-b\n  username: b\n  password: secret-b\n  namespaces: [ghcr.io]", LAYER_BLOB.len());
+	let tenants = std::sync::Arc::new(super::Tenants::parse(yaml.as_bytes()).unwrap());
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_tenants(Some(tenants.clone())));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let get = |uri: String, credentials: Option<&str>| {
+		let request = test::TestRequest::get().uri(&uri);
+		match credentials {
+			Some(credentials) => request.insert_header(("Authorization", format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials)))).to_request(),
+			None => request.to_request()
+		}
+	};
+
+	let response = test::call_service(&app, get("/v2/".into(), None)).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let response = test::call_service(&app, get(format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), Some("a:wrong"))).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let response = test::call_service(&app, get(format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), Some("b:secret-b"))).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let response = test::call_service(&app, get(format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), Some("a:secret-a"))).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/_tenant/team-a/{IMAGE}/latest"), Duration::MAX).await.is_ok());
+	let response = test::call_service(&app, get(format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB)), Some("a:secret-a"))).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, LAYER_BLOB);
+	for _ in 0..100 {
+		if (tenants.check_quota("team-a").is_err()) {
+			break;
+		}
+		rt::time::sleep(Duration::from_millis(10)).await;
+	}
+	assert!(h.repo.read(&blob_storage_path(LAYER_BLOB), Duration::MAX).await.is_err());
+
+	// Over quota, what's cached is still served, but nothing new is fetched
+	let response = test::call_service(&app, get(format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(CONFIG_BLOB)), Some("a:secret-a"))).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	let response = test::call_service(&app, get(format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(LAYER_BLOB)), Some("a:secret-a"))).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 1);
+
+	let catalog: serde_json::Value = test::call_and_read_body_json(&app, get("/v2/_catalog".into(), Some("a:secret-a"))).await;
+	assert_eq!(catalog, serde_json::json!({ "repositories": [format!("{NAMESPACE}/{IMAGE}")] }));
+	let catalog: serde_json::Value = test::call_and_read_body_json(&app, get("/v2/_catalog".into(), Some("b:secret-b"))).await;
+	assert_eq!(catalog, serde_json::json!({ "repositories": [] }));
+}
+
+#[actix_web::test]
+async fn debug_endpoints_need_the_admin_token() {
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_admin_token(Some("admin".into())));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::debug::configure).configure(super::admin)).await;
+	for token in [None, Some("guess")] {
+		let mut request = test::TestRequest::get().uri("/_admin/debug/memory");
+		if let Some(token) = token {
+			request = request.insert_header((super::admin_token::HEADER, token));
+		}
+		let response = test::call_service(&app, request.to_request()).await;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	}
+	let memory: serde_json::Value = test::call_and_read_body_json(&app, test::TestRequest::get().uri("/_admin/debug/memory").insert_header((super::admin_token::HEADER, "admin")).to_request()).await;
+	assert!(memory["cache"]["known_blobs"].is_number(), "{memory}");
+}
+
+#[actix_web::test]
+async fn admin_api_needs_the_token_and_is_scoped_to_tenants() {
+	let yaml = format!("- name: team-a\n  username: a\n  password: secret-a\n  namespaces: [{NAMESPACE}]\n- name: team-b\n  username: b\n  password: secret-b\n  namespaces: [ghcr.io]");
+	let tenants = std::sync::Arc::new(super::Tenants::parse(yaml.as_bytes()).unwrap());
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_admin_token(Some("admin".into())).with_tenants(Some(tenants.clone())));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry).configure(super::admin)).await;
+	let get = |uri: String, token: Option<&str>, credentials: Option<&str>| {
+		let mut request = test::TestRequest::get().uri(&uri);
+		if let Some(token) = token {
+			request = request.insert_header((super::admin_token::HEADER, token));
+		}
+		if let Some(credentials) = credentials {
+			request = request.insert_header(("Authorization", format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))));
+		}
+		request.to_request()
+	};
+	let response = test::call_service(&app, get(format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest"), None, Some("a:secret-a"))).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	let stats = format!("/_admin/stats/image/{NAMESPACE}/{IMAGE}");
+	for token in [None, Some("guess")] {
+		let response = test::call_service(&app, get(stats.clone(), token, Some("a:secret-a"))).await;
+		assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	}
+	// The token alone doesn't say whose cache to look at
+	let response = test::call_service(&app, get(stats.clone(), Some("admin"), None)).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	let report: serde_json::Value = test::call_and_read_body_json(&app, get(stats.clone(), Some("admin"), Some("a:secret-a"))).await;
+	assert_eq!(report["tags"][0]["tag"], "latest");
+	// Other tests pull the same image as the same tenant
+	assert!(report["manifests"]["misses"].as_u64().unwrap() >= 1, "{report}");
+	let response = test::call_service(&app, get(stats, Some("admin"), Some("b:secret-b"))).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let namespaces: serde_json::Value = test::call_and_read_body_json(&app, get("/_admin/namespaces".into(), Some("admin"), Some("b:secret-b"))).await;
+	assert_eq!(namespaces, serde_json::json!([]));
+	let response = test::call_service(&app, get(format!("/_admin/namespaces/{NAMESPACE}"), Some("admin"), Some("b:secret-b"))).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let response = test::call_service(&app, test::TestRequest::delete().uri(&format!("/_admin/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+	// Webhooks carry a token of their own, and none is configured here
+	let response = test::call_service(&app, test::TestRequest::post().uri(&format!("/_admin/webhook/{NAMESPACE}")).set_json(serde_json::json!({})).to_request()).await;
+	assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn rewritten_manifests_are_served_under_their_own_digest() {
+	let h = harness(MockUpstream::new(), "rewrites:\n  - rule: annotate\n    annotations:\n      org.example.cached-by: oci-registry", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let rewritten_digest = response.headers().get("docker-content-digest").unwrap().to_str().unwrap().to_owned();
+	let body = test::read_body(response).await;
+	assert_eq!(rewritten_digest, digest(&body));
+	assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["annotations"]["org.example.cached-by"], "oci-registry");
+
+	// The digest the client was given resolves without going upstream, which wouldn't know it
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{rewritten_digest}")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, body);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+
+	// By its original digest, the manifest can only be served as upstream has it
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{}", digest(manifest().as_bytes()))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+}
+
+#[actix_web::test]
+async fn manifests_with_foreign_layers_are_served_under_their_final_digest() {
+	let mut mock = MockUpstream::new();
+	let foreign = format!(r#"{{"schemaVersion":2,"mediaType":"{MANIFEST_MEDIA_TYPE}","config":{{"mediaType":"application/vnd.docker.container.image.v1+json","size":{},"digest":"{}"}},"layers":[{{"mediaType":"application/vnd.docker.image.rootfs.foreign.diff.tar.gzip","size":{},"digest":"{}","urls":["https://example.com/layer"]}}]}}"#, CONFIG_BLOB.len(), digest(CONFIG_BLOB), LAYER_BLOB.len(), digest(LAYER_BLOB));
+	mock.manifests.insert("windows".to_owned(), Bytes::from(foreign));
+	let h = harness(mock, "foreign_layers: cache\nrewrites:\n  - rule: annotate\n    annotations:\n      org.example.cached-by: oci-registry", false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/windows")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	let served_digest = response.headers().get("docker-content-digest").unwrap().to_str().unwrap().to_owned();
+	let body = test::read_body(response).await;
+	assert_eq!(served_digest, digest(&body));
+	let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+	assert_eq!(parsed["layers"][0]["mediaType"], "application/vnd.docker.image.rootfs.diff.tar.gzip");
+	assert_eq!(parsed["annotations"]["org.example.cached-by"], "oci-registry");
+
+	// Both rewrites changed the digest, and it's the last one clients are given
+	for _ in 0..2 {
+		let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{served_digest}")).to_request()).await;
+		assert_eq!(response.status(), StatusCode::OK);
+		assert_eq!(test::read_body(response).await, body);
+	}
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+}
+
+/// Refuses pulls of `blocked`, fetches of the config blob, and caching the `latest` tag.
+struct Policy;
+
+impl super::plugin::Plugin for Policy {
+	fn name(&self) -> &str {
+		"policy"
+	}
+
+	fn call(&self, request: &super::plugin::RequestInfo<'_>) -> super::plugin::Decision {
+		use super::plugin::Decision;
+		use super::plugin::Hook;
+		match (request.hook, request.reference) {
+			(Hook::Authorize, "blocked") => Decision::Deny("blocked is blocked".into()),
+			(Hook::BeforeFetch, reference) if reference == digest(CONFIG_BLOB) => Decision::Deny("no configs".into()),
+			(Hook::AfterFill, "latest") if request.size.is_some() => Decision::Deny("latest is too fresh".into()),
+			_ => Decision::Annotate(vec![("seen-by".into(), "policy".into())])
+		}
+	}
+}
+
+#[actix_web::test]
+async fn plugins_can_refuse_requests_at_each_hook() {
+	let h = harness_with(MockUpstream::new(), "", false, |config| config.with_plugins(vec![std::sync::Arc::new(Policy)]));
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/blocked")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 0);
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/blobs/{}", digest(CONFIG_BLOB))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	assert_eq!(h.upstream.blob_requests.load(Ordering::Relaxed), 0);
+
+	// Refused once it's been fetched, so it's evicted again
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest")).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	assert_eq!(h.upstream.manifest_requests.load(Ordering::Relaxed), 1);
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Duration::MAX).await.is_err());
+
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{}", digest(manifest().as_bytes()))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+}
+
+const SIGNATURE_KEY: &str = "-----BEGIN PUBLIC KEY-----\n    MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEmFQseuBeda69davDL0F2oblH83dQ\n    PEFbDhU+CIOl5sn/GjZ1oDpi2cJpL5zLGWYTrWJRa9o/YXMynaeXUZWPnQ==\n    -----END PUBLIC KEY-----";
+const OTHER_SIGNATURE_KEY: &str = "-----BEGIN PUBLIC KEY-----\n    MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAELul3BGoh9qcjRew/E+QQa1xaDoXK\n    cWaNt0DQkEmS3EDwGFX9KF94vfeo0Hh54NYtD82a2tdM+B7H8umDaSvdkA==\n    -----END PUBLIC KEY-----";
+
+/// The mock's image, signed by `SIGNATURE_KEY` as cosign would sign it
+fn signed_upstream() -> MockUpstream {
+	let subject = digest(manifest().as_bytes());
+	let payload = Bytes::from(format!(r#"{{"critical":{{"identity":{{"docker-reference":"mock/library/busybox"}},"image":{{"docker-manifest-digest":"{subject}"}},"type":"cosign container image signature"}},"optional":null}}"#));
+	let signature = "MEUCIFH1Oz7jj4D0JLVoQBxz92xUCbY50cFonpqliqeVeCwNAiEAw6KZ372Ew4/pdk72NkPJ+GXVPqokbIN3J2JEcGze4vM=";
+	let signatures = format!(
+		r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","size":2,"digest":"sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"}},"layers":[{{"mediaType":"application/vnd.dev.cosign.simplesigning.v1+json","size":{},"digest":"{}","annotations":{{"dev.cosignproject.cosign/signature":"{signature}"}}}}]}}"#,
+		payload.len(),
+		digest(&payload)
+	);
+	let mut mock = MockUpstream::new();
+	mock.manifests.insert(format!("{}.sig", subject.replace(':', "-")), Bytes::from(signatures));
+	mock.blobs.insert(digest(&payload), payload);
+	mock
+}
+
+#[actix_web::test]
+async fn signatures_are_required_where_configured() {
+	let uri = format!("/v2/{NAMESPACE}/{IMAGE}/manifests/latest");
+	let keys = |key: &str| format!("signature_keys:\n  - |\n    {key}\n");
+
+	let h = harness(MockUpstream::new(), &keys(SIGNATURE_KEY), false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Duration::MAX).await.is_err());
+
+	let h = harness(signed_upstream(), &keys(OTHER_SIGNATURE_KEY), false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+	let h = harness(signed_upstream(), &keys(SIGNATURE_KEY), false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+	assert_eq!(test::read_body(response).await, manifest().as_bytes());
+	// The signature itself can be pulled through us, to check for ourselves
+	let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/v2/{NAMESPACE}/{IMAGE}/manifests/{}.sig", digest(manifest().as_bytes()).replace(':', "-"))).to_request()).await;
+	assert_eq!(response.status(), StatusCode::OK);
+
+	// What's checked is the manifest fetched, whatever digest upstream gives for it
+	let h = harness(MockUpstream { wrong_manifest_digest: true, ..signed_upstream() }, &keys(SIGNATURE_KEY), false);
+	let app = test::init_service(App::new().app_data(h.config.clone()).configure(super::registry)).await;
+	let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+	assert_eq!(response.status(), StatusCode::FORBIDDEN);
+	assert!(h.repo.read(&format!("manifests/{NAMESPACE}/{IMAGE}/latest"), Duration::MAX).await.is_err());
+}
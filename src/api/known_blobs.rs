@@ -0,0 +1,120 @@
+use core::time::Duration;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Blobs we've recently seen in storage, by storage path, along with their lengths.  Lets a `HEAD`
+/// for a blob be answered without a round trip to storage, which on S3 or a network filesystem is
+/// most of what it costs.
+///
+/// Only ever trusted to say that a blob exists; anything not in here (or in here for longer than
+/// the TTL) is looked up in storage as usual.  Other replicas sharing the same storage, and the
+/// cleanup job, can delete blobs out from under us, so the TTL bounds how long we'll go on
+/// claiming to have one; a client that's told a blob exists and then can't get it just gets it
+/// pulled from upstream again.
+pub struct KnownBlobs {
+	ttl: Duration,
+	blobs: Mutex<HashMap<String, (u64, Instant)>>
+}
+
+impl KnownBlobs {
+	const PRUNE_THRESHOLD: usize = 65536;
+
+	/// A TTL of zero disables the index entirely.
+	pub fn new(ttl: Duration) -> Self {
+		Self { ttl, blobs: Mutex::new(HashMap::new()) }
+	}
+
+	/// The length of the blob at `path`, if we've seen it in storage within the TTL.
+	pub fn get(&self, path: &str) -> Option<u64> {
+		match self.blobs.lock().unwrap().get(path) {
+			Some((len, at)) if at.elapsed() < self.ttl => Some(*len),
+			_ => None
+		}
+	}
+
+	pub fn insert(&self, path: &str, len: u64) {
+		if (self.ttl.is_zero()) {
+			return;
+		}
+		let mut blobs = self.blobs.lock().unwrap();
+		if (blobs.len() >= Self::PRUNE_THRESHOLD) {
+			blobs.retain(|_, (_, at)| at.elapsed() < self.ttl);
+			// Still full of live entries; rather than track recency, start over
+			if (blobs.len() >= Self::PRUNE_THRESHOLD) {
+				blobs.clear();
+			}
+		}
+		blobs.insert(path.to_owned(), (len, Instant::now()));
+	}
+
+	pub fn remove(&self, path: &str) {
+		self.blobs.lock().unwrap().remove(path);
+	}
+
+	/// How many blobs are remembered, including any past the TTL that haven't been pruned yet.
+	pub fn entries(&self) -> usize {
+		self.blobs.lock().unwrap().len()
+	}
+
+	/// Every blob still within the TTL, with its length and how long ago it was seen.
+	pub fn snapshot(&self) -> Vec<(String, u64, Duration)> {
+		let blobs = self.blobs.lock().unwrap();
+		blobs.iter().filter(|(_, (_, at))| at.elapsed() < self.ttl).map(|(path, (len, at))| (path.clone(), *len, at.elapsed())).collect()
+	}
+
+	/// Remembers blobs from a [`snapshot`](Self::snapshot), as if seen as long ago as they were then.
+	pub fn restore(&self, blobs: impl IntoIterator<Item = (String, u64, Duration)>) {
+		let now = Instant::now();
+		let mut known = self.blobs.lock().unwrap();
+		for (path, len, age) in blobs {
+			if let Some(at) = now.checked_sub(age).filter(|_| age < self.ttl && known.len() < Self::PRUNE_THRESHOLD) {
+				known.insert(path, (len, at));
+			}
+		}
+	}
+}
+
+impl Default for KnownBlobs {
+	fn default() -> Self {
+		Self::new(Duration::from_secs(300))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn known_blobs() {
+		let known = KnownBlobs::new(Duration::from_secs(60));
+		assert_eq!(known.get("blobs/sha256/ab/cdef"), None);
+		known.insert("blobs/sha256/ab/cdef", 1234);
+		assert_eq!(known.get("blobs/sha256/ab/cdef"), Some(1234));
+		known.remove("blobs/sha256/ab/cdef");
+		assert_eq!(known.get("blobs/sha256/ab/cdef"), None);
+
+		let disabled = KnownBlobs::new(Duration::ZERO);
+		disabled.insert("blobs/sha256/ab/cdef", 1234);
+		assert_eq!(disabled.get("blobs/sha256/ab/cdef"), None);
+
+		let expiring = KnownBlobs::new(Duration::from_millis(1));
+		expiring.insert("blobs/sha256/ab/cdef", 1234);
+		std::thread::sleep(Duration::from_millis(5));
+		assert_eq!(expiring.get("blobs/sha256/ab/cdef"), None);
+	}
+
+	#[test]
+	fn snapshot_and_restore() {
+		let known = KnownBlobs::new(Duration::from_secs(60));
+		known.insert("blobs/sha256/ab/cdef", 1234);
+		let snapshot = known.snapshot();
+		assert_eq!(snapshot.len(), 1);
+
+		let restored = KnownBlobs::new(Duration::from_secs(60));
+		restored.restore(snapshot);
+		assert_eq!(restored.get("blobs/sha256/ab/cdef"), Some(1234));
+		restored.restore([("blobs/sha256/12/3456".to_owned(), 5678, Duration::from_secs(90))]);
+		assert_eq!(restored.get("blobs/sha256/12/3456"), None);
+	}
+}
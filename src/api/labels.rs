@@ -0,0 +1,272 @@
+//! Labels:  the annotations of manifests cached here, and the labels of their image configs, are
+//! indexed as they're cached, so that `/_admin/search` can say which cached images carry a label,
+//! such as which come from a given source repository, without exporting the whole cache.  A config
+//! is usually pulled after its manifest, so its labels are read into the index the first time a
+//! search finds it cached.
+
+use std::collections::BTreeMap;
+use std::iter;
+
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::warn;
+
+use super::blob_storage_path;
+use super::error::Error;
+use super::Access;
+use super::RequestConfig;
+use crate::storage::Repository;
+
+const INDEX_PREFIX: &str = "labels/";
+
+/// Image configs bigger than this aren't read for their labels.
+const MAX_CONFIG_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct Parsed {
+	#[serde(default)]
+	config: Option<ConfigDescriptor>,
+	#[serde(default)]
+	annotations: Option<BTreeMap<String, String>>
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigDescriptor {
+	digest: String
+}
+
+/// An image config, as far as its labels go.
+#[derive(Debug, Deserialize)]
+struct ImageConfig {
+	#[serde(default)]
+	config: Option<ContainerConfig>
+}
+
+#[derive(Debug, Deserialize)]
+struct ContainerConfig {
+	#[serde(default, rename = "Labels")]
+	labels: Option<BTreeMap<String, String>>
+}
+
+/// A cached manifest, as it's kept in the index.
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+	namespace: String,
+	image: String,
+	reference: String,
+	digest: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	tenant: Option<String>,
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	annotations: BTreeMap<String, String>,
+	/// Where the image config is stored, for its labels to be read from once it's cached
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	config: Option<String>,
+	/// `None` until the config has been read
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	labels: Option<BTreeMap<String, String>>
+}
+
+/// A cached image a search found, as it's listed in the response.
+#[derive(Debug, Serialize)]
+struct Found<'a> {
+	namespace: &'a str,
+	image: &'a str,
+	reference: &'a str,
+	digest: &'a str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	tenant: Option<&'a str>,
+	annotations: &'a BTreeMap<String, String>,
+	labels: &'a BTreeMap<String, String>
+}
+
+/// What a cached manifest is indexed as, if it has anything to search by.
+fn entry(namespace: &str, image: &str, reference: &str, manifest: &[u8], access: &Access) -> Option<Entry> {
+	let parsed: Parsed = serde_json::from_slice(manifest).ok()?;
+	let annotations = parsed.annotations.unwrap_or_default();
+	if (parsed.config.is_none() && annotations.is_empty()) {
+		return None;
+	}
+	Some(Entry {
+		namespace: namespace.to_owned(),
+		image: image.to_owned(),
+		reference: reference.to_owned(),
+		digest: format!("sha256:{}", hex::encode(Sha256::digest(manifest))),
+		tenant: access.tenant().map(str::to_owned),
+		annotations,
+		config: parsed.config.map(|c| blob_storage_path(&c.digest, access)),
+		labels: None
+	})
+}
+
+/// The labels an image config sets; none for a config that isn't valid JSON.
+fn config_labels(config: &[u8]) -> BTreeMap<String, String> {
+	serde_json::from_slice::<ImageConfig>(config).ok().and_then(|c| c.config?.labels).unwrap_or_default()
+}
+
+/// Where a manifest is indexed, given where its image's manifests are stored.
+fn index_path(manifest_dir: &str, reference: &str) -> String {
+	let dir = manifest_dir.strip_prefix("manifests/").unwrap_or(manifest_dir);
+	format!("{INDEX_PREFIX}{dir}/{reference}")
+}
+
+/// Where the manifest an index entry is for is stored.
+fn manifest_path(index_path: &str) -> String {
+	format!("manifests/{}", index_path.strip_prefix(INDEX_PREFIX).unwrap_or(index_path))
+}
+
+async fn write_entry(repo: &Repository, path: &str, entry: &Entry) {
+	let body = match serde_json::to_vec(entry) {
+		Ok(v) => Bytes::from(v),
+		Err(_) => return
+	};
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	if let Err(error) = repo.write(path, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body))), len).await {
+		warn!(path, %error, "Failed to index labels");
+	}
+}
+
+/// Indexes a manifest that's just been cached.  Manifests cached for pass-through credentials are
+/// private to them, and left out.  Only fails in the logs; a missing entry just leaves the image
+/// out of searches.
+pub async fn record(repo: &Repository, manifest_dir: &str, namespace: &str, image: &str, reference: &str, manifest: &[u8], access: &Access) {
+	if (matches!(access, Access::Private(_))) {
+		return;
+	}
+	if let Some(entry) = entry(namespace, image, reference, manifest, access) {
+		write_entry(repo, &index_path(manifest_dir, reference), &entry).await;
+	}
+}
+
+/// Reads the labels of an entry's config into it, if the config is cached now.  Returns whether
+/// it did.
+async fn fill_labels(repo: &Repository, entry: &mut Entry) -> Result<bool, Error> {
+	let Some(path) = entry.config.as_deref().filter(|_| entry.labels.is_none()) else {
+		return Ok(false);
+	};
+	let stream = match repo.read(path, core::time::Duration::MAX).await {
+		Ok(stream) => stream,
+		Err(e) if e.is_not_found() => return Ok(false),
+		Err(e) => return Err(e.into())
+	};
+	entry.labels = Some(match stream.length() > MAX_CONFIG_SIZE {
+		true => BTreeMap::new(),
+		false => config_labels(&stream.into_inner().try_collect::<BytesMut>().await?)
+	});
+	Ok(true)
+}
+
+/// Whether `value` is `wanted`, or for `wanted` ending in `*`, starts with the rest of it.
+pub(super) fn value_matches(value: &str, wanted: &str) -> bool {
+	match wanted.strip_suffix('*') {
+		Some(prefix) => value.starts_with(prefix),
+		None => value == wanted
+	}
+}
+
+/// A `key` or `key=value` filter, with the value matched as by [`value_matches`].
+fn matches(filter: &str, values: &BTreeMap<String, String>) -> bool {
+	let (key, wanted) = match filter.split_once('=') {
+		Some((key, value)) => (key, Some(value)),
+		None => (filter, None)
+	};
+	match (values.get(key), wanted) {
+		(None, _) => false,
+		(Some(_), None) => true,
+		(Some(value), Some(wanted)) => value_matches(value, wanted)
+	}
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+	/// Matched against both config labels and manifest annotations
+	label: Option<String>,
+	/// Matched against manifest annotations only
+	annotation: Option<String>,
+	namespace: Option<String>
+}
+
+/// Lists the cached images matching every filter given, in the caller's cache.
+pub async fn search(http_req: HttpRequest, query: web::Query<SearchQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let access = config.admin_access(&http_req)?;
+	let mut found = Vec::new();
+	for path in config.repo.list(INDEX_PREFIX).await? {
+		let body = match config.repo.read(&path, core::time::Duration::MAX).await {
+			Ok(stream) => stream.into_inner().try_collect::<BytesMut>().await?,
+			// Cleaned up since it was listed
+			Err(e) if e.is_not_found() => continue,
+			Err(e) => return Err(e.into())
+		};
+		let mut entry: Entry = match serde_json::from_slice(&body) {
+			Ok(v) => v,
+			Err(error) => {
+				warn!(path, %error, "Skipping invalid label index entry");
+				continue;
+			}
+		};
+		if (query.namespace.as_deref().is_some_and(|ns| ns != entry.namespace) || entry.tenant.as_deref() != access.tenant()) {
+			continue;
+		}
+		// Only what's still cached counts.  Entries for manifests that have been cleaned up or
+		// purged are kept, for if they're restored or pulled again.
+		match config.repo.stat_manifest(&manifest_path(&path), core::time::Duration::MAX).await {
+			Ok(_) => (),
+			Err(e) if e.is_not_found() => continue,
+			Err(e) => return Err(e.into())
+		};
+		if (fill_labels(&config.repo, &mut entry).await?) {
+			write_entry(&config.repo, &path, &entry).await;
+		}
+		let labels = entry.labels.as_ref();
+		let label_matches = |filter: &str| labels.is_some_and(|l| matches(filter, l)) || matches(filter, &entry.annotations);
+		if (query.label.as_deref().map_or(true, label_matches) && query.annotation.as_deref().map_or(true, |f| matches(f, &entry.annotations))) {
+			found.push(entry);
+		}
+	}
+	found.sort_by(|a, b| (&a.namespace, &a.image, &a.reference).cmp(&(&b.namespace, &b.image, &b.reference)));
+	let empty = BTreeMap::new();
+	let found = found
+		.iter()
+		.map(|e| Found {
+			namespace: &e.namespace,
+			image: &e.image,
+			reference: &e.reference,
+			digest: &e.digest,
+			tenant: e.tenant.as_deref(),
+			annotations: &e.annotations,
+			labels: e.labels.as_ref().unwrap_or(&empty)
+		})
+		.collect::<Vec<_>>();
+	Ok(HttpResponse::Ok().json(found))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn labels() {
+		let manifest = br#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"sha256:aabbcc","size":2},"layers":[],"annotations":{"org.opencontainers.image.source":"https://github.com/example/app"}}"#;
+		let entry = entry("docker.io", "library/app", "1.0", manifest, &Access::Shared).unwrap();
+		assert_eq!(entry.config.as_deref(), Some("blobs/sha256/aa/bbcc"));
+		assert!(matches("org.opencontainers.image.source", &entry.annotations));
+		assert!(matches("org.opencontainers.image.source=https://github.com/example/*", &entry.annotations));
+		assert!(!matches("org.opencontainers.image.source=https://github.com/other/app", &entry.annotations));
+		assert!(super::entry("docker.io", "library/app", "1.0", br#"{"schemaVersion":1,"fsLayers":[]}"#, &Access::Shared).is_none());
+
+		let labels = config_labels(br#"{"architecture":"amd64","config":{"Labels":{"org.opencontainers.image.version":"1.0"}}}"#);
+		assert!(matches("org.opencontainers.image.version=1.0", &labels));
+		assert!(config_labels(br#"{"config":{"Labels":null}}"#).is_empty());
+
+		assert_eq!(index_path("manifests/docker.io/library/app", "1.0"), "labels/docker.io/library/app/1.0");
+		assert_eq!(manifest_path("labels/docker.io/library/app/1.0"), "manifests/docker.io/library/app/1.0");
+	}
+}
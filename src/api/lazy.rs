@@ -0,0 +1,136 @@
+//! Lazy pulling, the way stargz-snapshotter and the SOCI snapshotter do it:  rather than pulling a
+//! layer before starting a container, they mount it straight away and read what the container
+//! opens as it opens it, each read a `Range` request for a small part of the blob, and many at once.
+//! Their indexes (eStargz's table of contents, at the end of the layer, and SOCI's index manifests
+//! and zTOCs, found through the referrers API) are ordinary manifests and blobs as far as the cache
+//! is concerned.
+//!
+//! A ranged read of a blob that isn't cached yet is passed straight through to upstream, rather than
+//! waiting on the whole blob, and the whole blob is filled in the background, once however many
+//! reads of it there are, so that the reads after it are served from cache.
+
+use core::str::FromStr;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use actix_web::body::SizedStream;
+use actix_web::http;
+use actix_web::rt;
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use compact_str::CompactString;
+use futures::stream::TryStreamExt;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use tokio::time::timeout_at;
+use tokio::time::Instant;
+use tracing::debug;
+use tracing::warn;
+use tracing::Instrument;
+use tracing::Span;
+
+use super::check_upstream;
+use super::mirror::drain;
+use super::range;
+use super::range::Requested;
+use super::serve_blob;
+use super::trace::Hop;
+use super::Access;
+use super::BlobRequest;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::storage::health;
+
+static PASSED_THROUGH: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("blob_range_passthroughs", "Number of ranged blob reads passed through to upstream while the blob wasn't cached", &["namespace"]).unwrap());
+
+/// Digests of the blobs being filled in the background for ranged reads.
+static FILLING: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// Held while a blob is being filled in the background.
+struct Filling(String);
+
+impl Filling {
+	fn start(digest: &str) -> Option<Self> {
+		FILLING.lock().unwrap().insert(digest.to_owned()).then(|| Self(digest.to_owned()))
+	}
+}
+
+impl Drop for Filling {
+	fn drop(&mut self) {
+		FILLING.lock().unwrap().remove(&self.0);
+	}
+}
+
+/// The `Range` header of a blob pull, if it asks for a single part of the blob.
+pub(super) fn wanted_part(http_req: Option<&HttpRequest>) -> Option<&str> {
+	let header = http_req?.headers().get(http::header::RANGE)?.to_str().ok()?;
+	// Whatever the blob's length, a header that doesn't ask for a part of it doesn't here either
+	matches!(range::requested(Some(header), u64::MAX), Requested::Part(_)).then_some(header)
+}
+
+/// Passes a ranged read of a blob that isn't cached through to upstream, and starts filling the
+/// blob in the background.  `None` if the read isn't one that can be, because it isn't a shared pull,
+/// or upstream won't serve just part of the blob; it's served the usual way instead.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn pass_through(config: &web::Data<RequestConfig>, upstream: &crate::upstream::Client, http_req: Option<&HttpRequest>, access: &Access, namespace: &str, image: &str, upstream_image: &str, digest: &str, anonymous: bool, deadline: Instant, hop: &Hop) -> Option<HttpResponse> {
+	let range = wanted_part(http_req)?;
+	// Parts are asked for with the configured credentials, and filling needs storage to fill
+	if (!matches!(access, Access::Shared) || health::is_degraded() || check_upstream(config, upstream).is_err()) {
+		return None;
+	}
+	let part = timeout_at(deadline, upstream.fetch_part(upstream_image, digest, range, anonymous, hop)).await.ok().flatten()?;
+	PASSED_THROUGH.with_label_values(&[namespace]).inc();
+	debug!(namespace, image, digest, range, "Passing ranged read of uncached blob through to upstream");
+	fill(config, namespace, image, digest);
+
+	let mut response = HttpResponse::PartialContent();
+	response.insert_header((http::header::ACCEPT_RANGES, "bytes"));
+	if let Some(content_range) = part.headers().get(http::header::CONTENT_RANGE).and_then(|v| v.to_str().ok()) {
+		response.insert_header((http::header::CONTENT_RANGE, content_range.to_owned()));
+	}
+	let len = part.content_length();
+	let body = part.bytes_stream().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+	Some(match len {
+		Some(len) => response.body(SizedStream::new(len, body)),
+		None => response.streaming(body)
+	})
+}
+
+/// Fills a blob in the background, unless it's being filled already.
+fn fill(config: &web::Data<RequestConfig>, namespace: &str, image: &str, digest: &str) {
+	let Some(filling) = Filling::start(digest) else {
+		return;
+	};
+	let Ok(image) = ImageName::from_str(image) else {
+		return;
+	};
+	let config = config.clone();
+	let namespace = CompactString::from(namespace);
+	let req = BlobRequest { image, digest: digest.to_owned() };
+	rt::spawn(async move {
+		let _filling = filling;
+		let digest = req.digest.clone();
+		let result = match serve_blob(config, req, Some(&namespace), None).await {
+			Ok(response) => drain(response).await,
+			Err(error) => Err(error)
+		};
+		if let Err(error) = result {
+			warn!(namespace = namespace.as_str(), digest, %error, "Failed to fill blob for ranged reads");
+		}
+	}.instrument(Span::current()));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn filling() {
+		let first = Filling::start("sha256:aaaa").unwrap();
+		assert!(Filling::start("sha256:aaaa").is_none());
+		drop(first);
+		assert!(Filling::start("sha256:aaaa").is_some());
+	}
+}
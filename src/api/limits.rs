@@ -0,0 +1,100 @@
+//! Request size limits, so that one client sending more than any registry client would can't
+//! run the proxy out of memory:  registry requests whose headers add up to more than
+//! `--max-header-size` are refused with a 431 before they're routed, and pushed upload chunks
+//! over `--max-upload-chunk-size` with a 413 and `SIZE_INVALID`.  Chunks that say how long they are
+//! are refused before any of them is read; those that don't are cut off on the way upstream once
+//! they go over.  Either limit is off at 0.
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header;
+use actix_web::http::header::HeaderMap;
+use actix_web::web;
+use actix_web::HttpRequest;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+
+use super::error::Error;
+use super::RequestConfig;
+
+static REJECTED: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("request_limit_rejections", "Number of requests refused for going over a size limit, by limit", &["limit"]).unwrap());
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+	/// The most bytes of header names and values a request can carry
+	pub max_header_size: usize,
+	/// The most bytes a pushed upload chunk can carry
+	pub max_upload_chunk_size: u64
+}
+
+impl Limits {
+	/// The upload chunk limit, if there is one.
+	pub(super) fn upload_chunk(&self) -> Option<u64> {
+		(self.max_upload_chunk_size != 0).then_some(self.max_upload_chunk_size)
+	}
+}
+
+/// How many bytes of headers a request carries, not counting the separators between them.
+fn header_size(headers: &HeaderMap) -> usize {
+	headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum()
+}
+
+/// Refuses registry requests with more headers than `--max-header-size` allows.
+pub(super) fn check_headers(req: &ServiceRequest) -> Result<(), Error> {
+	let limit = match req.app_data::<web::Data<RequestConfig>>() {
+		Some(config) if (config.limits.max_header_size != 0) => config.limits.max_header_size,
+		_ => return Ok(())
+	};
+	match header_size(req.headers()) > limit {
+		true => {
+			REJECTED.with_label_values(&["header_size"]).inc();
+			Err(Error::HeadersTooLarge { limit })
+		},
+		false => Ok(())
+	}
+}
+
+/// Refuses an upload chunk that says up front it's longer than `limit`.
+pub(super) fn check_upload_length(http_req: &HttpRequest, limit: u64) -> Result<(), Error> {
+	let length = http_req.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+	match length.is_some_and(|length| length > limit) {
+		true => Err(upload_chunk_too_large(limit)),
+		false => Ok(())
+	}
+}
+
+/// The error for an upload chunk found to be over `limit`, counted.
+pub(super) fn upload_chunk_too_large(limit: u64) -> Error {
+	REJECTED.with_label_values(&["upload_chunk_size"]).inc();
+	Error::UploadChunkTooLarge { limit }
+}
+
+#[cfg(test)]
+mod tests {
+	use actix_web::http::header::HeaderName;
+	use actix_web::http::header::HeaderValue;
+	use actix_web::http::StatusCode;
+	use actix_web::ResponseError;
+
+	use super::*;
+	use crate::api::error::ErrorCode;
+
+	#[test]
+	fn header_sizes() {
+		let mut headers = HeaderMap::new();
+		assert_eq!(header_size(&headers), 0);
+		headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+		headers.append(HeaderName::from_static("x-padding"), HeaderValue::from_static("aaaa"));
+		headers.append(HeaderName::from_static("x-padding"), HeaderValue::from_static("bb"));
+		assert_eq!(header_size(&headers), 6 + 16 + 9 + 4 + 9 + 2);
+	}
+
+	#[test]
+	fn errors_say_what_the_limit_is() {
+		let error = Error::HeadersTooLarge { limit: 16384 };
+		assert_eq!((error.status_code(), error.code()), (StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, ErrorCode::Denied));
+		let error = upload_chunk_too_large(1024);
+		assert_eq!((error.status_code(), error.code()), (StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::SizeInvalid));
+		assert!(error.to_string().contains("1024"));
+	}
+}
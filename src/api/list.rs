@@ -0,0 +1,182 @@
+use std::borrow::Cow;
+
+use actix_web::http::header;
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::error::Error;
+use super::manifest_storage_dir;
+use super::tenant::Tenant;
+use super::Access;
+use super::ManifestQueryString;
+use super::RequestConfig;
+use crate::image::ImageName;
+
+/// The `n` and `last` query parameters the distribution spec uses for paginated lists.
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+	n: Option<usize>,
+	last: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+struct Catalog {
+	repositories: Vec<String>
+}
+
+#[derive(Debug, Serialize)]
+struct Tags<'a> {
+	name: &'a str,
+	tags: Vec<String>
+}
+
+/// Percent-encodes everything but RFC 3986's unreserved characters, for use in a query string.
+fn encode_query_value(value: &str) -> String {
+	let mut out = String::with_capacity(value.len());
+	for byte in value.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(byte as char),
+			_ => out.push_str(&format!("%{byte:02X}"))
+		}
+	}
+	out
+}
+
+/// Cuts one page out of a list the way docker/distribution does:  entries are sorted, start after
+/// `last`, and number at most `n` (capped at `max`).  If there are more, also returns a `Link`
+/// header pointing at the next page; `url` is the list's URL including any query parameters other
+/// than `n` and `last`.
+fn paginate(mut items: Vec<String>, query: &PageQuery, max: usize, url: &str) -> (Vec<String>, Option<String>) {
+	items.sort_unstable();
+	items.dedup();
+	if let Some(last) = query.last.as_deref() {
+		items.retain(|i| i.as_str() > last);
+	}
+	let n = query.n.unwrap_or(max).min(max);
+	if (items.len() <= n) {
+		return (items, None);
+	}
+	items.truncate(n);
+	let separator = match url.contains('?') {
+		true => '&',
+		false => '?'
+	};
+	let link = items.last().map(|last| format!(r#"<{url}{separator}n={n}&last={}>; rel="next""#, encode_query_value(last)));
+	(items, link)
+}
+
+fn page_response(body: &impl Serialize, link: Option<String>) -> HttpResponse {
+	let mut response = HttpResponse::Ok();
+	if let Some(link) = link {
+		response.insert_header((header::LINK, link));
+	}
+	response.json(body)
+}
+
+/// Maps a stored manifest's path onto the repository name clients would pull it by, leaving out
+/// anything cached on behalf of specific credentials or other tenants.  A tenant's own
+/// repositories are named the way the tenant pulls them.
+pub(super) fn repository_name(path: &str, access: &Access) -> Option<String> {
+	let (repository, _) = path.strip_prefix("manifests/")?.rsplit_once('/')?;
+	match access.tenant() {
+		Some(tenant) => {
+			let (namespace, image) = repository.split_once(&format!("/{}/", Tenant::storage_prefix(tenant)))?;
+			Some(format!("{namespace}/{image}"))
+		},
+		None => match repository.contains("/_private/") || repository.contains("/_tenant/") {
+			true => None,
+			false => Some(repository.to_owned())
+		}
+	}
+}
+
+/// Lists the repositories we have cached manifests for.  This is a cache, not the upstream's
+/// catalog, so only repositories that have been pulled through us show up.
+pub async fn catalog(http_req: HttpRequest, query: web::Query<PageQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let access = match config.tenants.as_deref() {
+		Some(tenants) => Access::Tenant(tenants.authenticate(&http_req)?.name().into()),
+		None => Access::Shared
+	};
+	let repositories = config.repo.list_manifests().await?.iter().filter_map(|p| repository_name(p, &access)).collect();
+	let (repositories, link) = paginate(repositories, &query, config.max_page_size, &config.absolute_path("/v2/_catalog"));
+	Ok(page_response(&Catalog { repositories }, link))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagsRequest {
+	image: ImageName
+}
+
+/// Lists the tags we have cached for a repository, or upstream's tags for it, where its namespace
+/// has a `tag_list_ttl`.
+pub async fn tags(http_req: HttpRequest, req: web::Path<TagsRequest>, qstr: web::Query<ManifestQueryString>, query: web::Query<PageQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let (namespace, image) = config.route(qstr.ns.as_deref(), req.image.as_ref(), Some(&http_req))?;
+	let upstream = config.upstream.lock().await.get(namespace).ok().cloned();
+	// Listed by the name the image is cached by, not the one it was asked for by
+	let image = upstream.as_ref().map_or(Cow::Borrowed(image), |upstream| upstream.canonical_image(image));
+	let access = match config.tenants.as_deref() {
+		Some(tenants) => Access::Tenant(tenants.admit(&http_req, namespace)?.name().into()),
+		None => Access::Shared
+	};
+	let prefix = format!("{}/", manifest_storage_dir(namespace, &image, &access));
+	let tags = config
+		.repo
+		.list(&prefix)
+		.await?
+		.iter()
+		.filter_map(|p| p.strip_prefix(prefix.as_str()))
+		// Digests aren't tags, sidecars aren't manifests, and anything further down belongs to some
+		// other repository
+		.filter(|t| !t.contains(['/', ':']) && !t.starts_with('.'))
+		.map(str::to_owned)
+		.collect::<Vec<_>>();
+	let tags = match upstream.as_ref().and_then(|upstream| Some((upstream, upstream.tag_list_ttl()?))) {
+		Some((upstream, ttl)) => config.tag_lists.get(upstream, &upstream.upstream_image(&image), ttl, tags).await?,
+		None => tags
+	};
+	if (tags.is_empty()) {
+		return Err(Error::NameUnknown);
+	}
+	let url = match qstr.ns.as_deref() {
+		Some(ns) => config.absolute_path(&format!("/v2/{}/tags/list?ns={}", req.image, encode_query_value(ns))),
+		None => config.absolute_path(&format!("/v2/{}/tags/list", req.image))
+	};
+	let (tags, link) = paginate(tags, &query, config.max_page_size, &url);
+	Ok(page_response(&Tags { name: req.image.as_ref(), tags }, link))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn page(n: Option<usize>, last: Option<&str>) -> PageQuery {
+		PageQuery { n, last: last.map(str::to_owned) }
+	}
+
+	#[test]
+	fn pagination() {
+		let items = || ["c", "a", "b", "d", "a"].into_iter().map(str::to_owned).collect::<Vec<_>>();
+
+		assert_eq!(paginate(items(), &page(None, None), 100, "/v2/_catalog"), (vec!["a".into(), "b".into(), "c".into(), "d".into()], None));
+		assert_eq!(paginate(items(), &page(Some(2), None), 100, "/v2/_catalog"), (vec!["a".into(), "b".into()], Some(r#"</v2/_catalog?n=2&last=b>; rel="next""#.into())));
+		assert_eq!(paginate(items(), &page(Some(2), Some("b")), 100, "/v2/_catalog"), (vec!["c".into(), "d".into()], None));
+		// n is capped at the configured maximum
+		assert_eq!(paginate(items(), &page(Some(100), None), 3, "/v2/_catalog"), (vec!["a".into(), "b".into(), "c".into()], Some(r#"</v2/_catalog?n=3&last=c>; rel="next""#.into())));
+		assert_eq!(paginate(items(), &page(Some(1), None), 100, "/v2/foo/tags/list?ns=docker.io"), (vec!["a".into()], Some(r#"</v2/foo/tags/list?ns=docker.io&n=1&last=a>; rel="next""#.into())));
+	}
+
+	#[test]
+	fn repository_names() {
+		let tenant = Access::Tenant("team-a".into());
+		assert_eq!(repository_name("manifests/docker.io/library/busybox/latest", &Access::Shared).as_deref(), Some("docker.io/library/busybox"));
+		assert_eq!(repository_name("manifests/docker.io/_private/abcd/library/busybox/latest", &Access::Shared), None);
+		assert_eq!(repository_name("manifests/docker.io/_tenant/team-a/library/busybox/latest", &Access::Shared), None);
+		assert_eq!(repository_name("manifests/docker.io/_tenant/team-a/library/busybox/latest", &tenant).as_deref(), Some("docker.io/library/busybox"));
+		assert_eq!(repository_name("manifests/docker.io/_tenant/team-b/library/busybox/latest", &tenant), None);
+		assert_eq!(repository_name("manifests/docker.io/library/busybox/latest", &tenant), None);
+		assert_eq!(encode_query_value("docker.io/library/busybox"), "docker.io%2Flibrary%2Fbusybox");
+	}
+}
@@ -0,0 +1,125 @@
+//! Gauges of the work under way, for scaling the cache on what it's actually doing rather than on
+//! CPU, which says little about a process that spends most of its time waiting on upstream and
+//! storage:  the manifest and blob pulls being served, counted until their responses have been sent
+//! in full, and how far behind the readers of blobs being filled from upstream are.  Upstream
+//! downloads and storage operations under way have gauges of their own, next to the code doing them.
+
+use core::pin::Pin;
+use core::task::Context;
+use core::task::Poll;
+
+use actix_web::body::BodySize;
+use actix_web::body::BoxBody;
+use actix_web::body::MessageBody;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use prometheus::register_int_gauge;
+use prometheus::register_int_gauge_vec;
+use prometheus::IntGauge;
+use prometheus::IntGaugeVec;
+
+static PULLS: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("pulls_in_flight", "Number of manifest and blob pulls being served, until their responses have been sent", &["kind"]).unwrap());
+static BUFFERED: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("blob_fill_buffered_chunks", "Number of chunks read from upstream that readers of blobs being filled haven't caught up on").unwrap());
+static BLOCKED: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("blob_fills_blocked", "Number of blob fills waiting on a slow reader before reading more from upstream").unwrap());
+
+/// How many pulls are being served, of either kind.
+pub(super) fn pulls_in_flight() -> i64 {
+	["manifest", "blob"].iter().map(|kind| PULLS.with_label_values(&[kind]).get()).sum()
+}
+
+/// Held for as long as a pull is being served.
+pub(super) struct Pull(IntGauge);
+
+impl Pull {
+	pub(super) fn start(kind: &'static str) -> Self {
+		let gauge = PULLS.with_label_values(&[kind]);
+		gauge.inc();
+		Self(gauge)
+	}
+
+	/// Keeps the pull counted until `response` has been sent, or the client has gone away.
+	pub(super) fn until_sent(self, response: HttpResponse) -> HttpResponse {
+		response.map_body(|_, body| BoxBody::new(Counted { body, _pull: self }))
+	}
+}
+
+impl Drop for Pull {
+	fn drop(&mut self) {
+		self.0.dec();
+	}
+}
+
+/// A response body, with the pull it's for.
+struct Counted {
+	body: BoxBody,
+	_pull: Pull
+}
+
+impl MessageBody for Counted {
+	type Error = Box<dyn std::error::Error>;
+
+	fn size(&self) -> BodySize {
+		self.body.size()
+	}
+
+	fn poll_next(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+		Pin::new(&mut self.get_mut().body).poll_next(ctx)
+	}
+}
+
+/// A blob fill's share of [`BUFFERED`], as of the last chunk it read; taken back out when the fill
+/// is done reading.
+#[derive(Default)]
+pub(super) struct Buffered(i64);
+
+impl Buffered {
+	pub(super) fn set(&mut self, len: usize) {
+		let len = len.try_into().unwrap_or(i64::MAX);
+		BUFFERED.add(len - self.0);
+		self.0 = len;
+	}
+}
+
+impl Drop for Buffered {
+	fn drop(&mut self) {
+		BUFFERED.sub(self.0);
+	}
+}
+
+/// Held while a blob fill waits on room in its channel.
+pub(super) struct Blocked(());
+
+impl Blocked {
+	pub(super) fn start() -> Self {
+		BLOCKED.inc();
+		Self(())
+	}
+}
+
+impl Drop for Blocked {
+	fn drop(&mut self) {
+		BLOCKED.dec();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn buffered() {
+		let before = BUFFERED.get();
+		let mut first = Buffered::default();
+		let mut second = Buffered::default();
+		first.set(3);
+		second.set(5);
+		assert_eq!(BUFFERED.get() - before, 8);
+		first.set(1);
+		assert_eq!(BUFFERED.get() - before, 6);
+		drop(second);
+		assert_eq!(BUFFERED.get() - before, 1);
+		drop(first);
+		assert_eq!(BUFFERED.get(), before);
+	}
+}
@@ -0,0 +1,145 @@
+//! Maintenance mode, for draining an instance in an orderly way, say ahead of a storage migration:
+//! while it's `unavailable`, new pulls are answered with a 503 and a `Retry-After`, so that clients
+//! and load balancers go elsewhere, while the pulls already under way finish; while it's
+//! `cache-only`, pulls are still served, but only from cache.  In either mode, nothing is fetched
+//! from upstream, background pulls like mirroring included.  Toggled under `/_admin/maintenance`,
+//! which also says how many pulls are still in flight, so that whatever's draining the instance can
+//! tell when it's done.
+
+use core::time::Duration;
+use std::sync::Mutex;
+
+use actix_web::web;
+use actix_web::HttpResponse;
+use once_cell::sync::Lazy;
+use prometheus::register_int_gauge;
+use prometheus::IntGauge;
+use serde::Deserialize;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::warn;
+
+use super::error::Error;
+use super::load;
+use super::RequestConfig;
+
+static MODE: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("maintenance_mode", "Whether the instance is in maintenance mode, refusing new pulls (2), serving them from cache only (1), or not (0)").unwrap());
+
+/// What clients are told to wait before trying again, unless the toggle says otherwise.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+	/// Serving pulls as usual
+	#[default]
+	Off,
+	/// Serving pulls from cache, and failing the rest rather than going to upstream
+	CacheOnly,
+	/// Refusing new pulls
+	Unavailable
+}
+
+impl Mode {
+	fn gauge(self) -> i64 {
+		match self {
+			Self::Off => 0,
+			Self::CacheOnly => 1,
+			Self::Unavailable => 2
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct State {
+	mode: Mode,
+	retry_after: Duration,
+	since: Option<OffsetDateTime>
+}
+
+#[derive(Debug, Default)]
+pub struct Maintenance(Mutex<State>);
+
+impl Maintenance {
+	fn state(&self) -> State {
+		*self.0.lock().unwrap()
+	}
+
+	fn set(&self, mode: Mode, retry_after: Duration) {
+		let since = match mode {
+			Mode::Off => None,
+			_ => Some(OffsetDateTime::now_utc())
+		};
+		*self.0.lock().unwrap() = State { mode, retry_after, since };
+		MODE.set(mode.gauge());
+	}
+
+	/// Fails a new pull while pulls are refused.
+	pub(super) fn admit(&self) -> Result<(), Error> {
+		match self.state() {
+			State { mode: Mode::Unavailable, retry_after, .. } => Err(Error::Maintenance(retry_after)),
+			_ => Ok(())
+		}
+	}
+
+	/// Fails whatever would go to upstream while in either mode.
+	pub(super) fn check_upstream(&self) -> Result<(), Error> {
+		match self.state() {
+			State { mode: Mode::Off, .. } => Ok(()),
+			State { retry_after, .. } => Err(Error::Maintenance(retry_after))
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceQueryString {
+	mode: Mode,
+	/// In seconds
+	retry_after: Option<u64>
+}
+
+fn status(config: &RequestConfig) -> HttpResponse {
+	let state = config.maintenance.state();
+	HttpResponse::Ok().json(serde_json::json!({
+		"mode": state.mode,
+		"retry_after_seconds": (state.mode != Mode::Off).then_some(state.retry_after.as_secs()),
+		"since": state.since.and_then(|since| since.format(&Rfc3339).ok()),
+		"pulls_in_flight": load::pulls_in_flight()
+	}))
+}
+
+pub async fn get(config: web::Data<RequestConfig>) -> HttpResponse {
+	status(&config)
+}
+
+pub async fn set(qstr: web::Query<MaintenanceQueryString>, config: web::Data<RequestConfig>) -> HttpResponse {
+	let retry_after = qstr.retry_after.map(Duration::from_secs).unwrap_or(DEFAULT_RETRY_AFTER);
+	config.maintenance.set(qstr.mode, retry_after);
+	warn!(mode = ?qstr.mode, retry_after = retry_after.as_secs(), "Maintenance mode changed");
+	status(&config)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn modes() {
+		let maintenance = Maintenance::default();
+		assert!(maintenance.admit().is_ok());
+		assert!(maintenance.check_upstream().is_ok());
+
+		maintenance.set(Mode::CacheOnly, Duration::from_secs(5));
+		assert!(maintenance.admit().is_ok());
+		assert!(matches!(maintenance.check_upstream(), Err(Error::Maintenance(retry_after)) if retry_after == Duration::from_secs(5)));
+
+		maintenance.set(Mode::Unavailable, DEFAULT_RETRY_AFTER);
+		assert!(matches!(maintenance.admit(), Err(Error::Maintenance(_))));
+		assert!(maintenance.check_upstream().is_err());
+
+		maintenance.set(Mode::Off, DEFAULT_RETRY_AFTER);
+		assert!(maintenance.admit().is_ok());
+		assert!(maintenance.state().since.is_none());
+	}
+}
@@ -0,0 +1,348 @@
+//! Mirroring:  a declarative list of repositories, and which of their tags, that the proxy keeps
+//! cached on its own rather than waiting for a client to pull them.  A reconciler goes over the list
+//! periodically, asking upstream for each repository's tags and pulling every one that matches
+//! through the cache, the same way a client would; tags that haven't moved are cache hits, so a pass
+//! over an up to date mirror costs a tag listing and a few `stat`s per image.
+
+use core::future;
+use core::str::FromStr;
+use core::time::Duration;
+use std::path::Path;
+use std::sync::Mutex;
+
+use actix_web::body;
+use actix_web::body::MessageBody;
+use actix_web::web;
+use actix_web::HttpResponse;
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge_vec;
+use prometheus::IntCounterVec;
+use prometheus::IntGaugeVec;
+use semver::Version;
+use semver::VersionReq;
+use serde::Deserialize;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::time::MissedTickBehavior;
+use tracing::info;
+use tracing::warn;
+
+use super::authenticate_with_upstream;
+use super::blob_storage_path;
+use super::error::Error;
+use super::pins::References;
+use super::serve_blob;
+use super::serve_manifest;
+use super::Access;
+use super::BlobRequest;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::image::ImageReference;
+
+static MATCHED_TAGS: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("mirror_tags_matched", "Number of upstream tags matching a mirrored repository's patterns", &["repository"]).unwrap());
+static CACHED_TAGS: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("mirror_tags_cached", "Number of a mirrored repository's matching tags that are fully cached", &["repository"]).unwrap());
+static ERRORS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("mirror_errors", "Number of errors mirroring a repository", &["repository"]).unwrap());
+
+/// One repository to mirror, like:
+///
+/// ```yaml
+/// - repository: docker.io/library/nginx
+///   tags: ["1.25*", stable-alpine]
+///   versions: ">=1.25 <2"
+///   platforms: [linux/amd64, linux/arm64]
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "RawEntry")]
+pub struct Entry {
+	repository: String,
+	namespace: CompactString,
+	image: String,
+	tags: Vec<String>,
+	versions: Option<VersionReq>,
+	platforms: Vec<String>
+}
+
+#[derive(Deserialize)]
+struct RawEntry {
+	/// The first path component is the namespace
+	repository: String,
+	/// Patterns for the tags to mirror, where `*` matches anything and `?` any one character
+	#[serde(default)]
+	tags: Vec<String>,
+	/// A semver requirement; tags that read as a version meeting it are mirrored too
+	#[serde(default)]
+	versions: Option<String>,
+	/// Of multi-platform images, only mirror these (`os/architecture`, optionally followed by
+	/// `/variant`); all of them if empty
+	#[serde(default)]
+	platforms: Vec<String>
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidEntry {
+	#[error("Invalid repository '{0}'; expected namespace/image")]
+	Repository(String),
+	#[error("Invalid version requirement for {0}: {1}")]
+	Versions(String, semver::Error),
+	#[error("Nothing to mirror for {0}; expected tags, versions, or both")]
+	Empty(String)
+}
+
+impl TryFrom<RawEntry> for Entry {
+	type Error = InvalidEntry;
+
+	fn try_from(raw: RawEntry) -> Result<Self, Self::Error> {
+		let Some((namespace, image)) = raw.repository.split_once('/').filter(|(ns, image)| !ns.is_empty() && ImageName::from_str(image).is_ok()) else {
+			return Err(InvalidEntry::Repository(raw.repository.clone()));
+		};
+		if (raw.tags.is_empty() && raw.versions.is_none()) {
+			return Err(InvalidEntry::Empty(raw.repository.clone()));
+		}
+		let versions = match raw.versions.as_deref().map(parse_versions) {
+			Some(Ok(v)) => Some(v),
+			Some(Err(e)) => return Err(InvalidEntry::Versions(raw.repository.clone(), e)),
+			None => None
+		};
+		Ok(Self { namespace: namespace.into(), image: image.to_owned(), repository: raw.repository.clone(), tags: raw.tags, versions, platforms: raw.platforms })
+	}
+}
+
+impl Entry {
+	pub fn namespace(&self) -> &str {
+		self.namespace.as_str()
+	}
+
+	fn matches(&self, tag: &str) -> bool {
+		if (self.tags.iter().any(|pattern| glob_matches(pattern.as_bytes(), tag.as_bytes()))) {
+			return true;
+		}
+		match (&self.versions, tag_version(tag)) {
+			(Some(versions), Some(version)) => versions.matches(&version),
+			_ => false
+		}
+	}
+
+	fn wants_platform(&self, descriptor: &super::pins::Descriptor) -> bool {
+		match (self.platforms.is_empty(), &descriptor.platform) {
+			(true, _) => true,
+			(false, Some(platform)) => self.platforms.iter().any(|name| platform.is(name)),
+			(false, None) => false
+		}
+	}
+}
+
+/// Parses a semver requirement whose comparators may be separated by whitespace, as in `>=1.25 <2`,
+/// as well as by commas.
+fn parse_versions(s: &str) -> Result<VersionReq, semver::Error> {
+	let mut comparators = Vec::new();
+	let mut op = String::new();
+	for token in s.split(|c: char| c == ',' || c.is_whitespace()).filter(|t| !t.is_empty()) {
+		match token.chars().all(|c| "<>=~^".contains(c)) {
+			// An operator with a space after it, as in `>= 1.25`
+			true => op.push_str(token),
+			false => comparators.push(format!("{}{token}", core::mem::take(&mut op)))
+		}
+	}
+	if (!op.is_empty()) {
+		comparators.push(op);
+	}
+	VersionReq::parse(&comparators.join(", "))
+}
+
+/// Reads a tag as a version, the way images tend to be tagged:  an optional `v`, and a missing minor
+/// or patch version taken as zero, so `v1.25` is 1.25.0.  Suffixes like `-alpine` come through as
+/// pre-release identifiers, so those tags only meet requirements that ask for pre-releases.
+fn tag_version(tag: &str) -> Option<Version> {
+	let tag = tag.strip_prefix('v').unwrap_or(tag);
+	let (core, suffix) = tag.split_at(tag.find(['-', '+']).unwrap_or(tag.len()));
+	let padded = match core.matches('.').count() {
+		0 => format!("{core}.0.0{suffix}"),
+		1 => format!("{core}.0{suffix}"),
+		_ => tag.to_owned()
+	};
+	Version::parse(&padded).ok()
+}
+
+pub(super) fn glob_matches(pattern: &[u8], s: &[u8]) -> bool {
+	match (pattern.first(), s.first()) {
+		(None, None) => true,
+		(Some(b'*'), _) => glob_matches(&pattern[1..], s) || (!s.is_empty() && glob_matches(pattern, &s[1..])),
+		(Some(b'?'), Some(_)) => glob_matches(&pattern[1..], &s[1..]),
+		(Some(p), Some(c)) if p == c => glob_matches(&pattern[1..], &s[1..]),
+		_ => false
+	}
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+	#[error("Failed to read mirror file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Invalid mirror file: {0}")]
+	Yaml(#[from] serde_yaml::Error)
+}
+
+pub async fn load(path: &Path) -> Result<Vec<Entry>, LoadError> {
+	Ok(serde_yaml::from_slice(&tokio::fs::read(path).await?)?)
+}
+
+/// How the last pass over one repository went.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct EntryStatus {
+	repository: String,
+	/// When the pass finished, in RFC 3339
+	last_run: String,
+	/// Upstream tags matching the repository's patterns
+	tags: Vec<String>,
+	/// How many of those are fully cached
+	cached: usize,
+	errors: Vec<String>
+}
+
+/// What the reconciler last found for each repository, for `/_admin/mirror`.
+#[derive(Default)]
+pub struct Status(Mutex<Vec<EntryStatus>>);
+
+impl Status {
+	fn update(&self, status: EntryStatus) {
+		let mut entries = self.0.lock().unwrap();
+		match entries.iter_mut().find(|e| e.repository == status.repository) {
+			Some(entry) => *entry = status,
+			None => entries.push(status)
+		};
+	}
+}
+
+pub async fn status(config: web::Data<RequestConfig>) -> HttpResponse {
+	let entries = config.mirror.0.lock().unwrap().clone();
+	HttpResponse::Ok().json(entries)
+}
+
+/// Goes over `entries` every `interval`, starting right away, until the task is dropped.  Has to be
+/// spawned on the actix runtime, as pulling blobs through the cache spawns tasks of its own there.
+pub async fn run(config: web::Data<RequestConfig>, entries: Vec<Entry>, interval: Duration) {
+	let mut interval = tokio::time::interval(interval);
+	interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	loop {
+		interval.tick().await;
+		for entry in &entries {
+			let status = reconcile(&config, entry).await;
+			info!(repository = entry.repository.as_str(), tags = status.tags.len(), cached = status.cached, errors = status.errors.len(), "Mirrored repository");
+			config.mirror.update(status);
+		}
+	}
+}
+
+pub(super) async fn reconcile(config: &web::Data<RequestConfig>, entry: &Entry) -> EntryStatus {
+	let mut status = EntryStatus { repository: entry.repository.clone(), ..EntryStatus::default() };
+	match list_tags(config, entry).await {
+		Ok(tags) => status.tags = tags.into_iter().filter(|tag| entry.matches(tag)).collect(),
+		Err(error) => {
+			warn!(repository = entry.repository.as_str(), %error, "Failed to list tags to mirror");
+			status.errors.push(error.to_string());
+		}
+	};
+	for tag in &status.tags {
+		match mirror_tag(config, entry, tag).await {
+			Ok(()) => status.cached += 1,
+			Err(error) => {
+				warn!(repository = entry.repository.as_str(), tag = tag.as_str(), %error, "Failed to mirror tag");
+				status.errors.push(format!("{tag}: {error}"));
+			}
+		};
+	}
+	status.last_run = OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default();
+
+	MATCHED_TAGS.with_label_values(&[&entry.repository]).set(status.tags.len().try_into().unwrap_or(i64::MAX));
+	CACHED_TAGS.with_label_values(&[&entry.repository]).set(status.cached.try_into().unwrap_or(i64::MAX));
+	ERRORS.with_label_values(&[&entry.repository]).inc_by(status.errors.len() as u64);
+	status
+}
+
+async fn list_tags(config: &RequestConfig, entry: &Entry) -> Result<Vec<String>, Error> {
+	let mut upstream = config.upstream.lock().await.get(&entry.namespace)?.clone();
+	let upstream_image = upstream.upstream_image(&entry.image).into_owned();
+	upstream.circuit.check()?;
+	let result = async {
+		authenticate_with_upstream(&mut upstream, &format!("repository:{}:pull", upstream_image)).await?;
+		upstream.tags(&upstream_image).await
+	}
+	.await;
+	upstream.circuit.record(&result);
+	result.map_err(|e| Error::from(upstream.normalize_error(e, false)))
+}
+
+/// Pulls a tag through the cache:  its manifest, the platform manifests if it's an index, and every
+/// blob they refer to that isn't already in storage.
+async fn mirror_tag(config: &web::Data<RequestConfig>, entry: &Entry, tag: &str) -> Result<(), Error> {
+	let blob_max_age = config.upstream.lock().await.get(&entry.namespace)?.cached_blob_max_age();
+	let mut pending = vec![tag.to_owned()];
+	let mut blobs = Vec::new();
+	while let Some(reference) = pending.pop() {
+		let req = ManifestRequest { image: image_name(entry)?, reference: ImageReference::from_str(&reference).map_err(|_| Error::ManifestUnknown)? };
+		let response = serve_manifest(config, &req, Some(&entry.namespace), None).await?;
+		let manifest = body::to_bytes(response.into_body()).await.map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())))?;
+		let references: References = serde_json::from_slice(&manifest)?;
+		pending.extend(references.manifests.iter().filter(|m| entry.wants_platform(m)).map(|m| m.digest.clone()));
+		blobs.extend(references.config.into_iter().chain(references.layers).map(|b| b.digest));
+	}
+
+	let paths = blobs.iter().map(|digest| blob_storage_path(digest, &Access::Shared)).collect::<Vec<_>>();
+	let stats = config.repo.stat_all(&paths.iter().map(String::as_str).collect::<Vec<_>>(), blob_max_age).await;
+	for (digest, stat) in blobs.into_iter().zip(stats) {
+		if (stat.is_ok()) {
+			continue;
+		}
+		let response = serve_blob(config.clone(), BlobRequest { image: image_name(entry)?, digest }, Some(&entry.namespace), None).await?;
+		drain(response).await?;
+	}
+	Ok(())
+}
+
+fn image_name(entry: &Entry) -> Result<ImageName, Error> {
+	ImageName::from_str(&entry.image).map_err(|_| Error::NameUnknown)
+}
+
+/// Reads a response body to the end, without keeping any of it; for blobs, this is what waits for
+/// the cache to be filled.
+pub(super) async fn drain(response: HttpResponse) -> Result<(), Error> {
+	let mut body = Box::pin(response.into_body());
+	while let Some(chunk) = future::poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+		chunk.map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())))?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tag_patterns() {
+		let entry: Vec<Entry> = serde_yaml::from_str("- repository: docker.io/library/nginx\n  tags: [\"1.25*\", stable-alpine, \"?.?\"]").unwrap();
+		assert_eq!((entry[0].namespace.as_str(), entry[0].image.as_str()), ("docker.io", "library/nginx"));
+		for tag in ["1.25", "1.25.3-alpine", "stable-alpine", "1.2"] {
+			assert!(entry[0].matches(tag), "{tag}");
+		}
+		for tag in ["1.24", "stable", "latest", "1.26", "11.2"] {
+			assert!(!entry[0].matches(tag), "{tag}");
+		}
+		assert!(serde_yaml::from_str::<Vec<Entry>>("- repository: nginx\n  tags: [latest]").is_err());
+		assert!(serde_yaml::from_str::<Vec<Entry>>("- repository: docker.io/library/nginx").is_err());
+	}
+
+	#[test]
+	fn version_requirements() {
+		let entry: Vec<Entry> = serde_yaml::from_str("- repository: docker.io/library/nginx\n  versions: \">= 1.25 <2\"").unwrap();
+		for tag in ["1.25", "1.25.3", "v1.26.0", "1.99"] {
+			assert!(entry[0].matches(tag), "{tag}");
+		}
+		for tag in ["1.24.0", "1", "2", "2.0.1", "1.25.3-alpine", "latest", "stable", "1.25.3.1"] {
+			assert!(!entry[0].matches(tag), "{tag}");
+		}
+		assert!(serde_yaml::from_str::<Vec<Entry>>("- repository: docker.io/library/nginx\n  versions: \">=one\"").is_err());
+	}
+}
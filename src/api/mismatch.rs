@@ -0,0 +1,135 @@
+//! Blobs from upstream that don't match their digest.  By the time a blob's digest can be checked,
+//! it's been streamed to the client, so the pull that got it fails, and what was read isn't cached.
+//! Corruption in transit, or on one of upstream's servers, may not happen twice, though, so where
+//! the upstream is configured to, the blob is fetched again in the background, up to
+//! `digest_mismatch_retries` more times from the same upstream and then from the
+//! `digest_mismatch_fallback` namespace's, so that the client's retry is served from cache.
+
+use core::str::FromStr;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use actix_web::rt;
+use actix_web::web;
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+use tracing::Instrument;
+use tracing::Span;
+
+use super::error::Error;
+use super::mirror::drain;
+use super::serve_blob;
+use super::stream::DigestMismatchError;
+use super::Access;
+use super::BlobRequest;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::storage::health;
+use crate::upstream::Client;
+
+static MISMATCHES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_digest_mismatches", "Number of blobs from upstream that didn't match their digest", &["namespace"]).unwrap());
+static REFETCHES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("digest_mismatch_refetches", "Number of blobs fetched again after not matching their digest, by whether a good copy was found", &["namespace", "result"]).unwrap());
+
+/// Digests of the blobs being fetched again, so that a blob that doesn't match its digest again
+/// while it is, or that several clients got at once, isn't fetched again more than once at a time.
+static REFETCHING: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// Held while a blob is being fetched again.
+struct Refetching(String);
+
+impl Refetching {
+	fn start(digest: &str) -> Option<Self> {
+		REFETCHING.lock().unwrap().insert(digest.to_owned()).then(|| Self(digest.to_owned()))
+	}
+}
+
+impl Drop for Refetching {
+	fn drop(&mut self) {
+		REFETCHING.lock().unwrap().remove(&self.0);
+	}
+}
+
+/// A blob being fetched from upstream, and what to do if it doesn't match its digest.
+pub(super) struct Refetch {
+	config: web::Data<RequestConfig>,
+	namespace: CompactString,
+	image: CompactString,
+	digest: String,
+	retries: usize,
+	fallback: Option<CompactString>
+}
+
+impl Refetch {
+	/// Only shared pulls are fetched again:  the copy they cache is the one everyone is served.
+	pub(super) fn new(config: &web::Data<RequestConfig>, upstream: &Client, namespace: &str, image: &str, digest: &str, access: &Access) -> Self {
+		let shared = matches!(access, Access::Shared);
+		Self {
+			config: config.clone(),
+			namespace: namespace.into(),
+			image: image.into(),
+			digest: digest.to_owned(),
+			retries: match shared {
+				true => upstream.digest_mismatch_retries,
+				false => 0
+			},
+			fallback: upstream.digest_mismatch_fallback.clone().filter(|_| shared)
+		}
+	}
+
+	/// Records that the blob didn't match its digest, and starts fetching it again if it's to be.
+	pub(super) fn mismatched(self, error: &DigestMismatchError) {
+		MISMATCHES.with_label_values(&[self.namespace.as_str()]).inc();
+		error!(namespace = self.namespace.as_str(), image = self.image.as_str(), expected = self.digest.as_str(), actual = %error.actual(), "Blob from upstream doesn't match its digest");
+		if ((self.retries > 0 || self.fallback.is_some()) && !health::is_degraded()) {
+			rt::spawn(self.run().instrument(Span::current()));
+		}
+	}
+
+	/// Where the blob is fetched from, in order.
+	fn sources(&self) -> impl Iterator<Item = CompactString> + '_ {
+		core::iter::repeat(self.namespace.clone()).take(self.retries).chain(self.fallback.clone())
+	}
+
+	async fn fetch(&self, namespace: &str) -> Result<(), Error> {
+		let req = BlobRequest { image: ImageName::from_str(&self.image).map_err(|_| Error::NameUnknown)?, digest: self.digest.clone() };
+		// The digest is checked as it's read, so getting to the end means it matched
+		drain(serve_blob(self.config.clone(), req, Some(namespace), None).await?).await
+	}
+
+	async fn run(self) {
+		let Some(_refetching) = Refetching::start(&self.digest) else {
+			return;
+		};
+		for (attempt, source) in self.sources().enumerate() {
+			match self.fetch(&source).await {
+				Ok(()) => {
+					REFETCHES.with_label_values(&[self.namespace.as_str(), "fetched"]).inc();
+					info!(namespace = self.namespace.as_str(), source = source.as_str(), digest = self.digest.as_str(), attempt = attempt + 1, "Fetched a good copy of a blob that didn't match its digest");
+					return;
+				},
+				Err(error) => warn!(namespace = self.namespace.as_str(), source = source.as_str(), digest = self.digest.as_str(), attempt = attempt + 1, %error, "Failed to fetch blob again")
+			};
+		}
+		REFETCHES.with_label_values(&[self.namespace.as_str(), "failed"]).inc();
+		error!(namespace = self.namespace.as_str(), digest = self.digest.as_str(), "Found no copy of blob that matches its digest");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn refetching_once_at_a_time() {
+		let first = Refetching::start("sha256:aaaa").unwrap();
+		assert!(Refetching::start("sha256:aaaa").is_none());
+		assert!(Refetching::start("sha256:bbbb").is_some());
+		drop(first);
+		assert!(Refetching::start("sha256:aaaa").is_some());
+	}
+}
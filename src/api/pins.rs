@@ -0,0 +1,352 @@
+//! Pinned images:  manifests, and everything they reference, that cleanup never ages out, for base
+//! images that need to stay cached even if they're rarely pulled.  Pins are declared in a YAML file
+//! read at startup, or added and removed at runtime through the admin API; runtime pins are kept in
+//! storage, so that they survive restarts.
+//!
+//! A tag can also be pinned at a digest, which holds it there:  it's served as that digest, from
+//! cache or upstream, whatever upstream has moved the tag to since, until it's unpinned.  That's the
+//! emergency brake for when upstream pushes a broken `stable`.
+
+use core::fmt;
+use core::str::FromStr;
+use core::time::Duration;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::path::Path;
+
+use actix_web::web;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use bytes::BytesMut;
+use compact_str::CompactString;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use super::blob_storage_path;
+use super::error::Error;
+use super::manifest_storage_dir;
+use super::split_image;
+use super::Access;
+use super::ManifestQueryString;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::image::ImageReference;
+use crate::storage::Repository;
+
+/// Where runtime pins are kept.
+const PINS_OBJECT: &str = "pins.json";
+/// And the digests tags are held at.
+const HOLDS_OBJECT: &str = "holds.json";
+
+/// An image reference like `docker.io/library/busybox:1.36` or `ghcr.io/org/app@sha256:...`;
+/// the first path component is the namespace.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pin {
+	namespace: CompactString,
+	image: String,
+	reference: String
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid pin '{0}'; expected namespace/image:tag or namespace/image@digest")]
+pub struct InvalidPin(String);
+
+impl FromStr for Pin {
+	type Err = InvalidPin;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let invalid = || InvalidPin(s.to_owned());
+		let (name, reference) = match s.rsplit_once('@') {
+			Some(v) => v,
+			None => match s.rsplit_once(':') {
+				// A colon before the last slash is a port, not a tag
+				Some((name, tag)) if !tag.contains('/') => (name, tag),
+				_ => (s, "latest")
+			}
+		};
+		let (namespace, image) = name.split_once('/').ok_or_else(invalid)?;
+		if (namespace.is_empty() || ImageName::from_str(image).is_err() || ImageReference::from_str(reference).is_err()) {
+			return Err(invalid());
+		}
+		Ok(Self { namespace: namespace.into(), image: image.to_owned(), reference: reference.to_owned() })
+	}
+}
+
+impl TryFrom<String> for Pin {
+	type Error = InvalidPin;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		s.parse()
+	}
+}
+
+impl fmt::Display for Pin {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let separator = match self.reference.starts_with("sha256:") {
+			true => '@',
+			false => ':'
+		};
+		write!(f, "{}/{}{separator}{}", self.namespace, self.image, self.reference)
+	}
+}
+
+impl From<Pin> for String {
+	fn from(pin: Pin) -> Self {
+		pin.to_string()
+	}
+}
+
+impl Pin {
+	fn from_request(req: &ManifestRequest, qstr: &ManifestQueryString, config: &RequestConfig) -> Self {
+		let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns(None));
+		Self { namespace: namespace.into(), image: image.to_owned(), reference: req.reference.to_string() }
+	}
+
+	pub fn namespace(&self) -> &str {
+		self.namespace.as_str()
+	}
+
+	pub(super) fn image(&self) -> &str {
+		&self.image
+	}
+
+	pub(super) fn reference(&self) -> &str {
+		&self.reference
+	}
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+	#[error("Failed to read pins file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Invalid pins file: {0}")]
+	Yaml(#[from] serde_yaml::Error),
+	#[error("Failed to read pins from storage: {0}")]
+	Storage(#[from] crate::storage::Error),
+	#[error("Invalid pins in storage: {0}")]
+	Json(#[from] serde_json::Error)
+}
+
+/// The current set of pins.
+#[derive(Default)]
+pub struct Pins {
+	/// From the pins file; can't be removed at runtime
+	declared: BTreeSet<Pin>,
+	/// Added through the admin API
+	added: Mutex<BTreeSet<Pin>>,
+	/// The digests tags are held at, by tag; always added through the admin API
+	held: Mutex<BTreeMap<Pin, String>>
+}
+
+/// The parts of a manifest or index that point at other objects.
+#[derive(Deserialize)]
+pub(super) struct References {
+	#[serde(default)]
+	pub(super) config: Option<Descriptor>,
+	#[serde(default)]
+	pub(super) layers: Vec<Descriptor>,
+	#[serde(default)]
+	pub(super) manifests: Vec<Descriptor>
+}
+
+#[derive(Deserialize)]
+pub(super) struct Descriptor {
+	pub(super) digest: String,
+	/// Only on the manifests in an index
+	#[serde(default)]
+	pub(super) platform: Option<Platform>
+}
+
+#[derive(Deserialize)]
+pub(super) struct Platform {
+	os: String,
+	architecture: String,
+	#[serde(default)]
+	variant: Option<String>
+}
+
+impl Platform {
+	/// Whether this is the platform named by `name`, as `os/architecture` or
+	/// `os/architecture/variant`.
+	pub(super) fn is(&self, name: &str) -> bool {
+		match name.split('/').collect::<Vec<_>>()[..] {
+			[os, architecture] => os == self.os && architecture == self.architecture,
+			[os, architecture, variant] => os == self.os && architecture == self.architecture && self.variant.as_deref() == Some(variant),
+			_ => false
+		}
+	}
+}
+
+async fn save(repo: &Repository, object: &str, value: &impl Serialize) -> Result<(), Error> {
+	let body = Bytes::from(serde_json::to_vec(value)?);
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	repo.write(object, futures::stream::iter(std::iter::once(Result::<_, std::io::Error>::Ok(body))), len).await?;
+	Ok(())
+}
+
+async fn read_all(repo: &Repository, object: &str) -> Result<BytesMut, crate::storage::Error> {
+	let stream = repo.read(object, Duration::MAX).await?;
+	Ok(stream.into_inner().try_collect::<BytesMut>().await?)
+}
+
+impl Pins {
+	/// Reads the pins file, if there is one, along with the runtime pins in storage.
+	pub async fn load(repo: &Repository, file: Option<&Path>) -> Result<Self, LoadError> {
+		let declared = match file {
+			Some(path) => serde_yaml::from_slice(&tokio::fs::read(path).await?)?,
+			None => BTreeSet::new()
+		};
+		let added = match read_all(repo, PINS_OBJECT).await {
+			Ok(body) => serde_json::from_slice(body.as_ref())?,
+			Err(e) if e.is_not_found() => BTreeSet::new(),
+			Err(e) => return Err(e.into())
+		};
+		let held = match read_all(repo, HOLDS_OBJECT).await {
+			Ok(body) => serde_json::from_slice(body.as_ref())?,
+			Err(e) if e.is_not_found() => BTreeMap::new(),
+			Err(e) => return Err(e.into())
+		};
+		Ok(Self { declared, added: Mutex::new(added), held: Mutex::new(held) })
+	}
+
+	pub async fn all(&self) -> BTreeSet<Pin> {
+		self.declared.iter().chain(self.added.lock().await.iter()).cloned().collect()
+	}
+
+	/// The digest each held tag is held at.
+	pub async fn holds(&self) -> BTreeMap<Pin, String> {
+		self.held.lock().await.clone()
+	}
+
+	/// The digest a tag is held at, if it is.
+	pub(super) async fn held(&self, namespace: &str, image: &str, tag: &str) -> Option<String> {
+		let pin = Pin { namespace: namespace.into(), image: image.to_owned(), reference: tag.to_owned() };
+		self.held.lock().await.get(&pin).cloned()
+	}
+
+	async fn add(&self, repo: &Repository, pin: Pin, digest: Option<String>) -> Result<(), Error> {
+		if let Some(digest) = digest {
+			if (pin.reference.starts_with("sha256:")) {
+				return Err(Error::HoldNeedsTag);
+			}
+			if (!matches!(ImageReference::from_str(&digest), Ok(ImageReference::Sha256(_)))) {
+				return Err(Error::InvalidDigest);
+			}
+			let mut held = self.held.lock().await;
+			if (held.get(&pin) != Some(&digest)) {
+				held.insert(pin.clone(), digest);
+				save(repo, HOLDS_OBJECT, &*held).await?;
+			}
+		}
+		let mut added = self.added.lock().await;
+		if (self.declared.contains(&pin) || !added.insert(pin)) {
+			return Ok(());
+		}
+		save(repo, PINS_OBJECT, &*added).await
+	}
+
+	/// Unpins `pin`, releasing the tag if it's held.  A pin from the pins file stays, but its tag
+	/// can still be released.
+	async fn remove(&self, repo: &Repository, pin: &Pin) -> Result<(), Error> {
+		let released = {
+			let mut held = self.held.lock().await;
+			let released = held.remove(pin).is_some();
+			if (released) {
+				save(repo, HOLDS_OBJECT, &*held).await?;
+			}
+			released
+		};
+		let mut added = self.added.lock().await;
+		if (added.remove(pin)) {
+			return save(repo, PINS_OBJECT, &*added).await;
+		}
+		match (released, self.declared.contains(pin)) {
+			(true, _) => Ok(()),
+			(false, true) => Err(Error::PinnedByConfig),
+			(false, false) => Err(Error::ManifestUnknown)
+		}
+	}
+
+	/// Every stored object that cleanup has to leave alone:  each pinned manifest, the manifests an
+	/// index refers to, and the config and layers of each.  Only what's already cached is found;
+	/// pinning an image doesn't pull it.
+	pub async fn protected_paths(&self, repo: &Repository) -> HashSet<String> {
+		let mut paths = HashSet::new();
+		// What a tag's held at has to stay, wherever the tag's moved to
+		let holds = self.holds().await.into_iter().map(|(pin, digest)| Pin { reference: digest, ..pin });
+		for pin in self.all().await.into_iter().chain(holds) {
+			let dir = manifest_storage_dir(&pin.namespace, &pin.image, &Access::Shared);
+			let mut pending = vec![format!("{dir}/{}", pin.reference)];
+			while let Some(path) = pending.pop() {
+				if (!paths.insert(path.clone())) {
+					continue;
+				}
+				let body = match read_all(repo, &path).await {
+					Ok(v) => v,
+					Err(e) if e.is_not_found() => continue,
+					Err(error) => {
+						warn!(%pin, path, %error, "Failed to read pinned manifest");
+						continue;
+					}
+				};
+				let Ok(references) = serde_json::from_slice::<References>(body.as_ref()) else {
+					continue;
+				};
+				pending.extend(references.manifests.iter().map(|m| format!("{dir}/{}", m.digest)));
+				paths.extend(references.config.iter().chain(references.layers.iter()).map(|b| blob_storage_path(&b.digest, &Access::Shared)));
+			}
+		}
+		paths
+	}
+}
+
+pub async fn list(config: web::Data<RequestConfig>) -> HttpResponse {
+	HttpResponse::Ok().json(config.pins.all().await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HoldQueryString {
+	/// The digest to hold a tag at
+	digest: Option<String>
+}
+
+/// Lists held tags, as a map from each to the digest it's held at.
+pub async fn list_holds(config: web::Data<RequestConfig>) -> HttpResponse {
+	HttpResponse::Ok().json(config.pins.holds().await)
+}
+
+pub async fn add(req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, hold: web::Query<HoldQueryString>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
+	config.pins.add(&config.repo, Pin::from_request(&req, &qstr, &config), hold.into_inner().digest).await?;
+	Ok("")
+}
+
+pub async fn remove(req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
+	config.pins.remove(&config.repo, &Pin::from_request(&req, &qstr, &config)).await?;
+	Ok("")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_pins() {
+		let pin: Pin = "docker.io/library/busybox:1.36".parse().unwrap();
+		assert_eq!((pin.namespace.as_str(), pin.image.as_str(), pin.reference.as_str()), ("docker.io", "library/busybox", "1.36"));
+		assert_eq!(pin.to_string(), "docker.io/library/busybox:1.36");
+		let digest = "sha256:226cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883";
+		let pin: Pin = format!("ghcr.io/org/app@{digest}").parse().unwrap();
+		assert_eq!(pin.reference, digest);
+		assert_eq!(pin.to_string(), format!("ghcr.io/org/app@{digest}"));
+		let pin: Pin = "localhost:5000/app".parse().unwrap();
+		assert_eq!((pin.namespace.as_str(), pin.reference.as_str()), ("localhost:5000", "latest"));
+		assert!("busybox".parse::<Pin>().is_err());
+		assert!("docker.io/library/busybox@sha256:nope".parse::<Pin>().is_err());
+	}
+}
@@ -0,0 +1,192 @@
+//! Plugins:  policy hooks for apps embedding the proxy, called at three points in each pull — when
+//! a client's request comes in, before anything is fetched from upstream for it, and once what was
+//! fetched has been cached — with what's known about the request.  Each can let it through, refuse
+//! it, or attach annotations to it for the logs, so that organization-specific rules (no images
+//! from some namespace, nothing past a size, an audit trail of what's pulled) don't need a fork.
+//!
+//! The hooks are synchronous and called in the request path, so they should be quick.  Builds with
+//! the `wasm-plugins` feature can also run plugins compiled to WebAssembly, sandboxed; see [`wasm`].
+
+use core::fmt;
+use std::sync::Arc;
+
+use actix_web::HttpMessage;
+use actix_web::HttpRequest;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use tracing::info;
+
+use super::auth::Access;
+use super::client_ip::ClientIp;
+use super::error::Error;
+
+#[cfg(feature = "wasm-plugins")]
+pub mod wasm;
+
+static DECISIONS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("plugin_decisions", "Number of decisions made by each plugin, by hook", &["plugin", "hook", "decision"]).unwrap());
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Hook {
+	/// A client's request has come in, and nothing's been looked up for it yet
+	Authorize,
+	/// The request is a cache miss, and is about to go to upstream
+	BeforeFetch,
+	/// What upstream sent has been written to the cache.  Refusing it evicts it, so that it's
+	/// fetched (and checked) again next time; a blob has been streamed to the client by then.
+	AfterFill
+}
+
+impl Hook {
+	fn as_str(self) -> &'static str {
+		match self {
+			Self::Authorize => "authorize",
+			Self::BeforeFetch => "before_fetch",
+			Self::AfterFill => "after_fill"
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ObjectKind {
+	Manifest,
+	Blob
+}
+
+/// What a hook is told about a request.
+#[derive(Clone, Debug)]
+pub struct RequestInfo<'a> {
+	pub hook: Hook,
+	pub kind: ObjectKind,
+	/// `GET` or `HEAD`; requests the proxy makes on its own, for mirroring and the like, are `GET`s
+	pub method: &'a str,
+	pub namespace: &'a str,
+	pub image: &'a str,
+	/// The tag or digest of a manifest, or the digest of a blob
+	pub reference: &'a str,
+	/// The client's address, for requests made by a client
+	pub client_ip: Option<String>,
+	/// The tenant, or the username of pass-through credentials, the request was made with
+	pub user: Option<&'a str>,
+	/// How big the cached object is; only known after the cache has been filled
+	pub size: Option<u64>
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Decision {
+	Allow,
+	/// Refuse the request with a `DENIED` error carrying this reason
+	Deny(String),
+	/// Let the request through, logging these with it
+	Annotate(Vec<(String, String)>)
+}
+
+impl Decision {
+	fn as_str(&self) -> &'static str {
+		match self {
+			Self::Allow => "allow",
+			Self::Deny(_) => "deny",
+			Self::Annotate(_) => "annotate"
+		}
+	}
+}
+
+pub trait Plugin: Send + Sync {
+	/// Used in logs, metrics, and errors
+	fn name(&self) -> &str;
+
+	/// Called at each hook, with what's known about the request so far.
+	fn call(&self, request: &RequestInfo<'_>) -> Decision;
+}
+
+/// Every plugin, called in the order they were added; the first to refuse a request decides.
+#[derive(Clone, Default)]
+pub struct Plugins(Vec<Arc<dyn Plugin>>);
+
+impl fmt::Debug for Plugins {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_list().entries(self.0.iter().map(|p| p.name())).finish()
+	}
+}
+
+impl From<Vec<Arc<dyn Plugin>>> for Plugins {
+	fn from(plugins: Vec<Arc<dyn Plugin>>) -> Self {
+		Self(plugins)
+	}
+}
+
+impl Plugins {
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	pub fn run(&self, request: &RequestInfo<'_>) -> Result<(), Error> {
+		for plugin in &self.0 {
+			let decision = plugin.call(request);
+			DECISIONS.with_label_values(&[plugin.name(), request.hook.as_str(), decision.as_str()]).inc();
+			match decision {
+				Decision::Allow => (),
+				Decision::Annotate(annotations) => info!(plugin = plugin.name(), hook = request.hook.as_str(), namespace = request.namespace, image = request.image, reference = request.reference, ?annotations, "Plugin annotated request"),
+				Decision::Deny(reason) => {
+					info!(plugin = plugin.name(), hook = request.hook.as_str(), namespace = request.namespace, image = request.image, reference = request.reference, reason = reason.as_str(), "Plugin refused request");
+					return Err(Error::PolicyDenied { plugin: plugin.name().to_owned(), reason });
+				}
+			};
+		}
+		Ok(())
+	}
+}
+
+/// Describes a request for a hook.  Without a client request, it's one the proxy is making on its
+/// own behalf.
+pub(super) fn request_info<'a>(hook: Hook, kind: ObjectKind, http_req: Option<&'a HttpRequest>, access: &'a Access, namespace: &'a str, image: &'a str, reference: &'a str) -> RequestInfo<'a> {
+	let user = match access {
+		Access::Shared => None,
+		Access::Private(credentials) => Some(credentials.username.as_str()),
+		Access::Tenant(name) => Some(name.as_str())
+	};
+	RequestInfo {
+		hook,
+		kind,
+		method: http_req.map_or("GET", |r| r.method().as_str()),
+		namespace,
+		image,
+		reference,
+		client_ip: http_req.and_then(|r| r.extensions().get::<ClientIp>().map(ToString::to_string)),
+		user,
+		size: None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct DenyNamespace(&'static str);
+
+	impl Plugin for DenyNamespace {
+		fn name(&self) -> &str {
+			"deny-namespace"
+		}
+
+		fn call(&self, request: &RequestInfo<'_>) -> Decision {
+			match request.namespace == self.0 {
+				true => Decision::Deny(format!("{} is off limits", self.0)),
+				false => Decision::Annotate(vec![("checked".into(), "yes".into())])
+			}
+		}
+	}
+
+	#[test]
+	fn decisions() {
+		let plugins = Plugins::from(vec![Arc::new(DenyNamespace("quay.io")) as Arc<dyn Plugin>]);
+		let access = Access::Shared;
+		let info = |namespace: &'static str| request_info(Hook::Authorize, ObjectKind::Manifest, None, &access, namespace, "library/alpine", "latest");
+		assert!(plugins.run(&info("docker.io")).is_ok());
+		match plugins.run(&info("quay.io")) {
+			Err(Error::PolicyDenied { plugin, reason }) => assert_eq!((plugin.as_str(), reason.as_str()), ("deny-namespace", "quay.io is off limits")),
+			result => panic!("{result:?}")
+		};
+		assert!(Plugins::default().run(&info("quay.io")).is_ok());
+	}
+}
@@ -0,0 +1,198 @@
+//! Plugins compiled to WebAssembly and run with wasmtime, in builds with the `wasm-plugins` feature,
+//! loaded with `--plugin`.  A plugin is a module that imports nothing, so that it can't reach
+//! anything but what it's told, and exports:
+//!
+//! * `memory`
+//! * `alloc(len: i32) -> i32`, which makes room for `len` bytes of request and says where
+//! * `call(ptr: i32, len: i32) -> i64`, which decides on the request written there, and says where
+//!   its decision is:  the address in the upper 32 bits, and the length in the lower 32
+//!
+//! Both are JSON.  The request has the fields of [`RequestInfo`], with `hook` and `kind` in snake
+//! case; the decision is `{"decision": "allow"}`, `{"decision": "deny", "reason": "..."}`, or
+//! `{"decision": "annotate", "annotations": {"key": "value"}}`.  Each call gets an instance of its
+//! own, so nothing's kept from one to the next, and a bounded amount of fuel, so that a plugin
+//! that runs away fails the call instead of holding up the request.  A call that fails, for that
+//! or any other reason, refuses the request.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::error;
+use wasmtime::Config;
+use wasmtime::Engine;
+use wasmtime::InstancePre;
+use wasmtime::Linker;
+use wasmtime::Module;
+use wasmtime::Store;
+
+use super::Decision;
+use super::ObjectKind;
+use super::Plugin;
+use super::RequestInfo;
+
+/// How much a call can do before it's cut off, in wasmtime's fuel:  around as many instructions
+const FUEL: u64 = 10_000_000;
+/// The longest a decision can be
+const MAX_DECISION_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+	#[error("Failed to read plugin: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Invalid plugin: {0:#}")]
+	Wasm(#[from] wasmtime::Error)
+}
+
+/// What a plugin is told about a request.
+#[derive(Serialize)]
+struct Request<'a> {
+	hook: &'static str,
+	kind: &'static str,
+	method: &'a str,
+	namespace: &'a str,
+	image: &'a str,
+	reference: &'a str,
+	client_ip: Option<&'a str>,
+	user: Option<&'a str>,
+	size: Option<u64>
+}
+
+impl<'a> From<&'a RequestInfo<'a>> for Request<'a> {
+	fn from(info: &'a RequestInfo<'a>) -> Self {
+		let kind = match info.kind {
+			ObjectKind::Manifest => "manifest",
+			ObjectKind::Blob => "blob"
+		};
+		Self { hook: info.hook.as_str(), kind, method: info.method, namespace: info.namespace, image: info.image, reference: info.reference, client_ip: info.client_ip.as_deref(), user: info.user, size: info.size }
+	}
+}
+
+/// What a plugin decides.
+#[derive(Deserialize)]
+#[serde(tag = "decision", rename_all = "snake_case")]
+enum Answer {
+	Allow,
+	Deny { reason: String },
+	Annotate {
+		#[serde(default)]
+		annotations: BTreeMap<String, String>
+	}
+}
+
+impl From<Answer> for Decision {
+	fn from(answer: Answer) -> Self {
+		match answer {
+			Answer::Allow => Self::Allow,
+			Answer::Deny { reason } => Self::Deny(reason),
+			Answer::Annotate { annotations } => Self::Annotate(annotations.into_iter().collect())
+		}
+	}
+}
+
+pub struct WasmPlugin {
+	name: String,
+	engine: Engine,
+	/// The module, compiled and checked against what it imports, ready for an instance per call
+	module: InstancePre<()>
+}
+
+impl WasmPlugin {
+	/// Compiles the module at `path`, named for its file, so that no request waits on that.
+	pub async fn load(path: &Path) -> Result<Self, LoadError> {
+		let wasm = tokio::fs::read(path).await?;
+		let name = path.file_stem().map_or_else(|| path.display().to_string(), |stem| stem.to_string_lossy().into_owned());
+		Ok(Self::new(name, &wasm)?)
+	}
+
+	fn new(name: String, wasm: &[u8]) -> Result<Self, wasmtime::Error> {
+		let mut config = Config::new();
+		config.consume_fuel(true);
+		let engine = Engine::new(&config)?;
+		let module = Module::new(&engine, wasm)?;
+		// Nothing's linked, so a module that imports anything is refused here
+		let module = Linker::new(&engine).instantiate_pre(&module)?;
+		Ok(Self { name, engine, module })
+	}
+
+	fn decide(&self, info: &RequestInfo<'_>) -> Result<Decision, wasmtime::Error> {
+		let request = serde_json::to_vec(&Request::from(info))?;
+		let mut store = Store::new(&self.engine, ());
+		store.set_fuel(FUEL)?;
+		let instance = self.module.instantiate(&mut store)?;
+		let memory = instance.get_memory(&mut store, "memory").ok_or_else(|| wasmtime::Error::msg("No memory exported"))?;
+		let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+		let call = instance.get_typed_func::<(i32, i32), i64>(&mut store, "call")?;
+
+		let len = i32::try_from(request.len())?;
+		let ptr = alloc.call(&mut store, len)?;
+		memory.write(&mut store, ptr as u32 as usize, &request)?;
+		let answer = call.call(&mut store, (ptr, len))? as u64;
+		let (ptr, len) = ((answer >> 32) as usize, (answer & 0xffff_ffff) as usize);
+		if (len > MAX_DECISION_SIZE) {
+			return Err(wasmtime::Error::msg(format!("Decision is {len} bytes, over the {MAX_DECISION_SIZE} byte limit")));
+		}
+		let mut answer = vec![0; len];
+		memory.read(&store, ptr, &mut answer)?;
+		Ok(serde_json::from_slice::<Answer>(&answer)?.into())
+	}
+}
+
+impl Plugin for WasmPlugin {
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn call(&self, request: &RequestInfo<'_>) -> Decision {
+		match self.decide(request) {
+			Ok(v) => v,
+			Err(error) => {
+				error!(plugin = self.name.as_str(), hook = request.hook.as_str(), error = %format_args!("{error:#}"), "Plugin failed; refusing the request");
+				Decision::Deny(format!("Plugin {} failed", self.name))
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::auth::Access;
+	use crate::api::plugin::request_info;
+	use crate::api::plugin::Hook;
+
+	/// A module that answers every call with `decision`, and takes its time about it if `spin`.
+	fn module(decision: &str, spin: bool) -> String {
+		let escaped = decision.replace('"', "\\\"");
+		let spin = match spin {
+			true => "(loop $spin (br $spin))",
+			false => ""
+		};
+		let answer = (1024u64 << 32) | decision.len() as u64;
+		format!(r#"(module
+			(memory (export "memory") 1)
+			(data (i32.const 1024) "{escaped}")
+			(func (export "alloc") (param i32) (result i32) i32.const 4096)
+			(func (export "call") (param i32 i32) (result i64) {spin} i64.const {answer}))"#)
+	}
+
+	#[test]
+	fn decisions() {
+		let access = Access::Shared;
+		let info = request_info(Hook::BeforeFetch, ObjectKind::Blob, None, &access, "docker.io", "library/alpine", "latest");
+		let deny = WasmPlugin::new("deny".into(), module(r#"{"decision": "deny", "reason": "off limits"}"#, false).as_bytes()).unwrap();
+		assert_eq!(deny.call(&info), Decision::Deny("off limits".into()));
+		let annotate = WasmPlugin::new("annotate".into(), module(r#"{"decision": "annotate", "annotations": {"team": "platform"}}"#, false).as_bytes()).unwrap();
+		assert_eq!(annotate.call(&info), Decision::Annotate(vec![("team".into(), "platform".into())]));
+		// Out of fuel
+		let spin = WasmPlugin::new("spin".into(), module(r#"{"decision": "allow"}"#, true).as_bytes()).unwrap();
+		assert_eq!(spin.call(&info), Decision::Deny("Plugin spin failed".into()));
+	}
+
+	#[test]
+	fn imports_are_refused() {
+		let wasm = r#"(module (import "env" "clock" (func)) (memory (export "memory") 1))"#;
+		assert!(WasmPlugin::new("imports".into(), wasm.as_bytes()).is_err());
+	}
+}
@@ -0,0 +1,319 @@
+//! Prefetching:  learning which images get pulled together (an app image, say, and the sidecars
+//! that always start next to it) and pulling the rest of them into the cache as soon as one of them
+//! is pulled, so that the others are cache hits by the time they're asked for.  What's learned,
+//! and what's predicted from it, is up to a [`Strategy`]; [`CoPulls`] counts which tags each client
+//! pulls shortly after which others.
+//!
+//! Only pulls from the shared cache are learned from and prefetched; nothing is fetched with a
+//! tenant's or a client's own credentials on their behalf.  Prefetching fetches manifests, and
+//! leaves their blobs to be fetched when they're pulled.
+
+use core::fmt;
+use core::str::FromStr;
+use core::time::Duration;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::web;
+use actix_web::HttpMessage;
+use actix_web::HttpRequest;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::debug;
+use tracing::warn;
+
+use super::client_ip::ClientIp;
+use super::error::Error;
+use super::mirror::drain;
+use super::serve_manifest;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::image::ImageReference;
+use crate::upstream::AuthMode;
+
+static PREFETCHES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_prefetches", "Number of manifests prefetched because an image they're usually pulled with was pulled", &["namespace", "result"]).unwrap());
+
+/// How many images' co-pulls are counted, so that memory stays bounded however many are pulled
+const MAX_IMAGES: usize = 10_000;
+/// How many images are counted as following each image
+const MAX_FOLLOWERS: usize = 100;
+/// How many clients' recent pulls are remembered
+const MAX_CLIENTS: usize = 10_000;
+
+/// A tag of an image, as it's pulled and prefetched.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct Image {
+	pub namespace: String,
+	pub image: String,
+	pub tag: String
+}
+
+impl fmt::Display for Image {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}:{}", self.namespace, self.image, self.tag)
+	}
+}
+
+/// How to tell what's about to be pulled from what has been.  Implementations are called in the
+/// request path, so they should be quick.
+pub trait Strategy: Send + Sync {
+	/// Used in logs
+	fn name(&self) -> &str;
+
+	/// Called for every tag a client pulls, with who the client is.
+	fn observe(&self, client: &str, image: &Image);
+
+	/// What to prefetch now that `image` has been pulled.
+	fn predict(&self, image: &Image) -> Vec<Image>;
+
+	/// What's been learned, to be kept in the checkpoint across restarts.
+	fn snapshot(&self) -> Option<serde_json::Value> {
+		None
+	}
+
+	/// Takes back what [`Strategy::snapshot`] returned before a restart.
+	fn restore(&self, _state: serde_json::Value) {}
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CoPullConfig {
+	/// How soon after one tag another has to be pulled, by the same client, to count as pulled with it
+	pub window: Duration,
+	/// How many times a tag has to have followed another before it's prefetched with it
+	pub min_count: u64,
+	/// The most tags prefetched after any one pull
+	pub max_predictions: usize
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+	pulls: u64,
+	/// How many times each image has been pulled within the window after this one
+	followers: HashMap<Image, u64>
+}
+
+#[derive(Debug, Default)]
+struct State {
+	images: HashMap<Image, Counts>,
+	/// What each client has pulled within the window, oldest first
+	recent: HashMap<String, VecDeque<(Instant, Image)>>
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Follower {
+	image: Image,
+	count: u64
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Snapshot {
+	image: Image,
+	pulls: u64,
+	followers: Vec<Follower>
+}
+
+/// Prefetches what's followed an image, pulled by the same client within a window, in at least
+/// half its pulls, and at least some number of times.
+#[derive(Debug)]
+pub struct CoPulls {
+	config: CoPullConfig,
+	state: Mutex<State>
+}
+
+impl CoPulls {
+	pub fn new(config: CoPullConfig) -> Self {
+		Self { config, state: Mutex::new(State::default()) }
+	}
+
+	fn observe_at(&self, client: &str, image: &Image, now: Instant) {
+		let mut state = self.state.lock().unwrap();
+		let State { images, recent } = &mut *state;
+		let window = self.config.window;
+		if (!recent.contains_key(client) && recent.len() >= MAX_CLIENTS) {
+			recent.retain(|_, pulls| pulls.back().is_some_and(|(at, _)| now.duration_since(*at) < window));
+			if (recent.len() >= MAX_CLIENTS) {
+				return;
+			}
+		}
+		let pulls = recent.entry(client.to_owned()).or_default();
+		while pulls.front().is_some_and(|(at, _)| now.duration_since(*at) >= window) {
+			pulls.pop_front();
+		}
+		// Clients ask for a tag more than once in a pull (a `HEAD`, then a `GET`), and pulling it
+		// again within the window isn't pulling anything new with it
+		if (pulls.iter().any(|(_, pulled)| pulled == image) || (!images.contains_key(image) && images.len() >= MAX_IMAGES)) {
+			return;
+		}
+		for (_, earlier) in pulls.iter() {
+			let Some(counts) = images.get_mut(earlier) else {
+				continue;
+			};
+			if (counts.followers.contains_key(image) || counts.followers.len() < MAX_FOLLOWERS) {
+				*counts.followers.entry(image.clone()).or_default() += 1;
+			}
+		}
+		images.entry(image.clone()).or_default().pulls += 1;
+		pulls.push_back((now, image.clone()));
+	}
+}
+
+impl Strategy for CoPulls {
+	fn name(&self) -> &str {
+		"co-pulls"
+	}
+
+	fn observe(&self, client: &str, image: &Image) {
+		self.observe_at(client, image, Instant::now());
+	}
+
+	fn predict(&self, image: &Image) -> Vec<Image> {
+		let state = self.state.lock().unwrap();
+		let Some(counts) = state.images.get(image) else {
+			return Vec::new();
+		};
+		let mut followers = counts.followers.iter().filter(|(_, n)| **n >= self.config.min_count && **n * 2 >= counts.pulls).collect::<Vec<_>>();
+		followers.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+		followers.into_iter().take(self.config.max_predictions).map(|(image, _)| image.clone()).collect()
+	}
+
+	fn snapshot(&self) -> Option<serde_json::Value> {
+		let state = self.state.lock().unwrap();
+		let snapshot = state
+			.images
+			.iter()
+			.map(|(image, counts)| Snapshot { image: image.clone(), pulls: counts.pulls, followers: counts.followers.iter().map(|(image, count)| Follower { image: image.clone(), count: *count }).collect() })
+			.collect::<Vec<_>>();
+		serde_json::to_value(snapshot).ok()
+	}
+
+	fn restore(&self, state: serde_json::Value) {
+		let snapshot = match serde_json::from_value::<Vec<Snapshot>>(state) {
+			Ok(v) => v,
+			Err(error) => {
+				warn!(%error, "Ignoring co-pull counts that couldn't be read");
+				return;
+			}
+		};
+		let mut state = self.state.lock().unwrap();
+		for entry in snapshot.into_iter().take(MAX_IMAGES) {
+			let followers = entry.followers.into_iter().take(MAX_FOLLOWERS).map(|f| (f.image, f.count)).collect();
+			state.images.insert(entry.image, Counts { pulls: entry.pulls, followers });
+		}
+	}
+}
+
+/// A [`Strategy`], and the prefetches it's started that haven't finished.
+pub struct Prefetcher {
+	strategy: Arc<dyn Strategy>,
+	/// So that an image isn't prefetched again while it's still being prefetched
+	in_flight: Mutex<HashSet<Image>>
+}
+
+impl fmt::Debug for Prefetcher {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Prefetcher").field("strategy", &self.strategy.name()).finish()
+	}
+}
+
+impl Prefetcher {
+	pub fn new(strategy: Arc<dyn Strategy>) -> Self {
+		Self { strategy, in_flight: Mutex::new(HashSet::new()) }
+	}
+
+	pub fn strategy(&self) -> &dyn Strategy {
+		self.strategy.as_ref()
+	}
+}
+
+/// Whether a namespace's pulls are served from the shared cache.
+async fn is_shared(config: &RequestConfig, namespace: &str) -> bool {
+	config.tenants.is_none() && config.upstream.lock().await.get(namespace).is_ok_and(|u| matches!(u.auth_mode, AuthMode::Proxy))
+}
+
+/// Learns from a client's pull of a tag, and prefetches what it says is about to be pulled next.
+pub(super) async fn pulled(config: &web::Data<RequestConfig>, http_req: &HttpRequest, namespace: &str, image: &str, tag: &str) {
+	let Some(prefetcher) = config.prefetch.clone() else {
+		return;
+	};
+	if (!is_shared(config, namespace).await) {
+		return;
+	}
+	let client = match http_req.extensions().get::<ClientIp>() {
+		Some(ip) => ip.to_string(),
+		None => http_req.peer_addr().map(|a| a.ip().to_string()).unwrap_or_default()
+	};
+	let pulled = Image { namespace: namespace.to_owned(), image: image.to_owned(), tag: tag.to_owned() };
+	prefetcher.strategy.observe(&client, &pulled);
+	for predicted in prefetcher.strategy.predict(&pulled) {
+		if (predicted == pulled || !is_shared(config, &predicted.namespace).await || !prefetcher.in_flight.lock().unwrap().insert(predicted.clone())) {
+			continue;
+		}
+		let config = config.clone();
+		let prefetcher = prefetcher.clone();
+		let after = pulled.to_string();
+		actix_web::rt::spawn(async move {
+			let result = prefetch(&config, &predicted).await;
+			prefetcher.in_flight.lock().unwrap().remove(&predicted);
+			match result {
+				Ok(()) => {
+					PREFETCHES.with_label_values(&[predicted.namespace.as_str(), "ok"]).inc();
+					debug!(image = %predicted, after, "Prefetched manifest");
+				},
+				Err(error) => {
+					PREFETCHES.with_label_values(&[predicted.namespace.as_str(), "error"]).inc();
+					debug!(image = %predicted, after, %error, "Failed to prefetch manifest");
+				}
+			};
+		});
+	}
+}
+
+async fn prefetch(config: &RequestConfig, image: &Image) -> Result<(), Error> {
+	let req = ManifestRequest {
+		image: ImageName::from_str(&image.image).map_err(|_| Error::NameUnknown)?,
+		reference: ImageReference::from_str(&image.tag).map_err(|_| Error::ManifestUnknown)?
+	};
+	drain(serve_manifest(config, &req, Some(&image.namespace), None).await?).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn image(name: &str) -> Image {
+		Image { namespace: "docker.io".into(), image: name.into(), tag: "latest".into() }
+	}
+
+	#[test]
+	fn co_pulls() {
+		let strategy = CoPulls::new(CoPullConfig { window: Duration::from_secs(60), min_count: 2, max_predictions: 2 });
+		let start = Instant::now();
+		let at = |secs: u64| start + Duration::from_secs(secs);
+		for (client, offset) in [("10.0.0.1", 0), ("10.0.0.2", 1000), ("10.0.0.3", 2000)] {
+			strategy.observe_at(client, &image("org/app"), at(offset));
+			strategy.observe_at(client, &image("org/app"), at(offset + 1));
+			strategy.observe_at(client, &image("org/sidecar"), at(offset + 5));
+			strategy.observe_at(client, &image("org/proxy"), at(offset + 10));
+		}
+		// Too long after to count
+		strategy.observe_at("10.0.0.1", &image("org/late"), at(100));
+		strategy.observe_at("10.0.0.4", &image("org/app"), at(3000));
+		strategy.observe_at("10.0.0.4", &image("org/once"), at(3001));
+		assert_eq!(strategy.predict(&image("org/app")), vec![image("org/proxy"), image("org/sidecar")]);
+		assert_eq!(strategy.predict(&image("org/sidecar")), vec![image("org/proxy")]);
+		assert!(strategy.predict(&image("org/proxy")).is_empty());
+		assert!(strategy.predict(&image("org/unknown")).is_empty());
+
+		let restored = CoPulls::new(strategy.config);
+		restored.restore(strategy.snapshot().unwrap());
+		assert_eq!(restored.predict(&image("org/app")), vec![image("org/proxy"), image("org/sidecar")]);
+	}
+}
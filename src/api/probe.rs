@@ -0,0 +1,149 @@
+//! Self-test probing:  a small image pulled through the whole path on a schedule, the way a client
+//! would pull it, so that upstream credentials that have expired or storage that's stopped taking
+//! writes show up in metrics before anyone's pull fails.  Each probe drops the image's cached
+//! manifest first, so that it has to come from upstream and be written to storage; reads it back
+//! from storage, to check that it was; and then pulls a blob it refers to.  A probe that fails
+//! upstream leaves the tag uncached, so it shouldn't be one that clients count on being served stale
+//! while upstream is down.
+
+use core::future::Future;
+use core::str::FromStr;
+use core::time::Duration;
+use std::time::Instant;
+
+use actix_web::body;
+use actix_web::web;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use once_cell::sync::Lazy;
+use prometheus::register_histogram_vec;
+use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge;
+use prometheus::HistogramVec;
+use prometheus::IntCounterVec;
+use prometheus::IntGauge;
+use time::OffsetDateTime;
+use tokio::time::MissedTickBehavior;
+use tracing::debug;
+use tracing::warn;
+
+use super::error::Error;
+use super::mirror::drain;
+use super::pins::Pin;
+use super::pins::References;
+use super::serve_blob;
+use super::serve_manifest;
+use super::Access;
+use super::BlobRequest;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::image::ImageReference;
+
+static SUCCESS: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("probe_success", "Whether the last self-test probe pulled its image all the way through (1) or not (0)").unwrap());
+static LAST_SUCCESS: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("probe_last_success_timestamp_seconds", "When the self-test probe last succeeded, as a Unix timestamp").unwrap());
+static FAILURES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("probe_failures", "Number of self-test probes that failed, by the stage they failed at", &["stage"]).unwrap());
+static DURATION: Lazy<HistogramVec> = Lazy::new(|| register_histogram_vec!("probe_duration_seconds", "Time taken by each stage of the self-test probe", &["stage"]).unwrap());
+
+/// The parts of a pull a probe goes through, in order.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Stage {
+	/// Fetching the manifest from upstream, and writing it to storage
+	Upstream,
+	/// Reading the manifest back from storage
+	Storage,
+	/// Pulling a blob the manifest refers to
+	Blob
+}
+
+impl Stage {
+	fn as_str(self) -> &'static str {
+		match self {
+			Self::Upstream => "upstream",
+			Self::Storage => "storage",
+			Self::Blob => "blob"
+		}
+	}
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Probe failed at the {} stage: {error}", stage.as_str())]
+pub(super) struct Failure {
+	pub(super) stage: Stage,
+	error: Error
+}
+
+/// Runs one stage of a probe, timing it.
+async fn stage<T>(stage: Stage, f: impl Future<Output = Result<T, Error>>) -> Result<T, Failure> {
+	let start = Instant::now();
+	let result = f.await;
+	DURATION.with_label_values(&[stage.as_str()]).observe(start.elapsed().as_secs_f64());
+	result.map_err(|error| Failure { stage, error })
+}
+
+fn manifest_request(image: &Pin, reference: &str) -> Result<ManifestRequest, Error> {
+	let name = ImageName::from_str(image.image()).map_err(|_| Error::NameUnknown)?;
+	Ok(ManifestRequest { image: name, reference: ImageReference::from_str(reference).map_err(|_| Error::ManifestUnknown)? })
+}
+
+async fn read_body(response: HttpResponse) -> Result<Bytes, Error> {
+	body::to_bytes(response.into_body()).await.map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::Other, e.to_string())))
+}
+
+/// Pulls `image` through the cache once, from upstream.
+pub(super) async fn probe(config: &web::Data<RequestConfig>, image: &Pin) -> Result<(), Failure> {
+	let req = manifest_request(image, image.reference()).map_err(|error| Failure { stage: Stage::Upstream, error })?;
+	let storage_path = req.storage_path(image.namespace(), &Access::Shared);
+	// Otherwise it's served from cache, without upstream being asked
+	config.repo.delete_manifest(&storage_path).await.map_err(|error| Failure { stage: Stage::Storage, error: error.into() })?;
+
+	let manifest = stage(Stage::Upstream, async { read_body(serve_manifest(config, &req, Some(image.namespace()), None).await?).await }).await?;
+	stage(Stage::Storage, async {
+		let (_, stream) = config.repo.read_manifest(&storage_path, Duration::MAX).await?;
+		let stored = stream.into_inner().try_collect::<BytesMut>().await?;
+		match stored[..] == manifest[..] {
+			true => Ok(()),
+			false => Err(Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, "Manifest read back from storage isn't the one fetched from upstream")))
+		}
+	})
+	.await?;
+	stage(Stage::Blob, async {
+		let mut references: References = serde_json::from_slice(&manifest)?;
+		// Of an index, the first platform's image
+		if let Some(platform) = references.manifests.first() {
+			let req = manifest_request(image, &platform.digest)?;
+			references = serde_json::from_slice(&read_body(serve_manifest(config, &req, Some(image.namespace()), None).await?).await?)?;
+		}
+		let Some(blob) = references.config.or_else(|| references.layers.into_iter().next()) else {
+			return Ok(());
+		};
+		let name = ImageName::from_str(image.image()).map_err(|_| Error::NameUnknown)?;
+		drain(serve_blob(config.clone(), BlobRequest { image: name, digest: blob.digest }, Some(image.namespace()), None).await?).await
+	})
+	.await
+}
+
+/// Probes `image` every `interval`, starting right away, until the task is dropped.  Has to be
+/// spawned on the actix runtime, as pulling blobs through the cache spawns tasks of its own there.
+pub async fn run(config: web::Data<RequestConfig>, image: Pin, interval: Duration) {
+	let mut interval = tokio::time::interval(interval);
+	interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	loop {
+		interval.tick().await;
+		let start = Instant::now();
+		match probe(&config, &image).await {
+			Ok(()) => {
+				SUCCESS.set(1);
+				LAST_SUCCESS.set(OffsetDateTime::now_utc().unix_timestamp());
+				debug!(%image, elapsed = ?start.elapsed(), "Probe succeeded");
+			},
+			Err(failure) => {
+				SUCCESS.set(0);
+				FAILURES.with_label_values(&[failure.stage.as_str()]).inc();
+				warn!(%image, stage = failure.stage.as_str(), error = %failure.error, "Probe failed");
+			}
+		};
+	}
+}
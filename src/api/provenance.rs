@@ -0,0 +1,162 @@
+//! Provenance:  with `--record-provenance`, each manifest and blob cached from upstream is stamped,
+//! alongside it in storage, with when it was fetched, by which version of `oci-registry`, and from
+//! where, so that after an upstream compromise it can be worked out exactly which cached bytes came
+//! from it and when.  Records are served through `/_admin/provenance` and included in `inspect`'s
+//! reports, and go when their objects do:  with admin purges right away, and with cleanup once the
+//! objects have aged out.
+
+use std::iter;
+
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::info;
+use tracing::warn;
+
+use super::error::Error;
+use super::split_image;
+use super::BlobRequest;
+use super::ManifestQueryString;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::storage::pacing::Pacer;
+use crate::storage::trash_path;
+use crate::storage::Repository;
+use crate::upstream::Client;
+
+const INDEX_PREFIX: &str = "provenance/";
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Provenance {
+	/// When it was fetched, in RFC 3339
+	pub fetched: String,
+	/// The version of `oci-registry` that fetched it
+	pub version: String,
+	/// The namespace it was fetched for
+	pub namespace: String,
+	/// The upstream it was fetched from, by URL
+	pub upstream: String,
+	/// The image upstream knows it by
+	pub upstream_image: String,
+	/// What upstream said a manifest's digest was, before it was converted or rewritten, if it said
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub upstream_digest: Option<String>,
+	/// For foreign layers, the URLs they were fetched from instead of upstream
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub foreign_urls: Vec<String>
+}
+
+impl Provenance {
+	/// Stamped as fetched from `upstream` just now.
+	pub(super) fn new(upstream: &Client, upstream_image: &str, upstream_digest: Option<&str>) -> Self {
+		Self {
+			fetched: OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+			version: env!("CARGO_PKG_VERSION").to_owned(),
+			namespace: upstream.namespace.to_string(),
+			upstream: upstream.base_url.to_string(),
+			upstream_image: upstream_image.to_owned(),
+			upstream_digest: upstream_digest.map(str::to_owned),
+			foreign_urls: Vec::new()
+		}
+	}
+}
+
+/// Where the provenance of the object at `object` is kept.
+fn index_path(object: &str) -> String {
+	format!("{INDEX_PREFIX}{object}")
+}
+
+/// Records where the object just written to `object` came from.  Only fails in the logs.
+pub(super) async fn record(repo: &Repository, object: &str, provenance: &Provenance) {
+	let body = match serde_json::to_vec(provenance) {
+		Ok(v) => Bytes::from(v),
+		Err(_) => return
+	};
+	let path = index_path(object);
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	if let Err(error) = repo.write(&path, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body))), len).await {
+		warn!(path, %error, "Failed to record provenance");
+	}
+}
+
+/// The provenance recorded for the object at `object`, if any was.
+pub async fn read(repo: &Repository, object: &str) -> Result<Option<Provenance>, crate::storage::Error> {
+	let body = match repo.read(&index_path(object), core::time::Duration::MAX).await {
+		Ok(stream) => stream.into_inner().try_collect::<BytesMut>().await?,
+		Err(e) if e.is_not_found() => return Ok(None),
+		Err(e) => return Err(e)
+	};
+	Ok(serde_json::from_slice(&body).ok())
+}
+
+/// Drops the provenance of an object purged for good.
+pub(super) async fn forget(repo: &Repository, object: &str) {
+	match repo.delete(&index_path(object)).await {
+		Ok(()) => (),
+		Err(e) if e.is_not_found() => (),
+		Err(error) => warn!(object, %error, "Failed to delete provenance of purged object")
+	};
+}
+
+/// Deletes the provenance of objects that are no longer cached; returns how many were deleted.
+pub async fn delete_orphaned(repo: &Repository, pacer: &mut Pacer) -> Result<usize, crate::storage::Error> {
+	let mut count = 0;
+	for path in repo.list(INDEX_PREFIX).await? {
+		let Some(object) = path.strip_prefix(INDEX_PREFIX) else {
+			continue;
+		};
+		// Purges into the trash can still be restored, provenance and all
+		let mut cached = false;
+		for candidate in [object.to_owned(), trash_path(object)] {
+			match repo.stat(&candidate, core::time::Duration::MAX).await {
+				Err(e) if e.is_not_found() => (),
+				_ => cached = true
+			};
+		}
+		if (cached) {
+			continue;
+		}
+		pacer.wait().await;
+		if (repo.delete(&path).await.is_ok()) {
+			info!(object, "Deleted provenance of aged out object");
+			count += 1;
+		}
+	}
+	Ok(count)
+}
+
+async fn respond(config: &RequestConfig, object: &str) -> Result<HttpResponse, Error> {
+	match read(&config.repo, object).await? {
+		Some(provenance) => Ok(HttpResponse::Ok().json(provenance)),
+		None => Ok(HttpResponse::NotFound().finish())
+	}
+}
+
+/// Where a manifest in the caller's cache came from.
+pub async fn manifest(http_req: HttpRequest, req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let (namespace, _) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns(None));
+	respond(&config, &req.storage_path(namespace, &config.admin_access(&http_req)?)).await
+}
+
+/// Where a blob in the caller's cache came from.
+pub async fn blob(http_req: HttpRequest, req: web::Path<BlobRequest>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	respond(&config, &req.storage_path(&config.admin_access(&http_req)?)).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn paths() {
+		assert_eq!(index_path("manifests/docker.io/library/alpine/3.19"), "provenance/manifests/docker.io/library/alpine/3.19");
+		assert_eq!(index_path("blobs/sha256/ab/abcd"), "provenance/blobs/sha256/ab/abcd");
+	}
+}
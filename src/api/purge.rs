@@ -0,0 +1,96 @@
+//! `purge`:  deletes cached manifests in bulk, by repository and tag glob and by age, straight from
+//! storage, with the paths the server stores them under, rather than by hand in the bucket.  Like
+//! `DELETE` on the admin API, they go into the trash unless the purge is `--permanent`.  Blobs are
+//! shared between repositories, so they're left for cleanup to age out.
+
+use std::time::SystemTime;
+
+use tracing::info;
+use tracing::warn;
+
+use super::manifest_storage_dir;
+use super::mirror::glob_matches;
+use super::Access;
+use crate::command::PurgeConfig;
+use crate::storage::is_sidecar;
+use crate::storage::Error;
+use crate::storage::Repository;
+
+/// How many manifests are looked at in storage at once
+const BATCH_SIZE: usize = 64;
+
+/// The repository and reference of a manifest stored under `prefix`, unless it's another
+/// partition's, whose paths start with `_`.
+fn split<'a>(object: &'a str, prefix: &str) -> Option<(&'a str, &'a str)> {
+	let path = object.strip_prefix(prefix)?;
+	if (path.starts_with('_') || is_sidecar(path)) {
+		return None;
+	}
+	path.rsplit_once('/')
+}
+
+/// Purges what `config` picks out, returning the manifests purged, or with `--dry-run`, those
+/// that would have been.
+pub async fn run(repo: &Repository, config: &PurgeConfig) -> Result<Vec<String>, Error> {
+	let access = match config.tenant.as_ref() {
+		Some(tenant) => Access::Tenant(tenant.clone()),
+		None => Access::Shared
+	};
+	// With no image, that's the directory every one of the namespace's images is under
+	let prefix = format!("{}/", manifest_storage_dir(&config.namespace, "", &access).trim_end_matches('/'));
+	let tag = config.tag.as_deref().unwrap_or("*");
+	let mut candidates = repo.list(&prefix).await?;
+	candidates.retain(|object| split(object, &prefix).is_some_and(|(repository, reference)| glob_matches(config.repo.as_bytes(), repository.as_bytes()) && glob_matches(tag.as_bytes(), reference.as_bytes())));
+
+	if let Some(older_than) = config.older_than.as_ref() {
+		let cutoff = SystemTime::now() - **older_than;
+		let mut old = Vec::new();
+		for batch in candidates.chunks(BATCH_SIZE) {
+			let stats = repo.stat_all(&batch.iter().map(String::as_str).collect::<Vec<_>>(), core::time::Duration::MAX).await;
+			for (object, stat) in batch.iter().zip(stats) {
+				match stat {
+					Ok(stat) if stat.modified() < cutoff => old.push(object.clone()),
+					Ok(_) => (),
+					Err(error) if error.is_not_found() => (),
+					Err(error) => return Err(error)
+				};
+			}
+		}
+		candidates = old;
+	}
+
+	if (config.dry_run) {
+		info!(count = candidates.len(), "Would purge manifests");
+		return Ok(candidates);
+	}
+	let mut purged = Vec::with_capacity(candidates.len());
+	for object in candidates {
+		let result = match config.permanent {
+			true => repo.delete_manifest(&object).await,
+			false => repo.trash_manifest(&object).await
+		};
+		match result {
+			Ok(()) => purged.push(object),
+			// Gone already, say by cleanup
+			Err(error) if error.is_not_found() => (),
+			Err(error) => warn!(object, %error, "Failed to purge manifest")
+		};
+	}
+	info!(count = purged.len(), permanent = config.permanent, "Purged manifests");
+	Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn splits() {
+		let prefix = "manifests/docker.io/";
+		assert_eq!(split("manifests/docker.io/library/alpine/latest", prefix), Some(("library/alpine", "latest")));
+		assert_eq!(split("manifests/docker.io/library/alpine/sha256:abcd", prefix), Some(("library/alpine", "sha256:abcd")));
+		assert_eq!(split("manifests/docker.io/library/alpine/.latest.meta", prefix), None);
+		assert_eq!(split("manifests/docker.io/_tenant/team-a/library/alpine/latest", prefix), None);
+		assert_eq!(split("manifests/gcr.io/distroless/static/latest", prefix), None);
+	}
+}
@@ -0,0 +1,360 @@
+//! Write-through pushes:  blobs and manifests pushed to a namespace with `write_through` set are
+//! forwarded to its upstream with the push credentials, and what upstream accepts is cached on the
+//! way through, so that CI can push to the same endpoint it pulls from.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use actix_web::http;
+use actix_web::http::header;
+use actix_web::http::header::HeaderName;
+use actix_web::http::header::HeaderValue;
+use actix_web::rt;
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use bytes::BytesMut;
+use compact_str::CompactString;
+use futures::stream::StreamExt;
+use serde::Deserialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::sync::mpsc;
+use tracing::error;
+use tracing::info;
+use tracing::Instrument;
+use tracing::Span;
+
+use super::error::Error;
+use super::labels;
+use super::limits;
+use super::manifest_storage_dir;
+use super::mirror::drain;
+use super::referrers;
+use super::sbom;
+use super::serve_blob;
+use super::trace;
+use super::trace::Hop;
+use super::Access;
+use super::BlobRequest;
+use super::ManifestQueryString;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::storage::declared_media_type;
+use crate::storage::replica;
+use crate::storage::ManifestMetadata;
+use crate::upstream::profile;
+use crate::upstream::Client;
+
+#[derive(Debug, Deserialize)]
+pub struct UploadRequest {
+	image: ImageName
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadSessionRequest {
+	image: ImageName,
+	session: String
+}
+
+/// Of an upload's query string, upstream only gets `digest`; a mount from another repository is
+/// left out, so that upstream just starts a regular upload.
+#[derive(Debug, Deserialize)]
+pub struct UploadQueryString {
+	ns: Option<CompactString>,
+	digest: Option<String>
+}
+
+/// The upstream a push is for, the image as it's known there, and whose cache what's pushed goes in.
+struct Target {
+	upstream: Client,
+	access: Access,
+	/// What to send upstream as the parent of its work on the push, if the client's was traced
+	hop: Hop,
+	namespace: CompactString,
+	image: String,
+	upstream_image: String
+}
+
+impl Target {
+	async fn resolve(config: &RequestConfig, image: &ImageName, ns: Option<&str>, http_req: &HttpRequest) -> Result<Self, Error> {
+		let (namespace, image) = config.route(ns, image.as_ref(), Some(http_req))?;
+		let upstream = config.upstream.lock().await.get(namespace)?.clone();
+		if (!upstream.write_through) {
+			return Err(Error::PushDisabled);
+		}
+		let access = match config.tenants.as_deref() {
+			Some(tenants) => Access::Tenant(tenants.admit(http_req, namespace)?.name().into()),
+			None => Access::Shared
+		};
+		let upstream_image = upstream.upstream_image(image).into_owned();
+		let hop = Hop::of(http_req);
+		Ok(Self { namespace: namespace.into(), image: image.to_owned(), upstream_image, upstream, access, hop })
+	}
+
+	/// The `Authorization` to push with:  a token scoped for pushing to the image, if upstream hands
+	/// those out, or the push credentials as they are if it wants basic auth.
+	async fn authorization(&self) -> Result<Option<HeaderValue>, Error> {
+		let response = trace::inject(self.upstream.http.get(format!("{}/v2/", self.upstream.base_url)), &self.hop).send().await.map_err(Error::Push)?;
+		let Some(challenge) = response.headers().get(header::WWW_AUTHENTICATE).and_then(|v| v.to_str().ok()) else {
+			return Ok(None);
+		};
+		match (profile::bearer_realm(challenge), self.upstream.push_credentials()) {
+			(Some((realm, service)), credentials) => {
+				let mut request = trace::inject(self.upstream.http.get(realm), &self.hop).query(&[("scope", format!("repository:{}:pull,push", self.upstream_image))]);
+				if let Some(service) = service {
+					request = request.query(&[("service", service)]);
+				}
+				if let Some((username, password)) = credentials {
+					request = request.basic_auth(username, Some(password));
+				}
+				let response = request.send().await.and_then(|r| r.error_for_status()).map_err(Error::Push)?;
+				let token = response.json::<profile::Token>().await.map_err(Error::Push)?;
+				let token = token.value().unwrap_or_default();
+				Ok(HeaderValue::from_str(&format!("Bearer {token}")).ok())
+			},
+			(None, Some((username, password))) => Ok(HeaderValue::from_str(&format!("Basic {}", BASE64.encode(format!("{username}:{password}")))).ok()),
+			(None, None) => Ok(None)
+		}
+	}
+
+	/// Makes the client's request of upstream instead, at `url`.
+	async fn forward(&self, http_req: &HttpRequest, url: String, digest: Option<&str>, body: Option<reqwest::Body>) -> Result<reqwest::Response, Error> {
+		let mut request = trace::inject(self.upstream.http.request(http_req.method().clone(), url), &self.hop);
+		if let Some(digest) = digest {
+			request = request.query(&[("digest", digest)]);
+		}
+		for name in [header::CONTENT_TYPE, header::CONTENT_LENGTH, header::CONTENT_RANGE] {
+			if let Some(value) = http_req.headers().get(&name) {
+				request = request.header(name, value.clone());
+			}
+		}
+		if let Some(authorization) = self.authorization().await? {
+			request = request.header(header::AUTHORIZATION, authorization);
+		}
+		if let Some(body) = body {
+			request = request.body(body);
+		}
+		request.send().await.map_err(Error::Push)
+	}
+}
+
+/// Streams a request body on to upstream.  Actix's payload can't leave the thread it arrived on,
+/// so it's pumped through a channel.  Past `limit`, the body's cut off with an error, and `over` set.
+fn request_body(mut payload: web::Payload, limit: Option<u64>, over: Arc<AtomicBool>) -> reqwest::Body {
+	let (tx, mut rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(16);
+	rt::spawn(async move {
+		let mut received = 0;
+		while let Some(chunk) = payload.next().await {
+			let chunk = chunk.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
+			if let Ok(chunk) = &chunk {
+				received += chunk.len() as u64;
+			}
+			if (limit.is_some_and(|limit| received > limit)) {
+				over.store(true, Ordering::Relaxed);
+				let _ = tx.send(Err(std::io::Error::new(std::io::ErrorKind::Other, "upload chunk over the size limit"))).await;
+				return;
+			}
+			if (tx.send(chunk).await.is_err()) {
+				return;
+			}
+		}
+	});
+	reqwest::Body::wrap_stream(futures::stream::poll_fn(move |cx| rx.poll_recv(cx)))
+}
+
+/// Forwards an upload request, and its body if it has one, unless the body's over
+/// `--max-upload-chunk-size`; see [`limits`](super::limits).
+async fn forward_upload(config: &RequestConfig, target: &Target, http_req: &HttpRequest, url: String, digest: Option<&str>, payload: Option<web::Payload>) -> Result<reqwest::Response, Error> {
+	let Some(payload) = payload else {
+		return target.forward(http_req, url, digest, None).await;
+	};
+	let limit = config.limits.upload_chunk();
+	if let Some(limit) = limit {
+		limits::check_upload_length(http_req, limit)?;
+	}
+	let over = Arc::new(AtomicBool::new(false));
+	let response = target.forward(http_req, url, digest, Some(request_body(payload, limit, over.clone()))).await;
+	match (over.load(Ordering::Relaxed), limit) {
+		(true, Some(limit)) => Err(limits::upload_chunk_too_large(limit)),
+		_ => response
+	}
+}
+
+/// Upstream's `Location` as an absolute URL; registries mostly send just the path.
+fn absolute_location(base_url: &str, location: &str) -> String {
+	match location.starts_with("http://") || location.starts_with("https://") {
+		true => location.to_owned(),
+		false => format!("{base_url}/{}", location.trim_start_matches('/'))
+	}
+}
+
+/// The session ID we hand out for an upload is upstream's URL for it, so that uploads need no state
+/// of our own, and can carry on through any instance.
+fn session_id(url: &str) -> String {
+	URL_SAFE_NO_PAD.encode(url)
+}
+
+/// Recovers upstream's URL for an upload from its session ID.  The ID comes from the client, so it
+/// has to point at upstream; otherwise it could send us, push credentials and all, anywhere.
+fn session_url(base_url: &str, session: &str) -> Result<String, Error> {
+	let url = URL_SAFE_NO_PAD.decode(session).ok().and_then(|v| String::from_utf8(v).ok()).ok_or(Error::BlobUploadUnknown)?;
+	match url.starts_with(&format!("{base_url}/")) {
+		true => Ok(url),
+		false => Err(Error::BlobUploadUnknown)
+	}
+}
+
+/// A URL for the client to find something at, keeping the namespace it asked for.
+fn local_location(config: &RequestConfig, path: String, ns: Option<&str>) -> String {
+	match ns {
+		Some(ns) => config.absolute_path(&format!("{path}?ns={ns}")),
+		None => config.absolute_path(&path)
+	}
+}
+
+/// Passes upstream's answer on to the client, with `location` in place of upstream's.
+async fn relay(response: reqwest::Response, location: Option<String>) -> Result<HttpResponse, Error> {
+	let mut builder = HttpResponse::build(response.status());
+	for name in [header::CONTENT_TYPE, header::RANGE, HeaderName::from_static("docker-upload-uuid"), HeaderName::from_static("docker-content-digest")] {
+		if let Some(value) = response.headers().get(&name) {
+			builder.insert_header((name, value.clone()));
+		}
+	}
+	if let Some(location) = location {
+		builder.insert_header((header::LOCATION, location));
+	}
+	Ok(builder.body(response.bytes().await.map_err(Error::Push)?))
+}
+
+/// Pulls a blob upstream just accepted into the cache, the way a client pulling it would.  A
+/// tenant's blobs are left for its first pull, so that they count towards its quota like any other.
+fn cache_blob(config: web::Data<RequestConfig>, target: &Target, digest: String) {
+	if (target.access.tenant().is_some()) {
+		return;
+	}
+	let Ok(image) = target.image.parse::<ImageName>() else {
+		return;
+	};
+	let namespace = target.namespace.clone();
+	rt::spawn(
+		async move {
+			let result = match serve_blob(config, BlobRequest { image, digest }, Some(&namespace), None).await {
+				Ok(response) => drain(response).await,
+				Err(e) => Err(e)
+			};
+			if let Err(error) = result {
+				error!(%error, "Failed to cache pushed blob");
+			}
+		}
+		.instrument(Span::current())
+	);
+}
+
+/// Relays upstream's answer to an upload request.  Uploads still going get a `Location` with
+/// upstream's URL for them as the session ID; finished ones point at the blob, which is then pulled
+/// into the cache.
+async fn upload_response(config: web::Data<RequestConfig>, target: Target, image: &ImageName, qstr: &UploadQueryString, response: reqwest::Response) -> Result<HttpResponse, Error> {
+	let get = |name: &str| response.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_owned);
+	let location = match (response.status(), get("docker-content-digest").or_else(|| qstr.digest.clone())) {
+		(http::StatusCode::CREATED, Some(digest)) => {
+			let location = local_location(&config, format!("/v2/{image}/blobs/{digest}"), qstr.ns.as_deref());
+			cache_blob(config, &target, digest);
+			Some(location)
+		},
+		_ => get("location").map(|url| {
+			let url = absolute_location(target.upstream.origin(), &url);
+			local_location(&config, format!("/v2/{image}/blobs/uploads/{}", session_id(&url)), qstr.ns.as_deref())
+		})
+	};
+	relay(response, location).await
+}
+
+/// Starts an upload upstream, or with `digest`, uploads a whole blob in one go.
+pub async fn start_upload(http_req: HttpRequest, req: web::Path<UploadRequest>, qstr: web::Query<UploadQueryString>, config: web::Data<RequestConfig>, payload: web::Payload) -> Result<HttpResponse, Error> {
+	let target = Target::resolve(&config, &req.image, qstr.ns.as_deref(), &http_req).await?;
+	let url = format!("{}/v2/{}/blobs/uploads/", target.upstream.base_url, target.upstream_image);
+	let payload = qstr.digest.is_some().then_some(payload);
+	let response = forward_upload(&config, &target, &http_req, url, qstr.digest.as_deref(), payload).await?;
+	upload_response(config, target, &req.image, &qstr, response).await
+}
+
+/// Carries on with an upload upstream:  a `PATCH` with more of the blob, the `PUT` that finishes
+/// it, a `GET` for how far along it is, or a `DELETE` to give up on it.
+pub async fn upload(http_req: HttpRequest, req: web::Path<UploadSessionRequest>, qstr: web::Query<UploadQueryString>, config: web::Data<RequestConfig>, payload: web::Payload) -> Result<HttpResponse, Error> {
+	let target = Target::resolve(&config, &req.image, qstr.ns.as_deref(), &http_req).await?;
+	let url = session_url(&target.upstream.base_url, &req.session)?;
+	let payload = (http_req.method() == http::Method::PATCH || http_req.method() == http::Method::PUT).then_some(payload);
+	let response = forward_upload(&config, &target, &http_req, url, qstr.digest.as_deref(), payload).await?;
+	upload_response(config, target, &req.image, &qstr, response).await
+}
+
+/// Pushes a manifest upstream, and once upstream has accepted it, caches it under the reference it
+/// was pushed as.
+pub async fn put_manifest(http_req: HttpRequest, req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>, mut payload: web::Payload) -> Result<HttpResponse, Error> {
+	let target = Target::resolve(&config, &req.image, qstr.ns.as_deref(), &http_req).await?;
+	let mut body = BytesMut::new();
+	while let Some(chunk) = payload.next().await {
+		let chunk = chunk?;
+		if (body.len() + chunk.len() > config.max_manifest_size) {
+			return Err(Error::PushedManifestTooLarge { limit: config.max_manifest_size });
+		}
+		body.extend_from_slice(&chunk);
+	}
+	let body = body.freeze();
+	let url = format!("{}/v2/{}/manifests/{}", target.upstream.base_url, target.upstream_image, req.reference);
+	let response = target.forward(&http_req, url, None, Some(body.clone().into())).await?;
+	if (response.status() != http::StatusCode::CREATED) {
+		return relay(response, None).await;
+	}
+
+	let digest = response.headers().get("docker-content-digest").and_then(|v| v.to_str().ok()).map(str::to_owned);
+	let digest = digest.unwrap_or_else(|| format!("sha256:{}", hex::encode(Sha256::digest(&body))));
+	let content_type = http_req.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+	let media_type = match (declared_media_type(body.as_ref()), content_type) {
+		(Some(v), _) => v.into_owned(),
+		(None, Some(v)) => v.to_owned(),
+		(None, None) => "application/json".to_owned()
+	};
+	let storage_path = req.storage_path(&target.namespace, &target.access);
+	let metadata = ManifestMetadata::new(media_type, Some(digest.clone()));
+	match config.repo.write_manifest(&storage_path, body.clone(), &metadata).await {
+		Ok(()) => {
+			info!(storage_path, "Cached pushed manifest");
+			config.replicate(&storage_path, replica::Kind::Manifest);
+			let manifest_dir = manifest_storage_dir(&target.namespace, req.image.as_ref(), &target.access);
+			referrers::record(&config.repo, &manifest_dir, &body, &metadata.media_type).await;
+			labels::record(&config.repo, &manifest_dir, &target.namespace, &target.image, &req.reference.to_str(), &body, &target.access).await;
+			sbom::record(&config.repo, &manifest_dir, &target.namespace, &target.image, &body, &target.access).await;
+		},
+		Err(error) => error!(storage_path, %error, "Failed to write pushed manifest to storage")
+	};
+	let location = local_location(&config, format!("/v2/{}/manifests/{digest}", req.image), qstr.ns.as_deref());
+	relay(response, Some(location)).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sessions() {
+		let base_url = "https://registry.example.com";
+		let url = absolute_location(base_url, "/v2/library/app/blobs/uploads/0b6e?_state=abc");
+		assert_eq!(url, "https://registry.example.com/v2/library/app/blobs/uploads/0b6e?_state=abc");
+		assert_eq!(session_url(base_url, &session_id(&url)).unwrap(), url);
+		assert_eq!(absolute_location(base_url, "https://uploads.example.com/v2/x"), "https://uploads.example.com/v2/x");
+
+		// Session IDs that don't decode to somewhere on upstream aren't followed
+		for url in ["https://registry.example.com.evil.com/v2/", "http://registry.example.com/v2/", "https://registry.example.com"] {
+			assert!(matches!(session_url(base_url, &session_id(url)), Err(Error::BlobUploadUnknown)), "{url}");
+		}
+		assert!(matches!(session_url(base_url, "not base64!"), Err(Error::BlobUploadUnknown)));
+	}
+}
@@ -0,0 +1,68 @@
+//! `Range` requests for cached blobs, so that a client can resume an interrupted download, or fetch
+//! a large blob in parallel pieces, without the whole blob being read again.  Only a single range
+//! is served; a header asking for several, or that can't be understood, gets the whole blob, as
+//! RFC 9110 allows.
+
+use core::ops::Range;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Requested {
+	Whole,
+	Part(Range<u64>),
+	/// Starts past the end of the blob
+	Unsatisfiable
+}
+
+/// What part of a blob of `length` bytes a `Range` header asks for.
+pub fn requested(header: Option<&str>, length: u64) -> Requested {
+	let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+		return Requested::Whole;
+	};
+	let Some((start, end)) = spec.trim().split_once('-').filter(|_| !spec.contains(',')) else {
+		return Requested::Whole;
+	};
+	let range = match (start.trim().parse::<u64>(), end.trim()) {
+		(Ok(start), "") => start..length,
+		(Ok(start), end) => match end.parse::<u64>() {
+			Ok(end) if end >= start => start..length.min(end.saturating_add(1)),
+			_ => return Requested::Whole
+		},
+		// The last `n` bytes
+		(Err(_), "") => return Requested::Whole,
+		(Err(_), n) if start.trim().is_empty() => match n.parse::<u64>() {
+			Ok(n) => length.saturating_sub(n)..length,
+			Err(_) => return Requested::Whole
+		},
+		(Err(_), _) => return Requested::Whole
+	};
+	match range.start < range.end {
+		true => Requested::Part(range),
+		false => Requested::Unsatisfiable
+	}
+}
+
+/// The `Content-Range` of a part of a blob of `length` bytes.
+pub fn content_range(range: &Range<u64>, length: u64) -> String {
+	format!("bytes {}-{}/{length}", range.start, range.end - 1)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ranges() {
+		assert_eq!(requested(None, 100), Requested::Whole);
+		assert_eq!(requested(Some("bytes=0-9"), 100), Requested::Part(0..10));
+		assert_eq!(requested(Some("bytes=90-"), 100), Requested::Part(90..100));
+		assert_eq!(requested(Some("bytes=90-200"), 100), Requested::Part(90..100));
+		assert_eq!(requested(Some("bytes=-10"), 100), Requested::Part(90..100));
+		assert_eq!(requested(Some("bytes=-200"), 100), Requested::Part(0..100));
+		assert_eq!(requested(Some("bytes=100-"), 100), Requested::Unsatisfiable);
+		assert_eq!(requested(Some("bytes=-0"), 100), Requested::Unsatisfiable);
+		assert_eq!(requested(Some("bytes=0-9,20-29"), 100), Requested::Whole);
+		assert_eq!(requested(Some("bytes=9-0"), 100), Requested::Whole);
+		assert_eq!(requested(Some("items=0-9"), 100), Requested::Whole);
+		assert_eq!(content_range(&(90..100), 100), "bytes 90-99/100");
+	}
+}
@@ -0,0 +1,89 @@
+//! Tags moved to a manifest that only differs from the one before in its annotations, as when
+//! upstream signs or stamps its indexes again without rebuilding anything:  the tag's digest
+//! changes, but every platform manifest, config and layer it references is the same as before.
+//! Those are all cached by digest, so nothing but the tag's own manifest is fetched again; these
+//! updates are counted, logged, and marked in the tag's history, so that churn from re-signing can
+//! be told apart from new builds.
+
+use core::time::Duration;
+
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use tracing::info;
+
+use super::pins::Descriptor;
+use super::pins::References;
+use super::RequestConfig;
+
+static UPDATES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_annotation_only_updates", "Number of expired tags replaced from upstream with a manifest that only differs in its annotations", &["namespace"]).unwrap());
+
+/// The digests of a manifest's config and layers, or of an index's manifests; `None` for one that
+/// references nothing.
+fn references(manifest: &[u8]) -> Option<(Option<String>, Vec<String>, Vec<String>)> {
+	let references = serde_json::from_slice::<References>(manifest).ok()?;
+	let digests = |descriptors: Vec<Descriptor>| descriptors.into_iter().map(|descriptor| descriptor.digest).collect::<Vec<_>>();
+	let references = (references.config.map(|descriptor| descriptor.digest), digests(references.layers), digests(references.manifests));
+	match references {
+		(None, layers, manifests) if layers.is_empty() && manifests.is_empty() => None,
+		references => Some(references)
+	}
+}
+
+/// Whether `new` is a different manifest from `old` that references exactly what it did.
+fn same_content(old: &[u8], new: &[u8]) -> bool {
+	old != new && references(old).is_some_and(|old| references(new) == Some(old))
+}
+
+/// Checks what's cached at `storage_path` against the manifest that's about to replace it, and
+/// returns whether only its annotations changed.
+pub(super) async fn check(config: &RequestConfig, namespace: &str, storage_path: &str, manifest: &[u8]) -> bool {
+	let Ok((_, body)) = config.repo.read_manifest(storage_path, Duration::MAX).await else {
+		return false;
+	};
+	let Ok(old) = body.into_inner().try_collect::<BytesMut>().await else {
+		return false;
+	};
+	if (!same_content(&old, manifest)) {
+		return false;
+	}
+	UPDATES.with_label_values(&[namespace]).inc();
+	info!(storage_path, "Upstream manifest changed only in its annotations; everything it references is cached already");
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const INDEX: &str = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:aa","size":1,"platform":{"os":"linux","architecture":"amd64"}},{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:bb","size":1,"platform":{"os":"linux","architecture":"arm64"}}]}"#;
+
+	#[test]
+	fn only_annotations_changed() {
+		let signed = INDEX.replacen('{', r#"{"annotations":{"org.opencontainers.image.created":"2024-05-01T00:00:00Z"},"#, 1);
+		assert!(same_content(INDEX.as_bytes(), signed.as_bytes()));
+		let resigned = signed.replace("2024-05-01", "2024-05-02");
+		assert!(same_content(signed.as_bytes(), resigned.as_bytes()));
+		// Nothing changed at all
+		assert!(!same_content(INDEX.as_bytes(), INDEX.as_bytes()));
+		// A platform rebuilt
+		assert!(!same_content(INDEX.as_bytes(), INDEX.replace("sha256:bb", "sha256:cc").as_bytes()));
+		// A platform dropped
+		let dropped = r#"{"schemaVersion":2,"manifests":[{"digest":"sha256:aa","size":1}]}"#;
+		assert!(!same_content(INDEX.as_bytes(), dropped.as_bytes()));
+		assert!(!same_content(b"{}", b"{ }"));
+		assert!(!same_content(b"not json", INDEX.as_bytes()));
+	}
+
+	#[test]
+	fn image_manifests_count_too() {
+		let manifest = r#"{"schemaVersion":2,"config":{"digest":"sha256:cc","size":1},"layers":[{"digest":"sha256:dd","size":1}]}"#;
+		let annotated = manifest.replacen('{', r#"{"annotations":{"signed":"yes"},"#, 1);
+		assert!(same_content(manifest.as_bytes(), annotated.as_bytes()));
+		// The config moved into the layers
+		let swapped = r#"{"schemaVersion":2,"layers":[{"digest":"sha256:cc","size":1},{"digest":"sha256:dd","size":1}]}"#;
+		assert!(!same_content(manifest.as_bytes(), swapped.as_bytes()));
+	}
+}
@@ -0,0 +1,191 @@
+//! Referrers:  manifests cached here that name another manifest as their `subject`, such as
+//! signatures, SBOMs, and attestations, are indexed by that subject as they're cached, so that the
+//! referrers API can answer from the cache with what it holds, whether they were pulled through by
+//! tag or digest, or pushed through to upstream.
+
+use std::iter;
+use std::str::FromStr;
+
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::warn;
+
+use super::error::Error;
+use super::manifest_storage_dir;
+use super::Access;
+use super::ManifestQueryString;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::image::ImageReference;
+use crate::storage::declared_media_type;
+use crate::storage::Repository;
+
+const INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+
+#[derive(Debug, Deserialize)]
+struct Linked {
+	#[serde(default, rename = "artifactType")]
+	artifact_type: Option<String>,
+	#[serde(default)]
+	config: Option<Config>,
+	#[serde(default)]
+	subject: Option<Subject>,
+	#[serde(default)]
+	annotations: Option<Map<String, Value>>
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+	#[serde(rename = "mediaType")]
+	media_type: String
+}
+
+#[derive(Debug, Deserialize)]
+struct Subject {
+	digest: String
+}
+
+/// A referrer, as it's listed in the referrers index.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Descriptor {
+	#[serde(rename = "mediaType")]
+	media_type: String,
+	digest: String,
+	size: u64,
+	#[serde(rename = "artifactType", skip_serializing_if = "Option::is_none")]
+	artifact_type: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	annotations: Option<Map<String, Value>>
+}
+
+#[derive(Debug, Serialize)]
+struct Index {
+	#[serde(rename = "schemaVersion")]
+	schema_version: u8,
+	#[serde(rename = "mediaType")]
+	media_type: &'static str,
+	manifests: Vec<Descriptor>
+}
+
+/// The subject a manifest refers to, and how it's listed among the subject's referrers.  `None`
+/// for manifests without a subject, or with one that isn't a digest, which would otherwise end up
+/// in the path it's indexed under.
+pub fn referrer(manifest: &[u8], media_type: &str) -> Option<(String, Descriptor)> {
+	let linked: Linked = serde_json::from_slice(manifest).ok()?;
+	let subject = linked.subject.filter(|s| matches!(ImageReference::from_str(&s.digest), Ok(ImageReference::Sha256(_))))?;
+	let descriptor = Descriptor {
+		media_type: declared_media_type(manifest).map_or_else(|| media_type.to_owned(), |m| m.into_owned()),
+		digest: format!("sha256:{}", hex::encode(Sha256::digest(manifest))),
+		size: manifest.len() as u64,
+		// Image manifests without an artifact type are typed by their config, per the spec
+		artifact_type: linked.artifact_type.or(linked.config.map(|c| c.media_type)),
+		annotations: linked.annotations
+	};
+	Some((subject.digest, descriptor))
+}
+
+/// Where an image's referrers are indexed, given where its manifests are stored.
+fn index_dir(manifest_dir: &str, subject: &str) -> String {
+	let dir = manifest_dir.strip_prefix("manifests/").unwrap_or(manifest_dir);
+	format!("referrers/{dir}/{subject}")
+}
+
+/// Adds a manifest that's just been cached to its subject's referrers, if it has a subject.  Only
+/// fails in the logs; a missing entry just leaves the referrer out of the list.
+pub async fn record(repo: &Repository, manifest_dir: &str, manifest: &[u8], media_type: &str) {
+	let Some((subject, descriptor)) = referrer(manifest, media_type) else {
+		return;
+	};
+	let path = format!("{}/{}", index_dir(manifest_dir, &subject), descriptor.digest);
+	let body = match serde_json::to_vec(&descriptor) {
+		Ok(v) => Bytes::from(v),
+		Err(_) => return
+	};
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	if let Err(error) = repo.write(&path, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body))), len).await {
+		warn!(path, %error, "Failed to index referrer");
+	}
+}
+
+/// Every cached referrer of `subject`, optionally of just one artifact type.
+pub async fn list(repo: &Repository, manifest_dir: &str, subject: &str, artifact_type: Option<&str>) -> Result<Vec<Descriptor>, Error> {
+	let mut referrers = Vec::new();
+	for path in repo.list(&format!("{}/", index_dir(manifest_dir, subject))).await? {
+		let body = match repo.read(&path, core::time::Duration::MAX).await {
+			Ok(stream) => stream.into_inner().try_collect::<BytesMut>().await?,
+			// Cleaned up since it was listed
+			Err(e) if e.is_not_found() => continue,
+			Err(e) => return Err(e.into())
+		};
+		let descriptor: Descriptor = serde_json::from_slice(&body)?;
+		if (artifact_type.map_or(true, |t| descriptor.artifact_type.as_deref() == Some(t))) {
+			referrers.push(descriptor);
+		}
+	}
+	referrers.sort_by(|a, b| a.digest.cmp(&b.digest));
+	Ok(referrers)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReferrersRequest {
+	image: ImageName,
+	digest: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReferrersQuery {
+	#[serde(rename = "artifactType")]
+	artifact_type: Option<String>
+}
+
+pub async fn referrers(http_req: HttpRequest, req: web::Path<ReferrersRequest>, qstr: web::Query<ManifestQueryString>, query: web::Query<ReferrersQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	if (!req.digest.starts_with("sha256:")) {
+		return Err(Error::InvalidDigest);
+	}
+	let (namespace, image) = config.route(qstr.ns.as_deref(), req.image.as_ref(), Some(&http_req))?;
+	let access = match config.tenants.as_deref() {
+		Some(tenants) => Access::Tenant(tenants.admit(&http_req, namespace)?.name().into()),
+		None => Access::Shared
+	};
+	let artifact_type = query.artifact_type.as_deref();
+	let manifests = list(&config.repo, &manifest_storage_dir(namespace, image, &access), &req.digest, artifact_type).await?;
+	let mut response = HttpResponse::Ok();
+	response.content_type(INDEX_MEDIA_TYPE);
+	if (artifact_type.is_some()) {
+		response.insert_header(("OCI-Filters-Applied", "artifactType"));
+	}
+	Ok(response.json(Index { schema_version: 2, media_type: INDEX_MEDIA_TYPE, manifests }))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn referrers() {
+		let signature = br#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{"mediaType":"application/vnd.dev.cosign.artifact.sig.v1+json","digest":"sha256:aa","size":2},"layers":[],"subject":{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:cccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc","size":3},"annotations":{"org.example":"yes"}}"#;
+		let (subject, descriptor) = referrer(signature, "application/json").unwrap();
+		assert_eq!(subject, format!("sha256:{}", "c".repeat(64)));
+		assert_eq!(descriptor.media_type, "application/vnd.oci.image.manifest.v1+json");
+		assert_eq!(descriptor.artifact_type.as_deref(), Some("application/vnd.dev.cosign.artifact.sig.v1+json"));
+		assert_eq!(descriptor.size, signature.len() as u64);
+		assert!(descriptor.annotations.is_some());
+
+		let sbom = br#"{"mediaType":"application/vnd.oci.artifact.manifest.v1+json","artifactType":"application/spdx+json","blobs":[],"subject":{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:cccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc","size":3}}"#;
+		assert_eq!(referrer(sbom, "application/json").unwrap().1.artifact_type.as_deref(), Some("application/spdx+json"));
+		assert!(referrer(br#"{"schemaVersion":2,"layers":[]}"#, "application/json").is_none());
+		assert!(referrer(br#"{"schemaVersion":2,"layers":[],"subject":{"digest":"../../../pins.json"}}"#, "application/json").is_none());
+
+		assert_eq!(index_dir("manifests/docker.io/library/alpine", "sha256:cc"), "referrers/docker.io/library/alpine/sha256:cc");
+	}
+}
@@ -0,0 +1,39 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderName;
+use actix_web::http::header::HeaderValue;
+
+pub const HEADER: &str = "x-request-id";
+
+/// Identifies a single client request across our logs and the response sent to the client.  If
+/// the client (or a proxy in front of us) sent a reasonable-looking `X-Request-Id`, it is reused
+/// so that the same ID shows up in every hop's logs.
+#[derive(Clone, Debug)]
+pub struct RequestId(HeaderValue);
+
+impl RequestId {
+	pub fn from_request(req: &ServiceRequest) -> Self {
+		let incoming = req.headers().get(HEADER).filter(|v| !v.is_empty() && v.len() <= 128 && v.to_str().is_ok());
+		match incoming {
+			Some(v) => Self(v.clone()),
+			None => Self::generate()
+		}
+	}
+
+	fn generate() -> Self {
+		let id = format!("{:032x}", rand::random::<u128>());
+		Self(HeaderValue::from_str(&id).unwrap())
+	}
+
+	pub fn header_name() -> HeaderName {
+		HeaderName::from_static(HEADER)
+	}
+
+	pub fn as_str(&self) -> &str {
+		// Only constructed from values that passed to_str() or from hex digits
+		self.0.to_str().unwrap_or_default()
+	}
+
+	pub fn header_value(&self) -> HeaderValue {
+		self.0.clone()
+	}
+}
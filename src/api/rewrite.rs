@@ -0,0 +1,248 @@
+//! Manifest rewriting:  rules configured per upstream that change manifests on their way into the
+//! cache, such as stripping foreign layer URLs for air-gapped networks, swapping layers for
+//! transcoded variants, adding annotations, or cutting indexes down to the platforms a deployment
+//! runs on.  A rewritten manifest is stored and served under
+//! its new digest, so only manifests requested by tag can be rewritten; one requested by digest has
+//! to be served as it is.
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+
+use bytes::Bytes;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Map;
+use serde_json::Value;
+use sha2::Digest;
+use sha2::Sha256;
+
+use super::foreign::distributable_media_type;
+use crate::validate::Report;
+
+/// A layer (or config) descriptor, as it appears in an image manifest.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Descriptor {
+	#[serde(rename = "mediaType")]
+	pub media_type: String,
+	pub digest: String,
+	pub size: u64
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "rule", rename_all = "kebab-case")]
+pub enum RewriteRule {
+	/// Drops the URLs of foreign layers and marks them distributable, so that clients ask us for them
+	/// rather than going out to the internet; with `foreign_layers: cache`, we fetch them instead
+	StripForeignUrls,
+	/// Replaces a layer with a variant of it, such as a zstd transcoding, which has to be pullable
+	/// from the same repository
+	ReplaceLayer { digest: String, with: Descriptor },
+	/// Adds annotations to the manifest, replacing any already there with the same keys
+	Annotate { annotations: BTreeMap<String, String> },
+	/// Drops the entries of an index for platforms other than these, given as `os/architecture` or
+	/// `os/architecture/variant`, along with the attestations for them.  The entries kept, and the
+	/// manifests they point at, are unchanged; an index with none of these platforms is left whole
+	FilterPlatforms { platforms: Vec<String> }
+}
+
+fn is_digest(digest: &str) -> bool {
+	digest.strip_prefix("sha256:").is_some_and(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+impl RewriteRule {
+	pub fn validate(&self, namespace: &str, report: &mut Report) {
+		match self {
+			Self::StripForeignUrls => (),
+			Self::ReplaceLayer { digest, with } => {
+				if (!is_digest(digest) || !is_digest(&with.digest)) {
+					report.error(namespace, format!("replace-layer digests have to be sha256:<64 hex digits>, not {digest} and {}", with.digest));
+				} else if (digest == &with.digest) {
+					report.warn(namespace, format!("replace-layer replaces {digest} with itself"));
+				}
+			},
+			Self::Annotate { annotations } if annotations.is_empty() => report.warn(namespace, "annotate rule has no annotations"),
+			Self::Annotate { .. } => (),
+			Self::FilterPlatforms { platforms } if platforms.is_empty() => report.warn(namespace, "filter-platforms rule has no platforms"),
+			Self::FilterPlatforms { platforms } => {
+				for platform in platforms {
+					if (!matches!(platform.split('/').collect::<Vec<_>>().as_slice(), [os, arch] | [os, arch, _] if !os.is_empty() && !arch.is_empty())) {
+						report.error(namespace, format!("filter-platforms platform {platform} isn't os/architecture or os/architecture/variant"));
+					}
+				}
+			}
+		};
+	}
+
+	/// Applies this rule to a parsed manifest, returning whether it changed anything.
+	fn apply(&self, manifest: &mut Map<String, Value>) -> bool {
+		match self {
+			Self::StripForeignUrls => {
+				let mut changed = false;
+				for layer in layers(manifest) {
+					if (layer.remove("urls").is_some()) {
+						changed = true;
+					}
+					if let Some(media_type) = layer.get("mediaType").and_then(Value::as_str).and_then(distributable_media_type) {
+						layer.insert("mediaType".into(), media_type.into());
+						changed = true;
+					}
+				}
+				changed
+			},
+			Self::ReplaceLayer { digest, with } => {
+				let mut changed = false;
+				for layer in layers(manifest) {
+					if (layer.get("digest").and_then(Value::as_str) == Some(digest.as_str())) {
+						// Whatever else the old layer said about itself doesn't hold for the new one
+						layer.clear();
+						layer.insert("mediaType".into(), with.media_type.as_str().into());
+						layer.insert("digest".into(), with.digest.as_str().into());
+						layer.insert("size".into(), with.size.into());
+						changed = true;
+					}
+				}
+				changed
+			},
+			Self::Annotate { annotations } if annotations.is_empty() => false,
+			Self::Annotate { annotations } => {
+				let existing = manifest.entry("annotations").or_insert_with(|| Value::Object(Map::new()));
+				let Some(existing) = existing.as_object_mut() else {
+					return false;
+				};
+				let mut changed = false;
+				for (key, value) in annotations {
+					if (existing.get(key).and_then(Value::as_str) != Some(value.as_str())) {
+						existing.insert(key.clone(), value.as_str().into());
+						changed = true;
+					}
+				}
+				changed
+			},
+			Self::FilterPlatforms { platforms } => {
+				let Some(entries) = manifest.get_mut("manifests").and_then(Value::as_array_mut) else {
+					return false;
+				};
+				// Entries that don't say what they're for are kept
+				let wanted = |entry: &Value| entry.get("platform").map_or(true, |platform| platforms.iter().any(|p| platform_matches(p, platform)));
+				if (!entries.iter().any(|entry| entry.get("platform").is_some() && wanted(entry))) {
+					return false;
+				}
+				let kept = entries.iter().filter(|entry| wanted(entry)).filter_map(|entry| entry.get("digest").and_then(Value::as_str)).map(str::to_owned).collect::<HashSet<_>>();
+				let len = entries.len();
+				// Attestations say they're for an unknown platform, and which entry they describe
+				entries.retain(|entry| wanted(entry) || attested(entry).is_some_and(|digest| kept.contains(digest)));
+				entries.len() != len
+			}
+		}
+	}
+}
+
+/// Whether an index entry's platform is the one `pattern` names; without a variant, it matches any.
+fn platform_matches(pattern: &str, platform: &Value) -> bool {
+	let mut parts = pattern.split('/');
+	let field = |name: &str| platform.get(name).and_then(Value::as_str);
+	parts.next() == field("os") && parts.next() == field("architecture") && parts.next().map_or(true, |variant| Some(variant) == field("variant"))
+}
+
+/// The digest of the entry an attestation manifest's index entry describes, as BuildKit records it.
+fn attested(entry: &Value) -> Option<&str> {
+	let annotations = entry.get("annotations")?;
+	match annotations.get("vnd.docker.reference.type").and_then(Value::as_str) {
+		Some("attestation-manifest") => annotations.get("vnd.docker.reference.digest").and_then(Value::as_str),
+		_ => None
+	}
+}
+
+fn layers(manifest: &mut Map<String, Value>) -> impl Iterator<Item = &mut Map<String, Value>> {
+	manifest.get_mut("layers").and_then(Value::as_array_mut).into_iter().flatten().filter_map(Value::as_object_mut)
+}
+
+/// Runs a manifest through each rule in turn.  Returns the new manifest and its digest, or `None`
+/// if no rule changed it (or it isn't a JSON object, as schema1 manifests with signatures aren't).
+pub fn apply(rules: &[RewriteRule], manifest: &[u8]) -> Option<(Bytes, String)> {
+	if (rules.is_empty()) {
+		return None;
+	}
+	let mut parsed: Map<String, Value> = serde_json::from_slice(manifest).ok()?;
+	let mut changed = false;
+	for rule in rules {
+		changed |= rule.apply(&mut parsed);
+	}
+	if (!changed) {
+		return None;
+	}
+	let body = serde_json::to_vec(&parsed).ok()?;
+	let digest = format!("sha256:{}", hex::encode(Sha256::digest(&body)));
+	Some((body.into(), digest))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const LAYER: &str = "sha256:2222222222222222222222222222222222222222222222222222222222222222";
+	const ZSTD_LAYER: &str = "sha256:3333333333333333333333333333333333333333333333333333333333333333";
+
+	fn manifest() -> String {
+		format!(
+			r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","size":1,"digest":"sha256:11"}},"layers":[{{"mediaType":"application/vnd.oci.image.layer.nondistributable.v1.tar+gzip","size":5,"digest":"sha256:44","urls":["https://example.com/layer"]}},{{"mediaType":"application/vnd.oci.image.layer.v1.tar+gzip","size":2,"digest":"{LAYER}"}}]}}"#
+		)
+	}
+
+	fn rules(yaml: &str) -> Vec<RewriteRule> {
+		serde_yaml::from_str(yaml).unwrap()
+	}
+
+	#[test]
+	fn rewrites() {
+		let rules = rules(&format!(
+			"- rule: strip-foreign-urls\n- rule: replace-layer\n  digest: {LAYER}\n  with:\n    mediaType: application/vnd.oci.image.layer.v1.tar+zstd\n    digest: {ZSTD_LAYER}\n    size: 1\n- rule: annotate\n  annotations:\n    org.example.cached-by: oci-registry"
+		));
+		let (body, digest) = apply(&rules, manifest().as_bytes()).unwrap();
+		assert_eq!(digest, format!("sha256:{}", hex::encode(Sha256::digest(&body))));
+		let parsed: Value = serde_json::from_slice(&body).unwrap();
+		assert_eq!(parsed["layers"][0], serde_json::json!({ "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "size": 5, "digest": "sha256:44" }));
+		assert_eq!(parsed["layers"][1], serde_json::json!({ "mediaType": "application/vnd.oci.image.layer.v1.tar+zstd", "size": 1, "digest": ZSTD_LAYER }));
+		assert_eq!(parsed["annotations"]["org.example.cached-by"], "oci-registry");
+
+		// Applying the same rules again changes nothing, so the digest is stable
+		assert_eq!(apply(&rules, &body), None);
+		assert_eq!(apply(&[], manifest().as_bytes()), None);
+	}
+
+	#[test]
+	fn platforms() {
+		let index = serde_json::json!({
+			"schemaVersion": 2,
+			"mediaType": "application/vnd.oci.image.index.v1+json",
+			"manifests": [
+				{ "mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:aa", "size": 1, "platform": { "os": "linux", "architecture": "amd64" } },
+				{ "mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:bb", "size": 1, "platform": { "os": "linux", "architecture": "arm", "variant": "v7" } },
+				{ "mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:cc", "size": 1, "platform": { "os": "linux", "architecture": "arm64", "variant": "v8" } },
+				{ "mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:dd", "size": 1, "platform": { "os": "unknown", "architecture": "unknown" }, "annotations": { "vnd.docker.reference.type": "attestation-manifest", "vnd.docker.reference.digest": "sha256:aa" } },
+				{ "mediaType": "application/vnd.oci.image.manifest.v1+json", "digest": "sha256:ee", "size": 1, "platform": { "os": "unknown", "architecture": "unknown" }, "annotations": { "vnd.docker.reference.type": "attestation-manifest", "vnd.docker.reference.digest": "sha256:bb" } }
+			]
+		});
+		let kept = |yaml: &str| {
+			apply(&rules(yaml), index.to_string().as_bytes()).map(|(body, _)| {
+				let parsed: Value = serde_json::from_slice(&body).unwrap();
+				parsed["manifests"].as_array().unwrap().iter().map(|entry| entry["digest"].as_str().unwrap().to_owned()).collect::<Vec<_>>()
+			})
+		};
+		assert_eq!(kept("- rule: filter-platforms\n  platforms: [linux/amd64, linux/arm64]").unwrap(), ["sha256:aa", "sha256:cc", "sha256:dd"]);
+		assert_eq!(kept("- rule: filter-platforms\n  platforms: [linux/arm/v7]").unwrap(), ["sha256:bb", "sha256:ee"]);
+		// None of the platforms, or not an index:  nothing to do
+		assert_eq!(kept("- rule: filter-platforms\n  platforms: [linux/arm/v6, windows/amd64]"), None);
+		assert_eq!(apply(&rules("- rule: filter-platforms\n  platforms: [linux/amd64]"), manifest().as_bytes()), None);
+	}
+
+	#[test]
+	fn validation() {
+		let mut report = Report::default();
+		for rule in rules(&format!("- rule: replace-layer\n  digest: sha256:22\n  with: {{mediaType: x, digest: {ZSTD_LAYER}, size: 1}}\n- rule: annotate\n  annotations: {{}}\n- rule: filter-platforms\n  platforms: [linux]")) {
+			rule.validate("docker.io", &mut report);
+		}
+		assert!(report.has_errors());
+		assert_eq!(report.problems().len(), 3);
+	}
+}
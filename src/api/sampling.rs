@@ -0,0 +1,177 @@
+//! Request sampling, with `--request-sample-file`:  a fraction of the pulls that come in are
+//! appended to a file, one JSON line each, with when they came in, what they asked for, and how
+//! they went.  Nothing else is kept:  no headers, no client addresses, and no query parameters but
+//! `ns`.  `bench --replay` sends the same requests again, on the same schedule, so that load tests
+//! see the mix of manifests, blobs, and tag lists real clients ask for.
+
+use std::path::PathBuf;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use actix_web::body::BodySize;
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceRequest;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::Method;
+use clap::Parser;
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+static SAMPLER: OnceCell<Sampler> = OnceCell::new();
+
+static SAMPLES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("request_samples", "Number of requests sampled for --request-sample-file, by whether they were written", &["result"]).unwrap());
+
+#[derive(Clone, Debug, Parser)]
+pub struct SamplingConfig {
+	/// File to append a sample of pulls to, one JSON line each, for `bench --replay` to send again;
+	/// only paths and timings are kept
+	#[clap(env, long)]
+	pub request_sample_file: Option<PathBuf>,
+	/// Fraction of pulls, between 0 and 1, that are sampled
+	#[clap(env, long, default_value_t = 0.01)]
+	pub request_sample_rate: f64
+}
+
+/// One sampled request, as a line of the sample file.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Sample {
+	/// When the request came in, in milliseconds since the Unix epoch
+	pub at_ms: u64,
+	pub method: String,
+	/// The path, with `ns` the only query parameter kept
+	pub path: String,
+	pub status: u16,
+	/// How long it took for the response to start
+	pub duration_ms: u64,
+	/// The response body's length, where it was known up front
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub bytes: Option<u64>
+}
+
+struct Sampler {
+	tx: mpsc::Sender<Sample>,
+	rate: f64
+}
+
+/// A sampled request whose response hasn't started yet.
+pub(super) struct Pending {
+	at: SystemTime,
+	start: Instant,
+	method: String,
+	path: String
+}
+
+/// Starts appending samples to the configured file, if there is one.  Only the first call has any
+/// effect.
+pub fn init(config: &SamplingConfig) {
+	let Some(path) = config.request_sample_file.clone() else {
+		return;
+	};
+	let (tx, rx) = mpsc::channel(1024);
+	if (SAMPLER.set(Sampler { tx, rate: config.request_sample_rate }).is_err()) {
+		return;
+	}
+	tokio::spawn(write(path, rx));
+}
+
+/// `path` with `ns` the only query parameter left of `query`.
+fn replayable_path(path: &str, query: &str) -> String {
+	match query.split('&').find(|parameter| parameter.starts_with("ns=")) {
+		Some(ns) => format!("{path}?{ns}"),
+		None => path.to_owned()
+	}
+}
+
+/// Picks pulls to sample, at the configured rate.
+pub(super) fn start(req: &ServiceRequest) -> Option<Pending> {
+	let sampler = SAMPLER.get()?;
+	if (req.method() != Method::GET && req.method() != Method::HEAD) {
+		return None;
+	}
+	if (rand::random::<f64>() >= sampler.rate) {
+		return None;
+	}
+	Some(Pending { at: SystemTime::now(), start: Instant::now(), method: req.method().to_string(), path: replayable_path(req.path(), req.query_string()) })
+}
+
+/// Queues a sampled request's line, now that its response has started.  Never waits; if the file
+/// is backed up, the sample is dropped.
+pub(super) fn finish<B: MessageBody>(pending: Option<Pending>, response: &ServiceResponse<B>) {
+	let (Some(pending), Some(sampler)) = (pending, SAMPLER.get()) else {
+		return;
+	};
+	let bytes = match response.response().body().size() {
+		BodySize::Sized(length) => Some(length),
+		_ => None
+	};
+	let sample = Sample {
+		at_ms: pending.at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+		method: pending.method,
+		path: pending.path,
+		status: response.status().as_u16(),
+		duration_ms: pending.start.elapsed().as_millis() as u64,
+		bytes
+	};
+	if (sampler.tx.try_send(sample).is_err()) {
+		SAMPLES.with_label_values(&["dropped"]).inc();
+	}
+}
+
+async fn write(path: PathBuf, mut rx: mpsc::Receiver<Sample>) {
+	let mut file = match OpenOptions::new().create(true).append(true).open(&path).await {
+		Ok(v) => v,
+		Err(error) => {
+			warn!(%error, path = %path.display(), "Failed to open request sample file; not sampling");
+			return;
+		}
+	};
+	while let Some(sample) = rx.recv().await {
+		let mut line = match serde_json::to_vec(&sample) {
+			Ok(v) => v,
+			Err(error) => {
+				warn!(%error, "Failed to serialize request sample");
+				continue;
+			}
+		};
+		line.push(b'\n');
+		match file.write_all(&line).await {
+			Ok(()) => SAMPLES.with_label_values(&["written"]).inc(),
+			Err(error) => {
+				warn!(%error, path = %path.display(), "Failed to write request sample");
+				SAMPLES.with_label_values(&["dropped"]).inc();
+			}
+		};
+		if let Err(error) = file.flush().await {
+			warn!(%error, path = %path.display(), "Failed to flush request sample file");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn only_ns_is_kept() {
+		assert_eq!(replayable_path("/v2/library/redis/manifests/7", ""), "/v2/library/redis/manifests/7");
+		assert_eq!(replayable_path("/v2/library/redis/manifests/7", "ns=docker.io"), "/v2/library/redis/manifests/7?ns=docker.io");
+		assert_eq!(replayable_path("/v2/library/redis/tags/list", "n=10&ns=docker.io&last=6"), "/v2/library/redis/tags/list?ns=docker.io");
+		assert_eq!(replayable_path("/v2/foo/blobs/sha256:abcd", "token=secret"), "/v2/foo/blobs/sha256:abcd");
+	}
+
+	#[test]
+	fn sample_lines() {
+		let sample = Sample { at_ms: 1000, method: "GET".into(), path: "/v2/foo/manifests/latest".into(), status: 200, duration_ms: 12, bytes: None };
+		let line = serde_json::to_string(&sample).unwrap();
+		assert_eq!(line, r#"{"at_ms":1000,"method":"GET","path":"/v2/foo/manifests/latest","status":200,"duration_ms":12}"#);
+		assert_eq!(serde_json::from_str::<Sample>(&line).unwrap(), sample);
+	}
+}
@@ -0,0 +1,316 @@
+//! SBOMs:  SPDX and CycloneDX documents cached alongside images, whether attached as referrers or
+//! as in-toto attestations, are indexed by the packages they list, so that `/_admin/sbom` can say
+//! which cached images contain a given package at a given version.  SBOM manifests are indexed as
+//! they're cached; the documents themselves are read into the index then if they're cached
+//! already, and otherwise the first time a query finds them cached.
+
+use std::iter;
+
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::warn;
+
+use super::blob_storage_path;
+use super::error::Error;
+use super::labels::value_matches;
+use super::Access;
+use super::RequestConfig;
+use crate::storage::Repository;
+
+const INDEX_PREFIX: &str = "sboms/";
+
+/// SBOM documents bigger than this aren't read for their packages.
+const MAX_DOCUMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+const SBOM_MEDIA_TYPES: &[&str] = &["application/spdx+json", "text/spdx+json", "application/vnd.cyclonedx+json"];
+const IN_TOTO_MEDIA_TYPE: &str = "application/vnd.in-toto+json";
+const PREDICATE_TYPE_ANNOTATION: &str = "in-toto.io/predicate-type";
+const SBOM_PREDICATE_TYPES: &[&str] = &["https://spdx.dev/Document", "https://cyclonedx.org/bom"];
+
+#[derive(Debug, Deserialize)]
+struct Parsed {
+	#[serde(default, rename = "artifactType")]
+	artifact_type: Option<String>,
+	#[serde(default)]
+	config: Option<Config>,
+	#[serde(default)]
+	layers: Vec<Layer>,
+	#[serde(default)]
+	subject: Option<Subject>
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+	#[serde(rename = "mediaType")]
+	media_type: String
+}
+
+#[derive(Debug, Deserialize)]
+struct Layer {
+	#[serde(rename = "mediaType")]
+	media_type: String,
+	digest: String,
+	#[serde(default)]
+	annotations: Option<serde_json::Map<String, Value>>
+}
+
+#[derive(Debug, Deserialize)]
+struct Subject {
+	digest: String
+}
+
+impl Layer {
+	fn is_sbom(&self) -> bool {
+		if (SBOM_MEDIA_TYPES.contains(&self.media_type.as_str())) {
+			return true;
+		}
+		let predicate_type = self.annotations.as_ref().and_then(|a| a.get(PREDICATE_TYPE_ANNOTATION)?.as_str());
+		self.media_type == IN_TOTO_MEDIA_TYPE && predicate_type.is_some_and(|t| SBOM_PREDICATE_TYPES.iter().any(|p| t.starts_with(p)))
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct Package {
+	name: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	version: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	purl: Option<String>
+}
+
+impl Package {
+	/// Whether this is the package called `name`, by its own name or the name in its purl.
+	fn is(&self, name: &str) -> bool {
+		let purl_name = self.purl.as_deref().and_then(|p| p.split(['@', '?', '#']).next()?.rsplit('/').next());
+		self.name.eq_ignore_ascii_case(name) || purl_name.is_some_and(|n| n.eq_ignore_ascii_case(name))
+	}
+}
+
+/// An SBOM manifest, as it's kept in the index.
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+	namespace: String,
+	image: String,
+	/// The SBOM manifest's own digest
+	digest: String,
+	/// The image the SBOM describes, once that's known
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	subject: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	tenant: Option<String>,
+	/// Where the documents are stored, for their packages to be read from once they're cached
+	documents: Vec<String>,
+	/// `None` until every document has been read
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	packages: Option<Vec<Package>>
+}
+
+/// An image a query found, as it's listed in the response.
+#[derive(Debug, Serialize)]
+struct Found<'a> {
+	namespace: &'a str,
+	image: &'a str,
+	subject: Option<&'a str>,
+	sbom: &'a str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	tenant: Option<&'a str>,
+	packages: Vec<&'a Package>
+}
+
+/// What an SBOM manifest is indexed as; `None` for manifests that aren't SBOMs.
+fn entry(namespace: &str, image: &str, manifest: &[u8], access: &Access) -> Option<Entry> {
+	let parsed: Parsed = serde_json::from_slice(manifest).ok()?;
+	let artifact_type = parsed.artifact_type.as_deref().or(parsed.config.as_ref().map(|c| c.media_type.as_str()));
+	let is_sbom_artifact = artifact_type.is_some_and(|t| SBOM_MEDIA_TYPES.contains(&t));
+	let documents = parsed.layers.iter().filter(|l| is_sbom_artifact || l.is_sbom()).map(|l| blob_storage_path(&l.digest, access)).collect::<Vec<_>>();
+	if (documents.is_empty()) {
+		return None;
+	}
+	Some(Entry {
+		namespace: namespace.to_owned(),
+		image: image.to_owned(),
+		digest: format!("sha256:{}", hex::encode(Sha256::digest(manifest))),
+		subject: parsed.subject.map(|s| s.digest),
+		tenant: access.tenant().map(str::to_owned),
+		documents,
+		packages: None
+	})
+}
+
+/// The packages an SPDX or CycloneDX document lists, bare or as the predicate of an in-toto
+/// statement, and for a statement, the image it's about.
+fn document_packages(document: &[u8]) -> (Option<String>, Vec<Package>) {
+	let Ok(value) = serde_json::from_slice::<Value>(document) else {
+		return (None, Vec::new());
+	};
+	let (subject, document) = match value.get("predicate") {
+		Some(predicate) => (value["subject"][0]["digest"]["sha256"].as_str().map(|d| format!("sha256:{d}")), predicate),
+		None => (None, &value)
+	};
+	let mut packages = Vec::new();
+	// SPDX
+	for package in document["packages"].as_array().into_iter().flatten() {
+		let purl = package["externalRefs"].as_array().into_iter().flatten().find(|r| r["referenceType"] == "purl").and_then(|r| r["referenceLocator"].as_str());
+		if let Some(name) = package["name"].as_str() {
+			packages.push(Package { name: name.to_owned(), version: package["versionInfo"].as_str().map(str::to_owned), purl: purl.map(str::to_owned) });
+		}
+	}
+	// CycloneDX, whose components can have components of their own
+	let mut components = document["components"].as_array().into_iter().flatten().collect::<Vec<_>>();
+	while let Some(component) = components.pop() {
+		if let Some(name) = component["name"].as_str() {
+			packages.push(Package { name: name.to_owned(), version: component["version"].as_str().map(str::to_owned), purl: component["purl"].as_str().map(str::to_owned) });
+		}
+		components.extend(component["components"].as_array().into_iter().flatten());
+	}
+	(subject, packages)
+}
+
+/// Where an SBOM manifest is indexed, given where its image's manifests are stored.
+fn index_path(manifest_dir: &str, digest: &str) -> String {
+	let dir = manifest_dir.strip_prefix("manifests/").unwrap_or(manifest_dir);
+	format!("{INDEX_PREFIX}{dir}/{digest}")
+}
+
+async fn write_entry(repo: &Repository, path: &str, entry: &Entry) {
+	let body = match serde_json::to_vec(entry) {
+		Ok(v) => Bytes::from(v),
+		Err(_) => return
+	};
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	if let Err(error) = repo.write(path, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body))), len).await {
+		warn!(path, %error, "Failed to index SBOM");
+	}
+}
+
+/// Reads an entry's documents into it, if they're all cached now.  Returns whether it did.
+async fn fill_packages(repo: &Repository, entry: &mut Entry) -> Result<bool, Error> {
+	if (entry.packages.is_some()) {
+		return Ok(false);
+	}
+	let mut packages = Vec::new();
+	for path in &entry.documents {
+		let stream = match repo.read(path, core::time::Duration::MAX).await {
+			Ok(stream) => stream,
+			Err(e) if e.is_not_found() => return Ok(false),
+			Err(e) => return Err(e.into())
+		};
+		if (stream.length() > MAX_DOCUMENT_SIZE) {
+			warn!(path, size = stream.length(), "SBOM document too large to index");
+			continue;
+		}
+		let (subject, found) = document_packages(&stream.into_inner().try_collect::<BytesMut>().await?);
+		entry.subject = entry.subject.take().or(subject);
+		packages.extend(found);
+	}
+	packages.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+	packages.dedup();
+	entry.packages = Some(packages);
+	Ok(true)
+}
+
+/// Indexes a manifest that's just been cached, if it's an SBOM.  Manifests cached for pass-through
+/// credentials are private to them, and left out.  Only fails in the logs; a missing entry just
+/// leaves the image out of queries.
+pub async fn record(repo: &Repository, manifest_dir: &str, namespace: &str, image: &str, manifest: &[u8], access: &Access) {
+	if (matches!(access, Access::Private(_))) {
+		return;
+	}
+	let Some(mut entry) = entry(namespace, image, manifest, access) else {
+		return;
+	};
+	// Pushed SBOMs, and those whose documents were pulled before, can be read right away
+	if let Err(error) = fill_packages(repo, &mut entry).await {
+		warn!(digest = entry.digest, %error, "Failed to read SBOM documents");
+	}
+	write_entry(repo, &index_path(manifest_dir, &entry.digest), &entry).await;
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SbomQuery {
+	package: String,
+	version: Option<String>,
+	namespace: Option<String>
+}
+
+/// Lists the cached images whose SBOMs list the package asked for, in the caller's cache.
+pub async fn query(http_req: HttpRequest, query: web::Query<SbomQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let access = config.admin_access(&http_req)?;
+	let mut entries = Vec::new();
+	for path in config.repo.list(INDEX_PREFIX).await? {
+		let body = match config.repo.read(&path, core::time::Duration::MAX).await {
+			Ok(stream) => stream.into_inner().try_collect::<BytesMut>().await?,
+			// Cleaned up since it was listed
+			Err(e) if e.is_not_found() => continue,
+			Err(e) => return Err(e.into())
+		};
+		let mut entry: Entry = match serde_json::from_slice(&body) {
+			Ok(v) => v,
+			Err(error) => {
+				warn!(path, %error, "Skipping invalid SBOM index entry");
+				continue;
+			}
+		};
+		if (query.namespace.as_deref().is_some_and(|ns| ns != entry.namespace) || entry.tenant.as_deref() != access.tenant()) {
+			continue;
+		}
+		if (fill_packages(&config.repo, &mut entry).await?) {
+			write_entry(&config.repo, &path, &entry).await;
+		}
+		entries.push(entry);
+	}
+	entries.sort_by(|a, b| (&a.namespace, &a.image, &a.subject).cmp(&(&b.namespace, &b.image, &b.subject)));
+	let found = entries
+		.iter()
+		.filter_map(|e| {
+			let packages = e
+				.packages
+				.iter()
+				.flatten()
+				.filter(|p| p.is(&query.package) && query.version.as_deref().map_or(true, |v| p.version.as_deref().is_some_and(|version| value_matches(version, v))))
+				.collect::<Vec<_>>();
+			match packages.is_empty() {
+				true => None,
+				false => Some(Found { namespace: &e.namespace, image: &e.image, subject: e.subject.as_deref(), sbom: &e.digest, tenant: e.tenant.as_deref(), packages })
+			}
+		})
+		.collect::<Vec<_>>();
+	Ok(HttpResponse::Ok().json(found))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sboms() {
+		let attestation = br#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{"mediaType":"application/vnd.in-toto+json","digest":"sha256:aa","size":2},"layers":[{"mediaType":"application/vnd.in-toto+json","digest":"sha256:bbcc","size":3,"annotations":{"in-toto.io/predicate-type":"https://spdx.dev/Document"}},{"mediaType":"application/vnd.in-toto+json","digest":"sha256:ddee","size":3,"annotations":{"in-toto.io/predicate-type":"https://slsa.dev/provenance/v0.2"}}]}"#;
+		let entry = entry("docker.io", "library/app", attestation, &Access::Shared).unwrap();
+		assert_eq!(entry.documents, ["blobs/sha256/bb/cc"]);
+		assert!(entry.subject.is_none());
+		let referrer = br#"{"mediaType":"application/vnd.oci.image.manifest.v1+json","artifactType":"application/vnd.cyclonedx+json","config":{"mediaType":"application/vnd.oci.empty.v1+json","digest":"sha256:44","size":2},"layers":[{"mediaType":"application/json","digest":"sha256:ffff","size":3}],"subject":{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:cc","size":3}}"#;
+		assert_eq!(super::entry("docker.io", "library/app", referrer, &Access::Shared).unwrap().subject.as_deref(), Some("sha256:cc"));
+		assert!(super::entry("docker.io", "library/app", br#"{"schemaVersion":2,"layers":[{"mediaType":"application/vnd.oci.image.layer.v1.tar+gzip","digest":"sha256:11","size":1}]}"#, &Access::Shared).is_none());
+
+		let spdx = br#"{"_type":"https://in-toto.io/Statement/v0.1","predicateType":"https://spdx.dev/Document","subject":[{"name":"pkg:docker/app","digest":{"sha256":"abcd"}}],"predicate":{"spdxVersion":"SPDX-2.3","packages":[{"name":"log4j-core","versionInfo":"2.14.1","externalRefs":[{"referenceCategory":"PACKAGE-MANAGER","referenceType":"purl","referenceLocator":"pkg:maven/org.apache.logging.log4j/log4j-core@2.14.1"}]}]}}"#;
+		let (subject, packages) = document_packages(spdx);
+		assert_eq!(subject.as_deref(), Some("sha256:abcd"));
+		assert!(packages[0].is("log4j-core"));
+		assert_eq!(packages[0].version.as_deref(), Some("2.14.1"));
+
+		let cyclonedx = br#"{"bomFormat":"CycloneDX","components":[{"name":"spring-core","version":"5.3.0","components":[{"name":"core","group":"org.apache.logging.log4j","version":"2.17.0","purl":"pkg:maven/org.apache.logging.log4j/log4j-core@2.17.0?type=jar"}]}]}"#;
+		let (subject, packages) = document_packages(cyclonedx);
+		assert!(subject.is_none());
+		assert_eq!(packages.len(), 2);
+		assert!(packages.iter().any(|p| p.is("log4j-core") && p.version.as_deref() == Some("2.17.0")));
+	}
+}
@@ -0,0 +1,279 @@
+//! Docker schema1 manifests, which some very old images are still only available as.  Current
+//! clients refuse to pull them, so depending on the upstream's [`Schema1Policy`], they're passed
+//! through, refused with an error that says why, or converted to schema2.
+//!
+//! Converting one takes building the image config that schema1 doesn't have, from the history the
+//! manifest carries instead.  The config lists the digest of each layer uncompressed, so every layer
+//! is read once, from storage if it's there or from upstream if not, to work that out.
+//!
+//! [`Schema1Policy`]: crate::upstream::Schema1Policy
+
+use core::time::Duration;
+use std::io::Write;
+
+use bytes::Bytes;
+use dkregistry::mediatypes::MediaTypes;
+use flate2::write::GzDecoder;
+use futures::stream::LocalBoxStream;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::info;
+
+use super::blob_storage_path;
+use super::error::Error;
+use super::with_namespace_parameter;
+use super::Access;
+use super::RequestConfig;
+use crate::storage::replica;
+use crate::storage::Manifest;
+use crate::upstream::Client;
+
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.docker.container.image.v1+json";
+const GZIP_LAYER_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+const TAR_LAYER_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar";
+
+/// Keys in a layer's `v1Compatibility` that describe the layer rather than the image; the rest of
+/// the top layer's is the image config.
+const LAYER_KEYS: &[&str] = &["id", "parent", "parent_id", "layer_id", "Size", "throwaway"];
+
+#[derive(Debug, Deserialize)]
+struct Schema1 {
+	#[serde(rename = "fsLayers")]
+	fs_layers: Vec<FsLayer>,
+	history: Vec<History>
+}
+
+#[derive(Debug, Deserialize)]
+struct FsLayer {
+	#[serde(rename = "blobSum")]
+	blob_sum: String
+}
+
+#[derive(Debug, Deserialize)]
+struct History {
+	#[serde(rename = "v1Compatibility")]
+	v1_compatibility: String
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct V1Compatibility {
+	#[serde(default)]
+	created: Option<String>,
+	#[serde(default)]
+	author: Option<String>,
+	#[serde(default)]
+	comment: Option<String>,
+	#[serde(default)]
+	container_config: Option<ContainerConfig>,
+	/// Set on entries that only changed the config, whose layer is empty
+	#[serde(default)]
+	throwaway: bool
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ContainerConfig {
+	#[serde(rename = "Cmd", default)]
+	cmd: Option<Vec<String>>
+}
+
+/// Whether this is a schema1 manifest, going by what upstream said or, failing that, what the
+/// manifest says.
+pub fn is_schema1(manifest: &Manifest) -> bool {
+	#[derive(Deserialize)]
+	struct Version {
+		#[serde(rename = "schemaVersion")]
+		schema_version: Option<u64>
+	}
+	matches!(manifest.media_type, MediaTypes::ManifestV2S1 | MediaTypes::ManifestV2S1Signed) || matches!(serde_json::from_slice::<Version>(manifest.manifest.as_ref()), Ok(Version { schema_version: Some(1) }))
+}
+
+fn digest(body: &[u8]) -> String {
+	format!("sha256:{}", hex::encode(Sha256::digest(body)))
+}
+
+/// What the schema2 manifest and image config need to know about a layer.
+#[derive(Debug, Eq, PartialEq)]
+struct LayerDigests {
+	media_type: &'static str,
+	size: u64,
+	diff_id: String
+}
+
+enum Decoder {
+	Gzip(GzDecoder<Sha256>),
+	Tar(Sha256)
+}
+
+/// Works out the size of a layer, and the digest of its contents uncompressed.  Layers that aren't
+/// gzipped are plain tarballs, whose digest is their own.
+async fn hash_layer(mut stream: LocalBoxStream<'_, Result<Bytes, crate::storage::Error>>) -> Result<LayerDigests, Error> {
+	let mut decoder: Option<Decoder> = None;
+	let mut size = 0u64;
+	while let Some(chunk) = stream.try_next().await? {
+		size += chunk.len() as u64;
+		let decoder = decoder.get_or_insert_with(|| match chunk.starts_with(&[0x1f, 0x8b]) {
+			true => Decoder::Gzip(GzDecoder::new(Sha256::new())),
+			false => Decoder::Tar(Sha256::new())
+		});
+		match decoder {
+			Decoder::Gzip(v) => v.write_all(&chunk)?,
+			Decoder::Tar(v) => v.update(&chunk)
+		};
+	}
+	let (media_type, hash) = match decoder {
+		Some(Decoder::Gzip(v)) => (GZIP_LAYER_MEDIA_TYPE, v.finish()?.finalize()),
+		Some(Decoder::Tar(v)) => (TAR_LAYER_MEDIA_TYPE, v.finalize()),
+		None => (TAR_LAYER_MEDIA_TYPE, Sha256::new().finalize())
+	};
+	Ok(LayerDigests { media_type, size, diff_id: format!("sha256:{}", hex::encode(hash)) })
+}
+
+async fn read_layer(config: &RequestConfig, upstream: &mut Client, namespace: &str, upstream_image: &str, access: &Access, digest: &str) -> Result<LayerDigests, Error> {
+	if let Ok(stream) = config.repo.read(&blob_storage_path(digest, access), Duration::MAX).await {
+		return hash_layer(stream.into_inner().err_into::<crate::storage::Error>().boxed_local()).await;
+	}
+	upstream.circuit.check()?;
+	let result = with_namespace_parameter(upstream.namespace_parameter, namespace, |ns| upstream.get_blob(upstream_image, digest, ns)).await;
+	upstream.circuit.record(&result);
+	let response = result.map_err(|e| Error::from(e).or_unknown(Error::BlobUnknown))?;
+	hash_layer(response.stream().boxed_local()).await
+}
+
+/// Builds the history entry the image config gets for a schema1 layer.
+fn history_entry(v1: &V1Compatibility) -> Value {
+	let mut entry = Map::new();
+	if let Some(created) = &v1.created {
+		entry.insert("created".into(), created.as_str().into());
+	}
+	if let Some(author) = &v1.author {
+		entry.insert("author".into(), author.as_str().into());
+	}
+	if let Some(cmd) = v1.container_config.as_ref().and_then(|c| c.cmd.as_ref()) {
+		entry.insert("created_by".into(), cmd.join(" ").into());
+	}
+	if let Some(comment) = &v1.comment {
+		entry.insert("comment".into(), comment.as_str().into());
+	}
+	if (v1.throwaway) {
+		entry.insert("empty_layer".into(), true.into());
+	}
+	Value::Object(entry)
+}
+
+/// The image config for a schema1 manifest, given its layers' uncompressed digests, bottom first.
+fn image_config(schema1: &Schema1, history: &[V1Compatibility], diff_ids: Vec<String>) -> Result<Map<String, Value>, Error> {
+	let top = schema1.history.first().ok_or_else(|| Error::Schema1Conversion("manifest has no history".into()))?;
+	let mut config: Map<String, Value> = serde_json::from_str(&top.v1_compatibility)?;
+	for key in LAYER_KEYS {
+		config.remove(*key);
+	}
+	config.insert("rootfs".into(), json!({ "type": "layers", "diff_ids": diff_ids }));
+	config.insert("history".into(), history.iter().rev().map(history_entry).collect());
+	Ok(config)
+}
+
+/// Converts a schema1 manifest to schema2, storing the image config it's given as a blob where a
+/// client pulling the converted manifest will look for it.
+pub async fn convert(config: &RequestConfig, upstream: &mut Client, namespace: &str, upstream_image: &str, access: &Access, manifest: &[u8]) -> Result<Manifest, Error> {
+	let schema1: Schema1 = serde_json::from_slice(manifest)?;
+	if (schema1.fs_layers.is_empty() || schema1.fs_layers.len() != schema1.history.len()) {
+		return Err(Error::Schema1Conversion("manifest's layers and history don't line up".into()));
+	}
+	let history = schema1.history.iter().map(|h| serde_json::from_str::<V1Compatibility>(&h.v1_compatibility)).collect::<Result<Vec<_>, _>>()?;
+
+	// Schema1 lists layers top first
+	let mut layers = Vec::new();
+	let mut diff_ids = Vec::new();
+	for (layer, v1) in schema1.fs_layers.iter().zip(&history).rev() {
+		if (v1.throwaway) {
+			continue;
+		}
+		let digests = read_layer(config, upstream, namespace, upstream_image, access, &layer.blob_sum).await?;
+		layers.push(json!({ "mediaType": digests.media_type, "size": digests.size, "digest": layer.blob_sum }));
+		diff_ids.push(digests.diff_id);
+	}
+
+	let image_config = Bytes::from(serde_json::to_vec(&image_config(&schema1, &history, diff_ids)?)?);
+	let config_digest = digest(&image_config);
+	let len = image_config.len();
+	let config_path = blob_storage_path(&config_digest, access);
+	config.repo.write(&config_path, futures::stream::iter(std::iter::once(Result::<_, std::io::Error>::Ok(image_config))), len.try_into().unwrap_or(i64::MAX)).await?;
+	config.replicate(&config_path, replica::Kind::Blob);
+
+	let converted = serde_json::to_vec(&json!({
+		"schemaVersion": 2,
+		"mediaType": MediaTypes::ManifestV2S2.to_string(),
+		"config": { "mediaType": CONFIG_MEDIA_TYPE, "size": len, "digest": config_digest },
+		"layers": layers
+	}))?;
+	let converted_digest = digest(&converted);
+	info!(namespace, image = upstream_image, layers = layers.len(), digest = converted_digest.as_str(), "Converted schema1 manifest to schema2");
+	Ok(Manifest::new(converted.into(), MediaTypes::ManifestV2S2, Some(converted_digest)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const EMPTY_TAR_GZ: &[u8] = &[
+		0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x62, 0x18, 0x05, 0xa3, 0x60, 0x14, 0x8c, 0x58, 0x00, 0x08, 0x00, 0x00, 0xff, 0xff, 0x2e, 0xaf, 0xb5, 0xef, 0x00, 0x04, 0x00, 0x00
+	];
+
+	fn stream(chunks: Vec<&'static [u8]>) -> LocalBoxStream<'static, Result<Bytes, crate::storage::Error>> {
+		futures::stream::iter(chunks.into_iter().map(|c| Ok(Bytes::from_static(c)))).boxed_local()
+	}
+
+	#[actix_web::test]
+	async fn layer_digests() {
+		// An empty tarball, gzipped; uncompressed, it's 1024 zeroes
+		let gzip = hash_layer(stream(vec![&EMPTY_TAR_GZ[..10], &EMPTY_TAR_GZ[10..]])).await.unwrap();
+		assert_eq!(gzip, LayerDigests { media_type: GZIP_LAYER_MEDIA_TYPE, size: 32, diff_id: "sha256:5f70bf18a086007016e948b04aed3b82103a36bea41755b6cddfaf10ace3c6ef".into() });
+
+		let tar = hash_layer(stream(vec![&b"not "[..], &b"gzipped"[..]])).await.unwrap();
+		assert_eq!(tar, LayerDigests { media_type: TAR_LAYER_MEDIA_TYPE, size: 11, diff_id: digest(b"not gzipped") });
+	}
+
+	#[test]
+	fn schema1_detection() {
+		let schema1 = Manifest::new(Bytes::from_static(br#"{"schemaVersion":1,"name":"library/busybox","tag":"latest"}"#), MediaTypes::ApplicationJson, None);
+		assert!(is_schema1(&schema1));
+		let schema2 = Manifest::new(Bytes::from_static(br#"{"schemaVersion":2}"#), MediaTypes::ManifestV2S2, None);
+		assert!(!is_schema1(&schema2));
+	}
+
+	#[test]
+	fn config_from_history() {
+		let manifest = r#"{
+			"schemaVersion": 1,
+			"fsLayers": [{"blobSum": "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4"}, {"blobSum": "sha256:0000000000000000000000000000000000000000000000000000000000000001"}],
+			"history": [
+				{"v1Compatibility": "{\"id\":\"b\",\"parent\":\"a\",\"architecture\":\"amd64\",\"os\":\"linux\",\"created\":\"2016-01-01T00:00:01Z\",\"config\":{\"Cmd\":[\"sh\"]},\"container_config\":{\"Cmd\":[\"/bin/sh\",\"-c\",\"#(nop) CMD [\\\"sh\\\"]\"]},\"throwaway\":true}"},
+				{"v1Compatibility": "{\"id\":\"a\",\"created\":\"2016-01-01T00:00:00Z\",\"container_config\":{\"Cmd\":[\"/bin/sh\",\"-c\",\"#(nop) ADD file:abc in /\"]}}"}
+			]
+		}"#;
+		let schema1: Schema1 = serde_json::from_str(manifest).unwrap();
+		let history = schema1.history.iter().map(|h| serde_json::from_str::<V1Compatibility>(&h.v1_compatibility).unwrap()).collect::<Vec<_>>();
+		let config = image_config(&schema1, &history, vec!["sha256:aa".into()]).unwrap();
+		assert_eq!(
+			Value::Object(config),
+			json!({
+				"architecture": "amd64",
+				"os": "linux",
+				"created": "2016-01-01T00:00:01Z",
+				"config": {"Cmd": ["sh"]},
+				"container_config": {"Cmd": ["/bin/sh", "-c", "#(nop) CMD [\"sh\"]"]},
+				"rootfs": {"type": "layers", "diff_ids": ["sha256:aa"]},
+				"history": [
+					{"created": "2016-01-01T00:00:00Z", "created_by": "/bin/sh -c #(nop) ADD file:abc in /"},
+					{"created": "2016-01-01T00:00:01Z", "created_by": "/bin/sh -c #(nop) CMD [\"sh\"]", "empty_layer": true}
+				]
+			})
+		);
+	}
+}
@@ -0,0 +1,159 @@
+//! `GET /v1/search?q=<term>`, the search API `docker search` speaks, over what's cached here:  the
+//! repositories whose names contain the term, or that have a cached tag that does, case aside.
+//! Like the catalog, this is a cache, not upstream's index, so only repositories that have been
+//! pulled through us are found.  Results come in pages of `n` (25 by default, and at most 100),
+//! with `page` counting from 1, and each lists the repository's cached tags alongside what `docker
+//! search` shows.
+//!
+//! Search is open to anyone who can reach the proxy, so it doesn't list storage for every search:
+//! what's cached is listed at most once a minute, and searches in between are served from the last
+//! listing, while those that come in during one wait for it rather than starting their own.  A
+//! repository pulled for the first time can take that long to be found.
+
+use core::time::Duration;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use compact_str::CompactString;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use super::error::Error;
+use super::list::repository_name;
+use super::Access;
+use super::RequestConfig;
+use crate::storage::Repository;
+
+const DEFAULT_PAGE_SIZE: usize = 25;
+/// However many results a page is asked for, or `--max-page-size` allows
+const MAX_PAGE_SIZE: usize = 100;
+/// How long searches are served from one listing of storage
+const LISTING_TTL: Duration = Duration::from_secs(60);
+
+/// Cached repositories, by name, and their cached tags
+type Repositories = BTreeMap<String, BTreeSet<String>>;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+	#[serde(default)]
+	q: String,
+	n: Option<usize>,
+	page: Option<usize>
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize)]
+struct Found {
+	name: String,
+	description: &'static str,
+	star_count: u64,
+	is_official: bool,
+	is_automated: bool,
+	tags: Vec<String>
+}
+
+#[derive(Debug, Serialize)]
+struct Results<'a> {
+	query: &'a str,
+	num_results: usize,
+	num_pages: usize,
+	page: usize,
+	page_size: usize,
+	results: Vec<Found>
+}
+
+#[derive(Default)]
+struct Listing {
+	listed: Option<Instant>,
+	/// Of the manifests in storage
+	paths: Vec<String>,
+	/// Worked out from `paths` for each tenant searching, or for no tenant, as they search
+	repositories: HashMap<Option<CompactString>, Arc<Repositories>>
+}
+
+/// The last listing of what's cached, that searches are served from.
+#[derive(Default)]
+pub struct SearchIndex {
+	listing: Mutex<Listing>
+}
+
+impl SearchIndex {
+	/// The repositories `access` can see, as of a listing of storage no older than `LISTING_TTL`;
+	/// storage is listed again if the last one's older than that.
+	async fn get(&self, repo: &Repository, access: &Access) -> Result<Arc<Repositories>, Error> {
+		let mut listing = self.listing.lock().await;
+		if (!listing.listed.is_some_and(|listed| listed.elapsed() < LISTING_TTL)) {
+			listing.paths = repo.list_manifests().await?;
+			listing.listed = Some(Instant::now());
+			listing.repositories.clear();
+		}
+		let Listing { paths, repositories: by_access, .. } = &mut *listing;
+		Ok(by_access.entry(access.tenant().map(CompactString::from)).or_insert_with(|| Arc::new(repositories(paths, access))).clone())
+	}
+}
+
+/// The cached tags of each repository, from the paths of the manifests in storage.
+fn repositories(paths: &[String], access: &Access) -> Repositories {
+	let mut repositories = Repositories::new();
+	for path in paths {
+		let (Some(repository), Some((_, reference))) = (repository_name(path, access), path.rsplit_once('/')) else {
+			continue;
+		};
+		let tags = repositories.entry(repository).or_default();
+		// Digests aren't tags, and sidecars aren't manifests
+		if (!reference.contains(':') && !reference.starts_with('.')) {
+			tags.insert(reference.to_owned());
+		}
+	}
+	repositories
+}
+
+/// The repositories whose names, or any of whose tags, contain `term`, case aside.
+fn matching(repositories: &Repositories, term: &str) -> Vec<Found> {
+	let term = term.to_lowercase();
+	repositories
+		.iter()
+		.filter(|(name, tags)| name.to_lowercase().contains(&term) || tags.iter().any(|tag| tag.to_lowercase().contains(&term)))
+		.map(|(name, tags)| Found { name: name.clone(), description: "", star_count: 0, is_official: false, is_automated: false, tags: tags.iter().cloned().collect() })
+		.collect()
+}
+
+/// Searches the cached repositories, as `docker search` asks to.
+pub async fn search(http_req: HttpRequest, query: web::Query<SearchQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let access = match config.tenants.as_deref() {
+		Some(tenants) => Access::Tenant(tenants.authenticate(&http_req)?.name().into()),
+		None => Access::Shared
+	};
+	let repositories = config.search_index.get(&config.repo, &access).await?;
+	let results = matching(&repositories, query.q.trim());
+	let page_size = query.n.unwrap_or(DEFAULT_PAGE_SIZE).min(config.max_page_size).min(MAX_PAGE_SIZE).max(1);
+	let page = query.page.unwrap_or(1).max(1);
+	let num_results = results.len();
+	let results = results.into_iter().skip((page - 1).saturating_mul(page_size)).take(page_size).collect();
+	Ok(HttpResponse::Ok().json(Results { query: &query.q, num_results, num_pages: num_results.div_ceil(page_size), page, page_size, results }))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn search_terms() {
+		let paths = ["manifests/docker.io/library/redis/7", "manifests/docker.io/library/redis/sha256:aa", "manifests/docker.io/library/redis/.7.meta", "manifests/docker.io/grafana/grafana/Alpine", "manifests/docker.io/_private/abcd/library/secret/latest"].map(str::to_owned);
+		let repositories = repositories(&paths, &Access::Shared);
+		assert_eq!(repositories.keys().collect::<Vec<_>>(), ["docker.io/grafana/grafana", "docker.io/library/redis"]);
+
+		let names = |term: &str| matching(&repositories, term).into_iter().map(|r| (r.name, r.tags)).collect::<Vec<_>>();
+		assert_eq!(names("REDIS"), [("docker.io/library/redis".to_owned(), vec!["7".to_owned()])]);
+		// By tag
+		assert_eq!(names("alpine"), [("docker.io/grafana/grafana".to_owned(), vec!["Alpine".to_owned()])]);
+		assert_eq!(names("").len(), 2);
+		assert!(names("secret").is_empty());
+	}
+}
@@ -0,0 +1,123 @@
+//! Shadowing:  for an upstream with a `shadow`, a share of the manifests fetched from it are fetched
+//! again, in the background, from another configured upstream, such as the internal mirror it's
+//! being migrated to, and the two compared, so that whether the new one serves the same images, and
+//! how fast, is known before anyone's moved onto it.  Clients are only ever served what the primary
+//! gave, and shadow fetches aren't counted towards the other upstream's circuit breaker.
+
+use core::time::Duration;
+
+use actix_web::rt;
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_histogram_vec;
+use prometheus::register_int_counter_vec;
+use prometheus::HistogramVec;
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tokio::time::Instant;
+use tracing::info;
+use tracing::warn;
+
+use super::fetch_manifest;
+use super::RequestConfig;
+use crate::storage::Manifest;
+use crate::upstream::pull::PulledManifest;
+use crate::upstream::Client;
+
+static COMPARISONS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("shadow_comparisons", "Number of manifests fetched from a shadow upstream too, by whether it gave the same digest", &["namespace", "result"]).unwrap());
+static FETCH_DURATION: Lazy<HistogramVec> = Lazy::new(|| register_histogram_vec!("shadow_fetch_duration_seconds", "Time taken to fetch shadowed manifests, from the primary upstream and from the shadow", &["namespace", "upstream"]).unwrap());
+
+/// How long a shadow fetch gets before it counts as an error
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const fn default_percent() -> f64 {
+	100.0
+}
+
+/// Where to shadow an upstream's manifest fetches to.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Shadow {
+	/// A configured namespace, whose upstream is the one to compare against
+	namespace: CompactString,
+	/// The share of fetches shadowed, as a percentage
+	#[serde(default = "default_percent")]
+	percent: f64
+}
+
+/// The digest of a manifest, as upstream gave it or as its content says.
+fn digest_of(manifest: &[u8], digest: Option<&str>) -> String {
+	digest.map(str::to_owned).unwrap_or_else(|| format!("sha256:{}", hex::encode(Sha256::digest(manifest))))
+}
+
+/// Fetches `reference`, just fetched from `upstream` in `latency` as `manifest`, from `upstream`'s
+/// shadow too, if it has one and this fetch is in its share, and compares the two.
+pub(super) async fn compare(config: &RequestConfig, upstream: &Client, namespace: &str, image: &str, reference: &str, manifest: &Manifest, latency: Duration) {
+	let Some(shadow) = upstream.shadow() else {
+		return;
+	};
+	if (rand::random::<f64>() * 100.0 >= shadow.percent) {
+		return;
+	}
+	let mut other = match config.upstream.lock().await.get(&shadow.namespace) {
+		Ok(v) => v.clone(),
+		Err(error) => {
+			warn!(namespace, shadow = shadow.namespace.as_str(), %error, "Shadow upstream isn't configured");
+			return;
+		}
+	};
+	let shadow_namespace = shadow.namespace.clone();
+	let namespace = namespace.to_owned();
+	let other_image = other.upstream_image(image).into_owned();
+	let reference = reference.to_owned();
+	let max_size = config.max_manifest_size;
+	let expected = digest_of(&manifest.manifest, manifest.digest.as_deref());
+	FETCH_DURATION.with_label_values(&[&namespace, "primary"]).observe(latency.as_secs_f64());
+	rt::spawn(async move {
+		let started = Instant::now();
+		let anonymous = !other.has_credentials();
+		let result = tokio::time::timeout(TIMEOUT, fetch_manifest(&mut other, &shadow_namespace, &other_image, &reference, anonymous, max_size)).await;
+		let elapsed = started.elapsed();
+		let result = match result {
+			Ok(Ok(PulledManifest::Manifest(body, _, digest))) => {
+				FETCH_DURATION.with_label_values(&[&namespace, "shadow"]).observe(elapsed.as_secs_f64());
+				let actual = digest_of(&body, digest.as_deref());
+				match actual == expected {
+					true => "match",
+					false => {
+						info!(namespace, shadow = shadow_namespace.as_str(), image = other_image, reference, expected, actual, "Shadow upstream gave a different manifest");
+						"mismatch"
+					}
+				}
+			},
+			Ok(Ok(PulledManifest::TooLarge(size))) => {
+				info!(namespace, shadow = shadow_namespace.as_str(), image = other_image, reference, size, "Shadow upstream gave a manifest too large to compare");
+				"error"
+			},
+			Ok(Err(error)) => {
+				info!(namespace, shadow = shadow_namespace.as_str(), image = other_image, reference, %error, "Shadow upstream failed to serve manifest");
+				"error"
+			},
+			Err(_) => {
+				info!(namespace, shadow = shadow_namespace.as_str(), image = other_image, reference, "Shadow upstream timed out serving manifest");
+				"error"
+			}
+		};
+		COMPARISONS.with_label_values(&[&namespace, result]).inc();
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn digests() {
+		let manifest = b"{}";
+		let computed = digest_of(manifest, None);
+		assert_eq!(computed, "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a");
+		assert_eq!(digest_of(manifest, Some("sha256:abcd")), "sha256:abcd");
+	}
+}
@@ -0,0 +1,239 @@
+//! Sharding blobs across a fleet of cache nodes:  each blob belongs to one node on a consistent-hash
+//! ring, and the others answer pulls of it with a 307 to that node, so that it's fetched from
+//! upstream and stored once across the fleet rather than once per node.  The ring is a YAML list of
+//! the nodes' URLs, the same on every node, and each node is told which one it is with
+//! `--shard-self`.  Adding or removing a node only moves the blobs on its share of the ring, and the
+//! file is watched, so the fleet can be resized without restarting it.
+//!
+//! Only blobs are sharded; manifests are small, and have to be checked for freshness on every pull
+//! anyway.  A redirect carries [`HOP_PARAM`], and a pull that does is served wherever it lands, so
+//! that nodes that briefly disagree about the ring don't send a client back and forth.
+
+use core::time::Duration;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::RwLock;
+
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter;
+use prometheus::register_int_gauge;
+use prometheus::IntCounter;
+use prometheus::IntGauge;
+use serde::Deserialize;
+use sha2::Digest;
+use sha2::Sha256;
+use tracing::info;
+use tracing::warn;
+
+static REDIRECTS: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("shard_redirects", "Number of blob pulls redirected to the node whose share of the ring the blob is on").unwrap());
+static NODES: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("shard_ring_nodes", "Number of nodes on the shard ring").unwrap());
+
+/// The query parameter marking a pull as redirected here already.
+pub const HOP_PARAM: &str = "shard-hop";
+
+/// Points each unit of weight puts on the ring; enough for shares to come out close to even.
+const POINTS_PER_WEIGHT: usize = 128;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+	#[error("Failed to read shard ring file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Invalid shard ring file: {0}")]
+	Yaml(#[from] serde_yaml::Error),
+	#[error("Shard ring has no nodes with any weight")]
+	Empty,
+	#[error("{0} isn't one of the nodes on the shard ring")]
+	NotInRing(String)
+}
+
+const fn default_weight() -> usize {
+	1
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum NodeConfig {
+	Url(String),
+	Weighted {
+		url: String,
+		/// How big a share of the blobs the node takes, relative to the others; zero takes none, for
+		/// draining it
+		#[serde(default = "default_weight")]
+		weight: usize
+	}
+}
+
+impl NodeConfig {
+	fn url(&self) -> &str {
+		match self {
+			Self::Url(url) | Self::Weighted { url, .. } => url.trim_end_matches('/')
+		}
+	}
+
+	fn weight(&self) -> usize {
+		match self {
+			Self::Url(_) => default_weight(),
+			Self::Weighted { weight, .. } => *weight
+		}
+	}
+}
+
+fn hash(key: &[u8]) -> u64 {
+	let digest = Sha256::digest(key);
+	u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// The nodes, and their points around the ring.
+#[derive(Debug)]
+struct Ring {
+	urls: Vec<String>,
+	/// Sorted, with the node each is for
+	points: Vec<(u64, usize)>
+}
+
+impl Ring {
+	fn parse(yaml: &[u8], this: &str) -> Result<Self, LoadError> {
+		let nodes: Vec<NodeConfig> = serde_yaml::from_slice(yaml)?;
+		if (!nodes.iter().any(|node| node.url() == this)) {
+			return Err(LoadError::NotInRing(this.to_owned()));
+		}
+		let mut points = Vec::new();
+		for (i, node) in nodes.iter().enumerate() {
+			for point in 0..node.weight() * POINTS_PER_WEIGHT {
+				points.push((hash(format!("{}#{point}", node.url()).as_bytes()), i));
+			}
+		}
+		if (points.is_empty()) {
+			return Err(LoadError::Empty);
+		}
+		points.sort_unstable();
+		Ok(Self { urls: nodes.iter().map(|node| node.url().to_owned()).collect(), points })
+	}
+
+	/// The node a blob belongs to:  the one with the first point at or after its digest's.
+	fn owner(&self, digest: &str) -> &str {
+		let h = hash(digest.as_bytes());
+		let i = self.points.partition_point(|(point, _)| *point < h);
+		let (_, node) = self.points[i % self.points.len()];
+		&self.urls[node]
+	}
+}
+
+pub struct Shards {
+	/// This node's URL, as it is on the ring
+	this: String,
+	ring: RwLock<Arc<Ring>>
+}
+
+impl Shards {
+	pub async fn load(path: &Path, this: &str) -> Result<Self, LoadError> {
+		Self::parse(&tokio::fs::read(path).await?, this)
+	}
+
+	pub(super) fn parse(yaml: &[u8], this: &str) -> Result<Self, LoadError> {
+		let this = this.trim_end_matches('/').to_owned();
+		let ring = Ring::parse(yaml, &this)?;
+		NODES.set(ring.urls.len().try_into().unwrap_or(i64::MAX));
+		Ok(Self { this, ring: RwLock::new(Arc::new(ring)) })
+	}
+
+	/// Where a pull of the blob with this digest should go instead, unless it's this node's.
+	fn elsewhere(&self, digest: &str) -> Option<String> {
+		let ring = self.ring.read().unwrap().clone();
+		let owner = ring.owner(digest);
+		(owner != self.this).then(|| owner.to_owned())
+	}
+
+	/// A redirect to the node a blob pull belongs to, if it isn't this one and the pull hasn't been
+	/// redirected already.
+	pub(super) fn redirect(&self, http_req: &HttpRequest, digest: &str) -> Option<HttpResponse> {
+		let query = http_req.query_string();
+		if (query.split('&').any(|param| param.split('=').next() == Some(HOP_PARAM))) {
+			return None;
+		}
+		let owner = self.elsewhere(digest)?;
+		let location = match query {
+			"" => format!("{owner}{}?{HOP_PARAM}=1", http_req.uri().path()),
+			query => format!("{owner}{}?{query}&{HOP_PARAM}=1", http_req.uri().path())
+		};
+		REDIRECTS.inc();
+		Some(HttpResponse::TemporaryRedirect().insert_header((header::LOCATION, location)).finish())
+	}
+
+	/// Re-reads the ring whenever the file changes, keeping the one in use if the new one doesn't
+	/// load.  Runs until the task is dropped.
+	pub async fn watch(self: Arc<Self>, path: PathBuf) {
+		let mut last = tokio::fs::read(&path).await.ok();
+		let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+		interval.tick().await;
+		loop {
+			interval.tick().await;
+			let contents = match tokio::fs::read(&path).await {
+				Ok(v) => v,
+				Err(error) => {
+					warn!(path = %path.display(), %error, "Failed to read shard ring file; keeping the ring we have");
+					continue;
+				}
+			};
+			if (last.as_ref() == Some(&contents)) {
+				continue;
+			}
+			match Ring::parse(&contents, &self.this) {
+				Ok(ring) => {
+					info!(path = %path.display(), nodes = ring.urls.len(), "Reloaded shard ring");
+					NODES.set(ring.urls.len().try_into().unwrap_or(i64::MAX));
+					*self.ring.write().unwrap() = Arc::new(ring);
+					last = Some(contents);
+				},
+				Err(error) => warn!(path = %path.display(), %error, "Failed to load shard ring file; keeping the ring we have")
+			};
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn digests() -> impl Iterator<Item = String> {
+		(0..3000).map(|i| format!("sha256:{}", hex::encode(Sha256::digest(i.to_string()))))
+	}
+
+	#[test]
+	fn shares() {
+		let three = Shards::parse(b"- http://a:5000\n- http://b:5000/\n- url: http://c:5000\n  weight: 2\n", "http://b:5000").unwrap();
+		let mut counts = [0; 3];
+		for digest in digests() {
+			match three.elsewhere(&digest).as_deref() {
+				Some("http://a:5000") => counts[0] += 1,
+				None => counts[1] += 1,
+				Some("http://c:5000") => counts[2] += 1,
+				other => panic!("{other:?}")
+			};
+		}
+		// Roughly a quarter, a quarter, and half
+		assert!(counts[0] > 500 && counts[0] < 1000, "{counts:?}");
+		assert!(counts[1] > 500 && counts[1] < 1000, "{counts:?}");
+		assert!(counts[2] > 1200 && counts[2] < 1800, "{counts:?}");
+
+		// Adding a node only moves blobs onto it
+		let four = Ring::parse(b"- http://a:5000\n- http://b:5000\n- url: http://c:5000\n  weight: 2\n- http://d:5000\n", "http://b:5000").unwrap();
+		let before = three.ring.read().unwrap().clone();
+		for digest in digests() {
+			let owner = four.owner(&digest);
+			assert!(owner == before.owner(&digest) || owner == "http://d:5000");
+		}
+	}
+
+	#[test]
+	fn invalid() {
+		assert!(matches!(Shards::parse(b"- http://a:5000\n", "http://b:5000"), Err(LoadError::NotInRing(_))));
+		assert!(matches!(Shards::parse(b"- url: http://a:5000\n  weight: 0\n", "http://a:5000"), Err(LoadError::Empty)));
+	}
+}
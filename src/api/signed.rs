@@ -0,0 +1,244 @@
+//! Signed URLs:  links to one cached manifest or blob that anyone holding them can fetch with a
+//! plain `GET` until they expire, for consumers that can't speak the registry protocol, like
+//! firmware updaters.  They're minted through `/_admin/sign`, with the admin token, and served under
+//! `/_signed`, where middleware checks the signature and expiry before a request gets any further.
+//! Each is signed for the cache of whoever minted it, the shared one or a tenant's, and serves from
+//! that one only.
+
+use core::future;
+use core::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use actix_web::dev::Service;
+use actix_web::dev::ServiceRequest;
+use actix_web::dev::ServiceResponse;
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use compact_str::CompactString;
+use futures::future::FutureExt;
+use hmac::Hmac;
+use hmac::Mac;
+use hmac::NewMac;
+use serde::Deserialize;
+use serde_with::serde_as;
+use serde_with::DisplayFromStr;
+use sha2_09::Sha256;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use super::admin_token;
+use super::error::Error;
+use super::serve_blob_as;
+use super::serve_manifest_as;
+use super::split_image;
+use super::Access;
+use super::BlobRequest;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::image::ImageReference;
+
+fn mac(key: &[u8], message: &[u8]) -> Hmac<Sha256> {
+	let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+	mac.update(message);
+	mac
+}
+
+/// HMAC-SHA256 of `message` under `key`.
+pub(super) fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+	mac(key, message).finalize().into_bytes().to_vec()
+}
+
+/// What's signed for a URL:  its path and expiry, and the tenant it's for, if any.  Shared URLs
+/// are signed as they were before there were tenants, so those already handed out still work.
+fn message(path: &str, expires: u64, tenant: Option<&str>) -> String {
+	match tenant {
+		Some(tenant) => format!("{path}\n{expires}\n{tenant}"),
+		None => format!("{path}\n{expires}")
+	}
+}
+
+/// The secret URLs are signed with, along with the longest they can be made to last.
+#[derive(Clone)]
+pub struct SigningKey {
+	key: Vec<u8>,
+	max_ttl: Duration
+}
+
+impl SigningKey {
+	pub fn new(key: &str, max_ttl: Duration) -> Self {
+		Self { key: key.as_bytes().to_vec(), max_ttl }
+	}
+
+	/// The signature for `path`, good until `expires` (in seconds since the epoch), from `tenant`'s
+	/// cache if given.
+	fn sign(&self, path: &str, expires: u64, tenant: Option<&str>) -> String {
+		URL_SAFE_NO_PAD.encode(hmac(&self.key, message(path, expires, tenant).as_bytes()))
+	}
+
+	fn verify(&self, path: &str, query: &SignedQuery, now: u64) -> Result<(), Error> {
+		let signature = URL_SAFE_NO_PAD.decode(&query.signature).map_err(|_| Error::SignatureInvalid)?;
+		mac(&self.key, message(path, query.expires, query.tenant.as_deref()).as_bytes()).verify(&signature).map_err(|_| Error::SignatureInvalid)?;
+		match query.expires > now {
+			true => Ok(()),
+			false => Err(Error::SignedUrlExpired)
+		}
+	}
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignedQuery {
+	expires: u64,
+	signature: String,
+	/// The tenant whose cache it serves from
+	tenant: Option<CompactString>
+}
+
+fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Turns away anything under `/_signed` without a valid, unexpired signature for its path.
+fn check(req: &ServiceRequest) -> Result<(), Error> {
+	let Some(key) = req.app_data::<web::Data<RequestConfig>>().and_then(|config| config.signing_key.as_ref()) else {
+		return Err(Error::SigningDisabled);
+	};
+	let query = web::Query::<SignedQuery>::from_query(req.query_string()).map_err(|_| Error::SignatureInvalid)?;
+	key.verify(req.path(), &query, now())
+}
+
+/// Registers `/_signed`, where signed URLs are served.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+	cfg.service(
+		web::scope("/_signed")
+			.app_data(super::path_config())
+			.wrap_fn(|req, srv| match super::cdn::check(&req).and_then(|()| check(&req)) {
+				Ok(()) => srv.call(req).map(|response| response.map(ServiceResponse::map_into_boxed_body)).left_future(),
+				Err(error) => future::ready(Ok(req.error_response(error))).right_future()
+			})
+			.wrap(super::logger())
+			.route("/{namespace}/{image:[^{}]+}/manifests/{reference}", web::get().to(manifest))
+			.route("/{namespace}/{image:[^{}]+}/blobs/{digest}", web::get().to(blob))
+	);
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignedManifestRequest {
+	namespace: CompactString,
+	image: ImageName,
+	reference: ImageReference
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignedBlobRequest {
+	namespace: CompactString,
+	image: ImageName,
+	digest: String
+}
+
+/// Whose cache a signed URL serves from:  the tenant's it was signed for, where there are tenants,
+/// as long as that tenant can still pull from `namespace`, and otherwise the shared one.
+fn access(config: &RequestConfig, tenant: Option<&str>, namespace: &str) -> Result<Access, Error> {
+	match (config.tenants.as_deref(), tenant) {
+		(None, None) => Ok(Access::Shared),
+		(Some(tenants), Some(tenant)) if tenants.allows(tenant, namespace) => Ok(Access::Tenant(tenant.into())),
+		_ => Err(Error::SignatureInvalid)
+	}
+}
+
+/// Serves a manifest through a signed URL, with the proxy's own credentials.
+pub async fn manifest(req: web::Path<SignedManifestRequest>, qstr: web::Query<SignedQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let SignedManifestRequest { namespace, image, reference } = req.into_inner();
+	let access = access(&config, qstr.tenant.as_deref(), &namespace)?;
+	serve_manifest_as(&config, &ManifestRequest { image, reference }, Some(namespace.as_str()), None, access).await
+}
+
+/// Serves a blob through a signed URL, with the proxy's own credentials.
+pub async fn blob(req: web::Path<SignedBlobRequest>, qstr: web::Query<SignedQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let SignedBlobRequest { namespace, image, digest } = req.into_inner();
+	let access = access(&config, qstr.tenant.as_deref(), &namespace)?;
+	serve_blob_as(config, BlobRequest { image, digest }, Some(namespace.as_str()), None, access).await
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct SignQuery {
+	ns: Option<CompactString>,
+	/// How long the URL works for; one hour if not given
+	#[serde(default)]
+	#[serde_as(as = "Option<DisplayFromStr>")]
+	ttl: Option<humantime::Duration>
+}
+
+/// Mints a signed URL for `{path}` (`manifests/{reference}` or `blobs/{digest}`) in `image`, for
+/// the cache the caller's credentials are for.
+async fn mint(http_req: &HttpRequest, config: &RequestConfig, qstr: &SignQuery, image: &ImageName, path: &str) -> Result<HttpResponse, Error> {
+	let key = config.signing_key.as_ref().ok_or(Error::SigningDisabled)?;
+	admin_token::check(config.admin_token.as_deref(), http_req)?;
+	let ttl = qstr.ttl.map_or(Duration::from_secs(3600), |ttl| *ttl);
+	if (ttl.is_zero() || ttl > key.max_ttl) {
+		return Err(Error::SignedUrlTtl(key.max_ttl));
+	}
+	let (namespace, image) = split_image(qstr.ns.as_deref(), image.as_ref(), config.default_ns(None));
+	let upstream = { config.upstream.lock().await.get(namespace)?.clone() };
+	// Content pulled with a client's own credentials can't be fetched without them
+	let tenant = match config.access(http_req, namespace, &upstream)? {
+		Access::Shared => None,
+		Access::Tenant(tenant) => Some(tenant),
+		Access::Private(_) => return Err(Error::SignedUrlPrivate)
+	};
+	let path = config.absolute_path(&format!("/_signed/{namespace}/{image}/{path}"));
+	let expires = now() + ttl.as_secs();
+	let signature = key.sign(&path, expires, tenant.as_deref());
+	let connection = http_req.connection_info();
+	let tenant = tenant.map(|tenant| format!("&tenant={tenant}")).unwrap_or_default();
+	let url = format!("{}://{}{path}?expires={expires}&signature={signature}{tenant}", connection.scheme(), connection.host());
+	let expires_at = OffsetDateTime::from_unix_timestamp(expires.try_into().unwrap_or(i64::MAX)).ok().and_then(|t| t.format(&Rfc3339).ok());
+	Ok(HttpResponse::Ok().json(serde_json::json!({ "url": url, "expires": expires_at })))
+}
+
+pub async fn sign_manifest(http_req: HttpRequest, req: web::Path<ManifestRequest>, qstr: web::Query<SignQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	mint(&http_req, &config, &qstr, &req.image, &format!("manifests/{}", req.reference)).await
+}
+
+pub async fn sign_blob(http_req: HttpRequest, req: web::Path<BlobRequest>, qstr: web::Query<SignQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	mint(&http_req, &config, &qstr, &req.image, &format!("blobs/{}", req.digest)).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn hmac() {
+		// RFC 4231, test case 2
+		assert_eq!(hex::encode(super::hmac(b"Jefe", b"what do ya want for nothing?")), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+	}
+
+	#[test]
+	fn signatures() {
+		let key = SigningKey::new("secret", Duration::MAX);
+		let path = "/_signed/docker.io/library/alpine/manifests/3.19";
+		let query = SignedQuery { expires: 1000, signature: key.sign(path, 1000, None), tenant: None };
+		assert!(key.verify(path, &query, 999).is_ok());
+		assert!(matches!(key.verify(path, &query, 1000), Err(Error::SignedUrlExpired)));
+		assert!(matches!(key.verify("/_signed/docker.io/library/alpine/manifests/3.20", &query, 999), Err(Error::SignatureInvalid)));
+		// Nor can it be pointed at a tenant's cache
+		let query = SignedQuery { tenant: Some("platform".into()), ..query };
+		assert!(matches!(key.verify(path, &query, 999), Err(Error::SignatureInvalid)));
+		let query = SignedQuery { expires: 2000, tenant: None, ..query };
+		assert!(matches!(key.verify(path, &query, 999), Err(Error::SignatureInvalid)));
+		assert!(matches!(SigningKey::new("other", Duration::MAX).verify(path, &SignedQuery { expires: 1000, signature: key.sign(path, 1000, None), tenant: None }, 999), Err(Error::SignatureInvalid)));
+
+		let query = SignedQuery { expires: 1000, signature: key.sign(path, 1000, Some("platform")), tenant: Some("platform".into()) };
+		assert!(key.verify(path, &query, 999).is_ok());
+		let query = SignedQuery { tenant: Some("other".into()), ..query };
+		assert!(matches!(key.verify(path, &query, 999), Err(Error::SignatureInvalid)));
+		let query = SignedQuery { tenant: None, ..query };
+		assert!(matches!(key.verify(path, &query, 999), Err(Error::SignatureInvalid)));
+	}
+}
@@ -10,6 +10,7 @@ use futures::task::Poll;
 use pin_project::pin_project;
 use sha2::Digest;
 use sha2::Sha256;
+use tokio::sync::oneshot;
 
 #[pin_project]
 pub struct DigestCheckedStream<S, E, IE>
@@ -81,12 +82,106 @@ where
 	}
 }
 
+/// Checks that a blob from upstream is as long as upstream said it was, failing as soon as it's read
+/// further than that, or when it ends short of it, rather than leaving it to the digest check at the
+/// end to notice.
+pub struct LengthCheckedStream<S, E> {
+	inner: S,
+	expected: u64,
+	read: u64,
+	done: bool,
+	_e: PhantomData<E>
+}
+
+impl<S, E> LengthCheckedStream<S, E> {
+	pub fn new(inner: S, expected: u64) -> Self {
+		Self { inner, expected, read: 0, done: false, _e: PhantomData }
+	}
+}
+
+impl<S, E, IE> Stream for LengthCheckedStream<S, E>
+where
+	S: Stream<Item = Result<Bytes, IE>> + Unpin,
+	E: From<IE> + From<LengthMismatchError> + Unpin
+{
+	type Item = Result<Bytes, E>;
+
+	fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		if (self.done) {
+			return Poll::Ready(None);
+		}
+		let Poll::Ready(chunk) = self.inner.poll_next_unpin(ctx) else {
+			return Poll::Pending;
+		};
+		let error = match chunk {
+			Some(Ok(chunk)) => {
+				self.read += chunk.len() as u64;
+				match (self.read > self.expected) {
+					true => LengthMismatchError { expected: self.expected, read: self.read },
+					false => return Poll::Ready(Some(Ok(chunk)))
+				}
+			},
+			Some(Err(error)) => return Poll::Ready(Some(Err(error.into()))),
+			None if self.read == self.expected => return Poll::Ready(None),
+			None => LengthMismatchError { expected: self.expected, read: self.read }
+		};
+		self.done = true;
+		Poll::Ready(Some(Err(error.into())))
+	}
+}
+
+/// The client's side of a proxied blob.  If it's dropped before reaching the end of the blob, which
+/// is what happens when the client disconnects, `on_abort` fires.
+pub struct AbortNotifyingStream<S> {
+	inner: S,
+	finished: bool,
+	on_abort: Option<oneshot::Sender<()>>
+}
+
+impl<S> AbortNotifyingStream<S> {
+	pub fn new(inner: S, on_abort: Option<oneshot::Sender<()>>) -> Self {
+		Self { inner, finished: false, on_abort }
+	}
+}
+
+impl<S, T, E> Stream for AbortNotifyingStream<S>
+where
+	S: Stream<Item = Result<T, E>> + Unpin
+{
+	type Item = Result<T, E>;
+
+	fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		let result = self.inner.poll_next_unpin(ctx);
+		// An error ends the blob just as surely as reaching the end does; either way, there's
+		// nothing left to abort
+		if (matches!(result, Poll::Ready(None | Some(Err(_))))) {
+			self.finished = true;
+		}
+		result
+	}
+}
+
+impl<S> Drop for AbortNotifyingStream<S> {
+	fn drop(&mut self) {
+		if let (false, Some(on_abort)) = (self.finished, self.on_abort.take()) {
+			let _ = on_abort.send(());
+		}
+	}
+}
+
 #[derive(Debug, Clone)]
 pub struct DigestMismatchError {
 	expected: [u8; 32],
 	actual: [u8; 32]
 }
 
+impl DigestMismatchError {
+	/// The digest of what was read instead.
+	pub fn actual(&self) -> String {
+		format!("sha256:{}", hex::encode(self.actual))
+	}
+}
+
 impl fmt::Display for DigestMismatchError {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		f.write_str("Digest '")?;
@@ -103,6 +198,23 @@ impl fmt::Display for DigestMismatchError {
 
 impl std::error::Error for DigestMismatchError {}
 
+#[derive(Debug, Clone)]
+pub struct LengthMismatchError {
+	expected: u64,
+	read: u64
+}
+
+impl fmt::Display for LengthMismatchError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match (self.read > self.expected) {
+			true => write!(f, "Upstream sent more than the {} bytes it said the blob was", self.expected),
+			false => write!(f, "Upstream ended the blob after {} of the {} bytes it said it was", self.read, self.expected)
+		}
+	}
+}
+
+impl std::error::Error for LengthMismatchError {}
+
 pub async fn hash<S, E>(mut stream: S) -> Result<[u8; 32], E>
 where
 	S: Stream<Item = Result<Bytes, E>> + Unpin,
@@ -115,3 +227,21 @@ where
 	}
 	Ok(hasher.finalize().into())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	async fn read(chunks: &[&'static [u8]], expected: u64) -> Vec<Result<Bytes, String>> {
+		let inner = futures::stream::iter(chunks.iter().map(|c| Ok::<_, LengthMismatchError>(Bytes::from_static(c))).collect::<Vec<_>>());
+		LengthCheckedStream::<_, LengthMismatchError>::new(inner, expected).map(|chunk| chunk.map_err(|e| e.to_string())).collect().await
+	}
+
+	#[actix_web::test]
+	async fn length_checked() {
+		assert_eq!(read(&[b"abc", b"def"], 6).await, vec![Ok(Bytes::from_static(b"abc")), Ok(Bytes::from_static(b"def"))]);
+		assert_eq!(read(&[b"abc"], 6).await, vec![Ok(Bytes::from_static(b"abc")), Err("Upstream ended the blob after 3 of the 6 bytes it said it was".to_owned())]);
+		// Fails on the chunk that goes past the end, without passing it on or reading further
+		assert_eq!(read(&[b"abc", b"defg", b"h"], 6).await, vec![Ok(Bytes::from_static(b"abc")), Err("Upstream sent more than the 6 bytes it said the blob was".to_owned())]);
+	}
+}
@@ -0,0 +1,142 @@
+//! Restart sweeps:  with `--restart-sweep-tags`, the tags most recently cached (or refreshed) whose
+//! manifests have expired while the proxy was down are pulled through the cache again in the
+//! background after startup, a batch at a time, so that after a long outage the cache catches up
+//! with upstream at a pace of its own choosing, rather than on the first pull of each tag, all at
+//! once.  Tags are revalidated the way their namespace's `revalidation` says, the same as for a
+//! client; only tags from the shared cache are swept.
+
+use core::str::FromStr;
+use core::time::Duration;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use actix_web::web;
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use tokio::time::MissedTickBehavior;
+use tracing::info;
+use tracing::warn;
+
+use super::error::Error;
+use super::mirror::drain;
+use super::serve_manifest;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::image::ImageReference;
+
+static SWEPT: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("restart_sweep_tags", "Number of expired tags pulled through the cache again after startup", &["namespace", "result"]).unwrap());
+
+/// How much of the cache to sweep, and how fast.
+#[derive(Clone, Copy, Debug)]
+pub struct Sweep {
+	/// The most tags swept
+	pub tags: usize,
+	/// How many tags are pulled at once
+	pub batch_size: usize,
+	/// How long after one batch starts the next does
+	pub interval: Duration
+}
+
+/// A tag cached in the shared cache.
+#[derive(Debug, Eq, PartialEq)]
+struct Tag {
+	namespace: CompactString,
+	image: String,
+	tag: String
+}
+
+/// The tag a shared cache manifest is stored under, if it's stored under a tag.
+fn tag(object: &str) -> Option<Tag> {
+	let (namespace, rest) = object.strip_prefix("manifests/")?.split_once('/')?;
+	let (image, tag) = rest.rsplit_once('/')?;
+	if (image.starts_with("_tenant/") || image.starts_with("_private/") || tag.contains(':') || tag.starts_with('.')) {
+		return None;
+	}
+	Some(Tag { namespace: namespace.into(), image: image.to_owned(), tag: tag.to_owned() })
+}
+
+/// The expired tags worth sweeping, newest first:  those cached for longer than their namespace's
+/// invalidation time, up to `limit` of them.
+fn expired(mut tags: Vec<(Tag, SystemTime)>, invalidation: &HashMap<CompactString, Duration>, now: SystemTime, limit: usize) -> Vec<Tag> {
+	tags.retain(|(tag, modified)| invalidation.get(&tag.namespace).is_some_and(|ttl| now.duration_since(*modified).unwrap_or_default() > *ttl));
+	tags.sort_by(|(_, a), (_, b)| b.cmp(a));
+	tags.into_iter().take(limit).map(|(tag, _)| tag).collect()
+}
+
+async fn candidates(config: &RequestConfig, limit: usize) -> Result<Vec<Tag>, Error> {
+	let tags = config.repo.list_manifests().await?.into_iter().filter_map(|object| Some((tag(&object)?, object))).collect::<Vec<_>>();
+	let stats = config.repo.stat_all(&tags.iter().map(|(_, object)| object.as_str()).collect::<Vec<_>>(), Duration::MAX).await;
+	// Anything that's gone since it was listed has nothing to sweep
+	let tags = tags.into_iter().zip(stats).filter_map(|((tag, _), stat)| Some((tag, stat.ok()?.modified()))).collect();
+	Ok(expired(tags, &config.invalidation_config().await.manifests, SystemTime::now(), limit))
+}
+
+async fn pull(config: &RequestConfig, tag: &Tag) -> Result<(), Error> {
+	let req = ManifestRequest {
+		image: ImageName::from_str(&tag.image).map_err(|_| Error::NameUnknown)?,
+		reference: ImageReference::from_str(&tag.tag).map_err(|_| Error::ManifestUnknown)?
+	};
+	drain(serve_manifest(config, &req, Some(&tag.namespace), None).await?).await
+}
+
+/// Sweeps the expired tags, once.
+pub async fn run(config: web::Data<RequestConfig>, sweep: Sweep) {
+	let tags = match candidates(&config, sweep.tags).await {
+		Ok(v) => v,
+		Err(error) => {
+			warn!(%error, "Failed to list tags for the restart sweep");
+			return;
+		}
+	};
+	info!(count = tags.len(), "Sweeping expired tags");
+	let mut interval = tokio::time::interval(sweep.interval);
+	interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	let (mut refreshed, mut failed) = (0, 0);
+	for batch in tags.chunks(sweep.batch_size.max(1)) {
+		interval.tick().await;
+		let results = futures::future::join_all(batch.iter().map(|tag| pull(&config, tag))).await;
+		for (tag, result) in batch.iter().zip(results) {
+			let label = match result {
+				Ok(()) => {
+					refreshed += 1;
+					"refreshed"
+				},
+				Err(error) => {
+					failed += 1;
+					warn!(namespace = tag.namespace.as_str(), image = tag.image.as_str(), tag = tag.tag.as_str(), %error, "Failed to sweep tag");
+					"failed"
+				}
+			};
+			SWEPT.with_label_values(&[tag.namespace.as_str(), label]).inc();
+		}
+	}
+	info!(refreshed, failed, "Restart sweep done");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tags() {
+		assert_eq!(tag("manifests/docker.io/library/alpine/3.19"), Some(Tag { namespace: "docker.io".into(), image: "library/alpine".into(), tag: "3.19".into() }));
+		assert_eq!(tag("manifests/registry.local:5000/app/latest").map(|t| t.namespace), Some("registry.local:5000".into()));
+		for object in ["manifests/docker.io/library/alpine/sha256:aa", "manifests/docker.io/library/alpine/.latest.meta", "manifests/docker.io/_tenant/a/library/alpine/latest", "manifests/docker.io/_private/ab/app/latest", "blobs/sha256/ab/cd"] {
+			assert_eq!(tag(object), None, "{object}");
+		}
+	}
+
+	#[test]
+	fn only_expired_tags_newest_first() {
+		let now = SystemTime::now();
+		let hours = |h: u64| now - Duration::from_secs(h * 3600);
+		let invalidation = HashMap::<CompactString, _>::from([("docker.io".into(), Duration::from_secs(3600))]);
+		let entry = |image: &str, modified| (tag(&format!("manifests/docker.io/{image}/latest")).unwrap(), modified);
+		let tags = vec![entry("old", hours(48)), entry("fresh", now), entry("recent", hours(2)), entry("older", hours(72)), (tag("manifests/quay.io/app/latest").unwrap(), hours(48))];
+		let expired = expired(tags, &invalidation, now, 2);
+		assert_eq!(expired.iter().map(|t| t.image.as_str()).collect::<Vec<_>>(), ["recent", "old"]);
+	}
+}
@@ -0,0 +1,111 @@
+//! Upstream's tag lists, for namespaces with a `tag_list_ttl`:  `/tags/list` lists what upstream has
+//! rather than only what's been pulled through here, and each repository's list is kept for that
+//! long, so that tools like Renovate listing tags of hundreds of repositories every hour ask
+//! upstream once per period, whichever page they ask for.  Pulls of the same repository's list wait
+//! for one refresh rather than each asking upstream.
+//!
+//! Lists are fetched a page at a time, with the `last` parameter, and a refresh that fails partway,
+//! say to a rate limit, picks up after the last tag it got the next time around instead of starting
+//! over, so that even the longest lists get refreshed eventually.  Until then, and whenever
+//! upstream can't be reached, the last complete list is served, along with what's been fetched of
+//! the next and the tags cached here.
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::warn;
+
+use super::error::Error;
+use crate::upstream::Client;
+
+static LISTS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("tag_list_requests", "Number of tag lists served for namespaces that list upstream's tags, by where they came from", &["namespace", "result"]).unwrap());
+
+/// How many tags are asked of upstream at once
+const PAGE_SIZE: usize = 1000;
+
+#[derive(Debug, Default)]
+struct TagList {
+	/// As of the last complete refresh
+	tags: BTreeSet<String>,
+	refreshed: Option<Instant>,
+	/// What a refresh that stopped partway got, and the last of it, to carry on after
+	partial: BTreeSet<String>,
+	resume: Option<String>
+}
+
+impl TagList {
+	/// Asks upstream for the rest of the list, from wherever the last refresh got to.
+	async fn refresh(&mut self, upstream: &Client, image: &str) -> Result<(), dkregistry::errors::Error> {
+		loop {
+			let (tags, more) = upstream.tags_page(image, PAGE_SIZE, self.resume.as_deref()).await?;
+			// A page with nothing in it says there's nothing more, whatever else it says
+			let more = more && !tags.is_empty();
+			self.resume = tags.last().cloned().or(self.resume.take());
+			self.partial.extend(tags);
+			if (!more) {
+				break;
+			}
+		}
+		self.tags = std::mem::take(&mut self.partial);
+		self.resume = None;
+		self.refreshed = Some(Instant::now());
+		Ok(())
+	}
+
+	/// Everything known to be in the list, short of a complete refresh.
+	fn known(&self) -> BTreeSet<String> {
+		self.tags.union(&self.partial).cloned().collect()
+	}
+}
+
+/// The tag lists fetched from upstream, by namespace and upstream image.
+#[derive(Default)]
+pub struct TagLists {
+	lists: std::sync::Mutex<HashMap<(CompactString, String), Arc<Mutex<TagList>>>>
+}
+
+impl TagLists {
+	/// Upstream's tags for `upstream_image`, from the list kept for `ttl` if it's that fresh, merged
+	/// with `cached` if upstream can't be asked; the error's only returned if there's nothing to
+	/// serve instead of it.
+	pub(super) async fn get(&self, upstream: &Client, upstream_image: &str, ttl: core::time::Duration, cached: Vec<String>) -> Result<Vec<String>, Error> {
+		let namespace = upstream.namespace.as_str();
+		let list = self.lists.lock().unwrap().entry((upstream.namespace.clone(), upstream_image.to_owned())).or_default().clone();
+		let mut list = list.lock().await;
+		if (list.refreshed.is_some_and(|refreshed| refreshed.elapsed() < ttl)) {
+			LISTS.with_label_values(&[namespace, "hit"]).inc();
+			return Ok(list.tags.iter().cloned().collect());
+		}
+		let result = match upstream.circuit.check() {
+			Ok(()) => {
+				let result = list.refresh(upstream, upstream_image).await;
+				upstream.circuit.record(&result);
+				result.map_err(|e| Error::from(upstream.normalize_error(e, false)))
+			},
+			Err(e) => Err(e.into())
+		};
+		match result {
+			Ok(()) => {
+				LISTS.with_label_values(&[namespace, "refreshed"]).inc();
+				Ok(list.tags.iter().cloned().collect())
+			},
+			Err(error) => {
+				let mut tags = list.known();
+				tags.extend(cached);
+				if (tags.is_empty()) {
+					return Err(error);
+				}
+				warn!(namespace, image = upstream_image, %error, "Failed to list tags upstream; serving what's known of them");
+				LISTS.with_label_values(&[namespace, "fallback"]).inc();
+				Ok(tags.into_iter().collect())
+			}
+		}
+	}
+}
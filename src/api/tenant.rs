@@ -0,0 +1,219 @@
+//! Tenants:  teams sharing one instance, each with credentials of its own, a list of the namespaces
+//! it may pull from, and optionally a cap on how much storage its cache may take up.  Each tenant's
+//! content is cached apart from everyone else's, so that it can be accounted for exactly, and shows
+//! up in metrics labelled with the tenant's name.
+
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use std::path::Path;
+
+use actix_web::http::header::HeaderValue;
+use actix_web::HttpRequest;
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge_vec;
+use prometheus::IntCounterVec;
+use prometheus::IntGaugeVec;
+use serde::Deserialize;
+use tracing::warn;
+
+use super::auth::Credentials;
+use super::error::Error;
+use super::webhook::tokens_match;
+use crate::storage::Repository;
+use crate::util::SecretString;
+
+static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("tenant_requests", "Number of requests made by each tenant", &["tenant"]).unwrap());
+static STORAGE: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("tenant_storage_bytes", "Bytes of blobs cached for each tenant", &["tenant"]).unwrap());
+static QUOTA: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("tenant_quota_bytes", "Storage quota of each tenant that has one", &["tenant"]).unwrap());
+static REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("tenant_quota_rejections", "Number of pulls refused because they'd have cached more than the tenant's quota", &["tenant"]).unwrap());
+
+#[derive(Debug, Deserialize)]
+struct TenantConfig {
+	/// Used in storage paths and metrics; letters, digits, `-`, and `_` only
+	name: CompactString,
+	username: CompactString,
+	password: SecretString,
+	/// The namespaces this tenant may pull from; any, if empty
+	#[serde(default)]
+	namespaces: Vec<CompactString>,
+	/// How many bytes of blobs this tenant's cache may hold before pulls that would add more are
+	/// refused
+	#[serde(default)]
+	quota_bytes: Option<u64>
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+	#[error("Failed to read tenants file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Invalid tenants file: {0}")]
+	Yaml(#[from] serde_yaml::Error),
+	#[error("Invalid tenant name {0:?}; only letters, digits, '-', and '_' are allowed")]
+	InvalidName(CompactString),
+	#[error("More than one tenant is named {0:?} or has username {0:?}")]
+	Duplicate(CompactString)
+}
+
+#[derive(Debug)]
+pub struct Tenant {
+	config: TenantConfig,
+	/// Bytes of blobs in this tenant's cache, as of the last count plus whatever's been cached since
+	usage: AtomicU64
+}
+
+impl Tenant {
+	pub fn name(&self) -> &str {
+		self.config.name.as_str()
+	}
+
+	fn allows(&self, namespace: &str) -> bool {
+		self.config.namespaces.is_empty() || self.config.namespaces.iter().any(|n| n == namespace)
+	}
+
+	/// Where this tenant's blobs are stored, under `blobs/`; manifests go under the same prefix
+	/// within each namespace.
+	pub fn storage_prefix(name: &str) -> String {
+		format!("_tenant/{name}")
+	}
+}
+
+/// Every configured tenant.  Once there are any, every registry request has to come from one of
+/// them.
+#[derive(Debug)]
+pub struct Tenants {
+	tenants: Vec<Tenant>
+}
+
+impl Tenants {
+	pub async fn load(path: &Path) -> Result<Self, LoadError> {
+		Self::parse(&tokio::fs::read(path).await?)
+	}
+
+	pub(super) fn parse(yaml: &[u8]) -> Result<Self, LoadError> {
+		let configs: Vec<TenantConfig> = serde_yaml::from_slice(yaml)?;
+		let mut tenants: Vec<Tenant> = Vec::with_capacity(configs.len());
+		for config in configs {
+			if (config.name.is_empty() || !config.name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')) {
+				return Err(LoadError::InvalidName(config.name));
+			}
+			if let Some(other) = tenants.iter().find(|t| t.config.name == config.name || t.config.username == config.username) {
+				let duplicate = match other.config.name == config.name {
+					true => config.name,
+					false => config.username
+				};
+				return Err(LoadError::Duplicate(duplicate));
+			}
+			if let Some(quota) = config.quota_bytes {
+				QUOTA.with_label_values(&[config.name.as_str()]).set(quota.try_into().unwrap_or(i64::MAX));
+			}
+			tenants.push(Tenant { config, usage: AtomicU64::new(0) });
+		}
+		Ok(Self { tenants })
+	}
+
+	fn challenge() -> Error {
+		Error::Unauthorized(Some(HeaderValue::from_static(r#"Basic realm="oci-registry""#)))
+	}
+
+	/// The tenant whose basic credentials the request carries.
+	pub fn authenticate(&self, req: &HttpRequest) -> Result<&Tenant, Error> {
+		let credentials = Credentials::from_request(req).ok_or_else(Self::challenge)?;
+		let tenant = self.tenants.iter().find(|t| t.config.username == credentials.username).ok_or_else(Self::challenge)?;
+		if (!tokens_match(tenant.config.password.expose().as_bytes(), credentials.password.expose().as_bytes())) {
+			return Err(Self::challenge());
+		}
+		REQUESTS.with_label_values(&[tenant.name()]).inc();
+		Ok(tenant)
+	}
+
+	/// The tenant whose credentials the request carries, if it's allowed to pull from `namespace`.
+	pub fn admit(&self, req: &HttpRequest, namespace: &str) -> Result<&Tenant, Error> {
+		let tenant = self.authenticate(req)?;
+		match tenant.allows(namespace) {
+			true => Ok(tenant),
+			false => Err(Error::NamespaceDenied { tenant: tenant.config.name.clone(), namespace: namespace.into() })
+		}
+	}
+
+	/// Every namespace some tenant is allowed to pull from.
+	pub fn namespaces(&self) -> impl Iterator<Item = &str> {
+		self.tenants.iter().flat_map(|t| t.config.namespaces.iter().map(CompactString::as_str))
+	}
+
+	fn get(&self, name: &str) -> Option<&Tenant> {
+		self.tenants.iter().find(|t| t.name() == name)
+	}
+
+	/// Whether there's a tenant called `name` that's allowed to pull from `namespace`.
+	pub fn allows(&self, name: &str, namespace: &str) -> bool {
+		self.get(name).is_some_and(|tenant| tenant.allows(namespace))
+	}
+
+	/// Fails if the tenant's cache is already at its quota, so nothing more should be added to it.
+	pub fn check_quota(&self, name: &str) -> Result<(), Error> {
+		let Some(tenant) = self.get(name) else {
+			return Ok(());
+		};
+		match tenant.config.quota_bytes {
+			Some(quota) if tenant.usage.load(Ordering::Relaxed) >= quota => {
+				REJECTIONS.with_label_values(&[name]).inc();
+				Err(Error::QuotaExceeded(tenant.config.name.clone()))
+			},
+			_ => Ok(())
+		}
+	}
+
+	/// Counts a blob just cached for a tenant towards its quota.
+	pub fn record(&self, name: &str, bytes: u64) {
+		if let Some(tenant) = self.get(name) {
+			let usage = tenant.usage.fetch_add(bytes, Ordering::Relaxed) + bytes;
+			STORAGE.with_label_values(&[name]).set(usage.try_into().unwrap_or(i64::MAX));
+		}
+	}
+
+	/// Counts what's actually in each tenant's cache, since cleanup ages things out of it.
+	pub async fn refresh_usage(&self, repo: &Repository) {
+		for tenant in &self.tenants {
+			match repo.usage(&format!("blobs/{}/", Tenant::storage_prefix(tenant.name()))).await {
+				Ok(usage) => {
+					tenant.usage.store(usage, Ordering::Relaxed);
+					STORAGE.with_label_values(&[tenant.name()]).set(usage.try_into().unwrap_or(i64::MAX));
+				},
+				Err(error) => warn!(tenant = tenant.name(), %error, "Failed to count tenant's storage usage")
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tenants(yaml: &str) -> Result<Tenants, LoadError> {
+		Tenants::parse(yaml.as_bytes())
+	}
+
+	#[test]
+	fn config() {
+		let t = tenants("- name: team-a\n  username: a\n  password: x\n  namespaces: [docker.io]\n  quota_bytes: 100\n- name: team-b\n  username: b\n  password: y").unwrap();
+		assert!(t.tenants[0].allows("docker.io"));
+		assert!(!t.tenants[0].allows("ghcr.io"));
+		assert!(t.tenants[1].allows("ghcr.io"));
+		assert!(matches!(tenants("- name: team/a\n  username: a\n  password: x"), Err(LoadError::InvalidName(_))));
+		assert!(matches!(tenants("- name: a\n  username: a\n  password: x\n- name: b\n  username: a\n  password: y"), Err(LoadError::Duplicate(_))));
+	}
+
+	#[test]
+	fn quotas() {
+		let t = tenants("- name: team-a\n  username: a\n  password: x\n  quota_bytes: 100\n- name: team-b\n  username: b\n  password: y").unwrap();
+		assert!(t.check_quota("team-a").is_ok());
+		t.record("team-a", 60);
+		assert!(t.check_quota("team-a").is_ok());
+		t.record("team-a", 60);
+		assert!(matches!(t.check_quota("team-a"), Err(Error::QuotaExceeded(_))));
+		t.record("team-b", 1 << 40);
+		assert!(t.check_quota("team-b").is_ok());
+	}
+}
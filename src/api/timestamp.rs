@@ -0,0 +1,434 @@
+//! Timestamps of cache fills:  with `--timestamp-url`, the digest of each manifest newly cached
+//! from upstream is sent to that RFC 3161 time-stamping authority, and its signed reply kept in
+//! storage, so that it can later be proven that the manifest existed with that content at that
+//! time, to anyone who trusts the authority; `openssl ts -verify` checks them.  With
+//! `--attestation-key` instead, or as well, for when the authority can't be reached, the proxy
+//! attests to it itself, with an HMAC over the digest and time, which proves less, but only to
+//! whoever holds the key.
+//!
+//! Timestamps are kept by digest under `timestamps/`, so a tag moving doesn't replace the proof for
+//! what it pointed at before, and they're obtained in the background, so that pulls don't wait on
+//! the authority.  Manifests cached for pass-through credentials are left out, as for tag history.
+
+use core::time::Duration;
+use std::iter;
+
+use actix_web::rt;
+use actix_web::web;
+use actix_web::HttpResponse;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::warn;
+
+use super::error::Error;
+use super::manifest_storage_dir;
+use super::signed::hmac;
+use super::split_image;
+use super::Access;
+use super::ManifestQueryString;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::image::ImageReference;
+use crate::storage::Repository;
+
+static TIMESTAMPS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_timestamps", "Number of newly cached manifests timestamped, by how", &["result"]).unwrap());
+
+const INDEX_PREFIX: &str = "timestamps/";
+
+/// How long the authority gets to answer
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The DER encoding of SHA-256's object identifier, 2.16.840.1.101.3.4.2.1
+const SHA256_OID: &[u8] = &[0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+/// The DER encoding of `id-ct-TSTInfo`'s object identifier, 1.2.840.113549.1.9.16.1.4
+const TST_INFO_OID: &[u8] = &[0x06, 0x0b, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x01, 0x04];
+
+#[derive(Debug, thiserror::Error)]
+enum TsaError {
+	#[error("{0}")]
+	Http(#[from] reqwest::Error),
+	#[error("Reply isn't a time-stamp response")]
+	Malformed,
+	#[error("Time-stamp request refused with status {0}")]
+	Refused(u8),
+	#[error("Time-stamp token isn't for the digest asked about")]
+	WrongDigest,
+	#[error("Time-stamp token doesn't carry the nonce sent with the request")]
+	WrongNonce
+}
+
+/// Where cache fills are timestamped, and how.
+pub struct Timestamper {
+	authority: Option<reqwest::Url>,
+	attestation_key: Option<Vec<u8>>,
+	http: reqwest::Client
+}
+
+/// A local attestation, for when there's no authority to ask.
+#[derive(Debug, Serialize)]
+struct Attestation<'a> {
+	digest: &'a str,
+	time: String,
+	/// Base64 HMAC-SHA256 of the digest and time, separated by a newline
+	hmac: String
+}
+
+impl Timestamper {
+	/// `None` if there's neither an authority nor a key to timestamp with.
+	pub fn new(authority: Option<reqwest::Url>, attestation_key: Option<&str>) -> Option<Self> {
+		(authority.is_some() || attestation_key.is_some()).then(|| Self { authority, attestation_key: attestation_key.map(|key| key.as_bytes().to_vec()), http: reqwest::Client::new() })
+	}
+
+	async fn request(&self, url: &reqwest::Url, hash: &[u8]) -> Result<Bytes, TsaError> {
+		let nonce = rand::random();
+		let query = time_stamp_request(hash, nonce);
+		let response = self.http.post(url.clone()).timeout(TIMEOUT).header("content-type", "application/timestamp-query").body(query).send().await?.error_for_status()?;
+		let reply = response.bytes().await?;
+		check_response(&reply, hash, nonce)?;
+		Ok(reply)
+	}
+
+	fn attest(&self, key: &[u8], digest: &str) -> Option<Bytes> {
+		let time = OffsetDateTime::now_utc().format(&Rfc3339).ok()?;
+		let hmac = STANDARD.encode(hmac(key, format!("{digest}\n{time}").as_bytes()));
+		serde_json::to_vec(&Attestation { digest, time, hmac }).ok().map(Bytes::from)
+	}
+
+	/// Timestamps `digest`, a hash of what was cached, from the authority if there is one and it
+	/// answers, and otherwise with the attestation key; the bytes to keep, and their extension.
+	async fn timestamp(&self, digest: &str, hash: &[u8]) -> Option<(Bytes, &'static str)> {
+		if let Some(url) = &self.authority {
+			match self.request(url, hash).await {
+				Ok(reply) => {
+					TIMESTAMPS.with_label_values(&["authority"]).inc();
+					return Some((reply, "tsr"));
+				},
+				Err(error) => warn!(digest, %error, "Failed to get a timestamp from the time-stamping authority")
+			};
+		}
+		let attestation = self.attestation_key.as_deref().and_then(|key| self.attest(key, digest));
+		TIMESTAMPS.with_label_values(&[match attestation.is_some() {
+			true => "attested",
+			false => "failed"
+		}]).inc();
+		attestation.map(|attestation| (attestation, "json"))
+	}
+}
+
+/// Appends a DER tag-length-value to `out`.
+fn der(out: &mut Vec<u8>, tag: u8, content: &[u8]) {
+	out.push(tag);
+	match content.len() {
+		len if len < 0x80 => out.push(len as u8),
+		len => {
+			let bytes = len.to_be_bytes();
+			let bytes = &bytes[bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len() - 1)..];
+			out.push(0x80 | bytes.len() as u8);
+			out.extend_from_slice(bytes);
+		}
+	};
+	out.extend_from_slice(content);
+}
+
+/// The content of a DER INTEGER holding `value`.
+fn integer(value: u64) -> Vec<u8> {
+	// INTEGERs are signed, so a set high bit needs a zero in front
+	let bytes = value.to_be_bytes();
+	let bytes = &bytes[bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len() - 1)..];
+	match bytes[0] & 0x80 {
+		0 => bytes.to_vec(),
+		_ => iter::once(0).chain(bytes.iter().copied()).collect()
+	}
+}
+
+/// A `TimeStampReq` for a SHA-256 `hash`, asking for the authority's certificate in the reply.
+fn time_stamp_request(hash: &[u8], nonce: u64) -> Vec<u8> {
+	let mut algorithm = SHA256_OID.to_vec();
+	der(&mut algorithm, 0x05, &[]);
+	let mut imprint = Vec::new();
+	der(&mut imprint, 0x30, &algorithm);
+	der(&mut imprint, 0x04, hash);
+
+	let mut request = Vec::new();
+	der(&mut request, 0x02, &[1]);
+	der(&mut request, 0x30, &imprint);
+	der(&mut request, 0x02, &integer(nonce));
+	der(&mut request, 0x01, &[0xff]);
+	let mut out = Vec::new();
+	der(&mut out, 0x30, &request);
+	out
+}
+
+/// Reads a DER header at the start of `data`:  the tag, and the content with whatever follows it.
+fn read_header(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+	let (&tag, rest) = data.split_first()?;
+	let (&first, rest) = rest.split_first()?;
+	let (len, rest) = match first {
+		len if len < 0x80 => (len as usize, rest),
+		long => {
+			let count = (long & 0x7f) as usize;
+			if (count == 0 || count > core::mem::size_of::<usize>() || rest.len() < count) {
+				return None;
+			}
+			(rest[..count].iter().fold(0, |len, &b| (len << 8) | b as usize), &rest[count..])
+		}
+	};
+	match rest.len() >= len {
+		true => Some((tag, &rest[..len], &rest[len..])),
+		false => None
+	}
+}
+
+/// Reads the DER value at the start of `data`, which has to be tagged `tag`:  its content, and
+/// whatever follows it.
+fn expect(data: &[u8], tag: u8) -> Result<(&[u8], &[u8]), TsaError> {
+	match read_header(data) {
+		Some((found, content, rest)) if found == tag => Ok((content, rest)),
+		_ => Err(TsaError::Malformed)
+	}
+}
+
+/// The content of the `TSTInfo` in a `TimeStampToken`:  a `ContentInfo` holding `SignedData`, whose
+/// encapsulated content is the DER `TSTInfo` the authority signed.
+fn tst_info(token: &[u8]) -> Result<&[u8], TsaError> {
+	let (content_info, _) = expect(token, 0x30)?;
+	let (_, rest) = expect(content_info, 0x06)?;
+	let (signed_data, _) = expect(rest, 0xa0)?;
+	let (signed_data, _) = expect(signed_data, 0x30)?;
+	let (_, rest) = expect(signed_data, 0x02)?;
+	let (_, rest) = expect(rest, 0x31)?;
+	let (encapsulated, _) = expect(rest, 0x30)?;
+	let (content_type, rest) = expect(encapsulated, 0x06)?;
+	if (content_type != &TST_INFO_OID[2..]) {
+		return Err(TsaError::Malformed);
+	}
+	let (content, _) = expect(rest, 0xa0)?;
+	let (content, _) = expect(content, 0x04)?;
+	let (tst_info, _) = expect(content, 0x30)?;
+	Ok(tst_info)
+}
+
+/// Checks that a `TimeStampResp` granted the request, and that its token's message imprint is
+/// SHA-256 of `hash`, and it carries the `nonce` sent, so that it can't be an old reply played
+/// back.  The token's signature is left to whoever relies on it, who has to decide which
+/// authorities to trust anyway.
+fn check_response(reply: &[u8], hash: &[u8], nonce: u64) -> Result<(), TsaError> {
+	let (response, _) = expect(reply, 0x30)?;
+	let (status_info, token) = expect(response, 0x30)?;
+	let (status, _) = expect(status_info, 0x02)?;
+	match status {
+		// granted, or grantedWithMods
+		[0] | [1] => (),
+		[status] => return Err(TsaError::Refused(*status)),
+		_ => return Err(TsaError::Malformed)
+	};
+
+	let info = tst_info(token)?;
+	let (_, rest) = expect(info, 0x02)?;
+	let (_, rest) = expect(rest, 0x06)?;
+	let (imprint, rest) = expect(rest, 0x30)?;
+	let (algorithm, hashed) = expect(imprint, 0x30)?;
+	let (algorithm, parameters) = expect(algorithm, 0x06)?;
+	let (hashed, _) = expect(hashed, 0x04)?;
+	if (algorithm != &SHA256_OID[2..] || !matches!(parameters, [] | [0x05, 0x00]) || hashed != hash) {
+		return Err(TsaError::WrongDigest);
+	}
+
+	let (_, rest) = expect(rest, 0x02)?;
+	let (_, mut rest) = expect(rest, 0x18)?;
+	// accuracy and ordering come before the nonce, where they're given
+	for optional in [0x30, 0x01] {
+		if let Some((_, _, after)) = read_header(rest).filter(|(tag, ..)| *tag == optional) {
+			rest = after;
+		}
+	}
+	match read_header(rest) {
+		Some((0x02, found, _)) if found == integer(nonce).as_slice() => Ok(()),
+		_ => Err(TsaError::WrongNonce)
+	}
+}
+
+/// Where the timestamp for the manifest with `digest`, stored in `manifest_dir`, is kept.
+fn index_path(manifest_dir: &str, digest: &str, extension: &str) -> String {
+	let dir = manifest_dir.strip_prefix("manifests/").unwrap_or(manifest_dir);
+	format!("{INDEX_PREFIX}{dir}/{digest}.{extension}")
+}
+
+async fn exists(repo: &Repository, manifest_dir: &str, digest: &str) -> bool {
+	for extension in ["tsr", "json"] {
+		if (repo.stat(&index_path(manifest_dir, digest, extension), Duration::MAX).await.is_ok()) {
+			return true;
+		}
+	}
+	false
+}
+
+/// Timestamps a manifest just cached in `manifest_dir`, in the background, unless it's been
+/// timestamped already.
+pub(super) fn record(config: &RequestConfig, manifest_dir: String, manifest: &[u8], access: &Access) {
+	let Some(timestamper) = config.timestamper.clone() else {
+		return;
+	};
+	if (matches!(access, Access::Private(_))) {
+		return;
+	}
+	let hash = Sha256::digest(manifest).to_vec();
+	let repo = config.repo.clone();
+	rt::spawn(async move {
+		let digest = format!("sha256:{}", hex::encode(&hash));
+		if (exists(&repo, &manifest_dir, &digest).await) {
+			return;
+		}
+		let Some((body, extension)) = timestamper.timestamp(&digest, &hash).await else {
+			return;
+		};
+		let path = index_path(&manifest_dir, &digest, extension);
+		let len = body.len().try_into().unwrap_or(i64::MAX);
+		if let Err(error) = repo.write(&path, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body))), len).await {
+			warn!(path, %error, "Failed to store manifest timestamp");
+		}
+	});
+}
+
+/// The timestamp for a cached manifest:  the authority's DER-encoded `TimeStampResp`, or the
+/// proxy's own attestation as JSON.  A tag is looked up by the digest of what's cached for it.
+pub async fn timestamp(req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns(None));
+	let dir = manifest_storage_dir(namespace, image, &Access::Shared);
+	let digest = match &req.reference {
+		ImageReference::Tag(_) => {
+			let (_, body) = config.repo.read_manifest(&req.storage_path(namespace, &Access::Shared), Duration::MAX).await.map_err(|e| Error::from(e).or_unknown(Error::ManifestUnknown))?;
+			let body = body.into_inner().try_collect::<BytesMut>().await?;
+			format!("sha256:{}", hex::encode(Sha256::digest(&body)))
+		},
+		reference => reference.to_string()
+	};
+	for (extension, content_type) in [("tsr", "application/timestamp-reply"), ("json", "application/json")] {
+		match config.repo.read(&index_path(&dir, &digest, extension), Duration::MAX).await {
+			Ok(stream) => {
+				let body = stream.into_inner().try_collect::<BytesMut>().await?;
+				return Ok(HttpResponse::Ok().content_type(content_type).body(body.freeze()));
+			},
+			Err(e) if e.is_not_found() => (),
+			Err(e) => return Err(e.into())
+		};
+	}
+	Ok(HttpResponse::NotFound().finish())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn requests() {
+		let hash = [0xab; 32];
+		let request = time_stamp_request(&hash, 0x80);
+		// SEQUENCE { INTEGER 1, SEQUENCE { SEQUENCE { sha256, NULL }, OCTET STRING hash }, INTEGER nonce, BOOLEAN TRUE }
+		let mut expected = vec![0x30, 0x3d, 0x02, 0x01, 0x01, 0x30, 0x31, 0x30, 0x0d];
+		expected.extend_from_slice(SHA256_OID);
+		expected.extend_from_slice(&[0x05, 0x00, 0x04, 0x20]);
+		expected.extend_from_slice(&hash);
+		expected.extend_from_slice(&[0x02, 0x02, 0x00, 0x80, 0x01, 0x01, 0xff]);
+		assert_eq!(request, expected);
+
+		let mut long = Vec::new();
+		der(&mut long, 0x04, &[0; 300]);
+		assert_eq!(&long[..4], &[0x04, 0x82, 0x01, 0x2c]);
+		assert_eq!(read_header(&long).map(|(tag, content, rest)| (tag, content.len(), rest.len())), Some((0x04, 300, 0)));
+	}
+
+	/// A `TimeStampToken` for `imprint`, the DER `MessageImprint`, with `nonce` if there is one.
+	fn token(imprint: &[u8], nonce: Option<u64>) -> Vec<u8> {
+		let mut info = Vec::new();
+		der(&mut info, 0x02, &[1]);
+		der(&mut info, 0x06, &[0x2a, 0x03, 0x04]);
+		info.extend_from_slice(imprint);
+		der(&mut info, 0x02, &[0x2f]);
+		der(&mut info, 0x18, b"20261014120000Z");
+		let mut accuracy = Vec::new();
+		der(&mut accuracy, 0x02, &[1]);
+		der(&mut info, 0x30, &accuracy);
+		if let Some(nonce) = nonce {
+			der(&mut info, 0x02, &integer(nonce));
+		}
+		let mut tst_info = Vec::new();
+		der(&mut tst_info, 0x30, &info);
+		let mut content = Vec::new();
+		der(&mut content, 0x04, &tst_info);
+		let mut encapsulated = TST_INFO_OID.to_vec();
+		der(&mut encapsulated, 0xa0, &content);
+
+		let mut signed_data = Vec::new();
+		der(&mut signed_data, 0x02, &[3]);
+		der(&mut signed_data, 0x31, &[]);
+		der(&mut signed_data, 0x30, &encapsulated);
+		der(&mut signed_data, 0x31, &[]);
+		let mut wrapped = Vec::new();
+		der(&mut wrapped, 0x30, &signed_data);
+		let mut content_info = Vec::new();
+		der(&mut content_info, 0x06, &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02]);
+		der(&mut content_info, 0xa0, &wrapped);
+		let mut out = Vec::new();
+		der(&mut out, 0x30, &content_info);
+		out
+	}
+
+	fn imprint(oid: &[u8], hash: &[u8]) -> Vec<u8> {
+		let mut algorithm = oid.to_vec();
+		der(&mut algorithm, 0x05, &[]);
+		let mut imprint = Vec::new();
+		der(&mut imprint, 0x30, &algorithm);
+		der(&mut imprint, 0x04, hash);
+		let mut out = Vec::new();
+		der(&mut out, 0x30, &imprint);
+		out
+	}
+
+	fn reply(status: u8, token: &[u8]) -> Vec<u8> {
+		let mut status_info = Vec::new();
+		der(&mut status_info, 0x02, &[status]);
+		let mut response = Vec::new();
+		der(&mut response, 0x30, &status_info);
+		response.extend_from_slice(token);
+		let mut out = Vec::new();
+		der(&mut out, 0x30, &response);
+		out
+	}
+
+	#[test]
+	fn responses() {
+		let hash = [0xcd; 32];
+		let granted = token(&imprint(SHA256_OID, &hash), Some(0x80));
+		assert!(check_response(&reply(0, &granted), &hash, 0x80).is_ok());
+		assert!(check_response(&reply(1, &granted), &hash, 0x80).is_ok());
+		assert!(matches!(check_response(&reply(2, &[]), &hash, 0x80), Err(TsaError::Refused(2))));
+		assert!(matches!(check_response(&reply(0, &token(&imprint(SHA256_OID, &[0; 32]), Some(0x80))), &hash, 0x80), Err(TsaError::WrongDigest)));
+		// The right bytes, hashed some other way
+		let sha1 = [0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a];
+		assert!(matches!(check_response(&reply(0, &token(&imprint(&sha1, &hash), Some(0x80))), &hash, 0x80), Err(TsaError::WrongDigest)));
+		assert!(matches!(check_response(b"<html>", &hash, 0x80), Err(TsaError::Malformed)));
+		assert_eq!(index_path("manifests/docker.io/library/alpine", "sha256:ab", "tsr"), "timestamps/docker.io/library/alpine/sha256:ab.tsr");
+	}
+
+	#[test]
+	fn nonce_mismatch() {
+		let hash = [0xcd; 32];
+		let answer = |nonce| reply(0, &token(&imprint(SHA256_OID, &hash), nonce));
+		assert!(check_response(&answer(Some(0x1234_5678)), &hash, 0x1234_5678).is_ok());
+		// A reply to some other request, played back
+		assert!(matches!(check_response(&answer(Some(0x1234_5679)), &hash, 0x1234_5678), Err(TsaError::WrongNonce)));
+		assert!(matches!(check_response(&answer(None), &hash, 0x1234_5678), Err(TsaError::WrongNonce)));
+	}
+}
@@ -0,0 +1,252 @@
+//! W3C trace context propagation:  a `traceparent` (and `tracestate`) sent by a client is passed on
+//! to upstream, as the parent of a span of our own for each request we make there, so that a trace
+//! started by a CI runner carries on through the cache to the registry behind it.  Everything we do
+//! for a traced request is logged with its trace ID.  Every request made of upstream for a client
+//! carries the client request's `X-Request-Id` too, traced or not, so that upstream's logs can be
+//! matched up with ours.
+
+use core::time::Duration;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderName;
+use actix_web::http::header::HeaderValue;
+use actix_web::HttpMessage;
+use actix_web::HttpRequest;
+use tracing::field::Empty;
+use tracing::info_span;
+use tracing::Span;
+
+use super::request_id::RequestId;
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+/// Where a request sits in a distributed trace:  the trace it belongs to, and the span that made it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceContext {
+	trace_id: u128,
+	parent_id: u64,
+	flags: u8,
+	/// Vendor-specific data, passed on as it came
+	state: Option<HeaderValue>
+}
+
+impl TraceContext {
+	/// The client's trace context, if it sent a valid `traceparent`.
+	pub fn from_request(req: &ServiceRequest) -> Option<Self> {
+		let mut context = Self::parse(req.headers().get(TRACEPARENT)?.to_str().ok()?)?;
+		context.state = req.headers().get(TRACESTATE).filter(|v| !v.is_empty() && v.len() <= 512).cloned();
+		Some(context)
+	}
+
+	/// Parses a `traceparent` header.  Versions past `00` may add fields after the four we know,
+	/// which are ignored, as the spec asks.
+	fn parse(value: &str) -> Option<Self> {
+		let mut fields = value.trim().split('-');
+		let (version, trace_id, parent_id, flags) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+		let is_hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+		if (!is_hex(version, 2) || version == "ff" || !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2)) {
+			return None;
+		}
+		if (version == "00" && fields.next().is_some()) {
+			return None;
+		}
+		let trace_id = u128::from_str_radix(trace_id, 16).ok().filter(|&id| id != 0)?;
+		let parent_id = u64::from_str_radix(parent_id, 16).ok().filter(|&id| id != 0)?;
+		let flags = u8::from_str_radix(flags, 16).ok()?;
+		Some(Self { trace_id, parent_id, flags, state: None })
+	}
+
+	/// The trace context a handler's request came with, if any.
+	pub fn of(req: &HttpRequest) -> Option<Self> {
+		req.extensions().get::<Self>().cloned()
+	}
+
+	/// A context for a new span in the same trace, with this one as its parent.
+	pub fn child(&self) -> Self {
+		Self { parent_id: rand::random::<u64>().max(1), ..self.clone() }
+	}
+
+	pub fn trace_id(&self) -> String {
+		format!("{:032x}", self.trace_id)
+	}
+
+	pub fn span_id(&self) -> String {
+		format!("{:016x}", self.parent_id)
+	}
+
+	fn traceparent(&self) -> String {
+		format!("00-{}-{}-{:02x}", self.trace_id(), self.span_id(), self.flags)
+	}
+
+	/// The headers that carry this context on to the next hop.
+	pub fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+		let mut headers = Vec::with_capacity(2);
+		if let Ok(value) = HeaderValue::from_str(&self.traceparent()) {
+			headers.push((HeaderName::from_static(TRACEPARENT), value));
+		}
+		if let Some(state) = self.state.as_ref() {
+			headers.push((HeaderName::from_static(TRACESTATE), state.clone()));
+		}
+		headers
+	}
+}
+
+/// How a pull was answered, as far as the cache goes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheDecision {
+	/// From cache, fresh enough to serve as it was
+	Hit,
+	/// From cache, once upstream said the expired tag hadn't moved
+	Revalidated,
+	/// From cache, expired, because upstream was unavailable
+	Stale,
+	/// From upstream
+	Miss
+}
+
+impl CacheDecision {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			Self::Hit => "hit",
+			Self::Revalidated => "revalidated",
+			Self::Stale => "stale",
+			Self::Miss => "miss"
+		}
+	}
+}
+
+/// The span everything done for a client request is logged in.  How a pull was answered is filled
+/// in as it's decided:  the `cache` decision, the `age_ms` of what was served from cache, how many
+/// `upstream_attempts` were made, and the bytes served `bytes_from_cache` or `bytes_from_upstream`.
+pub fn request(request_id: &str, client_ip: &dyn core::fmt::Display) -> Span {
+	info_span!("request", request_id, %client_ip, trace_id = Empty, cache = Empty, age_ms = Empty, upstream_attempts = Empty, bytes_from_cache = Empty, bytes_from_upstream = Empty)
+}
+
+/// Records on the current request's span that it was answered from cache with `bytes` of what was
+/// stored `age` ago.  Pulls the proxy makes in the background, such as prefetches, have no request
+/// span, so nothing's recorded for them.
+pub fn served_from_cache(decision: CacheDecision, age: Option<Duration>, bytes: u64) {
+	let span = Span::current();
+	span.record("cache", decision.as_str());
+	if let Some(age) = age {
+		span.record("age_ms", u64::try_from(age.as_millis()).unwrap_or(u64::MAX));
+	}
+	span.record("bytes_from_cache", bytes);
+}
+
+/// Records on the current request's span how many requests of upstream a pull took.
+pub fn upstream_attempts(attempts: u32) {
+	Span::current().record("upstream_attempts", attempts);
+}
+
+/// Records on the current request's span that it was answered with `bytes` from upstream.
+pub fn served_from_upstream(bytes: u64) {
+	let span = Span::current();
+	span.record("cache", CacheDecision::Miss.as_str());
+	span.record("bytes_from_upstream", bytes);
+}
+
+/// What's passed on to upstream with a request made of it on a client's behalf, to tie the two
+/// together:  the client request's ID, and when it was traced, the context of a span of our own.
+#[derive(Clone, Debug, Default)]
+pub struct Hop {
+	context: Option<TraceContext>,
+	request_id: Option<RequestId>
+}
+
+impl Hop {
+	/// What a request of upstream made for `http_req` passes on.
+	pub fn of(http_req: &HttpRequest) -> Self {
+		Self { context: TraceContext::of(http_req).map(|context| context.child()), request_id: http_req.extensions().get::<RequestId>().cloned() }
+	}
+
+	/// The headers that carry it.
+	fn headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+		let mut headers = self.context.as_ref().map(TraceContext::headers).unwrap_or_default();
+		if let Some(request_id) = self.request_id.as_ref() {
+			headers.push((RequestId::header_name(), request_id.header_value()));
+		}
+		headers
+	}
+}
+
+/// A span for a request of upstream made on behalf of `http_req`, along with what to pass on to
+/// upstream with it.
+pub fn upstream(http_req: Option<&HttpRequest>, namespace: &str) -> (Span, Hop) {
+	let hop = http_req.map(Hop::of).unwrap_or_default();
+	let span = match hop.context.as_ref() {
+		Some(child) => info_span!("upstream", namespace, trace_id = child.trace_id().as_str(), span_id = child.span_id().as_str()),
+		None => info_span!("upstream", namespace)
+	};
+	(span, hop)
+}
+
+/// Adds the headers that tie a request of upstream to the client request it's made for.
+pub fn inject(mut request: reqwest::RequestBuilder, hop: &Hop) -> reqwest::RequestBuilder {
+	for (name, value) in hop.headers() {
+		request = request.header(name, value);
+	}
+	request
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn traceparents() {
+		let context = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+		assert_eq!(context.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+		assert_eq!(context.span_id(), "00f067aa0ba902b7");
+		assert_eq!(context.traceparent(), "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+		let child = context.child();
+		assert_eq!(child.trace_id(), context.trace_id());
+		assert_ne!(child.span_id(), context.span_id());
+		assert_eq!(child.flags, 1);
+
+		// Later versions can add fields; version 00 can't
+		assert!(TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra").is_some());
+		for invalid in [
+			"00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+			"ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+			"00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+			"00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+			"00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+			"00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+			"00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7"
+		] {
+			assert!(TraceContext::parse(invalid).is_none(), "{invalid}");
+		}
+	}
+
+	#[derive(Clone, Default)]
+	struct Buffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+	impl std::io::Write for Buffer {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			std::io::Write::write(&mut *self.0.lock().unwrap(), buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn cache_decisions() {
+		let buffer = Buffer::default();
+		let writer = buffer.clone();
+		let subscriber = tracing_subscriber::fmt().with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE).with_ansi(false).with_writer(move || writer.clone()).finish();
+		tracing::subscriber::with_default(subscriber, || {
+			let _entered = request("abc", &"192.0.2.1").entered();
+			upstream_attempts(2);
+			served_from_cache(CacheDecision::Stale, Some(Duration::from_secs(90)), 512);
+		});
+		let log = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+		for field in ["request_id=\"abc\"", "cache=\"stale\"", "age_ms=90000", "upstream_attempts=2", "bytes_from_cache=512", "close"] {
+			assert!(log.contains(field), "{field} in {log}");
+		}
+		assert!(!log.contains("bytes_from_upstream"));
+	}
+}
@@ -0,0 +1,131 @@
+//! Runtime invalidation times:  a namespace's `manifest_invalidation_time` and
+//! `blob_invalidation_time` can be overridden through `/_admin/ttls`, say to keep serving what's
+//! cached for longer while upstream's having an incident, without a redeploy.  Overrides take effect
+//! from the next request on, cleanup included, and are kept in storage, like runtime pins and
+//! aliases, so that they survive restarts; removing one goes back to the upstream config's.
+
+use core::time::Duration;
+use std::collections::BTreeMap;
+
+use actix_web::web;
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use bytes::BytesMut;
+use compact_str::CompactString;
+use futures::stream::TryStreamExt;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use super::error::Error;
+use super::RequestConfig;
+use crate::storage::Repository;
+use crate::upstream::Clients;
+use crate::upstream::TtlOverride;
+
+/// Where runtime invalidation times are kept.
+const TTLS_OBJECT: &str = "ttls.json";
+
+/// Held while overrides are changed and saved, so that saves land in the order they were made.
+static SAVING: Lazy<Mutex<()>> = Lazy::new(Default::default);
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+	#[error("Failed to read invalidation times from storage: {0}")]
+	Storage(#[from] crate::storage::Error),
+	#[error("Invalid invalidation times in storage: {0}")]
+	Json(#[from] serde_json::Error),
+	#[error("Failed to set up namespace {0}: {1}")]
+	Namespace(CompactString, dkregistry::errors::Error)
+}
+
+async fn read_all(repo: &Repository) -> Result<BytesMut, crate::storage::Error> {
+	let stream = repo.read(TTLS_OBJECT, Duration::MAX).await?;
+	Ok(stream.into_inner().try_collect::<BytesMut>().await?)
+}
+
+/// Applies the invalidation times kept in storage to `clients`.
+pub async fn load(repo: &Repository, clients: &mut Clients) -> Result<(), LoadError> {
+	let overrides: BTreeMap<CompactString, TtlOverride> = match read_all(repo).await {
+		Ok(body) => serde_json::from_slice(body.as_ref())?,
+		Err(e) if e.is_not_found() => return Ok(()),
+		Err(e) => return Err(e.into())
+	};
+	for (namespace, ttl) in overrides {
+		clients.get(&namespace).map_err(|e| LoadError::Namespace(namespace.clone(), e))?;
+		clients.set_ttl_override(&namespace, Some(ttl));
+	}
+	Ok(())
+}
+
+async fn save(repo: &Repository, overrides: &BTreeMap<CompactString, TtlOverride>) -> Result<(), Error> {
+	let body = Bytes::from(serde_json::to_vec(overrides)?);
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	repo.write(TTLS_OBJECT, futures::stream::iter(std::iter::once(Result::<_, std::io::Error>::Ok(body))), len).await?;
+	Ok(())
+}
+
+/// A namespace's invalidation times as they stand, and what's overriding them, if anything.
+#[derive(Debug, Serialize)]
+struct Ttls {
+	namespace: CompactString,
+	manifest_invalidation_time: String,
+	blob_invalidation_time: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	overridden: Option<TtlOverride>
+}
+
+fn ttls(clients: &mut Clients, namespace: &str) -> Result<Ttls, Error> {
+	let overridden = clients.ttl_overrides().get(namespace).cloned();
+	let client = clients.get(namespace)?;
+	Ok(Ttls {
+		namespace: namespace.into(),
+		manifest_invalidation_time: humantime::format_duration(client.manifest_invalidation_time).to_string(),
+		blob_invalidation_time: humantime::format_duration(client.blob_invalidation_time).to_string(),
+		overridden
+	})
+}
+
+/// Every namespace's overrides.
+pub async fn list(config: web::Data<RequestConfig>) -> HttpResponse {
+	let clients = config.upstream.lock().await;
+	HttpResponse::Ok().json(clients.ttl_overrides().iter().collect::<BTreeMap<_, _>>())
+}
+
+pub async fn get(namespace: web::Path<String>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	Ok(HttpResponse::Ok().json(ttls(&mut *config.upstream.lock().await, &namespace)?))
+}
+
+pub async fn set(namespace: web::Path<String>, qstr: web::Query<TtlOverride>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let ttl = qstr.into_inner();
+	if (ttl == TtlOverride::default()) {
+		return Err(Error::TtlUnset);
+	}
+	update(&config, &namespace, Some(ttl)).await
+}
+
+pub async fn remove(namespace: web::Path<String>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	update(&config, &namespace, None).await
+}
+
+/// Saves the overrides with `namespace`'s changed, then applies them.
+async fn update(config: &RequestConfig, namespace: &str, ttl: Option<TtlOverride>) -> Result<HttpResponse, Error> {
+	let _saving = SAVING.lock().await;
+	let mut overrides = {
+		let mut clients = config.upstream.lock().await;
+		// Only for namespaces there's an upstream for
+		clients.get(namespace)?;
+		clients.ttl_overrides().iter().map(|(k, v)| (k.clone(), v.clone())).collect::<BTreeMap<_, _>>()
+	};
+	match ttl.clone() {
+		Some(ttl) => overrides.insert(namespace.into(), ttl),
+		None => overrides.remove(namespace)
+	};
+	save(&config.repo, &overrides).await?;
+	let mut clients = config.upstream.lock().await;
+	clients.set_ttl_override(namespace, ttl);
+	let ttls = ttls(&mut clients, namespace)?;
+	warn!(namespace, manifest_invalidation_time = ttls.manifest_invalidation_time.as_str(), blob_invalidation_time = ttls.blob_invalidation_time.as_str(), "Invalidation times changed");
+	Ok(HttpResponse::Ok().json(ttls))
+}
@@ -0,0 +1,66 @@
+//! Upstream overrides:  a client presenting `--upstream-override-token` in `X-Oci-Upstream-Token`
+//! can name another upstream in `X-Oci-Upstream` for its request to go to, instead of the one
+//! configured for the namespace, to try out a new mirror or to route around one that's down without
+//! rolling out new configuration.  What's fetched from there is cached for the namespace as usual,
+//! so it has to pass the namespace's signature checks and is rewritten by its rules, not the other
+//! upstream's.
+
+use actix_web::HttpRequest;
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use tracing::info;
+
+use super::error::Error;
+use super::webhook::tokens_match;
+
+static OVERRIDES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_overrides", "Number of requests sent to another upstream than their namespace's by X-Oci-Upstream", &["namespace", "upstream"]).unwrap());
+
+/// Names the upstream to go to instead
+pub const HEADER: &str = "x-oci-upstream";
+/// Carries the token that allows it
+pub const TOKEN_HEADER: &str = "x-oci-upstream-token";
+
+/// The upstream `http_req` asks to go to instead of `namespace`'s, if any; asking without the
+/// right token, or with overrides disabled, is refused rather than ignored, so that nobody takes
+/// a response from the usual upstream for one from the mirror they're testing.
+pub(super) fn requested(expected: Option<&str>, http_req: Option<&HttpRequest>, namespace: &str) -> Result<Option<CompactString>, Error> {
+	let Some(http_req) = http_req else {
+		return Ok(None);
+	};
+	let Some(upstream) = http_req.headers().get(HEADER) else {
+		return Ok(None);
+	};
+	let token = http_req.headers().get(TOKEN_HEADER).map(|v| v.as_bytes());
+	match (expected, token) {
+		(Some(expected), Some(token)) if tokens_match(token, expected.as_bytes()) => (),
+		_ => return Err(Error::UpstreamOverrideDenied)
+	}
+	let upstream = upstream.to_str().ok().map(str::trim).filter(|u| !u.is_empty() && !u.contains('/')).ok_or(Error::UpstreamOverrideDenied)?;
+	info!(namespace, upstream, "Request sent to overridden upstream");
+	OVERRIDES.with_label_values(&[namespace, upstream]).inc();
+	Ok(Some(upstream.into()))
+}
+
+#[cfg(test)]
+mod tests {
+	use actix_web::test::TestRequest;
+
+	use super::*;
+
+	#[test]
+	fn needs_the_token() {
+		let plain = TestRequest::default().to_http_request();
+		assert!(requested(Some("secret"), Some(&plain), "docker.io").unwrap().is_none());
+		assert!(requested(Some("secret"), None, "docker.io").unwrap().is_none());
+
+		let untrusted = TestRequest::default().insert_header((HEADER, "mirror.gcr.io")).to_http_request();
+		assert!(requested(Some("secret"), Some(&untrusted), "docker.io").is_err());
+		let wrong = TestRequest::default().insert_header((HEADER, "mirror.gcr.io")).insert_header((TOKEN_HEADER, "guess")).to_http_request();
+		assert!(requested(Some("secret"), Some(&wrong), "docker.io").is_err());
+		let trusted = TestRequest::default().insert_header((HEADER, "mirror.gcr.io")).insert_header((TOKEN_HEADER, "secret")).to_http_request();
+		assert!(requested(None, Some(&trusted), "docker.io").is_err());
+		assert_eq!(requested(Some("secret"), Some(&trusted), "docker.io").unwrap().as_deref(), Some("mirror.gcr.io"));
+	}
+}
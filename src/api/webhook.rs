@@ -0,0 +1,207 @@
+//! Webhooks:  push notifications from Docker Hub, Harbor, and GitHub (for GHCR) that a tag has
+//! moved, so that our copy of it can be dropped (and optionally pulled again) right away, rather
+//! than served until it expires.
+
+use core::str::FromStr;
+
+use actix_web::body;
+use actix_web::http::header;
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use serde::Deserialize;
+use tracing::info;
+use tracing::warn;
+
+use super::error::Error;
+use super::manifest_storage_dir;
+use super::serve_manifest;
+use super::Access;
+use super::ManifestRequest;
+use super::RequestConfig;
+use crate::image::ImageName;
+use crate::image::ImageReference;
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookQuery {
+	/// For senders that can't set headers, like Docker Hub
+	token: Option<String>,
+	/// Pull the tag again right away, instead of waiting for a client to ask for it
+	#[serde(default)]
+	refresh: bool
+}
+
+/// The payloads we understand, told apart by their shape.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+	DockerHub { push_data: DockerHubPush, repository: DockerHubRepository },
+	Harbor { event_data: HarborEvent },
+	Github { package: GithubPackage }
+}
+
+#[derive(Debug, Deserialize)]
+struct DockerHubPush {
+	tag: String
+}
+
+#[derive(Debug, Deserialize)]
+struct DockerHubRepository {
+	repo_name: String
+}
+
+#[derive(Debug, Deserialize)]
+struct HarborEvent {
+	repository: HarborRepository,
+	#[serde(default)]
+	resources: Vec<HarborResource>
+}
+
+#[derive(Debug, Deserialize)]
+struct HarborRepository {
+	repo_full_name: String
+}
+
+#[derive(Debug, Deserialize)]
+struct HarborResource {
+	#[serde(default)]
+	tag: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubPackage {
+	name: String,
+	owner: GithubOwner,
+	package_version: GithubPackageVersion
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubOwner {
+	login: String
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubPackageVersion {
+	container_metadata: GithubContainerMetadata
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubContainerMetadata {
+	tag: GithubTag
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubTag {
+	name: String
+}
+
+impl Payload {
+	/// The upstream image named in the payload, and the tags that moved.
+	fn tags(self) -> (String, Vec<String>) {
+		let (image, tags) = match self {
+			Self::DockerHub { push_data, repository } => (repository.repo_name, vec![push_data.tag]),
+			Self::Harbor { event_data } => (event_data.repository.repo_full_name, event_data.resources.into_iter().filter_map(|r| r.tag).collect()),
+			// GHCR's repository names are the lowercase owner and package name
+			Self::Github { package } => (format!("{}/{}", package.owner.login, package.name).to_ascii_lowercase(), vec![package.package_version.container_metadata.tag.name])
+		};
+		// Untagged pushes have nothing cached under a tag to invalidate
+		(image, tags.into_iter().filter(|t| !t.is_empty()).collect())
+	}
+}
+
+/// Compares tokens without giving away how much of one matched through timing.
+pub(super) fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn authorized(expected: &str, http_req: &HttpRequest, qstr: &WebhookQuery) -> bool {
+	let header = http_req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()).map(|v| v.strip_prefix("Bearer ").unwrap_or(v));
+	[qstr.token.as_deref(), header].into_iter().flatten().any(|token| tokens_match(token.as_bytes(), expected.as_bytes()))
+}
+
+/// Drops our copy of each tag named in a webhook payload from the upstream for `namespace`, so that
+/// the next pull of it goes to upstream.  Only the shared cache is touched; copies cached for
+/// individual credentials expire as usual.
+pub async fn receive(http_req: HttpRequest, namespace: web::Path<CompactString>, qstr: web::Query<WebhookQuery>, payload: web::Bytes, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	static INVALIDATIONS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("webhook_invalidations", "Number of cached tags invalidated by upstream webhooks", &["namespace"]).unwrap());
+
+	let Some(expected) = config.webhook_token.as_deref() else {
+		return Ok(HttpResponse::NotFound().finish());
+	};
+	if (!authorized(expected, &http_req, &qstr)) {
+		return Err(Error::Unauthorized(None));
+	}
+	let namespace = namespace.into_inner();
+	let (upstream_image, tags) = serde_json::from_slice::<Payload>(&payload)?.tags();
+	let images = config.upstream.lock().await.get(&namespace)?.local_images(&upstream_image);
+
+	let mut invalidated = Vec::new();
+	for image in &images {
+		let dir = manifest_storage_dir(&namespace, image, &Access::Shared);
+		for tag in &tags {
+			match config.repo.delete_manifest(&format!("{dir}/{tag}")).await {
+				Ok(()) => (),
+				Err(e) if e.is_not_found() => (),
+				Err(error) => {
+					warn!(namespace = namespace.as_str(), image = image.as_str(), tag = tag.as_str(), %error, "Failed to invalidate cached tag");
+					continue;
+				}
+			};
+			INVALIDATIONS.with_label_values(&[namespace.as_str()]).inc();
+			if let Some(cdn) = &config.cdn {
+				cdn.purge_manifest(&config.base_path, &config.default_ns, &namespace, image, tag);
+			}
+			invalidated.push(format!("{namespace}/{image}:{tag}"));
+			if (qstr.refresh) {
+				refresh(&config, &namespace, image, tag).await;
+			}
+		}
+	}
+	info!(namespace = namespace.as_str(), image = upstream_image.as_str(), ?tags, "Invalidated tags from webhook");
+	Ok(HttpResponse::Ok().json(serde_json::json!({ "invalidated": invalidated })))
+}
+
+async fn refresh(config: &RequestConfig, namespace: &str, image: &str, tag: &str) {
+	let (Ok(image_name), Ok(reference)) = (ImageName::from_str(image), ImageReference::from_str(tag)) else {
+		return;
+	};
+	let result = match serve_manifest(config, &ManifestRequest { image: image_name, reference }, Some(namespace), None).await {
+		Ok(response) => body::to_bytes(response.into_body()).await.map(drop).map_err(|e| e.to_string()),
+		Err(error) => Err(error.to_string())
+	};
+	if let Err(error) = result {
+		warn!(namespace, image, tag, %error, "Failed to refresh tag from webhook");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_payloads() {
+		let docker_hub = r#"{"callback_url":"https://registry.hub.docker.com/u/svendowideit/testhook/hook/2141b5bi5i5b02bec211i4eeih0242eg11000a/","push_data":{"pushed_at":1417566161,"pusher":"trustedbuilder","tag":"latest"},"repository":{"name":"testhook","namespace":"svendowideit","repo_name":"svendowideit/testhook"}}"#;
+		assert_eq!(serde_json::from_str::<Payload>(docker_hub).unwrap().tags(), ("svendowideit/testhook".to_owned(), vec!["latest".to_owned()]));
+
+		let harbor = r#"{"type":"PUSH_ARTIFACT","occur_at":1680501893,"operator":"admin","event_data":{"resources":[{"digest":"sha256:954b378c375d852eb3c63ab88978f640b4348b01c1b3456a024a81536dafbbf4","tag":"v1.2","resource_url":"harbor.example.com/project/app:v1.2"}],"repository":{"date_created":1680501893,"name":"app","namespace":"project","repo_full_name":"project/app","repo_type":"private"}}}"#;
+		assert_eq!(serde_json::from_str::<Payload>(harbor).unwrap().tags(), ("project/app".to_owned(), vec!["v1.2".to_owned()]));
+
+		let github = r#"{"action":"published","package":{"name":"App","package_type":"CONTAINER","owner":{"login":"Example"},"package_version":{"version":"sha256:954b378c375d852eb3c63ab88978f640b4348b01c1b3456a024a81536dafbbf4","container_metadata":{"tag":{"name":"1.0","digest":"sha256:954b378c375d852eb3c63ab88978f640b4348b01c1b3456a024a81536dafbbf4"}}}}}"#;
+		assert_eq!(serde_json::from_str::<Payload>(github).unwrap().tags(), ("example/app".to_owned(), vec!["1.0".to_owned()]));
+
+		let untagged = r#"{"package":{"name":"app","owner":{"login":"example"},"package_version":{"container_metadata":{"tag":{"name":"","digest":"sha256:954b378c375d852eb3c63ab88978f640b4348b01c1b3456a024a81536dafbbf4"}}}}}"#;
+		assert!(serde_json::from_str::<Payload>(untagged).unwrap().tags().1.is_empty());
+		assert!(serde_json::from_str::<Payload>(r#"{"hello":"world"}"#).is_err());
+	}
+
+	#[test]
+	fn token_comparison() {
+		assert!(tokens_match(b"secret", b"secret"));
+		assert!(!tokens_match(b"secret", b"secreT"));
+		assert!(!tokens_match(b"secret", b"secrets"));
+	}
+}
@@ -0,0 +1,297 @@
+use core::time::Duration;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Instant;
+
+use futures::stream::StreamExt;
+use reqwest::header::ACCEPT;
+use reqwest::Method;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::api::sampling::Sample as Recorded;
+use crate::command::BenchConfig;
+
+const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.index.v1+json, application/vnd.docker.distribution.manifest.list.v2+json, application/vnd.oci.image.manifest.v1+json, application/vnd.docker.distribution.manifest.v2+json";
+
+const CACHE_METRICS: [&str; 4] = ["manifest_cache_hits", "manifest_cache_misses", "blob_cache_hits", "blob_cache_misses"];
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+	digest: String,
+	#[serde(default)]
+	platform: Option<Platform>
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+	os: String,
+	architecture: String
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+	#[serde(default)]
+	manifests: Vec<Descriptor>,
+	#[serde(default)]
+	config: Option<Descriptor>,
+	#[serde(default)]
+	layers: Vec<Descriptor>
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum Kind {
+	Manifest,
+	Blob,
+	/// Tag lists, referrers, and the like, which only come up in replays
+	Other
+}
+
+#[derive(Debug)]
+struct Sample {
+	kind: Kind,
+	latency: Duration,
+	bytes: u64,
+	ok: bool
+}
+
+/// Splits `name:tag` or `name@digest` into the name and the reference.
+fn parse_image(image: &str) -> (&str, &str) {
+	if let Some((name, digest)) = image.split_once('@') {
+		return (name, digest);
+	}
+	match image.rsplit_once(':') {
+		Some((name, tag)) if !tag.contains('/') => (name, tag),
+		_ => (image, "latest")
+	}
+}
+
+/// What a registry path asks for.
+fn kind(path: &str) -> Kind {
+	let path = path.split('?').next().unwrap_or(path);
+	match path.rsplit_once('/').map(|(path, _)| path) {
+		Some(path) if path.ends_with("/manifests") => Kind::Manifest,
+		Some(path) if path.ends_with("/blobs") => Kind::Blob,
+		_ => Kind::Other
+	}
+}
+
+async fn get(http: &reqwest::Client, method: Method, url: &str, kind: Kind, samples: &mut Vec<Sample>) -> Option<bytes::Bytes> {
+	let start = Instant::now();
+	let mut request = http.request(method, url);
+	if (kind == Kind::Manifest) {
+		request = request.header(ACCEPT, MANIFEST_ACCEPT);
+	}
+	let result = async {
+		let response = request.send().await?.error_for_status()?;
+		match kind {
+			Kind::Manifest | Kind::Other => Ok::<_, reqwest::Error>((response.bytes().await?, None)),
+			Kind::Blob => {
+				let mut bytes = 0;
+				let mut stream = response.bytes_stream();
+				while let Some(chunk) = stream.next().await {
+					bytes += chunk?.len() as u64;
+				}
+				Ok((bytes::Bytes::new(), Some(bytes)))
+			}
+		}
+	}
+	.await;
+	let latency = start.elapsed();
+	match result {
+		Ok((body, streamed)) => {
+			samples.push(Sample { kind, latency, bytes: streamed.unwrap_or(body.len() as u64), ok: true });
+			Some(body)
+		},
+		Err(error) => {
+			warn!(url, %error, "Request failed");
+			samples.push(Sample { kind, latency, bytes: 0, ok: false });
+			None
+		}
+	}
+}
+
+/// Pulls an image the way a container runtime would:  manifest (resolving an index to a single
+/// platform), then config and layers.
+async fn pull(http: &reqwest::Client, target: &str, image: &str, samples: &mut Vec<Sample>) {
+	let (name, reference) = parse_image(image);
+	let Some(body) = get(http, Method::GET, &format!("{target}/v2/{name}/manifests/{reference}"), Kind::Manifest, samples).await else {
+		return;
+	};
+	let Ok(mut manifest) = serde_json::from_slice::<Manifest>(body.as_ref()) else {
+		warn!(image, "Unparseable manifest");
+		return;
+	};
+	if (!manifest.manifests.is_empty()) {
+		let chosen = manifest.manifests.iter().find(|m| m.platform.as_ref().is_some_and(|p| p.os == "linux" && p.architecture == "amd64")).unwrap_or(&manifest.manifests[0]);
+		let Some(body) = get(http, Method::GET, &format!("{target}/v2/{name}/manifests/{}", chosen.digest), Kind::Manifest, samples).await else {
+			return;
+		};
+		manifest = match serde_json::from_slice(body.as_ref()) {
+			Ok(v) => v,
+			Err(_) => {
+				warn!(image, "Unparseable manifest");
+				return;
+			}
+		};
+	}
+	for blob in manifest.config.iter().chain(manifest.layers.iter()) {
+		get(http, Method::GET, &format!("{target}/v2/{name}/blobs/{}", blob.digest), Kind::Blob, samples).await;
+	}
+}
+
+/// Sums every series of each cache metric we care about out of a Prometheus text exposition.
+async fn cache_metrics(http: &reqwest::Client, target: &str) -> Option<HashMap<&'static str, f64>> {
+	let body = http.get(format!("{target}/metrics")).send().await.ok()?.text().await.ok()?;
+	let mut totals = HashMap::new();
+	for line in body.lines().filter(|l| !l.starts_with('#')) {
+		let Some((series, value)) = line.rsplit_once(' ') else {
+			continue;
+		};
+		let name = series.split('{').next().unwrap_or(series);
+		if let (Some(metric), Ok(value)) = (CACHE_METRICS.iter().find(|m| **m == name), value.parse::<f64>()) {
+			*totals.entry(*metric).or_insert(0.0) += value;
+		}
+	}
+	Some(totals)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+	if (sorted.is_empty()) {
+		return Duration::ZERO;
+	}
+	let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+	sorted[index.min(sorted.len() - 1)]
+}
+
+fn hit_ratio(before: &HashMap<&str, f64>, after: &HashMap<&str, f64>, hits: &str, misses: &str) -> String {
+	let delta = |k: &str| after.get(k).copied().unwrap_or(0.0) - before.get(k).copied().unwrap_or(0.0);
+	let (hits, misses) = (delta(hits), delta(misses));
+	match hits + misses {
+		total if total > 0.0 => format!("{:.1}% ({hits} hits, {misses} misses)", hits / total * 100.0),
+		_ => "n/a".into()
+	}
+}
+
+/// Reads a `--request-sample-file`, in the order its requests came in, skipping anything that
+/// isn't a sample.
+fn read_samples(path: &Path) -> std::io::Result<Vec<Recorded>> {
+	let mut recorded = std::fs::read_to_string(path)?.lines().filter_map(|line| serde_json::from_str::<Recorded>(line).ok()).collect::<Vec<_>>();
+	recorded.sort_by_key(|r| r.at_ms);
+	Ok(recorded)
+}
+
+/// Sends sampled requests again, `speed` times as fast as they first came in (as fast as they
+/// can be sent at `0`), with at most `clients` at once.
+async fn replay(http: &reqwest::Client, target: &str, recorded: Vec<Recorded>, speed: f64, clients: usize) -> Vec<Sample> {
+	let first = recorded.first().map(|r| r.at_ms).unwrap_or_default();
+	let start = tokio::time::Instant::now();
+	futures::stream::iter(recorded)
+		.map(|recorded| async move {
+			let mut samples = Vec::new();
+			let Ok(method) = Method::from_bytes(recorded.method.as_bytes()) else {
+				return samples;
+			};
+			if (speed > 0.0) {
+				tokio::time::sleep_until(start + Duration::from_millis(recorded.at_ms - first).div_f64(speed)).await;
+			}
+			get(http, method, &format!("{target}{}", recorded.path), kind(&recorded.path), &mut samples).await;
+			samples
+		})
+		.buffer_unordered(clients.max(1))
+		.flat_map(futures::stream::iter)
+		.collect()
+		.await
+}
+
+pub async fn run(config: &BenchConfig) {
+	let target = config.target.trim_end_matches('/');
+	let http = reqwest::Client::new();
+	let recorded = match &config.replay {
+		Some(path) => match read_samples(path) {
+			Ok(v) => Some(v),
+			Err(error) => {
+				println!("Failed to read {}: {error}", path.display());
+				return;
+			}
+		},
+		None => None
+	};
+	let before = cache_metrics(&http, target).await;
+
+	let start = Instant::now();
+	let samples = match recorded {
+		Some(recorded) => replay(&http, target, recorded, config.speed, config.clients).await,
+		None => {
+			let clients = (0..config.clients).map(|_| {
+				let http = http.clone();
+				async move {
+					let mut samples = Vec::new();
+					for _ in 0..config.iterations {
+						for image in config.images.iter() {
+							pull(&http, target, image, &mut samples).await;
+						}
+					}
+					samples
+				}
+			});
+			futures::future::join_all(clients).await.into_iter().flatten().collect::<Vec<_>>()
+		}
+	};
+	let elapsed = start.elapsed();
+
+	let total_bytes: u64 = samples.iter().map(|s| s.bytes).sum();
+	let failures = samples.iter().filter(|s| !s.ok).count();
+	println!("{} requests in {}, {failures} failed", samples.len(), humantime::format_duration(Duration::from_millis(elapsed.as_millis() as u64)));
+	println!("Throughput: {:.1} requests/s, {:.2} MiB/s", samples.len() as f64 / elapsed.as_secs_f64(), total_bytes as f64 / 1048576.0 / elapsed.as_secs_f64());
+	for kind in [Kind::Manifest, Kind::Blob, Kind::Other] {
+		let mut latencies = samples.iter().filter(|s| s.kind == kind && s.ok).map(|s| s.latency).collect::<Vec<_>>();
+		if (kind == Kind::Other && latencies.is_empty()) {
+			continue;
+		}
+		latencies.sort_unstable();
+		println!(
+			"{kind:?} latency:  p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+			percentile(&latencies, 0.5),
+			percentile(&latencies, 0.9),
+			percentile(&latencies, 0.99),
+			latencies.last().copied().unwrap_or_default()
+		);
+	}
+	match (before, cache_metrics(&http, target).await) {
+		(Some(before), Some(after)) => {
+			println!("Manifest hit ratio: {}", hit_ratio(&before, &after, "manifest_cache_hits", "manifest_cache_misses"));
+			println!("Blob hit ratio: {}", hit_ratio(&before, &after, "blob_cache_hits", "blob_cache_misses"));
+		},
+		_ => println!("Hit ratios unavailable; couldn't read {target}/metrics")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_images() {
+		assert_eq!(parse_image("docker.io/library/busybox:1.36"), ("docker.io/library/busybox", "1.36"));
+		assert_eq!(parse_image("localhost:5000/foo"), ("localhost:5000/foo", "latest"));
+		assert_eq!(parse_image("foo@sha256:abcd"), ("foo", "sha256:abcd"));
+	}
+
+	#[test]
+	fn percentiles() {
+		let latencies = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+		assert_eq!(percentile(&latencies, 0.5), Duration::from_millis(51));
+		assert_eq!(percentile(&latencies, 0.99), Duration::from_millis(99));
+		assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+	}
+
+	#[test]
+	fn replayed_kinds() {
+		assert_eq!(kind("/v2/library/redis/manifests/7?ns=docker.io"), Kind::Manifest);
+		assert_eq!(kind("/v2/library/redis/blobs/sha256:abcd"), Kind::Blob);
+		assert_eq!(kind("/v2/library/redis/tags/list"), Kind::Other);
+		assert_eq!(kind("/v2/manifests/tags/list"), Kind::Other);
+		assert_eq!(kind("/v2/"), Kind::Other);
+	}
+}
@@ -0,0 +1,92 @@
+//! Fault injection, for exercising retries, circuit breakers, stale serving, and digest repair
+//! without having to break a real upstream or disk.  Only compiled in with the `chaos` feature;
+//! without it, every hook here is a no-op.
+
+#[cfg(feature = "chaos")]
+use core::time::Duration;
+
+use bytes::Bytes;
+#[cfg(feature = "chaos")]
+use clap::Parser;
+use futures::stream::LocalBoxStream;
+
+#[cfg(feature = "chaos")]
+static CONFIG: once_cell::sync::OnceCell<ChaosConfig> = once_cell::sync::OnceCell::new();
+
+#[cfg(feature = "chaos")]
+#[derive(Clone, Debug, Default, Parser)]
+pub struct ChaosConfig {
+	/// Fraction of requests to upstream, between 0 and 1, that fail as though upstream had answered
+	/// 503 Service Unavailable
+	#[clap(env = "CHAOS_UPSTREAM_FAILURE_RATE", long = "chaos-upstream-failure-rate", default_value_t = 0.0)]
+	pub upstream_failure_rate: f64,
+	/// Delay added to every write to storage
+	#[clap(env = "CHAOS_STORAGE_WRITE_DELAY", long = "chaos-storage-write-delay", value_parser = humantime::parse_duration, default_value = "0s")]
+	pub storage_write_delay: Duration,
+	/// Fraction of blobs fetched from upstream, between 0 and 1, that have a byte flipped before
+	/// their digest is checked
+	#[clap(env = "CHAOS_CORRUPT_BLOB_RATE", long = "chaos-corrupt-blob-rate", default_value_t = 0.0)]
+	pub corrupt_blob_rate: f64
+}
+
+#[cfg(feature = "chaos")]
+impl ChaosConfig {
+	fn is_enabled(&self) -> bool {
+		self.upstream_failure_rate > 0.0 || !self.storage_write_delay.is_zero() || self.corrupt_blob_rate > 0.0
+	}
+}
+
+/// Installs the fault injection configuration; only the first call has any effect.
+#[cfg(feature = "chaos")]
+pub fn init(config: ChaosConfig) {
+	if (config.is_enabled()) {
+		tracing::warn!(?config, "Fault injection is enabled; do not run this in production");
+	}
+	let _ = CONFIG.set(config);
+}
+
+#[cfg(feature = "chaos")]
+fn roll(rate: impl FnOnce(&ChaosConfig) -> f64) -> bool {
+	CONFIG.get().is_some_and(|c| rand::random::<f64>() < rate(c))
+}
+
+/// Called before each request to upstream; fails it some of the time.
+pub fn upstream_request() -> Result<(), dkregistry::errors::Error> {
+	#[cfg(feature = "chaos")]
+	if (roll(|c| c.upstream_failure_rate)) {
+		tracing::warn!("Chaos:  failing request to upstream");
+		return Err(dkregistry::errors::Error::UnexpectedHttpStatus(actix_web::http::StatusCode::SERVICE_UNAVAILABLE));
+	}
+	Ok(())
+}
+
+/// Called before each write to storage; slows it down.
+pub async fn storage_write() {
+	#[cfg(feature = "chaos")]
+	if let Some(delay) = CONFIG.get().map(|c| c.storage_write_delay).filter(|d| !d.is_zero()) {
+		tokio::time::sleep(delay).await;
+	}
+}
+
+/// Wraps a blob body fetched from upstream; corrupts its first byte some of the time.
+pub fn upstream_blob<E: 'static>(stream: LocalBoxStream<'static, Result<Bytes, E>>) -> LocalBoxStream<'static, Result<Bytes, E>> {
+	#[cfg(feature = "chaos")]
+	if (roll(|c| c.corrupt_blob_rate)) {
+		use futures::stream::StreamExt;
+		use futures::stream::TryStreamExt;
+		tracing::warn!("Chaos:  corrupting blob from upstream");
+		let mut corrupted = false;
+		return stream
+			.map_ok(move |chunk| {
+				if (corrupted || chunk.is_empty()) {
+					return chunk;
+				}
+				corrupted = true;
+				let mut chunk = bytes::BytesMut::from(chunk.as_ref());
+				chunk[0] ^= 0xff;
+				chunk.freeze()
+			})
+			.boxed_local();
+	}
+	stream
+}
@@ -0,0 +1,154 @@
+//! Client configuration:  containerd `hosts.toml` files and a dockerd `daemon.json` that point
+//! clients at this instance for each configured upstream, addressed the way this instance routes
+//! requests to namespaces — by the listener port configured for a namespace, if there is one, and
+//! otherwise by the `ns` parameter containerd sends with mirrored requests.  Docker only mirrors
+//! Docker Hub, and doesn't send `ns`, so it needs an address that serves `docker.io` by default.
+
+use std::path::Path;
+
+use compact_str::CompactString;
+use reqwest::Url;
+use tracing::info;
+use tracing::warn;
+
+use crate::api::is_registry_host;
+use crate::api::ListenerNamespace;
+use crate::command::ClientConfig;
+
+/// How this instance is addressed, as configured for serving.
+#[derive(Debug)]
+pub struct Routing<'a> {
+	pub default_namespace: &'a str,
+	pub listener_namespaces: &'a [ListenerNamespace],
+	/// As returned by [`crate::api::normalize_base_path`]
+	pub base_path: &'a str
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("Invalid proxy URL {0:?}; expected something like http://registry-cache.internal:5000")]
+	InvalidProxyUrl(String),
+	#[error("Failed to write {0}: {1}")]
+	Io(String, std::io::Error)
+}
+
+impl Routing<'_> {
+	/// The URL clients should reach `namespace` at, with any path prefix but no `/v2`.
+	fn mirror_url(&self, proxy: &Url, namespace: &str) -> Url {
+		let mut url = proxy.clone();
+		if let Some(listener) = self.listener_namespaces.iter().find(|l| l.namespace == namespace) {
+			// Only fails for URLs that can't have a port, which we've already ruled out
+			let _ = url.set_port(Some(listener.address.port()));
+		}
+		url.set_path(self.base_path);
+		url
+	}
+
+	/// The URL requests that don't name a namespace, as Docker's don't, should be sent to for them
+	/// to end up at `namespace`, if there is one.
+	fn default_url(&self, proxy: &Url, namespace: &str) -> Option<Url> {
+		let listened = self.listener_namespaces.iter().any(|l| l.namespace == namespace);
+		(listened || self.default_namespace == namespace).then(|| self.mirror_url(proxy, namespace))
+	}
+}
+
+/// Where containerd should fall back to for `namespace` when the mirror can't be reached.
+fn server(namespace: &str) -> String {
+	match namespace {
+		"docker.io" => "https://registry-1.docker.io".into(),
+		host => format!("https://{host}")
+	}
+}
+
+/// The `certs.d/<namespace>/hosts.toml` that sends containerd's pulls from `namespace` to us.
+pub fn hosts_toml(routing: &Routing<'_>, proxy: &Url, namespace: &str) -> String {
+	let url = routing.mirror_url(proxy, namespace);
+	// containerd only appends `/v2` to a host URL without a path of its own
+	let (host, override_path) = match url.path().trim_end_matches('/') {
+		"" => (url.as_str().trim_end_matches('/').to_owned(), false),
+		path => (format!("{}{path}/v2", url.origin().ascii_serialization()), true)
+	};
+	let mut toml = format!("server = \"{}\"\n\n[host.\"{host}\"]\n  capabilities = [\"pull\", \"resolve\"]\n", server(namespace));
+	if (override_path) {
+		toml.push_str("  override_path = true\n");
+	}
+	toml
+}
+
+/// The `daemon.json` that sends dockerd's pulls from Docker Hub to us, if some address of ours
+/// serves Docker Hub by default.
+pub fn daemon_json(routing: &Routing<'_>, proxy: &Url) -> Option<String> {
+	let url = routing.default_url(proxy, "docker.io")?;
+	let mut json = serde_json::json!({ "registry-mirrors": [url.as_str().trim_end_matches('/')] });
+	if (url.scheme() == "http") {
+		let host = match url.port_or_known_default() {
+			Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+			None => url.host_str().unwrap_or_default().to_owned()
+		};
+		json["insecure-registries"] = serde_json::json!([host]);
+	}
+	serde_json::to_string_pretty(&json).ok()
+}
+
+fn emit(output_dir: Option<&Path>, path: &str, contents: &str) -> Result<(), Error> {
+	match output_dir {
+		Some(dir) => {
+			let path = dir.join(path);
+			let write = || {
+				if let Some(parent) = path.parent() {
+					std::fs::create_dir_all(parent)?;
+				}
+				std::fs::write(&path, contents)
+			};
+			write().map_err(|e| Error::Io(path.display().to_string(), e))?;
+			info!(path = %path.display(), "Wrote client configuration");
+		},
+		None => println!("# {path}\n{contents}\n")
+	};
+	Ok(())
+}
+
+/// Writes the configuration for each of `namespaces` to the output directory, or to stdout.
+pub fn run(config: &ClientConfig, routing: &Routing<'_>, mut namespaces: Vec<CompactString>) -> Result<(), Error> {
+	let proxy = Url::parse(&config.proxy_url).ok().filter(|u| matches!(u.scheme(), "http" | "https") && u.host_str().is_some()).ok_or_else(|| Error::InvalidProxyUrl(config.proxy_url.clone()))?;
+	if (!config.namespaces.is_empty()) {
+		namespaces = config.namespaces.clone();
+	}
+	namespaces.sort();
+	namespaces.dedup();
+	let output_dir = config.output_dir.as_deref();
+	for namespace in &namespaces {
+		// containerd picks a hosts.toml by the registry named in the image, which these can't be
+		if (!is_registry_host(namespace)) {
+			warn!(namespace = namespace.as_str(), "Namespace isn't a registry hostname, so containerd can't be pointed at it; skipping");
+			continue;
+		}
+		emit(output_dir, &format!("certs.d/{namespace}/hosts.toml"), &hosts_toml(routing, &proxy, namespace))?;
+	}
+	match daemon_json(routing, &proxy) {
+		Some(json) => emit(output_dir, "daemon.json", &json)?,
+		None => warn!("Nothing serves docker.io by default, which dockerd needs of a mirror; set --default-namespace or --listen-namespace for it to get a daemon.json")
+	};
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn generated() {
+		let listeners = ["0.0.0.0:5002=ghcr.io".parse::<ListenerNamespace>().unwrap()];
+		let routing = Routing { default_namespace: "docker.io", listener_namespaces: &listeners, base_path: "" };
+		let proxy = Url::parse("http://cache.internal:5000").unwrap();
+		assert_eq!(hosts_toml(&routing, &proxy, "docker.io"), "server = \"https://registry-1.docker.io\"\n\n[host.\"http://cache.internal:5000\"]\n  capabilities = [\"pull\", \"resolve\"]\n");
+		assert_eq!(hosts_toml(&routing, &proxy, "ghcr.io"), "server = \"https://ghcr.io\"\n\n[host.\"http://cache.internal:5002\"]\n  capabilities = [\"pull\", \"resolve\"]\n");
+		let json: serde_json::Value = serde_json::from_str(&daemon_json(&routing, &proxy).unwrap()).unwrap();
+		assert_eq!(json, serde_json::json!({ "registry-mirrors": ["http://cache.internal:5000"], "insecure-registries": ["cache.internal:5000"] }));
+
+		let routing = Routing { default_namespace: "quay.io", listener_namespaces: &[], base_path: "/registry" };
+		let proxy = Url::parse("https://cache.internal").unwrap();
+		assert_eq!(hosts_toml(&routing, &proxy, "docker.io"), "server = \"https://registry-1.docker.io\"\n\n[host.\"https://cache.internal/registry/v2\"]\n  capabilities = [\"pull\", \"resolve\"]\n  override_path = true\n");
+		assert_eq!(daemon_json(&routing, &proxy), None);
+	}
+}
@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+
+use camino::Utf8PathBuf;
+use clap::Parser;
+use clap::Subcommand;
+use compact_str::CompactString;
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+	/// Serve the registry API; this is the default if no subcommand is given
+	Serve,
+	/// Rewrite existing objects in storage to match the current storage layout version
+	Migrate(MigrateConfig),
+	/// Simulate many clients pulling images from a running instance, and report how it held up
+	Bench(BenchConfig),
+	/// Check the configuration, including that storage can be reached, and exit; nonzero if
+	/// anything would keep the registry from serving properly
+	CheckConfig,
+	/// Write containerd hosts.toml files and a dockerd daemon.json pointing clients at this
+	/// instance for each configured upstream, and exit
+	ClientConfig(ClientConfig),
+	/// Copy what's cached into a directory laid out as filesystem storage, for carrying into an
+	/// air-gapped environment; with a previous state file, only what's changed since
+	Export(ExportConfig),
+	/// Report how much storage sharing blobs between repositories, namespaces, and tenants saves,
+	/// as JSON on stdout, and exit
+	DedupReport,
+	/// Print what's cached for an image, as JSON on stdout:  its manifest, the manifest for each
+	/// platform of an index, and whether each blob they reference is in storage, and exit
+	Inspect(InspectConfig),
+	/// Purge cached manifests by repository and tag glob and by age, into the trash unless
+	/// `--permanent`, and exit
+	Purge(PurgeConfig),
+	/// Import what a docker/distribution registry, such as a pull-through cache being replaced, has
+	/// in its storage, and exit
+	ImportDistribution(ImportDistributionConfig)
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct MigrateConfig {
+	/// Log which migrations would run without modifying storage
+	#[clap(long)]
+	pub dry_run: bool
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct BenchConfig {
+	/// Base URL of the instance to pull from
+	#[clap(long, default_value = "http://localhost:80")]
+	pub target: String,
+	/// Number of clients pulling in parallel
+	#[clap(long, default_value_t = 8)]
+	pub clients: usize,
+	/// Number of times each client pulls each image
+	#[clap(long, default_value_t = 1)]
+	pub iterations: usize,
+	/// Images to pull, as `[namespace/]name:tag` or `[namespace/]name@digest`
+	#[clap(long = "image", required_unless_present = "replay")]
+	pub images: Vec<String>,
+	/// A `--request-sample-file` to send again instead of pulling images
+	#[clap(long, conflicts_with = "images")]
+	pub replay: Option<PathBuf>,
+	/// How many times as fast as they first came in sampled requests are replayed; `0` sends them as
+	/// fast as `--clients` allows
+	#[clap(long, default_value_t = 1.0)]
+	pub speed: f64
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct ClientConfig {
+	/// The URL clients reach this instance at, e.g. `http://registry-cache.internal:5000`; ports for
+	/// listener namespaces are swapped in as configured
+	#[clap(long)]
+	pub proxy_url: String,
+	/// Where to write `certs.d/<host>/hosts.toml` and `daemon.json`; printed to stdout otherwise
+	#[clap(long)]
+	pub output_dir: Option<PathBuf>,
+	/// Namespaces to write configuration for; every one in the upstream config if not given
+	#[clap(long = "namespace")]
+	pub namespaces: Vec<CompactString>
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct ExportConfig {
+	/// Where to write the bundle; it can be served with `filesystem --root` as it is
+	#[clap(long)]
+	pub output_dir: Utf8PathBuf,
+	/// Where to write the list of what's been exported, for the next export to start from
+	#[clap(long)]
+	pub state_file: PathBuf,
+	/// The state file an earlier export wrote; only objects added or changed since it are exported
+	#[clap(long)]
+	pub previous_state: Option<PathBuf>,
+	/// How many objects are copied at once
+	#[clap(long, default_value_t = 8)]
+	pub concurrency: usize
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct ImportDistributionConfig {
+	/// YAML file describing the distribution registry's storage, as for `--replica-config-file`:
+	/// `backend: s3` or `backend: filesystem`, and that backend's settings
+	#[clap(long)]
+	pub source_config_file: PathBuf,
+	/// The namespace to import repositories into, i.e. the upstream the registry was a cache of
+	#[clap(long)]
+	pub namespace: CompactString,
+	/// Where distribution's storage starts in the source, under its `rootdirectory`
+	#[clap(long, default_value = "docker/registry/v2")]
+	pub root: String,
+	/// How many objects are copied at once
+	#[clap(long, default_value_t = 8)]
+	pub concurrency: usize
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct InspectConfig {
+	/// The image, as `<namespace>/<image>[:<tag>|@<digest>]`
+	pub image: String,
+	/// Look in this tenant's cache instead of the shared one
+	#[clap(long)]
+	pub tenant: Option<CompactString>,
+	/// Include the manifests themselves
+	#[clap(long)]
+	pub manifest: bool
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct PurgeConfig {
+	/// The namespace to purge from
+	#[clap(long)]
+	pub namespace: CompactString,
+	/// Repositories to purge, as a glob, e.g. `library/*`
+	#[clap(long)]
+	pub repo: String,
+	/// Tags and digests to purge, as a glob; all of them if not given
+	#[clap(long)]
+	pub tag: Option<String>,
+	/// Only purge manifests cached longer ago than this
+	#[clap(long)]
+	pub older_than: Option<humantime::Duration>,
+	/// Purge from this tenant's cache instead of the shared one
+	#[clap(long)]
+	pub tenant: Option<CompactString>,
+	/// Delete the manifests rather than moving them into the trash
+	#[clap(long)]
+	pub permanent: bool,
+	/// List what would be purged without purging it
+	#[clap(long)]
+	pub dry_run: bool
+}
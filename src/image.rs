@@ -10,20 +10,26 @@ use serde_with::DeserializeFromStr;
 
 mod error;
 
-static RE_IMAGE: Lazy<Regex> = lazy_regex!("^[a-z0-9]+([._-][a-z0-9]+)*(/[a-z0-9]+([._-][a-z0-9]+)*)*$");
+/// Repository path components as the distribution spec has them, optionally following a registry
+/// host (dot-separated hostname labels or a bracketed IPv6 literal, and an optional port) when the
+/// namespace is part of the image name.
+static RE_IMAGE: Lazy<Regex> = lazy_regex!(r"^(([a-zA-Z0-9]([a-zA-Z0-9-]*[a-zA-Z0-9])?(\.[a-zA-Z0-9]([a-zA-Z0-9-]*[a-zA-Z0-9])?)*|\[[0-9a-fA-F:.]+\])(:[0-9]+)?/)?[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*(/[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*)*$");
 static RE_TAG: Lazy<Regex> = lazy_regex!("^[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}$");
 
+/// The longest image name, registry host included, that clients generally accept
+pub const MAX_NAME_LENGTH: usize = 255;
+
 fn is_valid_sha256(s: &str) -> bool {
-	s.len() == 64 && s.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()) && hex::decode(s).is_ok()
+	s.len() == 64 && s.bytes().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c))
 }
 
-#[derive(Debug, DeserializeFromStr)]
+#[derive(Clone, Debug, DeserializeFromStr)]
 pub struct ImageName(CompactString);
 impl FromStr for ImageName {
 	type Err = error::InvalidImageName;
 
 	fn from_str(input: &str) -> Result<Self, Self::Err> {
-		match RE_IMAGE.is_match(input) {
+		match input.len() <= MAX_NAME_LENGTH && RE_IMAGE.is_match(input) {
 			false => Err(error::InvalidImageName(input.to_string())),
 			true => Ok(ImageName(input.into()))
 		}
@@ -85,3 +91,78 @@ impl ImageReference {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use rand::seq::SliceRandom;
+	use rand::Rng;
+
+	use super::*;
+
+	#[test]
+	fn image_names() {
+		for name in ["alpine", "library/alpine", "docker.io/library/alpine", "registry.local:5000/team/app", "[::1]:5000/app", "a/b__c/d--e/f.g", "my-registry.example.com/a"] {
+			assert!(ImageName::from_str(name).is_ok(), "{name}");
+		}
+		let long = format!("a/{}", "b".repeat(MAX_NAME_LENGTH - 2));
+		assert!(ImageName::from_str(&long).is_ok());
+		for name in ["", "Alpine", "library/", "/alpine", "a//b", "a___b", "a_-b", "-a", "a.", "host..local/app", "-host/app", "host:port/app", "a/b c", "a/b?c", format!("{long}b").as_str()] {
+			assert!(ImageName::from_str(name).is_err(), "{name}");
+		}
+	}
+
+	#[test]
+	fn references() {
+		let digest = "sha256:".to_owned() + &"0f".repeat(32);
+		assert!(matches!(ImageReference::from_str("latest"), Ok(ImageReference::Tag(_))));
+		assert!(matches!(ImageReference::from_str("_1.2-rc.3"), Ok(ImageReference::Tag(_))));
+		assert!(matches!(ImageReference::from_str(&"a".repeat(128)), Ok(ImageReference::Tag(_))));
+		assert_eq!(ImageReference::from_str(&digest).unwrap().to_string(), digest);
+		for reference in ["", ".hidden", "-rc", "a".repeat(129).as_str(), "a:b", "a/b", "sha256:", "sha256:0f0f", digest.to_uppercase().replace("SHA256", "sha256").as_str(), format!("{digest}00").as_str(), digest.replace("sha256", "sha512").as_str()] {
+			assert!(ImageReference::from_str(reference).is_err(), "{reference}");
+		}
+	}
+
+	/// Strings built at random from the characters the grammar cares about, and some it doesn't.
+	fn fuzz(rng: &mut impl Rng, alphabet: &[&str], max_parts: usize) -> String {
+		(0..rng.gen_range(0..=max_parts)).map(|_| *alphabet.choose(rng).unwrap()).collect()
+	}
+
+	#[test]
+	fn fuzzed_names_parse_only_when_they_keep_to_the_grammar() {
+		let alphabet = ["a", "z", "0", "9", "A", ".", "-", "_", "__", "/", ":", "5000", "[", "]", "::1", " ", "%2F", "\u{e9}", "\0", "ab", "../"];
+		let mut rng = rand::thread_rng();
+		for _ in 0..20_000 {
+			let input = fuzz(&mut rng, &alphabet, 32);
+			let Ok(name) = ImageName::from_str(&input) else {
+				continue;
+			};
+			assert_eq!(name.to_string(), input);
+			assert!(input.len() <= MAX_NAME_LENGTH, "{input}");
+			let path = input.rsplit_once(']').map_or(input.as_str(), |(_, rest)| rest);
+			let path = path.split_once('/').filter(|(host, _)| host.contains(['.', ':', '[']) || host.chars().any(|c| c.is_ascii_uppercase()) || input.starts_with('[')).map_or(path, |(_, path)| path);
+			assert!(!path.is_empty() && path.split('/').all(|part| !part.is_empty() && !part.starts_with(['.', '_', '-']) && !part.ends_with(['.', '_', '-'])), "{input}");
+			assert!(input.chars().all(|c| c.is_ascii_alphanumeric() || "._-/:[]".contains(c)), "{input}");
+		}
+	}
+
+	#[test]
+	fn fuzzed_references_parse_only_when_they_keep_to_the_grammar() {
+		let alphabet = ["a", "f", "g", "0", "Z", ".", "-", "_", ":", "sha256:", "/", " ", "\u{e9}", "0123456789abcdef"];
+		let mut rng = rand::thread_rng();
+		for _ in 0..20_000 {
+			let input = fuzz(&mut rng, &alphabet, 40);
+			match ImageReference::from_str(&input) {
+				Ok(ImageReference::Tag(tag)) => {
+					assert_eq!(tag, input);
+					assert!((1..=128).contains(&tag.len()) && !tag.starts_with(['.', '-']) && tag.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)), "{input}");
+				},
+				Ok(ImageReference::Sha256(hex)) => {
+					assert_eq!(format!("sha256:{hex}"), input);
+					assert!(hex.len() == 64 && hex::decode(&hex).is_ok(), "{input}");
+				},
+				Err(_) => ()
+			}
+		}
+	}
+}
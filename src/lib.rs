@@ -1,8 +1,23 @@
 #![allow(dead_code)]
 #![allow(unused_parens)]
 
+//! A caching pull-through proxy for OCI registries.  The `oci-registry` binary is a thin wrapper
+//! around this crate; to embed the proxy in your own actix-web app, build a [`RequestConfig`] from
+//! a [`storage::Repository`] and [`upstream::Clients`], add it as app data, and register the
+//! services you want with [`api::registry`], [`api::admin`], and [`api::helm::configure`].
+
 pub mod api;
-mod image;
-mod storage;
-mod upstream;
+pub mod bench;
+pub mod chaos;
+pub mod client_config;
+pub mod command;
+pub mod image;
+pub mod logging;
+pub mod report;
+pub mod secrets;
+pub mod storage;
+pub mod upstream;
 mod util;
+pub mod validate;
+
+pub use api::RequestConfig;
@@ -0,0 +1,100 @@
+use actix_web::web;
+use actix_web::HttpResponse;
+use clap::Parser;
+use clap::ValueEnum;
+use tracing_subscriber::fmt;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::Layer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::reload;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Registry;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum LogFormat {
+	#[default]
+	Compact,
+	Pretty,
+	Json
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct LogConfig {
+	#[clap(env, long, value_enum, default_value_t = LogFormat::Compact)]
+	log_format: LogFormat,
+	/// Which events to log, in `RUST_LOG` syntax (e.g. `info` or `warn,oci_registry=debug`); if not
+	/// given, `RUST_LOG` is used.  Can be changed at runtime with `PUT /_admin/log-level`.
+	#[clap(env, long)]
+	log_level: Option<String>,
+	/// Also log each span as it closes, with how long it took:  each request's, with how it was
+	/// served from cache, and those of the upstream and storage requests made for it.
+	#[clap(env, long)]
+	log_spans: bool
+}
+
+/// Lets the log filter be swapped out while we're running.
+#[derive(Clone)]
+pub struct LogHandle(reload::Handle<EnvFilter, Registry>);
+
+/// In builds with the `tokio-console` feature, what tokio-console connects to, on
+/// `TOKIO_CONSOLE_BIND` (`127.0.0.1:6669` unless that says otherwise); it sees every task, whatever
+/// the log filter.
+#[cfg(feature = "tokio-console")]
+fn console() -> Option<console_subscriber::ConsoleLayer> {
+	Some(console_subscriber::ConsoleLayer::builder().with_default_env().spawn())
+}
+
+#[cfg(not(feature = "tokio-console"))]
+fn console() -> Option<tracing_subscriber::layer::Identity> {
+	None
+}
+
+/// Installs the global tracing subscriber.
+pub fn init(config: &LogConfig) -> LogHandle {
+	let filter = match config.log_level.as_deref() {
+		Some(level) => EnvFilter::try_new(level).unwrap_or_else(|error| {
+			eprintln!("Invalid --log-level '{level}' ({error}); falling back to RUST_LOG");
+			EnvFilter::from_default_env()
+		}),
+		None => EnvFilter::from_default_env()
+	};
+	let (filter, handle) = reload::Layer::new(filter);
+	let registry = tracing_subscriber::registry();
+	let span_events = match config.log_spans {
+		true => FmtSpan::CLOSE,
+		false => FmtSpan::NONE
+	};
+	// The filter only applies to what's logged, so that tokio-console still sees tokio's own events
+	let console = console();
+	match config.log_format {
+		LogFormat::Compact => registry.with(fmt::layer().with_span_events(span_events).compact().with_filter(filter)).with(console).init(),
+		LogFormat::Pretty => registry.with(fmt::layer().with_span_events(span_events).pretty().with_filter(filter)).with(console).init(),
+		LogFormat::Json => registry.with(fmt::layer().with_span_events(span_events).json().with_filter(filter)).with(console).init()
+	};
+	LogHandle(handle)
+}
+
+/// Returns the log filter currently in effect.
+pub async fn get_level(handle: web::Data<LogHandle>) -> HttpResponse {
+	match handle.0.with_current(ToString::to_string) {
+		Ok(level) => HttpResponse::Ok().body(level),
+		Err(error) => HttpResponse::InternalServerError().body(error.to_string())
+	}
+}
+
+/// Replaces the log filter with the one in the request body.
+pub async fn set_level(handle: web::Data<LogHandle>, body: String) -> HttpResponse {
+	let filter = match EnvFilter::try_new(body.trim()) {
+		Ok(v) => v,
+		Err(error) => return HttpResponse::BadRequest().body(error.to_string())
+	};
+	let level = filter.to_string();
+	match handle.0.reload(filter) {
+		Ok(()) => {
+			tracing::warn!(level = level.as_str(), "Log level changed");
+			HttpResponse::Ok().body(level)
+		},
+		Err(error) => HttpResponse::InternalServerError().body(error.to_string())
+	}
+}
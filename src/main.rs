@@ -1,12 +1,18 @@
 #![allow(unused_parens)]
 use core::future;
 use core::time::Duration;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::SystemTime;
 
+use actix_web::body::BodySize;
+use actix_web::body::MessageBody;
 use actix_web::dev::Service;
 use actix_web::http::header::HeaderName;
-use actix_web::http::header::HeaderValue;
+use actix_web::http::KeepAlive;
 use actix_web::web;
+use actix_web::HttpMessage;
 use actix_web::HttpResponse;
 use actix_web_prometheus::PrometheusMetricsBuilder;
 use clap::Parser;
@@ -16,16 +22,74 @@ use tokio::sync::oneshot;
 use tracing::error;
 use tracing::info;
 use tracing::warn;
+use tracing::Instrument;
 
-mod api;
-mod image;
-mod storage;
-mod upstream;
-mod util;
+use oci_registry::api;
+use oci_registry::api::alias::Aliases;
+use oci_registry::api::cache_control;
+use oci_registry::api::cache_control::CacheControl;
+use oci_registry::api::cdn;
+use oci_registry::api::cdn::Cdn;
+use oci_registry::api::checkpoint;
+use oci_registry::api::client_ip::ClientIp;
+use oci_registry::api::client_ip::IpNetwork;
+use oci_registry::api::client_ip::TrustedProxies;
+use oci_registry::api::handoff;
+use oci_registry::api::handoff::Handoff;
+use oci_registry::api::identity::ClientIdentities;
+use oci_registry::api::limits::Limits;
+use oci_registry::api::mirror;
+use oci_registry::api::pins::Pin;
+use oci_registry::api::pins::Pins;
+use oci_registry::api::prefetch;
+use oci_registry::api::prefetch::CoPullConfig;
+use oci_registry::api::prefetch::CoPulls;
+use oci_registry::api::probe;
+use oci_registry::api::request_id::RequestId;
+use oci_registry::api::sampling;
+use oci_registry::api::sampling::SamplingConfig;
+use oci_registry::api::shard::Shards;
+use oci_registry::api::sweep;
+use oci_registry::api::sweep::Sweep;
+use oci_registry::api::tenant::Tenants;
+use oci_registry::api::timestamp::Timestamper;
+use oci_registry::api::trace;
+use oci_registry::api::trace::TraceContext;
+use oci_registry::api::ClientAbortPolicy;
+use oci_registry::api::ListenerNamespace;
+use oci_registry::bench;
+#[cfg(feature = "chaos")]
+use oci_registry::chaos;
+use oci_registry::client_config;
+use oci_registry::command::Command;
+use oci_registry::logging;
+use oci_registry::logging::LogConfig;
+use oci_registry::logging::LogHandle;
+use oci_registry::report;
+use oci_registry::report::ReportConfig;
+use oci_registry::secrets;
+use oci_registry::secrets::Secrets;
+use oci_registry::secrets::SecretsConfig;
+use oci_registry::storage;
+use oci_registry::storage::check::CheckAction;
+use oci_registry::storage::pacing::InFlight;
+use oci_registry::storage::pacing::Pacer;
+use oci_registry::storage::pacing::PacingConfig;
+use oci_registry::storage::readahead::ReadAhead;
+use oci_registry::storage::replica::ReplicaConfig;
+use oci_registry::storage::s3::transport::TlsReport;
+use oci_registry::storage::StorageConfig;
+use oci_registry::upstream::dns;
+use oci_registry::upstream::dns::DnsConfig;
+use oci_registry::upstream::docker_config;
+use oci_registry::upstream::tls_sessions;
+use oci_registry::upstream::InvalidationConfig;
+use oci_registry::upstream::UpstreamConfig;
+use oci_registry::validate::Report;
 
-use storage::StorageConfig;
-use upstream::InvalidationConfig;
-use upstream::UpstreamConfig;
+#[cfg(feature = "heap-stats")]
+#[global_allocator]
+static ALLOCATOR: api::debug::CountingAllocator = api::debug::CountingAllocator;
 
 #[derive(Debug, Parser)]
 struct Config {
@@ -35,18 +99,372 @@ struct Config {
 	listen: socket_address::Address,
 	#[clap(env, long, default_value = "docker.io")]
 	default_namespace: CompactString,
+	/// Comma-separated extra addresses to listen on, each with a default namespace of its own, as
+	/// `address=namespace`; e.g. `0.0.0.0:5002=ghcr.io` serves a mirror of `ghcr.io` on port 5002,
+	/// for clients that expect one mirror per registry.
+	#[clap(env, long, value_delimiter = ',')]
+	listen_namespace: Vec<ListenerNamespace>,
+	/// Like `--listen-namespace`, but each address serves nothing but its namespace, as that
+	/// registry's own mirror protocol expects; e.g. `0.0.0.0:5000=docker.io` can be given to
+	/// dockerd as a `registry-mirrors` entry.  Image names are taken as they are, and requests for
+	/// any other namespace are refused.
+	#[clap(env, long, value_delimiter = ',')]
+	pinned_listen_namespace: Vec<ListenerNamespace>,
 	/// If enabled, will validate a blob's SHA256 digest when reading it from cache storage; if the
 	/// digest doesn't match what was expected based on the request URL, it will be deleted from
 	/// storage and re-retrieved from upstream.  This has an impact on performance, as the entire
 	/// blob needs to be read from storage twice instead of just once.
 	#[clap(env, long, default_value_t = false)]
 	check_cache_digest: bool,
+	/// If enabled, manifests pulled by digest are checked against it when they're served from
+	/// cache, and deleted and pulled from upstream again if they don't match.  Manifests are small,
+	/// so unlike `--check-cache-digest`, this costs little.
+	#[clap(env, long, default_value_t = false)]
+	check_manifest_digest: bool,
+	/// Which `Cache-Control` headers pulls are served with, for HTTP caches in front such as a CDN:
+	/// `standard` marks what's pulled by digest `immutable` and manifests pulled by tag `no-cache`,
+	/// and `off` leaves them out.
+	#[clap(env, long, value_enum, default_value_t = cache_control::Policy::Standard)]
+	cache_control: cache_control::Policy,
+	/// How long HTTP caches in front may keep what's pulled by digest.
+	#[clap(env, long, default_value = "365d")]
+	immutable_max_age: humantime::Duration,
+	/// How long HTTP caches in front may keep manifests pulled by tag, where upstream hasn't said;
+	/// `0s` has them ask every time.
+	#[clap(env, long, default_value = "0s")]
+	tag_max_age: humantime::Duration,
+	/// If enabled, pulls say whether they were answered from cache in `X-Cache` (`HIT`, `MISS`,
+	/// `STALE`, or `REVALIDATED`), and how many seconds old what was served from cache is in
+	/// `X-Cache-Age`, for anyone looking into a slow pull without the server's logs.
+	#[clap(env, long, default_value_t = false)]
+	cache_status_headers: bool,
+	/// Comma-separated secrets, any of which a CDN in front has to present in
+	/// `--cdn-origin-header` for registry requests to be served; without any, requests are served
+	/// however they arrive.  More than one lets a secret be rotated.
+	#[clap(env, long, value_delimiter = ',')]
+	cdn_origin_secret: Vec<String>,
+	/// The header the CDN presents `--cdn-origin-secret` in.
+	#[clap(env, long, default_value = cdn::DEFAULT_HEADER)]
+	cdn_origin_header: HeaderName,
+	/// URL that objects purged through the admin API, or invalidated by webhooks, are POSTed to, so
+	/// that they can be purged from the CDN too.
+	#[clap(env, long)]
+	cdn_purge_url: Option<reqwest::Url>,
+	/// Bearer token sent to `--cdn-purge-url`.
+	#[clap(env, long)]
+	cdn_purge_token: Option<String>,
+	/// If enabled, each manifest and blob cached from upstream is stamped with when it was fetched,
+	/// by which version, and from which upstream, for `/_admin/provenance` and `inspect` to report.
+	#[clap(env, long, default_value_t = false)]
+	record_provenance: bool,
+	/// RFC 3161 time-stamping authority that the digest of each manifest newly cached from upstream
+	/// is sent to, for a signed timestamp proving it was cached with that content at that time.
+	#[clap(env, long)]
+	timestamp_url: Option<reqwest::Url>,
+	/// Key for the proxy to attest to newly cached manifests itself, with an HMAC over their digests
+	/// and the time, where there's no `--timestamp-url` or it can't be reached.
+	#[clap(env, long)]
+	attestation_key: Option<String>,
+	/// If enabled, cached Helm charts are also served as a classic Helm chart repository under
+	/// `/_helm`, for tooling that can't pull charts from OCI registries.
+	#[clap(env, long, default_value_t = false)]
+	helm_repository: bool,
+	/// If enabled, runtime diagnostics, such as memory use, are served under `/_admin/debug`, to whoever has the admin token.
+	#[clap(env, long, default_value_t = false)]
+	debug_endpoints: bool,
+	/// Manifests larger than this many bytes are rejected instead of being cached and served.
+	#[clap(env, long, default_value_t = 4 * 1024 * 1024)]
+	max_manifest_size: usize,
+	/// Registry requests whose header names and values add up to more than this many bytes are
+	/// refused with a 431; 0 leaves only the HTTP server's own limit.
+	#[clap(env, long, default_value_t = 32 * 1024)]
+	max_header_size: usize,
+	/// Pushed upload chunks, or monolithic uploads, larger than this many bytes are refused with a
+	/// 413 and `SIZE_INVALID`, for clients to push in smaller chunks; 0 for no limit.
+	#[clap(env, long, default_value_t = 0)]
+	max_upload_chunk_size: u64,
+	/// Whether to send manifests, tag lists, referrers and the catalog gzip- or zstd-compressed to
+	/// clients that accept it; blobs are never compressed.
+	#[clap(env, long, default_value_t = false)]
+	compress_responses: bool,
+	/// Path prefix to serve the registry API (and the admin and Helm endpoints) under, for
+	/// deployments behind a reverse proxy that routes by path; e.g. `/registry` serves the API at
+	/// `/registry/v2/`.  Health checks and metrics stay at the root.
+	#[clap(env, long, default_value = "")]
+	base_path: String,
+	/// The most entries `/v2/_catalog` and tag listings return in one page; clients asking for
+	/// more (or not saying) get a `Link` header pointing at the next page.
+	#[clap(env, long, default_value_t = 1000)]
+	max_page_size: usize,
+	/// Comma-separated addresses or CIDR blocks of load balancers and reverse proxies whose
+	/// `Forwarded` and `X-Forwarded-For` headers are believed, so that logs attribute requests to
+	/// real client addresses.  When listening on a Unix socket, any value trusts all connections.
+	#[clap(env, long, value_delimiter = ',')]
+	trusted_proxies: Vec<IpNetwork>,
+	/// A request header naming the client a pull is for, such as a node name set in containerd's
+	/// `hosts.toml`, for the per-client `client_requests` and `client_bytes` metrics.  Clients that
+	/// don't send it are counted by their basic auth username.
+	#[clap(env, long)]
+	client_identity_header: Option<HeaderName>,
+	/// How many clients get per-client metrics of their own; the rest are counted together as
+	/// `_other`.  `0` turns per-client metrics off.
+	#[clap(env, long, default_value_t = 100)]
+	client_identity_limit: usize,
+	/// How long a manifest request may take, end to end, before we stop waiting on upstream and
+	/// storage for it.
+	#[clap(env, long, default_value = "30s")]
+	manifest_deadline: humantime::Duration,
+	/// How long a blob request may take, end to end, including streaming the blob itself, before we
+	/// stop reading it from upstream and writing it to storage.
+	#[clap(env, long, default_value = "30m")]
+	blob_deadline: humantime::Duration,
+	/// What to do with a blob being fetched from upstream when the client that asked for it
+	/// disconnects:  keep filling the cache (`continue`), or stop and delete the partial blob
+	/// (`abort`).
+	#[clap(env, long, value_enum, default_value_t = ClientAbortPolicy::Continue)]
+	client_abort_policy: ClientAbortPolicy,
+	/// How long a blob seen in storage is assumed to still be there, so that `HEAD` requests for it
+	/// can be answered without asking storage again; `0s` always asks.
+	#[clap(env, long, default_value = "5m")]
+	known_blob_ttl: humantime::Duration,
+	/// YAML file listing images to keep cached no matter how old they get, as
+	/// `namespace/image:tag` or `namespace/image@digest`; more can be pinned at runtime through
+	/// `/_admin/pins`.
+	#[clap(env, long)]
+	pins_file: Option<PathBuf>,
+	/// YAML file mapping aliases, as `repository:tag`, to the images they stand for, as
+	/// `namespace/image:tag`, `namespace/image@digest`, or both; more can be added at runtime through
+	/// `/_admin/aliases`.
+	#[clap(env, long)]
+	aliases_file: Option<PathBuf>,
+	/// YAML file listing repositories, and patterns for which of their tags, to keep cached ahead of
+	/// any client asking for them; see `/_admin/mirror` for how that's going.
+	#[clap(env, long)]
+	mirror_file: Option<PathBuf>,
+	/// How often to check mirrored repositories upstream for new and moved tags.
+	#[clap(env, long, default_value = "15m")]
+	mirror_interval: humantime::Duration,
+	/// Whether to learn which tags clients pull together (an app image and its sidecars, say) and,
+	/// when one of them is pulled, fetch the others' manifests into the cache ahead of the client.
+	#[clap(env, long, default_value_t = false)]
+	prefetch: bool,
+	/// How soon after one tag another has to be pulled, by the same client, to count as pulled
+	/// with it.
+	#[clap(env, long, default_value = "2m")]
+	prefetch_window: humantime::Duration,
+	/// How many times a tag has to have been pulled after another before it's prefetched with it;
+	/// it also has to have followed at least half of that tag's pulls.
+	#[clap(env, long, default_value_t = 3)]
+	prefetch_min_count: u64,
+	/// The most tags prefetched after any one pull.
+	#[clap(env, long, default_value_t = 5)]
+	prefetch_max: usize,
+	/// A small image, as `namespace/image:tag`, to pull through the cache from upstream on a
+	/// schedule, as a self-test; see the `probe_*` metrics for how that's going.
+	#[clap(env, long)]
+	probe_image: Option<Pin>,
+	/// How often to pull `--probe-image`.
+	#[clap(env, long, default_value = "5m")]
+	probe_interval: humantime::Duration,
+	/// How many of the most recently cached tags whose manifests have expired, say while the proxy
+	/// was down, to pull through the cache again in the background after startup; `0` doesn't.
+	#[clap(env, long, default_value_t = 0)]
+	restart_sweep_tags: usize,
+	/// How many tags the restart sweep pulls at once.
+	#[clap(env, long, default_value_t = 10)]
+	restart_sweep_batch_size: usize,
+	/// How long after one batch of the restart sweep starts the next does.
+	#[clap(env, long, default_value = "1s")]
+	restart_sweep_interval: humantime::Duration,
+	/// Token that upstream webhooks have to present, as a `token` query parameter or a bearer
+	/// token, to invalidate cached tags through `/_admin/webhook/{namespace}`; without one, that
+	/// endpoint is disabled.
+	#[clap(env, long)]
+	webhook_token: Option<String>,
+	/// Token that clients have to present in `X-Oci-Upstream-Token` to send a request to the upstream
+	/// named in `X-Oci-Upstream`, rather than the one configured for its namespace; without one,
+	/// requests with `X-Oci-Upstream` are refused.
+	#[clap(env, long)]
+	upstream_override_token: Option<String>,
+	/// Token that has to be presented in `X-Admin-Token` for anything under `/_admin` but webhooks;
+	/// without one, the admin API is open, and signed URLs can't be minted.
+	#[clap(env, long)]
+	admin_token: Option<String>,
+	/// Secret for signing URLs minted through `/_admin/sign`, with which cached manifests and blobs
+	/// can be fetched under `/_signed` until they expire; without one, signed URLs are disabled.
+	#[clap(env, long)]
+	url_signing_key: Option<String>,
+	/// Secret that what's cached for pass-through credentials is kept under an HMAC of them with,
+	/// needed for namespaces with `auth_mode: passthrough`.  Changing it leaves everything cached
+	/// for them behind, to be fetched again.
+	#[clap(env, long, conflicts_with = "partition_key_file")]
+	partition_key: Option<String>,
+	/// File to read `--partition-key` from instead, such as a mounted secret.
+	#[clap(env, long)]
+	partition_key_file: Option<PathBuf>,
+	/// The longest a signed URL can be made to last.
+	#[clap(env, long, default_value = "7d")]
+	signed_url_max_ttl: humantime::Duration,
+	/// How long objects purged through the admin API stay in the trash, from which they can be
+	/// restored, before they're deleted for good; `0s` deletes them right away.
+	#[clap(env, long, default_value = "7d")]
+	trash_retention: humantime::Duration,
+	/// Whether to look through storage at startup for obviously broken objects (empty ones,
+	/// manifests that aren't JSON, blobs stored somewhere their digest couldn't put them), and what
+	/// to do about them.
+	#[clap(env, long, value_enum, default_value_t = CheckAction::Off)]
+	storage_check: CheckAction,
+	/// Whether to save in-memory state (blobs known to be in storage, and recent access checks with
+	/// upstream for pass-through credentials) to storage on shutdown, and restore it at startup, so
+	/// that a restart doesn't send all of those lookups to storage and upstream at once.
+	#[clap(env, long, default_value_t = false)]
+	checkpoint: bool,
+	/// With `--checkpoint`, for up to how many of the scopes anonymous tokens were taken for in the
+	/// hour before shutdown to take tokens again at startup, in the background, so that the first
+	/// pulls after a restart don't wait on upstream's token endpoint; 0 doesn't.
+	#[clap(env, long, default_value_t = 100)]
+	checkpoint_warm_tokens: usize,
+	/// With `--checkpoint`, a secret to encrypt the TLS sessions of requests to upstreams with, so
+	/// that they can be saved with the rest and resumed after a restart; without one, they aren't.
+	#[clap(env, long)]
+	checkpoint_tls_key: Option<String>,
+	/// This instance's name, under which `--checkpoint` saves its cache hit and miss counters, for
+	/// the next instance to start under the same name to carry on from; a name that stays put across
+	/// restarts, such as a StatefulSet pod's, is what makes that work.  Unset, they start from zero.
+	#[clap(env, long)]
+	instance_name: Option<String>,
+	/// Whether to bind listeners with `SO_REUSEPORT`, so that a new process can bind alongside this
+	/// one and take over without a moment where nothing is listening.
+	#[clap(env, long, default_value_t = false)]
+	reuse_port: bool,
+	/// Unix socket to hand off over on restart:  at startup, once it's listening, a process tells
+	/// whatever is listening there to stop accepting connections and say which blobs it's still
+	/// filling, and waits for those instead of fetching them again; then it listens there itself,
+	/// for whatever takes over from it.  Best with `--reuse-port`.
+	#[clap(env, long)]
+	handoff_socket: Option<PathBuf>,
+	/// With `--handoff-socket`, how long a process that's handed off has to finish serving the pulls
+	/// it's in the middle of before it exits anyway.
+	#[clap(env, long, default_value = "5m")]
+	handoff_drain_timeout: humantime::Duration,
+	/// YAML file describing a second storage location (an S3 bucket, possibly in another region, or
+	/// a filesystem root) to copy everything newly cached to, for disaster recovery.
+	#[clap(env, long)]
+	replica_config_file: Option<PathBuf>,
+	/// YAML file listing tenants:  teams with credentials of their own, the namespaces they may
+	/// pull from, and optionally a quota on how much their cache may hold.  With tenants, every
+	/// registry request has to come from one of them, and each tenant's content is cached apart.
+	#[clap(env, long)]
+	tenants_file: Option<PathBuf>,
+	/// YAML file listing the URLs of a fleet of cache nodes, each optionally with a weight, to shard
+	/// blobs across:  each blob is cached by one node, and the others redirect pulls of it there.
+	/// Re-read when it changes.
+	#[clap(env, long, requires = "shard_self")]
+	shard_ring_file: Option<PathBuf>,
+	/// This node's URL, as it is in `--shard-ring-file`.
+	#[clap(env, long, requires = "shard_ring_file")]
+	shard_self: Option<String>,
+	/// How long a single storage operation (a read, write, delete, listing and so on) can take before
+	/// it's logged as slow; `0s` never logs them.
+	#[clap(env, long, default_value = "0s")]
+	storage_slow_threshold: humantime::Duration,
+	/// Chunks read from storage are put together until they're at least this many bytes before
+	/// they're sent on to clients; `0` sends them on as the storage backend yields them.
+	#[clap(env, long, default_value_t = 0)]
+	storage_read_buffer_size: usize,
+	/// How many chunks of an object are read from storage ahead of the client streaming it,
+	/// for high-latency storage to keep up with fast clients; `0` reads only as fast as the client
+	/// takes them.
+	#[clap(env, long, default_value_t = 0)]
+	storage_read_ahead: usize,
+	/// How many storage operations have to fail in a row, for reasons other than a missing object,
+	/// for storage to be taken to be down; until it can be reached again, pulls are passed through
+	/// to upstream without caching.  `0` never takes it down.
+	#[clap(env, long, default_value_t = 5)]
+	storage_failure_threshold: usize,
+	/// How often to check whether storage can be reached again, while it's down.
+	#[clap(env, long, default_value = "10s")]
+	storage_recheck_interval: humantime::Duration,
+	/// How often to rewrite `storage-index.json`, the description of what's in storage for backup
+	/// and verification tooling; `0s` never writes it.
+	#[clap(env, long, default_value = "1h")]
+	storage_index_interval: humantime::Duration,
+	/// How long an idle client connection is kept open for another request (on HTTP/2, how long
+	/// between pings); `0s` closes each HTTP/1 connection after one request.  Clients pulling many
+	/// blobs in a row save a handshake per blob with a longer one.
+	#[clap(env, long, default_value = "5s")]
+	keep_alive: humantime::Duration,
+	/// How long a client has to send a request's headers, once it's connected or finished its last
+	/// request; `0s` doesn't limit it.
+	#[clap(env, long, default_value = "5s")]
+	client_request_timeout: humantime::Duration,
+	/// How long to wait for a client to close its end of a connection that's being shut down; `0s`
+	/// doesn't wait.
+	#[clap(env, long, default_value = "1s")]
+	client_disconnect_timeout: humantime::Duration,
+	/// The most connections each worker serves at once; past that, new ones wait to be accepted.
+	#[clap(env, long, default_value_t = 25000)]
+	max_connections: usize,
+	/// The most connections waiting to be accepted, per listener.
+	#[clap(env, long, default_value_t = 1024)]
+	backlog: u32,
+	/// How many worker threads serve requests; `0` starts one per CPU.
+	#[clap(env, long, default_value_t = 0)]
+	workers: usize,
+	/// Prints, as JSON, which TLS library this binary uses and which root certificates it trusts
+	/// for upstreams and for S3, then exits, without starting anything.
+	#[clap(long)]
+	print_tls_backend: bool,
+	#[clap(flatten)]
+	log: LogConfig,
+	#[clap(flatten)]
+	report: ReportConfig,
+	#[clap(flatten)]
+	sampling: SamplingConfig,
 	#[clap(flatten)]
 	upstream: UpstreamConfig,
+	#[clap(flatten)]
+	dns: DnsConfig,
+	#[clap(flatten)]
+	secrets: SecretsConfig,
+	#[clap(flatten)]
+	eviction: PacingConfig,
+	#[cfg(feature = "chaos")]
+	#[clap(flatten)]
+	chaos: chaos::ChaosConfig,
+	/// Comma-separated WebAssembly modules to run every pull through, in order, as policy plugins;
+	/// see `oci_registry::api::plugin::wasm` for what they export.
+	#[cfg(feature = "wasm-plugins")]
+	#[clap(env, long, value_delimiter = ',')]
+	plugin: Vec<PathBuf>,
 	#[clap(subcommand)]
 	storage: StorageConfig
 }
 
+impl Config {
+	/// Every extra listener, pinned or not.
+	fn listeners(&self) -> Vec<ListenerNamespace> {
+		self.listen_namespace.iter().cloned().chain(self.pinned_listen_namespace.iter().cloned().map(ListenerNamespace::pinned)).collect()
+	}
+
+	fn prefetch_strategy(&self) -> Option<Arc<dyn prefetch::Strategy>> {
+		let config = CoPullConfig { window: *self.prefetch_window, min_count: self.prefetch_min_count, max_predictions: self.prefetch_max };
+		self.prefetch.then(|| Arc::new(CoPulls::new(config)) as Arc<dyn prefetch::Strategy>)
+	}
+
+	/// CDN origin mode, if there's a secret to check or a purge hook to call.
+	fn cdn(&self) -> Option<Cdn> {
+		if (self.cdn_origin_secret.is_empty() && self.cdn_purge_url.is_none()) {
+			return None;
+		}
+		let cdn = Cdn::new(self.cdn_origin_header.clone(), self.cdn_origin_secret.clone());
+		Some(match &self.cdn_purge_url {
+			Some(url) => cdn.with_purge(url.clone(), self.cdn_purge_token.clone()),
+			None => cdn
+		})
+	}
+}
+
 #[inline]
 fn liveness() -> future::Ready<HttpResponse> {
 	future::ready(HttpResponse::Ok().body(""))
@@ -59,9 +477,10 @@ async fn readiness() -> Result<&'static str, api::error::Error> {
 	Ok("")
 }
 
-async fn cleanup(upstream: &InvalidationConfig, repo: &storage::Repository) {
+async fn cleanup(upstream: &InvalidationConfig, repo: &storage::Repository, pins: &Pins, tenants: Option<&Tenants>, trash_retention: Duration, mut pacer: Pacer) {
 	let now = SystemTime::now();
-	let mut count = match repo.delete_old_blobs(now - upstream.blob).await {
+	let keep = pins.protected_paths(repo).await;
+	let mut count = match repo.delete_old_blobs(now - upstream.blob, &keep, &mut pacer).await {
 		Ok(v) => v,
 		Err(error) => {
 			error!(%error, "Error cleaning up blobs");
@@ -70,31 +489,549 @@ async fn cleanup(upstream: &InvalidationConfig, repo: &storage::Repository) {
 	};
 	for (ns, age) in upstream.manifests.iter() {
 		let ns: &str = ns.as_ref();
-		match repo.delete_old_manifests(ns, now - *age).await {
+		match repo.delete_old_manifests(ns, now - *age, &keep, &mut pacer).await {
 			Ok(v) => count += v,
 			Err(error) => error!(%error, namespace = ns, "Error cleaning up manifests")
 		};
 	}
+	match api::provenance::delete_orphaned(repo, &mut pacer).await {
+		Ok(v) => count += v,
+		Err(error) => error!(%error, "Error cleaning up provenance records")
+	};
+	if (!trash_retention.is_zero()) {
+		match repo.delete_old_trash(now - trash_retention, &mut pacer).await {
+			Ok(v) => count += v,
+			Err(error) => error!(%error, "Error emptying trash")
+		};
+	}
 
 	if (count > 0) {
 		warn!(count, "Aged out objects");
 	} else {
 		info!(count, "Aged out objects");
 	}
+	if let Some(tenants) = tenants {
+		tenants.refresh_usage(repo).await;
+	}
 }
 
 #[actix_web::main]
 async fn main() {
 	let config = Config::parse();
+	if (config.print_tls_backend) {
+		println!("{}", serde_json::to_string_pretty(&TlsReport::new(config.storage.tls_roots())).unwrap());
+		return;
+	}
+
+	let log_handle = logging::init(&config.log);
+
+	match config.storage.command().clone() {
+		Command::Serve => serve(config, log_handle).await,
+		Command::Migrate(migrate) => {
+			let repo = config.storage.repository();
+			if let Err(error) = storage::layout::migrate(&repo, migrate.dry_run).await {
+				error!(%error, "Storage migration failed");
+				std::process::exit(1);
+			}
+		},
+		Command::Bench(bench) => bench::run(&bench).await,
+		Command::Export(export) => {
+			let repo = config.storage.repository();
+			if let Err(error) = fetch_secrets(&config, &repo).await {
+				error!(%error, "Failed to fetch secrets");
+				std::process::exit(1);
+			}
+			if let Err(error) = storage::export::run(&repo, &export).await {
+				error!(%error, "Export failed");
+				std::process::exit(1);
+			}
+		},
+		Command::DedupReport => {
+			let repo = config.storage.repository();
+			if let Err(error) = fetch_secrets(&config, &repo).await {
+				error!(%error, "Failed to fetch secrets");
+				std::process::exit(1);
+			}
+			match storage::dedup::report(&repo).await {
+				Ok(report) => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+				Err(error) => {
+					error!(%error, "Dedup report failed");
+					std::process::exit(1);
+				}
+			};
+		},
+		Command::Inspect(inspect) => {
+			let repo = config.storage.repository();
+			if let Err(error) = fetch_secrets(&config, &repo).await {
+				error!(%error, "Failed to fetch secrets");
+				std::process::exit(1);
+			}
+			match api::inspect::run(&repo, &inspect).await {
+				Ok(report) => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+				Err(error) => {
+					error!(%error, "Inspect failed");
+					std::process::exit(1);
+				}
+			};
+		},
+		Command::Purge(purge) => {
+			let repo = config.storage.repository();
+			if let Err(error) = fetch_secrets(&config, &repo).await {
+				error!(%error, "Failed to fetch secrets");
+				std::process::exit(1);
+			}
+			match api::purge::run(&repo, &purge).await {
+				Ok(objects) => {
+					for object in objects {
+						println!("{object}");
+					}
+				},
+				Err(error) => {
+					error!(%error, "Purge failed");
+					std::process::exit(1);
+				}
+			};
+		},
+		Command::ImportDistribution(import) => {
+			let repo = config.storage.repository();
+			if let Err(error) = fetch_secrets(&config, &repo).await {
+				error!(%error, "Failed to fetch secrets");
+				std::process::exit(1);
+			}
+			let result = match storage::distribution::load_source(&import.source_config_file).await {
+				Ok(source) => storage::distribution::run(&source, &repo, &import).await,
+				Err(e) => Err(e)
+			};
+			if let Err(error) = result {
+				error!(%error, "Import failed");
+				std::process::exit(1);
+			}
+		},
+		Command::CheckConfig => {
+			let repo = config.storage.repository();
+			if let Err(error) = fetch_secrets(&config, &repo).await {
+				error!(%error, "Failed to fetch secrets");
+				std::process::exit(1);
+			}
+			let report = validate(&config, &repo).await;
+			report.log();
+			if (report.has_errors()) {
+				std::process::exit(1);
+			}
+			info!(warnings = report.problems().len(), "Configuration is valid");
+		},
+		Command::ClientConfig(args) => {
+			let mut report = Report::default();
+			api::auth::set_partition_key(partition_key(&config).ok().flatten());
+			let namespaces = config.upstream.validate(&mut report).await;
+			report.log();
+			let Some(namespaces) = namespaces.filter(|_| !report.has_errors()) else {
+				std::process::exit(1);
+			};
+			let base_path = api::normalize_base_path(&config.base_path);
+			let listeners = config.listeners();
+			let routing = client_config::Routing { default_namespace: &config.default_namespace, listener_namespaces: &listeners, base_path: &base_path };
+			if let Err(error) = client_config::run(&args, &routing, namespaces.into_iter().collect()) {
+				error!(%error, "Failed to write client configuration");
+				std::process::exit(1);
+			}
+		}
+	};
+}
+
+/// Fetches any S3 credentials kept in a secret store into `repo`, so that storage can be reached.
+async fn fetch_secrets(config: &Config, repo: &storage::Repository) -> Result<Option<Secrets>, secrets::Error> {
+	let mut secrets = config.secrets.secrets()?;
+	if let Some(secrets) = secrets.as_mut() {
+		secrets.fill_s3(repo).await?;
+	}
+	Ok(secrets)
+}
+
+/// The partition key, from `--partition-key` or the file `--partition-key-file` names, trimmed.
+fn partition_key(config: &Config) -> Result<Option<Vec<u8>>, std::io::Error> {
+	match (config.partition_key.as_deref(), config.partition_key_file.as_ref()) {
+		(Some(key), _) => Ok(Some(key.as_bytes().to_vec())),
+		(None, Some(path)) => Ok(Some(std::fs::read_to_string(path)?.trim().as_bytes().to_vec())),
+		(None, None) => Ok(None)
+	}
+}
+
+/// Checks everything that can be checked before serving; see `oci_registry::validate`.
+async fn validate(config: &Config, repo: &storage::Repository) -> Report {
+	let mut report = Report::default();
+	if (config.storage.needs_credentials() && !config.secrets.provides_s3_credentials()) {
+		report.error("--access-key/--secret-key", "Needed for S3 storage, unless credentials are fetched with --s3-credentials-secret");
+	}
+	// Pass-through namespaces are checked for a partition key along with the rest of the upstream config
+	let partition_key = match partition_key(config) {
+		Ok(v) => v,
+		Err(error) => {
+			report.error("--partition-key-file", format!("Couldn't be read: {error}"));
+			None
+		}
+	};
+	api::auth::set_partition_key(partition_key.clone());
+	let namespaces = config.upstream.validate(&mut report).await;
+	let check_namespace = |report: &mut Report, subject: &str, namespace: &str| {
+		if let Some(namespaces) = &namespaces {
+			if (!namespaces.contains(namespace)) {
+				report.warn(subject, format!("{namespace} isn't in the upstream config, so it'll be treated as a registry hostname"));
+			}
+		}
+	};
+
+	check_namespace(&mut report, "--default-namespace", &config.default_namespace);
+	let mut addresses = HashSet::new();
+	if let socket_address::Address::Network(addr) = &config.listen {
+		addresses.insert(addr.to_string());
+	}
+	for listener in config.listeners() {
+		let flag = match listener.pinned {
+			true => "--pinned-listen-namespace",
+			false => "--listen-namespace"
+		};
+		if (!addresses.insert(listener.address.to_string())) {
+			report.error(flag, format!("{} is listened on more than once", listener.address));
+		}
+		check_namespace(&mut report, flag, &listener.namespace);
+	}
+
+	if (config.max_page_size == 0) {
+		report.error("--max-page-size", "Has to be at least 1");
+	}
+	if (config.manifest_deadline.is_zero() || config.blob_deadline.is_zero()) {
+		report.error("--manifest-deadline/--blob-deadline", "A zero deadline would fail every request");
+	}
+	if (config.webhook_token.as_deref() == Some("")) {
+		report.error("--webhook-token", "Empty; leave it unset to disable the webhook endpoint instead");
+	}
+	if (config.upstream_override_token.as_deref() == Some("")) {
+		report.error("--upstream-override-token", "Empty; leave it unset to disable upstream overrides instead");
+	}
+	if (config.admin_token.as_deref() == Some("")) {
+		report.error("--admin-token", "Empty; leave it unset to disable what needs it instead");
+	}
+	match (config.admin_token.is_none(), config.url_signing_key.is_some()) {
+		(true, true) => report.warn("--admin-token", "Unset; /_admin is open to anyone who can reach it, and no signed URLs can be minted"),
+		(true, false) => report.warn("--admin-token", "Unset; /_admin is open to anyone who can reach it"),
+		(false, _) => ()
+	};
+	match partition_key.as_deref() {
+		Some([]) => report.error("--partition-key", "Empty; leave it unset if no namespace uses auth_mode: passthrough"),
+		Some(key) if key.len() < 32 => report.warn("--partition-key", "Shorter than 32 bytes; a long random secret is much harder to guess"),
+		_ => ()
+	};
+	match (config.checkpoint_tls_key.as_deref(), config.checkpoint) {
+		(Some(""), _) => report.error("--checkpoint-tls-key", "Empty; leave it unset not to save TLS sessions instead"),
+		(Some(key), true) if key.len() < 32 => report.warn("--checkpoint-tls-key", "Shorter than 32 bytes; a long random secret is much harder to guess"),
+		(Some(_), false) => report.warn("--checkpoint-tls-key", "Set without --checkpoint; no TLS sessions are saved"),
+		_ => ()
+	};
+	if (config.cdn_origin_secret.iter().any(String::is_empty)) {
+		report.error("--cdn-origin-secret", "Empty secrets would admit requests without one");
+	}
+	if (!config.cdn_origin_secret.is_empty() && config.cache_control == cache_control::Policy::Off) {
+		report.warn("--cache-control", "Off in CDN origin mode; the CDN won't be told how long it can keep anything");
+	}
+	if (config.cdn_purge_token.is_some() && config.cdn_purge_url.is_none()) {
+		report.warn("--cdn-purge-token", "Set without --cdn-purge-url; nothing's sent it");
+	}
+	match config.attestation_key.as_deref() {
+		Some("") => report.error("--attestation-key", "Empty; leave it unset to disable attestations instead"),
+		Some(key) if key.len() < 32 => report.warn("--attestation-key", "Shorter than 32 bytes; a long random secret is much harder to guess"),
+		_ => ()
+	};
+	match config.url_signing_key.as_deref() {
+		Some("") => report.error("--url-signing-key", "Empty; leave it unset to disable signed URLs instead"),
+		Some(key) if key.len() < 32 => report.warn("--url-signing-key", "Shorter than 32 bytes; a long random secret is much harder to guess"),
+		_ => ()
+	};
+
+	if (config.sampling.request_sample_file.is_some() && !(config.sampling.request_sample_rate > 0.0 && config.sampling.request_sample_rate <= 1.0)) {
+		report.error("--request-sample-rate", "Has to be more than 0 and at most 1");
+	}
 
-	tracing_subscriber::fmt().with_env_filter(tracing_subscriber::EnvFilter::from_default_env()).compact().init();
+	if (config.storage_failure_threshold > 0 && config.storage_recheck_interval.is_zero()) {
+		report.error("--storage-recheck-interval", "Has to be more than zero for storage to be brought back after an outage");
+	}
+	if (config.storage_read_ahead.saturating_mul(config.storage_read_buffer_size) > 64 * 1024 * 1024) {
+		report.warn("--storage-read-ahead", "Reads more than 64 MiB ahead of each client; with many pulls at once, that adds up");
+	}
+	if let Some(image) = &config.probe_image {
+		check_namespace(&mut report, "--probe-image", image.namespace());
+		if (config.probe_interval.is_zero()) {
+			report.error("--probe-interval", "Has to be more than zero for probing to run");
+		}
+	}
+	if (config.restart_sweep_tags > 0) {
+		if (config.restart_sweep_batch_size == 0) {
+			report.error("--restart-sweep-batch-size", "Has to be more than zero for the restart sweep to run");
+		}
+		if (config.restart_sweep_interval.is_zero()) {
+			report.error("--restart-sweep-interval", "Has to be more than zero; pace the sweep with --restart-sweep-batch-size instead");
+		}
+	}
 
+	if let Err(error) = repo.check_access().await {
+		report.error("storage", format!("Can't be reached: {error}"));
+		// Everything below reads from storage too
+		return report;
+	}
+	match Pins::load(repo, config.pins_file.as_deref()).await {
+		Ok(pins) => {
+			for pin in pins.all().await {
+				check_namespace(&mut report, "--pins-file", pin.namespace());
+			}
+		},
+		Err(error) => report.error("--pins-file", format!("Failed to load pins: {error}"))
+	};
+	match Aliases::load(repo, config.aliases_file.as_deref()).await {
+		Ok(aliases) => {
+			for target in aliases.all().await.values() {
+				check_namespace(&mut report, "--aliases-file", target.namespace());
+			}
+		},
+		Err(error) => report.error("--aliases-file", format!("Failed to load aliases: {error}"))
+	};
+	if let Some(path) = &config.mirror_file {
+		match mirror::load(path).await {
+			Ok(entries) => {
+				for entry in &entries {
+					check_namespace(&mut report, "--mirror-file", entry.namespace());
+				}
+				if (!entries.is_empty() && config.mirror_interval.is_zero()) {
+					report.error("--mirror-interval", "Has to be more than zero for mirroring to run");
+				}
+			},
+			Err(error) => report.error("--mirror-file", format!("Failed to load mirror file: {error}"))
+		};
+	}
+	if let Some(path) = &config.replica_config_file {
+		if let Err(error) = ReplicaConfig::load(path).await {
+			report.error("--replica-config-file", error.to_string());
+		}
+	}
+	if let Some(path) = &config.tenants_file {
+		match Tenants::load(path).await {
+			Ok(tenants) => {
+				for namespace in tenants.namespaces() {
+					check_namespace(&mut report, "--tenants-file", namespace);
+				}
+			},
+			Err(error) => report.error("--tenants-file", error.to_string())
+		};
+	}
+	if let (Some(path), Some(this)) = (&config.shard_ring_file, &config.shard_self) {
+		if let Err(error) = Shards::load(path, this).await {
+			report.error("--shard-ring-file", error.to_string());
+		}
+	}
+	#[cfg(feature = "wasm-plugins")]
+	for path in &config.plugin {
+		if let Err(error) = api::plugin::wasm::WasmPlugin::load(path).await {
+			report.error("--plugin", format!("{}: {error}", path.display()));
+		}
+	}
+	report
+}
+
+async fn serve(config: Config, log_handle: LogHandle) {
+	#[cfg(feature = "chaos")]
+	chaos::init(config.chaos.clone());
+	report::init(&config.report);
+	sampling::init(&config.sampling);
+	dns::init(&config.dns);
+	storage::metrics::set_slow_threshold(*config.storage_slow_threshold);
+	storage::readahead::configure(ReadAhead { buffer_size: config.storage_read_buffer_size, chunks: config.storage_read_ahead });
+	storage::health::set_failure_threshold(config.storage_failure_threshold);
 	let repo = config.storage.repository();
-	let upstream = config.upstream.clients().await.unwrap();
+	let mut secrets = match fetch_secrets(&config, &repo).await {
+		Ok(v) => v,
+		Err(error) => {
+			error!(%error, "Failed to fetch secrets");
+			std::process::exit(1);
+		}
+	};
+	let report = validate(&config, &repo).await;
+	report.log();
+	if (report.has_errors()) {
+		error!("Invalid configuration; run the check-config subcommand to see what's wrong without starting up");
+		std::process::exit(1);
+	}
+	api::info::export_build_info(&repo);
+	if let Err(error) = storage::layout::check(&repo).await {
+		error!(%error, "Storage layout check failed");
+		std::process::exit(1);
+	}
+	if let Err(error) = api::auth::check_stored_partition_key(&repo).await {
+		warn!(%error, "Failed to check storage for a partition key left there");
+	}
+	if let Err(error) = storage::check::run(&repo, config.storage_check).await {
+		error!(%error, "Storage consistency check failed");
+	}
+	let pins = match Pins::load(&repo, config.pins_file.as_deref()).await {
+		Ok(v) => Arc::new(v),
+		Err(error) => {
+			error!(%error, "Failed to load pins");
+			std::process::exit(1);
+		}
+	};
+	let aliases = match Aliases::load(&repo, config.aliases_file.as_deref()).await {
+		Ok(v) => Arc::new(v),
+		Err(error) => {
+			error!(%error, "Failed to load aliases");
+			std::process::exit(1);
+		}
+	};
+	let mirror_entries = match &config.mirror_file {
+		Some(path) => match mirror::load(path).await {
+			Ok(v) => v,
+			Err(error) => {
+				error!(%error, "Failed to load mirror file");
+				std::process::exit(1);
+			}
+		},
+		None => Vec::new()
+	};
+	let replicator = match &config.replica_config_file {
+		Some(path) => match ReplicaConfig::load(path).await {
+			Ok(replica) => {
+				let (replicator, worker) = replica.start(repo.clone());
+				actix_web::rt::spawn(worker);
+				Some(replicator)
+			},
+			Err(error) => {
+				error!(%error, "Failed to load replica config file");
+				std::process::exit(1);
+			}
+		},
+		None => None
+	};
+	let tenants = match &config.tenants_file {
+		Some(path) => match Tenants::load(path).await {
+			Ok(tenants) => {
+				tenants.refresh_usage(&repo).await;
+				Some(Arc::new(tenants))
+			},
+			Err(error) => {
+				error!(%error, "Failed to load tenants file");
+				std::process::exit(1);
+			}
+		},
+		None => None
+	};
+	#[cfg_attr(not(feature = "wasm-plugins"), allow(unused_mut))]
+	let mut plugins: Vec<Arc<dyn api::plugin::Plugin>> = Vec::new();
+	#[cfg(feature = "wasm-plugins")]
+	for path in &config.plugin {
+		match api::plugin::wasm::WasmPlugin::load(path).await {
+			Ok(plugin) => plugins.push(Arc::new(plugin)),
+			Err(error) => {
+				error!(%error, path = %path.display(), "Failed to load plugin");
+				std::process::exit(1);
+			}
+		};
+	}
+	if let Some(key) = config.checkpoint_tls_key.as_deref().filter(|_| config.checkpoint) {
+		tls_sessions::enable(key);
+	}
+	let mut upstream = config.upstream.clients().await.unwrap();
+	if let Some(secrets) = secrets.as_mut() {
+		if let Err(error) = secrets.fill_upstream(&mut upstream).await {
+			error!(%error, "Failed to fetch upstream credentials");
+			std::process::exit(1);
+		}
+	}
+	if let Err(error) = api::ttl::load(&repo, &mut upstream).await {
+		error!(%error, "Failed to load invalidation times");
+		std::process::exit(1);
+	}
+	actix_web::rt::spawn(repo.clone().refresh_stats());
+	if (config.storage_failure_threshold > 0) {
+		actix_web::rt::spawn(storage::health::monitor(repo.clone(), *config.storage_recheck_interval));
+	}
+	if (!config.storage_index_interval.is_zero()) {
+		actix_web::rt::spawn(storage::index::run(repo.clone(), *config.storage_index_interval));
+	}
 	let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+	let in_flight = InFlight::default();
+	let prometheus = PrometheusMetricsBuilder::new("http").endpoint("/metrics").build().unwrap();
+	let helm_repository = config.helm_repository;
+	let debug_endpoints = config.debug_endpoints;
+	let trusted_proxies = TrustedProxies::from(config.trusted_proxies.clone());
+	let identities = Arc::new(ClientIdentities::new(config.client_identity_header.clone(), config.client_identity_limit));
+	let log_handle = web::Data::new(log_handle);
+	let shards = match (&config.shard_ring_file, &config.shard_self) {
+		(Some(path), Some(this)) => match Shards::load(path, this).await {
+			Ok(shards) => {
+				let shards = Arc::new(shards);
+				actix_web::rt::spawn(shards.clone().watch(path.clone()));
+				Some(shards)
+			},
+			Err(error) => {
+				error!(%error, "Failed to load shard ring file");
+				std::process::exit(1);
+			}
+		},
+		_ => None
+	};
+	let base_path = api::normalize_base_path(&config.base_path);
+	let secrets_repo = repo.clone();
+	// Cleanup starts once the rest of the per-request config has these, to follow runtime
+	// invalidation times
+	let (cleanup_repo, cleanup_pins, cleanup_tenants) = (repo.clone(), pins.clone(), tenants.clone());
+	let handoff = Arc::new(Handoff::default());
+	let per_request_config = web::Data::new(
+		api::RequestConfig::new(repo, upstream, config.default_namespace, config.check_cache_digest, config.max_manifest_size)
+			.with_base_path(base_path.clone())
+			.with_max_page_size(config.max_page_size)
+			.with_deadlines(*config.manifest_deadline, *config.blob_deadline)
+			.with_client_abort_policy(config.client_abort_policy)
+			.with_known_blob_ttl(*config.known_blob_ttl)
+			.with_pins(pins)
+			.with_aliases(aliases)
+			.with_check_manifest_digest(config.check_manifest_digest)
+			.with_cache_control(CacheControl { policy: config.cache_control, immutable_max_age: *config.immutable_max_age, tag_max_age: *config.tag_max_age })
+			.with_cache_status_headers(config.cache_status_headers)
+			.with_cdn(config.cdn())
+			.with_limits(Limits { max_header_size: config.max_header_size, max_upload_chunk_size: config.max_upload_chunk_size })
+			.with_compression(config.compress_responses)
+			.with_instance_name(config.instance_name.clone())
+			.with_provenance(config.record_provenance)
+			.with_timestamper(Timestamper::new(config.timestamp_url.clone(), config.attestation_key.as_deref()))
+			.with_webhook_token(config.webhook_token.clone())
+			.with_upstream_override_token(config.upstream_override_token.clone())
+			.with_admin_token(config.admin_token.clone())
+			.with_trash(!config.trash_retention.is_zero())
+			.with_listener_namespaces(config.listeners())
+			.with_replicator(replicator)
+			.with_signing_key(config.url_signing_key.as_deref(), *config.signed_url_max_ttl)
+			.with_tenants(tenants)
+			.with_prefetch(config.prefetch_strategy())
+			.with_shards(shards)
+			.with_handoff(handoff.clone())
+			.with_plugins(plugins)
+	);
+	if (config.checkpoint) {
+		match checkpoint::restore(&per_request_config).await {
+			Ok(restored) => {
+				let request_config = per_request_config.clone();
+				let limit = config.checkpoint_warm_tokens;
+				actix_web::rt::spawn(async move { restored.warm_up(&request_config, limit).await });
+			},
+			Err(error) => warn!(%error, "Failed to restore checkpoint")
+		}
+	}
+	let state = per_request_config.clone();
 	let background = {
-		let repo = repo.clone();
-		let upstream = upstream.invalidation_config();
+		let repo = cleanup_repo;
+		let request_config = per_request_config.clone();
+		let eviction = config.eviction.clone();
+		let in_flight = in_flight.clone();
+		let pins = cleanup_pins;
+		let tenants = cleanup_tenants;
+		let trash_retention = *config.trash_retention;
 		tokio::task::spawn(async move {
 			let mut interval = tokio::time::interval(Duration::from_secs(300));
 			loop {
@@ -102,53 +1039,130 @@ async fn main() {
 					_ = interval.tick() => (),
 					_ = &mut shutdown_rx => break
 				};
-				cleanup(&upstream, &repo).await;
+				cleanup(&request_config.invalidation_config().await, &repo, &pins, tenants.as_deref(), trash_retention, eviction.pacer(in_flight.clone())).await;
 			}
 		})
 	};
 
-	let prometheus = PrometheusMetricsBuilder::new("http").endpoint("/metrics").build().unwrap();
-	let per_request_config = web::Data::new(api::RequestConfig::new(repo, upstream, config.default_namespace, config.check_cache_digest));
+	let mirror = match mirror_entries.is_empty() {
+		true => None,
+		false => Some(actix_web::rt::spawn(mirror::run(per_request_config.clone(), mirror_entries, *config.mirror_interval)))
+	};
+	if (config.restart_sweep_tags > 0) {
+		let sweep = Sweep { tags: config.restart_sweep_tags, batch_size: config.restart_sweep_batch_size, interval: *config.restart_sweep_interval };
+		actix_web::rt::spawn(sweep::run(per_request_config.clone(), sweep));
+	}
+	let probe = config.probe_image.clone().map(|image| actix_web::rt::spawn(probe::run(per_request_config.clone(), image, *config.probe_interval)));
+
+	if let Some(path) = config.upstream.docker_config_file() {
+		actix_web::rt::spawn(docker_config::watch(per_request_config.clone(), path.clone()));
+	}
+	if let Some(secrets) = secrets {
+		actix_web::rt::spawn(secrets.run(per_request_config.clone(), secrets_repo));
+	}
 
 	let server = actix_web::HttpServer::new(move || {
 		actix_web::App::new()
 			.app_data(per_request_config.clone())
+			.app_data(log_handle.clone())
 			.wrap(prometheus.clone())
-			.service(
-				web::scope("/v2")
-					.wrap(actix_web::middleware::Logger::default())
-					.route("/", web::get().to(api::root))
-					// /v2/library/telegraf/manifests/1.24-alpine
-					// /v2/library/redis/manifests/sha256:226cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883
-					// /v2/docker.io/library/telegraf/manifests/1.24-alpine
-					// /v2/docker.io/library/redis/manifests/sha256:226cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883
-					.route("/{image:[^{}]+}/manifests/{reference}", web::head().to(api::manifest))
-					.route("/{image:[^{}]+}/manifests/{reference}", web::get().to(api::manifest))
-					// /v2/grafana/grafana/blobs/sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd
-					// /v2/docker.io/grafana/grafana/blobs/sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd
-					.route("/{image:[^{}]+}/blobs/{digest}", web::get().to(api::blob))
-					.wrap_fn(|req, srv| {
-						srv.call(req).map(|response| {
-							response.map(|mut ok| {
-								ok.headers_mut()
-									.insert(HeaderName::from_static("docker-distribution-api-version"), HeaderValue::from_static("registry/2.0"));
-								ok
-							})
+			.wrap_fn({
+				let trusted_proxies = trusted_proxies.clone();
+				let in_flight = in_flight.clone();
+				let identities = identities.clone();
+				move |req, srv| {
+					let in_flight = in_flight.track();
+					let pull = identities.record(&req);
+					let request_id = RequestId::from_request(&req);
+					let client_ip = ClientIp::from_request(&req, &trusted_proxies);
+					let span = trace::request(request_id.as_str(), &client_ip);
+					if let Some(context) = TraceContext::from_request(&req) {
+						span.record("trace_id", context.trace_id().as_str());
+						req.extensions_mut().insert(context);
+					}
+					req.extensions_mut().insert(request_id.clone());
+					req.extensions_mut().insert(client_ip);
+					srv.call(req).instrument(span).map(move |response| {
+						// Until the response starts; a blob streamed afterwards isn't counted
+						drop(in_flight);
+						response.map(|mut ok| {
+							if let (Some(pull), true, BodySize::Sized(len)) = (pull, ok.status().is_success(), ok.response().body().size()) {
+								pull.sent(len);
+							}
+							ok.headers_mut().insert(RequestId::header_name(), request_id.header_value());
+							ok
 						})
 					})
-			)
+				}
+			})
+			// Registered ahead of the base path scope, which would otherwise swallow it when the base
+			// path is empty
+			.route("/", web::get().to(liveness))
 			.service(
-				web::scope("/_admin")
-					.wrap(actix_web::middleware::Logger::default())
-					.route("/{image:[^{}]+}/manifests/{reference}", web::delete().to(api::delete_manifest))
-					.route("/{image:[^{}]+}/blobs/{digest}", web::delete().to(api::delete_blob))
+				web::scope(&base_path)
+					.configure(|cfg| {
+						if (debug_endpoints) {
+							api::debug::configure(cfg);
+						}
+					})
+					.configure(api::registry)
+					.configure(api::admin)
+					.configure(api::signed::configure)
+					.configure(|cfg| {
+						if (helm_repository) {
+							api::helm::configure(cfg);
+						}
+					})
 			)
-			.route("/", web::get().to(liveness))
 	});
-	match config.listen {
-		socket_address::Address::Network(addr) => server.shutdown_timeout(10).bind(&addr).unwrap().run().await.unwrap(),
-		socket_address::Address::UnixSocket(path) => server.shutdown_timeout(10).bind_uds(&path).unwrap().run().await.unwrap()
+	let server = server
+		.keep_alive(match config.keep_alive.is_zero() {
+			true => KeepAlive::Disabled,
+			false => KeepAlive::Timeout(*config.keep_alive)
+		})
+		.client_request_timeout(*config.client_request_timeout)
+		.client_disconnect_timeout(*config.client_disconnect_timeout)
+		.max_connections(config.max_connections)
+		.backlog(config.backlog);
+	let server = match config.workers {
+		0 => server,
+		n => server.workers(n)
 	};
+	// Having handed off, there's no hurry to stop before the pulls being served are done
+	let server = match config.handoff_socket.is_some() {
+		true => server.shutdown_timeout(config.handoff_drain_timeout.as_secs()),
+		false => server.shutdown_timeout(10)
+	};
+	let mut server = match (&config.listen, config.reuse_port) {
+		(socket_address::Address::Network(addr), true) => handoff::reuse_port_listeners(addr, config.backlog).unwrap().into_iter().fold(server, |server, listener| server.listen(listener).unwrap()),
+		(socket_address::Address::Network(addr), false) => server.bind(addr).unwrap(),
+		(socket_address::Address::UnixSocket(path), _) => server.bind_uds(path).unwrap()
+	};
+	for listener in config.listeners() {
+		server = match config.reuse_port {
+			true => handoff::reuse_port_listeners(listener.address, config.backlog).unwrap().into_iter().fold(server, |server, listener| server.listen(listener).unwrap()),
+			false => server.bind(listener.address).unwrap()
+		};
+	}
+	if let Some(path) = config.handoff_socket.as_ref() {
+		handoff::take_over(path, handoff.clone()).await;
+	}
+	let server = server.run();
+	if let Some(path) = config.handoff_socket.clone() {
+		actix_web::rt::spawn(handoff::serve(path, server.handle()));
+	}
+	server.await.unwrap();
+	if let Some(mirror) = mirror {
+		mirror.abort();
+	}
+	if let Some(probe) = probe {
+		probe.abort();
+	}
+	if (config.checkpoint) {
+		if let Err(error) = checkpoint::save(&state).await {
+			error!(%error, "Failed to save checkpoint");
+		}
+	}
 	shutdown_tx.send(()).unwrap();
 	background.await.unwrap();
 }
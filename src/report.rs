@@ -0,0 +1,122 @@
+//! Error reports:  panics, and errors that mean something is wrong with the cache itself (digest
+//! mismatches, failed storage writes), are POSTed as JSON to a webhook when they happen, so that
+//! operators hear about them without having to watch the logs.
+
+use core::fmt;
+use core::time::Duration;
+
+use clap::Parser;
+use once_cell::sync::OnceCell;
+use reqwest::header;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::debug;
+use tracing::warn;
+
+static REPORTER: OnceCell<Reporter> = OnceCell::new();
+
+#[derive(Clone, Debug, Parser)]
+pub struct ReportConfig {
+	/// URL to POST a JSON report to on panics and high-severity errors, such as digest mismatches
+	/// and failed storage writes
+	#[clap(env, long)]
+	error_webhook: Option<reqwest::Url>,
+	/// Fraction of errors, between 0 and 1, that are reported; panics are always reported
+	#[clap(env, long, default_value_t = 1.0)]
+	error_sample_rate: f64,
+	/// The most reports to send in any one minute; past that, they're dropped until the minute is up
+	#[clap(env, long, default_value_t = 60)]
+	error_reports_per_minute: u32
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+	Panic,
+	DigestMismatch,
+	StorageWrite
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+	kind: Kind,
+	message: String,
+	path: Option<String>,
+	timestamp: String,
+	version: &'static str
+}
+
+struct Reporter {
+	tx: mpsc::Sender<Report>,
+	sample_rate: f64
+}
+
+/// Starts sending reports to the configured webhook, if there is one, and hooks panics.  Only the
+/// first call has any effect.
+pub fn init(config: &ReportConfig) {
+	let Some(url) = config.error_webhook.clone() else {
+		return;
+	};
+	let (tx, rx) = mpsc::channel(64);
+	if (REPORTER.set(Reporter { tx, sample_rate: config.error_sample_rate }).is_err()) {
+		return;
+	}
+	tokio::spawn(send(url, rx, config.error_reports_per_minute));
+	let previous = std::panic::take_hook();
+	std::panic::set_hook(Box::new(move |info| {
+		report(Kind::Panic, info, None);
+		previous(info);
+	}));
+}
+
+/// Queues a report, subject to sampling.  Never waits; if the webhook is backed up, the report is
+/// dropped.
+pub fn report(kind: Kind, message: impl fmt::Display, path: Option<&str>) {
+	let Some(reporter) = REPORTER.get() else {
+		return;
+	};
+	if (kind != Kind::Panic && rand::random::<f64>() >= reporter.sample_rate) {
+		return;
+	}
+	let report = Report {
+		kind,
+		message: message.to_string(),
+		path: path.map(str::to_owned),
+		timestamp: OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+		version: env!("CARGO_PKG_VERSION")
+	};
+	if (reporter.tx.try_send(report).is_err()) {
+		debug!(?kind, "Error report queue full; dropping report");
+	}
+}
+
+async fn send(url: reqwest::Url, mut rx: mpsc::Receiver<Report>, per_minute: u32) {
+	let client = reqwest::Client::new();
+	let mut window = Instant::now();
+	let mut sent = 0;
+	while let Some(report) = rx.recv().await {
+		if (window.elapsed() >= Duration::from_secs(60)) {
+			window = Instant::now();
+			sent = 0;
+		}
+		if (sent >= per_minute) {
+			debug!(kind = ?report.kind, "Error report rate limit reached; dropping report");
+			continue;
+		}
+		sent += 1;
+		let body = match serde_json::to_vec(&report) {
+			Ok(v) => v,
+			Err(error) => {
+				warn!(%error, "Failed to serialize error report");
+				continue;
+			}
+		};
+		let result = client.post(url.clone()).timeout(Duration::from_secs(10)).header(header::CONTENT_TYPE, "application/json").body(body).send().await.and_then(reqwest::Response::error_for_status);
+		if let Err(error) = result {
+			warn!(%error, "Failed to send error report");
+		}
+	}
+}
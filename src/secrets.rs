@@ -0,0 +1,384 @@
+//! External secret stores:  upstream credentials and S3 keys fetched from HashiCorp Vault or AWS
+//! Secrets Manager at startup, and again on an interval, so that none of them has to appear in
+//! flags, environment variables, or config files.  Secrets with a renewable lease, such as the
+//! dynamic credentials Vault's AWS secrets engine issues, are renewed before it runs out, and
+//! fetched anew once it can't be.
+
+use core::convert::Infallible;
+use core::str::FromStr;
+use core::time::Duration;
+use std::collections::HashMap;
+
+use actix_web::web;
+use clap::Parser;
+use compact_str::CompactString;
+use rusoto_core::request::HttpClient;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use rusoto_core::RusotoError;
+use rusoto_credential::DefaultCredentialsProvider;
+use serde::Deserialize;
+use serde_json::Map;
+use serde_json::Value;
+use tracing::info;
+use tracing::warn;
+
+use crate::api::RequestConfig;
+use crate::storage::Repository;
+use crate::upstream::Clients;
+use crate::util::SecretString;
+
+/// Leases are renewed once this much of them is left.
+const RENEW_AT: f64 = 0.5;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SecretSource {
+	/// A path under Vault's API, such as `secret/data/oci-registry` for a KV v2 secret or
+	/// `aws/creds/oci-registry` for dynamic AWS credentials
+	Vault(String),
+	/// The name or ARN of a secret in AWS Secrets Manager, whose value is JSON
+	AwsSecretsManager(String)
+}
+
+impl FromStr for SecretSource {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.split_once(':') {
+			Some(("vault", path)) if !path.trim_matches('/').is_empty() => Ok(Self::Vault(path.trim_matches('/').to_owned())),
+			Some(("aws-secrets-manager", id)) if !id.is_empty() => Ok(Self::AwsSecretsManager(id.to_owned())),
+			_ => Err(Error::InvalidSource(s.to_owned()))
+		}
+	}
+}
+
+impl core::fmt::Display for SecretSource {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Self::Vault(path) => write!(f, "vault:{path}"),
+			Self::AwsSecretsManager(id) => write!(f, "aws-secrets-manager:{id}")
+		}
+	}
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct SecretsConfig {
+	/// Where to fetch upstream credentials from, as `vault:<path>` or
+	/// `aws-secrets-manager:<secret id>`; the secret holds the same map from namespace to username
+	/// and password as `$UPSTREAM_CREDENTIALS`, and wins over it
+	#[clap(env, long)]
+	upstream_credentials_secret: Option<SecretSource>,
+	/// Where to fetch S3 credentials from, the same way; the secret holds `access_key` and
+	/// `secret_key`, and optionally `security_token`, as Vault's AWS secrets engine issues them
+	#[clap(env, long)]
+	s3_credentials_secret: Option<SecretSource>,
+	/// How often secrets without a lease are fetched again
+	#[clap(env, long, default_value = "5m")]
+	secrets_refresh_interval: humantime::Duration,
+	#[clap(env = "VAULT_ADDR", long)]
+	vault_addr: Option<String>,
+	#[clap(env = "VAULT_TOKEN", long, hide_env_values = true)]
+	vault_token: Option<SecretString>,
+	/// The region to use AWS Secrets Manager in; AWS credentials are found the usual way, from the
+	/// environment, a profile, or the instance or task role
+	#[clap(env = "AWS_REGION", long, default_value = "us-east-1")]
+	secrets_manager_region: String
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("Invalid secret source {0:?}; expected vault:<path> or aws-secrets-manager:<secret id>")]
+	InvalidSource(String),
+	#[error("--vault-addr and --vault-token (or $VAULT_ADDR and $VAULT_TOKEN) are needed to read secrets from Vault")]
+	VaultNotConfigured,
+	#[error("Vault request failed: {0}")]
+	Vault(#[from] reqwest::Error),
+	#[error("Vault returned {0} for {1}")]
+	VaultStatus(reqwest::StatusCode, String),
+	#[error("Secrets Manager request failed: {0}")]
+	SecretsManager(String),
+	#[error("Secret {0} isn't in the expected format: {1}")]
+	Format(SecretSource, String)
+}
+
+#[derive(Debug)]
+struct Lease {
+	id: String,
+	duration: Duration,
+	renewable: bool
+}
+
+#[derive(Debug)]
+struct Secret {
+	data: Map<String, Value>,
+	lease: Option<Lease>
+}
+
+#[derive(Deserialize)]
+struct VaultResponse {
+	#[serde(default)]
+	lease_id: String,
+	#[serde(default)]
+	lease_duration: u64,
+	#[serde(default)]
+	renewable: bool,
+	#[serde(default)]
+	data: Map<String, Value>
+}
+
+impl VaultResponse {
+	fn lease(&self) -> Option<Lease> {
+		match (self.lease_id.is_empty(), self.lease_duration) {
+			(false, duration) if duration > 0 => Some(Lease { id: self.lease_id.clone(), duration: Duration::from_secs(duration), renewable: self.renewable }),
+			_ => None
+		}
+	}
+}
+
+#[derive(Deserialize)]
+struct UpstreamCredentials {
+	username: SecretString,
+	password: SecretString
+}
+
+#[derive(Deserialize)]
+struct S3Credentials {
+	access_key: SecretString,
+	secret_key: SecretString,
+	#[serde(default)]
+	security_token: Option<SecretString>
+}
+
+/// Reads secrets from wherever they're kept.
+struct Store {
+	http: reqwest::Client,
+	vault: Option<(String, SecretString)>,
+	aws: Option<(rusoto_core::Client, Region)>
+}
+
+impl Store {
+	async fn fetch(&self, source: &SecretSource) -> Result<Secret, Error> {
+		match source {
+			SecretSource::Vault(path) => self.vault_read(path).await,
+			SecretSource::AwsSecretsManager(id) => self.secrets_manager_read(source, id).await
+		}
+	}
+
+	async fn vault_request(&self, request: impl FnOnce(&reqwest::Client, String) -> reqwest::RequestBuilder, path: &str) -> Result<VaultResponse, Error> {
+		let (addr, token) = self.vault.as_ref().ok_or(Error::VaultNotConfigured)?;
+		let response = request(&self.http, format!("{}/v1/{path}", addr.trim_end_matches('/'))).header("X-Vault-Token", token.expose()).send().await?;
+		if (!response.status().is_success()) {
+			return Err(Error::VaultStatus(response.status(), path.to_owned()));
+		}
+		let body = response.bytes().await?;
+		serde_json::from_slice(&body).map_err(|e| Error::Format(SecretSource::Vault(path.to_owned()), e.to_string()))
+	}
+
+	async fn vault_read(&self, path: &str) -> Result<Secret, Error> {
+		let response = self.vault_request(|http, url| http.get(url), path).await?;
+		let lease = response.lease();
+		let mut data = response.data;
+		// KV v2 wraps the secret itself in another layer, alongside its metadata
+		if let (Some(Value::Object(inner)), true) = (data.get("data"), data.contains_key("metadata")) {
+			data = inner.clone();
+		}
+		Ok(Secret { data, lease })
+	}
+
+	/// Extends a lease, returning how long it now has left.
+	async fn vault_renew(&self, lease: &Lease) -> Result<Duration, Error> {
+		let body = serde_json::json!({ "lease_id": lease.id }).to_string();
+		let response = self.vault_request(|http, url| http.put(url).header("Content-Type", "application/json").body(body), "sys/leases/renew").await?;
+		Ok(Duration::from_secs(response.lease_duration))
+	}
+
+	async fn secrets_manager_read(&self, source: &SecretSource, id: &str) -> Result<Secret, Error> {
+		#[derive(Deserialize)]
+		struct Response {
+			#[serde(rename = "SecretString")]
+			secret_string: Option<String>
+		}
+
+		let (client, region) = self.aws.as_ref().ok_or_else(|| Error::SecretsManager("no AWS client".into()))?;
+		let mut request = SignedRequest::new("POST", "secretsmanager", region, "/");
+		request.set_content_type("application/x-amz-json-1.1".to_owned());
+		request.add_header("x-amz-target", "secretsmanager.GetSecretValue");
+		request.set_payload(Some(serde_json::json!({ "SecretId": id }).to_string().into_bytes()));
+		let response = client.sign_and_dispatch(request).await.map_err(|e| Error::SecretsManager(RusotoError::<Infallible>::from(e).to_string()))?;
+		let response = response.buffer().await.map_err(|e| Error::SecretsManager(e.to_string()))?;
+		if (!response.status.is_success()) {
+			return Err(Error::SecretsManager(format!("{} for {id}: {}", response.status, String::from_utf8_lossy(&response.body))));
+		}
+		let format = |e: serde_json::Error| Error::Format(source.clone(), e.to_string());
+		let response: Response = serde_json::from_slice(&response.body).map_err(format)?;
+		let value = response.secret_string.ok_or_else(|| Error::Format(source.clone(), "not a string secret".into()))?;
+		Ok(Secret { data: serde_json::from_str(&value).map_err(format)?, lease: None })
+	}
+}
+
+/// Secrets this instance takes its credentials from, and their leases.
+pub struct Secrets {
+	store: Store,
+	upstream: Option<(SecretSource, Option<Lease>)>,
+	s3: Option<(SecretSource, Option<Lease>)>,
+	interval: Duration
+}
+
+impl SecretsConfig {
+	/// Whether S3 credentials will be fetched from a secret store.
+	pub fn provides_s3_credentials(&self) -> bool {
+		self.s3_credentials_secret.is_some()
+	}
+
+	/// Sets up a client for each secret store in use; `None` if no secrets are to be fetched.
+	pub fn secrets(&self) -> Result<Option<Secrets>, Error> {
+		let sources = [self.upstream_credentials_secret.as_ref(), self.s3_credentials_secret.as_ref()];
+		if (sources.iter().all(Option::is_none)) {
+			return Ok(None);
+		}
+		let uses = |f: fn(&SecretSource) -> bool| sources.iter().flatten().any(|s| f(s));
+		let vault = match (self.vault_addr.clone(), self.vault_token.clone()) {
+			(Some(addr), Some(token)) => Some((addr, token)),
+			_ if uses(|s| matches!(s, SecretSource::Vault(_))) => return Err(Error::VaultNotConfigured),
+			_ => None
+		};
+		let aws = match uses(|s| matches!(s, SecretSource::AwsSecretsManager(_))) {
+			true => {
+				let region = Region::from_str(&self.secrets_manager_region).map_err(|e| Error::SecretsManager(e.to_string()))?;
+				let credentials = DefaultCredentialsProvider::new().map_err(|e| Error::SecretsManager(e.to_string()))?;
+				let http = HttpClient::new().map_err(|e| Error::SecretsManager(e.to_string()))?;
+				Some((rusoto_core::Client::new_with(credentials, http), region))
+			},
+			false => None
+		};
+		Ok(Some(Secrets {
+			store: Store { http: reqwest::Client::new(), vault, aws },
+			upstream: self.upstream_credentials_secret.clone().map(|s| (s, None)),
+			s3: self.s3_credentials_secret.clone().map(|s| (s, None)),
+			interval: *self.secrets_refresh_interval
+		}))
+	}
+}
+
+fn upstream_credentials(source: &SecretSource, secret: Secret) -> Result<HashMap<CompactString, UpstreamCredentials>, Error> {
+	serde_json::from_value(Value::Object(secret.data)).map_err(|e| Error::Format(source.clone(), e.to_string()))
+}
+
+fn set_s3_credentials(source: &SecretSource, secret: Secret, repo: &Repository) -> Result<(), Error> {
+	let keys: S3Credentials = serde_json::from_value(Value::Object(secret.data)).map_err(|e| Error::Format(source.clone(), e.to_string()))?;
+	if let Some(credentials) = repo.s3_credentials() {
+		credentials.set(keys.access_key.expose().to_owned(), keys.secret_key.expose().to_owned(), keys.security_token.map(|t| t.expose().to_owned()));
+	}
+	Ok(())
+}
+
+fn set_upstream_credentials(clients: &mut Clients, credentials: HashMap<CompactString, UpstreamCredentials>) {
+	for (namespace, credentials) in credentials {
+		match clients.set_credentials(&namespace, &credentials.username, &credentials.password) {
+			Ok(true) => (),
+			Ok(false) => warn!(namespace = namespace.as_str(), "Namespace found in the upstream credentials secret, but not in the upstream config; ignoring it"),
+			Err(error) => warn!(namespace = namespace.as_str(), %error, "Failed to set up a client with credentials from the secret store")
+		};
+	}
+}
+
+impl Secrets {
+	/// Fetches S3 credentials into `repo`, if they're kept in a secret store.
+	pub async fn fill_s3(&mut self, repo: &Repository) -> Result<(), Error> {
+		let Some((source, lease)) = self.s3.as_mut() else {
+			return Ok(());
+		};
+		let mut secret = self.store.fetch(source).await?;
+		*lease = secret.lease.take();
+		set_s3_credentials(source, secret, repo)
+	}
+
+	/// Fetches upstream credentials into `clients`, if they're kept in a secret store.
+	pub async fn fill_upstream(&mut self, clients: &mut Clients) -> Result<(), Error> {
+		let Some((source, lease)) = self.upstream.as_mut() else {
+			return Ok(());
+		};
+		let mut secret = self.store.fetch(source).await?;
+		*lease = secret.lease.take();
+		set_upstream_credentials(clients, upstream_credentials(source, secret)?);
+		Ok(())
+	}
+
+	/// How long until something needs renewing or fetching again.
+	fn next_refresh(&self) -> Duration {
+		let leases = [self.upstream.as_ref(), self.s3.as_ref()].into_iter().flatten().filter_map(|(_, lease)| lease.as_ref());
+		leases.map(|lease| lease.duration.mul_f64(RENEW_AT).max(Duration::from_secs(1))).fold(self.interval, Duration::min)
+	}
+
+	/// Renews the secret's lease if it can be, and otherwise fetches it anew, returning the new
+	/// secret if there is one.
+	async fn refresh(store: &Store, source: &SecretSource, lease: &mut Option<Lease>) -> Result<Option<Secret>, Error> {
+		if let Some(current) = lease.as_mut().filter(|l| l.renewable) {
+			match store.vault_renew(current).await {
+				// A lease renewed for less than it's asked for is reaching its maximum TTL
+				Ok(duration) if duration >= current.duration.mul_f64(RENEW_AT) => {
+					current.duration = duration;
+					return Ok(None);
+				},
+				Ok(_) => info!(source = %source, "Lease is nearly at its maximum TTL; fetching a new secret"),
+				Err(error) => warn!(source = %source, %error, "Failed to renew lease; fetching a new secret")
+			};
+		}
+		let mut secret = store.fetch(source).await?;
+		*lease = secret.lease.take();
+		Ok(Some(secret))
+	}
+
+	/// Keeps credentials fresh for as long as the server runs.
+	pub async fn run(mut self, config: web::Data<RequestConfig>, repo: Repository) {
+		loop {
+			tokio::time::sleep(self.next_refresh()).await;
+			if let Some((source, lease)) = self.s3.as_mut() {
+				let result = match Self::refresh(&self.store, source, lease).await {
+					Ok(Some(secret)) => set_s3_credentials(source, secret, &repo),
+					Ok(None) => Ok(()),
+					Err(error) => Err(error)
+				};
+				if let Err(error) = result {
+					warn!(source = %source, %error, "Failed to refresh S3 credentials; keeping the ones we have");
+				}
+			}
+			if let Some((source, lease)) = self.upstream.as_mut() {
+				let result = match Self::refresh(&self.store, source, lease).await {
+					Ok(Some(secret)) => upstream_credentials(source, secret).map(Some),
+					Ok(None) => Ok(None),
+					Err(error) => Err(error)
+				};
+				match result {
+					Ok(Some(credentials)) => config.set_upstream_credentials(|clients| set_upstream_credentials(clients, credentials)).await,
+					Ok(None) => (),
+					Err(error) => warn!(source = %source, %error, "Failed to refresh upstream credentials; keeping the ones we have")
+				};
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sources() {
+		assert_eq!("vault:/secret/data/oci-registry/".parse::<SecretSource>().unwrap(), SecretSource::Vault("secret/data/oci-registry".into()));
+		assert_eq!("aws-secrets-manager:arn:aws:secretsmanager:us-east-1:1:secret:oci".parse::<SecretSource>().unwrap(), SecretSource::AwsSecretsManager("arn:aws:secretsmanager:us-east-1:1:secret:oci".into()));
+		for invalid in ["vault:", "secret/data/oci-registry", "gcp:x"] {
+			assert!(invalid.parse::<SecretSource>().is_err(), "{invalid}");
+		}
+	}
+
+	#[test]
+	fn refresh_schedule() {
+		let store = Store { http: reqwest::Client::new(), vault: None, aws: None };
+		let lease = |secs| Some(Lease { id: "aws/creds/x/1".into(), duration: Duration::from_secs(secs), renewable: true });
+		let mut secrets = Secrets { store, upstream: Some((SecretSource::Vault("kv/data/x".into()), None)), s3: None, interval: Duration::from_secs(300) };
+		assert_eq!(secrets.next_refresh(), Duration::from_secs(300));
+		secrets.s3 = Some((SecretSource::Vault("aws/creds/x".into()), lease(120)));
+		assert_eq!(secrets.next_refresh(), Duration::from_secs(60));
+	}
+}
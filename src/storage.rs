@@ -1,34 +1,86 @@
+use core::ops::Range;
 use core::time::Duration;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::iter;
 use std::time::SystemTime;
 
 use actix_web::body::SizedStream;
 use bytes::Bytes;
+use bytes::BytesMut;
 use clap::Subcommand;
 use compact_str::format_compact;
 use dkregistry::mediatypes::MediaTypes;
 use futures::stream::BoxStream;
+use futures::stream::StreamExt;
 use futures::stream::TryStream;
 use futures::stream::TryStreamExt;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::command::Command;
+use pacing::Pacer;
+
+pub mod check;
+pub mod dedup;
+pub mod distribution;
 mod error;
+pub mod export;
 pub mod filesystem;
+pub mod health;
+pub mod index;
+pub mod layout;
+pub mod metrics;
+pub mod pacing;
+pub mod readahead;
+pub mod replica;
 pub mod s3;
 
 pub use error::Error;
 
 #[derive(Clone, Debug, Subcommand)]
 pub enum StorageConfig {
-	S3(s3::Config),
-	Filesystem(filesystem::Config)
+	S3 {
+		#[clap(flatten)]
+		config: s3::Config,
+		#[clap(subcommand)]
+		command: Option<Command>
+	},
+	Filesystem {
+		#[clap(flatten)]
+		config: filesystem::Config,
+		#[clap(subcommand)]
+		command: Option<Command>
+	}
 }
 
 impl StorageConfig {
 	pub fn repository(&self) -> Repository {
 		match self {
-			Self::S3(config) => Repository::S3(config.repository()),
-			Self::Filesystem(config) => Repository::Filesystem(config.repository())
+			Self::S3 { config, .. } => Repository::S3(config.repository()),
+			Self::Filesystem { config, .. } => Repository::Filesystem(config.repository())
+		}
+	}
+
+	/// Whether storage credentials have to come from somewhere other than the storage flags.
+	pub fn needs_credentials(&self) -> bool {
+		match self {
+			Self::S3 { config, .. } => !config.has_credentials(),
+			Self::Filesystem { .. } => false
+		}
+	}
+
+	/// The root certificates S3 is checked against, if storage is S3.
+	pub fn tls_roots(&self) -> Option<s3::transport::TlsRoots> {
+		match self {
+			Self::S3 { config, .. } => Some(config.tls_roots()),
+			Self::Filesystem { .. } => None
+		}
+	}
+
+	pub fn command(&self) -> &Command {
+		match self {
+			Self::S3 { command, .. } | Self::Filesystem { command, .. } => command.as_ref().unwrap_or(&Command::Serve)
 		}
 	}
 }
@@ -41,23 +93,84 @@ pub enum Repository {
 
 pub struct ReadStream {
 	length: u64,
+	/// When the object was written, if it's read from storage
+	modified: Option<SystemTime>,
 	inner: BoxStream<'static, Result<Bytes, std::io::Error>>
 }
 
 impl ReadStream {
 	pub fn new(length: u64, inner: BoxStream<'static, Result<Bytes, std::io::Error>>) -> Self {
-		Self { length, inner }
+		Self { length, modified: None, inner }
+	}
+
+	pub fn with_modified(self, modified: SystemTime) -> Self {
+		Self { modified: Some(modified), ..self }
 	}
 
 	pub fn length(&self) -> u64 {
 		self.length
 	}
 
+	/// How long ago the object was written, if it's known.
+	pub fn age(&self) -> Option<Duration> {
+		self.modified.map(|modified| SystemTime::now().duration_since(modified).unwrap_or_default())
+	}
+
+	/// Counts the bytes read from this stream towards the backend's total.
+	fn counted(self, backend: &'static str) -> Self {
+		Self { inner: Box::pin(self.inner.inspect_ok(move |chunk| metrics::read(backend, chunk.len()))), ..self }
+	}
+
+	/// Buffers the stream as `--storage-read-buffer-size` and `--storage-read-ahead` say.
+	fn buffered(self, backend: &'static str) -> Self {
+		Self { inner: readahead::buffered(self.inner, self.length, backend), ..self }
+	}
+
 	pub fn into_inner(self) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
 		self.inner
 	}
 }
 
+/// What storage knows about an object, short of its contents.
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+	length: u64,
+	modified: SystemTime
+}
+
+impl Stat {
+	pub fn new(length: u64, modified: SystemTime) -> Self {
+		Self { length, modified }
+	}
+
+	pub fn length(&self) -> u64 {
+		self.length
+	}
+
+	pub fn modified(&self) -> SystemTime {
+		self.modified
+	}
+
+	pub fn age(&self) -> Duration {
+		SystemTime::now().duration_since(self.modified).unwrap_or_default()
+	}
+
+	/// Fails with [`Error::ObjectTooOld`] if the object is older than `invalidation`.
+	fn check_age(self, invalidation: Duration) -> Result<Self, Error> {
+		match self.age() {
+			age if age > invalidation => Err(Error::ObjectTooOld(age.into())),
+			_ => Ok(self)
+		}
+	}
+}
+
+/// A stream with the object's length and no body, for answering `HEAD` requests.
+impl From<Stat> for ReadStream {
+	fn from(stat: Stat) -> Self {
+		Self::new(stat.length, Box::pin(futures::stream::empty())).with_modified(stat.modified)
+	}
+}
+
 impl From<ReadStream> for SizedStream<BoxStream<'static, Result<Bytes, Box<dyn std::error::Error + 'static>>>> {
 	fn from(stream: ReadStream) -> Self {
 		SizedStream::new(stream.length, Box::pin(stream.inner.err_into()))
@@ -65,12 +178,88 @@ impl From<ReadStream> for SizedStream<BoxStream<'static, Result<Bytes, Box<dyn s
 }
 
 impl Repository {
-	pub async fn read(&self, object: &str, invalidation: Duration) -> Result<ReadStream, Error> {
-		let result = match self {
-			Self::S3(r) => r.read(object, invalidation).await?,
-			Self::Filesystem(r) => r.read(object.into(), invalidation).await?
+	/// The S3 client's credentials, which can be replaced while it's in use.
+	pub fn s3_credentials(&self) -> Option<&s3::SharedCredentials> {
+		match self {
+			Self::S3(repo) => Some(repo.credentials()),
+			Self::Filesystem(_) => None
+		}
+	}
+
+	/// Which storage backend this is, for reporting.
+	pub fn backend(&self) -> &'static str {
+		match self {
+			Self::S3(_) => "s3",
+			Self::Filesystem(_) => "filesystem"
+		}
+	}
+
+	/// Keeps the S3 stat cache up to date, if there is one and it's to be refreshed; returns right
+	/// away otherwise.
+	pub async fn refresh_stats(self) {
+		if let Self::S3(repo) = self {
+			repo.refresh_stats().await;
+		}
+	}
+
+	/// Makes sure storage can be reached at all:  that the bucket exists and the credentials are
+	/// good for listing it, or that the root is usable as a directory.
+	pub async fn check_access(&self) -> Result<(), Error> {
+		match self {
+			Self::S3(r) => {
+				r.list_keys(layout::VERSION_OBJECT).await?;
+			},
+			Self::Filesystem(r) => r.check_root().await?
 		};
-		Ok(result)
+		Ok(())
+	}
+
+	/// Opens an object for reading.  The time it takes is to the start of the object; bytes are
+	/// counted as they're read.
+	pub async fn read(&self, object: &str, invalidation: Duration) -> Result<ReadStream, Error> {
+		let backend = self.backend();
+		let stream = metrics::timed(backend, "read", object, async {
+			let result = match self {
+				Self::S3(r) => r.read(object, invalidation).await?,
+				Self::Filesystem(r) => r.read(object.into(), invalidation).await?
+			};
+			Ok::<_, Error>(result)
+		})
+		.await?;
+		Ok(stream.counted(backend).buffered(backend))
+	}
+
+	/// Reads just `range` of an object, which has to be within it.
+	pub async fn read_range(&self, object: &str, invalidation: Duration, range: Range<u64>) -> Result<ReadStream, Error> {
+		let backend = self.backend();
+		let stream = metrics::timed(backend, "read", object, async {
+			let result = match self {
+				Self::S3(r) => r.read_range(object, invalidation, range).await?,
+				Self::Filesystem(r) => r.read_range(object.into(), invalidation, range).await?
+			};
+			Ok::<_, Error>(result)
+		})
+		.await?;
+		Ok(stream.counted(backend).buffered(backend))
+	}
+
+	/// Looks up an object's length and age without reading it; on S3, this is a `HeadObject` rather
+	/// than a `GetObject`.
+	pub async fn stat(&self, object: &str, invalidation: Duration) -> Result<Stat, Error> {
+		let stat = metrics::timed(self.backend(), "stat", object, async {
+			let stat = match self {
+				Self::S3(r) => r.stat(object).await?,
+				Self::Filesystem(r) => r.stat(object.into()).await?
+			};
+			Ok::<_, Error>(stat)
+		})
+		.await?;
+		stat.check_age(invalidation)
+	}
+
+	/// Looks up several objects at once, a few at a time, returning results in the same order.
+	pub async fn stat_all(&self, objects: &[&str], invalidation: Duration) -> Vec<Result<Stat, Error>> {
+		futures::stream::iter(objects).map(|o| self.stat(o, invalidation)).buffered(16).collect().await
 	}
 
 	pub async fn write<S, E>(&self, object: &str, reader: S, length: i64) -> Result<(), Error>
@@ -79,48 +268,342 @@ impl Repository {
 		E: std::error::Error + From<std::io::Error> + Send + Sync + 'static,
 		Error: From<E>
 	{
-		#[allow(clippy::let_unit_value)] // Because it's likely that we will change the return type eventually, it'll require fewer changes, and it's harmless as-is.
-		let result = match self {
-			Self::S3(r) => r.write(object, reader, length).await?,
-			Self::Filesystem(r) => r.write(object.into(), reader).await?
-		};
-		Ok(result)
+		crate::chaos::storage_write().await;
+		let backend = self.backend();
+		let reader = reader.inspect_ok(move |chunk| metrics::written(backend, chunk.len()));
+		// Blobs are written as they're read from upstream, so for those, this includes waiting on it
+		metrics::timed(backend, "write", object, async {
+			#[allow(clippy::let_unit_value)] // Because it's likely that we will change the return type eventually, it'll require fewer changes, and it's harmless as-is.
+			let result = match self {
+				Self::S3(r) => r.write(object, reader, length).await?,
+				Self::Filesystem(r) => r.write(object.into(), reader).await?
+			};
+			Ok::<_, Error>(result)
+		})
+		.await
 	}
 
-	pub async fn delete(&self, object: &str) -> Result<(), Error> {
+	/// Stores a manifest exactly as upstream sent it.  Its media type and digest go in S3 object
+	/// metadata, or in a sidecar file on the filesystem.
+	pub async fn write_manifest(&self, object: &str, manifest: Bytes, metadata: &ManifestMetadata) -> Result<(), Error> {
 		match self {
-			Self::S3(r) => r.delete(object).await?,
-			Self::Filesystem(r) => r.delete(object.as_ref()).await?
-		};
+			Self::S3(r) => {
+				crate::chaos::storage_write().await;
+				metrics::written(self.backend(), manifest.len());
+				metrics::timed(self.backend(), "write_manifest", object, r.write_manifest(object, manifest, metadata)).await
+			},
+			Self::Filesystem(_) => {
+				let len = manifest.len().try_into().unwrap_or(i64::MAX);
+				self.write(object, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(manifest))), len).await?;
+				let sidecar = Bytes::from(serde_json::to_vec(metadata).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?);
+				let len = sidecar.len().try_into().unwrap_or(i64::MAX);
+				self.write(&sidecar_path(object), futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(sidecar))), len).await
+			}
+		}
+	}
+
+	/// Reads a manifest's metadata, leaving the manifest itself to be streamed.
+	pub async fn read_manifest(&self, object: &str, invalidation: Duration) -> Result<(ManifestMetadata, ReadStream), Error> {
+		match self {
+			Self::S3(r) => {
+				let (metadata, body) = metrics::timed(self.backend(), "read_manifest", object, r.read_manifest(object, invalidation)).await?;
+				Ok((metadata, body.counted(self.backend())))
+			},
+			Self::Filesystem(_) => {
+				let body = self.read(object, invalidation).await?;
+				let sidecar = self.read(&sidecar_path(object), Duration::MAX).await?.into_inner().try_collect::<BytesMut>().await?;
+				let metadata = serde_json::from_slice(sidecar.as_ref()).map_err(|_| Error::InvalidManifestMetadata)?;
+				Ok((metadata, body))
+			}
+		}
+	}
+
+	/// Like [`Self::read_manifest`], but without reading the manifest itself.
+	pub async fn stat_manifest(&self, object: &str, invalidation: Duration) -> Result<(ManifestMetadata, Stat), Error> {
+		match self {
+			Self::S3(r) => {
+				let (metadata, stat) = metrics::timed(self.backend(), "stat_manifest", object, r.stat_manifest(object)).await?;
+				Ok((metadata, stat.check_age(invalidation)?))
+			},
+			Self::Filesystem(_) => {
+				let stat = self.stat(object, invalidation).await?;
+				let sidecar = self.read(&sidecar_path(object), Duration::MAX).await?.into_inner().try_collect::<BytesMut>().await?;
+				let metadata = serde_json::from_slice(sidecar.as_ref()).map_err(|_| Error::InvalidManifestMetadata)?;
+				Ok((metadata, stat))
+			}
+		}
+	}
+
+	pub async fn delete(&self, object: &str) -> Result<(), Error> {
+		metrics::timed(self.backend(), "delete", object, async {
+			match self {
+				Self::S3(r) => r.delete(object).await?,
+				Self::Filesystem(r) => r.delete(object.into()).await?
+			};
+			Ok::<_, Error>(())
+		})
+		.await
+	}
+
+	/// Deletes a manifest along with its metadata.
+	pub async fn delete_manifest(&self, object: &str) -> Result<(), Error> {
+		self.delete(object).await?;
+		if let Self::Filesystem(_) = self {
+			match self.delete(&sidecar_path(object)).await {
+				Err(e) if e.is_not_found() => (),
+				result => result?
+			};
+		}
 		Ok(())
 	}
 
-	pub async fn delete_old_blobs(&self, older_than: SystemTime) -> Result<usize, Error> {
+	/// Moves an object elsewhere; on S3, by copying it.  It ages from when it was moved.
+	pub async fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+		metrics::timed(self.backend(), "rename", from, async {
+			match self {
+				Self::S3(r) => r.rename(from, to).await?,
+				Self::Filesystem(r) => r.rename(from.into(), to.into()).await?
+			};
+			Ok::<_, Error>(())
+		})
+		.await
+	}
+
+	/// Moves a manifest elsewhere along with its metadata.
+	pub async fn rename_manifest(&self, from: &str, to: &str) -> Result<(), Error> {
+		self.rename(from, to).await?;
+		if let Self::Filesystem(_) = self {
+			match self.rename(&sidecar_path(from), &sidecar_path(to)).await {
+				Err(e) if e.is_not_found() => (),
+				result => result?
+			};
+		}
+		Ok(())
+	}
+
+	/// Moves an object into the trash, from which [`Self::restore`] can put it back until
+	/// [`Self::delete_old_trash`] gets to it.
+	pub async fn trash(&self, object: &str) -> Result<(), Error> {
+		self.rename(object, &trash_path(object)).await
+	}
+
+	pub async fn restore(&self, object: &str) -> Result<(), Error> {
+		self.rename(&trash_path(object), object).await
+	}
+
+	pub async fn trash_manifest(&self, object: &str) -> Result<(), Error> {
+		self.rename_manifest(object, &trash_path(object)).await
+	}
+
+	pub async fn restore_manifest(&self, object: &str) -> Result<(), Error> {
+		self.rename_manifest(&trash_path(object), object).await
+	}
+
+	/// Lists the objects in the trash by where they were before, leaving out metadata sidecars.
+	pub async fn list_trash(&self) -> Result<Vec<String>, Error> {
+		let objects = self.list(TRASH_PREFIX).await?;
+		Ok(objects.into_iter().filter(|o| !is_sidecar(o)).filter_map(|o| o.strip_prefix(TRASH_PREFIX).map(str::to_owned)).collect())
+	}
+
+	/// Permanently deletes whatever was moved into the trash before `older_than`.
+	pub async fn delete_old_trash(&self, older_than: SystemTime, pacer: &mut Pacer) -> Result<usize, Error> {
+		match self {
+			Self::S3(r) => r.delete_old_objects(older_than, TRASH_PREFIX, |_| false, pacer).await,
+			Self::Filesystem(r) => r.delete_old_files(older_than, TRASH_PREFIX.trim_end_matches('/').as_ref(), |_| false, pacer).await
+		}
+	}
+
+	/// Lists every stored manifest, leaving out metadata sidecars.
+	pub async fn list_manifests(&self) -> Result<Vec<String>, Error> {
+		let mut objects = self.list("manifests/").await?;
+		objects.retain(|o| !is_sidecar(o));
+		Ok(objects)
+	}
+
+	/// Lists the names of every object whose name starts with `prefix`.
+	pub async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+		metrics::timed(self.backend(), "list", prefix, async {
+			match self {
+				Self::S3(r) => r.list_keys(prefix).await,
+				Self::Filesystem(r) => r.list_files(prefix.as_ref()).await
+			}
+		})
+		.await
+	}
+
+	/// How many bytes are stored under `prefix`.
+	pub async fn usage(&self, prefix: &str) -> Result<u64, Error> {
+		metrics::timed(self.backend(), "usage", prefix, async {
+			match self {
+				Self::S3(r) => r.total_size(prefix).await,
+				Self::Filesystem(r) => r.total_size(prefix.as_ref()).await
+			}
+		})
+		.await
+	}
+
+	/// Deletes blobs last written before `older_than`, other than those in `keep`.
+	pub async fn delete_old_blobs(&self, older_than: SystemTime, keep: &HashSet<String>, pacer: &mut Pacer) -> Result<usize, Error> {
 		match self {
-			Self::S3(r) => r.delete_old_objects(older_than, "blobs/").await,
-			Self::Filesystem(r) => r.delete_old_files(older_than, "blobs".as_ref()).await
+			Self::S3(r) => r.delete_old_objects(older_than, "blobs/", |o| keep.contains(o), pacer).await,
+			Self::Filesystem(r) => {
+				let count = r.delete_old_files(older_than, "blobs".as_ref(), |o| keep.contains(o), pacer).await?;
+				// What the deleted blobs were hard linked to, if they were the last copies
+				r.delete_unlinked_content(pacer).await?;
+				Ok(count)
+			}
 		}
 	}
 
-	pub async fn delete_old_manifests(&self, ns: &str, older_than: SystemTime) -> Result<usize, Error> {
+	/// Deletes a namespace's manifests last written before `older_than`, other than those in
+	/// `keep` and those cached by digest, which never go stale, along with their metadata.
+	pub async fn delete_old_manifests(&self, ns: &str, older_than: SystemTime, keep: &HashSet<String>, pacer: &mut Pacer) -> Result<usize, Error> {
 		let prefix = format_compact!("manifests/{ns}");
 		let prefix: &str = prefix.as_ref();
 		match self {
-			Self::S3(r) => r.delete_old_objects(older_than, prefix).await,
-			Self::Filesystem(r) => r.delete_old_files(older_than, prefix.as_ref()).await
+			Self::S3(r) => r.delete_old_objects(older_than, prefix, |o| keep.contains(o) || is_digest_addressed(o), pacer).await,
+			Self::Filesystem(r) => {
+				let keep: HashSet<_> = keep.iter().flat_map(|o| [o.clone(), sidecar_path(o)]).collect();
+				r.delete_old_files(older_than, prefix.as_ref(), |o| keep.contains(o) || is_digest_addressed(o), pacer).await
+			}
 		}
 	}
 }
 
+/// Where purged objects are kept until they're restored or age out.
+const TRASH_PREFIX: &str = "trash/";
+
+pub(crate) fn trash_path(object: &str) -> String {
+	format!("{TRASH_PREFIX}{object}")
+}
+
+/// Where a manifest's metadata lives on the filesystem:  next to it, under a name that can't be a
+/// tag or a digest.
+fn sidecar_path(object: &str) -> String {
+	match object.rsplit_once('/') {
+		Some((dir, name)) => format!("{dir}/.{name}.meta"),
+		None => format!(".{object}.meta")
+	}
+}
+
+pub(crate) fn is_sidecar(object: &str) -> bool {
+	object.rsplit('/').next().is_some_and(|name| name.starts_with('.') && name.ends_with(".meta"))
+}
+
+/// Whether `object` is a manifest cached by digest, or the metadata of one.
+fn is_digest_addressed(object: &str) -> bool {
+	object.rsplit('/').next().is_some_and(|name| name.trim_start_matches('.').starts_with("sha256:"))
+}
+
+/// What we need to know to serve a stored manifest without parsing it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ManifestMetadata {
+	pub media_type: String,
+	pub digest: Option<String>,
+	/// How long upstream said the manifest stays fresh for, in seconds from when it was cached, where
+	/// it said and we listened
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub max_age: Option<u64>
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Manifest {
 	pub manifest: Bytes,
 	pub media_type: MediaTypes,
-	pub digest: Option<String>
+	pub digest: Option<String>,
+	#[serde(default)]
+	pub max_age: Option<u64>
 }
 
 impl Manifest {
 	pub fn new(manifest: Bytes, media_type: MediaTypes, digest: Option<String>) -> Self {
-		Self { manifest, media_type, digest }
+		Self { manifest, media_type, digest, max_age: None }
+	}
+
+	/// What's stored alongside the manifest.
+	pub fn metadata(&self) -> ManifestMetadata {
+		ManifestMetadata {
+			media_type: self.content_type().into_owned(),
+			digest: self.digest.clone(),
+			max_age: self.max_age
+		}
+	}
+
+	/// The media type to serve this manifest with.  The manifest's own `mediaType` is preferred,
+	/// so that media types we don't know about - OCI artifact manifests, for one - come back out
+	/// exactly as they went in.
+	pub fn content_type(&self) -> Cow<'_, str> {
+		declared_media_type(self.manifest.as_ref()).unwrap_or_else(|| Cow::Owned(self.media_type.to_string()))
+	}
+}
+
+/// The `mediaType` a manifest gives for itself, if it gives a sensible one.
+pub fn declared_media_type(manifest: &[u8]) -> Option<Cow<'_, str>> {
+	#[derive(Deserialize)]
+	struct MediaType<'a> {
+		#[serde(rename = "mediaType", borrow)]
+		media_type: Option<Cow<'a, str>>
+	}
+	match serde_json::from_slice::<MediaType<'_>>(manifest) {
+		Ok(MediaType { media_type: Some(v) }) if v.contains('/') && v.bytes().all(|b| b.is_ascii_graphic()) => Some(v),
+		_ => None
+	}
+}
+
+impl ManifestMetadata {
+	pub fn new(media_type: String, digest: Option<String>) -> Self {
+		Self { media_type, digest, max_age: None }
+	}
+
+	/// How old the manifest can be and still be served, given how old it could be if upstream
+	/// hadn't said.
+	pub fn max_age(&self, default: Duration) -> Duration {
+		self.max_age.map_or(default, Duration::from_secs)
+	}
+
+	/// Whether this is the media type `manifest` gives for itself.  Manifests that don't say are
+	/// served with whatever upstream said, so anything goes for those.
+	pub fn matches(&self, manifest: &[u8]) -> bool {
+		match declared_media_type(manifest) {
+			Some(v) => v == self.media_type,
+			None => true
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn content_type(manifest: &'static str) -> String {
+		Manifest::new(Bytes::from_static(manifest.as_bytes()), MediaTypes::ApplicationJson, None).content_type().into_owned()
+	}
+
+	#[test]
+	fn artifact_content_types() {
+		// Helm chart, as pushed by `helm push`
+		let helm = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{"mediaType":"application/vnd.cncf.helm.config.v1+json","digest":"sha256:aa","size":1},"layers":[{"mediaType":"application/vnd.cncf.helm.chart.content.v1.tar+gzip","digest":"sha256:bb","size":2}]}"#;
+		assert_eq!(content_type(helm), "application/vnd.oci.image.manifest.v1+json");
+		// WASM module, with an artifactType and the empty config
+		let wasm = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","artifactType":"application/vnd.wasm.config.v0+json","config":{"mediaType":"application/vnd.oci.empty.v1+json","digest":"sha256:aa","size":2},"layers":[{"mediaType":"application/wasm","digest":"sha256:bb","size":2}]}"#;
+		assert_eq!(content_type(wasm), "application/vnd.oci.image.manifest.v1+json");
+		// SBOM pushed by older ORAS as an artifact manifest
+		let sbom = r#"{"mediaType":"application/vnd.oci.artifact.manifest.v1+json","artifactType":"application/spdx+json","blobs":[{"mediaType":"application/spdx+json","digest":"sha256:bb","size":2}],"subject":{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:cc","size":3}}"#;
+		assert_eq!(content_type(sbom), "application/vnd.oci.artifact.manifest.v1+json");
+		// SOCI index, as `soci create` pushes it for lazy pulling
+		let soci = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","artifactType":"application/vnd.amazon.soci.index.v2+json","config":{"mediaType":"application/vnd.amazon.soci.index.v2+json","digest":"sha256:aa","size":2},"layers":[{"mediaType":"application/octet-stream","digest":"sha256:bb","size":2,"annotations":{"com.amazon.soci.image-layer-digest":"sha256:dd"}}],"subject":{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"sha256:cc","size":3}}"#;
+		assert_eq!(content_type(soci), "application/vnd.oci.image.manifest.v1+json");
+		// No mediaType at all, or a nonsensical one
+		assert_eq!(content_type(r#"{"schemaVersion":2}"#), MediaTypes::ApplicationJson.to_string());
+		assert_eq!(content_type(r#"{"mediaType":"bogus\n"}"#), MediaTypes::ApplicationJson.to_string());
+	}
+
+	#[test]
+	fn metadata_matches_manifest() {
+		let index = br#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[]}"#;
+		let metadata = |media_type: &str| ManifestMetadata::new(media_type.to_owned(), None);
+		assert!(metadata("application/vnd.oci.image.index.v1+json").matches(index));
+		assert!(!metadata("application/vnd.docker.distribution.manifest.v2+json").matches(index));
+		assert!(metadata("application/vnd.oci.image.manifest.v1+json").matches(br#"{"schemaVersion":2}"#));
 	}
 }
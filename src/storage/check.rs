@@ -0,0 +1,150 @@
+//! A sweep over storage for entries that are obviously broken, such as those left behind by a crash
+//! or a full disk, so that they can be cleared out up front instead of being discovered one failed
+//! pull at a time.
+
+use core::fmt;
+use core::time::Duration;
+use std::collections::BTreeMap;
+
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use tracing::info;
+use tracing::warn;
+
+use super::Error;
+use super::Repository;
+
+/// What to do about broken entries found at startup.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum CheckAction {
+	/// Don't look for them
+	#[default]
+	Off,
+	/// Only log them
+	Report,
+	/// Delete them
+	Delete,
+	/// Move them under `quarantine/`, for a closer look
+	Quarantine
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Problem {
+	/// Zero bytes long; nothing we store can be empty
+	Empty,
+	/// Doesn't parse as JSON, or its media type and digest are missing
+	InvalidManifest,
+	/// Stored with a media type other than the one it gives for itself
+	MediaTypeMismatch,
+	/// Under a path that can't be a blob's digest
+	MisplacedBlob
+}
+
+impl fmt::Display for Problem {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Empty => "empty",
+			Self::InvalidManifest => "invalid_manifest",
+			Self::MediaTypeMismatch => "media_type_mismatch",
+			Self::MisplacedBlob => "misplaced_blob"
+		})
+	}
+}
+
+const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// Whether a blob's path is one [`crate::api`] could have written it under:  `blobs/`, an optional
+/// credential prefix, then `sha256/`, the first two characters of the hash, and the rest of it.
+fn is_blob_path(object: &str) -> bool {
+	let mut parts = object.rsplit('/');
+	let (Some(rest), Some(prefix), Some(method)) = (parts.next(), parts.next(), parts.next()) else {
+		return false;
+	};
+	let hash = format!("{prefix}{rest}");
+	object.starts_with("blobs/") && method == "sha256" && prefix.len() == 2 && hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+async fn check_manifest(repo: &Repository, object: &str) -> Result<Option<Problem>, Error> {
+	if (repo.stat(object, Duration::MAX).await?.length() == 0) {
+		return Ok(Some(Problem::Empty));
+	}
+	let (metadata, body) = match repo.read_manifest(object, Duration::MAX).await {
+		Ok(v) => v,
+		// The manifest is there, so what's missing is its sidecar
+		Err(e) if e.is_not_found() => return Ok(Some(Problem::InvalidManifest)),
+		Err(Error::InvalidManifestMetadata) => return Ok(Some(Problem::InvalidManifest)),
+		Err(e) => return Err(e)
+	};
+	let body = body.into_inner().try_collect::<BytesMut>().await?;
+	match serde_json::from_slice::<serde::de::IgnoredAny>(body.as_ref()) {
+		Ok(_) if !metadata.matches(body.as_ref()) => Ok(Some(Problem::MediaTypeMismatch)),
+		Ok(_) => Ok(None),
+		Err(_) => Ok(Some(Problem::InvalidManifest))
+	}
+}
+
+async fn check_blob(repo: &Repository, object: &str) -> Result<Option<Problem>, Error> {
+	if (!is_blob_path(object)) {
+		return Ok(Some(Problem::MisplacedBlob));
+	}
+	match repo.stat(object, Duration::MAX).await?.length() {
+		0 => Ok(Some(Problem::Empty)),
+		_ => Ok(None)
+	}
+}
+
+/// Looks over every stored manifest and blob, doing `action` about the broken ones, and returns how
+/// many of each problem there were.  Objects that can't be checked at all are logged and skipped.
+pub async fn run(repo: &Repository, action: CheckAction) -> Result<BTreeMap<Problem, usize>, Error> {
+	let mut found = BTreeMap::new();
+	if (action == CheckAction::Off) {
+		return Ok(found);
+	}
+	let manifests = repo.list_manifests().await?.into_iter().map(|o| (o, true));
+	let blobs = repo.list("blobs/").await?.into_iter().map(|o| (o, false));
+	for (object, is_manifest) in manifests.chain(blobs) {
+		let result = match is_manifest {
+			true => check_manifest(repo, &object).await,
+			false => check_blob(repo, &object).await
+		};
+		let problem = match result {
+			Ok(Some(v)) => v,
+			Ok(None) => continue,
+			Err(e) if e.is_not_found() => continue,
+			Err(error) => {
+				warn!(object, %error, "Failed to check stored object");
+				continue;
+			}
+		};
+		*found.entry(problem).or_default() += 1;
+		let result = match (action, is_manifest) {
+			(CheckAction::Off | CheckAction::Report, _) => Ok(()),
+			(CheckAction::Delete, true) => repo.delete_manifest(&object).await,
+			(CheckAction::Delete, false) => repo.delete(&object).await,
+			(CheckAction::Quarantine, true) => repo.rename_manifest(&object, &format!("{QUARANTINE_PREFIX}{object}")).await,
+			(CheckAction::Quarantine, false) => repo.rename(&object, &format!("{QUARANTINE_PREFIX}{object}")).await
+		};
+		match result {
+			Ok(()) => warn!(object, %problem, ?action, "Found broken object in storage"),
+			Err(error) => warn!(object, %problem, %error, "Failed to clear out broken object in storage")
+		};
+	}
+	info!(?found, ?action, "Storage check finished");
+	Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn blob_paths() {
+		assert!(is_blob_path("blobs/sha256/22/6cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883"));
+		assert!(is_blob_path("blobs/_private/3f2a/sha256/22/6cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883"));
+		assert!(!is_blob_path("blobs/sha256/22/6cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb88"));
+		assert!(!is_blob_path("blobs/sha256/226/cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883"));
+		assert!(!is_blob_path("blobs/sha512/22/6cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883"));
+		assert!(!is_blob_path("blobs/sha256/22/6CBAFC637CD58CF008BF87EC9D1548AD1B672EF4279433495BDFF100CDB883"));
+		assert!(!is_blob_path("blobs/_/_/whatever"));
+	}
+}
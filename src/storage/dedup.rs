@@ -0,0 +1,214 @@
+//! Dedup reports:  how much storage content addressing saves.  Every repository that references a
+//! blob would need its own copy of it if blobs were stored by repository; here, each is stored once
+//! for everyone sharing the cache, and once more for each tenant or set of pass-through credentials
+//! that's pulled it.  The report adds up what's referenced against what's stored, and on filesystem
+//! storage, what hard links between those copies save on top.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+
+use bytes::BytesMut;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+
+use super::Repository;
+
+/// How many of the most shared blobs the report lists
+const TOP: usize = 10;
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+	digest: String,
+	#[serde(default)]
+	size: u64
+}
+
+#[derive(Debug, Deserialize)]
+struct Parsed {
+	#[serde(default)]
+	config: Option<Descriptor>,
+	#[serde(default)]
+	layers: Vec<Descriptor>
+}
+
+/// Where a manifest is cached, as far as sharing its blobs goes.
+#[derive(Debug, Eq, PartialEq)]
+struct Location<'a> {
+	namespace: &'a str,
+	repository: &'a str,
+	/// `_tenant/<name>` or `_private/<credentials>`; `None` for the shared cache
+	partition: Option<&'a str>
+}
+
+/// Where a manifest at `object` is cached:  `manifests/<namespace>/[<partition>/]<repository>/<reference>`.
+fn location(object: &str) -> Option<Location<'_>> {
+	let path = object.strip_prefix("manifests/")?;
+	let (namespace, rest) = path.split_once('/')?;
+	let (dir, _reference) = rest.rsplit_once('/')?;
+	let partition_len = match dir.starts_with('_') {
+		true => dir.match_indices('/').nth(1).map(|(i, _)| i)?,
+		false => 0
+	};
+	let (partition, repository) = match partition_len {
+		0 => (None, dir),
+		n => (Some(&dir[..n]), &dir[n + 1..])
+	};
+	Some(Location { namespace, repository, partition })
+}
+
+/// Where a blob is stored for a partition, as the API stores it.
+fn blob_path(digest: &str, partition: Option<&str>) -> String {
+	let (method, hash) = digest.split_once(':').unwrap_or(("_", digest));
+	let (hash_prefix, rest_of_hash) = (hash.get(..2).unwrap_or("_"), hash.get(2..).unwrap_or(hash));
+	match partition {
+		Some(partition) => format!("blobs/{partition}/{method}/{hash_prefix}/{rest_of_hash}"),
+		None => format!("blobs/{method}/{hash_prefix}/{rest_of_hash}")
+	}
+}
+
+/// Where a blob is referenced from.
+#[derive(Debug, Default)]
+struct Usage {
+	size: u64,
+	namespaces: BTreeSet<String>,
+	repositories: BTreeSet<(String, String)>,
+	/// The namespaces it's referenced from in each partition
+	partitions: BTreeMap<Option<String>, BTreeSet<String>>
+}
+
+impl Usage {
+	/// The namespaces referencing each copy of the blob that's stored
+	fn copies<'a>(&'a self, digest: &'a str, stored: &'a HashSet<String>) -> impl Iterator<Item = &'a BTreeSet<String>> + 'a {
+		self.partitions.iter().filter(move |(p, _)| stored.contains(&blob_path(digest, p.as_deref()))).map(|(_, namespaces)| namespaces)
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct SharedBlob {
+	digest: String,
+	size: u64,
+	repositories: usize,
+	namespaces: usize
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+	/// Distinct blobs cached manifests reference that are stored; those that haven't been pulled
+	/// don't count
+	blobs: usize,
+	/// What storing each repository's blobs separately would take
+	referenced_bytes: u64,
+	/// What the blobs take, stored once per partition they're cached in
+	stored_bytes: u64,
+	saved_bytes: u64,
+	/// How much of what's saved is blobs shared between namespaces, i.e. different upstreams
+	saved_across_namespaces: u64,
+	/// Saved on top of that by hard linking copies in different partitions; filesystem storage only
+	#[serde(skip_serializing_if = "Option::is_none")]
+	hard_linked_bytes: Option<u64>,
+	top: Vec<SharedBlob>
+}
+
+impl Report {
+	/// Adds up a report from the blobs each cached manifest references, and which of those are
+	/// stored.
+	fn new(usage: &BTreeMap<String, Usage>, stored: &HashSet<String>) -> Self {
+		let usage = usage.iter().filter(|(digest, blob)| blob.copies(digest, stored).next().is_some()).collect::<Vec<_>>();
+		let mut report = Self { blobs: usage.len(), ..Self::default() };
+		for (digest, blob) in &usage {
+			report.referenced_bytes += blob.size * blob.repositories.len() as u64;
+			for namespaces in blob.copies(digest, stored) {
+				report.stored_bytes += blob.size;
+				// The one copy in a partition serves every namespace there
+				report.saved_across_namespaces += blob.size * (namespaces.len() as u64 - 1);
+			}
+		}
+		report.saved_bytes = report.referenced_bytes.saturating_sub(report.stored_bytes);
+		let mut shared = usage.into_iter().filter(|(_, b)| b.repositories.len() > 1).collect::<Vec<_>>();
+		shared.sort_by_key(|(_, b)| core::cmp::Reverse(b.size * (b.repositories.len() as u64 - 1)));
+		report.top = shared
+			.into_iter()
+			.take(TOP)
+			.map(|(digest, b)| SharedBlob { digest: digest.clone(), size: b.size, repositories: b.repositories.len(), namespaces: b.namespaces.len() })
+			.collect();
+		report
+	}
+}
+
+/// Reads every cached manifest for the blobs it references, and reports what sharing them saves.
+pub async fn report(repo: &Repository) -> Result<Report, super::Error> {
+	let stored = repo.list("blobs/").await?.into_iter().collect::<HashSet<_>>();
+	let mut usage = BTreeMap::<String, Usage>::new();
+	for object in repo.list_manifests().await? {
+		let Some(location) = location(&object) else {
+			continue;
+		};
+		let body = match repo.read_manifest(&object, core::time::Duration::MAX).await {
+			Ok((_, stream)) => stream.into_inner().try_collect::<BytesMut>().await?,
+			// Cleaned up since it was listed
+			Err(e) if e.is_not_found() => continue,
+			Err(e) => return Err(e)
+		};
+		// Indexes and lists reference manifests, which aren't shared by digest
+		let Ok(parsed) = serde_json::from_slice::<Parsed>(&body) else {
+			warn!(object, "Skipping manifest that couldn't be parsed");
+			continue;
+		};
+		for descriptor in parsed.config.into_iter().chain(parsed.layers) {
+			let blob = usage.entry(descriptor.digest).or_default();
+			blob.size = blob.size.max(descriptor.size);
+			blob.namespaces.insert(location.namespace.to_owned());
+			blob.repositories.insert((location.namespace.to_owned(), location.repository.to_owned()));
+			blob.partitions.entry(location.partition.map(str::to_owned)).or_default().insert(location.namespace.to_owned());
+		}
+	}
+	let mut report = Report::new(&usage, &stored);
+	if let Repository::Filesystem(r) = repo {
+		report.hard_linked_bytes = Some(r.hard_linked_bytes().await?);
+	}
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn locations() {
+		assert_eq!(location("manifests/docker.io/library/alpine/latest"), Some(Location { namespace: "docker.io", repository: "library/alpine", partition: None }));
+		assert_eq!(location("manifests/docker.io/_tenant/team-a/library/alpine/latest"), Some(Location { namespace: "docker.io", repository: "library/alpine", partition: Some("_tenant/team-a") }));
+		assert_eq!(location("manifests/ghcr.io/_private/abcd/org/app/sha256:1234"), Some(Location { namespace: "ghcr.io", repository: "org/app", partition: Some("_private/abcd") }));
+		assert_eq!(location("manifests/docker.io"), None);
+		assert_eq!(blob_path("sha256:abcdef", Some("_tenant/team-a")), "blobs/_tenant/team-a/sha256/ab/cdef");
+	}
+
+	#[test]
+	fn savings() {
+		let mut usage = BTreeMap::new();
+		let blob = |size: u64, repositories: &[(&str, &str, Option<&str>)]| Usage {
+			size,
+			namespaces: repositories.iter().map(|r| r.0.to_owned()).collect(),
+			repositories: repositories.iter().map(|r| (r.0.to_owned(), r.1.to_owned())).collect(),
+			partitions: repositories.iter().fold(BTreeMap::new(), |mut partitions, r| {
+				partitions.entry(r.2.map(str::to_owned)).or_insert_with(BTreeSet::new).insert(r.0.to_owned());
+				partitions
+			})
+		};
+		usage.insert("sha256:aa11".to_owned(), blob(100, &[("docker.io", "library/app", None), ("docker.io", "library/other", None), ("ghcr.io", "org/app", None), ("ghcr.io", "org/app", Some("_tenant/a"))]));
+		usage.insert("sha256:bb22".to_owned(), blob(10, &[("docker.io", "library/app", None)]));
+		// Referenced, but not pulled
+		usage.insert("sha256:cc33".to_owned(), blob(1000, &[("docker.io", "library/app", None)]));
+		let stored = ["blobs/sha256/aa/11", "blobs/_tenant/a/sha256/aa/11", "blobs/sha256/bb/22"].into_iter().map(str::to_owned).collect();
+		let report = Report::new(&usage, &stored);
+		assert_eq!(report.blobs, 2);
+		assert_eq!(report.referenced_bytes, 310);
+		assert_eq!(report.stored_bytes, 210);
+		assert_eq!(report.saved_bytes, 100);
+		assert_eq!(report.saved_across_namespaces, 100);
+		assert_eq!(report.top.len(), 1);
+		assert_eq!(report.top[0].repositories, 3);
+	}
+}
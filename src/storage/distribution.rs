@@ -0,0 +1,255 @@
+//! Imports from docker/distribution storage:  a bucket or directory that a distribution registry,
+//! such as one running as a pull-through cache, laid out under `docker/registry/v2/`, converted to
+//! this layout so that moving off it doesn't mean pulling everything it cached from upstream again.
+//! Distribution stores manifests as blobs, linked into each repository by digest and by tag; every
+//! linked manifest is imported under its repository in one namespace, and every blob where a pull
+//! would have stored it.  Importing again skips what's already been imported, except for tags,
+//! which may have moved.
+
+use core::time::Duration;
+use std::path::Path;
+
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use tracing::error;
+use tracing::info;
+
+use super::declared_media_type;
+use super::layout;
+use super::replica::ReplicaStorage;
+use super::ManifestMetadata;
+use super::Repository;
+use crate::command::ImportDistributionConfig;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("{0}")]
+	Storage(#[from] super::Error),
+	#[error("Failed to read source config file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Invalid source config file: {0}")]
+	Yaml(#[from] serde_yaml::Error),
+	#[error("Invalid link {0}; expected a digest")]
+	InvalidLink(String),
+	#[error("{0} objects couldn't be imported; importing again will try them again")]
+	Incomplete(usize)
+}
+
+/// Reads where to import from:  the same settings as a replica's, from `--source-config-file`.
+pub async fn load_source(path: &Path) -> Result<Repository, Error> {
+	let storage: ReplicaStorage = serde_yaml::from_slice(&tokio::fs::read(path).await?)?;
+	Ok(storage.repository())
+}
+
+/// What a link under `repositories/` points a repository at.
+#[derive(Debug, Eq, PartialEq)]
+enum Link<'a> {
+	/// `<repository>/_manifests/revisions/<method>/<hash>/link`
+	Revision { repository: &'a str },
+	/// `<repository>/_manifests/tags/<tag>/current/link`; a tag's history, under `index/`, isn't
+	/// imported
+	Tag { repository: &'a str, tag: &'a str }
+}
+
+impl Link<'_> {
+	fn repository(&self) -> &str {
+		match self {
+			Self::Revision { repository } | Self::Tag { repository, .. } => repository
+		}
+	}
+}
+
+/// What the object at `path`, relative to `repositories/`, links to, if it's a manifest link.
+fn link(path: &str) -> Option<Link<'_>> {
+	let (repository, rest) = path.split_once("/_manifests/")?;
+	match rest.strip_suffix("/link")?.split('/').collect::<Vec<_>>().as_slice() {
+		["revisions", _, _] => Some(Link::Revision { repository }),
+		["tags", tag, "current"] => Some(Link::Tag { repository, tag }),
+		_ => None
+	}
+}
+
+/// Where distribution keeps a blob, relative to its root.
+fn source_blob_path(method: &str, hash: &str) -> String {
+	format!("blobs/{method}/{}/{hash}/data", hash.get(..2).unwrap_or_default())
+}
+
+/// Where a blob at `path`, relative to distribution's `blobs/`, is stored here.
+fn blob_path(path: &str) -> Option<String> {
+	let mut parts = path.strip_suffix("/data")?.rsplit('/');
+	let (hash, _, method) = (parts.next()?, parts.next()?, parts.next()?);
+	match parts.next() {
+		None if hash.len() > 2 => Some(format!("blobs/{method}/{}/{}", &hash[..2], &hash[2..])),
+		_ => None
+	}
+}
+
+/// The media type to serve an imported manifest with.  Distribution keeps the one it was pushed
+/// with in the manifest itself, except for the oldest manifests, which don't say.
+fn media_type(manifest: &[u8]) -> String {
+	#[derive(Deserialize)]
+	struct Version {
+		#[serde(rename = "schemaVersion")]
+		schema_version: Option<u32>
+	}
+	if let Some(media_type) = declared_media_type(manifest) {
+		return media_type.into_owned();
+	}
+	match serde_json::from_slice::<Version>(manifest).ok().and_then(|v| v.schema_version) {
+		Some(1) => "application/vnd.docker.distribution.manifest.v1+prettyjws".into(),
+		_ => "application/vnd.oci.image.manifest.v1+json".into()
+	}
+}
+
+async fn read_all(repo: &Repository, object: &str) -> Result<Bytes, super::Error> {
+	Ok(repo.read(object, Duration::MAX).await?.into_inner().try_collect::<BytesMut>().await?.freeze())
+}
+
+/// Copies a blob, unless it's been imported already; returns whether it was copied.
+async fn import_blob(source: &Repository, dest: &Repository, from: &str, to: &str) -> Result<bool, super::Error> {
+	if (dest.stat(to, Duration::MAX).await.is_ok()) {
+		return Ok(false);
+	}
+	let stream = source.read(from, Duration::MAX).await?;
+	let len = stream.length().try_into().unwrap_or(i64::MAX);
+	dest.write(to, stream.into_inner(), len).await?;
+	Ok(true)
+}
+
+/// Imports the manifest a link points at, under its repository in `namespace`; returns whether it
+/// was written.
+async fn import_manifest(source: &Repository, dest: &Repository, root: &str, namespace: &str, path: &str, link: Link<'_>) -> Result<bool, Error> {
+	let digest = String::from_utf8_lossy(&read_all(source, path).await?).trim().to_owned();
+	let Some((method, hash)) = digest.split_once(':').filter(|(_, hash)| hash.len() > 2) else {
+		return Err(Error::InvalidLink(path.to_owned()));
+	};
+	let reference = match &link {
+		Link::Revision { .. } => digest.as_str(),
+		Link::Tag { tag, .. } => *tag
+	};
+	let object = format!("manifests/{namespace}/{}/{reference}", link.repository());
+	// What a digest names can't change
+	if (matches!(link, Link::Revision { .. }) && dest.stat_manifest(&object, Duration::MAX).await.is_ok()) {
+		return Ok(false);
+	}
+	let manifest = read_all(source, &format!("{root}{}", source_blob_path(method, hash))).await?;
+	let metadata = ManifestMetadata::new(media_type(&manifest), Some(digest.clone()));
+	dest.write_manifest(&object, manifest, &metadata).await?;
+	Ok(true)
+}
+
+/// Imports every blob and linked manifest in distribution storage into `dest`.
+pub async fn run(source: &Repository, dest: &Repository, config: &ImportDistributionConfig) -> Result<(), Error> {
+	// Marks empty storage as current, and refuses to add to storage that needs migrating first
+	layout::check(dest).await?;
+	let root = match config.root.trim_matches('/') {
+		"" => String::new(),
+		root => format!("{root}/")
+	};
+	let concurrency = config.concurrency.max(1);
+	let mut failed = 0;
+
+	let blobs = format!("{root}blobs/");
+	let objects = source.list(&blobs).await?;
+	let copies = objects.iter().filter_map(|from| Some((from.as_str(), blob_path(from.strip_prefix(&blobs)?)?))).collect::<Vec<_>>();
+	info!(blobs = copies.len(), "Importing blobs");
+	let results = futures::stream::iter(copies)
+		.map(|(from, to)| async move { (from, import_blob(source, dest, from, &to).await) })
+		.buffer_unordered(concurrency)
+		.collect::<Vec<_>>()
+		.await;
+	let mut imported_blobs = 0;
+	for (object, result) in results {
+		match result {
+			Ok(copied) => imported_blobs += usize::from(copied),
+			Err(error) => {
+				error!(object, %error, "Failed to import blob");
+				failed += 1;
+			}
+		};
+	}
+
+	let repositories = format!("{root}repositories/");
+	let objects = source.list(&repositories).await?;
+	let links = objects.iter().filter_map(|path| Some((path.as_str(), link(path.strip_prefix(&repositories)?)?))).collect::<Vec<_>>();
+	info!(links = links.len(), namespace = config.namespace.as_str(), "Importing manifests");
+	let results = futures::stream::iter(links)
+		.map(|(path, link)| {
+			let root = root.as_str();
+			async move { (path, import_manifest(source, dest, root, &config.namespace, path, link).await) }
+		})
+		.buffer_unordered(concurrency)
+		.collect::<Vec<_>>()
+		.await;
+	let mut imported_manifests = 0;
+	for (link, result) in results {
+		match result {
+			Ok(written) => imported_manifests += usize::from(written),
+			Err(error) => {
+				error!(link, %error, "Failed to import manifest");
+				failed += 1;
+			}
+		};
+	}
+
+	info!(blobs = imported_blobs, manifests = imported_manifests, failed, "Import finished");
+	match failed {
+		0 => Ok(()),
+		n => Err(Error::Incomplete(n))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use camino::Utf8PathBuf;
+
+	use super::*;
+	use crate::storage::filesystem;
+
+	#[test]
+	fn paths() {
+		assert_eq!(link("library/alpine/_manifests/tags/3.19/current/link"), Some(Link::Tag { repository: "library/alpine", tag: "3.19" }));
+		assert_eq!(link("org/team/app/_manifests/revisions/sha256/abcd/link"), Some(Link::Revision { repository: "org/team/app" }));
+		assert_eq!(link("library/alpine/_manifests/tags/3.19/index/sha256/abcd/link"), None);
+		assert_eq!(link("library/alpine/_layers/sha256/abcd/link"), None);
+		assert_eq!(blob_path("sha256/ab/abcdef/data").as_deref(), Some("blobs/sha256/ab/cdef"));
+		assert_eq!(blob_path("sha256/ab/abcdef/startedat"), None);
+		assert_eq!(source_blob_path("sha256", "abcdef"), "blobs/sha256/ab/abcdef/data");
+		assert_eq!(media_type(br#"{"schemaVersion":1,"name":"library/alpine"}"#), "application/vnd.docker.distribution.manifest.v1+prettyjws");
+	}
+
+	#[actix_web::test]
+	async fn import() {
+		let dir = Utf8PathBuf::try_from(std::env::temp_dir().join(format!("oci-registry-distribution-test-{}", std::process::id()))).unwrap();
+		let source = Repository::Filesystem(filesystem::Repository::new(dir.join("source")));
+		let dest = Repository::Filesystem(filesystem::Repository::new(dir.join("dest")));
+		let write = |object: String, body: &'static str| {
+			let source = source.clone();
+			async move { source.write(&object, futures::stream::iter([Result::<_, std::io::Error>::Ok(Bytes::from_static(body.as_bytes()))]), body.len() as i64).await.unwrap() }
+		};
+		let manifest = r#"{"schemaVersion":2,"mediaType":"application/vnd.docker.distribution.manifest.v2+json","config":{"digest":"sha256:bbbb","size":2},"layers":[]}"#;
+		let root = "docker/registry/v2";
+		write(format!("{root}/blobs/sha256/aa/aaaa/data"), manifest).await;
+		write(format!("{root}/blobs/sha256/bb/bbbb/data"), "{}").await;
+		write(format!("{root}/repositories/library/app/_manifests/revisions/sha256/aaaa/link"), "sha256:aaaa").await;
+		write(format!("{root}/repositories/library/app/_manifests/tags/1.0/current/link"), "sha256:aaaa\n").await;
+		write(format!("{root}/repositories/library/app/_layers/sha256/bbbb/link"), "sha256:bbbb").await;
+		let config = ImportDistributionConfig { source_config_file: dir.join("source.yaml").into_std_path_buf(), namespace: "docker.io".into(), root: root.into(), concurrency: 2 };
+
+		run(&source, &dest, &config).await.unwrap();
+		assert_eq!(read_all(&dest, "blobs/sha256/bb/bb").await.unwrap(), Bytes::from_static(b"{}"));
+		for reference in ["1.0", "sha256:aaaa"] {
+			let (metadata, stream) = dest.read_manifest(&format!("manifests/docker.io/library/app/{reference}"), Duration::MAX).await.unwrap();
+			assert_eq!(metadata.media_type, "application/vnd.docker.distribution.manifest.v2+json");
+			assert_eq!(metadata.digest.as_deref(), Some("sha256:aaaa"));
+			assert_eq!(stream.into_inner().try_collect::<BytesMut>().await.unwrap(), manifest.as_bytes());
+		}
+		assert_eq!(layout::read_version(&dest).await.unwrap(), Some(layout::CURRENT_VERSION));
+		// Again, with nothing new to copy
+		run(&source, &dest, &config).await.unwrap();
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
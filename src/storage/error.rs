@@ -1,7 +1,10 @@
+use actix_web::http::StatusCode;
 use arcerror::ArcError;
+use rusoto_core::request::BufferedHttpResponse;
 use rusoto_core::RusotoError;
 
 use crate::api::stream::DigestMismatchError;
+use crate::api::stream::LengthMismatchError;
 
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum Error {
@@ -11,18 +14,51 @@ pub enum Error {
 	RusotoList(ArcError<RusotoError<rusoto_s3::ListObjectsV2Error>>),
 	#[error("Failed to get object from S3: {0:?}")]
 	RusotoGet(ArcError<RusotoError<rusoto_s3::GetObjectError>>),
+	#[error("Failed to get object metadata from S3: {0:?}")]
+	RusotoHead(ArcError<RusotoError<rusoto_s3::HeadObjectError>>),
 	#[error("Failed to put object into S3: {0:?}")]
 	RusotoPut(ArcError<RusotoError<rusoto_s3::PutObjectError>>),
+	#[error("Failed to copy object in S3: {0:?}")]
+	RusotoCopy(ArcError<RusotoError<rusoto_s3::CopyObjectError>>),
 	#[error("Failed to delete object from S3: {0:?}")]
 	RusotoDelete(ArcError<RusotoError<rusoto_s3::DeleteObjectError>>),
 	#[error("Failed to parse datetime: {0}")]
 	ParseTime(#[from] time::error::Parse),
 	#[error("Object too old: {0}")]
 	ObjectTooOld(humantime::Duration),
+	#[error("Request deadline exceeded")]
+	DeadlineExceeded,
+	#[error("Client disconnected before the blob was fully sent")]
+	ClientAborted,
 	#[error("Error reading from upstream: {0}")]
 	Upstream(ArcError<dkregistry::errors::Error>),
 	#[error("{0}")]
-	DataCorrupt(#[from] DigestMismatchError)
+	DataCorrupt(#[from] DigestMismatchError),
+	#[error("{0}")]
+	LengthMismatch(#[from] LengthMismatchError),
+	#[error("Invalid storage layout version marker")]
+	InvalidLayoutVersion,
+	#[error("Stored manifest has missing or invalid metadata")]
+	InvalidManifestMetadata,
+	#[error("Storage layout version {found} is older than the current version {expected}; run the `migrate` subcommand")]
+	LayoutOutdated { found: u32, expected: u32 },
+	#[error("Storage layout version {found} is newer than the current version {expected}; refusing to touch it")]
+	LayoutTooNew { found: u32, expected: u32 }
+}
+
+impl Error {
+	pub fn is_not_found(&self) -> bool {
+		match self {
+			Self::Io(e) => e.kind() == std::io::ErrorKind::NotFound,
+			Self::RusotoGet(e) => matches!(e.as_ref(), &RusotoError::Service(rusoto_s3::GetObjectError::NoSuchKey(_))),
+			// HEAD responses have no body to say NoSuchKey in, so a missing key usually just comes back as a 404
+			Self::RusotoHead(e) => matches!(e.as_ref(), &RusotoError::Service(rusoto_s3::HeadObjectError::NoSuchKey(_)) | &RusotoError::Unknown(BufferedHttpResponse { status: StatusCode::NOT_FOUND, .. })),
+			// A missing source isn't one of the errors rusoto knows CopyObject can return
+			Self::RusotoCopy(e) => matches!(e.as_ref(), &RusotoError::Unknown(BufferedHttpResponse { status: StatusCode::NOT_FOUND, .. })),
+			Self::RusotoDelete(e) => matches!(e.as_ref(), &RusotoError::Unknown(BufferedHttpResponse { status: StatusCode::NOT_FOUND, .. })),
+			_ => false
+		}
+	}
 }
 
 impl From<std::io::Error> for Error {
@@ -46,6 +82,13 @@ impl From<RusotoError<rusoto_s3::GetObjectError>> for Error {
 	}
 }
 
+impl From<RusotoError<rusoto_s3::HeadObjectError>> for Error {
+	#[inline]
+	fn from(inner: RusotoError<rusoto_s3::HeadObjectError>) -> Self {
+		Self::RusotoHead(ArcError::from(inner))
+	}
+}
+
 impl From<RusotoError<rusoto_s3::PutObjectError>> for Error {
 	#[inline]
 	fn from(inner: RusotoError<rusoto_s3::PutObjectError>) -> Self {
@@ -53,6 +96,13 @@ impl From<RusotoError<rusoto_s3::PutObjectError>> for Error {
 	}
 }
 
+impl From<RusotoError<rusoto_s3::CopyObjectError>> for Error {
+	#[inline]
+	fn from(inner: RusotoError<rusoto_s3::CopyObjectError>) -> Self {
+		Self::RusotoCopy(ArcError::from(inner))
+	}
+}
+
 impl From<RusotoError<rusoto_s3::DeleteObjectError>> for Error {
 	#[inline]
 	fn from(inner: RusotoError<rusoto_s3::DeleteObjectError>) -> Self {
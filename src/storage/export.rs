@@ -0,0 +1,206 @@
+//! Air-gap exports:  what's cached, copied into a directory laid out as filesystem storage, which can
+//! be carried across the gap and served from as it is, or copied into other storage.  Given the
+//! state file an earlier export wrote, only objects added or changed since then are copied, along
+//! with a list of those that are gone, so that a refresh doesn't mean shipping the whole cache.
+
+use core::time::Duration;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use futures::stream::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::error;
+use tracing::info;
+
+use super::filesystem;
+use super::layout;
+use super::replica;
+use super::replica::Kind;
+use super::Repository;
+use crate::command::ExportConfig;
+
+/// What gets exported:  everything needed to serve what's cached, but not the trash
+const PREFIXES: &[(&str, Kind)] = &[("blobs/", Kind::Blob), ("manifests/", Kind::Manifest), ("referrers/", Kind::Blob), ("labels/", Kind::Blob), ("sboms/", Kind::Blob), ("history/", Kind::Blob), ("provenance/", Kind::Blob), ("timestamps/", Kind::Blob), ("foreign/", Kind::Blob)];
+
+/// Written to the top of the bundle, for whoever imports it.
+const BUNDLE_INDEX: &str = "export.json";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("{0}")]
+	Storage(#[from] super::Error),
+	#[error("Failed to read or write {0}: {1}")]
+	Io(String, std::io::Error),
+	#[error("Invalid export state file {0}: {1}")]
+	State(String, serde_json::Error),
+	#[error("{0} objects couldn't be exported; they'll be tried again by the next export")]
+	Incomplete(usize)
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ObjectState {
+	size: u64,
+	/// Seconds since the Unix epoch
+	modified: u64
+}
+
+/// Every object an export (and the ones before it) has shipped.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ExportState {
+	/// When the export that wrote this started, in seconds since the Unix epoch
+	exported_at: u64,
+	objects: BTreeMap<String, ObjectState>
+}
+
+#[derive(Debug, Serialize)]
+struct BundleIndex<'a> {
+	/// When the export this bundle is a delta from started; `None` for a full export
+	base: Option<u64>,
+	exported_at: u64,
+	added: &'a [&'a str],
+	/// Objects shipped by earlier exports that are no longer cached
+	removed: &'a [&'a str]
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// The objects in `current` that are new or changed since `previous`, and those in `previous` that
+/// are gone.  Manifests under a tag change in place, so a new size or modification time counts.
+fn diff<'a>(current: &'a BTreeMap<String, (Kind, ObjectState)>, previous: &'a BTreeMap<String, ObjectState>) -> (Vec<&'a str>, Vec<&'a str>) {
+	let added = current.iter().filter(|(object, (_, state))| previous.get(*object) != Some(state)).map(|(object, _)| object.as_str()).collect();
+	let removed = previous.keys().filter(|object| !current.contains_key(*object)).map(String::as_str).collect();
+	(added, removed)
+}
+
+async fn read_state(path: &Path) -> Result<ExportState, Error> {
+	let body = tokio::fs::read(path).await.map_err(|e| Error::Io(path.display().to_string(), e))?;
+	serde_json::from_slice(&body).map_err(|e| Error::State(path.display().to_string(), e))
+}
+
+async fn write_json(path: &Path, value: &impl Serialize) -> Result<(), Error> {
+	// Only fails for values that can't be JSON, which these always can
+	let body = serde_json::to_vec_pretty(value).unwrap_or_default();
+	tokio::fs::write(path, body).await.map_err(|e| Error::Io(path.display().to_string(), e))
+}
+
+/// Lists everything there is to export, with its size and modification time.
+async fn list(repo: &Repository) -> Result<BTreeMap<String, (Kind, ObjectState)>, super::Error> {
+	let mut objects = BTreeMap::new();
+	for (prefix, kind) in PREFIXES {
+		let names = match kind {
+			Kind::Manifest => repo.list_manifests().await?,
+			Kind::Blob => repo.list(prefix).await?
+		};
+		let stats = repo.stat_all(&names.iter().map(String::as_str).collect::<Vec<_>>(), Duration::MAX).await;
+		for (name, stat) in names.into_iter().zip(stats) {
+			match stat {
+				Ok(stat) => {
+					objects.insert(name, (*kind, ObjectState { size: stat.length(), modified: unix_secs(stat.modified()) }));
+				},
+				// Deleted since it was listed
+				Err(e) if e.is_not_found() => (),
+				Err(e) => return Err(e)
+			};
+		}
+	}
+	Ok(objects)
+}
+
+/// Exports what's been cached (or, with a previous state file, what's been cached since) to the
+/// output directory, and writes the new state file.
+pub async fn run(repo: &Repository, config: &ExportConfig) -> Result<(), Error> {
+	let started = unix_secs(SystemTime::now());
+	let previous = match config.previous_state.as_deref() {
+		Some(path) => Some(read_state(path).await?),
+		None => None
+	};
+	let current = list(repo).await?;
+	let empty = BTreeMap::new();
+	let (added, removed) = diff(&current, previous.as_ref().map_or(&empty, |p| &p.objects));
+	info!(added = added.len(), removed = removed.len(), incremental = previous.is_some(), "Exporting");
+
+	let bundle = Repository::Filesystem(filesystem::Repository::new(config.output_dir.clone()));
+	let results = futures::stream::iter(added.iter().copied())
+		.map(|object| {
+			let bundle = &bundle;
+			let kind = current[object].0;
+			async move { (object, replica::copy(repo, bundle, object, kind).await) }
+		})
+		.buffer_unordered(config.concurrency.max(1))
+		.collect::<Vec<_>>()
+		.await;
+	let mut failed = Vec::new();
+	for (object, result) in results {
+		match result {
+			Ok(()) => (),
+			// Deleted since it was listed; the next export will list it as removed
+			Err(e) if e.is_not_found() => failed.push(object),
+			Err(error) => {
+				error!(object, %error, "Failed to export object");
+				failed.push(object);
+			}
+		};
+	}
+	layout::write_version(&bundle, layout::CURRENT_VERSION).await?;
+
+	let exported = added.iter().copied().filter(|o| !failed.contains(o)).collect::<Vec<_>>();
+	let index = BundleIndex { base: previous.as_ref().map(|p| p.exported_at), exported_at: started, added: &exported, removed: &removed };
+	write_json(&config.output_dir.join(BUNDLE_INDEX).into_std_path_buf(), &index).await?;
+	// What failed is left out, or left as it was, so that the next export tries it again
+	let mut objects = previous.map(|p| p.objects).unwrap_or_default();
+	objects.retain(|object, _| current.contains_key(object));
+	for object in &exported {
+		objects.insert((*object).to_owned(), current[*object].1);
+	}
+	write_json(&config.state_file, &ExportState { exported_at: started, objects }).await?;
+	info!(exported = exported.len(), removed = removed.len(), failed = failed.len(), output_dir = config.output_dir.as_str(), "Export finished");
+	match failed.len() {
+		0 => Ok(()),
+		n => Err(Error::Incomplete(n))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use camino::Utf8PathBuf;
+
+	use super::*;
+
+	#[test]
+	fn changes() {
+		let state = |size, modified| ObjectState { size, modified };
+		let current = [("blobs/sha256/aa/a", (Kind::Blob, state(1, 10))), ("manifests/docker.io/library/alpine/latest", (Kind::Manifest, state(2, 20))), ("blobs/sha256/bb/b", (Kind::Blob, state(3, 30)))].into_iter().map(|(o, s)| (o.to_owned(), s)).collect();
+		let previous = [("blobs/sha256/aa/a", state(1, 10)), ("manifests/docker.io/library/alpine/latest", state(2, 15)), ("blobs/sha256/cc/c", state(4, 5))].into_iter().map(|(o, s)| (o.to_owned(), s)).collect();
+		let (added, removed) = diff(&current, &previous);
+		assert_eq!(added, ["blobs/sha256/bb/b", "manifests/docker.io/library/alpine/latest"]);
+		assert_eq!(removed, ["blobs/sha256/cc/c"]);
+	}
+
+	#[actix_web::test]
+	async fn incremental() {
+		let dir = Utf8PathBuf::try_from(std::env::temp_dir().join(format!("oci-registry-export-test-{}", std::process::id()))).unwrap();
+		let source = Repository::Filesystem(filesystem::Repository::new(dir.join("source")));
+		let write = |object: &'static str| {
+			let source = source.clone();
+			async move { source.write(object, futures::stream::iter([Result::<_, std::io::Error>::Ok(bytes::Bytes::from_static(b"x"))]), 1).await.unwrap() }
+		};
+		write("blobs/sha256/aa/a").await;
+		let config = |n: u32, previous: bool| ExportConfig { output_dir: dir.join(format!("bundle-{n}")), state_file: dir.join("state.json").into_std_path_buf(), previous_state: previous.then(|| dir.join("state.json").into_std_path_buf()), concurrency: 2 };
+
+		run(&source, &config(1, false)).await.unwrap();
+		write("blobs/sha256/bb/b").await;
+		run(&source, &config(2, true)).await.unwrap();
+		let index: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("bundle-2").join(BUNDLE_INDEX)).unwrap()).unwrap();
+		assert_eq!(index["added"], serde_json::json!(["blobs/sha256/bb/b"]));
+		assert!(!dir.join("bundle-2/blobs/sha256/aa/a").exists());
+		assert!(dir.join("bundle-2/blobs/sha256/bb/b").exists());
+		let state = read_state(dir.join("state.json").as_std_path()).await.unwrap();
+		assert_eq!(state.objects.len(), 2);
+		let _ = std::fs::remove_dir_all(&dir);
+	}
+}
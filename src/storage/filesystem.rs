@@ -1,78 +1,162 @@
+use core::ops::Range;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
 use core::time::Duration;
+use std::io::SeekFrom;
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::time::SystemTime;
 
 use actix_web::web::Bytes;
 use async_stream::try_stream;
 use async_walkdir::WalkDir;
+use bytes::BytesMut;
 use camino::Utf8Component;
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
 use clap::Parser;
+use futures::stream::BoxStream;
 use futures::stream::StreamExt;
 use futures::stream::TryStream;
 use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde_with::serde_as;
+use serde_with::DisplayFromStr;
 use tokio::fs::create_dir_all;
+use tokio::fs::hard_link;
 use tokio::fs::remove_file;
+use tokio::fs::rename;
 use tokio::fs::symlink_metadata;
 use tokio::fs::File;
 use tokio::fs::OpenOptions;
-use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeekExt;
 use tokio::io::AsyncWriteExt;
-use tokio::io::BufReader;
 use tokio::io::BufWriter;
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 
+use super::pacing::Pacer;
 use super::ReadStream;
+use super::Stat;
 
-#[derive(Clone, Debug, Parser)]
+#[serde_as]
+#[derive(Clone, Debug, Deserialize, Parser)]
 pub struct Config {
 	#[clap(env = "FILESYSTEM_ROOT", long)]
-	root: Utf8PathBuf
+	#[serde_as(as = "DisplayFromStr")]
+	root: Utf8PathBuf,
+	/// Whether copies of the same blob cached for different tenants or pass-through credentials
+	/// are hard links to one file, rather than taking up space of their own.
+	#[clap(env = "FILESYSTEM_HARD_LINK_DUPLICATES", long, default_value_t = false)]
+	#[serde(default)]
+	hard_link_duplicates: bool
 }
 
 impl Config {
 	pub fn repository(&self) -> Repository {
-		Repository { root: self.root.clone() }
+		Repository { root: self.root.clone(), hard_link_duplicates: self.hard_link_duplicates }
 	}
 }
 
 #[derive(Debug, Clone)]
 pub struct Repository {
-	root: Utf8PathBuf
+	root: Utf8PathBuf,
+	hard_link_duplicates: bool
+}
+
+/// Where the one copy of each blob that its other copies are hard links to is kept.  It's outside
+/// `blobs/`, so that it's never served, and stays put while blobs are purged and restored.
+const CONTENT_DIR: &str = "content";
+
+/// Where the copy of a blob that others are linked to is kept, for a blob stored at `object`
+fn content_path(object: &Utf8Path) -> Option<Utf8PathBuf> {
+	let path = object.as_str().strip_prefix("blobs/")?;
+	// The digest's last three components, whichever partition the blob is stored in
+	let mut components = path.rsplitn(4, '/');
+	let (rest, prefix, method) = (components.next()?, components.next()?, components.next()?);
+	Some(Utf8PathBuf::from(format!("{CONTENT_DIR}/{method}/{prefix}/{rest}")))
+}
+
+/// What objects being written are called until they're complete.  Listings leave them out.
+const PARTIAL_MARKER: &str = ".partial-";
+
+static PARTIAL_WRITES: AtomicU64 = AtomicU64::new(0);
+
+/// Where an object is written before it's renamed to `path`; unique to the write, so that two
+/// writes of one object don't interleave.
+fn partial_path(path: &Utf8Path) -> Utf8PathBuf {
+	Utf8PathBuf::from(format!("{path}{PARTIAL_MARKER}{}-{}", std::process::id(), PARTIAL_WRITES.fetch_add(1, Ordering::Relaxed)))
+}
+
+/// How much of a file is read at once.  Large reads keep the cost per byte down when serving big
+/// blobs at line rate.
+const READ_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Streams a file, reading straight into the buffers that are handed on, rather than copying out
+/// of a `BufReader`'s.
+fn chunks<R: AsyncRead + Send + Unpin + 'static>(mut reader: R) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+	Box::pin(try_stream! {
+		let mut buf = BytesMut::new();
+		loop {
+			buf.reserve(READ_CHUNK_SIZE);
+			if (reader.read_buf(&mut buf).await? == 0) {
+				break;
+			}
+			yield buf.split().freeze();
+		}
+	})
 }
 
 impl Repository {
+	pub fn new(root: Utf8PathBuf) -> Self {
+		Self { root, hard_link_duplicates: false }
+	}
+
+	/// Fails if the root can't be looked at, or is something other than a directory.  A root that
+	/// doesn't exist yet is fine, since it's created along with the first object written.
+	pub async fn check_root(&self) -> Result<(), std::io::Error> {
+		match tokio::fs::metadata(&self.root).await {
+			Ok(metadata) if !metadata.is_dir() => Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{} isn't a directory", self.root))),
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+			_ => Ok(())
+		}
+	}
+
 	fn full_path(&self, path: &Utf8Path) -> Utf8PathBuf {
 		let path = path.components().filter(|c| matches!(c, Utf8Component::ParentDir | Utf8Component::Normal(_))).collect::<Utf8PathBuf>();
 		self.root.join(path)
 	}
 
+	pub async fn stat(&self, object: &Utf8Path) -> Result<Stat, std::io::Error> {
+		let metadata = symlink_metadata(self.full_path(object)).await?;
+		Ok(Stat::new(metadata.len(), metadata.modified()?))
+	}
+
 	pub async fn read(&self, object: &Utf8Path, invalidation: Duration) -> Result<ReadStream, super::Error> {
 		let path = self.full_path(object);
-		let (age, length) = {
-			let metadata = symlink_metadata(&path).await?;
-			(SystemTime::now().duration_since(metadata.modified()?).unwrap_or_default(), metadata.len())
-		};
+		let stat = self.stat(object).await?;
+		let age = stat.age();
 		if (age > invalidation) {
 			return Err(super::Error::ObjectTooOld(age.into()));
 		}
-		let mut file = BufReader::with_capacity(16384, File::open(path).await?);
-		Ok(ReadStream::new(
-			length,
-			Box::pin(try_stream! {
-				loop {
-					let buf = file.fill_buf().await?;
-					if(buf.is_empty()) {
-						break;
-					}
-					let len = buf.len();
-					yield Bytes::copy_from_slice(buf);
-					file.consume(len);
-				}
-			})
-		))
+		Ok(ReadStream::new(stat.length(), chunks(File::open(path).await?)).with_modified(stat.modified()))
+	}
+
+	/// Reads just `range` of an object, which has to be within it.
+	pub async fn read_range(&self, object: &Utf8Path, invalidation: Duration, range: Range<u64>) -> Result<ReadStream, super::Error> {
+		let path = self.full_path(object);
+		let stat = self.stat(object).await?;
+		let age = stat.age();
+		if (age > invalidation) {
+			return Err(super::Error::ObjectTooOld(age.into()));
+		}
+		let mut file = File::open(path).await?;
+		file.seek(SeekFrom::Start(range.start)).await?;
+		let length = range.end - range.start;
+		Ok(ReadStream::new(length, chunks(file.take(length))).with_modified(stat.modified()))
 	}
 
 	pub async fn write<S, E>(&self, object: &Utf8Path, reader: S) -> Result<(), super::Error>
@@ -100,23 +184,170 @@ impl Repository {
 		if let Some(parent) = path.parent() {
 			create_dir_all(parent).await?;
 		}
-		let file = OpenOptions::default().create(true).read(false).write(true).truncate(true).open(&path).await?;
+		// Written next to where it goes and renamed into place once it's all there, so that nothing
+		// half-written, or found not to match its digest at the end, is ever read.  The rename also
+		// leaves any copies the old file was linked to alone.
+		let partial = partial_path(&path);
+		let file = OpenOptions::default().create_new(true).read(false).write(true).open(&partial).await?;
 		let mut file = BufWriter::with_capacity(16384, file);
 
-		match _write(&mut file, reader).await {
-			Ok(_) => Ok(file.flush().await?),
-			Err(e) => {
-				self.delete(object.as_ref()).await?;
-				Err(e)
+		let result = match _write(&mut file, reader).await {
+			Ok(()) => file.flush().await.map_err(super::Error::from),
+			Err(e) => Err(e)
+		};
+		drop(file);
+		let result = match result {
+			Ok(()) => rename(&partial, &path).await.map_err(super::Error::from),
+			Err(e) => Err(e)
+		};
+		if let Err(e) = result {
+			if let Err(error) = remove_file(&partial).await {
+				error!(path = %partial, %error, "Failed to delete partially written object");
+			}
+			return Err(e);
+		}
+		if (self.hard_link_duplicates) {
+			if let Err(error) = self.link_duplicate(object).await {
+				warn!(%object, %error, "Failed to hard link blob to its other copies");
 			}
 		}
+		Ok(())
+	}
+
+	/// Makes a blob that's just been written a hard link to the blob's other copies, if there are
+	/// any, or the one they'll be linked to, if not.
+	async fn link_duplicate(&self, object: &Utf8Path) -> Result<(), std::io::Error> {
+		let Some(content) = content_path(object) else {
+			return Ok(());
+		};
+		let path = self.full_path(object);
+		let content = self.full_path(&content);
+		let written = symlink_metadata(&path).await?;
+		match symlink_metadata(&content).await {
+			Ok(existing) if existing.ino() == written.ino() => Ok(()),
+			// Swapped in with a rename, so that readers see one copy or the other, and never a gap
+			Ok(existing) if existing.len() == written.len() => {
+				let link = path.with_extension("link");
+				hard_link(&content, &link).await?;
+				rename(&link, &path).await?;
+				// Links share a modification time, so the blob ages from now for every copy
+				OpenOptions::default().write(true).open(&path).await?.into_std().await.set_modified(SystemTime::now())
+			},
+			// Whatever's there isn't this blob; the new copy takes its place
+			Ok(_) => {
+				let link = content.with_extension("link");
+				hard_link(&path, &link).await?;
+				rename(&link, &content).await
+			},
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+				if let Some(parent) = content.parent() {
+					create_dir_all(parent).await?;
+				}
+				match hard_link(&path, &content).await {
+					Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(e),
+					_ => Ok(())
+				}
+			},
+			Err(e) => Err(e)
+		}
 	}
 
-	pub async fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
-		remove_file(path).await
+	/// Deletes the copies blobs were linked to that no blob is linked to anymore.
+	pub async fn delete_unlinked_content(&self, pacer: &mut Pacer) -> Result<usize, super::Error> {
+		let mut count = 0;
+		for object in self.list_files(CONTENT_DIR.as_ref()).await? {
+			let path = self.full_path(object.as_ref());
+			match symlink_metadata(&path).await {
+				Ok(metadata) if metadata.nlink() == 1 => {
+					pacer.wait().await;
+					match remove_file(&path).await {
+						Ok(()) => count += 1,
+						Err(error) => error!(%path, %error, "Error deleting unlinked blob content")
+					};
+				},
+				Ok(_) => (),
+				Err(error) => error!(%path, %error, "Error reading metadata")
+			};
+		}
+		Ok(count)
+	}
+
+	/// How many bytes hard links between copies of the same blob save:  the size of each blob for
+	/// every copy after the first.
+	pub async fn hard_linked_bytes(&self) -> Result<u64, super::Error> {
+		let mut saved = 0;
+		for object in self.list_files(CONTENT_DIR.as_ref()).await? {
+			if let Ok(metadata) = symlink_metadata(self.full_path(object.as_ref())).await {
+				// One of the links is the content directory's own
+				saved += metadata.len() * metadata.nlink().saturating_sub(2);
+			}
+		}
+		Ok(saved)
+	}
+
+	pub async fn delete(&self, object: &Utf8Path) -> Result<(), std::io::Error> {
+		remove_file(self.full_path(object)).await
+	}
+
+	/// Moves an object, creating directories as needed.  Its modification time is reset, so that it
+	/// ages from when it was moved, the same as an S3 copy.
+	pub async fn rename(&self, from: &Utf8Path, to: &Utf8Path) -> Result<(), std::io::Error> {
+		let to = self.full_path(to);
+		if let Some(parent) = to.parent() {
+			create_dir_all(parent).await?;
+		}
+		rename(self.full_path(from), &to).await?;
+		OpenOptions::default().write(true).open(&to).await?.into_std().await.set_modified(SystemTime::now())
+	}
+
+	/// Lists every file under `prefix`, as paths relative to the storage root.
+	pub async fn list_files(&self, prefix: &Utf8Path) -> Result<Vec<String>, super::Error> {
+		let mut files = Vec::new();
+		let mut entries = WalkDir::new(self.full_path(prefix));
+		let mut first_iteration = true;
+		while let Some(entry) = entries.next().await {
+			let entry = match entry {
+				Ok(v) => v,
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound && first_iteration => continue,
+				Err(e) => return Err(e.into())
+			};
+			first_iteration = false;
+			if (!entry.metadata().await?.is_file()) {
+				continue;
+			}
+			let path = entry.path();
+			let Some(path) = path.strip_prefix(&self.root).ok().and_then(Path::to_str) else {
+				continue;
+			};
+			if (path.contains(PARTIAL_MARKER)) {
+				continue;
+			}
+			files.push(path.to_owned());
+		}
+		Ok(files)
+	}
+
+	/// The combined length of every file under `prefix`.
+	pub async fn total_size(&self, prefix: &Utf8Path) -> Result<u64, super::Error> {
+		let mut total = 0;
+		let mut entries = WalkDir::new(self.full_path(prefix));
+		let mut first_iteration = true;
+		while let Some(entry) = entries.next().await {
+			let entry = match entry {
+				Ok(v) => v,
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound && first_iteration => continue,
+				Err(e) => return Err(e.into())
+			};
+			first_iteration = false;
+			let metadata = entry.metadata().await?;
+			if (metadata.is_file()) {
+				total += metadata.len();
+			}
+		}
+		Ok(total)
 	}
 
-	pub async fn delete_old_files(&self, older_than: SystemTime, prefix: &Utf8Path) -> Result<usize, super::Error> {
+	pub async fn delete_old_files(&self, older_than: SystemTime, prefix: &Utf8Path, keep: impl Fn(&str) -> bool, pacer: &mut Pacer) -> Result<usize, super::Error> {
 		let mut count = 0;
 		let root = self.root.join(prefix);
 		let mut entries = WalkDir::new(root);
@@ -140,7 +371,7 @@ impl Repository {
 					continue;
 				}
 			};
-			if (!metadata.is_file()) {
+			if (!metadata.is_file() || path.strip_prefix(&self.root).ok().and_then(Path::to_str).is_some_and(&keep)) {
 				continue;
 			}
 			let modified = match metadata.modified() {
@@ -151,7 +382,8 @@ impl Repository {
 				}
 			};
 			if (modified < older_than) {
-				match self.delete(&path).await {
+				pacer.wait().await;
+				match remove_file(&path).await {
 					Ok(_) => info!(path = %path.display(), "Aged out"),
 					Err(error) => {
 						error!(path = %path.display(), %error, "Error deleting object");
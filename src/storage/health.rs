@@ -0,0 +1,136 @@
+//! Storage health:  operations that fail for reasons other than the object not being there are
+//! counted, and once enough fail in a row, storage is taken to be down.  While it is, pulls pass
+//! through to upstream without reading from or writing to the cache, so that a storage outage costs
+//! cache hits rather than failed pulls, until storage answers a check again.
+
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use once_cell::sync::Lazy;
+use prometheus::register_int_gauge;
+use prometheus::IntGauge;
+use tokio::time::MissedTickBehavior;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+use super::Error;
+use super::Repository;
+
+static DEGRADED: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("storage_degraded", "Whether storage is taken to be down, and pulls are passed through to upstream without caching (1) or not (0)").unwrap());
+
+static HEALTH: Health = Health::new();
+
+struct Health {
+	/// How many failures in a row take storage down; zero never does
+	threshold: AtomicUsize,
+	failures: AtomicUsize,
+	degraded: AtomicBool
+}
+
+impl Health {
+	const fn new() -> Self {
+		Self { threshold: AtomicUsize::new(0), failures: AtomicUsize::new(0), degraded: AtomicBool::new(false) }
+	}
+
+	/// Counts an operation's result; returns whether it's what took storage down.
+	fn record<T>(&self, result: &Result<T, Error>) -> bool {
+		match result {
+			Ok(_) => {
+				self.failures.store(0, Ordering::Relaxed);
+				false
+			},
+			Err(error) if is_storage_failure(error) => {
+				let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+				let threshold = self.threshold.load(Ordering::Relaxed);
+				threshold > 0 && failures >= threshold && !self.degraded.swap(true, Ordering::Relaxed)
+			},
+			Err(_) => false
+		}
+	}
+
+	fn recovered(&self) {
+		self.failures.store(0, Ordering::Relaxed);
+		self.degraded.store(false, Ordering::Relaxed);
+	}
+}
+
+/// Whether an error says something about storage itself; a missing or expired object doesn't, and
+/// neither do the upstream and client problems that surface through a blob's write.
+fn is_storage_failure(error: &Error) -> bool {
+	!error.is_not_found() && !matches!(error, Error::ObjectTooOld(_) | Error::ClientAborted | Error::Upstream(_) | Error::DataCorrupt(_) | Error::LengthMismatch(_) | Error::DeadlineExceeded | Error::InvalidManifestMetadata)
+}
+
+/// Sets how many storage operations have to fail in a row for storage to be taken to be down; zero
+/// never takes it down.
+pub fn set_failure_threshold(threshold: usize) {
+	HEALTH.threshold.store(threshold, Ordering::Relaxed);
+}
+
+/// Counts a storage operation's result towards storage's health.
+pub(super) fn record<T>(result: &Result<T, Error>) {
+	if (HEALTH.record(result)) {
+		DEGRADED.set(1);
+		warn!(failures = HEALTH.failures.load(Ordering::Relaxed), "Storage is failing; passing pulls through to upstream without caching until it recovers");
+	}
+}
+
+/// Whether storage is down, and pulls are to be passed through without it.
+pub fn is_degraded() -> bool {
+	HEALTH.degraded.load(Ordering::Relaxed)
+}
+
+/// While storage is down, checks every `interval` whether it can be reached again, and brings it
+/// back once it can.  Runs until the task is dropped.
+pub async fn monitor(repo: Repository, interval: Duration) {
+	let mut interval = tokio::time::interval(interval);
+	interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	loop {
+		interval.tick().await;
+		if (!is_degraded()) {
+			continue;
+		}
+		match repo.check_access().await {
+			Ok(()) => {
+				HEALTH.recovered();
+				DEGRADED.set(0);
+				info!("Storage has recovered; caching pulls again");
+			},
+			Err(error) => debug!(%error, "Storage is still unavailable")
+		};
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn failures_in_a_row() {
+		let health = Health::new();
+		health.threshold.store(3, Ordering::Relaxed);
+		let failed = || Err::<(), _>(Error::from(std::io::Error::from(std::io::ErrorKind::PermissionDenied)));
+		let missing = Err::<(), _>(Error::from(std::io::Error::from(std::io::ErrorKind::NotFound)));
+
+		assert!(!health.record(&failed()));
+		assert!(!health.record(&failed()));
+		// A success in between starts the count over, and missing objects don't count
+		assert!(!health.record(&Ok(())));
+		assert!(!health.record(&missing));
+		assert!(!health.record(&failed()));
+		assert!(!health.record(&failed()));
+		assert!(health.record(&failed()));
+		assert!(health.degraded.load(Ordering::Relaxed));
+		// Only the failure that took it down says so
+		assert!(!health.record(&failed()));
+		health.recovered();
+		assert!(!health.degraded.load(Ordering::Relaxed));
+
+		health.threshold.store(0, Ordering::Relaxed);
+		for _ in 0..10 {
+			assert!(!health.record(&failed()));
+		}
+	}
+}
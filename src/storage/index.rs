@@ -0,0 +1,165 @@
+//! The storage index:  a JSON object at the top of storage, rewritten every
+//! `--storage-index-interval`, that says what the rest of storage holds, so that tooling that
+//! backs it up or verifies it doesn't have to know how this crate lays it out:  the layout
+//! version, what's kept under each top-level prefix and how many objects there are, and which
+//! namespaces have manifests cached.  It's a snapshot as of when it was written; objects come and
+//! go between one and the next, and with several instances sharing storage, whichever wrote last
+//! wins.
+
+use core::time::Duration;
+use std::collections::BTreeMap;
+use std::iter;
+
+use bytes::Bytes;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::time::MissedTickBehavior;
+use tracing::debug;
+use tracing::warn;
+
+use super::is_sidecar;
+use super::layout;
+use super::Error;
+use super::Repository;
+
+pub const INDEX_OBJECT: &str = "storage-index.json";
+
+/// The version of the index's own format, bumped whenever it changes in a way readers would have
+/// to know about.
+const FORMAT_VERSION: u32 = 1;
+
+/// What this crate keeps under each top-level prefix.
+const PREFIXES: &[(&str, &str)] = &[
+	("manifests/", "Cached manifests, as manifests/<namespace>/<image>/<tag or digest>, with those cached for a tenant or for pass-through credentials under manifests/<namespace>/_tenant/<tenant>/ or manifests/<namespace>/_private/<partition>/; objects named .<name>.meta hold the metadata of the object <name> next to them"),
+	("blobs/", "Cached blobs, as blobs/<algorithm>/<first two hex digits>/<rest of the hex digest>, under blobs/_tenant/<tenant>/ or blobs/_private/<partition>/ for tenants and pass-through credentials"),
+	("foreign/", "Foreign layers fetched from the URLs their manifests give, keyed like blobs"),
+	("referrers/", "Index of cached manifests by the manifest they name as their subject"),
+	("labels/", "Index of cached images by their annotations and labels"),
+	("sboms/", "Index of cached images by the packages their SBOMs list"),
+	("history/", "Where each tag has pointed upstream, and when it moved"),
+	("provenance/", "Where each cached object was fetched from, and when; keyed by the object's own key"),
+	("timestamps/", "RFC 3161 timestamps and attestations of cached manifests; keyed by the manifest's own key"),
+	("trash/", "Purged objects that can still be restored, keyed by trash/ and the key they had"),
+	("quarantine/", "Objects storage checks found corrupt, keyed by quarantine/ and the key they had"),
+	("content/", "Filesystem storage only:  one copy of each blob's content, which its copies under blobs/ are hard links to")
+];
+
+#[derive(Debug, Serialize)]
+pub struct StorageIndex {
+	format_version: u32,
+	/// How objects are keyed; see `oci-registry migrate`
+	layout_version: u32,
+	/// When this was written, in RFC 3339
+	written: String,
+	/// The version of `oci-registry` that wrote it
+	written_by: String,
+	/// Every top-level prefix there are objects under
+	prefixes: BTreeMap<String, Prefix>,
+	/// The namespaces with manifests cached
+	namespaces: BTreeMap<String, Namespace>,
+	/// Objects at the top level, such as `layout-version` and the runtime state objects
+	objects: Vec<String>
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize)]
+struct Prefix {
+	/// What's kept there; prefixes this version doesn't know of are left undescribed
+	#[serde(skip_serializing_if = "Option::is_none")]
+	description: Option<&'static str>,
+	/// How many objects there are, metadata included
+	objects: usize
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Serialize)]
+struct Namespace {
+	/// How many manifests there are, tenants' included, not counting their metadata
+	manifests: usize
+}
+
+impl StorageIndex {
+	fn new(layout_version: u32, objects: &[String]) -> Self {
+		let mut prefixes = BTreeMap::new();
+		let mut namespaces = BTreeMap::<String, Namespace>::new();
+		let mut top_level = Vec::new();
+		for object in objects {
+			let Some((prefix, rest)) = object.split_once('/') else {
+				if (object != INDEX_OBJECT) {
+					top_level.push(object.clone());
+				}
+				continue;
+			};
+			let prefix = format!("{prefix}/");
+			if (prefix == "manifests/" && !is_sidecar(object)) {
+				if let Some((namespace, _)) = rest.split_once('/') {
+					namespaces.entry(namespace.to_owned()).or_default().manifests += 1;
+				}
+			}
+			prefixes
+				.entry(prefix)
+				.or_insert_with_key(|prefix| Prefix { description: PREFIXES.iter().find(|(p, _)| *p == prefix.as_str()).map(|(_, description)| *description), objects: 0 })
+				.objects += 1;
+		}
+		top_level.sort();
+		Self {
+			format_version: FORMAT_VERSION,
+			layout_version,
+			written: OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+			written_by: env!("CARGO_PKG_VERSION").to_owned(),
+			prefixes,
+			namespaces,
+			objects: top_level
+		}
+	}
+}
+
+/// Lists storage and writes the index of what's in it.
+pub async fn write(repo: &Repository) -> Result<(), Error> {
+	let layout_version = layout::read_version(repo).await?.unwrap_or(layout::CURRENT_VERSION);
+	let index = StorageIndex::new(layout_version, &repo.list("").await?);
+	let body = Bytes::from(serde_json::to_vec_pretty(&index).unwrap_or_default());
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	repo.write(INDEX_OBJECT, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body))), len).await?;
+	debug!(objects = index.prefixes.values().map(|p| p.objects).sum::<usize>(), "Wrote storage index");
+	Ok(())
+}
+
+/// Rewrites the index every `interval`, starting now.
+pub async fn run(repo: Repository, interval: Duration) {
+	let mut interval = tokio::time::interval(interval);
+	interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	loop {
+		interval.tick().await;
+		if let Err(error) = write(&repo).await {
+			warn!(%error, "Failed to write storage index");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn counts_objects_by_prefix_and_namespace() {
+		let objects = [
+			"layout-version",
+			"storage-index.json",
+			"pins.json",
+			"manifests/docker.io/library/alpine/latest",
+			"manifests/docker.io/library/alpine/.latest.meta",
+			"manifests/docker.io/_tenant/a/library/alpine/3.19",
+			"manifests/ghcr.io/org/app/sha256:aa",
+			"blobs/sha256/ab/cdef",
+			"scratch/left-behind"
+		]
+		.map(str::to_owned);
+		let index = StorageIndex::new(4, &objects);
+		assert_eq!(index.objects, ["layout-version", "pins.json"]);
+		assert_eq!(index.namespaces.iter().map(|(ns, counts)| (ns.as_str(), counts.manifests)).collect::<Vec<_>>(), [("docker.io", 2), ("ghcr.io", 1)]);
+		assert_eq!(index.prefixes["manifests/"].objects, 4);
+		assert_eq!(index.prefixes["blobs/"].objects, 1);
+		assert!(index.prefixes["blobs/"].description.is_some());
+		assert_eq!(index.prefixes["scratch/"], Prefix { description: None, objects: 1 });
+	}
+}
@@ -0,0 +1,280 @@
+use core::time::Duration;
+use std::iter;
+
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::future::BoxFuture;
+use futures::stream::TryStreamExt;
+use tracing::info;
+use tracing::warn;
+
+use super::is_sidecar;
+use super::Error;
+use super::Manifest;
+use super::ManifestMetadata;
+use super::Repository;
+
+/// The layout version written by this build of oci-registry.  Bump this whenever the mapping from
+/// requests to storage keys changes, and add a corresponding entry to `MIGRATIONS`.
+pub const CURRENT_VERSION: u32 = 5;
+
+/// Caches written before the layout marker existed are all version 1.
+const UNMARKED_VERSION: u32 = 1;
+
+pub(super) const VERSION_OBJECT: &str = "layout-version";
+
+pub struct Migration {
+	/// The version this migration upgrades from; it leaves storage at `from + 1`.
+	pub from: u32,
+	pub description: &'static str,
+	/// Returns the number of objects rewritten.
+	pub run: for<'a> fn(&'a Repository) -> BoxFuture<'a, Result<usize, Error>>
+}
+
+static MIGRATIONS: &[Migration] = &[
+	Migration {
+		from: 1,
+		description: "Store manifests as a metadata header followed by the raw manifest, instead of a JSON envelope",
+		run: unwrap_manifests
+	},
+	Migration {
+		from: 2,
+		description: "Move manifest metadata out of the stored object and into object metadata or a sidecar file",
+		run: move_manifest_metadata
+	},
+	Migration {
+		from: 3,
+		description: "Store manifests cached for tenants and pass-through credentials under the image name without its namespace",
+		run: unqualify_partitioned_manifests
+	},
+	Migration {
+		from: 4,
+		description: "Drop what's cached for pass-through credentials under plain hashes of them, which can't be renamed to keyed ones without the credentials",
+		run: drop_unkeyed_partitions
+	}
+];
+
+/// Layout version 2 manifests start with a line of JSON metadata; anything this long isn't one.
+const MAX_MANIFEST_HEADER_SIZE: usize = 4096;
+
+async fn read_all(repo: &Repository, object: &str) -> Result<BytesMut, Error> {
+	let stream = repo.read(object, Duration::MAX).await?;
+	Ok(stream.into_inner().try_collect::<BytesMut>().await?)
+}
+
+fn unwrap_manifests(repo: &Repository) -> BoxFuture<'_, Result<usize, Error>> {
+	Box::pin(async move {
+		let mut count = 0;
+		for object in repo.list_manifests().await? {
+			let body = read_all(repo, &object).await?;
+			// Anything that isn't an envelope was already rewritten by an interrupted run
+			let Ok(manifest) = serde_json::from_slice::<Manifest>(body.as_ref()) else {
+				continue;
+			};
+			let mut body = serde_json::to_vec(&manifest.metadata()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+			body.push(b'\n');
+			body.extend_from_slice(manifest.manifest.as_ref());
+			let len = body.len().try_into().unwrap_or(i64::MAX);
+			repo.write(&object, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(Bytes::from(body)))), len).await?;
+			count += 1;
+		}
+		Ok(count)
+	})
+}
+
+fn move_manifest_metadata(repo: &Repository) -> BoxFuture<'_, Result<usize, Error>> {
+	Box::pin(async move {
+		let mut count = 0;
+		for object in repo.list_manifests().await? {
+			let mut body = read_all(repo, &object).await?;
+			// Anything without a header was already rewritten by an interrupted run
+			let Some(newline) = body.iter().take(MAX_MANIFEST_HEADER_SIZE).position(|b| *b == b'\n') else {
+				continue;
+			};
+			let Ok(metadata) = serde_json::from_slice::<ManifestMetadata>(&body[..newline]) else {
+				continue;
+			};
+			let manifest = body.split_off(newline + 1).freeze();
+			repo.write_manifest(&object, manifest, &metadata).await?;
+			count += 1;
+		}
+		Ok(count)
+	})
+}
+
+/// Where version 3 wrote tenants' and pass-through credentials' manifests (and what's indexed
+/// alongside them) for clients that named the namespace in the image, as in
+/// `manifests/docker.io/_tenant/a/docker.io/library/alpine/latest`, in the version 4 layout.
+fn unqualified_path(object: &str) -> Option<String> {
+	let (trash, object) = match object.strip_prefix(super::TRASH_PREFIX) {
+		Some(object) => (super::TRASH_PREFIX, object),
+		None => ("", object)
+	};
+	let (prefix, path) = object.split_once('/')?;
+	let mut parts = path.splitn(4, '/');
+	let (ns, kind, id, image) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+	let image = image.strip_prefix(ns)?.strip_prefix('/').filter(|_| kind.starts_with('_'))?;
+	Some(format!("{trash}{prefix}/{ns}/{kind}/{id}/{image}"))
+}
+
+fn unqualify_partitioned_manifests(repo: &Repository) -> BoxFuture<'_, Result<usize, Error>> {
+	Box::pin(async move {
+		let mut count = 0;
+		for prefix in ["manifests/", "trash/manifests/", "referrers/", "labels/", "sboms/"] {
+			let manifests = prefix.ends_with("manifests/");
+			for object in repo.list(prefix).await? {
+				let Some(to) = unqualified_path(&object).filter(|_| !(manifests && is_sidecar(&object))) else {
+					continue;
+				};
+				// Cached again under the new name since; that copy is just as good, and newer
+				let exists = match repo.stat(&to, Duration::MAX).await {
+					Ok(_) => true,
+					Err(e) if e.is_not_found() => false,
+					Err(e) => return Err(e)
+				};
+				match (manifests, exists) {
+					(true, true) => repo.delete_manifest(&object).await?,
+					(true, false) => repo.rename_manifest(&object, &to).await?,
+					(false, true) => repo.delete(&object).await?,
+					(false, false) => repo.rename(&object, &to).await?
+				};
+				count += 1;
+			}
+		}
+		Ok(count)
+	})
+}
+
+/// Whether `object` was cached for pass-through credentials, as under
+/// `manifests/docker.io/_private/<partition>/library/alpine/latest` or `blobs/_private/<partition>/`,
+/// in the trash or not.
+fn is_private(object: &str) -> bool {
+	let object = object.strip_prefix(super::TRASH_PREFIX).unwrap_or(object);
+	let mut parts = object.splitn(4, '/');
+	match (parts.next(), parts.next(), parts.next()) {
+		(Some("blobs"), Some("_private"), _) => true,
+		(Some(_), Some(_), Some("_private")) => true,
+		_ => false
+	}
+}
+
+/// Version 4 named pass-through credentials' partitions with a plain hash of the credentials; the
+/// keyed names version 5 uses can only be worked out from the credentials themselves, so what was
+/// cached under the old ones is fetched again, under the new, the next time it's pulled.
+fn drop_unkeyed_partitions(repo: &Repository) -> BoxFuture<'_, Result<usize, Error>> {
+	Box::pin(async move {
+		let mut count = 0;
+		for prefix in ["manifests/", "blobs/_private/", "referrers/", "labels/", "sboms/", super::TRASH_PREFIX] {
+			for object in repo.list(prefix).await? {
+				if (!is_private(&object) || is_sidecar(&object)) {
+					continue;
+				}
+				match object.strip_prefix(super::TRASH_PREFIX).unwrap_or(&object).starts_with("manifests/") {
+					true => repo.delete_manifest(&object).await?,
+					false => repo.delete(&object).await?
+				};
+				count += 1;
+			}
+		}
+		Ok(count)
+	})
+}
+
+pub async fn read_version(repo: &Repository) -> Result<Option<u32>, Error> {
+	let stream = match repo.read(VERSION_OBJECT, Duration::MAX).await {
+		Ok(v) => v,
+		Err(e) if e.is_not_found() => return Ok(None),
+		Err(e) => return Err(e)
+	};
+	let body = stream.into_inner().try_collect::<BytesMut>().await?;
+	let version = core::str::from_utf8(body.as_ref()).ok().and_then(|s| s.trim().parse().ok()).ok_or(Error::InvalidLayoutVersion)?;
+	Ok(Some(version))
+}
+
+pub async fn write_version(repo: &Repository, version: u32) -> Result<(), Error> {
+	let body = Bytes::from(format!("{version}\n"));
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	repo.write(VERSION_OBJECT, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body))), len).await
+}
+
+/// Makes sure storage is laid out the way this build expects before serving from it.  Unmarked
+/// storage is assumed to be in the original layout, unless there's nothing in it yet.
+pub async fn check(repo: &Repository) -> Result<(), Error> {
+	let found = match read_version(repo).await? {
+		Some(v) => v,
+		None => {
+			let version = match repo.list_manifests().await?.is_empty() {
+				true => CURRENT_VERSION,
+				false => UNMARKED_VERSION
+			};
+			if (version == CURRENT_VERSION) {
+				info!(version, "No storage layout marker found; writing one");
+				write_version(repo, version).await?;
+			}
+			version
+		}
+	};
+	match found.cmp(&CURRENT_VERSION) {
+		core::cmp::Ordering::Equal => Ok(()),
+		core::cmp::Ordering::Less => Err(Error::LayoutOutdated { found, expected: CURRENT_VERSION }),
+		core::cmp::Ordering::Greater => Err(Error::LayoutTooNew { found, expected: CURRENT_VERSION })
+	}
+}
+
+/// Runs every migration needed to bring storage from its current layout version up to
+/// `CURRENT_VERSION`, updating the marker after each step so that an interrupted migration can be
+/// resumed.
+pub async fn migrate(repo: &Repository, dry_run: bool) -> Result<(), Error> {
+	let marker = read_version(repo).await?;
+	let mut version = marker.unwrap_or(UNMARKED_VERSION);
+	if (version > CURRENT_VERSION) {
+		return Err(Error::LayoutTooNew { found: version, expected: CURRENT_VERSION });
+	}
+	if (version == CURRENT_VERSION) {
+		info!(version, "Storage layout is already current");
+	}
+	while (version < CURRENT_VERSION) {
+		let Some(migration) = MIGRATIONS.iter().find(|m| m.from == version) else {
+			warn!(version, "No migration registered for this layout version");
+			return Err(Error::LayoutOutdated { found: version, expected: CURRENT_VERSION });
+		};
+		info!(from = version, to = version + 1, description = migration.description, dry_run, "Migrating storage layout");
+		if (!dry_run) {
+			let count = (migration.run)(repo).await?;
+			info!(from = version, to = version + 1, count, "Migrated objects");
+		}
+		version += 1;
+		if (!dry_run) {
+			write_version(repo, version).await?;
+		}
+	}
+	if (marker.is_none() && !dry_run) {
+		write_version(repo, version).await?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn unqualified_paths() {
+		assert_eq!(unqualified_path("manifests/docker.io/_tenant/a/docker.io/library/alpine/latest").as_deref(), Some("manifests/docker.io/_tenant/a/library/alpine/latest"));
+		assert_eq!(unqualified_path("referrers/ghcr.io/_private/abcd/ghcr.io/org/app/sha256:aa/sha256:bb").as_deref(), Some("referrers/ghcr.io/_private/abcd/org/app/sha256:aa/sha256:bb"));
+		assert_eq!(unqualified_path("trash/manifests/docker.io/_tenant/a/docker.io/library/alpine/latest").as_deref(), Some("trash/manifests/docker.io/_tenant/a/library/alpine/latest"));
+		assert_eq!(unqualified_path("manifests/docker.io/_tenant/a/library/alpine/latest"), None);
+		assert_eq!(unqualified_path("manifests/docker.io/library/docker.io/latest"), None);
+		assert_eq!(unqualified_path("manifests/docker.io/_tenant/a/docker.iox/app/latest"), None);
+	}
+
+	#[test]
+	fn private_objects() {
+		assert!(is_private("manifests/docker.io/_private/abcd/library/alpine/latest"));
+		assert!(is_private("blobs/_private/abcd/sha256/aa/bb"));
+		assert!(is_private("trash/referrers/ghcr.io/_private/abcd/org/app/sha256:aa/sha256:bb"));
+		assert!(!is_private("manifests/docker.io/_tenant/a/library/alpine/latest"));
+		assert!(!is_private("manifests/docker.io/library/_private/latest"));
+		assert!(!is_private("blobs/sha256/aa/bb"));
+	}
+}
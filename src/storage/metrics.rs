@@ -0,0 +1,96 @@
+//! How long storage operations take, how many are under way, and how much they move, so that a slow
+//! backend can be told apart from a slow upstream, along with warnings for operations slower than a
+//! threshold.
+
+use core::future::Future;
+use core::time::Duration;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use prometheus::register_histogram_vec;
+use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge_vec;
+use prometheus::HistogramVec;
+use prometheus::IntCounterVec;
+use prometheus::IntGauge;
+use prometheus::IntGaugeVec;
+use tracing::info_span;
+use tracing::warn;
+use tracing::Instrument;
+
+use super::Error;
+
+static DURATION: Lazy<HistogramVec> = Lazy::new(|| register_histogram_vec!("storage_operation_duration_seconds", "Time taken by storage operations", &["backend", "operation", "result"]).unwrap());
+static IN_PROGRESS: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("storage_operations_in_progress", "Number of storage operations under way", &["backend", "operation"]).unwrap());
+static BYTES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("storage_bytes", "Number of bytes read from or written to storage", &["backend", "direction"]).unwrap());
+
+static SLOW_THRESHOLD: OnceCell<Duration> = OnceCell::new();
+
+/// Sets how long a storage operation can take before it's logged as slow; zero, the default, never
+/// logs them.  Only the first call has any effect.
+pub fn set_slow_threshold(threshold: Duration) {
+	let _ = SLOW_THRESHOLD.set(threshold);
+}
+
+/// Held for as long as an operation is under way, including one that's given up on part way.
+struct InProgress(IntGauge);
+
+impl InProgress {
+	fn start(backend: &'static str, operation: &'static str) -> Self {
+		let gauge = IN_PROGRESS.with_label_values(&[backend, operation]);
+		gauge.inc();
+		Self(gauge)
+	}
+}
+
+impl Drop for InProgress {
+	fn drop(&mut self) {
+		self.0.dec();
+	}
+}
+
+fn outcome<T>(result: &Result<T, Error>) -> &'static str {
+	match result {
+		Ok(_) => "ok",
+		Err(e) if e.is_not_found() => "not_found",
+		Err(_) => "error"
+	}
+}
+
+/// Runs a storage operation on `object`, recording how long it took.
+pub(super) async fn timed<T>(backend: &'static str, operation: &'static str, object: &str, f: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+	let start = Instant::now();
+	let in_progress = InProgress::start(backend, operation);
+	let result = f.instrument(info_span!("storage", backend, operation)).await;
+	drop(in_progress);
+	let elapsed = start.elapsed();
+	let outcome = outcome(&result);
+	DURATION.with_label_values(&[backend, operation, outcome]).observe(elapsed.as_secs_f64());
+	super::health::record(&result);
+	match SLOW_THRESHOLD.get() {
+		Some(threshold) if !threshold.is_zero() && elapsed >= *threshold => warn!(backend, operation, object, outcome, elapsed_ms = elapsed.as_millis() as u64, "Slow storage operation"),
+		_ => ()
+	};
+	result
+}
+
+pub(super) fn read(backend: &'static str, len: usize) {
+	BYTES.with_label_values(&[backend, "read"]).inc_by(len as u64);
+}
+
+pub(super) fn written(backend: &'static str, len: usize) {
+	BYTES.with_label_values(&[backend, "written"]).inc_by(len as u64);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn outcomes() {
+		assert_eq!(outcome(&Ok::<_, Error>(())), "ok");
+		assert_eq!(outcome::<()>(&Err(std::io::Error::from(std::io::ErrorKind::NotFound).into())), "not_found");
+		assert_eq!(outcome::<()>(&Err(Error::DeadlineExceeded)), "error");
+	}
+}
@@ -0,0 +1,131 @@
+//! Pacing for cache maintenance:  deleting what's aged out goes no faster than configured, and
+//! waits while the registry is busy serving requests, so that cleanup doesn't compete with pulls
+//! for storage.
+
+use core::time::Duration;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use clap::Parser;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter;
+use prometheus::register_int_gauge;
+use prometheus::IntCounter;
+use prometheus::IntGauge;
+use tokio::time::Instant;
+use tracing::info;
+
+static IN_FLIGHT: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("requests_in_flight", "Number of requests currently being served").unwrap());
+static PAUSED: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("eviction_paused_seconds", "Time cache cleanup has spent waiting for request load to drop, in seconds").unwrap());
+
+#[derive(Clone, Debug, Parser)]
+pub struct PacingConfig {
+	/// The most objects cache cleanup deletes per second; `0` doesn't limit it.
+	#[clap(env, long, default_value_t = 0)]
+	eviction_max_deletes_per_second: u32,
+	/// While more than this many requests are being served, cache cleanup waits for them to drop
+	/// before deleting anything else; `0` never waits.
+	#[clap(env, long, default_value_t = 0)]
+	eviction_pause_above_in_flight: usize,
+	/// How often paused cache cleanup checks whether the load has dropped.
+	#[clap(env, long, default_value = "1s")]
+	eviction_pause_check_interval: humantime::Duration
+}
+
+impl PacingConfig {
+	pub fn pacer(&self, in_flight: InFlight) -> Pacer {
+		let interval = match self.eviction_max_deletes_per_second {
+			0 => Duration::ZERO,
+			n => Duration::from_secs(1) / n
+		};
+		Pacer { interval, next: None, pause_above: self.eviction_pause_above_in_flight, check_interval: *self.eviction_pause_check_interval, in_flight }
+	}
+}
+
+/// Counts the requests being served, for cleanup to hold off on while there are many.
+#[derive(Clone, Debug, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+/// Counts a request as in flight for as long as it's held.
+#[derive(Debug)]
+pub struct InFlightGuard(InFlight);
+
+impl Drop for InFlightGuard {
+	fn drop(&mut self) {
+		(self.0).0.fetch_sub(1, Ordering::Relaxed);
+		IN_FLIGHT.dec();
+	}
+}
+
+impl InFlight {
+	pub fn track(&self) -> InFlightGuard {
+		self.0.fetch_add(1, Ordering::Relaxed);
+		IN_FLIGHT.inc();
+		InFlightGuard(self.clone())
+	}
+
+	pub fn count(&self) -> usize {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+/// Spaces out the deletes of one cleanup run.  The default doesn't hold anything up.
+#[derive(Debug, Default)]
+pub struct Pacer {
+	/// The least time between deletes
+	interval: Duration,
+	next: Option<Instant>,
+	/// `0` for never pausing
+	pause_above: usize,
+	check_interval: Duration,
+	in_flight: InFlight
+}
+
+impl Pacer {
+	/// Waits until it's this pacer's turn to delete another object.
+	pub async fn wait(&mut self) {
+		if let Some(next) = self.next {
+			tokio::time::sleep_until(next).await;
+		}
+		if (self.pause_above > 0 && self.in_flight.count() > self.pause_above) {
+			let paused = Instant::now();
+			info!(in_flight = self.in_flight.count(), threshold = self.pause_above, "Pausing cache cleanup while request load is high");
+			while (self.in_flight.count() > self.pause_above) {
+				tokio::time::sleep(self.check_interval.max(Duration::from_millis(10))).await;
+			}
+			let elapsed = paused.elapsed();
+			PAUSED.inc_by(elapsed.as_secs());
+			info!(paused_secs = elapsed.as_secs(), "Resuming cache cleanup");
+		}
+		if (!self.interval.is_zero()) {
+			self.next = Some(Instant::now() + self.interval);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[actix_web::test]
+	async fn pacing() {
+		let in_flight = InFlight::default();
+		let config = PacingConfig { eviction_max_deletes_per_second: 20, eviction_pause_above_in_flight: 1, eviction_pause_check_interval: Duration::from_millis(10).into() };
+		let mut pacer = config.pacer(in_flight.clone());
+		let started = Instant::now();
+		for _ in 0..3 {
+			pacer.wait().await;
+		}
+		assert!(started.elapsed() >= Duration::from_millis(100));
+
+		let requests = [in_flight.track(), in_flight.track()];
+		assert_eq!(in_flight.count(), 2);
+		let waiting = actix_web::rt::spawn(async move { pacer.wait().await });
+		actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+		assert!(!waiting.is_finished());
+		drop(requests);
+		waiting.await.unwrap();
+		assert_eq!(in_flight.count(), 0);
+	}
+}
@@ -0,0 +1,187 @@
+//! Buffering for objects streamed out of storage.  Backends yield an object in whatever chunks
+//! they get it in, and only as fast as whoever's reading takes them, so on storage with a long
+//! round trip, such as S3 over a WAN, a client can spend much of a pull waiting on the next chunk.
+//! With `--storage-read-buffer-size`, chunks are put together until they're at least that big
+//! before they're passed on, and with `--storage-read-ahead`, that many of them are read from
+//! storage ahead of the client, on a task of their own, so that storage is being read while the
+//! client takes what's already there.
+//!
+//! Either way, `storage_stream_throughput_bytes_per_second` has how fast objects of at least
+//! [`MEASURED_LENGTH`] were streamed, from the first chunk asked for to the last one taken, and
+//! `storage_stream_wait_seconds` how long readers spent waiting on storage for chunks, for telling
+//! whether the settings help:  with enough read-ahead, what's left of the wait is the client's own.
+
+use core::time::Duration;
+use std::time::Instant;
+
+use actix_web::rt;
+use bytes::Bytes;
+use bytes::BytesMut;
+use futures::stream::BoxStream;
+use futures::stream::StreamExt;
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use prometheus::exponential_buckets;
+use prometheus::register_counter_vec;
+use prometheus::register_histogram_vec;
+use prometheus::CounterVec;
+use prometheus::HistogramVec;
+use tokio::sync::mpsc;
+
+/// Objects shorter than this aren't measured, as their throughput is mostly the time to first byte
+pub const MEASURED_LENGTH: u64 = 1024 * 1024;
+
+static THROUGHPUT: Lazy<HistogramVec> = Lazy::new(|| {
+	register_histogram_vec!(
+		"storage_stream_throughput_bytes_per_second",
+		"How fast objects of at least 1 MiB were streamed out of storage, from the first chunk asked for to the last one taken",
+		&["backend"],
+		exponential_buckets(256.0 * 1024.0, 2.0, 12).unwrap()
+	)
+	.unwrap()
+});
+static WAIT: Lazy<CounterVec> = Lazy::new(|| register_counter_vec!("storage_stream_wait_seconds", "Time spent waiting on storage for the next chunk of an object being streamed", &["backend"]).unwrap());
+
+static CONFIG: OnceCell<ReadAhead> = OnceCell::new();
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadAhead {
+	/// The fewest bytes passed on at a time, but for the end of the object; 0 passes chunks on as
+	/// the backend yields them
+	pub buffer_size: usize,
+	/// How many chunks are read ahead of the reader; 0 reads only as fast as it takes them
+	pub chunks: usize
+}
+
+/// Sets how objects are buffered as they're read; by default, they aren't.  Only the first call
+/// has any effect.
+pub fn configure(read_ahead: ReadAhead) {
+	let _ = CONFIG.set(read_ahead);
+}
+
+/// Buffers an object of `length` bytes read from `backend` as configured, and measures how fast
+/// it's read.
+pub(super) fn buffered(inner: BoxStream<'static, Result<Bytes, std::io::Error>>, length: u64, backend: &'static str) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+	let config = CONFIG.get().copied().unwrap_or_default();
+	let mut inner = inner;
+	if (config.buffer_size != 0) {
+		inner = coalesce(inner, config.buffer_size);
+	}
+	// Anything that fits in one buffer is read in one go anyway
+	if (config.chunks != 0 && length > config.buffer_size as u64) {
+		inner = read_ahead(inner, config.chunks);
+	}
+	measured(inner, length, backend)
+}
+
+/// Puts chunks together until there are at least `size` bytes of them.
+fn coalesce(inner: BoxStream<'static, Result<Bytes, std::io::Error>>, size: usize) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+	futures::stream::unfold(Some(inner), move |inner| async move {
+		let mut inner = inner?;
+		let mut buffer = BytesMut::new();
+		loop {
+			match inner.next().await {
+				Some(Ok(chunk)) if (buffer.is_empty() && chunk.len() >= size) => return Some((Ok(chunk), Some(inner))),
+				Some(Ok(chunk)) => {
+					buffer.extend_from_slice(&chunk);
+					if (buffer.len() >= size) {
+						return Some((Ok(buffer.freeze()), Some(inner)));
+					}
+				},
+				// What's buffered is no use to a reader that's about to be told the object's broken
+				Some(Err(e)) => return Some((Err(e), None)),
+				None => {
+					return match buffer.is_empty() {
+						true => None,
+						false => Some((Ok(buffer.freeze()), None))
+					};
+				}
+			}
+		}
+	})
+	.boxed()
+}
+
+/// Reads up to `chunks` chunks ahead of the reader.  The read stops once the reader's gone.
+fn read_ahead(mut inner: BoxStream<'static, Result<Bytes, std::io::Error>>, chunks: usize) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+	let (tx, mut rx) = mpsc::channel(chunks);
+	rt::spawn(async move {
+		while let Some(chunk) = inner.next().await {
+			if (tx.send(chunk).await.is_err()) {
+				return;
+			}
+		}
+	});
+	futures::stream::poll_fn(move |cx| rx.poll_recv(cx)).boxed()
+}
+
+struct Measured {
+	inner: BoxStream<'static, Result<Bytes, std::io::Error>>,
+	start: Option<Instant>,
+	read: u64
+}
+
+fn measured(inner: BoxStream<'static, Result<Bytes, std::io::Error>>, length: u64, backend: &'static str) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+	let state = Measured { inner, start: None, read: 0 };
+	futures::stream::unfold(Some(state), move |state| async move {
+		let mut state = state?;
+		let start = *state.start.get_or_insert_with(Instant::now);
+		let asked = Instant::now();
+		let item = state.inner.next().await;
+		WAIT.with_label_values(&[backend]).inc_by(asked.elapsed().as_secs_f64());
+		match item {
+			Some(Ok(chunk)) => {
+				state.read += chunk.len() as u64;
+				Some((Ok(chunk), Some(state)))
+			},
+			Some(Err(e)) => Some((Err(e), Some(state))),
+			None => {
+				if let Some(rate) = throughput(length, state.read, start.elapsed()) {
+					THROUGHPUT.with_label_values(&[backend]).observe(rate);
+				}
+				None
+			}
+		}
+	})
+	.boxed()
+}
+
+/// Bytes per second, for objects big enough to say anything and read all the way through.
+fn throughput(length: u64, read: u64, elapsed: Duration) -> Option<f64> {
+	match length >= MEASURED_LENGTH && read == length && !elapsed.is_zero() {
+		true => Some(read as f64 / elapsed.as_secs_f64()),
+		false => None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn chunks(sizes: &[usize]) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+		futures::stream::iter(sizes.iter().map(|size| Ok(Bytes::from(vec![b'a'; *size]))).collect::<Vec<_>>()).boxed()
+	}
+
+	async fn sizes(stream: BoxStream<'static, Result<Bytes, std::io::Error>>) -> Vec<usize> {
+		stream.map(|chunk| chunk.unwrap().len()).collect().await
+	}
+
+	#[actix_web::test]
+	async fn small_chunks_are_put_together() {
+		assert_eq!(sizes(coalesce(chunks(&[3, 3, 3, 10, 1, 1]), 8)).await, [9, 10, 2]);
+		assert_eq!(sizes(coalesce(chunks(&[]), 8)).await, Vec::<usize>::new());
+	}
+
+	#[actix_web::test]
+	async fn read_ahead_passes_everything_on() {
+		assert_eq!(sizes(read_ahead(chunks(&[1, 2, 3, 4, 5]), 2)).await, [1, 2, 3, 4, 5]);
+	}
+
+	#[test]
+	fn only_whole_big_objects_are_measured() {
+		let second = Duration::from_secs(1);
+		assert_eq!(throughput(4 * MEASURED_LENGTH, 4 * MEASURED_LENGTH, 2 * second), Some(2.0 * MEASURED_LENGTH as f64));
+		assert_eq!(throughput(4 * MEASURED_LENGTH, MEASURED_LENGTH, second), None);
+		assert_eq!(throughput(1024, 1024, second), None);
+	}
+}
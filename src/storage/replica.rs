@@ -0,0 +1,226 @@
+//! Copies newly cached objects to a second storage location, such as a bucket in another region,
+//! so that losing the first doesn't mean pulling everything from upstream again all at once.
+
+use core::future::Future;
+use core::time::Duration;
+use std::path::Path;
+use std::time::Instant;
+
+use actix_web::rt;
+use bytes::BytesMut;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use once_cell::sync::Lazy;
+use prometheus::register_histogram;
+use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge;
+use prometheus::Histogram;
+use prometheus::IntCounterVec;
+use prometheus::IntGauge;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::error;
+use tracing::warn;
+
+use super::filesystem;
+use super::s3;
+use super::Error;
+use super::Repository;
+
+static QUEUE_LENGTH: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("replication_queue_length", "Number of objects waiting to be copied to the replica").unwrap());
+static LAG: Lazy<Histogram> = Lazy::new(|| register_histogram!("replication_lag_seconds", "Time from an object being cached to it being copied to the replica", vec![0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0]).unwrap());
+static REPLICATED: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("replicated_objects", "Number of objects copied to the replica", &["kind"]).unwrap());
+static RETRIES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("replication_retries", "Number of failed copies to the replica that were tried again", &["kind"]).unwrap());
+static FAILURES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("replication_failures", "Number of objects given up on after every attempt to copy them to the replica failed", &["kind"]).unwrap());
+static DROPPED: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("replication_dropped", "Number of objects not copied to the replica because the queue was full", &["kind"]).unwrap());
+
+/// The first retry waits this long, and each one after that twice as long as the last.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const fn default_queue_size() -> usize {
+	10_000
+}
+
+const fn default_concurrency() -> usize {
+	4
+}
+
+const fn default_max_attempts() -> u32 {
+	5
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum ReplicaStorage {
+	S3(s3::Config),
+	Filesystem(filesystem::Config)
+}
+
+impl ReplicaStorage {
+	pub fn repository(&self) -> Repository {
+		match self {
+			Self::S3(config) => Repository::S3(config.repository()),
+			Self::Filesystem(config) => Repository::Filesystem(config.repository())
+		}
+	}
+}
+
+/// Where to copy cached objects to, and how hard to try, as read from `--replica-config-file`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReplicaConfig {
+	#[serde(flatten)]
+	storage: ReplicaStorage,
+	/// How many objects can be waiting to be copied before newly cached ones are dropped
+	#[serde(default = "default_queue_size")]
+	queue_size: usize,
+	/// How many objects are copied at once
+	#[serde(default = "default_concurrency")]
+	concurrency: usize,
+	/// How many times copying an object is tried before it's given up on
+	#[serde(default = "default_max_attempts")]
+	max_attempts: u32
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+	#[error("Failed to read replica config file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Invalid replica config file: {0}")]
+	Yaml(#[from] serde_yaml::Error),
+	#[error("Invalid replica config file: S3 storage needs access_key and secret_key")]
+	MissingCredentials
+}
+
+impl ReplicaConfig {
+	pub async fn load(path: &Path) -> Result<Self, LoadError> {
+		let config: Self = serde_yaml::from_slice(&tokio::fs::read(path).await?)?;
+		match &config.storage {
+			ReplicaStorage::S3(s3) if !s3.has_credentials() => Err(LoadError::MissingCredentials),
+			_ => Ok(config)
+		}
+	}
+
+	/// Returns a handle for queueing objects to be copied from `source`, and the task that copies
+	/// them, which needs to be spawned.
+	pub fn start(&self, source: Repository) -> (Replicator, impl Future<Output = ()>) {
+		let replica = self.storage.repository();
+		let (tx, rx) = mpsc::channel(self.queue_size.max(1));
+		let replicator = Replicator { tx };
+		let worker = run(source, replica, rx, replicator.clone(), self.concurrency.max(1), self.max_attempts.max(1));
+		(replicator, worker)
+	}
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+	Blob,
+	/// Copied along with its metadata
+	Manifest
+}
+
+impl Kind {
+	fn as_str(self) -> &'static str {
+		match self {
+			Self::Blob => "blob",
+			Self::Manifest => "manifest"
+		}
+	}
+}
+
+#[derive(Debug)]
+struct Job {
+	object: String,
+	kind: Kind,
+	queued: Instant,
+	attempt: u32
+}
+
+/// Queues objects to be copied to the replica.  The queue is only in memory; whatever's still in it
+/// at shutdown isn't copied.
+#[derive(Clone, Debug)]
+pub struct Replicator {
+	tx: mpsc::Sender<Job>
+}
+
+impl Replicator {
+	/// Queues an object that's just been written to storage.  If the queue is full, the object is
+	/// dropped (and counted) rather than holding up the request that cached it.
+	pub fn enqueue(&self, object: &str, kind: Kind) {
+		self.send(Job { object: object.to_owned(), kind, queued: Instant::now(), attempt: 0 });
+	}
+
+	fn send(&self, job: Job) {
+		let kind = job.kind;
+		match self.tx.try_send(job) {
+			Ok(()) => QUEUE_LENGTH.inc(),
+			Err(mpsc::error::TrySendError::Full(job)) => {
+				DROPPED.with_label_values(&[kind.as_str()]).inc();
+				warn!(object = job.object.as_str(), "Replication queue is full; not copying object to replica");
+			},
+			Err(mpsc::error::TrySendError::Closed(_)) => ()
+		}
+	}
+}
+
+/// Copies an object from one storage backend to another, under the same name.
+pub(super) async fn copy(source: &Repository, dest: &Repository, object: &str, kind: Kind) -> Result<(), Error> {
+	match kind {
+		Kind::Blob => {
+			let stream = source.read(object, Duration::MAX).await?;
+			let len = stream.length().try_into().unwrap_or(i64::MAX);
+			dest.write(object, stream.into_inner(), len).await
+		},
+		Kind::Manifest => {
+			let (metadata, body) = source.read_manifest(object, Duration::MAX).await?;
+			let body = body.into_inner().try_collect::<BytesMut>().await?.freeze();
+			dest.write_manifest(object, body, &metadata).await
+		}
+	}
+}
+
+async fn run(source: Repository, replica: Repository, mut rx: mpsc::Receiver<Job>, replicator: Replicator, concurrency: usize, max_attempts: u32) {
+	let (source, replica, replicator) = (&source, &replica, &replicator);
+	futures::stream::poll_fn(move |cx| rx.poll_recv(cx))
+		.for_each_concurrent(concurrency, |job| async move {
+			QUEUE_LENGTH.dec();
+			let kind = job.kind.as_str();
+			match copy(source, replica, &job.object, job.kind).await {
+				Ok(()) => {
+					REPLICATED.with_label_values(&[kind]).inc();
+					LAG.observe(job.queued.elapsed().as_secs_f64());
+				},
+				// Deleted or replaced since it was queued; whatever replaced it was queued too
+				Err(e) if e.is_not_found() => (),
+				Err(error) if job.attempt + 1 < max_attempts => {
+					RETRIES.with_label_values(&[kind]).inc();
+					let delay = RETRY_DELAY * 2u32.saturating_pow(job.attempt);
+					warn!(object = job.object.as_str(), %error, attempt = job.attempt + 1, ?delay, "Failed to copy object to replica; will try again");
+					let replicator = replicator.clone();
+					rt::spawn(async move {
+						rt::time::sleep(delay).await;
+						replicator.send(Job { attempt: job.attempt + 1, ..job });
+					});
+				},
+				Err(error) => {
+					FAILURES.with_label_values(&[kind]).inc();
+					error!(object = job.object.as_str(), %error, "Failed to copy object to replica; giving up");
+				}
+			}
+		})
+		.await;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn config() {
+		let config: ReplicaConfig = serde_yaml::from_str("backend: filesystem\nroot: /mnt/replica\nconcurrency: 8").unwrap();
+		assert!(matches!(config.storage, ReplicaStorage::Filesystem(_)));
+		assert_eq!((config.queue_size, config.concurrency, config.max_attempts), (10_000, 8, 5));
+		let config: ReplicaConfig = serde_yaml::from_str("backend: s3\nbucket: cache-dr\naccess_key: a\nsecret_key: b").unwrap();
+		assert!(matches!(config.storage, ReplicaStorage::S3(_)));
+		assert!(serde_yaml::from_str::<ReplicaConfig>("backend: tape\nroot: /dev/st0").is_err());
+	}
+}
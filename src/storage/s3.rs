@@ -1,10 +1,15 @@
+use core::ops::Range;
 use core::pin::Pin;
 use core::time::Duration;
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::RwLock;
 use std::time::SystemTime;
 use std::vec::IntoIter;
 
 use actix_web::web::Bytes;
+use async_trait::async_trait;
 use clap::Parser;
 use compact_str::CompactString;
 use futures::future::BoxFuture;
@@ -19,52 +24,221 @@ use rusoto_core::request::HttpClient;
 use rusoto_core::ByteStream;
 use rusoto_core::Region;
 use rusoto_core::RusotoError;
-use rusoto_credential::StaticProvider;
+use rusoto_credential::AwsCredentials;
+use rusoto_credential::CredentialsError;
+use rusoto_credential::ProvideAwsCredentials;
+use rusoto_s3::CopyObjectRequest;
 use rusoto_s3::DeleteObjectError;
 use rusoto_s3::DeleteObjectRequest;
 use rusoto_s3::GetObjectError;
 use rusoto_s3::GetObjectOutput;
 use rusoto_s3::GetObjectRequest;
+use rusoto_s3::HeadObjectError;
+use rusoto_s3::HeadObjectOutput;
+use rusoto_s3::HeadObjectRequest;
 use rusoto_s3::ListObjectsV2Error;
 use rusoto_s3::ListObjectsV2Output;
 use rusoto_s3::ListObjectsV2Request;
 use rusoto_s3::PutObjectRequest;
 use rusoto_s3::S3Client;
 use rusoto_s3::S3;
+use serde::Deserialize;
+use serde_with::serde_as;
+use serde_with::DisplayFromStr;
 use time::format_description::well_known::Rfc2822;
 use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 use tracing::info;
+use tracing::warn;
 
+use super::pacing::Pacer;
+use super::ManifestMetadata;
 use super::ReadStream;
+use super::Stat;
 
-#[derive(Clone, Debug, Parser)]
+mod failover;
+use failover::Failover;
+use failover::Target;
+mod placement;
+use placement::KeyValue;
+use placement::Placement;
+mod stats;
+use stats::Stats;
+pub mod transport;
+use transport::TlsRoots;
+
+fn default_region() -> CompactString {
+	"us-east-1".into()
+}
+
+fn default_stat_cache() -> humantime::Duration {
+	Duration::ZERO.into()
+}
+
+const fn default_large_blob_size() -> u64 {
+	64 * 1024 * 1024
+}
+
+const fn default_failover_threshold() -> usize {
+	3
+}
+
+fn default_failover_retry_interval() -> humantime::Duration {
+	Duration::from_secs(60).into()
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Deserialize, Parser)]
 pub struct Config {
 	#[clap(env = "S3_HOST", long)]
 	host: Option<String>,
+	/// May be left out when credentials are fetched with `--s3-credentials-secret` instead
 	#[clap(env = "S3_ACCESS_KEY", long)]
-	access_key: CompactString,
-	#[clap(env = "S3_SECRET_KEY", long)]
-	secret_key: String,
+	#[serde(default)]
+	access_key: Option<CompactString>,
+	#[clap(env = "S3_SECRET_KEY", long, hide_env_values = true)]
+	#[serde(default)]
+	secret_key: Option<String>,
 	#[clap(env = "S3_REGION", long, default_value = "us-east-1")]
+	#[serde(default = "default_region")]
 	region: CompactString,
 	#[clap(env = "S3_BUCKET", long)]
-	bucket: CompactString
+	bucket: CompactString,
+	/// How long an object's size and age, once S3 has told us, are trusted without asking again,
+	/// so that checking whether a cached manifest or blob is fresh doesn't cost a request on every
+	/// pull; `0s` always asks.
+	#[clap(env = "S3_STAT_CACHE_TTL", long, default_value = "0s")]
+	#[serde(default = "default_stat_cache")]
+	#[serde_as(as = "DisplayFromStr")]
+	stat_cache_ttl: humantime::Duration,
+	/// How often to confirm what's remembered with `--stat-cache-ttl` by listing the bucket, where
+	/// that takes fewer requests than looking each object up again; `0s` never lists.
+	#[clap(env = "S3_STAT_REFRESH_INTERVAL", long, default_value = "0s")]
+	#[serde(default = "default_stat_cache")]
+	#[serde_as(as = "DisplayFromStr")]
+	stat_refresh_interval: humantime::Duration,
+	/// Storage class to write objects with, like `STANDARD_IA` or `GLACIER_IR`; without one, the
+	/// bucket's default is used.
+	#[clap(env = "S3_STORAGE_CLASS", long)]
+	#[serde(default)]
+	storage_class: Option<String>,
+	/// Storage class for manifests, which are read on every pull, in place of `--storage-class`.
+	#[clap(env = "S3_MANIFEST_STORAGE_CLASS", long)]
+	#[serde(default)]
+	manifest_storage_class: Option<String>,
+	/// Comma-separated storage classes for the manifests of particular namespaces, as
+	/// `namespace=CLASS`, in place of `--manifest-storage-class`.
+	#[clap(env = "S3_NAMESPACE_STORAGE_CLASS", long, value_delimiter = ',')]
+	#[serde(default)]
+	#[serde_as(as = "Vec<DisplayFromStr>")]
+	namespace_storage_class: Vec<KeyValue>,
+	/// Storage class for blobs of at least `--large-blob-size` bytes, in place of
+	/// `--storage-class`.
+	#[clap(env = "S3_LARGE_BLOB_STORAGE_CLASS", long)]
+	#[serde(default)]
+	large_blob_storage_class: Option<String>,
+	#[clap(env = "S3_LARGE_BLOB_SIZE", long, default_value_t = default_large_blob_size())]
+	#[serde(default = "default_large_blob_size")]
+	large_blob_size: u64,
+	/// Comma-separated tags to put on every object written, as `key=value`, for lifecycle rules or
+	/// cost allocation to match on.
+	#[clap(env = "S3_OBJECT_TAGS", long, value_delimiter = ',')]
+	#[serde(default)]
+	#[serde_as(as = "Vec<DisplayFromStr>")]
+	object_tags: Vec<KeyValue>,
+	/// Bucket to fail over to when the primary can't be reached, say a replica in another region;
+	/// reached with the same credentials.
+	#[clap(env = "S3_SECONDARY_BUCKET", long)]
+	#[serde(default)]
+	secondary_bucket: Option<CompactString>,
+	/// Endpoint of the secondary bucket, if it's not on AWS.
+	#[clap(env = "S3_SECONDARY_HOST", long, requires = "secondary_bucket")]
+	#[serde(default)]
+	secondary_host: Option<String>,
+	/// Region of the secondary bucket; the primary's if not given.
+	#[clap(env = "S3_SECONDARY_REGION", long, requires = "secondary_bucket")]
+	#[serde(default)]
+	secondary_region: Option<CompactString>,
+	/// How many operations in a row the primary has to fail, for want of an answer, to fail over
+	/// to the secondary.
+	#[clap(env = "S3_FAILOVER_THRESHOLD", long, default_value_t = default_failover_threshold())]
+	#[serde(default = "default_failover_threshold")]
+	failover_threshold: usize,
+	/// While failed over, how often to try the primary again.
+	#[clap(env = "S3_FAILOVER_RETRY_INTERVAL", long, default_value = "60s")]
+	#[serde(default = "default_failover_retry_interval")]
+	#[serde_as(as = "DisplayFromStr")]
+	failover_retry_interval: humantime::Duration,
+	/// Which root certificates S3's certificate is checked against:  the system's, or those
+	/// compiled in, for static binaries on images with no CA bundle.
+	#[clap(env = "S3_TLS_ROOTS", long, value_enum, default_value_t = TlsRoots::System)]
+	#[serde(default)]
+	tls_roots: TlsRoots
 }
 
 impl Config {
 	pub fn repository(&self) -> Repository {
-		let region = match self.host.clone() {
-			Some(s) => Region::Custom { name: self.region.to_string(), endpoint: s },
-			None => Region::from_str(&self.region).unwrap()
+		let credentials = SharedCredentials::default();
+		if let (Some(access_key), Some(secret_key)) = (self.access_key.as_ref(), self.secret_key.as_ref()) {
+			credentials.set(access_key.to_string(), secret_key.clone(), None);
+		}
+		let target = |host: Option<&String>, region: &str, bucket: &CompactString| {
+			let region = match host.cloned() {
+				Some(s) => Region::Custom { name: region.to_owned(), endpoint: s },
+				None => Region::from_str(region).unwrap()
+			};
+			let client = match self.tls_roots {
+				TlsRoots::System => S3Client::new_with(HttpClient::new().unwrap(), credentials.clone(), region),
+				TlsRoots::Bundled => S3Client::new_with(transport::Reqwest::new().unwrap(), credentials.clone(), region)
+			};
+			Target { client, bucket: bucket.clone() }
 		};
-		let creds = StaticProvider::new(self.access_key.to_string(), self.secret_key.clone(), None, None);
-		let http = HttpClient::new().unwrap();
+		let primary = target(self.host.as_ref(), &self.region, &self.bucket);
+		let secondary = self.secondary_bucket.as_ref().map(|bucket| target(self.secondary_host.as_ref(), self.secondary_region.as_deref().unwrap_or(&self.region), bucket));
 		Repository {
-			inner: S3Client::new_with(http, creds, region),
-			bucket: self.bucket.clone()
+			targets: Arc::new(Failover::new(primary, secondary, self.failover_threshold, *self.failover_retry_interval)),
+			credentials,
+			stats: Arc::new(Stats::new(*self.stat_cache_ttl)),
+			stat_refresh_interval: *self.stat_refresh_interval,
+			placement: Arc::new(Placement::new(
+				self.storage_class.clone(),
+				self.manifest_storage_class.clone(),
+				self.large_blob_storage_class.clone(),
+				self.large_blob_size,
+				&self.namespace_storage_class,
+				&self.object_tags
+			))
 		}
 	}
+
+	pub fn tls_roots(&self) -> TlsRoots {
+		self.tls_roots
+	}
+
+	/// Whether the access key and secret key were given; if not, they have to be fetched from a
+	/// secret store before storage can be used.
+	pub fn has_credentials(&self) -> bool {
+		self.access_key.is_some() && self.secret_key.is_some()
+	}
+}
+
+/// S3 credentials that can be swapped out while the client is in use, as they are when they're
+/// fetched from a secret store and renewed.
+#[derive(Clone, Default)]
+pub struct SharedCredentials(Arc<RwLock<Option<AwsCredentials>>>);
+
+impl SharedCredentials {
+	pub fn set(&self, access_key: String, secret_key: String, session_token: Option<String>) {
+		*self.0.write().unwrap() = Some(AwsCredentials::new(access_key, secret_key, session_token, None));
+	}
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for SharedCredentials {
+	async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+		let credentials = self.0.read().unwrap().clone();
+		credentials.ok_or_else(|| CredentialsError::new("No S3 credentials yet; they haven't been fetched from the secret store"))
+	}
 }
 
 struct ListObjectsStream {
@@ -126,47 +300,243 @@ impl Stream for ListObjectsStream {
 	}
 }
 
+/// Stored manifests carry their digest as `x-amz-meta-digest`.
+const DIGEST_METADATA_KEY: &str = "digest";
+/// And how long upstream said they stay fresh, if it said, as `x-amz-meta-max-age`.
+const MAX_AGE_METADATA_KEY: &str = "max-age";
+
+fn manifest_metadata(content_type: Option<&String>, metadata: Option<&HashMap<String, String>>) -> Result<ManifestMetadata, super::Error> {
+	Ok(ManifestMetadata {
+		media_type: content_type.cloned().ok_or(super::Error::InvalidManifestMetadata)?,
+		digest: metadata.and_then(|m| m.get(DIGEST_METADATA_KEY).cloned()),
+		max_age: metadata.and_then(|m| m.get(MAX_AGE_METADATA_KEY)).and_then(|v| v.parse().ok())
+	})
+}
+
+fn parse_last_modified(last_modified: Option<&str>) -> Result<OffsetDateTime, super::Error> {
+	Ok(last_modified.map(|s| OffsetDateTime::parse(s, &Rfc2822)).transpose()?.unwrap_or(OffsetDateTime::UNIX_EPOCH))
+}
+
+/// URL-encodes a key for a `CopySource`, which unlike the key of the request itself, rusoto passes
+/// along as-is.
+fn encode_key(key: &str) -> String {
+	let mut encoded = String::with_capacity(key.len());
+	for b in key.bytes() {
+		match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(b as char),
+			_ => encoded.push_str(&format!("%{b:02X}"))
+		};
+	}
+	encoded
+}
+
+fn read_stream(obj: GetObjectOutput, invalidation: Duration) -> Result<ReadStream, super::Error> {
+	let time = parse_last_modified(obj.last_modified.as_deref())?;
+	let age = Duration::try_from(SystemTime::now() - time).unwrap_or_default();
+	if (age > invalidation) {
+		return Err(super::Error::ObjectTooOld(age.into()));
+	}
+
+	Ok(ReadStream::new(obj.content_length.unwrap().try_into().unwrap_or_default(), Box::pin(obj.body.unwrap())).with_modified(time.into()))
+}
+
 #[derive(Clone)]
 pub struct Repository {
-	inner: S3Client,
-	bucket: CompactString
+	targets: Arc<Failover>,
+	credentials: SharedCredentials,
+	stats: Arc<Stats>,
+	stat_refresh_interval: Duration,
+	placement: Arc<Placement>
 }
 
 impl Repository {
+	pub fn credentials(&self) -> &SharedCredentials {
+		&self.credentials
+	}
+
+	/// Lists `prefix`; the pages after the first come from the same bucket as it did.
 	async fn list_objects(&self, prefix: &str) -> Result<ListObjectsStream, RusotoError<ListObjectsV2Error>> {
-		let req = ListObjectsV2Request {
-			bucket: self.bucket.to_string(),
-			prefix: Some(prefix.into()),
-			..Default::default()
-		};
-		let result = self.inner.list_objects_v2(req).await?;
-		Ok(ListObjectsStream {
-			client: self.inner.clone(),
-			bucket: self.bucket.clone(),
-			current_continuation_token: result.continuation_token,
-			current_contents: result.contents.unwrap_or_default().into_iter(),
-			current_future: None
-		})
+		self.targets
+			.call(|target| {
+				let req = ListObjectsV2Request {
+					bucket: target.bucket.to_string(),
+					prefix: Some(prefix.into()),
+					..Default::default()
+				};
+				async move {
+					let result = target.client.list_objects_v2(req).await?;
+					Ok::<_, RusotoError<ListObjectsV2Error>>(ListObjectsStream {
+						client: target.client,
+						bucket: target.bucket,
+						current_continuation_token: result.continuation_token,
+						current_contents: result.contents.unwrap_or_default().into_iter(),
+						current_future: None
+					})
+				}
+			})
+			.await
 	}
 
 	async fn get_object(&self, object: &str) -> Result<GetObjectOutput, RusotoError<GetObjectError>> {
-		let req = GetObjectRequest {
-			bucket: self.bucket.to_string(),
-			key: object.into(),
-			..Default::default()
-		};
-		self.inner.get_object(req).await
+		self.get_object_range(object, None).await
+	}
+
+	async fn get_object_range(&self, object: &str, range: Option<String>) -> Result<GetObjectOutput, RusotoError<GetObjectError>> {
+		self.targets
+			.call(|target| {
+				let req = GetObjectRequest {
+					bucket: target.bucket.to_string(),
+					key: object.into(),
+					range: range.clone(),
+					..Default::default()
+				};
+				async move { target.client.get_object(req).await }
+			})
+			.await
+	}
+
+	async fn head_object(&self, object: &str) -> Result<HeadObjectOutput, RusotoError<HeadObjectError>> {
+		self.targets
+			.call(|target| {
+				let req = HeadObjectRequest {
+					bucket: target.bucket.to_string(),
+					key: object.into(),
+					..Default::default()
+				};
+				async move { target.client.head_object(req).await }
+			})
+			.await
+	}
+
+	fn stat_of(obj: &HeadObjectOutput) -> Result<Stat, super::Error> {
+		let modified = parse_last_modified(obj.last_modified.as_deref())?;
+		Ok(Stat::new(obj.content_length.unwrap_or_default().try_into().unwrap_or_default(), modified.into()))
+	}
+
+	pub async fn stat(&self, object: &str) -> Result<Stat, super::Error> {
+		if let Some(stat) = self.stats.get(object) {
+			return Ok(stat);
+		}
+		let stat = Self::stat_of(&self.head_object(object).await?)?;
+		self.stats.insert(object, stat, None);
+		Ok(stat)
+	}
+
+	pub async fn stat_manifest(&self, object: &str) -> Result<(ManifestMetadata, Stat), super::Error> {
+		if let Some(cached) = self.stats.get_manifest(object) {
+			return Ok(cached);
+		}
+		let obj = self.head_object(object).await?;
+		let metadata = manifest_metadata(obj.content_type.as_ref(), obj.metadata.as_ref())?;
+		let stat = Self::stat_of(&obj)?;
+		self.stats.insert(object, stat, Some(metadata.clone()));
+		Ok((metadata, stat))
+	}
+
+	/// Fails without asking S3 if what's remembered of the object says it's too old already.
+	fn check_remembered_age(&self, object: &str, invalidation: Duration) -> Result<(), super::Error> {
+		match self.stats.get(object).map(|s| s.age()) {
+			Some(age) if age > invalidation => Err(super::Error::ObjectTooOld(age.into())),
+			_ => Ok(())
+		}
 	}
 
 	pub async fn read(&self, object: &str, invalidation: Duration) -> Result<ReadStream, super::Error> {
-		let obj = self.get_object(object).await?;
-		let time = obj.last_modified.map(|s| OffsetDateTime::parse(&s, &Rfc2822)).transpose()?.unwrap_or(OffsetDateTime::UNIX_EPOCH);
-		let age = Duration::try_from(SystemTime::now() - time).unwrap_or_default();
-		if (age > invalidation) {
-			return Err(super::Error::ObjectTooOld(age.into()));
+		self.check_remembered_age(object, invalidation)?;
+		let obj = self.get_object(object).await.map_err(|e| self.forget(object, e))?;
+		self.remember(object, &obj, None);
+		read_stream(obj, invalidation)
+	}
+
+	/// Reads just `range` of an object, which has to be within it; S3 only sends those bytes.
+	pub async fn read_range(&self, object: &str, invalidation: Duration, range: Range<u64>) -> Result<ReadStream, super::Error> {
+		self.check_remembered_age(object, invalidation)?;
+		// Not remembered; the length S3 gives is the range's
+		let obj = self.get_object_range(object, Some(format!("bytes={}-{}", range.start, range.end - 1))).await.map_err(|e| self.forget(object, e))?;
+		read_stream(obj, invalidation)
+	}
+
+	pub async fn read_manifest(&self, object: &str, invalidation: Duration) -> Result<(ManifestMetadata, ReadStream), super::Error> {
+		self.check_remembered_age(object, invalidation)?;
+		let obj = self.get_object(object).await.map_err(|e| self.forget(object, e))?;
+		let metadata = manifest_metadata(obj.content_type.as_ref(), obj.metadata.as_ref())?;
+		self.remember(object, &obj, Some(metadata.clone()));
+		Ok((metadata, read_stream(obj, invalidation)?))
+	}
+
+	fn forget<E>(&self, object: &str, error: E) -> E {
+		self.stats.remove(object);
+		error
+	}
+
+	/// Remembers an object's size and age from reading it, for the next freshness check.
+	fn remember(&self, object: &str, obj: &GetObjectOutput, metadata: Option<ManifestMetadata>) {
+		if let (Ok(modified), Some(length)) = (parse_last_modified(obj.last_modified.as_deref()), obj.content_length) {
+			self.stats.insert(object, Stat::new(length.try_into().unwrap_or_default(), modified.into()), metadata);
+		}
+	}
+
+	/// Confirms what's remembered of objects' sizes and ages by listing the prefixes they're under,
+	/// every `--stat-refresh-interval`, for as long as it's running.
+	pub async fn refresh_stats(self) {
+		if (!self.stats.is_enabled() || self.stat_refresh_interval.is_zero()) {
+			return;
+		}
+		let mut interval = tokio::time::interval(self.stat_refresh_interval);
+		loop {
+			interval.tick().await;
+			for prefix in ["manifests/", "blobs/"] {
+				if (!self.stats.worth_listing(prefix)) {
+					continue;
+				}
+				match self.list_stats(prefix).await {
+					Ok(listing) => self.stats.refresh(prefix, listing),
+					Err(error) => warn!(prefix, %error, "Failed to list objects to refresh their cached sizes and ages")
+				};
+			}
 		}
+	}
 
-		Ok(ReadStream::new(obj.content_length.unwrap().try_into().unwrap_or_default(), Box::pin(obj.body.unwrap())))
+	async fn list_stats(&self, prefix: &str) -> Result<Vec<(String, Stat)>, super::Error> {
+		let mut listing = Vec::new();
+		let mut stream = self.list_objects(prefix).await?;
+		while let Some(obj) = stream.next().await {
+			let obj = obj?;
+			let Some(key) = obj.key else {
+				continue;
+			};
+			let modified = obj.last_modified.and_then(|s| OffsetDateTime::parse(&s, &Rfc3339).ok()).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+			listing.push((key, Stat::new(obj.size.and_then(|s| u64::try_from(s).ok()).unwrap_or_default(), modified.into())));
+		}
+		Ok(listing)
+	}
+
+	pub async fn write_manifest(&self, object: &str, body: Bytes, metadata: &ManifestMetadata) -> Result<(), super::Error> {
+		self.stats.remove(object);
+		let mut user_metadata = HashMap::new();
+		if let Some(digest) = metadata.digest.as_ref() {
+			user_metadata.insert(DIGEST_METADATA_KEY.to_owned(), digest.clone());
+		}
+		if let Some(max_age) = metadata.max_age {
+			user_metadata.insert(MAX_AGE_METADATA_KEY.to_owned(), max_age.to_string());
+		}
+		self.targets
+			.call(|target| {
+				let req = PutObjectRequest {
+					bucket: target.bucket.to_string(),
+					key: object.into(),
+					content_length: Some(body.len().try_into().unwrap_or(i64::MAX)),
+					content_type: Some(metadata.media_type.clone()),
+					metadata: (!user_metadata.is_empty()).then(|| user_metadata.clone()),
+					storage_class: self.placement.storage_class(object, Some(body.len() as u64)),
+					tagging: self.placement.tagging(),
+					body: Some(ByteStream::from(body.to_vec())),
+					..Default::default()
+				};
+				async move { target.client.put_object(req).await }
+			})
+			.await?;
+		Ok(())
 	}
 
 	pub async fn write<S, E>(&self, object: &str, reader: S, length: i64) -> Result<(), super::Error>
@@ -175,15 +545,22 @@ impl Repository {
 		E: std::error::Error + Send + Sync + 'static,
 		super::Error: From<E>
 	{
-		let req = PutObjectRequest {
-			bucket: self.bucket.to_string(),
-			key: object.into(),
-			content_length: Some(length),
-			body: Some(ByteStream::new(reader.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)))),
-			..Default::default()
-		};
-
-		if let Err(e) = self.inner.put_object(req).await {
+		let body = ByteStream::new(reader.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)));
+		self.stats.remove(object);
+		// The blob can only be streamed in once, so it isn't tried on the secondary too
+		let put = self.targets.call_once(|target| {
+			let req = PutObjectRequest {
+				bucket: target.bucket.to_string(),
+				key: object.into(),
+				content_length: Some(length),
+				storage_class: self.placement.storage_class(object, u64::try_from(length).ok()),
+				tagging: self.placement.tagging(),
+				body: Some(body),
+				..Default::default()
+			};
+			async move { target.client.put_object(req).await }
+		});
+		if let Err(e) = put.await {
 			self.delete(object).await?;
 			return Err(e.into());
 		}
@@ -192,25 +569,76 @@ impl Repository {
 	}
 
 	pub async fn delete(&self, object: &str) -> Result<(), RusotoError<DeleteObjectError>> {
-		let req = DeleteObjectRequest {
-			bucket: self.bucket.to_string(),
-			key: object.to_owned(),
-			..Default::default()
+		self.stats.remove(object);
+		self.targets
+			.call(|target| {
+				let req = DeleteObjectRequest {
+					bucket: target.bucket.to_string(),
+					key: object.to_owned(),
+					..Default::default()
+				};
+				async move { target.client.delete_object(req).await }
+			})
+			.await?;
+		Ok(())
+	}
+
+	/// Copies an object, metadata and tags and all, then deletes the original.
+	pub async fn rename(&self, from: &str, to: &str) -> Result<(), super::Error> {
+		// A copy goes to the default storage class unless it's told otherwise
+		let size = match self.placement.depends_on_size(to) {
+			true => Some(self.stat(from).await?.length()),
+			false => None
 		};
-		self.inner.delete_object(req).await?;
+		self.stats.remove(to);
+		self.targets
+			.call(|target| {
+				let req = CopyObjectRequest {
+					bucket: target.bucket.to_string(),
+					key: to.to_owned(),
+					copy_source: format!("{}/{}", target.bucket, encode_key(from)),
+					storage_class: self.placement.storage_class(to, size),
+					..Default::default()
+				};
+				async move { target.client.copy_object(req).await }
+			})
+			.await?;
+		self.delete(from).await?;
 		Ok(())
 	}
 
-	pub async fn delete_old_objects(&self, older_than: SystemTime, prefix: &str) -> Result<usize, super::Error> {
+	pub async fn list_keys(&self, prefix: &str) -> Result<Vec<String>, super::Error> {
+		let mut keys = Vec::new();
+		let mut stream = self.list_objects(prefix).await?;
+		while let Some(obj) = stream.next().await {
+			if let Some(key) = obj?.key {
+				keys.push(key);
+			}
+		}
+		Ok(keys)
+	}
+
+	/// The combined size of every object under `prefix`.
+	pub async fn total_size(&self, prefix: &str) -> Result<u64, super::Error> {
+		let mut total = 0;
+		let mut stream = self.list_objects(prefix).await?;
+		while let Some(obj) = stream.next().await {
+			total += obj?.size.and_then(|s| u64::try_from(s).ok()).unwrap_or_default();
+		}
+		Ok(total)
+	}
+
+	pub async fn delete_old_objects(&self, older_than: SystemTime, prefix: &str, keep: impl Fn(&str) -> bool, pacer: &mut Pacer) -> Result<usize, super::Error> {
 		let mut count = 0;
 		let mut stream = self.list_objects(prefix).await?;
 		while let Some(obj) = stream.next().await {
 			let obj = obj?;
-			let Some(key) = obj.key else {
+			let Some(key) = obj.key.filter(|k| !keep(k)) else {
 				continue;
 			};
 			let modified = obj.last_modified.and_then(|s| OffsetDateTime::parse(&s, &Rfc3339).ok()).unwrap_or(OffsetDateTime::UNIX_EPOCH);
 			if (modified < older_than) {
+				pacer.wait().await;
 				match self.delete(key.as_ref()).await {
 					Ok(_) => info!(object = key, "Aged out"),
 					Err(_) => continue
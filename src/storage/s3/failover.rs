@@ -0,0 +1,199 @@
+//! Failing over to a secondary bucket, say a replica in another region, when the primary can't be
+//! reached.  An operation the primary fails for want of an answer, as opposed to with one like a
+//! missing object, is tried on the secondary straight away; once enough fail in a row, everything
+//! goes to the secondary, with one operation every retry interval tried on the primary first, and
+//! the first of those to succeed switching back.  Keeping the secondary's contents in step with the
+//! primary's, by bucket replication or `--replica-config-file`, is up to whoever set it up; whatever
+//! it's missing is just pulled from upstream again.
+
+use core::time::Duration;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter;
+use prometheus::register_int_gauge;
+use prometheus::IntCounter;
+use prometheus::IntGauge;
+use rusoto_core::RusotoError;
+use rusoto_s3::S3Client;
+use tracing::info;
+use tracing::warn;
+
+static ACTIVE: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("s3_failover_active", "Whether S3 operations are going to the secondary bucket (1) or the primary (0)").unwrap());
+static FAILOVERS: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("s3_failovers", "Number of times S3 operations were switched over to the secondary bucket").unwrap());
+static SECONDARY_OPERATIONS: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("s3_secondary_operations", "Number of S3 operations sent to the secondary bucket").unwrap());
+
+/// A bucket, and the client for wherever it is.
+#[derive(Clone)]
+pub struct Target {
+	pub client: S3Client,
+	pub bucket: CompactString
+}
+
+/// Which bucket an operation went to, and why.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Role {
+	Primary,
+	/// The primary, while failed over, to see whether it's back
+	Probe,
+	Secondary
+}
+
+/// Whether an error says the bucket couldn't be reached, rather than answering.
+fn is_outage<E>(error: &RusotoError<E>) -> bool {
+	match error {
+		RusotoError::HttpDispatch(_) => true,
+		RusotoError::Unknown(response) => response.status.is_server_error(),
+		_ => false
+	}
+}
+
+pub struct Failover {
+	primary: Target,
+	secondary: Option<Target>,
+	/// How many outages in a row fail over
+	threshold: usize,
+	retry_interval: Duration,
+	state: Mutex<State>
+}
+
+#[derive(Default)]
+struct State {
+	failures: usize,
+	/// When the primary was last tried, while failed over
+	failed_over: Option<Instant>
+}
+
+impl Failover {
+	pub fn new(primary: Target, secondary: Option<Target>, threshold: usize, retry_interval: Duration) -> Self {
+		Self { primary, secondary, threshold: threshold.max(1), retry_interval, state: Mutex::default() }
+	}
+
+	/// Where the operation that's about to start goes to first.
+	fn pick(&self, now: Instant) -> Role {
+		let mut state = self.state.lock().unwrap();
+		match state.failed_over {
+			Some(tried) if now.duration_since(tried) >= self.retry_interval => {
+				state.failed_over = Some(now);
+				Role::Probe
+			},
+			Some(_) => Role::Secondary,
+			None => Role::Primary
+		}
+	}
+
+	/// Counts how an operation on the primary went; returns whether to try it on the secondary.
+	fn record(&self, role: Role, outage: bool, now: Instant) -> bool {
+		let Some(secondary) = self.secondary.as_ref() else {
+			return false;
+		};
+		let mut state = self.state.lock().unwrap();
+		match (role, outage) {
+			(Role::Primary, false) => state.failures = 0,
+			(Role::Primary, true) => {
+				state.failures += 1;
+				if (state.failures >= self.threshold && state.failed_over.is_none()) {
+					state.failed_over = Some(now);
+					ACTIVE.set(1);
+					FAILOVERS.inc();
+					warn!(failures = state.failures, secondary = secondary.bucket.as_str(), "Primary S3 bucket is failing; failing over to the secondary");
+				}
+			},
+			(Role::Probe, false) => {
+				*state = State::default();
+				ACTIVE.set(0);
+				info!(primary = self.primary.bucket.as_str(), "Primary S3 bucket has recovered; failing back to it");
+			},
+			(Role::Probe, true) | (Role::Secondary, _) => ()
+		};
+		outage && role != Role::Secondary
+	}
+
+	/// Runs `f` against the bucket whose turn it is, and against the secondary as well if the
+	/// primary can't be reached.
+	pub async fn call<T, E, F, Fut>(&self, f: F) -> Result<T, RusotoError<E>>
+	where
+		F: Fn(Target) -> Fut,
+		Fut: core::future::Future<Output = Result<T, RusotoError<E>>>
+	{
+		let role = self.pick(Instant::now());
+		let result = f(self.target(role).clone()).await;
+		if (!self.record(role, result.as_ref().is_err_and(is_outage), Instant::now())) {
+			return result;
+		}
+		match self.secondary.as_ref() {
+			Some(secondary) => {
+				SECONDARY_OPERATIONS.inc();
+				f(secondary.clone()).await
+			},
+			None => result
+		}
+	}
+
+	/// Runs `f` against the bucket whose turn it is, for operations that can't be tried twice, like
+	/// streaming a blob in.
+	pub async fn call_once<T, E, F, Fut>(&self, f: F) -> Result<T, RusotoError<E>>
+	where
+		F: FnOnce(Target) -> Fut,
+		Fut: core::future::Future<Output = Result<T, RusotoError<E>>>
+	{
+		let role = self.pick(Instant::now());
+		let result = f(self.target(role).clone()).await;
+		self.record(role, result.as_ref().is_err_and(is_outage), Instant::now());
+		result
+	}
+
+	fn target(&self, role: Role) -> &Target {
+		match (role, self.secondary.as_ref()) {
+			(Role::Secondary, Some(secondary)) => {
+				SECONDARY_OPERATIONS.inc();
+				secondary
+			},
+			_ => &self.primary
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn target(bucket: &str) -> Target {
+		let client = S3Client::new_with(rusoto_core::request::HttpClient::new().unwrap(), rusoto_credential::StaticProvider::new_minimal(String::new(), String::new()), rusoto_core::Region::UsEast1);
+		Target { client, bucket: bucket.into() }
+	}
+
+	#[test]
+	fn fails_over_and_back() {
+		let failover = Failover::new(target("primary"), Some(target("secondary")), 2, Duration::from_secs(60));
+		let start = Instant::now();
+		assert_eq!(failover.pick(start), Role::Primary);
+		// Each outage is tried on the secondary, but it takes two in a row to fail over
+		assert!(failover.record(Role::Primary, true, start));
+		assert!(!failover.record(Role::Primary, false, start));
+		assert!(failover.record(Role::Primary, true, start));
+		assert_eq!(failover.pick(start), Role::Primary);
+		assert!(failover.record(Role::Primary, true, start));
+		assert_eq!(failover.pick(start), Role::Secondary);
+
+		// Then the primary is tried once a minute, by one operation at a time
+		let later = start + Duration::from_secs(60);
+		assert_eq!(failover.pick(later), Role::Probe);
+		assert_eq!(failover.pick(later), Role::Secondary);
+		assert!(failover.record(Role::Probe, true, later));
+		let later = later + Duration::from_secs(60);
+		assert_eq!(failover.pick(later), Role::Probe);
+		assert!(!failover.record(Role::Probe, false, later));
+		assert_eq!(failover.pick(later), Role::Primary);
+	}
+
+	#[test]
+	fn without_secondary() {
+		let failover = Failover::new(target("primary"), None, 1, Duration::from_secs(60));
+		let now = Instant::now();
+		assert!(!failover.record(Role::Primary, true, now));
+		assert_eq!(failover.pick(now), Role::Primary);
+	}
+}
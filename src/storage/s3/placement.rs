@@ -0,0 +1,114 @@
+use core::fmt;
+use core::str::FromStr;
+use std::collections::HashMap;
+
+use super::encode_key;
+
+/// A `key=value` pair, as storage classes by namespace and object tags are given.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyValue {
+	key: String,
+	value: String
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid '{0}'; expected key=value")]
+pub struct InvalidKeyValue(String);
+
+impl FromStr for KeyValue {
+	type Err = InvalidKeyValue;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.split_once('=') {
+			Some((key, value)) if !key.is_empty() => Ok(Self { key: key.to_owned(), value: value.to_owned() }),
+			_ => Err(InvalidKeyValue(s.to_owned()))
+		}
+	}
+}
+
+impl fmt::Display for KeyValue {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}={}", self.key, self.value)
+	}
+}
+
+/// Which storage class each object is written with, and what it's tagged with, so that what's
+/// rarely read can sit somewhere cheaper without lifecycle rules that move objects behind the
+/// cache's back.  `None` leaves an object to the bucket's default.
+#[derive(Clone, Debug, Default)]
+pub struct Placement {
+	default: Option<String>,
+	manifests: Option<String>,
+	large_blobs: Option<String>,
+	large_blob_size: u64,
+	/// Classes for manifests, by namespace
+	namespaces: HashMap<String, String>,
+	/// URL-encoded, as `PutObject` takes it
+	tagging: Option<String>
+}
+
+impl Placement {
+	pub fn new(default: Option<String>, manifests: Option<String>, large_blobs: Option<String>, large_blob_size: u64, namespaces: &[KeyValue], tags: &[KeyValue]) -> Self {
+		let tagging = tags.iter().map(|t| format!("{}={}", encode_key(&t.key).replace('/', "%2F"), encode_key(&t.value).replace('/', "%2F"))).collect::<Vec<_>>().join("&");
+		Self {
+			default,
+			manifests,
+			large_blobs,
+			large_blob_size,
+			namespaces: namespaces.iter().map(|n| (n.key.clone(), n.value.clone())).collect(),
+			tagging: (!tagging.is_empty()).then_some(tagging)
+		}
+	}
+
+	fn is_blob(object: &str) -> bool {
+		object.starts_with("blobs/") || object.starts_with("foreign/")
+	}
+
+	/// The storage class for an object, given its size if that's known.  Trashed objects go where
+	/// they'd go if they were still cached, so that restoring them doesn't move them.
+	pub fn storage_class(&self, object: &str, size: Option<u64>) -> Option<String> {
+		let object = object.strip_prefix("trash/").unwrap_or(object);
+		if let Some(path) = object.strip_prefix("manifests/") {
+			let namespace = path.split('/').next().unwrap_or_default();
+			return self.namespaces.get(namespace).or(self.manifests.as_ref()).or(self.default.as_ref()).cloned();
+		}
+		match (Self::is_blob(object), size) {
+			(true, Some(size)) if size >= self.large_blob_size => self.large_blobs.as_ref().or(self.default.as_ref()).cloned(),
+			_ => self.default.clone()
+		}
+	}
+
+	/// Whether the storage class for an object depends on its size.
+	pub fn depends_on_size(&self, object: &str) -> bool {
+		self.large_blobs.is_some() && Self::is_blob(object.strip_prefix("trash/").unwrap_or(object))
+	}
+
+	pub fn tagging(&self) -> Option<String> {
+		self.tagging.clone()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn placement() {
+		let namespaces = ["ghcr.io=STANDARD".parse().unwrap()];
+		let tags = ["team=platform".parse().unwrap(), "cost center=a/b".parse().unwrap()];
+		let placement = Placement::new(Some("STANDARD_IA".into()), Some("INTELLIGENT_TIERING".into()), Some("GLACIER_IR".into()), 1024, &namespaces, &tags);
+		assert_eq!(placement.storage_class("manifests/docker.io/library/alpine/latest", None).as_deref(), Some("INTELLIGENT_TIERING"));
+		assert_eq!(placement.storage_class("manifests/ghcr.io/org/app/1.0", None).as_deref(), Some("STANDARD"));
+		assert_eq!(placement.storage_class("blobs/sha256/ab/cdef", Some(4096)).as_deref(), Some("GLACIER_IR"));
+		assert_eq!(placement.storage_class("trash/blobs/sha256/ab/cdef", Some(4096)).as_deref(), Some("GLACIER_IR"));
+		assert_eq!(placement.storage_class("blobs/sha256/ab/cdef", Some(10)).as_deref(), Some("STANDARD_IA"));
+		assert_eq!(placement.storage_class("pins.json", Some(4096)).as_deref(), Some("STANDARD_IA"));
+		assert!(placement.depends_on_size("blobs/sha256/ab/cdef"));
+		assert!(!placement.depends_on_size("manifests/docker.io/library/alpine/latest"));
+		assert_eq!(placement.tagging().as_deref(), Some("team=platform&cost%20center=a%2Fb"));
+
+		assert!(Placement::default().storage_class("blobs/sha256/ab/cdef", Some(4096)).is_none());
+		assert!(Placement::default().tagging().is_none());
+		assert!("novalue".parse::<KeyValue>().is_err());
+	}
+}
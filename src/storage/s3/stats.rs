@@ -0,0 +1,158 @@
+use core::time::Duration;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+
+use crate::storage::ManifestMetadata;
+use crate::storage::Stat;
+
+static LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("s3_stat_cache_lookups", "Number of times an object's size and age were looked for in memory before asking S3, by whether they were there", &["result"]).unwrap());
+
+/// Objects' sizes and modification times (and, for manifests, their metadata), as S3 last told us,
+/// so that checking whether a cached object is still fresh doesn't cost a `HeadObject` or
+/// `GetObject` every time it's pulled.  Entries are trusted for the TTL after S3 last confirmed
+/// them, one at a time or, more cheaply for a lot of them, by a listing.
+///
+/// Other replicas sharing the bucket, and the cleanup job, can delete or rewrite objects out from
+/// under us; an object that turns out to be gone when it's read is just pulled from upstream
+/// again, and one that looks older than it is gets refetched a little early.
+pub struct Stats {
+	ttl: Duration,
+	entries: Mutex<HashMap<String, Entry>>,
+	/// How many objects each prefix held when it was last listed
+	listed: Mutex<HashMap<&'static str, usize>>
+}
+
+#[derive(Clone)]
+struct Entry {
+	stat: Stat,
+	metadata: Option<ManifestMetadata>,
+	confirmed: Instant
+}
+
+impl Stats {
+	const PRUNE_THRESHOLD: usize = 65536;
+
+	/// A TTL of zero disables the cache entirely.
+	pub fn new(ttl: Duration) -> Self {
+		Self { ttl, entries: Mutex::new(HashMap::new()), listed: Mutex::new(HashMap::new()) }
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		!self.ttl.is_zero()
+	}
+
+	fn lookup(&self, object: &str) -> Option<Entry> {
+		if (!self.is_enabled()) {
+			return None;
+		}
+		let entry = self.entries.lock().unwrap().get(object).filter(|e| e.confirmed.elapsed() < self.ttl).cloned();
+		let result = match entry.is_some() {
+			true => "hit",
+			false => "miss"
+		};
+		LOOKUPS.with_label_values(&[result]).inc();
+		entry
+	}
+
+	/// What S3 last said about an object, if it said so within the TTL.
+	pub fn get(&self, object: &str) -> Option<Stat> {
+		self.lookup(object).map(|e| e.stat)
+	}
+
+	/// Like [`Self::get`], for a manifest whose metadata was seen too.
+	pub fn get_manifest(&self, object: &str) -> Option<(ManifestMetadata, Stat)> {
+		self.lookup(object).and_then(|e| Some((e.metadata?, e.stat)))
+	}
+
+	/// Remembers what S3 just said about an object.  Without `metadata`, whatever was remembered of
+	/// it is kept, unless the object has changed since.
+	pub fn insert(&self, object: &str, stat: Stat, metadata: Option<ManifestMetadata>) {
+		if (!self.is_enabled()) {
+			return;
+		}
+		let mut entries = self.entries.lock().unwrap();
+		if (entries.len() >= Self::PRUNE_THRESHOLD) {
+			entries.retain(|_, e| e.confirmed.elapsed() < self.ttl);
+			// Still full of live entries; rather than track recency, start over
+			if (entries.len() >= Self::PRUNE_THRESHOLD) {
+				entries.clear();
+			}
+		}
+		let metadata = metadata.or_else(|| entries.get(object).filter(|e| e.stat.modified() == stat.modified()).and_then(|e| e.metadata.clone()));
+		entries.insert(object.to_owned(), Entry { stat, metadata, confirmed: Instant::now() });
+	}
+
+	pub fn remove(&self, object: &str) {
+		if (self.is_enabled()) {
+			self.entries.lock().unwrap().remove(object);
+		}
+	}
+
+	/// Whether listing `prefix` would take fewer requests than `HeadObject`s for what's remembered
+	/// under it:  one per thousand objects, against one per entry.  A prefix that's never been
+	/// listed is worth listing once to find out.
+	pub fn worth_listing(&self, prefix: &'static str) -> bool {
+		let remembered = self.entries.lock().unwrap().keys().filter(|k| k.starts_with(prefix)).count();
+		match self.listed.lock().unwrap().get(prefix) {
+			_ if remembered == 0 => false,
+			Some(listed) => remembered > listed / 1000 + 1,
+			None => true
+		}
+	}
+
+	/// Brings what's remembered under `prefix` up to date with a listing of it:  entries for
+	/// objects in the listing are confirmed, and those for objects that aren't are forgotten.
+	pub fn refresh(&self, prefix: &'static str, listing: impl IntoIterator<Item = (String, Stat)>) {
+		let mut entries = self.entries.lock().unwrap();
+		let now = Instant::now();
+		let mut listed = 0;
+		for (object, stat) in listing {
+			listed += 1;
+			if let Some(entry) = entries.get_mut(&object) {
+				if (entry.stat.modified() != stat.modified()) {
+					entry.metadata = None;
+				}
+				entry.stat = stat;
+				entry.confirmed = now;
+			}
+		}
+		entries.retain(|object, e| !object.starts_with(prefix) || e.confirmed == now);
+		self.listed.lock().unwrap().insert(prefix, listed);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::SystemTime;
+
+	use super::*;
+
+	#[test]
+	fn stats() {
+		let stats = Stats::new(Duration::from_secs(60));
+		let modified = SystemTime::now();
+		assert!(stats.get("blobs/sha256/ab/cdef").is_none());
+		stats.insert("manifests/docker.io/library/alpine/latest", Stat::new(10, modified), Some(ManifestMetadata::new("application/vnd.oci.image.index.v1+json".into(), None)));
+		stats.insert("blobs/sha256/ab/cdef", Stat::new(1234, modified), None);
+		assert_eq!(stats.get("blobs/sha256/ab/cdef").unwrap().length(), 1234);
+		assert!(stats.get_manifest("blobs/sha256/ab/cdef").is_none());
+		// Seeing the same manifest again without its metadata keeps it
+		stats.insert("manifests/docker.io/library/alpine/latest", Stat::new(10, modified), None);
+		assert!(stats.get_manifest("manifests/docker.io/library/alpine/latest").is_some());
+
+		assert!(stats.worth_listing("blobs/"));
+		stats.refresh("blobs/", [("blobs/sha256/12/3456".to_owned(), Stat::new(1, modified))]);
+		assert!(stats.get("blobs/sha256/ab/cdef").is_none());
+		assert!(stats.get("manifests/docker.io/library/alpine/latest").is_some());
+		assert!(!stats.worth_listing("blobs/"));
+
+		let disabled = Stats::new(Duration::ZERO);
+		disabled.insert("blobs/sha256/ab/cdef", Stat::new(1234, modified), None);
+		assert!(disabled.get("blobs/sha256/ab/cdef").is_none());
+	}
+}
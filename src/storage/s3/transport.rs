@@ -0,0 +1,142 @@
+//! How requests get to S3.  rusoto's own HTTP client trusts the system's root certificates, and
+//! panics at startup if it can't find any, as in a static binary on a `scratch` image; with
+//! `--tls-roots bundled`, requests go through reqwest instead, over rustls with the Mozilla roots
+//! compiled in, the same as requests to upstream registries do.  rusoto signs them either way.
+
+use core::pin::Pin;
+use core::time::Duration;
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use clap::ValueEnum;
+use futures::stream::Stream;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use futures::task::Context;
+use futures::task::Poll;
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderName;
+use reqwest::header::HeaderValue;
+use rusoto_core::request::DispatchSignedRequest;
+use rusoto_core::request::DispatchSignedRequestFuture;
+use rusoto_core::request::HttpDispatchError;
+use rusoto_core::request::HttpResponse;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::signature::SignedRequestPayload;
+use rusoto_core::ByteStream;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Where the system keeps its root certificates, in the order rustls' native root loading looks;
+/// `SSL_CERT_FILE` overrides them all.
+const SYSTEM_ROOTS: &[&str] = &[
+	"/etc/ssl/certs/ca-certificates.crt",
+	"/etc/pki/tls/certs/ca-bundle.crt",
+	"/etc/ssl/ca-bundle.pem",
+	"/etc/pki/tls/cacert.pem",
+	"/etc/pki/ca-trust/extracted/pem/tls-ca-bundle.pem",
+	"/etc/ssl/cert.pem"
+];
+
+/// Which root certificates S3's TLS certificate is checked against.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsRoots {
+	/// The system's, through rusoto's own HTTP client
+	#[default]
+	System,
+	/// The Mozilla roots compiled into the binary, through reqwest; needs no files at all
+	Bundled
+}
+
+/// The system's root certificate bundle, if it has one where it'd be looked for.
+pub fn system_roots() -> Option<PathBuf> {
+	if let Some(path) = std::env::var_os("SSL_CERT_FILE") {
+		return Some(PathBuf::from(path)).filter(|p| p.is_file());
+	}
+	SYSTEM_ROOTS.iter().map(PathBuf::from).find(|p| p.is_file())
+}
+
+/// rusoto needs its response bodies to be `Sync`, which reqwest's aren't; nothing polls one from
+/// two places at once, so a mutex that's never contended makes it so.
+struct SyncStream<S>(std::sync::Mutex<S>);
+
+impl<S: Stream + Unpin> Stream for SyncStream<S> {
+	type Item = S::Item;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		self.get_mut().0.get_mut().unwrap().poll_next_unpin(cx)
+	}
+}
+
+pub(super) struct Reqwest(reqwest::Client);
+
+impl Reqwest {
+	pub(super) fn new() -> Result<Self, reqwest::Error> {
+		Ok(Self(reqwest::Client::builder().build()?))
+	}
+}
+
+impl DispatchSignedRequest for Reqwest {
+	fn dispatch(&self, request: SignedRequest, timeout: Option<Duration>) -> DispatchSignedRequestFuture {
+		let client = self.0.clone();
+		Box::pin(async move {
+			let method = reqwest::Method::from_bytes(request.method().as_bytes()).map_err(|e| HttpDispatchError::new(e.to_string()))?;
+			let mut url = format!("{}://{}{}", request.scheme(), request.hostname(), request.canonical_path());
+			if (!request.canonical_query_string().is_empty()) {
+				url.push('?');
+				url.push_str(request.canonical_query_string());
+			}
+			let mut headers = HeaderMap::new();
+			for (name, values) in request.headers() {
+				let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| HttpDispatchError::new(format!("Invalid header name {name}: {e}")))?;
+				for value in values {
+					headers.append(&name, HeaderValue::from_bytes(value).map_err(|e| HttpDispatchError::new(format!("Invalid value for header {name}: {e}")))?);
+				}
+			}
+			let mut builder = client.request(method, url).headers(headers);
+			builder = match request.payload {
+				None => builder,
+				Some(SignedRequestPayload::Buffer(bytes)) => builder.body(bytes),
+				Some(SignedRequestPayload::Stream(stream)) => builder.body(reqwest::Body::wrap_stream(stream))
+			};
+			if let Some(timeout) = timeout {
+				builder = builder.timeout(timeout);
+			}
+			let response = builder.send().await.map_err(|e| HttpDispatchError::new(e.to_string()))?;
+			let status = response.status();
+			let mut headers = HeaderMap::<String>::default();
+			for (name, value) in response.headers() {
+				headers.append(name, String::from_utf8_lossy(value.as_bytes()).into_owned());
+			}
+			let body = response.bytes_stream().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+			let body: Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>> = Box::pin(body);
+			Ok(HttpResponse { status, body: ByteStream::new(SyncStream(std::sync::Mutex::new(body))), headers })
+		})
+	}
+}
+
+/// What `--print-tls-backend` prints:  how this binary makes TLS connections, and what it trusts.
+#[derive(Debug, Serialize)]
+pub struct TlsReport {
+	library: &'static str,
+	openssl: bool,
+	static_binary: bool,
+	upstream_roots: &'static str,
+	/// `None` unless storage is S3
+	s3_roots: Option<TlsRoots>,
+	system_roots: Option<PathBuf>
+}
+
+impl TlsReport {
+	pub fn new(s3_roots: Option<TlsRoots>) -> Self {
+		Self {
+			library: "rustls",
+			openssl: false,
+			static_binary: cfg!(target_feature = "crt-static") || cfg!(target_env = "musl"),
+			upstream_roots: "bundled",
+			s3_roots,
+			system_roots: system_roots()
+		}
+	}
+}
@@ -1,48 +1,774 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::iter;
+use std::sync::Arc;
 
 use camino::Utf8PathBuf;
 use clap::Parser;
 use compact_str::CompactString;
 use dkregistry::errors::Error;
-use dkregistry::v2::Client as InnerClient;
 use humantime::Duration;
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderName;
+use reqwest::header::HeaderValue;
+use reqwest::header::ACCEPT;
+use reqwest::header::RANGE;
+use reqwest::header::WWW_AUTHENTICATE;
 use serde::Deserialize;
+use serde::Serialize;
 use serde_with::serde_as;
 use serde_with::DisplayFromStr;
 use tokio::fs::read_to_string;
 use tracing::info;
 use tracing::warn;
 
+use crate::api::auth;
+use crate::api::cosign::PublicKey;
+use crate::api::rewrite::RewriteRule;
+use crate::api::shadow::Shadow;
+use crate::api::trace;
+use crate::api::trace::Hop;
 use crate::util::SecretString;
+use crate::validate::Report;
+
+pub mod auth_limit;
+use auth_limit::AuthLimit;
+pub mod circuit;
+pub mod dns;
+pub mod docker_config;
+use docker_config::DockerConfig;
+use circuit::CircuitBreaker;
+pub mod downloads;
+use downloads::DownloadLimit;
+pub mod freshness;
+pub mod names;
+use names::NameRule;
+pub mod profile;
+use profile::DefaultResolver;
+use profile::Profile;
+use profile::Resolver;
+pub mod pull;
+use pull::Authorization;
+use pull::Identity;
+pub mod ranges;
+use ranges::RangeFetch;
+pub mod throttle;
+use throttle::Throttle;
+pub mod tls_sessions;
+pub mod tokens;
+use tokens::AnonymousTokens;
+
+/// What manifests are accepted as, when asking about them
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, application/vnd.oci.image.manifest.v1+json, application/vnd.docker.distribution.manifest.list.v2+json, application/vnd.docker.distribution.manifest.v2+json";
 
 #[derive(Clone, Debug)]
 pub struct Client {
-	pub client: InnerClient,
+	pub namespace: CompactString,
+	/// Every request of upstream goes out over this, with the configured headers
+	pub http: reqwest::Client,
+	/// What this request's pulls authenticate with, once they have
+	pub authorization: Authorization,
+	/// Who this request pulls as
+	identity: Identity,
+	/// What this request's pulls pass on to upstream about the client request they're made for
+	pub hop: Hop,
+	pub base_url: Arc<str>,
+	pub profile: Profile,
+	path_prefix: Option<CompactString>,
+	pub challenge_mode: ChallengeMode,
+	pub auth_mode: AuthMode,
+	pub entitlement_recheck_interval: core::time::Duration,
+	settings: Arc<SingleUpstreamConfig>,
 	pub manifest_invalidation_time: core::time::Duration,
-	pub blob_invalidation_time: core::time::Duration
+	/// Whether manifests cached by tag stay fresh for as long as upstream's `Cache-Control` or
+	/// `Expires` says, where it says, rather than `manifest_invalidation_time`
+	pub cache_control: bool,
+	/// How long blobs are kept in storage since they were cached, before cleanup deletes them
+	pub blob_invalidation_time: core::time::Duration,
+	/// Whether blobs older than `blob_invalidation_time` are fetched from upstream again when
+	/// they're requested, rather than served until cleanup gets to them
+	pub refetch_expired_blobs: bool,
+	/// Whether a pull upstream refuses the configured credentials for is tried again anonymously
+	pub anonymous_fallback: bool,
+	pub circuit: Arc<CircuitBreaker>,
+	pub downloads: Arc<DownloadLimit>,
+	pub throttle: Arc<Throttle>,
+	pub anonymous_tokens: Arc<AnonymousTokens>,
+	pub auth_limit: Arc<AuthLimit>,
+	pub ranged: RangeFetch,
+	pub stale_policy: StalePolicy,
+	pub foreign_layers: ForeignLayerPolicy,
+	pub revalidation: RevalidationPolicy,
+	pub probe: ProbeMethod,
+	pub namespace_parameter: NamespaceParameter,
+	pub schema1: Schema1Policy,
+	/// How many more times a blob that didn't match its digest is fetched from this upstream, in
+	/// the background, for the cache
+	pub digest_mismatch_retries: usize,
+	/// The namespace whose upstream a blob that didn't match its digest is fetched from after that
+	pub digest_mismatch_fallback: Option<CompactString>,
+	/// Whether pushes to this namespace are forwarded to upstream
+	pub write_through: bool,
+	/// Whether upstream is another instance of this proxy, this one an edge cache in front of it
+	pub parent: bool
+}
+
+/// What to do when a cached object has expired but upstream can't be reached to refresh it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StalePolicy {
+	/// Return the upstream error to the client
+	#[default]
+	Fail,
+	/// Serve the expired object from cache
+	ServeStale,
+	/// Serve the expired object from cache with a `Warning: 110` header
+	ServeStaleWithWarningHeader
+}
+
+/// How to check whether an expired manifest, cached by tag, is still what the tag points at.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RevalidationPolicy {
+	/// Ask upstream for the tag's digest, the way `probe` says to, and only download the manifest if
+	/// it's changed.  With token-only probes, upstream can't be asked, so this is the same as `Get`.
+	#[default]
+	Head,
+	/// Always download the manifest again
+	Get
+}
+
+/// Whether pulls from upstream carry the `ns` parameter, naming the namespace they're for, as
+/// containerd does when pulling through a mirror.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NamespaceParameter {
+	/// Send it, and if upstream fails the request, send it again without; registries that don't
+	/// understand it may refuse it
+	#[default]
+	Auto,
+	/// Always send it, and take upstream's answer as it is
+	Always,
+	/// Never send it
+	Never
+}
+
+/// How upstream is asked about an object without fetching it:  the digest a tag points at, how long
+/// a manifest stays fresh, how long to back off for after a 429.  Registries differ in which of
+/// these count against rate limits, or are answered at all; the default depends on the profile.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProbeMethod {
+	/// A `HEAD`
+	Head,
+	/// A `GET` for the first byte; registries that ignore the range send a whole manifest, which is
+	/// small, and its body isn't read
+	RangedGet,
+	/// Only take a pull token, and never ask about the object itself:  tags aren't revalidated, and
+	/// upstream's `Cache-Control` and `Retry-After` go unheard.  In pass-through mode, rechecking
+	/// that a client may pull a cached blob only takes a token with its credentials, which proves
+	/// they're still good, but not that they can pull that image
+	Token
+}
+
+/// What to do with manifests that reference foreign (non-distributable) layers, which are hosted
+/// somewhere other than the registry itself.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForeignLayerPolicy {
+	/// Serve the manifest untouched; clients fetch foreign layers from their URLs themselves
+	#[default]
+	PassThrough,
+	/// Fetch foreign layers into the cache, and rewrite manifests requested by tag so that clients
+	/// pull those layers from us
+	Cache
+}
+
+/// What to do with Docker schema1 manifests, which current clients refuse to pull.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Schema1Policy {
+	/// Serve them as upstream did
+	#[default]
+	PassThrough,
+	/// Refuse them with an `UNSUPPORTED` error naming the problem
+	Reject,
+	/// Convert manifests requested by tag to schema2, building the image config from the manifest's
+	/// history; manifests requested by digest can't be converted, and are refused
+	Convert
+}
+
+/// How to challenge clients when upstream refuses our request for lack of authorization.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChallengeMode {
+	/// Relay upstream's own `WWW-Authenticate` challenge, scoped to the requested repository
+	#[default]
+	Upstream,
+	/// Ask the client for basic credentials for the proxy itself
+	Basic
+}
+
+/// Whose credentials are used to authenticate with upstream.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMode {
+	/// The proxy's own configured credentials, if any; everything fetched is shared by all clients
+	#[default]
+	Proxy,
+	/// The basic credentials presented by each client; content is cached separately per set of
+	/// credentials
+	Passthrough
+}
+
+/// An upstream's configuration, less anything secret, for `/_admin/info`.
+#[derive(Debug, Serialize)]
+pub struct UpstreamSummary {
+	namespace: CompactString,
+	host: CompactString,
+	tls: bool,
+	profile: Profile,
+	path_prefix: Option<CompactString>,
+	base_path: Option<CompactString>,
+	/// Whether the proxy has credentials of its own for this upstream
+	credentials: bool,
+	auth_mode: AuthMode,
+	anonymous_fallback: bool,
+	manifest_invalidation_time: String,
+	blob_invalidation_time: String,
+	refetch_expired_blobs: bool,
+	stale_policy: StalePolicy,
+	revalidation: RevalidationPolicy,
+	probe: ProbeMethod,
+	namespace_parameter: NamespaceParameter,
+	foreign_layers: ForeignLayerPolicy,
+	schema1: Schema1Policy,
+	digest_mismatch_retries: usize,
+	digest_mismatch_fallback: Option<CompactString>,
+	write_through: bool,
+	parent: bool,
+	/// Whether pushes use credentials of their own rather than the ones pulls use
+	push_credentials: bool,
+	/// The names of the extra headers sent upstream; their values aren't shown
+	headers: Vec<CompactString>,
+	rewrites: Vec<RewriteRule>,
+	image_names: Vec<NameRule>
+}
+
+/// Everything `/_admin/namespaces` says about an upstream:  its summary, the rest of its settings
+/// less anything secret, and how it's doing.
+#[derive(Debug, Serialize)]
+pub struct NamespaceStatus {
+	#[serde(flatten)]
+	summary: UpstreamSummary,
+	cache_control: bool,
+	tag_list_ttl: Option<String>,
+	challenge_mode: ChallengeMode,
+	entitlement_recheck_interval: String,
+	anonymous_token_ttl: String,
+	auth_rate_limit: f64,
+	auth_burst: u32,
+	/// Whether credentials are taken from the Docker config file
+	docker_credentials: bool,
+	circuit_failure_threshold: u32,
+	circuit_cooldown: String,
+	max_concurrent_downloads: usize,
+	download_queue_size: usize,
+	rate_limit_backoff: String,
+	rate_limit_max_wait: String,
+	range_fetch_parallelism: usize,
+	range_fetch_chunk_size: u64,
+	/// How many keys manifests have to be signed with one of
+	signature_keys: usize,
+	shadow: Option<Shadow>,
+	health: Health
+}
+
+/// How an upstream is doing, as far as this instance can tell.
+#[derive(Debug, Serialize)]
+pub struct Health {
+	circuit_open: bool,
+	/// How long until requests are let through again, while the circuit's open
+	circuit_retry_after: Option<String>,
+	consecutive_failures: u32,
+	/// How long until it's asked again, after it said to slow down
+	rate_limited_for: Option<String>,
+	queued_downloads: usize
+}
+
+impl UpstreamSummary {
+	pub fn namespace(&self) -> &str {
+		self.namespace.as_str()
+	}
+}
+
+impl NamespaceStatus {
+	pub fn namespace(&self) -> &str {
+		self.summary.namespace()
+	}
+}
+
+impl Client {
+	pub fn status(&self) -> NamespaceStatus {
+		let settings = &self.settings;
+		let circuit = self.circuit.check().err();
+		NamespaceStatus {
+			summary: self.summary(),
+			cache_control: self.cache_control,
+			tag_list_ttl: settings.tag_list_ttl.map(|ttl| ttl.to_string()),
+			challenge_mode: self.challenge_mode,
+			entitlement_recheck_interval: humantime::format_duration(self.entitlement_recheck_interval).to_string(),
+			anonymous_token_ttl: settings.anonymous_token_ttl.to_string(),
+			auth_rate_limit: settings.auth_rate_limit,
+			auth_burst: settings.auth_burst,
+			docker_credentials: settings.docker_credentials,
+			circuit_failure_threshold: settings.circuit_failure_threshold,
+			circuit_cooldown: settings.circuit_cooldown.to_string(),
+			max_concurrent_downloads: settings.max_concurrent_downloads,
+			download_queue_size: settings.download_queue_size,
+			rate_limit_backoff: settings.rate_limit_backoff.to_string(),
+			rate_limit_max_wait: settings.rate_limit_max_wait.to_string(),
+			range_fetch_parallelism: settings.range_fetch_parallelism,
+			range_fetch_chunk_size: settings.range_fetch_chunk_size,
+			signature_keys: settings.signature_keys.len(),
+			shadow: settings.shadow.clone(),
+			health: Health {
+				circuit_open: circuit.is_some(),
+				circuit_retry_after: circuit.map(|open| humantime::format_duration(open.retry_after).to_string()),
+				consecutive_failures: self.circuit.failures(),
+				rate_limited_for: self.throttle.check().err().map(|limited| humantime::format_duration(limited.retry_after).to_string()),
+				queued_downloads: self.downloads.queued()
+			}
+		}
+	}
+
+	pub fn summary(&self) -> UpstreamSummary {
+		UpstreamSummary {
+			namespace: self.namespace.clone(),
+			host: self.settings.host.clone(),
+			tls: self.settings.tls,
+			profile: self.profile,
+			path_prefix: self.path_prefix.clone(),
+			base_path: self.settings.base_path.clone(),
+			credentials: self.has_credentials(),
+			auth_mode: self.auth_mode,
+			anonymous_fallback: self.anonymous_fallback,
+			manifest_invalidation_time: humantime::format_duration(self.manifest_invalidation_time).to_string(),
+			blob_invalidation_time: humantime::format_duration(self.blob_invalidation_time).to_string(),
+			refetch_expired_blobs: self.refetch_expired_blobs,
+			stale_policy: self.stale_policy,
+			revalidation: self.revalidation,
+			probe: self.probe,
+			namespace_parameter: self.namespace_parameter,
+			foreign_layers: self.foreign_layers,
+			schema1: self.schema1,
+			digest_mismatch_retries: self.digest_mismatch_retries,
+			digest_mismatch_fallback: self.digest_mismatch_fallback.clone(),
+			write_through: self.write_through,
+			parent: self.parent,
+			push_credentials: self.settings.push_username.is_some(),
+			headers: self.settings.headers.keys().cloned().collect(),
+			rewrites: self.settings.rewrites.clone(),
+			image_names: self.settings.image_names.clone()
+		}
+	}
+
+	/// How old a cached blob can be and still be served.  A blob can't change, so by default any
+	/// age will do, and `blob_invalidation_time` only decides when cleanup deletes it.
+	pub fn cached_blob_max_age(&self) -> core::time::Duration {
+		match self.refetch_expired_blobs {
+			true => self.blob_invalidation_time,
+			false => core::time::Duration::MAX
+		}
+	}
+
+	pub fn rewrites(&self) -> &[RewriteRule] {
+		&self.settings.rewrites
+	}
+
+	/// The name `image`, as a client asked for it, is cached by.
+	pub fn canonical_image<'a>(&self, image: &'a str) -> Cow<'a, str> {
+		names::canonical(&self.settings.image_names, image)
+	}
+
+	/// The keys manifests from this upstream have to be signed with, if any
+	pub fn signature_keys(&self) -> &[PublicKey] {
+		&self.settings.signature_keys
+	}
+
+	/// This upstream, standing in for `namespace`'s:  what's fetched from it is cached for
+	/// `namespace`, so it's checked, named, and rewritten the way `namespace`'s own would be, and
+	/// nobody with the override token can cache what `namespace` wouldn't have let in.
+	pub fn standing_in_for(mut self, namespace: &Client) -> Self {
+		let mut settings = SingleUpstreamConfig::clone(&self.settings);
+		settings.signature_keys = namespace.settings.signature_keys.clone();
+		settings.rewrites = namespace.settings.rewrites.clone();
+		settings.image_names = namespace.settings.image_names.clone();
+		self.settings = Arc::new(settings);
+		self.foreign_layers = namespace.foreign_layers;
+		self.schema1 = namespace.schema1;
+		self
+	}
+
+	pub fn shadow(&self) -> Option<&Shadow> {
+		self.settings.shadow.as_ref()
+	}
+
+	/// How long upstream's tag lists are cached for, if `/tags/list` asks upstream at all.
+	pub fn tag_list_ttl(&self) -> Option<core::time::Duration> {
+		self.settings.tag_list_ttl.map(|ttl| *ttl)
+	}
+
+	/// How long upstream wants us to wait after a 429 for `path` (under `/v2/`), if it says.
+	/// A refused pull's error doesn't carry its `Retry-After` header, so this asks again with a
+	/// probe, taking a pull token first if upstream wants one.
+	pub async fn retry_after(&self, path: &str, image: &str) -> Option<core::time::Duration> {
+		let response = self.send_probe(path, image, &[]).await?.ok()?;
+		throttle::retry_after(response.headers())
+	}
+
+	/// How long upstream says the manifest `reference` points at stays fresh, if it says.
+	/// A pull doesn't pass its headers on, so this asks again with a probe.
+	pub async fn freshness(&self, image: &str, reference: &str) -> Option<core::time::Duration> {
+		let mut headers = vec![(ACCEPT, MANIFEST_TYPES.to_owned())];
+		// A parent answers an edge with what's left of its own copy's freshness
+		if (self.parent) {
+			headers.push((HeaderName::from_static(crate::api::edge::EDGE_HEADER), "1".to_owned()));
+		}
+		let response = self.send_probe(&format!("{image}/manifests/{reference}"), image, &headers).await?.ok()?;
+		match response.status().is_success() {
+			true => freshness::lifetime(response.headers(), time::OffsetDateTime::now_utc()),
+			false => None
+		}
+	}
+
+	/// The digest upstream says the manifest `reference` points at, asked for with a probe of our
+	/// own; `None` if it doesn't say, or with token-only probes, can't be asked.
+	pub async fn manifest_digest(&self, image: &str, reference: &str) -> Result<Option<String>, Error> {
+		let Some(response) = self.send_probe(&format!("{image}/manifests/{reference}"), image, &[(ACCEPT, MANIFEST_TYPES.to_owned())]).await else {
+			return Ok(None);
+		};
+		let response = response?;
+		match response.status().is_success() {
+			true => Ok(response.headers().get("docker-content-digest").and_then(|v| v.to_str().ok()).map(str::to_owned)),
+			false => Err(Error::UnexpectedHttpStatus(response.status()))
+		}
+	}
+
+	/// Asks about `path` (under `/v2/`) the way this upstream is configured to be asked, taking a
+	/// pull token for `image` first if upstream wants one.  `None` with token-only probes, which
+	/// don't ask.
+	async fn send_probe(&self, path: &str, image: &str, headers: &[(HeaderName, String)]) -> Option<Result<reqwest::Response, Error>> {
+		let url = format!("{}/v2/{path}", self.base_url);
+		let timeout = core::time::Duration::from_secs(5);
+		let request = || {
+			let request = match self.probe {
+				ProbeMethod::RangedGet => self.http.get(&url).header(RANGE, "bytes=0-0"),
+				_ => self.http.head(&url)
+			};
+			headers.iter().fold(trace::inject(request, &self.hop).timeout(timeout).query(&self.namespace_query().into_iter().collect::<Vec<_>>()), |request, (name, value)| request.header(name, value))
+		};
+		if (self.probe == ProbeMethod::Token) {
+			return None;
+		}
+		let response = match request().send().await {
+			Ok(v) => v,
+			Err(e) => return Some(Err(Error::Reqwest(e)))
+		};
+		if (response.status() != reqwest::StatusCode::UNAUTHORIZED) {
+			return Some(Ok(response));
+		}
+		let token = self.pull_token(&response, image, timeout).await?;
+		Some(request().bearer_auth(token).send().await.map_err(Error::Reqwest))
+	}
+
+	/// Takes a pull token for `image` where `response`, a 401, says to.
+	async fn pull_token(&self, response: &reqwest::Response, image: &str, timeout: core::time::Duration) -> Option<String> {
+		let challenge = response.headers().get(WWW_AUTHENTICATE)?.to_str().ok()?;
+		let (realm, service) = profile::bearer_realm(challenge)?;
+		let mut token_request = trace::inject(self.http.get(realm), &self.hop).timeout(timeout).query(&[("scope", format!("repository:{image}:pull"))]);
+		if let Some(service) = service {
+			token_request = token_request.query(&[("service", service)]);
+		}
+		if let Some(username) = self.settings.username.as_ref() {
+			token_request = token_request.basic_auth(username.expose(), self.settings.password.as_ref().map(|p| p.expose()));
+		}
+		let body = token_request.send().await.ok()?.bytes().await.ok()?;
+		let token: profile::Token = serde_json::from_slice(&body).ok()?;
+		token.value()
+	}
+
+	/// One page of upstream's tags for `image`:  up to `n` of them, the first after `last` if
+	/// given, and whether upstream says there are more.  Made with the token this request has taken
+	/// to pull, if it's taken one, or else one taken for the configured credentials if upstream
+	/// asks for one.
+	pub async fn tags_page(&self, image: &str, n: usize, last: Option<&str>) -> Result<(Vec<String>, bool), Error> {
+		#[derive(Deserialize)]
+		struct Page {
+			#[serde(default)]
+			tags: Option<Vec<String>>
+		}
+		let url = format!("{}/v2/{image}/tags/list", self.base_url);
+		let timeout = core::time::Duration::from_secs(30);
+		let mut query = vec![("n", n.to_string())];
+		query.extend(last.map(|last| ("last", last.to_owned())));
+		query.extend(self.namespace_query());
+		let request = || trace::inject(self.authorization.apply(self.http.get(&url)), &self.hop).timeout(timeout).query(&query);
+		let mut response = request().send().await.map_err(Error::Reqwest)?;
+		if (response.status() == reqwest::StatusCode::UNAUTHORIZED && matches!(self.authorization, Authorization::None)) {
+			if let Some(token) = self.pull_token(&response, image, timeout).await {
+				response = request().bearer_auth(token).send().await.map_err(Error::Reqwest)?;
+			}
+		}
+		if (!response.status().is_success()) {
+			return Err(Error::UnexpectedHttpStatus(response.status()));
+		}
+		let more = response.headers().contains_key(reqwest::header::LINK);
+		let page: Page = response.json().await.map_err(Error::Reqwest)?;
+		Ok((page.tags.unwrap_or_default(), more))
+	}
+
+	/// The `ns` parameter for the requests we make of upstream ourselves, where it's always sent.
+	fn namespace_query(&self) -> Option<(&'static str, String)> {
+		match self.namespace_parameter {
+			NamespaceParameter::Always => Some(("ns", self.namespace.to_string())),
+			NamespaceParameter::Auto | NamespaceParameter::Never => None
+		}
+	}
+
+	/// Maps the image name a client asked for onto the one upstream knows it by.
+	pub fn upstream_image<'a>(&self, image: &'a str) -> Cow<'a, str> {
+		self.profile.upstream_image(self.path_prefix.as_deref(), image)
+	}
+
+	/// Maps an image name upstream knows onto the ones clients may have asked for it by.
+	pub fn local_images(&self, upstream_image: &str) -> Vec<String> {
+		self.profile.local_images(self.path_prefix.as_deref(), upstream_image)
+	}
+
+	/// Upstream's scheme and host, without its base path, for resolving the absolute paths it sends
+	/// back.
+	pub fn origin(&self) -> &str {
+		let host = self.base_url.find("://").map_or(0, |i| i + 3);
+		match self.base_url[host..].find('/') {
+			Some(path) => &self.base_url[..host + path],
+			None => &self.base_url
+		}
+	}
+
+	pub fn has_credentials(&self) -> bool {
+		self.settings.username.is_some()
+	}
+
+	/// The credentials write-through pushes are made with:  the push credentials if there are any,
+	/// and otherwise the ones used for pulls.
+	pub fn push_credentials(&self) -> Option<(&str, &str)> {
+		let (username, password) = match self.settings.push_username.as_ref() {
+			Some(username) => (username, self.settings.push_password.as_ref()),
+			None => (self.settings.username.as_ref()?, self.settings.password.as_ref())
+		};
+		Some((username.expose(), password.map(|p| p.expose()).unwrap_or_default()))
+	}
+
+	/// Applies this upstream's profile to an error status it returned.
+	pub fn normalize_error(&self, error: Error, anonymous: bool) -> Error {
+		match error {
+			Error::UnexpectedHttpStatus(status) => Error::UnexpectedHttpStatus(self.profile.normalize_status(status, anonymous)),
+			Error::Client { status } => Error::Client { status: self.profile.normalize_status(status, anonymous) },
+			error => error
+		}
+	}
+
+	pub fn basic_challenge(&self) -> Option<HeaderValue> {
+		HeaderValue::from_str(&format!(r#"Basic realm="{}""#, self.namespace)).ok()
+	}
+
+	/// Returns the `WWW-Authenticate` challenge that should accompany a 401 for the given image.
+	pub async fn challenge(&self, image: &str) -> Option<HeaderValue> {
+		if (self.auth_mode == AuthMode::Passthrough) {
+			return self.basic_challenge();
+		}
+		match self.challenge_mode {
+			ChallengeMode::Basic => self.basic_challenge(),
+			ChallengeMode::Upstream => {
+				let response = match self.http.get(format!("{}/v2/", self.base_url)).send().await {
+					Ok(v) => v,
+					Err(error) => {
+						warn!(namespace = self.namespace.as_str(), %error, "Failed to retrieve authentication challenge from upstream");
+						return None;
+					}
+				};
+				let challenge = response.headers().get(WWW_AUTHENTICATE)?.to_str().ok()?;
+				HeaderValue::from_str(&profile::scoped_challenge(challenge, image)).ok()
+			}
+		}
+	}
+}
+
+/// How many upstreams that only overrides name are kept clients for; past this, they're forgotten
+/// and start over, like the known blobs index does, so that the hosts requests name can't grow it
+/// without bound.
+const MAX_OVERRIDE_CLIENTS: usize = 64;
+
+pub struct Clients {
+	clients: HashMap<CompactString, Client>,
+	/// For upstreams only named by requests' overrides, kept apart so that they can be forgotten
+	overrides: HashMap<CompactString, Client>,
+	resolver: Box<dyn Resolver>,
+	docker_config: Option<DockerConfig>,
+	ttl_overrides: HashMap<CompactString, TtlOverride>
+}
+
+/// Invalidation times set for a namespace at runtime, in place of the configured ones; whichever
+/// isn't set stays as configured.
+#[serde_as]
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TtlOverride {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	#[serde_as(as = "Option<DisplayFromStr>")]
+	pub manifest_invalidation_time: Option<Duration>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	#[serde_as(as = "Option<DisplayFromStr>")]
+	pub blob_invalidation_time: Option<Duration>
+}
+
+/// Sets `client`'s invalidation times to what `ttl` overrides them with, and the rest back to what's
+/// configured.
+fn apply_ttl_override(client: &mut Client, ttl: Option<&TtlOverride>) {
+	let ttl = ttl.cloned().unwrap_or_default();
+	client.manifest_invalidation_time = *ttl.manifest_invalidation_time.unwrap_or(client.settings.manifest_invalidation_time);
+	client.blob_invalidation_time = *ttl.blob_invalidation_time.unwrap_or(client.settings.blob_invalidation_time);
 }
 
-pub struct Clients(HashMap<CompactString, Client>);
 impl Clients {
 	pub fn get<'a>(&'a mut self, key: &str) -> Result<&'a mut Client, Error> {
-		if (!self.0.contains_key(key)) {
+		if (!self.clients.contains_key(key)) {
 			warn!(namespace = key, "Unknown namespace passed; configuring with default settings");
-			self.insert(key.into(), SingleUpstreamConfig::new(key.into()))?;
+			let config = self.resolver.resolve(key).unwrap_or_else(|| SingleUpstreamConfig::with_host(key.into(), key.into()));
+			self.insert(key.into(), config)?;
 		}
-		Ok(self.0.get_mut(key).unwrap())
+		Ok(self.clients.get_mut(key).unwrap())
+	}
+
+	/// The client for an upstream a request's override names:  the configured one, if it's
+	/// configured, or otherwise one set up with default settings, kept only among the last few.
+	pub fn get_override<'a>(&'a mut self, key: &str) -> Result<&'a mut Client, Error> {
+		if (self.clients.contains_key(key)) {
+			return Ok(self.clients.get_mut(key).unwrap());
+		}
+		if (!self.overrides.contains_key(key)) {
+			if (self.overrides.len() >= MAX_OVERRIDE_CLIENTS) {
+				self.overrides.clear();
+			}
+			let config = self.resolver.resolve(key).unwrap_or_else(|| SingleUpstreamConfig::with_host(key.into(), key.into()));
+			let client = self.build(key, config)?;
+			self.overrides.insert(key.into(), client);
+		}
+		Ok(self.overrides.get_mut(key).unwrap())
 	}
 
 	fn insert(&mut self, key: CompactString, config: SingleUpstreamConfig) -> Result<(), Error> {
-		self.0.insert(key, config.try_into()?);
+		let client = self.build(&key, config)?;
+		self.clients.insert(key, client);
 		Ok(())
 	}
 
+	fn build(&self, key: &str, config: SingleUpstreamConfig) -> Result<Client, Error> {
+		let mut client = config.try_into()?;
+		if let Some(docker) = self.docker_config.as_ref() {
+			apply_docker_config(&mut client, docker)?;
+		}
+		apply_ttl_override(&mut client, self.ttl_overrides.get(key));
+		Ok(client)
+	}
+
+	/// Replaces an upstream's credentials, as when they've been fetched again from a secret store.
+	/// Returns whether the namespace is configured at all.
+	pub(crate) fn set_credentials(&mut self, namespace: &str, username: &SecretString, password: &SecretString) -> Result<bool, Error> {
+		let mut found = false;
+		for client in self.clients.values_mut().filter(|c| c.namespace == namespace) {
+			found = true;
+			let same = |current: &Option<SecretString>, new: &SecretString| current.as_ref().is_some_and(|c| c.expose() == new.expose());
+			if (same(&client.settings.username, username) && same(&client.settings.password, password)) {
+				continue;
+			}
+			let mut settings = (*client.settings).clone();
+			settings.username = Some(username.clone());
+			settings.password = Some(password.clone());
+			settings.docker_credentials = false;
+			rebuild(client, settings)?;
+		}
+		Ok(found)
+	}
+
+	/// Gives every upstream without credentials of its own configured the ones the Docker config
+	/// has for its registry, replacing any it got from an earlier version.  Returns how many
+	/// upstreams' credentials changed.
+	pub fn set_docker_config(&mut self, docker: DockerConfig) -> usize {
+		let mut changed = 0;
+		for (namespace, client) in self.clients.iter_mut() {
+			match apply_docker_config(client, &docker) {
+				Ok(true) => changed += 1,
+				Ok(false) => (),
+				Err(error) => warn!(namespace = namespace.as_str(), %error, "Failed to set up a client with credentials from the Docker config file")
+			};
+		}
+		self.docker_config = Some(docker);
+		// Set up again with the new config as they're next asked for
+		self.overrides.clear();
+		changed
+	}
+
+	/// Overrides `namespace`'s invalidation times from the next request on; with `None`, goes back
+	/// to the configured ones.
+	pub fn set_ttl_override(&mut self, namespace: &str, ttl: Option<TtlOverride>) {
+		match ttl {
+			Some(ttl) => self.ttl_overrides.insert(namespace.into(), ttl),
+			None => self.ttl_overrides.remove(namespace)
+		};
+		for client in self.clients.values_mut().filter(|c| c.namespace == namespace) {
+			apply_ttl_override(client, self.ttl_overrides.get(namespace));
+		}
+	}
+
+	/// The invalidation times overridden at runtime, by namespace.
+	pub fn ttl_overrides(&self) -> &HashMap<CompactString, TtlOverride> {
+		&self.ttl_overrides
+	}
+
+	/// Every configured upstream, by namespace.
+	pub fn summary(&self) -> Vec<UpstreamSummary> {
+		let mut summary = self.clients.values().map(Client::summary).collect::<Vec<_>>();
+		summary.sort_by(|a, b| a.namespace.cmp(&b.namespace));
+		summary
+	}
+
+	/// The scopes each upstream took anonymous tokens for within the last `within`, by namespace,
+	/// and how long ago.
+	pub fn recent_anonymous_scopes(&self, within: core::time::Duration) -> Vec<(CompactString, String, core::time::Duration)> {
+		self.clients.values().flat_map(|client| client.anonymous_tokens.recent(within).into_iter().map(|(scope, age)| (client.namespace.clone(), scope, age))).collect()
+	}
+
+	/// Every configured upstream's settings and health, by namespace.
+	pub fn status(&self) -> Vec<NamespaceStatus> {
+		let mut clients = self.clients.values().collect::<Vec<_>>();
+		clients.sort_by(|a, b| a.namespace.cmp(&b.namespace));
+		clients.into_iter().map(Client::status).collect()
+	}
+
+	/// Replaces the resolver used to configure namespaces that weren't in the upstream config.
+	pub fn with_resolver(mut self, resolver: impl Resolver + 'static) -> Self {
+		self.resolver = Box::new(resolver);
+		self
+	}
+
 	pub fn invalidation_config(&self) -> InvalidationConfig {
 		let mut config = InvalidationConfig {
 			blob: core::time::Duration::from_secs(10),
-			manifests: HashMap::with_capacity(self.0.len())
+			manifests: HashMap::with_capacity(self.clients.len())
 		};
-		for (ns, client) in self.0.iter() {
+		for (ns, client) in self.clients.iter() {
 			if (ns.is_empty()) {
 				continue;
 			}
@@ -57,7 +783,7 @@ impl Clients {
 
 impl FromIterator<(CompactString, Client)> for Clients {
 	fn from_iter<T: IntoIterator<Item = (CompactString, Client)>>(iter: T) -> Self {
-		Self(iter.into_iter().collect())
+		Self { clients: iter.into_iter().collect(), overrides: HashMap::new(), resolver: Box::new(DefaultResolver), docker_config: None, ttl_overrides: HashMap::new() }
 	}
 }
 
@@ -79,6 +805,46 @@ fn default_blob_invalidation_time() -> Duration {
 	core::time::Duration::from_secs(14 * 86400).into()
 }
 
+const fn default_circuit_failure_threshold() -> u32 {
+	5
+}
+
+fn default_entitlement_recheck_interval() -> Duration {
+	core::time::Duration::from_secs(300).into()
+}
+
+fn default_circuit_cooldown() -> Duration {
+	core::time::Duration::from_secs(30).into()
+}
+
+const fn default_download_queue_size() -> usize {
+	100
+}
+
+fn default_rate_limit_backoff() -> Duration {
+	core::time::Duration::from_secs(60).into()
+}
+
+fn default_rate_limit_max_wait() -> Duration {
+	core::time::Duration::from_secs(10).into()
+}
+
+fn default_anonymous_token_ttl() -> Duration {
+	core::time::Duration::from_secs(60).into()
+}
+
+const fn default_auth_burst() -> u32 {
+	10
+}
+
+const fn default_range_fetch_parallelism() -> usize {
+	1
+}
+
+const fn default_range_fetch_chunk_size() -> u64 {
+	16 * 1024 * 1024
+}
+
 #[serde_as]
 #[derive(Clone, Debug, Deserialize)]
 pub struct SingleUpstreamConfig {
@@ -88,36 +854,288 @@ pub struct SingleUpstreamConfig {
 	tls: bool,
 	#[serde(default)]
 	accept_invalid_certs: bool,
+	/// Built-in quirk handling for this registry; detected from `host` if not set
+	#[serde(default)]
+	profile: Option<Profile>,
+	/// Prepended to every image name before it's requested from upstream
+	#[serde(default)]
+	path_prefix: Option<CompactString>,
+	/// The path upstream serves the registry API under, in front of `/v2/`, for registries like
+	/// Nexus and Artifactory that serve each repository at a URL of its own
+	#[serde(default)]
+	base_path: Option<CompactString>,
 	#[serde(default)]
 	user_agent: Option<arcstr::ArcStr>,
+	/// Extra headers, such as API keys or ones identifying egress traffic, sent with every request
+	/// of upstream
+	#[serde(default)]
+	headers: HashMap<CompactString, SecretString>,
 	#[serde(default)]
 	username: Option<SecretString>,
 	#[serde(default)]
 	password: Option<SecretString>,
+	/// Forward pushes to upstream, caching what it accepts
+	#[serde(default)]
+	write_through: bool,
+	#[serde(default)]
+	push_username: Option<SecretString>,
+	#[serde(default)]
+	push_password: Option<SecretString>,
 	#[serde(default = "default_manifest_invalidation_time")]
 	#[serde_as(as = "DisplayFromStr")]
 	manifest_invalidation_time: Duration,
+	/// Keep manifests cached by tag for as long as upstream's `Cache-Control` or `Expires` headers
+	/// say, where they say, instead of `manifest_invalidation_time`
+	#[serde(default)]
+	cache_control: bool,
 	#[serde(default = "default_blob_invalidation_time")]
 	#[serde_as(as = "DisplayFromStr")]
-	blob_invalidation_time: Duration
+	blob_invalidation_time: Duration,
+	/// Fetch blobs older than `blob_invalidation_time` from upstream again, as was done before
+	/// blobs were treated as immutable
+	#[serde(default)]
+	refetch_expired_blobs: bool,
+	/// After this many consecutive connection failures or server errors, stop contacting this
+	/// upstream for `circuit_cooldown`.  Zero disables the circuit breaker.
+	#[serde(default = "default_circuit_failure_threshold")]
+	circuit_failure_threshold: u32,
+	#[serde(default = "default_circuit_cooldown")]
+	#[serde_as(as = "DisplayFromStr")]
+	circuit_cooldown: Duration,
+	/// How many blobs can be downloaded from this upstream at once; zero doesn't limit them
+	#[serde(default)]
+	max_concurrent_downloads: usize,
+	/// How many downloads past `max_concurrent_downloads` can wait for one to finish; past that,
+	/// clients are told to retry later
+	#[serde(default = "default_download_queue_size")]
+	download_queue_size: usize,
+	/// After a 429, how long to stop asking this upstream for anything when it doesn't say how long
+	/// in a `Retry-After` header
+	#[serde(default = "default_rate_limit_backoff")]
+	#[serde_as(as = "DisplayFromStr")]
+	rate_limit_backoff: Duration,
+	/// The longest a pull waits out a 429 to try again, within its deadline, before answering with
+	/// a 429 of its own; zero never waits
+	#[serde(default = "default_rate_limit_max_wait")]
+	#[serde_as(as = "DisplayFromStr")]
+	rate_limit_max_wait: Duration,
+	/// How long an anonymous pull token is reused for the scope it was taken for, a little less
+	/// for some, rather than each pull taking its own; zero always takes a new one
+	#[serde(default = "default_anonymous_token_ttl")]
+	#[serde_as(as = "DisplayFromStr")]
+	anonymous_token_ttl: Duration,
+	/// How many token requests a second are made of this upstream's auth endpoint, at most, with
+	/// the rest waiting their turn; zero doesn't limit them
+	#[serde(default)]
+	auth_rate_limit: f64,
+	/// How many token requests can be made at once before `auth_rate_limit` kicks in
+	#[serde(default = "default_auth_burst")]
+	auth_burst: u32,
+	/// How many ranges of a large blob are fetched from upstream at once, for upstreams that serve
+	/// ranges; with one, blobs are fetched whole
+	#[serde(default = "default_range_fetch_parallelism")]
+	range_fetch_parallelism: usize,
+	/// How many bytes each of those ranges is; only blobs bigger than this are split up
+	#[serde(default = "default_range_fetch_chunk_size")]
+	range_fetch_chunk_size: u64,
+	#[serde(default)]
+	stale_policy: StalePolicy,
+	#[serde(default)]
+	foreign_layers: ForeignLayerPolicy,
+	#[serde(default)]
+	revalidation: RevalidationPolicy,
+	/// How upstream is asked about objects without fetching them; unset, the profile decides
+	#[serde(default)]
+	probe: Option<ProbeMethod>,
+	#[serde(default)]
+	namespace_parameter: NamespaceParameter,
+	#[serde(default)]
+	schema1: Schema1Policy,
+	/// When a blob from upstream doesn't match its digest, how many more times it's fetched from
+	/// upstream in the background, so that the client's retry can be served from cache; corruption
+	/// in transit, or on one of upstream's servers, may not happen twice
+	#[serde(default)]
+	digest_mismatch_retries: usize,
+	/// And the namespace whose upstream, such as a mirror of this one, it's then fetched from
+	#[serde(default)]
+	digest_mismatch_fallback: Option<CompactString>,
+	/// Upstream is another oci-registry, with this one an edge cache in front of it:  the namespace
+	/// is always passed on in `ns`, and tags expire here when they do there, if not sooner
+	#[serde(default)]
+	parent: bool,
+	/// Changes made to manifests requested by tag before they're cached, in order
+	#[serde(default)]
+	rewrites: Vec<RewriteRule>,
+	/// Changes made to the image names clients ask for, in order, giving the names images are
+	/// cached by and asked of upstream by
+	#[serde(default)]
+	image_names: Vec<NameRule>,
+	/// Cosign public keys, as PEM; with any, manifests are only cached and served once one of
+	/// them is found to have signed them
+	#[serde(default)]
+	signature_keys: Vec<PublicKey>,
+	/// With this, `/tags/list` lists upstream's tags, cached for this long, rather than only what's
+	/// cached here
+	#[serde(default)]
+	#[serde_as(as = "Option<DisplayFromStr>")]
+	tag_list_ttl: Option<Duration>,
+	/// Another namespace to fetch a share of the manifests fetched from this one from as well, in
+	/// the background, comparing the two
+	#[serde(default)]
+	shadow: Option<Shadow>,
+	#[serde(default)]
+	challenge_mode: ChallengeMode,
+	#[serde(default)]
+	auth_mode: AuthMode,
+	/// When upstream refuses the configured credentials, say because they've expired or been
+	/// revoked, try the pull again anonymously, so that public images can still be pulled
+	#[serde(default)]
+	anonymous_fallback: bool,
+	/// In pass-through auth mode, how long upstream's confirmation that a set of credentials can
+	/// pull an image is trusted before cached content is served to those credentials again
+	#[serde(default = "default_entitlement_recheck_interval")]
+	#[serde_as(as = "DisplayFromStr")]
+	entitlement_recheck_interval: Duration,
+	/// Whether `username` and `password` came from the Docker config file, and so are replaced
+	/// when it changes
+	#[serde(skip)]
+	docker_credentials: bool
 }
 
 impl SingleUpstreamConfig {
-	fn new(namespace: CompactString) -> Self {
-		Self::with_host(namespace.clone(), namespace)
-	}
-
 	fn with_host(namespace: CompactString, host: CompactString) -> Self {
 		Self {
 			namespace,
 			host,
 			tls: true,
 			accept_invalid_certs: false,
+			profile: None,
+			path_prefix: None,
+			base_path: None,
 			user_agent: None,
+			headers: HashMap::new(),
 			username: None,
 			password: None,
+			write_through: false,
+			push_username: None,
+			push_password: None,
 			manifest_invalidation_time: default_manifest_invalidation_time(),
-			blob_invalidation_time: default_blob_invalidation_time()
+			cache_control: false,
+			blob_invalidation_time: default_blob_invalidation_time(),
+			refetch_expired_blobs: false,
+			circuit_failure_threshold: default_circuit_failure_threshold(),
+			circuit_cooldown: default_circuit_cooldown(),
+			max_concurrent_downloads: 0,
+			download_queue_size: default_download_queue_size(),
+			rate_limit_backoff: default_rate_limit_backoff(),
+			rate_limit_max_wait: default_rate_limit_max_wait(),
+			anonymous_token_ttl: default_anonymous_token_ttl(),
+			auth_rate_limit: 0.0,
+			auth_burst: default_auth_burst(),
+			range_fetch_parallelism: default_range_fetch_parallelism(),
+			range_fetch_chunk_size: default_range_fetch_chunk_size(),
+			stale_policy: StalePolicy::default(),
+			foreign_layers: ForeignLayerPolicy::default(),
+			revalidation: RevalidationPolicy::default(),
+			probe: None,
+			namespace_parameter: NamespaceParameter::default(),
+			schema1: Schema1Policy::default(),
+			digest_mismatch_retries: 0,
+			digest_mismatch_fallback: None,
+			parent: false,
+			rewrites: Vec::new(),
+			image_names: Vec::new(),
+			signature_keys: Vec::new(),
+			tag_list_ttl: None,
+			shadow: None,
+			challenge_mode: ChallengeMode::default(),
+			auth_mode: AuthMode::default(),
+			anonymous_fallback: false,
+			entitlement_recheck_interval: default_entitlement_recheck_interval(),
+			docker_credentials: false
+		}
+	}
+
+	/// Where upstream's registry API is, less the scheme and `/v2/`:  its host, and its base path if
+	/// it has one.
+	fn registry(&self) -> String {
+		match self.base_path.as_deref().map(|p| p.trim_matches('/')) {
+			Some(path) if !path.is_empty() => format!("{}/{path}", self.host),
+			_ => self.host.to_string()
+		}
+	}
+
+	/// The extra headers to send upstream, along with a complaint about each one that isn't valid.
+	fn header_map(&self) -> (HeaderMap, Vec<String>) {
+		let mut headers = HeaderMap::new();
+		let mut invalid = Vec::new();
+		for (name, value) in &self.headers {
+			match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value.expose())) {
+				(Ok(name), Ok(mut value)) => {
+					// They're likely to include API keys
+					value.set_sensitive(true);
+					headers.insert(name, value);
+				},
+				(Err(_), _) => invalid.push(format!("{name} isn't a valid header name")),
+				(_, Err(_)) => invalid.push(format!("The value of header {name} isn't a valid header value"))
+			};
+		}
+		(headers, invalid)
+	}
+
+	/// Checks for settings that can't work together, without contacting upstream.
+	pub fn validate(&self, report: &mut Report) {
+		let namespace = self.namespace.as_str();
+		if (self.host.is_empty()) {
+			report.error(namespace, "host is empty");
+		} else if (self.host.contains("://")) {
+			report.error(namespace, format!("host {} includes a scheme; give just the hostname, and set tls: false for plain HTTP", self.host));
+		}
+		if (self.username.is_some() != self.password.is_some()) {
+			report.error(namespace, "username and password have to be set together");
+		}
+		if (self.push_username.is_some() != self.push_password.is_some()) {
+			report.error(namespace, "push_username and push_password have to be set together");
+		}
+		if (self.push_username.is_some() && !self.write_through) {
+			report.warn(namespace, "push_username and push_password are only used with write_through: true");
+		}
+		for problem in self.header_map().1 {
+			report.error(namespace, problem);
+		}
+		if (self.headers.keys().any(|name| name.eq_ignore_ascii_case("user-agent"))) {
+			report.warn(namespace, "User-Agent in headers only applies to some requests; set user_agent instead");
+		}
+		if (!self.host.contains("://") && self.host.contains('/')) {
+			report.error(namespace, format!("host {} includes a path; give just the hostname, and the path as base_path", self.host));
+		}
+		if (self.base_path.as_deref().is_some_and(|p| p.trim_end_matches('/').ends_with("/v2") || p.trim_matches('/') == "v2")) {
+			report.error(namespace, "base_path ends in /v2; give the path in front of it, which /v2/ is added to");
+		}
+		let profile = self.profile.unwrap_or_else(|| Profile::detect(&self.host));
+		if (profile.requires_path_prefix() && self.path_prefix.is_none() && self.base_path.is_none()) {
+			report.warn(namespace, format!("{profile:?} registries usually need path_prefix or base_path to be set"));
+		}
+		for rule in &self.rewrites {
+			rule.validate(namespace, report);
+		}
+		for rule in &self.image_names {
+			rule.validate(namespace, report);
+		}
+		if (self.auth_mode == AuthMode::Passthrough && !auth::has_partition_key()) {
+			report.error(namespace, "auth_mode: passthrough needs --partition-key or --partition-key-file, to name what's cached for each client's credentials with");
+		}
+		if (self.parent && self.namespace_parameter == NamespaceParameter::Never) {
+			report.error(namespace, "parent: true needs the namespace passed on in ns; namespace_parameter: never leaves it out");
+		}
+		if (!self.auth_rate_limit.is_finite() || self.auth_rate_limit < 0.0) {
+			report.error(namespace, "auth_rate_limit has to be a number of requests a second, or 0 for no limit");
+		}
+		if (self.digest_mismatch_fallback.as_ref() == Some(&self.namespace)) {
+			report.error(namespace, "digest_mismatch_fallback is this namespace; use digest_mismatch_retries to fetch from it again");
+		}
+		if let Err(error) = Client::try_from(self.clone()) {
+			report.error(namespace, format!("Failed to set up a client: {error}"));
 		}
 	}
 }
@@ -126,22 +1144,114 @@ impl TryFrom<SingleUpstreamConfig> for Client {
 	type Error = Error;
 
 	fn try_from(config: SingleUpstreamConfig) -> Result<Self, Self::Error> {
-		let client = InnerClient::configure()
-			.registry(&config.host)
-			.insecure_registry(!config.tls)
-			.accept_invalid_certs(config.accept_invalid_certs)
-			.user_agent(config.user_agent)
-			.username(config.username.map(|s| s.into_inner()))
-			.password(config.password.map(|s| s.into_inner()))
-			.build()?;
+		let mut http = reqwest::Client::builder().danger_accept_invalid_certs(config.accept_invalid_certs);
+		if let Some(user_agent) = config.user_agent.as_ref() {
+			http = http.user_agent(user_agent.as_str());
+		}
+		let (headers, invalid) = config.header_map();
+		for error in invalid {
+			warn!(namespace = config.namespace.as_str(), error = error.as_str(), "Not sending invalid header upstream");
+		}
+		http = http.default_headers(headers);
+		if let Some(resolver) = dns::resolver() {
+			http = http.dns_resolver(resolver);
+		}
+		// Upstreams whose certificates aren't checked keep to reqwest's own config, which can skip that
+		if let Some(tls) = tls_sessions::config().filter(|_| !config.accept_invalid_certs) {
+			http = http.use_preconfigured_tls(tls);
+		}
+		let base_url = match config.tls {
+			true => format!("https://{}", config.registry()),
+			false => format!("http://{}", config.registry())
+		};
+		let profile = config.profile.unwrap_or_else(|| Profile::detect(&config.host));
+		if (profile.requires_path_prefix() && config.path_prefix.is_none() && config.base_path.is_none()) {
+			warn!(namespace = config.namespace.as_str(), ?profile, "This registry usually requires path_prefix to be set");
+		}
 		Ok(Self {
-			client,
-			manifest_invalidation_time: config.manifest_invalidation_time.into(),
-			blob_invalidation_time: config.blob_invalidation_time.into()
+			namespace: config.namespace.clone(),
+			http: http.build()?,
+			authorization: Authorization::None,
+			identity: Identity::Configured,
+			hop: Hop::default(),
+			base_url: base_url.into(),
+			profile,
+			path_prefix: config.path_prefix.clone(),
+			challenge_mode: config.challenge_mode,
+			auth_mode: config.auth_mode,
+			anonymous_fallback: config.anonymous_fallback,
+			entitlement_recheck_interval: *config.entitlement_recheck_interval,
+			manifest_invalidation_time: *config.manifest_invalidation_time,
+			cache_control: config.cache_control,
+			blob_invalidation_time: *config.blob_invalidation_time,
+			refetch_expired_blobs: config.refetch_expired_blobs,
+			circuit: Arc::new(CircuitBreaker::new(config.namespace.clone(), config.circuit_failure_threshold, *config.circuit_cooldown)),
+			downloads: Arc::new(DownloadLimit::new(config.namespace.clone(), config.max_concurrent_downloads, config.download_queue_size)),
+			throttle: Arc::new(Throttle::new(config.namespace.clone(), *config.rate_limit_backoff, *config.rate_limit_max_wait)),
+			anonymous_tokens: Arc::new(AnonymousTokens::new(config.namespace.clone(), *config.anonymous_token_ttl)),
+			auth_limit: Arc::new(AuthLimit::new(config.namespace.clone(), config.auth_rate_limit, config.auth_burst)),
+			ranged: RangeFetch::new(config.range_fetch_parallelism, config.range_fetch_chunk_size),
+			stale_policy: config.stale_policy,
+			foreign_layers: config.foreign_layers,
+			revalidation: config.revalidation,
+			probe: config.probe.unwrap_or_else(|| profile.probe_method()),
+			// A parent needs to know which of its namespaces is meant
+			namespace_parameter: match (config.parent, config.namespace_parameter) {
+				(true, NamespaceParameter::Auto) => NamespaceParameter::Always,
+				(_, setting) => setting
+			},
+			schema1: config.schema1,
+			digest_mismatch_retries: config.digest_mismatch_retries,
+			digest_mismatch_fallback: config.digest_mismatch_fallback.clone(),
+			write_through: config.write_through,
+			parent: config.parent,
+			settings: Arc::new(config)
 		})
 	}
 }
 
+/// Sets `client` up with the credentials `docker` has for its registry, unless it has credentials of
+/// its own configured, returning whether they changed.  Its circuit breaker carries over.
+fn apply_docker_config(client: &mut Client, docker: &DockerConfig) -> Result<bool, Error> {
+	if (client.settings.username.is_some() && !client.settings.docker_credentials) {
+		return Ok(false);
+	}
+	let mut settings = (*client.settings).clone();
+	match docker.get(&settings.namespace, &settings.host) {
+		Some(auth) => {
+			settings.username = Some(auth.username.clone());
+			settings.password = Some(auth.password.clone());
+			settings.docker_credentials = true;
+		},
+		None if settings.docker_credentials => {
+			settings.username = None;
+			settings.password = None;
+			settings.docker_credentials = false;
+		},
+		None => return Ok(false)
+	};
+	rebuild(client, settings)?;
+	Ok(true)
+}
+
+/// Replaces `client` with one built from new settings, carrying its circuit breaker, download
+/// limit, rate limit backoff, auth rate limit, and invalidation times, which may have been
+/// overridden, over.
+fn rebuild(client: &mut Client, settings: SingleUpstreamConfig) -> Result<(), Error> {
+	let circuit = client.circuit.clone();
+	let downloads = client.downloads.clone();
+	let throttle = client.throttle.clone();
+	let auth_limit = client.auth_limit.clone();
+	let ttls = (client.manifest_invalidation_time, client.blob_invalidation_time);
+	*client = Client::try_from(settings)?;
+	client.circuit = circuit;
+	client.downloads = downloads;
+	client.throttle = throttle;
+	client.auth_limit = auth_limit;
+	(client.manifest_invalidation_time, client.blob_invalidation_time) = ttls;
+	Ok(())
+}
+
 #[derive(Debug, Parser)]
 pub struct UpstreamConfig {
 	#[clap(env, long, default_value = "docker.io")]
@@ -161,7 +1271,12 @@ pub struct UpstreamConfig {
 	///
 	/// Example: `{"docker.io": {"username": "foo", "password": "bar"}, "namespace2": {"username":
 	/// {"aaa", "pasword": "bbb"}}`
-	upstream_credentials: String
+	upstream_credentials: String,
+	#[clap(env, long)]
+	/// A Docker `config.json`, or a Kubernetes `dockerconfigjson` secret mounted as a file, to
+	/// take credentials from for upstreams that aren't given any otherwise.  It's re-read when it
+	/// changes.
+	docker_config_file: Option<Utf8PathBuf>
 }
 
 #[derive(Debug, Deserialize)]
@@ -171,6 +1286,75 @@ pub struct CredentialsOverride<'a> {
 }
 
 impl UpstreamConfig {
+	pub fn docker_config_file(&self) -> Option<&Utf8PathBuf> {
+		self.docker_config_file.as_ref()
+	}
+
+	/// Checks the upstream config file and credentials, returning the namespaces they configure, or
+	/// `None` if the config file couldn't be read at all.
+	pub async fn validate(&self, report: &mut Report) -> Option<HashSet<CompactString>> {
+		let mut upstream_credentials: HashMap<&str, CredentialsOverride<'_>> = match serde_json::from_str(self.upstream_credentials.as_ref()) {
+			Ok(v) => v,
+			Err(error) => {
+				report.error("--upstream-credentials", format!("Expected a JSON map from namespace to username and password: {error}"));
+				HashMap::new()
+			}
+		};
+		let configs = match self.upstream_config_file.as_ref() {
+			Some(file) => {
+				let parsed = match read_to_string(file).await {
+					Ok(v) => serde_yaml::from_str::<Vec<SingleUpstreamConfig>>(&v).map_err(|e| e.to_string()),
+					Err(e) => Err(e.to_string())
+				};
+				match parsed {
+					Ok(v) => v,
+					Err(error) => {
+						report.error(file.as_str(), format!("Invalid upstream config file: {error}"));
+						return None;
+					}
+				}
+			},
+			None => vec![SingleUpstreamConfig::with_host("docker.io".into(), "registry-1.docker.io".into())]
+		};
+		let mut namespaces = HashSet::new();
+		let mut fallbacks = Vec::new();
+		for mut config in configs {
+			if (!namespaces.insert(config.namespace.clone())) {
+				report.error(config.namespace.as_str(), "Configured more than once in the upstream config file");
+			}
+			if let Some(cred) = upstream_credentials.remove::<str>(config.namespace.as_ref()) {
+				config.username = Some(cred.username.into());
+				config.password = Some(cred.password.into());
+			}
+			config.validate(report);
+			if let Some(fallback) = config.digest_mismatch_fallback.take() {
+				fallbacks.push((config.namespace, fallback));
+			}
+		}
+		for (namespace, fallback) in fallbacks {
+			if (!namespaces.contains(&fallback)) {
+				report.error(namespace.as_str(), format!("digest_mismatch_fallback {fallback} isn't in the upstream config file"));
+			}
+		}
+		for namespace in upstream_credentials.keys() {
+			report.warn("--upstream-credentials", format!("{namespace} isn't in the upstream config file, so its credentials are ignored"));
+		}
+		if let Some(path) = self.docker_config_file.as_ref() {
+			match DockerConfig::load(path).await {
+				Ok(docker) => {
+					for key in docker.unusable() {
+						report.warn("--docker-config-file", format!("{key} has no username and password (or auth) to use; credential helpers and identity tokens aren't supported"));
+					}
+				},
+				Err(error) => report.error("--docker-config-file", error.to_string())
+			};
+		}
+		if (!namespaces.contains(&self.default_upstream_namespace)) {
+			report.warn("--default-upstream-namespace", format!("{} isn't in the upstream config file, so it'll be treated as a registry hostname", self.default_upstream_namespace));
+		}
+		Some(namespaces)
+	}
+
 	pub async fn clients(&self) -> Result<Clients, Error> {
 		let mut upstream_credentials: HashMap<&str, CredentialsOverride<'_>> = serde_json::from_str(self.upstream_credentials.as_ref()).unwrap();
 		let mut clients = match self.upstream_config_file.as_ref() {
@@ -204,20 +1388,12 @@ impl UpstreamConfig {
 					None => (None, None)
 				};
 				#[rustfmt::skip]
-				let client = SingleUpstreamConfig{
-					namespace: "docker.io".into(),
-					host: "registry-1.docker.io".into(),
-					tls: true,
-					accept_invalid_certs: false,
-					user_agent: None,
+				let client: Client = SingleUpstreamConfig{
 					username,
 					password,
-					manifest_invalidation_time: default_manifest_invalidation_time(),
-					blob_invalidation_time: default_blob_invalidation_time()
+					..SingleUpstreamConfig::with_host("docker.io".into(), "registry-1.docker.io".into())
 				}.try_into()?;
-				let mut map = HashMap::with_capacity(1);
-				map.insert("docker.io".into(), client);
-				Clients(map)
+				iter::once((CompactString::from("docker.io"), client)).collect()
 			}
 		};
 
@@ -225,8 +1401,27 @@ impl UpstreamConfig {
 			warn!(namespace, "Namespace found in UPSTREAM_CREDENTIALS, but not in upstream config file; will be ignored.");
 		}
 
+		if let Some(path) = self.docker_config_file.as_ref() {
+			clients.set_docker_config(DockerConfig::load(path).await.unwrap());
+		}
+
 		let default_client = clients.get(&self.default_upstream_namespace)?.clone();
-		clients.0.insert("".into(), default_client);
+		clients.clients.insert("".into(), default_client);
 		Ok(clients)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[actix_web::test]
+	async fn override_clients() {
+		let mut clients = std::iter::empty().collect::<Clients>();
+		for i in 0..MAX_OVERRIDE_CLIENTS + 10 {
+			clients.get_override(&format!("mirror-{i}.example.com")).unwrap();
+		}
+		assert_eq!(clients.overrides.len(), 10);
+		assert!(clients.clients.is_empty());
+	}
+}
@@ -0,0 +1,155 @@
+use core::time::Duration;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::sync::OwnedMutexGuard;
+
+static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_auth_requests", "Number of token requests made of an upstream, by whether they had to wait for the auth rate limit", &["namespace", "result"]).unwrap());
+static COALESCED: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_auth_coalesced", "Number of anonymous pulls that waited for another pull's token for the same scope rather than asking for their own", &["namespace"]).unwrap());
+
+/// Keeps the token requests made of an upstream under `auth_rate_limit` a second, with bursts of up
+/// to `auth_burst`, so that a storm of misses doesn't get us banned from its auth endpoint.
+/// Requests over the limit wait their turn, first come first served, within their deadline; and
+/// anonymous pulls of the same repository wait for the one pull already asking for a token, then
+/// reuse it, rather than each asking for their own.
+#[derive(Debug)]
+pub struct AuthLimit {
+	namespace: CompactString,
+	/// Tokens a second; zero is no limit
+	rate: f64,
+	burst: f64,
+	/// How many requests can be made right away, and when that was worked out
+	bucket: Mutex<(f64, Instant)>,
+	/// Held by the request that's waiting for the bucket to refill, with the rest queued behind it
+	queue: AsyncMutex<()>,
+	/// A lock for each scope an anonymous token is being taken for
+	scopes: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>
+}
+
+/// Held by the one pull taking an anonymous token for a scope, while others for the same scope wait
+/// for it.
+pub struct Turn<'a> {
+	limit: &'a AuthLimit,
+	scope: String,
+	guard: Option<OwnedMutexGuard<()>>
+}
+
+impl Drop for Turn<'_> {
+	fn drop(&mut self) {
+		self.guard.take();
+		let mut scopes = self.limit.scopes.lock().unwrap();
+		// Nothing but the map has hold of it, so nothing's waiting
+		if (scopes.get(&self.scope).is_some_and(|lock| Arc::strong_count(lock) == 1)) {
+			scopes.remove(&self.scope);
+		}
+	}
+}
+
+impl AuthLimit {
+	pub fn new(namespace: CompactString, rate: f64, burst: u32) -> Self {
+		let burst = f64::from(burst.max(1));
+		Self { namespace, rate, burst, bucket: Mutex::new((burst, Instant::now())), queue: AsyncMutex::new(()), scopes: Mutex::new(HashMap::new()) }
+	}
+
+	/// Waits until a token request can be made.
+	pub async fn acquire(&self) {
+		if (self.rate <= 0.0) {
+			return;
+		}
+		let _queue = self.queue.lock().await;
+		let mut waited = false;
+		while let Some(wait) = self.take(Instant::now()) {
+			waited = true;
+			tokio::time::sleep(wait).await;
+		}
+		let result = match waited {
+			true => "queued",
+			false => "immediate"
+		};
+		REQUESTS.with_label_values(&[self.namespace.as_str(), result]).inc();
+	}
+
+	/// Takes a request from the bucket as of `now`, or returns how long until there's one to take.
+	fn take(&self, now: Instant) -> Option<Duration> {
+		let mut bucket = self.bucket.lock().unwrap();
+		let (available, at) = *bucket;
+		let available = (available + now.saturating_duration_since(at).as_secs_f64() * self.rate).min(self.burst);
+		match available >= 1.0 {
+			true => {
+				*bucket = (available - 1.0, now);
+				None
+			},
+			false => {
+				*bucket = (available, now);
+				Some(Duration::from_secs_f64((1.0 - available) / self.rate))
+			}
+		}
+	}
+
+	/// Waits for any pull already taking an anonymous token for `scope`, then takes this one's turn;
+	/// returns whether it waited, in which case there may be a token for it now.
+	pub async fn turn(&self, scope: &str) -> (Turn<'_>, bool) {
+		let lock = self.scopes.lock().unwrap().entry(scope.to_owned()).or_default().clone();
+		let (guard, waited) = match lock.clone().try_lock_owned() {
+			Ok(guard) => (guard, false),
+			Err(_) => {
+				COALESCED.with_label_values(&[self.namespace.as_str()]).inc();
+				(lock.lock_owned().await, true)
+			}
+		};
+		(Turn { limit: self, scope: scope.to_owned(), guard: Some(guard) }, waited)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bucket() {
+		let limit = AuthLimit::new("auth-limit-test".into(), 2.0, 3);
+		let start = Instant::now();
+		assert_eq!(limit.take(start), None);
+		assert_eq!(limit.take(start), None);
+		assert_eq!(limit.take(start), None);
+		assert_eq!(limit.take(start), Some(Duration::from_millis(500)));
+		assert_eq!(limit.take(start + Duration::from_millis(250)), Some(Duration::from_millis(250)));
+		assert_eq!(limit.take(start + Duration::from_millis(500)), None);
+		// Refills up to the burst, and no further
+		let later = start + Duration::from_secs(60);
+		for _ in 0..3 {
+			assert_eq!(limit.take(later), None);
+		}
+		assert!(limit.take(later).is_some());
+	}
+
+	#[actix_web::test]
+	async fn turns() {
+		let limit = AuthLimit::new("auth-limit-test".into(), 0.0, 1);
+		let (first, waited) = limit.turn("repository:foo:pull").await;
+		assert!(!waited);
+		// Other scopes don't wait
+		let (other, waited) = limit.turn("repository:bar:pull").await;
+		assert!(!waited);
+		drop(other);
+
+		let second = async {
+			let (_turn, waited) = limit.turn("repository:foo:pull").await;
+			waited
+		};
+		let release = async {
+			tokio::task::yield_now().await;
+			drop(first);
+		};
+		let (waited, ()) = tokio::join!(second, release);
+		assert!(waited);
+		assert!(limit.scopes.lock().unwrap().is_empty());
+	}
+}
@@ -0,0 +1,111 @@
+use core::time::Duration;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::http::StatusCode;
+use compact_str::CompactString;
+use dkregistry::errors::Error;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge_vec;
+use prometheus::IntCounterVec;
+use prometheus::IntGaugeVec;
+use tracing::info;
+use tracing::warn;
+
+static CIRCUIT_OPEN: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("upstream_circuit_open", "Whether requests to an upstream are currently being short-circuited", &["namespace"]).unwrap());
+static FAILURES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_failures", "Number of requests to an upstream that failed due to connection errors or server errors", &["namespace"]).unwrap());
+
+/// Returned instead of contacting an upstream that has recently failed too many times in a row.
+#[derive(Clone, Debug, thiserror::Error)]
+#[error("Upstream for namespace '{namespace}' is unavailable; not retrying for {}", humantime::format_duration(*.retry_after))]
+pub struct CircuitOpen {
+	pub namespace: CompactString,
+	pub retry_after: Duration
+}
+
+/// Tracks consecutive failures against a single upstream.  Once `threshold` consecutive requests
+/// have failed, the circuit opens and requests fail fast for `cooldown`; after that, requests are
+/// let through again, and the first failure immediately re-opens the circuit.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+	namespace: CompactString,
+	threshold: u32,
+	cooldown: Duration,
+	failures: AtomicU32,
+	opened_at: Mutex<Option<Instant>>
+}
+
+impl CircuitBreaker {
+	pub fn new(namespace: CompactString, threshold: u32, cooldown: Duration) -> Self {
+		CIRCUIT_OPEN.with_label_values(&[namespace.as_str()]).set(0);
+		Self { namespace, threshold, cooldown, failures: AtomicU32::new(0), opened_at: Mutex::new(None) }
+	}
+
+	pub fn check(&self) -> Result<(), CircuitOpen> {
+		if (self.threshold == 0) {
+			return Ok(());
+		}
+		let opened_at = self.opened_at.lock().unwrap();
+		match *opened_at {
+			Some(at) if at.elapsed() < self.cooldown => Err(CircuitOpen { namespace: self.namespace.clone(), retry_after: self.cooldown - at.elapsed() }),
+			_ => Ok(())
+		}
+	}
+
+	pub fn is_open(&self) -> bool {
+		self.check().is_err()
+	}
+
+	/// How many requests in a row have failed.
+	pub fn failures(&self) -> u32 {
+		self.failures.load(Ordering::Relaxed)
+	}
+
+	/// Records the outcome of a request to upstream.  Only failures that indicate the upstream
+	/// itself is unhealthy count against it; a 404 is a perfectly healthy answer.
+	pub fn record<T>(&self, result: &Result<T, Error>) {
+		match result {
+			Ok(_) => self.success(),
+			Err(e) if is_unavailable(e) => self.failure(),
+			Err(_) => ()
+		}
+	}
+
+	fn success(&self) {
+		if (self.failures.swap(0, Ordering::Relaxed) == 0) {
+			return;
+		}
+		let mut opened_at = self.opened_at.lock().unwrap();
+		if (opened_at.take().is_some()) {
+			info!(namespace = self.namespace.as_str(), "Upstream recovered; closing circuit");
+			CIRCUIT_OPEN.with_label_values(&[self.namespace.as_str()]).set(0);
+		}
+	}
+
+	fn failure(&self) {
+		FAILURES.with_label_values(&[self.namespace.as_str()]).inc();
+		let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+		if (self.threshold == 0 || failures < self.threshold) {
+			return;
+		}
+		let mut opened_at = self.opened_at.lock().unwrap();
+		if (opened_at.is_none()) {
+			warn!(namespace = self.namespace.as_str(), failures, cooldown = %humantime::format_duration(self.cooldown), "Too many consecutive upstream failures; opening circuit");
+		}
+		*opened_at = Some(Instant::now());
+		CIRCUIT_OPEN.with_label_values(&[self.namespace.as_str()]).set(1);
+	}
+}
+
+/// Whether an error indicates that the upstream couldn't be reached or is failing, as opposed to
+/// having given a well-formed answer we didn't like.
+pub fn is_unavailable(err: &Error) -> bool {
+	match err {
+		Error::Reqwest(_) => true,
+		Error::UnexpectedHttpStatus(status) | Error::Client { status } => status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS,
+		_ => false
+	}
+}
@@ -0,0 +1,187 @@
+//! Name resolution for upstream connections:  lookups remembered for a while, addresses ordered or
+//! filtered by family, and hosts pinned to addresses, for networks where the system resolver is
+//! slow or one address family can't always be reached.  Connections try the addresses of the first
+//! family they're given, and fall back to the other family if those haven't connected within 300ms
+//! (happy eyeballs), so preferring the family that works turns trouble with the other into a short
+//! delay rather than a connect timeout.
+
+use core::str::FromStr;
+use core::time::Duration;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use clap::Parser;
+use clap::ValueEnum;
+use once_cell::sync::OnceCell;
+use reqwest::dns::Addrs;
+use reqwest::dns::Name;
+use reqwest::dns::Resolve;
+use reqwest::dns::Resolving;
+
+static RESOLVER: OnceCell<DnsResolver> = OnceCell::new();
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum IpPreference {
+	/// In the order the system resolver gives them
+	#[default]
+	System,
+	Ipv4First,
+	Ipv6First,
+	Ipv4Only,
+	Ipv6Only
+}
+
+/// A host to connect to at an address of our choosing, rather than whatever it resolves to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HostOverride {
+	host: String,
+	address: IpAddr
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid '{0}'; expected host=address")]
+pub struct InvalidHostOverride(String);
+
+impl FromStr for HostOverride {
+	type Err = InvalidHostOverride;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.split_once('=').map(|(host, address)| (host.trim(), address.trim().parse())) {
+			Some((host, Ok(address))) if !host.is_empty() => Ok(Self { host: host.to_ascii_lowercase(), address }),
+			_ => Err(InvalidHostOverride(s.to_owned()))
+		}
+	}
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct DnsConfig {
+	/// How long the addresses an upstream's hostname resolves to are used for before it's looked up
+	/// again; `0s` looks it up for every new connection, as the system resolver (or its cache) says.
+	#[clap(env, long, default_value = "0s")]
+	upstream_dns_cache_ttl: humantime::Duration,
+	/// Which address family to connect to upstreams over first, or only, on dual-stack networks.
+	#[clap(env, long, value_enum, default_value_t = IpPreference::System)]
+	upstream_ip_preference: IpPreference,
+	/// Comma-separated `host=address` pairs to connect to upstream hosts at instead of looking them
+	/// up, e.g. `registry-1.docker.io=10.0.0.5`; a host can be given more than once.
+	#[clap(env, long, value_delimiter = ',')]
+	upstream_resolve: Vec<HostOverride>
+}
+
+#[derive(Debug)]
+struct Inner {
+	ttl: Duration,
+	preference: IpPreference,
+	overrides: HashMap<String, Vec<IpAddr>>,
+	cache: Mutex<HashMap<String, (Instant, Vec<IpAddr>)>>
+}
+
+/// Resolves upstream hostnames as configured, for reqwest.
+#[derive(Clone, Debug)]
+pub struct DnsResolver(Arc<Inner>);
+
+impl DnsResolver {
+	fn new(config: &DnsConfig) -> Self {
+		let mut overrides = HashMap::<String, Vec<IpAddr>>::new();
+		for o in &config.upstream_resolve {
+			overrides.entry(o.host.clone()).or_default().push(o.address);
+		}
+		Self(Arc::new(Inner { ttl: *config.upstream_dns_cache_ttl, preference: config.upstream_ip_preference, overrides, cache: Mutex::new(HashMap::new()) }))
+	}
+
+	/// Puts addresses in the order they're to be tried in, leaving out those of a family that's not
+	/// to be used.  Within a family, the system's order is kept.
+	fn order(&self, mut addresses: Vec<IpAddr>) -> Vec<IpAddr> {
+		match self.0.preference {
+			IpPreference::System => (),
+			IpPreference::Ipv4First => addresses.sort_by_key(IpAddr::is_ipv6),
+			IpPreference::Ipv6First => addresses.sort_by_key(IpAddr::is_ipv4),
+			IpPreference::Ipv4Only => addresses.retain(IpAddr::is_ipv4),
+			IpPreference::Ipv6Only => addresses.retain(IpAddr::is_ipv6)
+		};
+		addresses
+	}
+
+	/// What was looked up for `host` recently enough to use again.
+	fn cached(&self, host: &str) -> Option<Vec<IpAddr>> {
+		let cache = self.0.cache.lock().unwrap();
+		cache.get(host).filter(|(at, _)| at.elapsed() < self.0.ttl).map(|(_, addresses)| addresses.clone())
+	}
+
+	async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, std::io::Error> {
+		let host = host.to_ascii_lowercase();
+		if let Some(addresses) = self.0.overrides.get(&host) {
+			return Ok(addresses.clone());
+		}
+		if let Some(addresses) = self.cached(&host) {
+			return Ok(addresses);
+		}
+		let addresses = self.order(tokio::net::lookup_host((host.as_str(), 0)).await?.map(|a| a.ip()).collect());
+		if (addresses.is_empty()) {
+			return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{host} has no addresses of the family connections are limited to")));
+		}
+		if (!self.0.ttl.is_zero()) {
+			let mut cache = self.0.cache.lock().unwrap();
+			// Drop what's expired, so that hosts that are no longer asked about don't pile up
+			cache.retain(|_, (at, _)| at.elapsed() < self.0.ttl);
+			cache.insert(host, (Instant::now(), addresses.clone()));
+		}
+		Ok(addresses)
+	}
+}
+
+impl Resolve for DnsResolver {
+	fn resolve(&self, name: Name) -> Resolving {
+		let resolver = self.clone();
+		Box::pin(async move {
+			let addresses = resolver.lookup(name.as_str()).await?;
+			// The port is filled in by the connector
+			Ok(Box::new(addresses.into_iter().map(|ip| SocketAddr::new(ip, 0))) as Addrs)
+		})
+	}
+}
+
+/// Sets up how upstream hostnames are resolved, for every upstream client created from now on.
+/// Without anything configured, reqwest's own resolver is left in place.
+pub fn init(config: &DnsConfig) {
+	if (config.upstream_dns_cache_ttl.is_zero() && config.upstream_ip_preference == IpPreference::System && config.upstream_resolve.is_empty()) {
+		return;
+	}
+	let _ = RESOLVER.set(DnsResolver::new(config));
+}
+
+/// The resolver upstream clients are to use, if one's been set up.
+pub(super) fn resolver() -> Option<Arc<DnsResolver>> {
+	RESOLVER.get().cloned().map(Arc::new)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config(preference: IpPreference, overrides: &[&str]) -> DnsConfig {
+		DnsConfig { upstream_dns_cache_ttl: Duration::from_secs(60).into(), upstream_ip_preference: preference, upstream_resolve: overrides.iter().map(|o| o.parse().unwrap()).collect() }
+	}
+
+	#[actix_web::test]
+	async fn resolution() {
+		let addresses = ["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2"].map(|a| a.parse::<IpAddr>().unwrap()).to_vec();
+		let order = |preference| DnsResolver::new(&config(preference, &[])).order(addresses.clone()).iter().map(ToString::to_string).collect::<Vec<_>>();
+		assert_eq!(order(IpPreference::Ipv4First), ["192.0.2.1", "192.0.2.2", "2001:db8::1", "2001:db8::2"]);
+		assert_eq!(order(IpPreference::Ipv6First), ["2001:db8::1", "2001:db8::2", "192.0.2.1", "192.0.2.2"]);
+		assert_eq!(order(IpPreference::Ipv6Only), ["2001:db8::1", "2001:db8::2"]);
+		assert_eq!(order(IpPreference::System).len(), 4);
+
+		let resolver = DnsResolver::new(&config(IpPreference::System, &["Registry.Internal=10.0.0.5", "registry.internal=10.0.0.6"]));
+		assert_eq!(resolver.lookup("registry.internal").await.unwrap(), ["10.0.0.5".parse::<IpAddr>().unwrap(), "10.0.0.6".parse().unwrap()]);
+		let localhost = resolver.lookup("localhost").await.unwrap();
+		assert!(localhost.iter().all(IpAddr::is_loopback));
+		assert_eq!(resolver.cached("localhost"), Some(localhost));
+		assert!("registry.internal".parse::<HostOverride>().is_err());
+		assert!("registry.internal=not-an-address".parse::<HostOverride>().is_err());
+	}
+}
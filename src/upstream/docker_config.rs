@@ -0,0 +1,193 @@
+//! Upstream credentials from a Docker `config.json`, or a Kubernetes `dockerconfigjson` (or legacy
+//! `dockercfg`) secret mounted as a file, so that credentials managed by existing tooling don't
+//! have to be copied into our own config.  They're only used for upstreams that aren't given
+//! credentials any other way, and the file is re-read whenever it changes, as mounted secrets do
+//! when they're rotated.
+
+use core::time::Duration;
+use std::collections::HashMap;
+
+use actix_web::web;
+use base64::Engine;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use compact_str::CompactString;
+use serde::Deserialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::api::RequestConfig;
+use crate::util::SecretString;
+
+/// How often the file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError {
+	#[error("Failed to read Docker config file: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Invalid Docker config file: {0}")]
+	Json(#[from] serde_json::Error)
+}
+
+#[derive(Debug, Deserialize)]
+struct Entry {
+	/// Base64 of `username:password`
+	#[serde(default)]
+	auth: Option<SecretString>,
+	#[serde(default)]
+	username: Option<SecretString>,
+	#[serde(default)]
+	password: Option<SecretString>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum File {
+	/// `config.json` and `kubernetes.io/dockerconfigjson`
+	Current { auths: HashMap<String, Entry> },
+	/// `~/.dockercfg` and `kubernetes.io/dockercfg`
+	Legacy(HashMap<String, Entry>)
+}
+
+#[derive(Clone, Debug)]
+pub struct Auth {
+	pub(crate) username: SecretString,
+	pub(crate) password: SecretString
+}
+
+impl Entry {
+	fn auth(self) -> Option<Auth> {
+		match (self.username, self.password, self.auth) {
+			(Some(username), Some(password), _) => Some(Auth { username, password }),
+			(_, _, Some(auth)) => {
+				let decoded = base64::engine::general_purpose::STANDARD.decode(auth.expose().trim()).ok()?;
+				let decoded = String::from_utf8(decoded).ok()?;
+				let (username, password) = decoded.split_once(':')?;
+				Some(Auth { username: username.into(), password: password.into() })
+			},
+			_ => None
+		}
+	}
+}
+
+/// The registry a `config.json` key is for.  Keys may carry a scheme and path, as Docker Hub's
+/// `https://index.docker.io/v1/` does, and Docker Hub goes by several names.
+fn registry_host(key: &str) -> &str {
+	let key = key.split_once("://").map_or(key, |(_, rest)| rest);
+	match key.split('/').next().unwrap_or(key) {
+		"index.docker.io" | "registry-1.docker.io" | "registry.hub.docker.com" => "docker.io",
+		host => host
+	}
+}
+
+/// Credentials by registry host.
+#[derive(Debug, Default)]
+pub struct DockerConfig {
+	auths: HashMap<CompactString, Auth>,
+	/// Keys of entries without a username and password we could use, such as identity tokens
+	unusable: Vec<String>
+}
+
+impl DockerConfig {
+	pub async fn load(path: &Utf8Path) -> Result<Self, LoadError> {
+		Self::parse(&tokio::fs::read(path).await?)
+	}
+
+	pub fn parse(json: &[u8]) -> Result<Self, LoadError> {
+		let entries = match serde_json::from_slice(json)? {
+			File::Current { auths } => auths,
+			File::Legacy(auths) => auths
+		};
+		let mut config = Self::default();
+		for (key, entry) in entries {
+			match entry.auth() {
+				Some(auth) => {
+					config.auths.insert(registry_host(&key).into(), auth);
+				},
+				None => config.unusable.push(key)
+			};
+		}
+		Ok(config)
+	}
+
+	pub fn unusable(&self) -> &[String] {
+		&self.unusable
+	}
+
+	/// The credentials for an upstream, looked up by its host and then by its namespace.
+	pub(super) fn get(&self, namespace: &str, host: &str) -> Option<&Auth> {
+		self.auths.get(registry_host(host)).or_else(|| self.auths.get(registry_host(namespace)))
+	}
+}
+
+/// Re-reads the file whenever its contents change, swapping the new credentials in for upstreams
+/// that take theirs from it.
+pub async fn watch(config: web::Data<RequestConfig>, path: Utf8PathBuf) {
+	let mut last = tokio::fs::read(&path).await.ok();
+	let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+	interval.tick().await;
+	loop {
+		interval.tick().await;
+		let contents = match tokio::fs::read(&path).await {
+			Ok(v) => v,
+			Err(error) => {
+				warn!(path = path.as_str(), %error, "Failed to read Docker config file; keeping the credentials we have");
+				continue;
+			}
+		};
+		if (last.as_ref() == Some(&contents)) {
+			continue;
+		}
+		match DockerConfig::parse(&contents) {
+			Ok(docker) => {
+				let changed = config.set_docker_config(docker).await;
+				info!(path = path.as_str(), upstreams = changed, "Reloaded credentials from Docker config file");
+				last = Some(contents);
+			},
+			// Likely caught halfway through being written; try again next time
+			Err(error) => warn!(path = path.as_str(), %error, "Failed to parse Docker config file; keeping the credentials we have")
+		};
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse() {
+		let auth = base64::engine::general_purpose::STANDARD.encode("hub-user:hub-pass");
+		let config = DockerConfig::parse(format!(r#"{{"auths":{{"https://index.docker.io/v1/":{{"auth":"{auth}"}},"ghcr.io":{{"username":"gh-user","password":"gh-pass"}},"quay.io":{{"identitytoken":"x"}}}}}}"#).as_bytes()).unwrap();
+		let hub = config.get("docker.io", "registry-1.docker.io").unwrap();
+		assert_eq!((hub.username.expose(), hub.password.expose()), ("hub-user", "hub-pass"));
+		let ghcr = config.get("ghcr", "ghcr.io").unwrap();
+		assert_eq!((ghcr.username.expose(), ghcr.password.expose()), ("gh-user", "gh-pass"));
+		assert!(config.get("quay.io", "quay.io").is_none());
+		assert_eq!(config.unusable(), ["quay.io"]);
+
+		// The legacy format is the same map, without `auths`
+		let legacy = DockerConfig::parse(br#"{"registry.example.com:5000":{"username":"u","password":"p"}}"#).unwrap();
+		assert!(legacy.get("example", "registry.example.com:5000").is_some());
+		assert!(DockerConfig::parse(b"[]").is_err());
+	}
+
+	#[test]
+	fn applied() {
+		use super::super::Client;
+		use super::super::Clients;
+		use super::super::SingleUpstreamConfig;
+
+		let configs: Vec<SingleUpstreamConfig> = serde_yaml::from_str("- namespace: ghcr.io\n  host: ghcr.io\n- namespace: quay.io\n  host: quay.io\n  username: me\n  password: mine").unwrap();
+		let mut clients = configs.into_iter().map(|c| (c.namespace.clone(), Client::try_from(c).unwrap())).collect::<Clients>();
+		let docker = DockerConfig::parse(br#"{"auths":{"ghcr.io":{"username":"u","password":"p"},"quay.io":{"username":"u","password":"p"}}}"#).unwrap();
+		assert_eq!(clients.set_docker_config(docker), 1);
+		assert!(clients.get("ghcr.io").unwrap().has_credentials());
+		// Configured credentials win
+		assert_eq!(clients.get("quay.io").unwrap().settings.username.as_ref().map(SecretString::expose), Some("me"));
+
+		// Gone from the file, they're gone from the upstream
+		assert_eq!(clients.set_docker_config(DockerConfig::parse(br#"{"auths":{}}"#).unwrap()), 1);
+		assert!(!clients.get("ghcr.io").unwrap().has_credentials());
+	}
+}
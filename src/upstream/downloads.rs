@@ -0,0 +1,127 @@
+use core::time::Duration;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge_vec;
+use prometheus::IntCounterVec;
+use prometheus::IntGaugeVec;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+static IN_PROGRESS: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("upstream_downloads_in_progress", "Number of blobs currently being downloaded from an upstream", &["namespace"]).unwrap());
+static QUEUED: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("upstream_downloads_queued", "Number of blob downloads from an upstream waiting for one in progress to finish", &["namespace"]).unwrap());
+static REJECTED: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_downloads_rejected", "Number of blob downloads from an upstream refused because too many were already waiting", &["namespace"]).unwrap());
+
+/// How long clients turned away by a full queue are told to wait before trying again.
+const RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Returned instead of queueing another download from an upstream that already has as many
+/// waiting as it's allowed.
+#[derive(Clone, Debug, thiserror::Error)]
+#[error("Too many downloads from upstream for namespace '{namespace}' are waiting; try again in {}", humantime::format_duration(*.retry_after))]
+pub struct DownloadQueueFull {
+	pub namespace: CompactString,
+	pub retry_after: Duration
+}
+
+/// Held for as long as a download from upstream is in progress.
+#[derive(Debug)]
+pub struct DownloadPermit {
+	namespace: CompactString,
+	_permit: OwnedSemaphorePermit
+}
+
+impl Drop for DownloadPermit {
+	fn drop(&mut self) {
+		IN_PROGRESS.with_label_values(&[self.namespace.as_str()]).dec();
+	}
+}
+
+/// Caps how many blobs are downloaded from a single upstream at once.  Downloads past the cap wait
+/// their turn, up to `queue_size` of them; past that, they're refused, so that a stampede of pulls
+/// neither hammers upstream nor piles up in memory here.
+#[derive(Debug)]
+pub struct DownloadLimit {
+	namespace: CompactString,
+	/// `None` when downloads aren't limited
+	semaphore: Option<Arc<Semaphore>>,
+	queue_size: usize,
+	queued: AtomicUsize
+}
+
+/// Counts a download as queued for as long as it's waiting, however the wait ends.
+struct Queued<'a>(&'a DownloadLimit);
+
+impl Drop for Queued<'_> {
+	fn drop(&mut self) {
+		self.0.queued.fetch_sub(1, Ordering::Relaxed);
+		QUEUED.with_label_values(&[self.0.namespace.as_str()]).dec();
+	}
+}
+
+impl DownloadLimit {
+	/// A `max_concurrent` of zero leaves downloads unlimited.
+	pub fn new(namespace: CompactString, max_concurrent: usize, queue_size: usize) -> Self {
+		IN_PROGRESS.with_label_values(&[namespace.as_str()]).set(0);
+		QUEUED.with_label_values(&[namespace.as_str()]).set(0);
+		let semaphore = (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent)));
+		Self { namespace, semaphore, queue_size, queued: AtomicUsize::new(0) }
+	}
+
+	/// How many downloads are waiting for a slot.
+	pub fn queued(&self) -> usize {
+		self.queued.load(Ordering::Relaxed)
+	}
+
+	/// Waits for a download slot, or fails right away if too many downloads are already waiting for
+	/// one.  Returns `None` if downloads aren't limited.
+	pub async fn acquire(&self) -> Result<Option<DownloadPermit>, DownloadQueueFull> {
+		let Some(semaphore) = self.semaphore.as_ref() else {
+			return Ok(None);
+		};
+		let permit = match semaphore.clone().try_acquire_owned() {
+			Ok(permit) => permit,
+			Err(_) => {
+				if (self.queued.fetch_add(1, Ordering::Relaxed) >= self.queue_size) {
+					self.queued.fetch_sub(1, Ordering::Relaxed);
+					REJECTED.with_label_values(&[self.namespace.as_str()]).inc();
+					warn!(namespace = self.namespace.as_str(), queue_size = self.queue_size, "Download queue is full; refusing download");
+					return Err(DownloadQueueFull { namespace: self.namespace.clone(), retry_after: RETRY_AFTER });
+				}
+				QUEUED.with_label_values(&[self.namespace.as_str()]).inc();
+				let _queued = Queued(self);
+				// The semaphore is never closed
+				semaphore.clone().acquire_owned().await.unwrap()
+			}
+		};
+		IN_PROGRESS.with_label_values(&[self.namespace.as_str()]).inc();
+		Ok(Some(DownloadPermit { namespace: self.namespace.clone(), _permit: permit }))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[actix_web::test]
+	async fn queue() {
+		let limit = Arc::new(DownloadLimit::new("downloads-test".into(), 1, 1));
+		let first = limit.acquire().await.unwrap().unwrap();
+		let waiting = actix_web::rt::spawn({
+			let limit = limit.clone();
+			async move { limit.acquire().await.map(|p| p.is_some()) }
+		});
+		actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+		// One in progress and one waiting; a third is turned away
+		assert!(limit.acquire().await.is_err());
+		drop(first);
+		assert!(waiting.await.unwrap().unwrap());
+
+		assert!(DownloadLimit::new("downloads-test-unlimited".into(), 0, 0).acquire().await.unwrap().is_none());
+	}
+}
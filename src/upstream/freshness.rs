@@ -0,0 +1,67 @@
+//! How long upstream says a manifest stays fresh, from the `Cache-Control` and `Expires` headers it
+//! serves it with, for upstreams set to `cache_control: true`.  We're a shared cache, so `s-maxage`
+//! wins over `max-age`, and either over `Expires`; `no-cache` and `no-store` make the manifest stale
+//! straight away, so that every pull of the tag checks with upstream whether it's moved.
+
+use core::time::Duration;
+
+use reqwest::header::HeaderMap;
+use reqwest::header::CACHE_CONTROL;
+use reqwest::header::DATE;
+use reqwest::header::EXPIRES;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+
+fn parse_date(value: &str) -> Option<OffsetDateTime> {
+	OffsetDateTime::parse(value.trim(), &Rfc2822).ok()
+}
+
+/// The freshness lifetime `headers` give, if they give one.
+pub fn lifetime(headers: &HeaderMap, now: OffsetDateTime) -> Option<Duration> {
+	let mut max_age = None;
+	let mut shared_max_age = None;
+	for directive in headers.get_all(CACHE_CONTROL).iter().filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')) {
+		let (name, value) = directive.split_once('=').map_or((directive, None), |(name, value)| (name, Some(value.trim().trim_matches('"'))));
+		match name.trim().to_ascii_lowercase().as_str() {
+			"no-cache" | "no-store" => return Some(Duration::ZERO),
+			"max-age" => max_age = value.and_then(|v| v.parse().ok()),
+			"s-maxage" => shared_max_age = value.and_then(|v| v.parse().ok()),
+			_ => ()
+		};
+	}
+	if let Some(seconds) = shared_max_age.or(max_age) {
+		return Some(Duration::from_secs(seconds));
+	}
+	// Relative to upstream's clock, if it says what that is
+	let expires = headers.get(EXPIRES)?.to_str().ok()?;
+	let Some(expires) = parse_date(expires) else {
+		// An invalid date means already expired
+		return Some(Duration::ZERO);
+	};
+	let date = headers.get(DATE).and_then(|v| v.to_str().ok()).and_then(parse_date).unwrap_or(now);
+	Some((expires - date).try_into().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+		pairs.iter().map(|(name, value)| (reqwest::header::HeaderName::from_static(name), reqwest::header::HeaderValue::from_static(value))).collect()
+	}
+
+	#[test]
+	fn lifetimes() {
+		let now = OffsetDateTime::UNIX_EPOCH;
+		assert_eq!(lifetime(&headers(&[]), now), None);
+		assert_eq!(lifetime(&headers(&[("cache-control", "public, max-age=300")]), now), Some(Duration::from_secs(300)));
+		assert_eq!(lifetime(&headers(&[("cache-control", "max-age=300, s-maxage=60")]), now), Some(Duration::from_secs(60)));
+		assert_eq!(lifetime(&headers(&[("cache-control", "private, no-cache"), ("expires", "Thu, 01 Jan 1970 01:00:00 GMT")]), now), Some(Duration::ZERO));
+		assert_eq!(lifetime(&headers(&[("cache-control", "public")]), now), None);
+
+		assert_eq!(lifetime(&headers(&[("expires", "Thu, 01 Jan 1970 01:00:00 GMT")]), now), Some(Duration::from_secs(3600)));
+		assert_eq!(lifetime(&headers(&[("expires", "Thu, 01 Jan 1970 01:00:00 GMT"), ("date", "Thu, 01 Jan 1970 00:30:00 GMT")]), now), Some(Duration::from_secs(1800)));
+		assert_eq!(lifetime(&headers(&[("expires", "Thu, 01 Jan 1970 01:00:00 GMT"), ("date", "Thu, 01 Jan 1970 02:00:00 GMT")]), now), Some(Duration::ZERO));
+		assert_eq!(lifetime(&headers(&[("expires", "0")]), now), Some(Duration::ZERO));
+	}
+}
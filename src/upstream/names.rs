@@ -0,0 +1,96 @@
+//! Image name rules:  changes made, per namespace, to the image names clients ask for, for
+//! registries that name things differently than clients do, say by a project prefix.  What they
+//! give is the image's canonical name, which is what's cached, so that the names an image can be
+//! pulled by share one copy of it, and what's asked of upstream, by way of its profile and
+//! `path_prefix` as usual.
+
+use std::borrow::Cow;
+
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_with::serde_as;
+use serde_with::DisplayFromStr;
+
+use crate::validate::Report;
+
+#[serde_as]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "rule", rename_all = "kebab-case")]
+pub enum NameRule {
+	/// Drops `prefix` from names that start with it, unless that leaves nothing
+	StripPrefix { prefix: String },
+	/// Adds `prefix` to names that don't already start with it
+	AddPrefix { prefix: String },
+	/// Replaces the first match of `pattern` with `replacement`, which can refer to the pattern's
+	/// groups as `$1` or `${name}`
+	Regex {
+		#[serde_as(as = "DisplayFromStr")]
+		pattern: Regex,
+		replacement: String
+	}
+}
+
+impl NameRule {
+	pub fn validate(&self, namespace: &str, report: &mut Report) {
+		match self {
+			Self::StripPrefix { prefix } | Self::AddPrefix { prefix } if prefix.is_empty() => report.error(namespace, "image name rule has an empty prefix"),
+			Self::StripPrefix { prefix } | Self::AddPrefix { prefix } if prefix.starts_with('/') => report.error(namespace, format!("image name prefix {prefix} starts with /")),
+			Self::StripPrefix { .. } | Self::AddPrefix { .. } => (),
+			Self::Regex { pattern, .. } if pattern.as_str().is_empty() => report.warn(namespace, "image name regex is empty, and matches every name"),
+			Self::Regex { .. } => ()
+		}
+	}
+
+	fn apply<'a>(&self, image: Cow<'a, str>) -> Cow<'a, str> {
+		let changed = match self {
+			Self::StripPrefix { prefix } => image.strip_prefix(prefix.as_str()).filter(|rest| !rest.is_empty()).map(str::to_owned),
+			Self::AddPrefix { prefix } => match image.starts_with(prefix.as_str()) {
+				true => None,
+				false => Some(format!("{prefix}{image}"))
+			},
+			Self::Regex { pattern, replacement } => match pattern.replace(&image, replacement.as_str()) {
+				Cow::Borrowed(_) => None,
+				Cow::Owned(replaced) => Some(replaced)
+			}
+		};
+		match changed {
+			Some(changed) => Cow::Owned(changed),
+			None => image
+		}
+	}
+}
+
+/// `image` under its canonical name:  as changed by each of `rules` in turn.  Borrowed if none of
+/// them changed it.
+pub fn canonical<'a>(rules: &[NameRule], image: &'a str) -> Cow<'a, str> {
+	rules.iter().fold(Cow::Borrowed(image), |image, rule| rule.apply(image))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rules(yaml: &str) -> Vec<NameRule> {
+		serde_yaml::from_str(yaml).unwrap()
+	}
+
+	#[test]
+	fn prefixes() {
+		let rules = rules("[{rule: strip-prefix, prefix: library/}, {rule: add-prefix, prefix: platform/}]");
+		assert_eq!(canonical(&rules, "library/alpine"), "platform/alpine");
+		assert_eq!(canonical(&rules, "alpine"), "platform/alpine");
+		assert_eq!(canonical(&rules, "platform/alpine"), "platform/alpine");
+		assert!(matches!(canonical(&rules, "platform/alpine"), Cow::Borrowed(_)));
+		// A name that's nothing but the prefix is left alone
+		assert_eq!(canonical(&rules[..1], "library/"), "library/");
+	}
+
+	#[test]
+	fn regex() {
+		let rules = rules(r#"[{rule: regex, pattern: "^team-([a-z]+)/", replacement: "teams/$1/"}]"#);
+		assert_eq!(canonical(&rules, "team-infra/nginx"), "teams/infra/nginx");
+		assert!(matches!(canonical(&rules, "teams/infra/nginx"), Cow::Borrowed(_)));
+		assert!(serde_yaml::from_str::<Vec<NameRule>>("[{rule: regex, pattern: '(', replacement: x}]").is_err());
+	}
+}
@@ -0,0 +1,283 @@
+use std::borrow::Cow;
+use std::iter;
+
+use actix_web::http::StatusCode;
+use compact_str::CompactString;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::ProbeMethod;
+use super::SingleUpstreamConfig;
+
+/// Built-in knowledge about registries whose behavior differs from the distribution spec, or from
+/// docker/distribution's interpretation of it.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+	/// A registry that behaves like docker/distribution
+	#[default]
+	Generic,
+	DockerHub,
+	Quay,
+	Ghcr,
+	/// Google Container Registry and Artifact Registry
+	Gcr,
+	Gitlab,
+	/// JFrog Artifactory, which serves each Docker repository under a path prefix
+	Artifactory
+}
+
+impl Profile {
+	/// Guesses a profile from an upstream's hostname.
+	pub fn detect(host: &str) -> Self {
+		let host = host.split(':').next().unwrap_or(host).to_ascii_lowercase();
+		match host.as_str() {
+			"docker.io" | "index.docker.io" | "registry-1.docker.io" => Self::DockerHub,
+			"quay.io" => Self::Quay,
+			"ghcr.io" => Self::Ghcr,
+			"gcr.io" => Self::Gcr,
+			"registry.gitlab.com" => Self::Gitlab,
+			_ if host.ends_with(".gcr.io") || host.ends_with("-docker.pkg.dev") => Self::Gcr,
+			_ if host.starts_with("registry.gitlab.") => Self::Gitlab,
+			_ if host.ends_with(".jfrog.io") => Self::Artifactory,
+			_ => Self::Generic
+		}
+	}
+
+	/// The host to contact for a namespace that wasn't configured explicitly.
+	pub fn default_host(self, namespace: &str) -> CompactString {
+		match self {
+			Self::DockerHub => "registry-1.docker.io".into(),
+			_ => namespace.into()
+		}
+	}
+
+	/// Whether requests to this registry only work with a path prefix in front of the image name,
+	/// such as an Artifactory repository key.
+	pub fn requires_path_prefix(self) -> bool {
+		self == Self::Artifactory
+	}
+
+	/// How to ask this registry about an object without fetching it, unless configured otherwise.
+	pub fn probe_method(self) -> ProbeMethod {
+		match self {
+			// Remote repositories don't always go to their own upstream for a `HEAD`, answering 404
+			// for manifests they haven't cached yet
+			Self::Artifactory => ProbeMethod::RangedGet,
+			// Docker Hub doesn't count `HEAD`s against pull rate limits, where it counts `GET`s
+			_ => ProbeMethod::Head
+		}
+	}
+
+	/// Maps a status returned by this registry onto what the distribution spec would have it
+	/// return.  `anonymous` is whether the request carried no credentials at all.
+	pub fn normalize_status(self, status: StatusCode, anonymous: bool) -> StatusCode {
+		match (self, status) {
+			// GHCR answers 403 where other registries answer 401, even for anonymous requests that
+			// just need a token
+			(Self::Ghcr, StatusCode::FORBIDDEN) => StatusCode::UNAUTHORIZED,
+			// Quay answers anonymous requests for repositories that don't exist with a 401; since
+			// there are no credentials we could retry with, that's a 404 as far as we're concerned
+			(Self::Quay, StatusCode::UNAUTHORIZED) if anonymous => StatusCode::NOT_FOUND,
+			_ => status
+		}
+	}
+
+	/// Maps the image name a client asked for onto the one this registry knows it by.
+	pub fn upstream_image<'a>(self, path_prefix: Option<&str>, image: &'a str) -> Cow<'a, str> {
+		let image = match self {
+			// Docker Hub only knows official images by their library/ name
+			Self::DockerHub if !image.contains('/') => Cow::Owned(format!("library/{image}")),
+			_ => Cow::Borrowed(image)
+		};
+		match path_prefix.map(|p| p.trim_matches('/')) {
+			Some(prefix) if !prefix.is_empty() => Cow::Owned(format!("{prefix}/{image}")),
+			_ => image
+		}
+	}
+
+	/// The image names a client could have asked for to get `upstream_image`; the inverse of
+	/// [`Self::upstream_image`].  Empty if it's outside the path prefix.
+	pub fn local_images(self, path_prefix: Option<&str>, upstream_image: &str) -> Vec<String> {
+		let image = match path_prefix.map(|p| p.trim_matches('/')) {
+			Some(prefix) if !prefix.is_empty() => match upstream_image.strip_prefix(prefix).and_then(|i| i.strip_prefix('/')) {
+				Some(v) => v,
+				None => return Vec::new()
+			},
+			_ => upstream_image
+		};
+		match (self, image.strip_prefix("library/")) {
+			(Self::DockerHub, Some(short)) if !short.contains('/') => vec![image.to_owned(), short.to_owned()],
+			_ => vec![image.to_owned()]
+		}
+	}
+}
+
+/// Rewrites a `WWW-Authenticate` challenge so that its scope is pull access to `image`.  Registries
+/// answering `/v2/` don't know which repository the client is after, so they either leave the
+/// scope out or, like GHCR, fill in a placeholder.
+pub fn scoped_challenge(challenge: &str, image: &str) -> String {
+	let Some((scheme, params)) = challenge.trim().split_once(' ') else {
+		return challenge.to_owned();
+	};
+	if (!scheme.eq_ignore_ascii_case("bearer")) {
+		return challenge.to_owned();
+	}
+	let scope = format!(r#"scope="repository:{image}:pull""#);
+	let params = split_params(params)
+		.into_iter()
+		.filter(|p| !p.get(..6).is_some_and(|k| k.eq_ignore_ascii_case("scope=")))
+		.chain(iter::once(scope.as_str()))
+		.collect::<Vec<_>>();
+	format!("{scheme} {}", params.join(","))
+}
+
+/// The token endpoint and service named by a `Bearer` challenge, for requesting a token with a
+/// scope of our own choosing.
+pub fn bearer_realm(challenge: &str) -> Option<(String, Option<String>)> {
+	let (scheme, params) = challenge.trim().split_once(' ')?;
+	if (!scheme.eq_ignore_ascii_case("bearer")) {
+		return None;
+	}
+	let mut realm = None;
+	let mut service = None;
+	for param in split_params(params) {
+		let Some((key, value)) = param.split_once('=') else {
+			continue;
+		};
+		let value = value.trim().trim_matches('"').to_owned();
+		match key.trim().to_ascii_lowercase().as_str() {
+			"realm" => realm = Some(value),
+			"service" => service = Some(value),
+			_ => ()
+		};
+	}
+	Some((realm?, service))
+}
+
+/// A token from upstream's token endpoint; registries differ on which of these they fill in.
+#[derive(Debug, Deserialize)]
+pub struct Token {
+	token: Option<String>,
+	access_token: Option<String>
+}
+
+impl Token {
+	pub fn value(self) -> Option<String> {
+		self.token.or(self.access_token)
+	}
+}
+
+/// Splits auth-params on commas that aren't inside a quoted string.
+fn split_params(params: &str) -> Vec<&str> {
+	let mut out = Vec::new();
+	let mut start = 0;
+	let mut quoted = false;
+	for (i, c) in params.char_indices() {
+		match c {
+			'"' => quoted = !quoted,
+			',' if !quoted => {
+				out.push(params[start..i].trim());
+				start = i + 1;
+			},
+			_ => ()
+		}
+	}
+	out.push(params[start..].trim());
+	out.retain(|p| !p.is_empty());
+	out
+}
+
+/// Maps a namespace that isn't in the upstream config file onto an upstream configuration.
+pub trait Resolver: Send + Sync {
+	fn resolve(&self, namespace: &str) -> Option<SingleUpstreamConfig>;
+}
+
+/// Treats the namespace as a hostname, applying the built-in profile for well-known registries.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultResolver;
+
+impl Resolver for DefaultResolver {
+	fn resolve(&self, namespace: &str) -> Option<SingleUpstreamConfig> {
+		let profile = Profile::detect(namespace);
+		Some(SingleUpstreamConfig {
+			profile: Some(profile),
+			..SingleUpstreamConfig::with_host(namespace.into(), profile.default_host(namespace))
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn detect() {
+		assert_eq!(Profile::detect("docker.io"), Profile::DockerHub);
+		assert_eq!(Profile::detect("registry-1.docker.io"), Profile::DockerHub);
+		assert_eq!(Profile::detect("quay.io"), Profile::Quay);
+		assert_eq!(Profile::detect("ghcr.io"), Profile::Ghcr);
+		assert_eq!(Profile::detect("us.gcr.io"), Profile::Gcr);
+		assert_eq!(Profile::detect("europe-west1-docker.pkg.dev"), Profile::Gcr);
+		assert_eq!(Profile::detect("registry.gitlab.example.com:5050"), Profile::Gitlab);
+		assert_eq!(Profile::detect("example.jfrog.io"), Profile::Artifactory);
+		assert_eq!(Profile::detect("registry.example.com"), Profile::Generic);
+	}
+
+	#[test]
+	fn probe_methods() {
+		assert_eq!(Profile::DockerHub.probe_method(), ProbeMethod::Head);
+		assert_eq!(Profile::Artifactory.probe_method(), ProbeMethod::RangedGet);
+		assert_eq!(Profile::Generic.probe_method(), ProbeMethod::Head);
+	}
+
+	#[test]
+	fn upstream_image() {
+		assert_eq!(Profile::DockerHub.upstream_image(None, "busybox"), "library/busybox");
+		assert_eq!(Profile::DockerHub.upstream_image(None, "grafana/mimirtool"), "grafana/mimirtool");
+		assert_eq!(Profile::Artifactory.upstream_image(Some("docker-remote/"), "envoyproxy/envoy"), "docker-remote/envoyproxy/envoy");
+		assert_eq!(Profile::Generic.upstream_image(None, "busybox"), "busybox");
+	}
+
+	#[test]
+	fn local_images() {
+		assert_eq!(Profile::DockerHub.local_images(None, "library/busybox"), ["library/busybox", "busybox"]);
+		assert_eq!(Profile::DockerHub.local_images(None, "grafana/mimirtool"), ["grafana/mimirtool"]);
+		assert_eq!(Profile::Artifactory.local_images(Some("docker-remote/"), "docker-remote/envoyproxy/envoy"), ["envoyproxy/envoy"]);
+		assert!(Profile::Artifactory.local_images(Some("docker-remote"), "other-remote/envoyproxy/envoy").is_empty());
+		assert_eq!(Profile::Generic.local_images(None, "library/busybox"), ["library/busybox"]);
+	}
+
+	// Recorded from each registry's response to an anonymous `GET /v2/`
+	const DOCKER_HUB_CHALLENGE: &str = r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io""#;
+	const GHCR_CHALLENGE: &str = r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:user/image:pull""#;
+	const QUAY_CHALLENGE: &str = r#"Bearer realm="https://quay.io/v2/auth",service="quay.io""#;
+
+	#[test]
+	fn challenge_scope() {
+		assert_eq!(scoped_challenge(DOCKER_HUB_CHALLENGE, "library/busybox"), r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/busybox:pull""#);
+		assert_eq!(scoped_challenge(GHCR_CHALLENGE, "buildbarn/bb-runner-installer"), r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:buildbarn/bb-runner-installer:pull""#);
+		assert_eq!(scoped_challenge(QUAY_CHALLENGE, "prometheus/node-exporter"), r#"Bearer realm="https://quay.io/v2/auth",service="quay.io",scope="repository:prometheus/node-exporter:pull""#);
+		assert_eq!(scoped_challenge(r#"Bearer realm="https://example.com/token",scope="repository:a/b:pull,push",service="example.com""#, "c/d"), r#"Bearer realm="https://example.com/token",service="example.com",scope="repository:c/d:pull""#);
+		assert_eq!(scoped_challenge(r#"Basic realm="Registry""#, "c/d"), r#"Basic realm="Registry""#);
+	}
+
+	#[test]
+	fn bearer_realms() {
+		assert_eq!(bearer_realm(DOCKER_HUB_CHALLENGE), Some(("https://auth.docker.io/token".to_owned(), Some("registry.docker.io".to_owned()))));
+		assert_eq!(bearer_realm(GHCR_CHALLENGE), Some(("https://ghcr.io/token".to_owned(), Some("ghcr.io".to_owned()))));
+		assert_eq!(bearer_realm(r#"Bearer realm="https://example.com/token?a=b,c""#), Some(("https://example.com/token?a=b,c".to_owned(), None)));
+		assert_eq!(bearer_realm(r#"Basic realm="Registry""#), None);
+		assert_eq!(bearer_realm(r#"Bearer service="example.com""#), None);
+	}
+
+	#[test]
+	fn status_quirks() {
+		// GHCR's answer to an anonymous pull without a token
+		assert_eq!(Profile::Ghcr.normalize_status(StatusCode::FORBIDDEN, true), StatusCode::UNAUTHORIZED);
+		// Quay's answer to an anonymous pull of a repository that doesn't exist
+		assert_eq!(Profile::Quay.normalize_status(StatusCode::UNAUTHORIZED, true), StatusCode::NOT_FOUND);
+		assert_eq!(Profile::Quay.normalize_status(StatusCode::UNAUTHORIZED, false), StatusCode::UNAUTHORIZED);
+		assert_eq!(Profile::Generic.normalize_status(StatusCode::FORBIDDEN, true), StatusCode::FORBIDDEN);
+	}
+}
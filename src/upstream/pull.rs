@@ -0,0 +1,223 @@
+//! Pulls from upstream, over the proxy's own HTTP client, so that they carry the headers configured
+//! for upstream like everything else the proxy sends it.  Pull tokens are taken the way dkregistry
+//! took them:  ahead of the pull, from wherever upstream's challenge on `/v2/` says, with whoever
+//! the request pulls as, and kept on the request's copy of the client for the rest of its pulls.
+
+use core::fmt;
+
+use bytes::Bytes;
+use bytes::BytesMut;
+use dkregistry::errors::Error;
+use dkregistry::mediatypes::MediaTypes;
+use futures::stream::Stream;
+use futures::stream::TryStreamExt;
+use reqwest::header::ACCEPT;
+use reqwest::header::HeaderName;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::header::RANGE;
+use reqwest::header::WWW_AUTHENTICATE;
+use reqwest::Method;
+use reqwest::RequestBuilder;
+
+use super::profile;
+use super::Client;
+use super::ProbeMethod;
+use super::MANIFEST_TYPES;
+use crate::api::auth::Credentials;
+use crate::api::trace;
+
+/// What pulled manifests are accepted as:  what they're asked about as, and schema 1, which is
+/// converted where it's allowed at all
+const PULLED_MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, application/vnd.oci.image.manifest.v1+json, application/vnd.docker.distribution.manifest.list.v2+json, application/vnd.docker.distribution.manifest.v2+json, application/vnd.docker.distribution.manifest.v1+prettyjws, application/vnd.docker.distribution.manifest.v1+json";
+
+/// How many tags are asked for at a time when listing them all
+const TAGS_PAGE_SIZE: usize = 1000;
+
+/// How requests of upstream authenticate.
+#[derive(Clone)]
+pub enum Authorization {
+	None,
+	Bearer(String),
+	Basic(String, Option<String>)
+}
+
+impl Authorization {
+	pub fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+		match self {
+			Self::None => request,
+			Self::Bearer(token) => request.bearer_auth(token),
+			Self::Basic(username, password) => request.basic_auth(username, password.as_ref())
+		}
+	}
+}
+
+/// Kept on every request's copy of the client, which gets logged; the token and password aren't.
+impl fmt::Debug for Authorization {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::None => f.write_str("None"),
+			Self::Bearer(_) => f.write_str("Bearer(REDACTED)"),
+			Self::Basic(username, _) => f.debug_tuple("Basic").field(username).finish()
+		}
+	}
+}
+
+/// Who a request's pulls authenticate as.
+#[derive(Clone, Debug)]
+pub enum Identity {
+	/// The configured credentials, if there are any
+	Configured,
+	/// No one, after upstream refused the configured credentials
+	Anonymous,
+	/// The credentials a client passed through
+	Client(Credentials)
+}
+
+/// What upstream sent for a manifest.
+pub enum PulledManifest {
+	/// The manifest, the media type it was served as, and the digest upstream gives it, if it does
+	Manifest(Bytes, MediaTypes, Option<String>),
+	/// Word that it's bigger than the pull would take:  how big upstream said it was, or how much
+	/// of it had been read when it went over
+	TooLarge(u64)
+}
+
+/// A blob upstream has started sending.
+pub struct Blob(reqwest::Response);
+
+impl Blob {
+	/// How big upstream says the blob is.
+	pub fn size(&self) -> Option<u64> {
+		self.0.content_length()
+	}
+
+	pub fn stream(self) -> impl Stream<Item = Result<Bytes, crate::storage::Error>> {
+		self.0.bytes_stream().map_err(|e| crate::storage::Error::from(Error::Reqwest(e)))
+	}
+}
+
+impl Client {
+	/// Makes the rest of this request's pulls with credentials a client passed through.
+	pub fn pull_as(&mut self, credentials: &Credentials) {
+		self.identity = Identity::Client(credentials.clone());
+		self.authorization = Authorization::None;
+	}
+
+	/// Makes the rest of this request's pulls anonymously.
+	pub fn pull_anonymously(&mut self) {
+		self.identity = Identity::Anonymous;
+		self.authorization = Authorization::None;
+	}
+
+	/// The username and password pulls are made with, if there are any.
+	fn pull_credentials(&self) -> Option<(&str, Option<&str>)> {
+		match &self.identity {
+			Identity::Configured => self.settings.username.as_ref().map(|username| (username.expose(), self.settings.password.as_ref().map(|p| p.expose()))),
+			Identity::Anonymous => None,
+			Identity::Client(credentials) => Some((credentials.username.as_str(), Some(credentials.password.expose())))
+		}
+	}
+
+	/// Takes a token for `scope` where upstream hands those out, or otherwise settles on basic auth
+	/// with the credentials pulls are made with, if there are any.
+	pub async fn authenticate(&mut self, scope: &str) -> Result<(), Error> {
+		let response = trace::inject(self.http.get(format!("{}/v2/", self.base_url)), &self.hop).send().await.map_err(Error::Reqwest)?;
+		let challenge = response.headers().get(WWW_AUTHENTICATE).and_then(|v| v.to_str().ok());
+		let authorization = match (challenge.and_then(profile::bearer_realm), self.pull_credentials()) {
+			(Some((realm, service)), credentials) => {
+				let mut request = trace::inject(self.http.get(realm), &self.hop).query(&[("scope", scope)]);
+				if let Some(service) = service {
+					request = request.query(&[("service", service)]);
+				}
+				if let Some((username, password)) = credentials {
+					request = request.basic_auth(username, password);
+				}
+				let response = request.send().await.map_err(Error::Reqwest)?;
+				if (!response.status().is_success()) {
+					return Err(Error::UnexpectedHttpStatus(response.status()));
+				}
+				let token: profile::Token = response.json().await.map_err(Error::Reqwest)?;
+				Authorization::Bearer(token.value().unwrap_or_default())
+			},
+			(None, Some((username, password))) => Authorization::Basic(username.to_owned(), password.map(str::to_owned)),
+			(None, None) => Authorization::None
+		};
+		self.authorization = authorization;
+		Ok(())
+	}
+
+	/// Asks upstream for `path`, under `/v2/`, as this request authenticated, with the `ns`
+	/// parameter if it's given.  Anything but a success is an error.
+	async fn pull(&self, method: Method, path: &str, ns: Option<&str>, headers: &[(HeaderName, &str)]) -> Result<reqwest::Response, Error> {
+		let request = self.authorization.apply(self.http.request(method, format!("{}/v2/{path}", self.base_url)));
+		let mut request = trace::inject(request, &self.hop);
+		if let Some(ns) = ns {
+			request = request.query(&[("ns", ns)]);
+		}
+		for (name, value) in headers {
+			request = request.header(name.clone(), *value);
+		}
+		let response = request.send().await.map_err(Error::Reqwest)?;
+		match response.status().is_success() {
+			true => Ok(response),
+			false => Err(Error::UnexpectedHttpStatus(response.status()))
+		}
+	}
+
+	/// Pulls the manifest `reference` points at, unless it's over `max_size` bytes; that's given up
+	/// on as soon as upstream says it is, or has sent more than that.
+	pub async fn get_manifest(&self, image: &str, reference: &str, ns: Option<&str>, max_size: usize) -> Result<PulledManifest, Error> {
+		let mut response = self.pull(Method::GET, &format!("{image}/manifests/{reference}"), ns, &[(ACCEPT, PULLED_MANIFEST_TYPES)]).await?;
+		if let Some(size) = response.content_length().filter(|size| *size > max_size as u64) {
+			return Ok(PulledManifest::TooLarge(size));
+		}
+		let content_type = response.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).and_then(|v| v.split(';').next());
+		// The manifest's own mediaType is what it's served as, where it has one
+		let media_type = content_type.and_then(|v| v.trim().parse().ok()).unwrap_or(MediaTypes::ApplicationJson);
+		let digest = response.headers().get("docker-content-digest").and_then(|v| v.to_str().ok()).map(str::to_owned);
+		let mut manifest = BytesMut::new();
+		while let Some(chunk) = response.chunk().await.map_err(Error::Reqwest)? {
+			manifest.extend_from_slice(&chunk);
+			if (manifest.len() > max_size) {
+				return Ok(PulledManifest::TooLarge(manifest.len() as u64));
+			}
+		}
+		Ok(PulledManifest::Manifest(manifest.freeze(), media_type, digest))
+	}
+
+	/// The digest upstream says the manifest `reference` points at, asked for with a `HEAD`.
+	pub async fn head_manifest(&self, image: &str, reference: &str) -> Result<Option<String>, Error> {
+		let ns = self.namespace_query().map(|(_, ns)| ns);
+		let response = self.pull(Method::HEAD, &format!("{image}/manifests/{reference}"), ns.as_deref(), &[(ACCEPT, MANIFEST_TYPES)]).await?;
+		Ok(response.headers().get("docker-content-digest").and_then(|v| v.to_str().ok()).map(str::to_owned))
+	}
+
+	/// Starts pulling a blob.
+	pub async fn get_blob(&self, image: &str, digest: &str, ns: Option<&str>) -> Result<Blob, Error> {
+		self.pull(Method::GET, &format!("{image}/blobs/{digest}"), ns, &[]).await.map(Blob)
+	}
+
+	/// Asks upstream about a blob the way it's configured to be probed, without pulling it, for
+	/// whether this request would be let have it.  Token-only probes don't ask at all.
+	pub async fn probe_blob(&self, image: &str, digest: &str, ns: Option<&str>) -> Result<(), Error> {
+		let path = format!("{image}/blobs/{digest}");
+		match self.probe {
+			ProbeMethod::Head => self.pull(Method::HEAD, &path, ns, &[]).await.map(drop),
+			ProbeMethod::RangedGet => self.pull(Method::GET, &path, ns, &[(RANGE, "bytes=0-0")]).await.map(drop),
+			ProbeMethod::Token => Ok(())
+		}
+	}
+
+	/// All of upstream's tags for `image`, a page at a time.
+	pub async fn tags(&self, image: &str) -> Result<Vec<String>, Error> {
+		let mut tags = Vec::new();
+		loop {
+			let (page, more) = self.tags_page(image, TAGS_PAGE_SIZE, tags.last().map(String::as_str)).await?;
+			let done = !more || page.is_empty();
+			tags.extend(page);
+			if (done) {
+				return Ok(tags);
+			}
+		}
+	}
+}
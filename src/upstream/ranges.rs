@@ -0,0 +1,181 @@
+use core::ops::Range;
+use std::rc::Rc;
+
+use bytes::Bytes;
+use futures::stream;
+use futures::stream::LocalBoxStream;
+use futures::stream::StreamExt;
+use reqwest::header::CONTENT_RANGE;
+use reqwest::header::RANGE;
+use reqwest::header::WWW_AUTHENTICATE;
+use reqwest::StatusCode;
+use tracing::debug;
+use tracing::warn;
+
+use super::profile;
+use super::pull::Authorization;
+use super::Client;
+use crate::api::trace;
+use crate::api::trace::Hop;
+
+/// How many times a range that fails is asked for before the whole blob fails
+const ATTEMPTS: usize = 2;
+
+/// How large blobs are fetched from upstream:  in ranges, several at once, and put back together in
+/// order, so that a single slow connection over a high-latency link isn't what limits a pull.
+#[derive(Clone, Copy, Debug)]
+pub struct RangeFetch {
+	parallelism: usize,
+	chunk_size: u64
+}
+
+impl RangeFetch {
+	pub fn new(parallelism: usize, chunk_size: u64) -> Self {
+		Self { parallelism, chunk_size: chunk_size.max(1) }
+	}
+
+	/// Whether a blob of `size` bytes is fetched in ranges.
+	pub fn applies(&self, size: u64) -> bool {
+		self.parallelism > 1 && size > self.chunk_size
+	}
+}
+
+/// The ranges a blob of `size` bytes is fetched in.
+fn ranges(size: u64, chunk_size: u64) -> Vec<Range<u64>> {
+	(0..size).step_by(chunk_size as usize).map(|start| start..size.min(start + chunk_size)).collect()
+}
+
+struct Fetcher {
+	http: reqwest::Client,
+	url: String,
+	authorization: Authorization,
+	size: u64,
+	hop: Hop
+}
+
+impl Fetcher {
+	async fn get(&self, range: &Range<u64>) -> Result<reqwest::Response, reqwest::Error> {
+		self.send(&format!("bytes={}-{}", range.start, range.end - 1)).await
+	}
+
+	async fn send(&self, range: &str) -> Result<reqwest::Response, reqwest::Error> {
+		let request = self.http.get(&self.url).header(RANGE, range);
+		trace::inject(self.authorization.apply(request), &self.hop).send().await
+	}
+
+	/// Reads a range that upstream has answered with, if that's what it answered with.
+	async fn read(&self, response: reqwest::Response, range: &Range<u64>) -> Result<Bytes, std::io::Error> {
+		let expected = format!("bytes {}-{}/{}", range.start, range.end - 1, self.size);
+		let content_range = response.headers().get(CONTENT_RANGE).and_then(|v| v.to_str().ok());
+		if (response.status() != StatusCode::PARTIAL_CONTENT || content_range != Some(expected.as_str())) {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Upstream answered a request for {expected} with {} {content_range:?}", response.status())));
+		}
+		let body = response.bytes().await.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+		match body.len() as u64 == range.end - range.start {
+			true => Ok(body),
+			false => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, format!("Upstream sent {} bytes for {expected}", body.len())))
+		}
+	}
+
+	async fn fetch(&self, range: Range<u64>) -> Result<Bytes, crate::storage::Error> {
+		let mut attempt = 1;
+		loop {
+			let result = match self.get(&range).await {
+				Ok(response) => self.read(response, &range).await,
+				Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+			};
+			match result {
+				Err(error) if attempt < ATTEMPTS => {
+					warn!(url = self.url, start = range.start, end = range.end, %error, "Failed to fetch blob range from upstream; trying again");
+					attempt += 1;
+				},
+				result => return Ok(result?)
+			};
+		}
+	}
+}
+
+impl Client {
+	/// How to authenticate requests for `image`'s blobs, if upstream's answer to one says it wants
+	/// that:  a pull token, taken with the configured credentials unless `anonymous`, or for
+	/// registries that don't issue tokens, the credentials themselves.
+	async fn blob_authorization(&self, response: &reqwest::Response, image: &str, anonymous: bool, hop: &Hop) -> Option<Authorization> {
+		let challenge = response.headers().get(WWW_AUTHENTICATE)?.to_str().ok()?;
+		let credentials = self.settings.username.as_ref().filter(|_| !anonymous).map(|u| (u.expose().to_owned(), self.settings.password.as_ref().map(|p| p.expose().to_owned())));
+		let Some((realm, service)) = profile::bearer_realm(challenge) else {
+			return credentials.map(|(username, password)| Authorization::Basic(username, password));
+		};
+		let mut request = trace::inject(self.http.get(realm), hop).query(&[("scope", format!("repository:{image}:pull"))]);
+		if let Some(service) = service {
+			request = request.query(&[("service", service)]);
+		}
+		if let Some((username, password)) = credentials {
+			request = request.basic_auth(username, password);
+		}
+		let body = request.send().await.ok()?.error_for_status().ok()?.bytes().await.ok()?;
+		let token: profile::Token = serde_json::from_slice(&body).ok()?;
+		Some(Authorization::Bearer(token.value()?))
+	}
+
+	/// Streams a blob of `size` bytes from upstream in ranges fetched in parallel, as configured,
+	/// once upstream has answered the first range with just that range.  `None` if it didn't, or
+	/// the blob couldn't be fetched this way at all; it's fetched whole instead.
+	pub async fn fetch_ranges(&self, image: &str, digest: &str, size: u64, anonymous: bool, hop: &Hop) -> Option<LocalBoxStream<'static, Result<Bytes, crate::storage::Error>>> {
+		let mut ranges = ranges(size, self.ranged.chunk_size).into_iter();
+		let first = ranges.next()?;
+		let mut fetcher = Fetcher { http: self.http.clone(), url: format!("{}/v2/{image}/blobs/{digest}", self.base_url), authorization: Authorization::None, size, hop: hop.clone() };
+		let mut response = fetcher.get(&first).await.ok()?;
+		if (response.status() == StatusCode::UNAUTHORIZED) {
+			fetcher.authorization = self.blob_authorization(&response, image, anonymous, hop).await?;
+			response = fetcher.get(&first).await.ok()?;
+		}
+		let first = match fetcher.read(response, &first).await {
+			Ok(v) => v,
+			Err(error) => {
+				debug!(namespace = self.namespace.as_str(), image, digest, %error, "Upstream didn't serve a range of the blob; fetching it whole");
+				return None;
+			}
+		};
+		let fetcher = Rc::new(fetcher);
+		let rest = stream::iter(ranges).map(move |range| {
+			let fetcher = fetcher.clone();
+			async move { fetcher.fetch(range).await }
+		});
+		Some(stream::once(async move { Ok(first) }).chain(rest.buffered(self.ranged.parallelism)).boxed_local())
+	}
+
+	/// Asks upstream for the part of a blob that a client's `Range` header asks for, as it has it,
+	/// for lazy pulls that read a blob a piece at a time.  `None` unless upstream answers with just
+	/// a part.
+	pub async fn fetch_part(&self, image: &str, digest: &str, range: &str, anonymous: bool, hop: &Hop) -> Option<reqwest::Response> {
+		let mut fetcher = Fetcher { http: self.http.clone(), url: format!("{}/v2/{image}/blobs/{digest}", self.base_url), authorization: Authorization::None, size: 0, hop: hop.clone() };
+		let mut response = fetcher.send(range).await.ok()?;
+		if (response.status() == StatusCode::UNAUTHORIZED) {
+			fetcher.authorization = self.blob_authorization(&response, image, anonymous, hop).await?;
+			response = fetcher.send(range).await.ok()?;
+		}
+		match (response.status(), response.headers().contains_key(CONTENT_RANGE)) {
+			(StatusCode::PARTIAL_CONTENT, true) => Some(response),
+			(status, _) => {
+				debug!(namespace = self.namespace.as_str(), image, digest, range, %status, "Upstream didn't serve part of the blob");
+				None
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn blob_ranges() {
+		assert_eq!(ranges(10, 4), vec![0..4, 4..8, 8..10]);
+		assert_eq!(ranges(8, 4), vec![0..4, 4..8]);
+		assert!(ranges(0, 4).is_empty());
+		let fetch = RangeFetch::new(4, 4);
+		assert!(fetch.applies(5));
+		assert!(!fetch.applies(4));
+		assert!(!RangeFetch::new(1, 4).applies(100));
+	}
+}
@@ -0,0 +1,123 @@
+use core::time::Duration;
+use std::sync::Mutex;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge_vec;
+use prometheus::IntCounterVec;
+use prometheus::IntGaugeVec;
+use reqwest::header::HeaderMap;
+use reqwest::header::RETRY_AFTER;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+use tracing::info;
+use tracing::warn;
+
+static THROTTLED: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("upstream_throttled", "Whether an upstream has rate-limited us, and we're waiting out its Retry-After before asking it again", &["namespace"]).unwrap());
+static RATE_LIMITED: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_rate_limited", "Number of requests an upstream answered with 429 Too Many Requests", &["namespace"]).unwrap());
+
+/// Returned instead of asking an upstream that's rate-limited us again before it said we could.
+#[derive(Clone, Debug, thiserror::Error)]
+#[error("Upstream for namespace '{namespace}' is rate limiting us; not asking again for {}", humantime::format_duration(*.retry_after))]
+pub struct RateLimited {
+	pub namespace: CompactString,
+	pub retry_after: Duration
+}
+
+/// Keeps track of when an upstream that answered with a 429 will take requests again.  Until
+/// then, requests to it fail fast, rather than adding to what it's counting against us.
+#[derive(Debug)]
+pub struct Throttle {
+	namespace: CompactString,
+	/// How long to back off for when upstream doesn't say
+	default_backoff: Duration,
+	/// The longest a request waits out a `Retry-After` to try again itself, rather than failing
+	max_wait: Duration,
+	until: Mutex<Option<Instant>>
+}
+
+impl Throttle {
+	pub fn new(namespace: CompactString, default_backoff: Duration, max_wait: Duration) -> Self {
+		THROTTLED.with_label_values(&[namespace.as_str()]).set(0);
+		Self { namespace, default_backoff, max_wait, until: Mutex::new(None) }
+	}
+
+	pub fn check(&self) -> Result<(), RateLimited> {
+		let mut until = self.until.lock().unwrap();
+		match *until {
+			Some(at) if at > Instant::now() => Err(RateLimited { namespace: self.namespace.clone(), retry_after: at - Instant::now() }),
+			Some(_) => {
+				*until = None;
+				info!(namespace = self.namespace.as_str(), "Upstream rate limit backoff is over");
+				THROTTLED.with_label_values(&[self.namespace.as_str()]).set(0);
+				Ok(())
+			},
+			None => Ok(())
+		}
+	}
+
+	/// Records a 429 from upstream, with how long it said to wait if it did, and returns how long
+	/// requests will hold off for.
+	pub fn rate_limited(&self, retry_after: Option<Duration>) -> Duration {
+		RATE_LIMITED.with_label_values(&[self.namespace.as_str()]).inc();
+		let backoff = retry_after.unwrap_or(self.default_backoff);
+		let mut until = self.until.lock().unwrap();
+		let at = Instant::now() + backoff;
+		// Another request may have been told to wait longer
+		if (until.map_or(true, |u| u < at)) {
+			*until = Some(at);
+		}
+		warn!(namespace = self.namespace.as_str(), backoff = %humantime::format_duration(backoff), from_upstream = retry_after.is_some(), "Upstream is rate limiting us; backing off");
+		THROTTLED.with_label_values(&[self.namespace.as_str()]).set(1);
+		backoff
+	}
+
+	/// Whether a request can wait `backoff` out within its deadline and try again.
+	pub fn can_wait(&self, backoff: Duration, deadline: Instant) -> bool {
+		!self.max_wait.is_zero() && backoff <= self.max_wait && Instant::now() + backoff < deadline
+	}
+}
+
+/// How long a `Retry-After` header says to wait, as either a number of seconds or an HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+	let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+	if let Ok(seconds) = value.parse::<u64>() {
+		return Some(Duration::from_secs(seconds));
+	}
+	let at = OffsetDateTime::parse(value, &Rfc2822).ok()?;
+	Some(SystemTime::from(at).duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+	use reqwest::header::HeaderValue;
+
+	use super::*;
+
+	#[test]
+	fn retry_after_header() {
+		let headers = |value: &'static str| HeaderMap::from_iter([(RETRY_AFTER, HeaderValue::from_static(value))]);
+		assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+		assert_eq!(retry_after(&headers("Sun, 06 Nov 1994 08:49:37 GMT")), Some(Duration::ZERO));
+		assert_eq!(retry_after(&headers("soon")), None);
+		assert_eq!(retry_after(&HeaderMap::new()), None);
+	}
+
+	#[test]
+	fn backoff() {
+		let throttle = Throttle::new("throttle-test".into(), Duration::from_secs(60), Duration::from_secs(5));
+		assert!(throttle.check().is_ok());
+		assert_eq!(throttle.rate_limited(Some(Duration::from_secs(2))), Duration::from_secs(2));
+		assert!(throttle.check().unwrap_err().retry_after <= Duration::from_secs(2));
+		assert!(throttle.can_wait(Duration::from_secs(2), Instant::now() + Duration::from_secs(30)));
+		assert!(!throttle.can_wait(Duration::from_secs(2), Instant::now() + Duration::from_secs(1)));
+		assert!(!throttle.can_wait(throttle.rate_limited(None), Instant::now() + Duration::from_secs(300)));
+
+		let expired = Throttle::new("throttle-test-expired".into(), Duration::from_secs(60), Duration::ZERO);
+		expired.rate_limited(Some(Duration::ZERO));
+		assert!(expired.check().is_ok());
+	}
+}
@@ -0,0 +1,164 @@
+//! Upstream TLS sessions, kept across restarts:  with `--checkpoint` and `--checkpoint-tls-key`,
+//! the sessions the proxy's requests of upstreams resume are saved with the checkpoint and taken
+//! back at startup, so that the first of those requests after a restart resume a session rather
+//! than each doing a full handshake.  Whoever has a session's secrets can read what was sent in it,
+//! so they're only saved encrypted, with a key derived from `--checkpoint-tls-key`.
+
+use core::fmt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use once_cell::sync::OnceCell;
+use ring::aead::Aad;
+use ring::aead::LessSafeKey;
+use ring::aead::Nonce;
+use ring::aead::UnboundKey;
+use ring::aead::AES_256_GCM;
+use ring::aead::NONCE_LEN;
+use rustls::client::Resumption;
+use rustls::client::StoresClientSessions;
+use sha2::Digest;
+use sha2::Sha256;
+
+/// Once it's holding this many, one session is dropped for each one added
+const MAX_SESSIONS: usize = 1024;
+
+static STORE: OnceCell<(Arc<SessionStore>, LessSafeKey)> = OnceCell::new();
+
+/// Sessions by what rustls keys them by, the server's name and what kind of value it is.
+pub struct SessionStore {
+	sessions: Mutex<HashMap<Vec<u8>, Vec<u8>>>
+}
+
+impl fmt::Debug for SessionStore {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("SessionStore").finish_non_exhaustive()
+	}
+}
+
+impl StoresClientSessions for SessionStore {
+	fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+		let mut sessions = self.sessions.lock().unwrap();
+		if (sessions.len() >= MAX_SESSIONS && !sessions.contains_key(&key)) {
+			if let Some(dropped) = sessions.keys().next().cloned() {
+				sessions.remove(&dropped);
+			}
+		}
+		sessions.insert(key, value);
+		true
+	}
+
+	fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+		self.sessions.lock().unwrap().get(key).cloned()
+	}
+}
+
+impl SessionStore {
+	fn new() -> Self {
+		Self { sessions: Mutex::new(HashMap::new()) }
+	}
+
+	/// Every session, encrypted with `key`, as base64.
+	fn seal(&self, key: &LessSafeKey) -> Option<String> {
+		let sessions: Vec<[String; 2]> = self.sessions.lock().unwrap().iter().map(|(k, v)| [STANDARD.encode(k), STANDARD.encode(v)]).collect();
+		let mut body = serde_json::to_vec(&sessions).ok()?;
+		let nonce = rand::random::<[u8; NONCE_LEN]>();
+		key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut body).ok()?;
+		Some(STANDARD.encode([&nonce[..], &body[..]].concat()))
+	}
+
+	/// Takes back what [`seal`](Self::seal) gave; how many sessions, or `None` if they can't be
+	/// decrypted with `key`.
+	fn open(&self, key: &LessSafeKey, sealed: &str) -> Option<usize> {
+		let sealed = STANDARD.decode(sealed).ok()?;
+		if (sealed.len() < NONCE_LEN) {
+			return None;
+		}
+		let (nonce, body) = sealed.split_at(NONCE_LEN);
+		let mut body = body.to_vec();
+		let body = key.open_in_place(Nonce::try_assume_unique_for_key(nonce).ok()?, Aad::empty(), &mut body).ok()?;
+		let sessions: Vec<[String; 2]> = serde_json::from_slice(body).ok()?;
+		let mut count = 0;
+		for [k, v] in sessions {
+			if let (Ok(k), Ok(v)) = (STANDARD.decode(k), STANDARD.decode(v)) {
+				self.put(k, v);
+				count += 1;
+			}
+		}
+		Some(count)
+	}
+}
+
+fn key(secret: &str) -> LessSafeKey {
+	// Any 32 bytes make an AES-256 key
+	LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &Sha256::digest(secret.as_bytes())).unwrap())
+}
+
+/// Keeps upstream TLS sessions to be saved, encrypted with a key derived from `secret`; upstream
+/// clients built after this resume them.
+pub fn enable(secret: &str) {
+	let _ = STORE.set((Arc::new(SessionStore::new()), key(secret)));
+}
+
+/// Whether sessions are kept to be saved.
+pub fn enabled() -> bool {
+	STORE.get().is_some()
+}
+
+/// A TLS config for an upstream client that resumes sessions from the store, if sessions are kept;
+/// otherwise, reqwest's own is as good.
+pub fn config() -> Option<rustls::ClientConfig> {
+	let (store, _) = STORE.get()?;
+	let mut roots = rustls::RootCertStore::empty();
+	roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)));
+	let mut config = rustls::ClientConfig::builder().with_safe_defaults().with_root_certificates(roots).with_no_client_auth();
+	// As reqwest sets up the configs it builds itself
+	config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+	config.resumption = Resumption::store(store.clone());
+	Some(config)
+}
+
+/// The sessions kept, encrypted, for the checkpoint; `None` unless they're kept.
+pub fn save() -> Option<String> {
+	let (store, key) = STORE.get()?;
+	store.seal(key)
+}
+
+/// Takes back sessions from a checkpoint; how many, or `None` if they aren't kept, or can't be
+/// decrypted, as when `--checkpoint-tls-key` has changed since.
+pub fn restore(sealed: &str) -> Option<usize> {
+	let (store, key) = STORE.get()?;
+	store.open(key, sealed)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sealed_sessions() {
+		let store = SessionStore::new();
+		store.put(b"session:registry-1.docker.io".to_vec(), vec![1, 2, 3]);
+		store.put(b"kx-hint:registry-1.docker.io".to_vec(), vec![4]);
+		let sealed = store.seal(&key("secret")).unwrap();
+		assert!(!sealed.contains("registry-1"));
+
+		let restored = SessionStore::new();
+		assert_eq!(restored.open(&key("secret"), &sealed), Some(2));
+		assert_eq!(restored.get(b"session:registry-1.docker.io"), Some(vec![1, 2, 3]));
+		assert_eq!(SessionStore::new().open(&key("another secret"), &sealed), None);
+		assert_eq!(SessionStore::new().open(&key("secret"), "AAAA"), None);
+	}
+
+	#[test]
+	fn bounded() {
+		let store = SessionStore::new();
+		for i in 0..MAX_SESSIONS + 10 {
+			store.put(i.to_be_bytes().to_vec(), vec![0]);
+		}
+		assert_eq!(store.sessions.lock().unwrap().len(), MAX_SESSIONS);
+	}
+}
@@ -0,0 +1,114 @@
+use core::time::Duration;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use compact_str::CompactString;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+
+use super::pull::Authorization;
+
+static LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_anonymous_token_cache_lookups", "Number of anonymous pull tokens looked for in the cache, by whether one was found", &["namespace", "result"]).unwrap());
+
+/// How much earlier than its TTL a token can be dropped, as a fraction of the TTL, so that the
+/// tokens taken by a burst of pulls don't all need taking again at once.
+const MAX_EARLY_EXPIRY: f64 = 0.2;
+
+/// Once it's holding this many, expired tokens are dropped as new ones are added.
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Anonymous pull tokens for an upstream, by scope.  An anonymous token for a public repository is
+/// the same whoever it's taken for, so rather than each pull taking one of its own, it's taken
+/// once and reused until shortly before it expires.  Tokens are kept for `ttl` rather than for as
+/// long as upstream says they last, which is best kept under the lifetime upstream gives them; 60
+/// seconds is the least the token spec allows a registry to give.
+#[derive(Debug)]
+pub struct AnonymousTokens {
+	namespace: CompactString,
+	ttl: Duration,
+	/// Each scope's token, when it was taken, and when it's dropped
+	tokens: Mutex<HashMap<String, (Authorization, Instant, Instant)>>
+}
+
+impl AnonymousTokens {
+	/// A TTL of zero disables the cache.
+	pub fn new(namespace: CompactString, ttl: Duration) -> Self {
+		Self { namespace, ttl, tokens: Mutex::new(HashMap::new()) }
+	}
+
+	/// Whether tokens are reused at all.
+	pub fn is_enabled(&self) -> bool {
+		!self.ttl.is_zero()
+	}
+
+	/// The anonymous token taken for `scope`, if one has been since it last expired.
+	pub fn get(&self, scope: &str) -> Option<Authorization> {
+		if (!self.is_enabled()) {
+			return None;
+		}
+		let token = match self.tokens.lock().unwrap().get(scope) {
+			Some((token, _, expires)) if *expires > Instant::now() => Some(token.clone()),
+			_ => None
+		};
+		let result = match token.is_some() {
+			true => "hit",
+			false => "miss"
+		};
+		LOOKUPS.with_label_values(&[self.namespace.as_str(), result]).inc();
+		token
+	}
+
+	/// Keeps `token`, an anonymous token just taken for `scope`.
+	pub fn insert(&self, scope: &str, token: Authorization) {
+		if (self.ttl.is_zero()) {
+			return;
+		}
+		let now = Instant::now();
+		let mut tokens = self.tokens.lock().unwrap();
+		if (tokens.len() >= PRUNE_THRESHOLD) {
+			tokens.retain(|_, (_, _, expires)| *expires > now);
+		}
+		tokens.insert(scope.to_owned(), (token, now, now + expiry(self.ttl, rand::random::<f64>())));
+	}
+
+	/// The scopes tokens were taken for within the last `within`, and how long ago, so that they can
+	/// be taken again after a restart; the tokens themselves are never kept anywhere but here.
+	pub fn recent(&self, within: Duration) -> Vec<(String, Duration)> {
+		let tokens = self.tokens.lock().unwrap();
+		tokens.iter().map(|(scope, (_, taken, _))| (scope, taken.elapsed())).filter(|(_, age)| *age < within).map(|(scope, age)| (scope.clone(), age)).collect()
+	}
+
+	/// Drops the token for `scope`, after upstream has refused it.
+	pub fn forget(&self, scope: &str) {
+		self.tokens.lock().unwrap().remove(scope);
+	}
+}
+
+/// How long a token is kept, given a random number in `0..1`.
+fn expiry(ttl: Duration, random: f64) -> Duration {
+	ttl.mul_f64(1.0 - MAX_EARLY_EXPIRY * random)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn jittered_expiry() {
+		let ttl = Duration::from_secs(60);
+		assert_eq!(expiry(ttl, 0.0), ttl);
+		assert_eq!(expiry(ttl, 0.5), Duration::from_secs(54));
+		assert!(expiry(ttl, 0.999) > Duration::from_secs(48));
+	}
+
+	#[test]
+	fn recent_scopes() {
+		let tokens = AnonymousTokens::new("docker.io".into(), Duration::from_secs(60));
+		tokens.insert("repository:library/alpine:pull", Authorization::Bearer("token".to_owned()));
+		let recent = tokens.recent(Duration::from_secs(3600));
+		assert_eq!(recent.iter().map(|(scope, _)| scope.as_str()).collect::<Vec<_>>(), ["repository:library/alpine:pull"]);
+		assert!(tokens.recent(Duration::ZERO).is_empty());
+	}
+}
@@ -44,4 +44,9 @@ impl SecretString {
 	pub(crate) fn into_inner(self) -> CompactString {
 		self.0
 	}
+
+	#[inline]
+	pub(crate) fn expose(&self) -> &str {
+		self.0.as_str()
+	}
 }
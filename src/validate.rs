@@ -0,0 +1,103 @@
+//! Checks the configuration as a whole before anything is served, so that a typo in a namespace or
+//! a password without a username shows up at startup, rather than as a confusing error on whichever
+//! request first trips over it.  The `check-config` subcommand runs the same checks and exits.
+
+use core::fmt;
+
+use tracing::error;
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Severity {
+	/// Probably a mistake, but the proxy can run with it
+	Warning,
+	/// The proxy can't run as configured
+	Error
+}
+
+#[derive(Clone, Debug)]
+pub struct Problem {
+	pub severity: Severity,
+	/// The flag, file, or namespace the problem is with
+	pub subject: String,
+	pub message: String
+}
+
+impl fmt::Display for Problem {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}: {}", self.subject, self.message)
+	}
+}
+
+/// Everything wrong with a configuration, collected so that all of it can be fixed in one go.
+#[derive(Debug, Default)]
+pub struct Report {
+	problems: Vec<Problem>
+}
+
+impl Report {
+	pub fn warn(&mut self, subject: impl Into<String>, message: impl Into<String>) {
+		self.push(Severity::Warning, subject.into(), message.into());
+	}
+
+	pub fn error(&mut self, subject: impl Into<String>, message: impl Into<String>) {
+		self.push(Severity::Error, subject.into(), message.into());
+	}
+
+	fn push(&mut self, severity: Severity, subject: String, message: String) {
+		self.problems.push(Problem { severity, subject, message });
+	}
+
+	pub fn problems(&self) -> &[Problem] {
+		&self.problems
+	}
+
+	pub fn has_errors(&self) -> bool {
+		self.problems.iter().any(|p| p.severity == Severity::Error)
+	}
+
+	pub fn log(&self) {
+		for problem in &self.problems {
+			let subject = problem.subject.as_str();
+			match problem.severity {
+				Severity::Warning => warn!(subject, "{}", problem.message),
+				Severity::Error => error!(subject, "{}", problem.message)
+			};
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::upstream::SingleUpstreamConfig;
+
+	fn check(yaml: &str) -> Vec<String> {
+		let config: SingleUpstreamConfig = serde_yaml::from_str(yaml).unwrap();
+		let mut report = Report::default();
+		config.validate(&mut report);
+		report.problems().iter().map(ToString::to_string).collect()
+	}
+
+	#[test]
+	fn severities() {
+		let mut report = Report::default();
+		report.warn("--default-namespace", "Not configured upstream");
+		assert!(!report.has_errors());
+		report.error("--max-page-size", "Has to be at least 1");
+		assert!(report.has_errors());
+		assert_eq!(report.problems()[1].to_string(), "--max-page-size: Has to be at least 1");
+	}
+
+	#[test]
+	fn upstreams() {
+		assert!(check("namespace: docker.io\nhost: registry-1.docker.io").is_empty());
+		assert_eq!(check("namespace: docker.io\nhost: registry-1.docker.io\nusername: foo"), ["docker.io: username and password have to be set together"]);
+		assert!(check("namespace: ghcr.io\nhost: https://ghcr.io").iter().any(|p| p.contains("includes a scheme")));
+		assert_eq!(check("namespace: ghcr.io\nhost: ghcr.io\npush_username: foo\npush_password: bar"), ["ghcr.io: push_username and push_password are only used with write_through: true"]);
+		assert_eq!(check("namespace: ghcr.io\nhost: ghcr.io\nheaders:\n  X-Team: platform").len(), 0);
+		assert_eq!(check("namespace: ghcr.io\nhost: ghcr.io\nheaders:\n  \"X Team\": platform"), ["ghcr.io: X Team isn't a valid header name"]);
+		assert!(check("namespace: docker.io\nhost: regional.example.com\nparent: true").is_empty());
+		assert_eq!(check("namespace: docker.io\nhost: regional.example.com\nparent: true\nnamespace_parameter: never"), ["docker.io: parent: true needs the namespace passed on in ns; namespace_parameter: never leaves it out"]);
+	}
+}